@@ -0,0 +1,20 @@
+//! Former name of the [`vstp`] crate
+//!
+//! VSTP was first published as `vstp_labs`. Everything here is a re-export
+//! of [`vstp`], so code written against the old name keeps compiling, and
+//! its types are the same ones: a `vstp_labs::Frame` can be passed wherever
+//! a `vstp::Frame` is expected. Features are forwarded under the same names.
+//!
+//! This crate only exists for the transition and gets no features of its
+//! own. Depend on `vstp` instead and replace `vstp_labs::` with `vstp::`.
+//!
+//! ```
+//! use vstp_labs::{encode_frame, try_decode_frame, Frame, FrameType};
+//!
+//! let frame = Frame::new(FrameType::Data).with_payload(b"hello".to_vec());
+//! let mut buf = encode_frame(&frame).unwrap().into();
+//! let decoded: vstp::Frame = try_decode_frame(&mut buf, 1024).unwrap().unwrap();
+//! assert_eq!(decoded, frame);
+//! ```
+
+pub use vstp::*;
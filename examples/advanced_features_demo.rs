@@ -210,25 +210,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Demo 6: File transfer simulation
     info!("🎯 Demo 6: File Transfer Simulation");
     
-    for chunk_id in 1..=5 {
-        let chunk_data = vec![chunk_id as u8; 1000]; // 1KB chunks
-        let file_chunk = Frame::new(FrameType::Data)
-            .with_header("file-id", "doc-123")
-            .with_header("chunk-number", &chunk_id.to_string())
-            .with_header("total-chunks", "5")
-            .with_header("file-size", "5000")
+    let file_data = vec![0xAB; 5000];
+    let chunks = Frame::chunk_payload(&file_data, 1000); // 1KB chunks
+    let total = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let file_chunk = chunk
             .with_header("file-name", "document.pdf")
-            .with_header("checksum", "md5:abc123")
-            .with_payload(chunk_data)
             .with_flag(Flags::REQ_ACK);
         
         let result = timeout(Duration::from_secs(3), 
                             udp_client.send_with_ack(file_chunk, "127.0.0.1:6970".parse()?)).await;
         
         if result.is_ok() && result.unwrap().is_ok() {
-            info!("✅ File chunk {}/5 sent successfully", chunk_id);
+            info!("✅ File chunk {}/{} sent successfully", i + 1, total);
         } else {
-            info!("❌ File chunk {}/5 failed", chunk_id);
+            info!("❌ File chunk {}/{} failed", i + 1, total);
         }
         
         tokio::time::sleep(Duration::from_millis(100)).await;
@@ -10,23 +10,22 @@ struct ChatMessage {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+
     println!("Enter your name: ");
-    let mut name = String::new();
-    io::stdin().read_line(&mut name).await?;
+    let name = stdin.next_line().await?.unwrap_or_default();
     let name = name.trim().to_string();
 
     // Connect to chat server
-    let mut client = VstpClient::connect_tcp("127.0.0.1:8080").await?;
+    let client = VstpClient::connect_tcp("127.0.0.1:8080").await?;
     println!("Connected to chat server!");
 
-    // Split into send and receive tasks
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-    
     // Handle incoming messages
-    let mut receive_client = client.clone();
+    let receive_client = client.clone();
+    let own_name = name.clone();
     tokio::spawn(async move {
         while let Ok(msg) = receive_client.receive::<ChatMessage>().await {
-            if msg.from != name {
+            if msg.from != own_name {
                 println!("{}: {}", msg.from, msg.content);
             }
         }
@@ -34,7 +33,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Handle user input
     println!("Start typing messages (press Enter to send):");
-    while let Some(Ok(line)) = stdin.next_line().await {
+    while let Ok(Some(line)) = stdin.next_line().await {
         let msg = ChatMessage {
             from: name.clone(),
             content: line,
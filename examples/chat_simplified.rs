@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use vstp::easy::{VstpClient, VstpServer};
+use vstp::easy::VstpServer;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatMessage {
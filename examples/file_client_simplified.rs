@@ -23,7 +23,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // Connect to file server
-    let mut client = VstpClient::connect_tcp("127.0.0.1:8080").await?;
+    let client = VstpClient::connect_tcp("127.0.0.1:8080").await?;
     println!("Connected to file server!");
 
     // Request file
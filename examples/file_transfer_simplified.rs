@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use tokio::fs;
-use vstp::easy::{VstpClient, VstpServer};
+use vstp::easy::VstpServer;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct FileRequest {
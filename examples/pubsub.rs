@@ -0,0 +1,46 @@
+//! Pub/sub over the easy API, with retained messages for late subscribers
+//!
+//! The broker's routes sit next to an ordinary one on the same router. A
+//! dashboard that subscribes after some alerts went out first gets the last
+//! few of them, then live ones.
+
+use futures::StreamExt;
+use vstp::easy::{VstpClient, VstpServer};
+use vstp::pubsub::{PubSub, PubSubConfig};
+use vstp::Router;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let pubsub = PubSub::new(PubSubConfig {
+        retain: 3,
+        ..PubSubConfig::default()
+    });
+    let stats = pubsub.clone();
+    let router = pubsub.routes(Router::new()).route("published", move |_: ()| {
+        let published = stats.stats().published();
+        async move { Ok(published) }
+    });
+    let server = VstpServer::bind_tcp("127.0.0.1:8080").await?;
+    tokio::spawn(server.serve_router(router));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let publisher = VstpClient::connect_tcp("127.0.0.1:8080").await?;
+    for level in ["disk 80%", "disk 90%", "disk 95%", "disk 99%"] {
+        publisher.publish("alerts", level).await?;
+    }
+
+    let dashboard = VstpClient::connect_tcp("127.0.0.1:8080").await?;
+    let mut alerts = dashboard.subscribe_with_backfill::<String>("alerts", 3);
+    for _ in 0..3 {
+        let alert = alerts.next().await.ok_or("subscription ended")??;
+        println!("dashboard got {:?} (retained: {})", alert.message, alert.retained);
+    }
+
+    let reached = publisher.publish("alerts", "disk full").await?;
+    let alert = alerts.next().await.ok_or("subscription ended")??;
+    println!("dashboard got {:?} live, one of {} subscriber(s)", alert.message, reached);
+
+    let published: u64 = publisher.call("published", ()).await?;
+    println!("{} alerts published", published);
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Run a VSTP TCP server on a listener inherited from systemd
+//!
+//! With socket activation, systemd binds the port and starts the service with
+//! the listening socket already open. A restart then never has a window where
+//! the port is closed: connections queue on the socket while the new process
+//! starts up. The protocol is:
+//!
+//! - `LISTEN_PID` is the PID the sockets are meant for
+//! - `LISTEN_FDS` is how many sockets were passed
+//! - the sockets are file descriptors 3, 4, ... (`SD_LISTEN_FDS_START`)
+//!
+//! A matching pair of units:
+//!
+//! ```ini
+//! # vstp.socket
+//! [Socket]
+//! ListenStream=6969
+//!
+//! # vstp.service
+//! [Service]
+//! ExecStart=/usr/local/bin/socket_activation
+//! ```
+//!
+//! Try it without systemd using `systemd-socket-activate -l 6969 ./socket_activation`.
+//! When started directly, the example binds the port itself.
+
+use std::error::Error;
+use tracing::info;
+use vstp::{
+    tcp::VstpTcpServer,
+    types::{Frame, SessionId},
+};
+
+/// First file descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Initialize tracing
+    tracing_subscriber::fmt::init();
+
+    let server = match inherited_listener() {
+        Some(listener) => {
+            info!("Using listener passed by systemd");
+            VstpTcpServer::from_std_listener(listener)?
+        }
+        None => {
+            info!("No inherited listener, binding 127.0.0.1:6969");
+            VstpTcpServer::bind("127.0.0.1:6969").await?
+        }
+    };
+
+    server.run(handle_frame).await?;
+
+    Ok(())
+}
+
+/// Take the listener systemd passed to this process, if any
+#[cfg(unix)]
+fn inherited_listener() -> Option<std::net::TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+
+    // Don't pass the sockets on to child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+
+    // SAFETY: systemd guarantees the descriptor is an open socket owned by us
+    Some(unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+fn inherited_listener() -> Option<std::net::TcpListener> {
+    None
+}
+
+/// Log incoming frames
+async fn handle_frame(session_id: SessionId, frame: Frame) {
+    info!("Session {}: Received {:?} frame", session_id, frame.typ);
+}
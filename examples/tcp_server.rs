@@ -31,7 +31,7 @@ async fn handle_frame(session_id: SessionId, frame: Frame) {
             // In a real implementation, you'd send a WELCOME frame back
         }
         FrameType::Data => {
-            if let Ok(payload_str) = String::from_utf8(frame.payload.clone()) {
+            if let Ok(payload_str) = frame.payload_text() {
                 info!("Session {}: Received data: {}", session_id, payload_str);
             } else {
                 info!(
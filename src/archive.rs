@@ -0,0 +1,517 @@
+//! Archive files of recorded frames
+//!
+//! A [`FrameArchiveWriter`] appends frames, e.g. from a [`WireTap`], to a
+//! file along with when they were seen, the session they belong to and their
+//! direction. A [`FrameArchiveReader`] reads them back in order, by record
+//! number, or by session.
+//!
+//! ```text
+//! MAGIC "VSAR" (4B) | VERSION (1B)
+//! then per record:
+//! LEN (4B BE) | CRC32 (4B BE) | TIMESTAMP (8B BE) | SESSION (16B BE) | DIR (1B) | FRAME
+//! ```
+//!
+//! TIMESTAMP is in microseconds since the UNIX epoch, DIR is 0 for inbound
+//! and 1 for outbound frames, and FRAME is the frame as [`encode_frame`]
+//! produces it. LEN counts and the CRC-32 covers every byte after the CRC.
+//!
+//! Records cut short or garbled by a crash are detected by their length and
+//! CRC. Readers stop at the first bad record and report where it starts;
+//! [`FrameArchiveWriter::open`] cuts such a tail off before appending.
+//!
+//! Random access uses an index of record offsets. Readers build it while
+//! opening the archive, starting from the sidecar file at [`index_path`] if
+//! there is one, so only records added since the sidecar was written need
+//! to be scanned. Writers with [`FrameArchiveWriter::with_index`] write the
+//! sidecar when they finish, and [`reindex`] rebuilds it from the archive.
+//!
+//! [`WireTap`]: crate::WireTap
+//! [`encode_frame`]: crate::encode_frame
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use crc_any::CRC;
+use tracing::warn;
+
+use crate::frame::{encode_frame, try_decode_frame};
+use crate::types::{Frame, SessionId, VstpError};
+
+/// First bytes of every archive file
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"VSAR";
+
+/// Archive format version written by this crate
+pub const ARCHIVE_VERSION: u8 = 1;
+
+/// First bytes of every index sidecar file
+const INDEX_MAGIC: [u8; 4] = *b"VSAI";
+
+const FILE_HEADER_LEN: u64 = 5;
+
+/// LEN and CRC
+const RECORD_PREFIX_LEN: usize = 8;
+
+/// TIMESTAMP, SESSION and DIR
+const RECORD_HEADER_LEN: usize = 25;
+
+/// Which way a recorded frame travelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Received by the recording side
+    Inbound = 0,
+    /// Sent by the recording side
+    Outbound = 1,
+}
+
+impl Direction {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Direction::Inbound),
+            1 => Some(Direction::Outbound),
+            _ => None,
+        }
+    }
+}
+
+/// One recorded frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveRecord {
+    /// When the frame was seen, to the microsecond
+    pub timestamp: SystemTime,
+    pub session_id: SessionId,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+
+/// Path of the index sidecar of the archive at `archive`: its name plus `.idx`
+pub fn index_path(archive: impl AsRef<Path>) -> PathBuf {
+    let mut path = archive.as_ref().as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// Offsets of an archive's records, overall and per session
+///
+/// Sidecar layout, all integers big-endian:
+///
+/// ```text
+/// MAGIC "VSAI" (4B) | VERSION (1B) | COVERED (8B) | COUNT (8B) | OFFSET (8B) * COUNT
+/// SESSIONS (8B) | per session: SESSION (16B) | N (8B) | OFFSET (8B) * N
+/// ```
+///
+/// COVERED is the length of the archive prefix the index describes.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ArchiveIndex {
+    covered: u64,
+    offsets: Vec<u64>,
+    sessions: BTreeMap<SessionId, Vec<u64>>,
+}
+
+impl ArchiveIndex {
+    fn empty() -> Self {
+        Self {
+            covered: FILE_HEADER_LEN,
+            ..Self::default()
+        }
+    }
+
+    /// Note a record at `offset` that ends at `end`
+    fn push(&mut self, offset: u64, session_id: SessionId, end: u64) {
+        self.offsets.push(offset);
+        self.sessions.entry(session_id).or_default().push(offset);
+        self.covered = end;
+    }
+
+    fn save(&self, path: &Path) -> Result<(), VstpError> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&INDEX_MAGIC)?;
+        out.write_all(&[ARCHIVE_VERSION])?;
+        out.write_all(&self.covered.to_be_bytes())?;
+        out.write_all(&(self.offsets.len() as u64).to_be_bytes())?;
+        for offset in &self.offsets {
+            out.write_all(&offset.to_be_bytes())?;
+        }
+        out.write_all(&(self.sessions.len() as u64).to_be_bytes())?;
+        for (session_id, offsets) in &self.sessions {
+            out.write_all(&session_id.to_be_bytes())?;
+            out.write_all(&(offsets.len() as u64).to_be_bytes())?;
+            for offset in offsets {
+                out.write_all(&offset.to_be_bytes())?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// The sidecar at `path`, if there is a usable one for an archive of `archive_len` bytes
+    fn load(path: &Path, archive_len: u64) -> Option<Self> {
+        let mut input = BufReader::new(File::open(path).ok()?);
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header).ok()?;
+        if header[..4] != INDEX_MAGIC || header[4] != ARCHIVE_VERSION {
+            return None;
+        }
+        let covered = read_u64(&mut input)?;
+        if !(FILE_HEADER_LEN..=archive_len).contains(&covered) {
+            return None;
+        }
+        let count = read_u64(&mut input)?;
+        let offsets = (0..count)
+            .map(|_| read_u64(&mut input))
+            .collect::<Option<Vec<_>>>()?;
+        let mut sessions = BTreeMap::new();
+        for _ in 0..read_u64(&mut input)? {
+            let mut session_id = [0u8; 16];
+            input.read_exact(&mut session_id).ok()?;
+            let n = read_u64(&mut input)?;
+            let list = (0..n)
+                .map(|_| read_u64(&mut input))
+                .collect::<Option<Vec<_>>>()?;
+            sessions.insert(SessionId::from_be_bytes(session_id), list);
+        }
+        Some(Self {
+            covered,
+            offsets,
+            sessions,
+        })
+    }
+}
+
+fn read_u64(input: &mut impl Read) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes).ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn not_an_archive(path: &Path) -> VstpError {
+    VstpError::Protocol(format!("{} is not a VSTP frame archive", path.display()))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = CRC::crc32();
+    crc.digest(bytes);
+    crc.get_crc() as u32
+}
+
+/// Check the file header of the archive `file`, returning the archive's length
+fn check_header(file: &mut File, path: &Path) -> Result<u64, VstpError> {
+    let mut header = [0u8; FILE_HEADER_LEN as usize];
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(not_an_archive(path)),
+        Err(e) => return Err(e.into()),
+    }
+    if header[..4] != ARCHIVE_MAGIC {
+        return Err(not_an_archive(path));
+    }
+    if header[4] != ARCHIVE_VERSION {
+        return Err(VstpError::Protocol(format!(
+            "{} is archive version {}, expected {}",
+            path.display(),
+            header[4],
+            ARCHIVE_VERSION
+        )));
+    }
+    Ok(file.metadata()?.len())
+}
+
+/// Read the record body at the reader's position, `None` if it is cut short or corrupt
+fn read_body(input: &mut impl Read, remaining: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut prefix = [0u8; RECORD_PREFIX_LEN];
+    if (remaining as usize) < RECORD_PREFIX_LEN {
+        return Ok(None);
+    }
+    input.read_exact(&mut prefix)?;
+    let len = u32::from_be_bytes(prefix[..4].try_into().unwrap()) as u64;
+    let crc = u32::from_be_bytes(prefix[4..].try_into().unwrap());
+    if len < RECORD_HEADER_LEN as u64 || len > remaining - RECORD_PREFIX_LEN as u64 {
+        return Ok(None);
+    }
+    let mut body = vec![0u8; len as usize];
+    input.read_exact(&mut body)?;
+    Ok((crc32(&body) == crc).then_some(body))
+}
+
+/// Index the records from `index.covered` on, returning where a corrupt tail starts
+fn scan(file: &mut File, len: u64, index: &mut ArchiveIndex) -> Result<Option<u64>, VstpError> {
+    let mut offset = index.covered;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut input = BufReader::new(file);
+    while offset < len {
+        let Some(body) = read_body(&mut input, len - offset)? else {
+            return Ok(Some(offset));
+        };
+        let end = offset + (RECORD_PREFIX_LEN + body.len()) as u64;
+        let session_id = SessionId::from_be_bytes(body[8..24].try_into().unwrap());
+        index.push(offset, session_id, end);
+        offset = end;
+    }
+    Ok(None)
+}
+
+fn decode_record(body: &[u8]) -> Result<ArchiveRecord, VstpError> {
+    let micros = u64::from_be_bytes(body[..8].try_into().unwrap());
+    let session_id = SessionId::from_be_bytes(body[8..24].try_into().unwrap());
+    let direction = Direction::from_u8(body[24])
+        .ok_or_else(|| VstpError::Protocol(format!("unknown direction {}", body[24])))?;
+    let mut encoded = BytesMut::from(&body[RECORD_HEADER_LEN..]);
+    let frame = try_decode_frame(&mut encoded, body.len())?
+        .filter(|_| encoded.is_empty())
+        .ok_or_else(|| VstpError::Protocol("record holds a partial frame".to_string()))?;
+    Ok(ArchiveRecord {
+        timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+        session_id,
+        direction,
+        frame,
+    })
+}
+
+/// Appends records to an archive file
+///
+/// Records are buffered; call [`flush`](FrameArchiveWriter::flush) or
+/// [`finish`](FrameArchiveWriter::finish) to get them to disk. To record
+/// from a [`WireTap`](crate::WireTap), share the writer behind a mutex and
+/// hand the tapped bytes to
+/// [`append_encoded`](FrameArchiveWriter::append_encoded).
+#[derive(Debug)]
+pub struct FrameArchiveWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    len: u64,
+    index: ArchiveIndex,
+    write_index: bool,
+}
+
+impl FrameArchiveWriter {
+    /// Start a new archive at `path`, replacing any file there and its sidecar
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, VstpError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::create(&path)?;
+        file.write_all(&ARCHIVE_MAGIC)?;
+        file.write_all(&[ARCHIVE_VERSION])?;
+        match std::fs::remove_file(index_path(&path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(Self {
+            file: BufWriter::new(file),
+            path,
+            len: FILE_HEADER_LEN,
+            index: ArchiveIndex::empty(),
+            write_index: false,
+        })
+    }
+
+    /// Append to the archive at `path`, creating it if there is none
+    ///
+    /// A corrupt tail left by a crash is cut off first.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, VstpError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Self::create(path);
+        }
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = check_header(&mut file, path)?;
+        let mut index =
+            ArchiveIndex::load(&index_path(path), len).unwrap_or_else(ArchiveIndex::empty);
+        if let Some(tail) = scan(&mut file, len, &mut index)? {
+            warn!(
+                "Cutting off {} corrupt bytes at the end of {}",
+                len - tail,
+                path.display()
+            );
+            file.set_len(tail)?;
+        }
+        file.seek(SeekFrom::Start(index.covered))?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            path: path.to_path_buf(),
+            len: index.covered,
+            index,
+            write_index: false,
+        })
+    }
+
+    /// Write the index sidecar on [`finish`](FrameArchiveWriter::finish)
+    pub fn with_index(mut self) -> Self {
+        self.write_index = true;
+        self
+    }
+
+    /// Append a frame seen at `timestamp`
+    pub fn append(&mut self, record: &ArchiveRecord) -> Result<(), VstpError> {
+        let encoded = encode_frame(&record.frame)?;
+        self.append_encoded(
+            record.timestamp,
+            record.session_id,
+            record.direction,
+            &encoded,
+        )
+    }
+
+    /// Append a frame that is already encoded, e.g. as seen by a wire tap
+    pub fn append_encoded(
+        &mut self,
+        timestamp: SystemTime,
+        session_id: SessionId,
+        direction: Direction,
+        frame: &[u8],
+    ) -> Result<(), VstpError> {
+        let micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut body = Vec::with_capacity(RECORD_HEADER_LEN + frame.len());
+        body.extend_from_slice(&micros.to_be_bytes());
+        body.extend_from_slice(&session_id.to_be_bytes());
+        body.push(direction as u8);
+        body.extend_from_slice(frame);
+        let len = u32::try_from(body.len()).map_err(|_| VstpError::FrameTooLarge {
+            size: body.len(),
+            limit: u32::MAX as usize,
+        })?;
+
+        self.file.write_all(&len.to_be_bytes())?;
+        self.file.write_all(&crc32(&body).to_be_bytes())?;
+        self.file.write_all(&body)?;
+        let offset = self.len;
+        self.len += (RECORD_PREFIX_LEN + body.len()) as u64;
+        self.index.push(offset, session_id, self.len);
+        Ok(())
+    }
+
+    /// Write buffered records to the file
+    pub fn flush(&mut self) -> Result<(), VstpError> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Flush the records and write the index sidecar, if the writer keeps an index
+    pub fn finish(mut self) -> Result<(), VstpError> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        if self.write_index {
+            self.index.save(&index_path(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the records of an archive file
+#[derive(Debug)]
+pub struct FrameArchiveReader {
+    file: BufReader<File>,
+    /// Offset the underlying file is at, to skip needless seeks
+    pos: u64,
+    index: ArchiveIndex,
+    corrupt_tail: Option<u64>,
+}
+
+impl FrameArchiveReader {
+    /// Open the archive at `path`, indexing any records its sidecar doesn't cover
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, VstpError> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let len = check_header(&mut file, path)?;
+        let index = ArchiveIndex::load(&index_path(path), len).unwrap_or_else(ArchiveIndex::empty);
+        Self::with_index(file, path, len, index)
+    }
+
+    fn with_index(
+        mut file: File,
+        path: &Path,
+        len: u64,
+        mut index: ArchiveIndex,
+    ) -> Result<Self, VstpError> {
+        let corrupt_tail = scan(&mut file, len, &mut index)?;
+        if let Some(tail) = corrupt_tail {
+            warn!(
+                "Skipping {} corrupt bytes at the end of {}",
+                len - tail,
+                path.display()
+            );
+        }
+        file.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+        Ok(Self {
+            file: BufReader::new(file),
+            pos: FILE_HEADER_LEN,
+            index,
+            corrupt_tail,
+        })
+    }
+
+    /// Number of intact records
+    pub fn len(&self) -> usize {
+        self.index.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.offsets.is_empty()
+    }
+
+    /// Offset of the first corrupt byte, if the archive ends in a damaged record
+    pub fn corrupt_tail(&self) -> Option<u64> {
+        self.corrupt_tail
+    }
+
+    /// Sessions with records in the archive, in ascending order
+    pub fn sessions(&self) -> impl Iterator<Item = SessionId> + '_ {
+        self.index.sessions.keys().copied()
+    }
+
+    /// Number of records of `session_id`
+    pub fn session_len(&self, session_id: SessionId) -> usize {
+        self.index.sessions.get(&session_id).map_or(0, Vec::len)
+    }
+
+    fn read_at(&mut self, offset: u64) -> Result<ArchiveRecord, VstpError> {
+        if self.pos != offset {
+            self.file.seek(SeekFrom::Start(offset))?;
+        }
+        let body = read_body(&mut self.file, u64::MAX)?;
+        self.pos = offset + (RECORD_PREFIX_LEN + body.as_ref().map_or(0, Vec::len)) as u64;
+        // Indexed records were intact when the archive was opened
+        let body = body.ok_or_else(|| {
+            VstpError::Protocol(format!("record at offset {} changed on disk", offset))
+        })?;
+        decode_record(&body)
+    }
+
+    /// Record number `n`, counting from 0
+    pub fn get(&mut self, n: usize) -> Result<Option<ArchiveRecord>, VstpError> {
+        match self.index.offsets.get(n) {
+            Some(&offset) => self.read_at(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Every record in order
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<ArchiveRecord, VstpError>> + '_ {
+        (0..self.len()).map(move |n| self.read_at(self.index.offsets[n]))
+    }
+
+    /// The records of `session_id` in order
+    pub fn session(
+        &mut self,
+        session_id: SessionId,
+    ) -> impl Iterator<Item = Result<ArchiveRecord, VstpError>> + '_ {
+        (0..self.session_len(session_id))
+            .map(move |n| self.read_at(self.index.sessions[&session_id][n]))
+    }
+}
+
+/// Rebuild the index sidecar of the archive at `path` from its records
+///
+/// Returns the number of intact records.
+pub fn reindex(path: impl AsRef<Path>) -> Result<usize, VstpError> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    let len = check_header(&mut file, path)?;
+    let reader = FrameArchiveReader::with_index(file, path, len, ArchiveIndex::empty())?;
+    reader.index.save(&index_path(path))?;
+    Ok(reader.len())
+}
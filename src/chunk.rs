@@ -0,0 +1,417 @@
+//! Splitting in-memory data into sequenced DATA frames and putting it back together
+//!
+//! A simple, synchronous alternative to streaming for data that is already
+//! held in memory. Every chunk carries these headers:
+//!
+//! - `stream-id`: identifier shared by all chunks of one payload
+//! - `seq`: position of the chunk, starting at 0
+//! - `total`: number of chunks in the payload
+//!
+//! The last chunk additionally carries `fin: 1`. Values are decimal ASCII.
+//! Chunks may be sent over any transport and reassembled in any order.
+//!
+//! Each chunk also carries a `chunk-hash` over its payload, and the last one
+//! a `payload-hash` over the whole payload, both checked on reassembly. A
+//! [`ChunkReceiver`] checks each chunk's hash as it arrives and answers a
+//! corrupted chunk with a repair request, an ERR `BadCrc` naming the chunk,
+//! which the [`ChunkSender`] answers by sending that chunk again:
+//!
+//! ```
+//! use vstp::chunk::{ChunkEvent, ChunkReceiver, ChunkSender};
+//!
+//! # fn main() -> Result<(), vstp::VstpError> {
+//! let sender = ChunkSender::new(b"some large payload", 4);
+//! let mut receiver = ChunkReceiver::new();
+//! for chunk in sender.chunks() {
+//!     let mut chunk = chunk.clone();
+//!     chunk.payload[0] ^= 1; // corrupted on the way
+//!     let ChunkEvent::Repair(request) = receiver.receive(chunk)? else { unreachable!() };
+//!     let resent = sender.repair(&request).unwrap();
+//!     if let ChunkEvent::Complete(payload) = receiver.receive(resent)? {
+//!         assert_eq!(payload, b"some large payload");
+//!     }
+//! }
+//! assert_eq!(receiver.report().repairs, 5);
+//! # Ok(()) }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use sha2::{Digest, Sha256};
+
+use crate::types::{ErrorCode, Frame, FrameType, VstpError};
+
+/// Header carrying the identifier shared by all chunks of a payload
+pub const STREAM_ID_HEADER: &str = "stream-id";
+
+/// Header carrying a chunk's position, starting at 0
+pub const SEQ_HEADER: &str = "seq";
+
+/// Header carrying the number of chunks in the payload
+pub const TOTAL_HEADER: &str = "total";
+
+/// Header marking the final chunk
+pub const FIN_HEADER: &str = "fin";
+
+/// Header carrying the first 8 bytes of the SHA-256 of a chunk's payload, in hex
+pub const CHUNK_HASH_HEADER: &str = "chunk-hash";
+
+/// Header on the final chunk carrying the SHA-256 of the whole payload, in hex
+pub const PAYLOAD_HASH_HEADER: &str = "payload-hash";
+
+/// Repair attempts a [`ChunkReceiver`] allows per chunk by default
+pub const DEFAULT_MAX_REPAIRS: u32 = 3;
+
+impl Frame {
+    /// Split `data` into DATA frames of at most `chunk_size` payload bytes
+    ///
+    /// Empty data yields a single empty chunk, so the receiver still sees
+    /// the payload. Panics if `chunk_size` is 0.
+    pub fn chunk_payload(data: &[u8], chunk_size: usize) -> Vec<Frame> {
+        assert!(chunk_size > 0, "chunk_size must be non-zero");
+
+        let stream_id = rand::random::<u64>().to_string();
+        let total = data.len().div_ceil(chunk_size).max(1);
+        (0..total)
+            .map(|seq| {
+                let start = seq * chunk_size;
+                let end = (start + chunk_size).min(data.len());
+                let frame = Frame::new(FrameType::Data)
+                    .with_header(STREAM_ID_HEADER, &stream_id)
+                    .with_header(SEQ_HEADER, &seq.to_string())
+                    .with_header(TOTAL_HEADER, &total.to_string())
+                    .with_header(CHUNK_HASH_HEADER, &chunk_hash(&data[start..end]))
+                    .with_payload(data[start..end].to_vec());
+                if seq + 1 == total {
+                    frame
+                        .with_header(FIN_HEADER, "1")
+                        .with_header(PAYLOAD_HASH_HEADER, &hex(&Sha256::digest(data)))
+                } else {
+                    frame
+                }
+            })
+            .collect()
+    }
+
+    /// Reassemble the payload from chunks produced by [`Frame::chunk_payload`]
+    ///
+    /// The chunks may be in any order and exact duplicates are ignored.
+    /// Fails if chunks are missing, belong to different streams, disagree
+    /// about the total or which chunk is final, or don't match their hashes.
+    pub fn reassemble(frames: &[Frame]) -> Result<Vec<u8>, VstpError> {
+        let first = frames
+            .first()
+            .ok_or_else(|| VstpError::Protocol("no chunks to reassemble".to_string()))?;
+        let stream_id = first.get_header(STREAM_ID_HEADER);
+        let total: usize = chunk_number(first, TOTAL_HEADER)?;
+        if total == 0 {
+            return Err(VstpError::Protocol("chunk total is 0".to_string()));
+        }
+
+        let mut chunks = BTreeMap::new();
+        for frame in frames {
+            if frame.get_header(STREAM_ID_HEADER) != stream_id {
+                return Err(VstpError::Protocol(
+                    "chunks belong to different streams".to_string(),
+                ));
+            }
+            if chunk_number::<usize>(frame, TOTAL_HEADER)? != total {
+                return Err(VstpError::Protocol("chunks disagree on total".to_string()));
+            }
+            let seq: usize = chunk_number(frame, SEQ_HEADER)?;
+            if seq >= total {
+                return Err(VstpError::Protocol(format!(
+                    "chunk {} is beyond total {}",
+                    seq, total
+                )));
+            }
+            verify_chunk(frame, seq)?;
+            let is_fin = frame.get_header(FIN_HEADER) == Some("1");
+            if is_fin != (seq + 1 == total) {
+                return Err(VstpError::Protocol(format!(
+                    "chunk {} has a misplaced final-chunk marker",
+                    seq
+                )));
+            }
+            if let Some(existing) = chunks.insert(seq, &frame.payload) {
+                if existing != &frame.payload {
+                    return Err(VstpError::Protocol(format!(
+                        "conflicting duplicates of chunk {}",
+                        seq
+                    )));
+                }
+            }
+        }
+
+        if chunks.len() != total {
+            let missing = (0..total).filter(|seq| !chunks.contains_key(seq));
+            return Err(VstpError::Protocol(format!(
+                "missing chunks: {:?}",
+                missing.collect::<Vec<_>>()
+            )));
+        }
+        let payload: Vec<u8> = chunks.into_values().flatten().copied().collect();
+        let fin = frames
+            .iter()
+            .find(|frame| frame.get_header(FIN_HEADER) == Some("1"));
+        verify_payload(fin, &payload)?;
+        Ok(payload)
+    }
+}
+
+/// What a [`ChunkReceiver`] made of a chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkEvent {
+    /// Stored; more chunks are needed
+    Pending,
+    /// The chunk didn't match its hash; send this request back to the sender
+    Repair(Frame),
+    /// That was the last chunk, here is the payload
+    Complete(Vec<u8>),
+}
+
+/// What a [`ChunkReceiver`] did so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChunkReport {
+    /// Chunks accepted
+    pub chunks: usize,
+    /// Repair requests sent, over all chunks
+    pub repairs: u64,
+}
+
+/// Reassembles one chunked payload, asking for chunks that arrive corrupted again
+///
+/// See the [module docs](self) for the repair exchange.
+#[derive(Debug)]
+pub struct ChunkReceiver {
+    stream_id: Option<String>,
+    chunks: BTreeMap<usize, Frame>,
+    /// Repair requests sent, by chunk
+    repairs: HashMap<usize, u32>,
+    max_repairs: u32,
+    report: ChunkReport,
+}
+
+impl Default for ChunkReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkReceiver {
+    /// A receiver allowing [`DEFAULT_MAX_REPAIRS`] repairs per chunk
+    pub fn new() -> Self {
+        Self {
+            stream_id: None,
+            chunks: BTreeMap::new(),
+            repairs: HashMap::new(),
+            max_repairs: DEFAULT_MAX_REPAIRS,
+            report: ChunkReport::default(),
+        }
+    }
+
+    /// Allow `max_repairs` repairs per chunk before giving up
+    pub fn with_max_repairs(mut self, max_repairs: u32) -> Self {
+        self.max_repairs = max_repairs;
+        self
+    }
+
+    /// Take a chunk
+    ///
+    /// Fails if the chunk is malformed or belongs to another stream, if a
+    /// chunk is still corrupted after `max_repairs` repairs, or if the
+    /// reassembled payload doesn't match its `payload-hash`.
+    pub fn receive(&mut self, frame: Frame) -> Result<ChunkEvent, VstpError> {
+        let stream_id = frame
+            .get_header(STREAM_ID_HEADER)
+            .ok_or_else(|| VstpError::Protocol("chunk has no stream-id header".to_string()))?;
+        match &self.stream_id {
+            Some(id) if id != stream_id => {
+                return Err(VstpError::Protocol(
+                    "chunks belong to different streams".to_string(),
+                ))
+            }
+            Some(_) => {}
+            None => self.stream_id = Some(stream_id.to_string()),
+        }
+        let seq: usize = chunk_number(&frame, SEQ_HEADER)?;
+
+        if verify_chunk(&frame, seq).is_err() {
+            let attempts = self.repairs.entry(seq).or_default();
+            if *attempts >= self.max_repairs {
+                return Err(VstpError::Protocol(format!(
+                    "chunk {} still corrupted after {} repairs",
+                    seq, attempts
+                )));
+            }
+            *attempts += 1;
+            self.report.repairs += 1;
+            return Ok(ChunkEvent::Repair(repair_request(stream_id, seq)));
+        }
+
+        if self.chunks.insert(seq, frame).is_none() {
+            self.report.chunks += 1;
+        }
+        let total: usize = match self.chunks.values().next() {
+            Some(first) => chunk_number(first, TOTAL_HEADER)?,
+            None => return Ok(ChunkEvent::Pending),
+        };
+        if self.chunks.len() < total {
+            return Ok(ChunkEvent::Pending);
+        }
+        let chunks: Vec<Frame> = std::mem::take(&mut self.chunks).into_values().collect();
+        Frame::reassemble(&chunks).map(ChunkEvent::Complete)
+    }
+
+    /// Chunks accepted and repairs requested so far
+    pub fn report(&self) -> ChunkReport {
+        self.report
+    }
+}
+
+/// Holds the chunks of a payload to answer a [`ChunkReceiver`]'s repair requests
+#[derive(Debug, Clone)]
+pub struct ChunkSender {
+    chunks: Vec<Frame>,
+}
+
+impl ChunkSender {
+    /// Split `data` with [`Frame::chunk_payload`]
+    pub fn new(data: &[u8], chunk_size: usize) -> Self {
+        Self {
+            chunks: Frame::chunk_payload(data, chunk_size),
+        }
+    }
+
+    /// The chunks to send
+    pub fn chunks(&self) -> &[Frame] {
+        &self.chunks
+    }
+
+    /// The chunk `request` asks for again; `None` if it isn't a repair
+    /// request for this payload
+    pub fn repair(&self, request: &Frame) -> Option<Frame> {
+        if request.error_code() != Some(ErrorCode::BadCrc)
+            || request.get_header(STREAM_ID_HEADER) != self.chunks[0].get_header(STREAM_ID_HEADER)
+        {
+            return None;
+        }
+        let seq: usize = chunk_number(request, SEQ_HEADER).ok()?;
+        self.chunks.get(seq).cloned()
+    }
+}
+
+/// ERR asking for chunk `seq` of stream `stream_id` again
+fn repair_request(stream_id: &str, seq: usize) -> Frame {
+    Frame::coded_error(ErrorCode::BadCrc, &format!("chunk {} failed its hash", seq))
+        .with_header(STREAM_ID_HEADER, stream_id)
+        .with_header(SEQ_HEADER, &seq.to_string())
+}
+
+fn chunk_hash(payload: &[u8]) -> String {
+    hex(&Sha256::digest(payload)[..8])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Check chunk `seq` against its `chunk-hash`, if it has one
+fn verify_chunk(frame: &Frame, seq: usize) -> Result<(), VstpError> {
+    match frame.get_header(CHUNK_HASH_HEADER) {
+        Some(expected) if expected != chunk_hash(&frame.payload) => Err(VstpError::Protocol(
+            format!("chunk {} doesn't match its hash", seq),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check a reassembled payload against the final chunk's `payload-hash`, if it has one
+fn verify_payload(fin: Option<&Frame>, payload: &[u8]) -> Result<(), VstpError> {
+    match fin.and_then(|frame| frame.get_header(PAYLOAD_HASH_HEADER)) {
+        Some(expected) if expected != hex(&Sha256::digest(payload)) => Err(VstpError::Protocol(
+            "payload doesn't match its hash".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn chunk_number<T: std::str::FromStr>(frame: &Frame, header: &str) -> Result<T, VstpError> {
+    frame
+        .get_header(header)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| VstpError::Protocol(format!("chunk has no valid {} header", header)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_and_reassemble_out_of_order() {
+        let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+        let mut chunks = Frame::chunk_payload(&data, 1000);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2].get_header(FIN_HEADER), Some("1"));
+        assert_eq!(chunks[2].payload.len(), 500);
+
+        chunks.reverse();
+        chunks.push(chunks[1].clone());
+        assert_eq!(Frame::reassemble(&chunks).unwrap(), data);
+    }
+
+    #[test]
+    fn test_empty_payload_is_one_chunk() {
+        let chunks = Frame::chunk_payload(&[], 16);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(Frame::reassemble(&chunks).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_reassemble_rejects_missing_and_mixed_chunks() {
+        let chunks = Frame::chunk_payload(&[1; 30], 10);
+        assert!(matches!(
+            Frame::reassemble(&chunks[..2]),
+            Err(VstpError::Protocol(_))
+        ));
+
+        let mut mixed = Frame::chunk_payload(&[2; 30], 10);
+        mixed[1] = chunks[1].clone();
+        assert!(matches!(
+            Frame::reassemble(&mixed),
+            Err(VstpError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_reassemble_checks_hashes() {
+        let mut chunks = Frame::chunk_payload(&[3; 30], 10);
+        chunks[1].payload[0] = 4;
+        assert!(Frame::reassemble(&chunks).is_err());
+
+        // Consistent chunks, but not the payload the sender hashed
+        let mut chunks = Frame::chunk_payload(&[3; 30], 10);
+        chunks[1].payload[0] = 4;
+        let hash = chunk_hash(&chunks[1].payload);
+        chunks[1].set_header(CHUNK_HASH_HEADER, &hash);
+        assert!(Frame::reassemble(&chunks).is_err());
+    }
+
+    #[test]
+    fn test_receiver_gives_up_after_max_repairs() {
+        let sender = ChunkSender::new(&[5; 30], 10);
+        let mut receiver = ChunkReceiver::new().with_max_repairs(2);
+        let mut corrupted = sender.chunks()[2].clone();
+        corrupted.payload[9] ^= 0xff;
+
+        for _ in 0..2 {
+            let ChunkEvent::Repair(request) = receiver.receive(corrupted.clone()).unwrap() else {
+                panic!("expected a repair request");
+            };
+            assert_eq!(sender.repair(&request), Some(sender.chunks()[2].clone()));
+        }
+        assert!(receiver.receive(corrupted).is_err());
+        assert_eq!(receiver.report().repairs, 2);
+        assert!(sender.repair(&Frame::error("BadCrc", "?")).is_none());
+    }
+}
@@ -0,0 +1,221 @@
+//! Estimating the server's clock from handshake and PING/PONG timing
+//!
+//! Devices with badly set clocks break absolute timestamps such as
+//! `expires-at-ms`. To compensate, servers stamp their WELCOME and PONG frames
+//! with `server-time-ms` (unix milliseconds), and clients turn each stamped
+//! reply into an offset between their clock and the server's:
+//!
+//! ```text
+//! offset = server-time-ms + rtt / 2 - local receive time
+//! ```
+//!
+//! This is not NTP. A single exchange is assumed to be symmetric, so the
+//! estimate is only good to about half the round-trip time plus whatever
+//! asymmetry the path has, which on a LAN is a few milliseconds and on mobile
+//! links can be hundreds. It is meant to keep TTLs and deadlines sane, not to
+//! order events across hosts.
+//!
+//! Offsets may be negative (the local clock runs ahead). Samples are ignored
+//! when the server time is implausible (before 2020 or after 2100) or the
+//! round trip took too long to say anything useful.
+
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::types::{Frame, FrameType};
+
+/// Header carrying the sender's wall clock as unix milliseconds
+pub const SERVER_TIME_MS_HEADER: &str = "server-time-ms";
+
+/// Header carrying the client's local send time on a PING, echoed on the PONG
+pub const PING_SENT_MS_HEADER: &str = "ping-sent-ms";
+
+/// 2020-01-01T00:00:00Z; earlier server times are treated as bogus
+const MIN_PLAUSIBLE_MS: u64 = 1_577_836_800_000;
+
+/// 2100-01-01T00:00:00Z; later server times are treated as bogus
+const MAX_PLAUSIBLE_MS: u64 = 4_102_444_800_000;
+
+/// Samples with a longer round trip are too imprecise to use
+const MAX_SAMPLE_RTT: Duration = Duration::from_secs(10);
+
+/// Add the current time as `server-time-ms`
+pub fn stamp_server_time(frame: Frame) -> Frame {
+    frame.with_header(SERVER_TIME_MS_HEADER, &unix_ms(SystemTime::now()).to_string())
+}
+
+/// One measurement of the server's clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    /// Server time minus local time, in milliseconds
+    pub offset_ms: i64,
+    /// Round trip of the exchange the sample came from
+    pub rtt: Duration,
+}
+
+/// Running estimate of how far the local clock is off from the server's
+///
+/// Each accepted sample replaces the previous one, so periodic re-syncs
+/// follow a drifting clock.
+#[derive(Debug)]
+pub struct ClockSync {
+    local_clock: fn() -> SystemTime,
+    sample: Mutex<Option<ClockSample>>,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::with_local_clock(SystemTime::now)
+    }
+
+    /// Estimate against a different local clock, e.g. a deliberately skewed one in tests
+    pub fn with_local_clock(local_clock: fn() -> SystemTime) -> Self {
+        Self {
+            local_clock,
+            sample: Mutex::new(None),
+        }
+    }
+
+    /// Current local time
+    pub fn local_now(&self) -> SystemTime {
+        (self.local_clock)()
+    }
+
+    /// Latest accepted sample, if any
+    pub fn sample(&self) -> Option<ClockSample> {
+        *self.sample.lock().unwrap()
+    }
+
+    /// Best guess at the server's current time; the local time until a sample is taken
+    pub fn estimated_server_time(&self) -> SystemTime {
+        let now = self.local_now();
+        match self.sample() {
+            Some(sample) if sample.offset_ms >= 0 => {
+                now + Duration::from_millis(sample.offset_ms as u64)
+            }
+            Some(sample) => now - Duration::from_millis(sample.offset_ms.unsigned_abs()),
+            None => now,
+        }
+    }
+
+    /// Record an exchange sent at `sent_at` and answered at `received_at`, both local times
+    ///
+    /// Returns whether the sample was accepted.
+    pub fn record(&self, sent_at: SystemTime, received_at: SystemTime, server_time_ms: u64) -> bool {
+        if !(MIN_PLAUSIBLE_MS..=MAX_PLAUSIBLE_MS).contains(&server_time_ms) {
+            return false;
+        }
+        let Ok(rtt) = received_at.duration_since(sent_at) else {
+            // The local clock stepped backwards mid-exchange
+            return false;
+        };
+        if rtt > MAX_SAMPLE_RTT {
+            return false;
+        }
+
+        let offset_ms = server_time_ms as i64 + (rtt.as_millis() / 2) as i64
+            - unix_ms(received_at) as i64;
+        *self.sample.lock().unwrap() = Some(ClockSample { offset_ms, rtt });
+        true
+    }
+
+    /// Record a stamped reply to a request sent at local time `sent_at`
+    pub fn record_reply(&self, sent_at: SystemTime, reply: &Frame) -> bool {
+        match server_time_ms(reply) {
+            Some(server_time_ms) => self.record(sent_at, self.local_now(), server_time_ms),
+            None => false,
+        }
+    }
+
+    /// A PING that lets [`record_pong`](ClockSync::record_pong) time the exchange
+    pub fn ping(&self) -> Frame {
+        Frame::new(FrameType::Ping)
+            .with_header(PING_SENT_MS_HEADER, &unix_ms(self.local_now()).to_string())
+    }
+
+    /// Record a stamped PONG answering a PING from [`ping`](ClockSync::ping)
+    pub fn record_pong(&self, pong: &Frame) -> bool {
+        let Some(sent_ms) = pong
+            .get_header(PING_SENT_MS_HEADER)
+            .and_then(|v| v.parse().ok())
+        else {
+            return false;
+        };
+        self.record_reply(UNIX_EPOCH + Duration::from_millis(sent_ms), pong)
+    }
+}
+
+/// The `ping-sent-ms` of a PING carrying nothing else, whose PONG
+/// [`encode_pong_into`](crate::frame::encode_pong_into) writes byte for byte
+pub(crate) fn plain_ping_sent_ms(frame: &Frame) -> Option<u64> {
+    let [header] = frame.headers.as_slice() else {
+        return None;
+    };
+    if frame.typ != FrameType::Ping || header.key != PING_SENT_MS_HEADER.as_bytes() {
+        return None;
+    }
+    let sent_ms = std::str::from_utf8(&header.value).ok()?.parse().ok()?;
+    // The PONG echoes the value as sent, e.g. with leading zeros
+    let canonical = itoa::Buffer::new().format(sent_ms).as_bytes() == header.value;
+    canonical.then_some(sent_ms)
+}
+
+fn server_time_ms(frame: &Frame) -> Option<u64> {
+    frame.get_header(SERVER_TIME_MS_HEADER)?.parse().ok()
+}
+
+pub(crate) fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: u64 = 3_600_000;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_offset_includes_half_the_round_trip() {
+        let sync = ClockSync::new();
+        let local = 1_700_000_000_000;
+        assert!(sync.record(at(local), at(local + 100), local + HOUR_MS));
+        let sample = sync.sample().unwrap();
+        assert_eq!(sample.rtt, Duration::from_millis(100));
+        assert_eq!(sample.offset_ms, HOUR_MS as i64 - 50);
+    }
+
+    #[test]
+    fn test_negative_offset() {
+        let sync = ClockSync::new();
+        let local = 1_700_000_000_000;
+        assert!(sync.record(at(local), at(local), local - HOUR_MS));
+        assert_eq!(sync.sample().unwrap().offset_ms, -(HOUR_MS as i64));
+
+        let estimate = sync.estimated_server_time();
+        let behind = SystemTime::now().duration_since(estimate).unwrap();
+        assert!(behind > Duration::from_millis(HOUR_MS - 1000));
+    }
+
+    #[test]
+    fn test_absurd_samples_are_ignored() {
+        let sync = ClockSync::new();
+        let local = 1_700_000_000_000;
+        assert!(!sync.record(at(local), at(local + 10), 0));
+        assert!(!sync.record(at(local), at(local + 10), u64::MAX));
+        assert!(!sync.record(at(local), at(local + 60_000), local));
+        assert!(!sync.record(at(local + 10), at(local), local));
+        assert_eq!(sync.sample(), None);
+    }
+}
@@ -1,38 +1,345 @@
-use bytes::{BufMut, BytesMut};
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::frame::{encode_frame, try_decode_frame};
-use crate::types::{Frame, VstpError};
+use crate::frame::{encode_frame, try_decode_frame, try_decode_frame_observed, Integrity};
+use crate::types::{Frame, HeaderRatioLimit, VstpError, VSTP_VERSION};
+
+/// Read buffer capacity `Framed` starts with, and the codec's default
+pub const DEFAULT_READ_BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// Callback given the bytes of one encoded frame
+pub type WireCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Callbacks given the exact bytes of every frame sent and received
+///
+/// Outgoing bytes are observed right after encoding, incoming ones as soon as
+/// the whole frame has arrived, before its CRC is checked. Callbacks run on
+/// the I/O path and should hand the bytes off rather than block. An empty
+/// tap costs one branch per frame.
+#[derive(Clone, Default)]
+pub struct WireTap {
+    out: Option<WireCallback>,
+    incoming: Option<WireCallback>,
+}
+
+impl WireTap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `callback` with each frame's bytes before they are sent
+    pub fn on_wire_out(mut self, callback: impl Fn(&[u8]) + Send + Sync + 'static) -> Self {
+        self.out = Some(Arc::new(callback));
+        self
+    }
+
+    /// Call `callback` with each frame's bytes after they are received
+    pub fn on_wire_in(mut self, callback: impl Fn(&[u8]) + Send + Sync + 'static) -> Self {
+        self.incoming = Some(Arc::new(callback));
+        self
+    }
+
+    pub(crate) fn wire_out(&self, bytes: &[u8]) {
+        if let Some(callback) = &self.out {
+            callback(bytes);
+        }
+    }
+
+    /// Decode the next frame from `buf`, showing its bytes to `on_wire_in`
+    pub(crate) fn decode(
+        &self,
+        buf: &mut BytesMut,
+        max_frame_size: usize,
+    ) -> Result<Option<Frame>, VstpError> {
+        match &self.incoming {
+            Some(callback) => try_decode_frame_observed(buf, max_frame_size, |b| callback(b)),
+            None => try_decode_frame(buf, max_frame_size),
+        }
+    }
+
+    /// [`decode`](WireTap::decode), also showing the frame's bytes to `observe`
+    pub(crate) fn decode_observed(
+        &self,
+        buf: &mut BytesMut,
+        max_frame_size: usize,
+        observe: impl FnOnce(&[u8]),
+    ) -> Result<Option<Frame>, VstpError> {
+        try_decode_frame_observed(buf, max_frame_size, |b| {
+            if let Some(callback) = &self.incoming {
+                callback(b);
+            }
+            observe(b)
+        })
+    }
+}
+
+impl fmt::Debug for WireTap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireTap")
+            .field("on_wire_out", &self.out.is_some())
+            .field("on_wire_in", &self.incoming.is_some())
+            .finish()
+    }
+}
 
 /// Tokio codec for VSTP frames
+///
+/// Frames are encoded in the codec's frame format version and integrity
+/// check, whatever their own `version` and flags say; the decoder accepts
+/// every supported version and check.
 pub struct VstpFrameCodec {
     max_frame_size: usize,
+    version: u8,
+    integrity: Integrity,
+    tap: WireTap,
+    last_frame_len: usize,
+    /// Bytes of the frame decoded last, while `keep_raw_bytes` is on
+    last_frame_bytes: Option<Bytes>,
+    keep_raw_bytes: bool,
+    bytes_encoded: u64,
+    header_ratio: Option<HeaderRatioLimit>,
+    read_buffer_capacity: usize,
+    max_read_buffer_capacity: Option<usize>,
 }
 
 impl VstpFrameCodec {
     pub fn new(max_frame_size: usize) -> Self {
-        Self { max_frame_size }
+        Self {
+            max_frame_size,
+            version: VSTP_VERSION,
+            integrity: Integrity::default(),
+            tap: WireTap::default(),
+            last_frame_len: 0,
+            last_frame_bytes: None,
+            keep_raw_bytes: false,
+            bytes_encoded: 0,
+            header_ratio: None,
+            read_buffer_capacity: DEFAULT_READ_BUFFER_CAPACITY,
+            max_read_buffer_capacity: None,
+        }
+    }
+
+    /// Size the read buffer for the frames expected, see
+    /// [`set_read_buffer_capacity`](VstpFrameCodec::set_read_buffer_capacity)
+    pub fn with_read_buffer_capacity(mut self, initial: usize, max: Option<usize>) -> Self {
+        self.set_read_buffer_capacity(initial, max);
+        self
+    }
+
+    /// Grow the read buffer to `initial` bytes once reading starts, and
+    /// give it back down to `initial` once it has grown past `max` and holds
+    /// no more than that
+    ///
+    /// The buffer otherwise starts at [`DEFAULT_READ_BUFFER_CAPACITY`] and
+    /// doubles while a larger frame arrives, so steadily large frames pay
+    /// for several reallocations each time the buffer drains. An `initial`
+    /// just above the usual encoded frame size avoids them. `max` bounds the
+    /// memory a connection keeps after an unusually large frame; `None`
+    /// keeps whatever the buffer grew to.
+    pub fn set_read_buffer_capacity(&mut self, initial: usize, max: Option<usize>) {
+        self.read_buffer_capacity = initial;
+        self.max_read_buffer_capacity = max;
+    }
+
+    /// Initial and maximum read buffer capacity
+    pub fn read_buffer_capacity(&self) -> (usize, Option<usize>) {
+        (self.read_buffer_capacity, self.max_read_buffer_capacity)
+    }
+
+    /// Show the bytes of every frame encoded and decoded to `tap`
+    pub fn with_wire_tap(mut self, tap: WireTap) -> Self {
+        self.tap = tap;
+        self
     }
 
-    pub fn default() -> Self {
+    pub fn set_wire_tap(&mut self, tap: WireTap) {
+        self.tap = tap;
+    }
+
+    pub(crate) fn wire_tap(&self) -> &WireTap {
+        &self.tap
+    }
+
+    /// Frame format version used for encoding
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Encode subsequent frames in `version`, e.g. once a handshake has agreed on it
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    /// Encode frames with the `integrity` trailer, see [`Integrity`] for the tradeoff
+    pub fn with_integrity(mut self, integrity: Integrity) -> Self {
+        self.integrity = integrity;
+        self
+    }
+
+    /// Integrity check used for encoding
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.integrity = integrity;
+    }
+
+    /// Encoded size of the frame decoded last
+    pub fn last_frame_len(&self) -> usize {
+        self.last_frame_len
+    }
+
+    /// Keep a copy of each decoded frame's bytes, for [`take_last_frame_bytes`](VstpFrameCodec::take_last_frame_bytes)
+    pub fn set_keep_raw_bytes(&mut self, keep: bool) {
+        self.keep_raw_bytes = keep;
+        if !keep {
+            self.last_frame_bytes = None;
+        }
+    }
+
+    /// Bytes of the frame decoded last, if they are kept and not taken yet
+    pub fn take_last_frame_bytes(&mut self) -> Option<Bytes> {
+        self.last_frame_bytes.take()
+    }
+
+    /// Bytes of all frames encoded so far
+    pub fn bytes_encoded(&self) -> u64 {
+        self.bytes_encoded
+    }
+
+    /// Fail decoding frames whose headers break `limit`; `None` (the default) turns the check off
+    pub fn set_max_header_to_payload_ratio(&mut self, limit: Option<HeaderRatioLimit>) {
+        self.header_ratio = limit;
+    }
+}
+
+impl Default for VstpFrameCodec {
+    fn default() -> Self {
         Self::new(8 * 1024 * 1024) // 8MB default
     }
 }
 
+/// Transport-independent incremental decoder
+///
+/// Feed bytes as they arrive with [`push`](FrameDecoder::push) and drain
+/// completed frames with [`next_frame`](FrameDecoder::next_frame). Partial
+/// frames stay buffered until the rest of their bytes are pushed, so this
+/// works with any event loop, not just tokio's `Framed`.
+///
+/// ```
+/// use vstp::{encode_frame, Frame, FrameDecoder, FrameType};
+///
+/// let encoded = encode_frame(&Frame::new(FrameType::Ping)).unwrap();
+/// let mut decoder = FrameDecoder::default();
+///
+/// decoder.push(&encoded[..4]);
+/// assert!(decoder.next_frame().unwrap().is_none());
+/// decoder.push(&encoded[4..]);
+/// assert_eq!(decoder.next_frame().unwrap().unwrap().typ, FrameType::Ping);
+/// ```
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+    max_frame_size: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Append received bytes
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame, or `None` until more bytes are pushed
+    ///
+    /// An error means the byte stream is corrupt and can't be resynchronized;
+    /// drop the connection or [`reset`](FrameDecoder::reset) the decoder.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, VstpError> {
+        try_decode_frame(&mut self.buf, self.max_frame_size)
+    }
+
+    /// Number of bytes buffered that don't yet form a complete frame
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Discard all buffered bytes
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(8 * 1024 * 1024) // Same limit as the codec
+    }
+}
+
 impl Decoder for VstpFrameCodec {
     type Item = Frame;
     type Error = VstpError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        try_decode_frame(src, self.max_frame_size)
+        if src.capacity() < self.read_buffer_capacity {
+            src.reserve(self.read_buffer_capacity - src.len());
+        }
+        let buffered = src.len();
+        let frame = if self.keep_raw_bytes {
+            let mut raw = None;
+            let frame = self.tap.decode_observed(src, self.max_frame_size, |b| {
+                raw = Some(Bytes::copy_from_slice(b))
+            })?;
+            if frame.is_some() {
+                self.last_frame_bytes = raw;
+            }
+            frame
+        } else {
+            self.tap.decode(src, self.max_frame_size)?
+        };
+        if let Some(frame) = &frame {
+            self.last_frame_len = buffered - src.len();
+            if let Some(limit) = &self.header_ratio {
+                limit.check(frame)?;
+            }
+            self.shrink_read_buffer(src);
+        }
+        Ok(frame)
+    }
+}
+
+impl VstpFrameCodec {
+    /// Swap a read buffer grown past the maximum for one of the initial capacity
+    fn shrink_read_buffer(&self, src: &mut BytesMut) {
+        let Some(max) = self.max_read_buffer_capacity else {
+            return;
+        };
+        if src.capacity() > max && src.len() <= self.read_buffer_capacity {
+            let mut fresh = BytesMut::with_capacity(self.read_buffer_capacity);
+            fresh.extend_from_slice(src);
+            *src = fresh;
+        }
     }
 }
 
 impl Encoder<Frame> for VstpFrameCodec {
     type Error = VstpError;
 
-    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, mut item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.version = self.version;
+        item.flags = self.integrity.apply(item.flags);
         let encoded = encode_frame(&item)?;
+        self.tap.wire_out(&encoded);
+        self.bytes_encoded += encoded.len() as u64;
         dst.put_slice(&encoded);
         Ok(())
     }
@@ -61,6 +368,29 @@ mod tests {
         assert_eq!(frame, decoded);
     }
 
+    #[test]
+    fn test_codec_integrity_choice() {
+        let frame = Frame::new(FrameType::Data).with_payload(b"hello".to_vec());
+        let mut crc_buf = BytesMut::new();
+        VstpFrameCodec::default()
+            .encode(frame.clone(), &mut crc_buf)
+            .unwrap();
+        let mut sha_codec = VstpFrameCodec::default().with_integrity(Integrity::Sha256);
+        let mut sha_buf = BytesMut::new();
+        sha_codec.encode(frame.clone(), &mut sha_buf).unwrap();
+        assert_eq!(sha_buf.len(), crc_buf.len() + 12);
+
+        // Any codec verifies either check, going by the frame's flags
+        let mut codec = VstpFrameCodec::default();
+        let decoded = codec.decode(&mut sha_buf).unwrap().unwrap();
+        assert_eq!(Integrity::of(decoded.flags), Integrity::Sha256);
+        assert_eq!(decoded.payload, frame.payload);
+        assert_eq!(
+            Integrity::of(sha_codec.decode(&mut crc_buf).unwrap().unwrap().flags),
+            Integrity::Crc32
+        );
+    }
+
     #[test]
     fn test_codec_partial_decode() {
         let mut codec = VstpFrameCodec::default();
@@ -79,4 +409,117 @@ mod tests {
         let decoded = codec.decode(&mut buf).unwrap().unwrap();
         assert_eq!(frame, decoded);
     }
+
+    /// Feed `encoded` in 16 KiB reads the way `Framed` does, counting the
+    /// times the read buffer moves before the frame is decoded
+    fn reallocations(codec: &mut VstpFrameCodec, encoded: &[u8]) -> (usize, BytesMut) {
+        let mut buf = BytesMut::with_capacity(DEFAULT_READ_BUFFER_CAPACITY);
+        let mut moves = 0;
+        for chunk in encoded.chunks(16 * 1024) {
+            let before = buf.as_ptr();
+            buf.reserve(1);
+            buf.extend_from_slice(chunk);
+            if buf.as_ptr() != before {
+                moves += 1;
+            }
+            let before = buf.as_ptr();
+            if codec.decode(&mut buf).unwrap().is_some() {
+                return (moves, buf);
+            }
+            if buf.as_ptr() != before {
+                moves += 1;
+            }
+        }
+        panic!("frame not decoded");
+    }
+
+    #[test]
+    fn test_read_buffer_capacity_avoids_reallocations() {
+        let frame = Frame::new(FrameType::Data).with_payload(vec![9; 256 * 1024]);
+        let encoded = encode_frame(&frame).unwrap();
+
+        let (moves, _) = reallocations(&mut VstpFrameCodec::default(), &encoded);
+        assert!(moves > 2, "{} reallocations", moves);
+
+        let mut codec = VstpFrameCodec::default().with_read_buffer_capacity(320 * 1024, None);
+        let (moves, _) = reallocations(&mut codec, &encoded);
+        assert!(moves <= 1, "{} reallocations", moves);
+    }
+
+    #[test]
+    fn test_read_buffer_shrinks_past_max() {
+        let frame = Frame::new(FrameType::Data).with_payload(vec![9; 256 * 1024]);
+        let encoded = encode_frame(&frame).unwrap();
+        let mut codec =
+            VstpFrameCodec::default().with_read_buffer_capacity(16 * 1024, Some(64 * 1024));
+        let (_, buf) = reallocations(&mut codec, &encoded);
+        assert_eq!(buf.capacity(), 16 * 1024);
+    }
+
+    #[test]
+    fn test_frame_decoder_byte_at_a_time() {
+        let frame = Frame::new(FrameType::Data)
+            .with_header("test", "value")
+            .with_payload(b"hello".to_vec());
+        let encoded = encode_frame(&frame).unwrap();
+
+        let mut decoder = FrameDecoder::default();
+        for (i, byte) in encoded.iter().enumerate() {
+            assert!(decoder.next_frame().unwrap().is_none());
+            decoder.push(&[*byte]);
+            assert_eq!(decoder.buffered_len(), i + 1);
+        }
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), frame);
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[test]
+    fn test_frame_decoder_multiple_frames_in_one_push() {
+        let first = Frame::new(FrameType::Hello);
+        let second = Frame::new(FrameType::Data).with_payload(b"x".to_vec());
+        let mut bytes = encode_frame(&first).unwrap().to_vec();
+        bytes.extend_from_slice(&encode_frame(&second).unwrap());
+        let split = bytes.len() - 3;
+
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&bytes[..split]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), first);
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.push(&bytes[split..]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), second);
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wire_tap_sees_exact_bytes() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (out, incoming) = (seen.clone(), seen.clone());
+        let tap = WireTap::new()
+            .on_wire_out(move |b| out.lock().unwrap().push(("out", b.to_vec())))
+            .on_wire_in(move |b| incoming.lock().unwrap().push(("in", b.to_vec())));
+        let mut codec = VstpFrameCodec::default().with_wire_tap(tap);
+
+        let frame = Frame::new(FrameType::Data).with_payload(b"tapped".to_vec());
+        let expected = encode_frame(&frame).unwrap().to_vec();
+        let mut buf = BytesMut::new();
+        codec.encode(frame.clone(), &mut buf).unwrap();
+        buf.extend_from_slice(b"VT");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame));
+        // The trailing partial frame isn't shown until it is complete
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(*seen, [("out", expected.clone()), ("in", expected)]);
+    }
+
+    #[test]
+    fn test_frame_decoder_rejects_garbage() {
+        let mut decoder = FrameDecoder::default();
+        decoder.push(b"not a vstp frame");
+        assert!(decoder.next_frame().is_err());
+        decoder.reset();
+        assert_eq!(decoder.buffered_len(), 0);
+    }
 }
@@ -0,0 +1,648 @@
+//! Payload compression that peers can switch on and off mid-session
+//!
+//! A compressed frame has the `COMP` flag set and a deflate-compressed
+//! payload. Receivers decompress every frame that carries the flag, whatever
+//! they believe the current setting is, so frames already in flight when the
+//! setting changes are always read correctly. The negotiated setting only
+//! decides whether a peer compresses what it *sends*.
+//!
+//! ## Renegotiating
+//!
+//! Either peer may propose a change with a PING carrying
+//! `compression-request: on|off`. The other peer answers with a PONG carrying
+//! `compression: on|off`, the setting it will use from then on:
+//!
+//! ```text
+//!  proposer                                 peer
+//!  Settled(off)                             Settled(off)
+//!  Proposed(on) --- PING request=on ------> Settled(on)
+//!               <-- PONG compression=on --- (compresses from here on)
+//!  Settled(on)
+//!  (compresses from here on)
+//! ```
+//!
+//! The proposer keeps its old setting until the answer arrives, and drops the
+//! proposal if the answer names the other setting. A peer that refuses
+//! compression answers `off`. Should proposals cross and leave the peers
+//! disagreeing, nothing is lost: each side still reads the other's frames by
+//! their flag, and the next proposal settles it.
+//!
+//! ## Dictionaries
+//!
+//! Deflate restarts with every frame, so small payloads that look alike barely
+//! shrink. Peers that hold the same zstd [`Dictionary`] under the same id can
+//! compress each payload against it instead: such frames carry the `COMP` flag
+//! and a `comp-dict` header naming the dictionary. Dictionaries are
+//! preconfigured or handed over out of band and registered on both sides;
+//! [`CompressionControl::dictionary_capabilities`] lists the registered ids
+//! for a WELCOME's `capabilities` header, and [`peer_dictionaries`] reads them
+//! back. A frame naming a dictionary the receiver doesn't hold fails with
+//! [`VstpError::UnknownDictionary`].
+//!
+//! Choosing a dictionary with [`CompressionControl::use_dictionary`] is a
+//! statement that the peer holds it, so it applies regardless of the
+//! negotiated deflate setting.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::Arc;
+
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use zstd::zstd_safe::CParameter;
+
+use crate::header_table::HeaderTable;
+use crate::types::{Flags, Frame, FrameType, VstpError};
+
+/// Header on a PING proposing a new compression setting, `on` or `off`
+pub const COMPRESSION_REQUEST_HEADER: &str = "compression-request";
+
+/// Header on a PONG answering a proposal with the setting now in use
+pub const COMPRESSION_HEADER: &str = "compression";
+
+/// Header naming the [`Dictionary`] a compressed payload was compressed against
+pub const COMP_DICT_HEADER: &str = "comp-dict";
+
+/// Prefix of the capability advertising a dictionary, followed by its id
+pub const DICTIONARY_CAPABILITY_PREFIX: &str = "comp-dict:";
+
+/// Payloads smaller than this are sent uncompressed by default
+const DEFAULT_MIN_SIZE: usize = 256;
+
+/// zstd level used with dictionaries
+const DICTIONARY_LEVEL: i32 = 3;
+
+/// A zstd dictionary that both peers hold under the same id
+#[derive(Clone)]
+pub struct Dictionary {
+    id: u32,
+    bytes: Arc<[u8]>,
+    encoder: Arc<EncoderDictionary<'static>>,
+    decoder: Arc<DecoderDictionary<'static>>,
+}
+
+impl Dictionary {
+    /// Use `bytes`, a trained dictionary or just typical content, as dictionary `id`
+    pub fn new(id: u32, bytes: Vec<u8>) -> Self {
+        Self {
+            id,
+            encoder: Arc::new(EncoderDictionary::copy(&bytes, DICTIONARY_LEVEL)),
+            decoder: Arc::new(DecoderDictionary::copy(&bytes)),
+            bytes: bytes.into(),
+        }
+    }
+
+    /// Train a dictionary of at most `max_size` bytes on sample payloads
+    ///
+    /// Needs a reasonable number of samples (a few hundred small ones).
+    pub fn train<S: AsRef<[u8]>>(
+        id: u32,
+        samples: &[S],
+        max_size: usize,
+    ) -> Result<Self, VstpError> {
+        let bytes = zstd::dict::from_samples(samples, max_size)?;
+        Ok(Self::new(id, bytes))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The dictionary content, to hand to the peer
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &self.id)
+            .field("len", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Ids of the dictionaries advertised in a `capabilities` header value
+pub fn peer_dictionaries(capabilities: &str) -> Vec<u32> {
+    capabilities
+        .split(',')
+        .filter_map(|c| c.trim().strip_prefix(DICTIONARY_CAPABILITY_PREFIX))
+        .filter_map(|id| id.parse().ok())
+        .collect()
+}
+
+impl Frame {
+    /// Deflate the payload and set the `COMP` flag
+    pub fn compress(mut self) -> Result<Frame, VstpError> {
+        let mut compressed = Vec::new();
+        DeflateEncoder::new(self.payload.as_slice(), Compression::fast())
+            .read_to_end(&mut compressed)?;
+        self.payload = compressed;
+        self.flags |= Flags::COMP;
+        Ok(self)
+    }
+
+    /// Compress the payload against `dictionary`, set the `COMP` flag and name
+    /// the dictionary in the `comp-dict` header
+    pub fn compress_with_dictionary(mut self, dictionary: &Dictionary) -> Result<Frame, VstpError> {
+        let mut compressor = Compressor::with_prepared_dictionary(&dictionary.encoder)?;
+        // The id travels in the header already
+        compressor.set_parameter(CParameter::DictIdFlag(false))?;
+        self.payload = compressor.compress(&self.payload)?;
+        self.flags |= Flags::COMP;
+        Ok(self.with_header(COMP_DICT_HEADER, &dictionary.id.to_string()))
+    }
+
+    /// Inflate the payload if the `COMP` flag is set, and clear the flag
+    ///
+    /// Fails if the payload inflates to more than `max_size` bytes, and with
+    /// [`VstpError::UnknownDictionary`] if it was compressed against a
+    /// dictionary; see [`decompress_with_dictionary`](Frame::decompress_with_dictionary).
+    pub fn decompress(mut self, max_size: usize) -> Result<Frame, VstpError> {
+        if !self.flags.is_compressed() {
+            return Ok(self);
+        }
+        if let Some(id) = dictionary_id(&self)? {
+            return Err(VstpError::UnknownDictionary(id));
+        }
+        let mut payload = Vec::new();
+        DeflateDecoder::new(self.payload.as_slice())
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut payload)
+            .map_err(|e| VstpError::Protocol(format!("Invalid compressed payload: {}", e)))?;
+        if payload.len() > max_size {
+            return Err(VstpError::FrameTooLarge {
+                size: payload.len(),
+                limit: max_size,
+            });
+        }
+        self.payload = payload;
+        self.flags.remove(Flags::COMP);
+        Ok(self)
+    }
+
+    /// Decompress a payload compressed against `dictionary`, clearing the
+    /// `COMP` flag and the `comp-dict` header
+    ///
+    /// Fails if the frame names a different dictionary or the payload
+    /// decompresses to more than `max_size` bytes.
+    pub fn decompress_with_dictionary(
+        mut self,
+        max_size: usize,
+        dictionary: &Dictionary,
+    ) -> Result<Frame, VstpError> {
+        if !self.flags.is_compressed() {
+            return Ok(self);
+        }
+        match dictionary_id(&self)? {
+            Some(id) if id == dictionary.id => {}
+            Some(id) => return Err(VstpError::UnknownDictionary(id)),
+            None => return self.decompress(max_size),
+        }
+        if let Ok(Some(size)) = zstd::zstd_safe::get_frame_content_size(&self.payload) {
+            if size > max_size as u64 {
+                return Err(VstpError::FrameTooLarge {
+                    size: size as usize,
+                    limit: max_size,
+                });
+            }
+        }
+        let mut decompressor = Decompressor::with_prepared_dictionary(&dictionary.decoder)?;
+        self.payload = decompressor
+            .decompress(&self.payload, max_size)
+            .map_err(|e| VstpError::Protocol(format!("Invalid compressed payload: {}", e)))?;
+        self.flags.remove(Flags::COMP);
+        self.headers
+            .retain(|h| h.key != COMP_DICT_HEADER.as_bytes());
+        Ok(self)
+    }
+}
+
+/// The dictionary a compressed frame names, if any
+fn dictionary_id(frame: &Frame) -> Result<Option<u32>, VstpError> {
+    frame
+        .get_header(COMP_DICT_HEADER)
+        .map(|id| {
+            id.parse().map_err(|_| {
+                VstpError::Protocol(format!("Invalid {} header: {}", COMP_DICT_HEADER, id))
+            })
+        })
+        .transpose()
+}
+
+/// Where a connection's compression setting stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionState {
+    /// Both peers agreed on this setting
+    Settled(bool),
+    /// Using `current` until the peer answers a proposal of `proposed`
+    Proposed { current: bool, proposed: bool },
+}
+
+impl CompressionState {
+    /// Whether outgoing payloads are compressed right now
+    pub fn enabled(&self) -> bool {
+        match *self {
+            CompressionState::Settled(enabled) => enabled,
+            CompressionState::Proposed { current, .. } => current,
+        }
+    }
+}
+
+/// Per-connection compression setting and the negotiation that changes it
+///
+/// Transport-independent: the connection runs outgoing frames through
+/// [`outgoing`](CompressionControl::outgoing), incoming ones through
+/// [`incoming`](CompressionControl::incoming), and sends whatever reply
+/// `incoming` hands back.
+#[derive(Debug)]
+pub struct CompressionControl {
+    state: CompressionState,
+    accept_compression: bool,
+    min_size: usize,
+    bytes_in: u64,
+    bytes_out: u64,
+    dictionaries: HashMap<u32, Dictionary>,
+    send_dictionary: Option<u32>,
+    header_table: HeaderTable,
+}
+
+impl Default for CompressionControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What [`CompressionControl::incoming`] made of a frame
+#[derive(Debug)]
+pub enum Incoming {
+    /// An application frame, decompressed if it was compressed
+    Frame(Frame),
+    /// A proposal from the peer; send this answer before anything else
+    Reply(Frame),
+    /// The peer's answer to our proposal, already applied
+    Settled,
+}
+
+impl CompressionControl {
+    /// Start uncompressed, accepting proposals to compress
+    pub fn new() -> Self {
+        Self {
+            state: CompressionState::Settled(false),
+            accept_compression: true,
+            min_size: DEFAULT_MIN_SIZE,
+            bytes_in: 0,
+            bytes_out: 0,
+            dictionaries: HashMap::new(),
+            send_dictionary: None,
+            header_table: HeaderTable::new(),
+        }
+    }
+
+    /// Answer proposals to compress with `off` instead of agreeing
+    pub fn refuse_compression(mut self) -> Self {
+        self.accept_compression = false;
+        self
+    }
+
+    /// Send payloads smaller than `min_size` uncompressed even when compression is on
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn state(&self) -> CompressionState {
+        self.state
+    }
+
+    /// Whether outgoing payloads are compressed right now
+    pub fn enabled(&self) -> bool {
+        self.state.enabled()
+    }
+
+    /// Compressed size over original size of the payloads compressed so far
+    ///
+    /// Close to (or above) 1.0 means the data doesn't compress and
+    /// compression is only costing CPU.
+    pub fn observed_ratio(&self) -> Option<f64> {
+        (self.bytes_in > 0).then(|| self.bytes_out as f64 / self.bytes_in as f64)
+    }
+
+    /// Hold `bytes` as dictionary `id`, replacing any dictionary with that id
+    ///
+    /// Incoming frames compressed against it can be read from now on.
+    pub fn register_dictionary(&mut self, id: u32, bytes: Vec<u8>) {
+        self.add_dictionary(Dictionary::new(id, bytes));
+    }
+
+    /// Hold an already built (e.g. trained) dictionary
+    pub fn add_dictionary(&mut self, dictionary: Dictionary) {
+        self.dictionaries.insert(dictionary.id, dictionary);
+    }
+
+    pub fn dictionary(&self, id: u32) -> Option<&Dictionary> {
+        self.dictionaries.get(&id)
+    }
+
+    /// Compress outgoing DATA payloads against dictionary `id`, or stop with `None`
+    ///
+    /// The peer must hold the same dictionary. While one is in use, payloads
+    /// of any size are compressed.
+    pub fn use_dictionary(&mut self, id: Option<u32>) -> Result<(), VstpError> {
+        if let Some(id) = id {
+            if !self.dictionaries.contains_key(&id) {
+                return Err(VstpError::UnknownDictionary(id));
+            }
+        }
+        self.send_dictionary = id;
+        Ok(())
+    }
+
+    /// The registered dictionaries as `capabilities` entries, e.g. `comp-dict:7`
+    pub fn dictionary_capabilities(&self) -> Vec<String> {
+        let mut ids: Vec<_> = self.dictionaries.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .map(|id| format!("{}{}", DICTIONARY_CAPABILITY_PREFIX, id))
+            .collect()
+    }
+
+    /// Header templates of this connection, see [`header_table`](crate::header_table)
+    pub fn header_table(&self) -> &HeaderTable {
+        &self.header_table
+    }
+
+    /// Header templates of this connection, e.g. to limit what the peer may set
+    pub fn header_table_mut(&mut self) -> &mut HeaderTable {
+        &mut self.header_table
+    }
+
+    /// Build a proposal to switch compression on or off
+    ///
+    /// Nothing changes until the peer answers. Returns `None` if the setting
+    /// is already `enabled` and no proposal is outstanding.
+    pub fn propose(&mut self, enabled: bool) -> Option<Frame> {
+        let current = self.enabled();
+        if self.state == CompressionState::Settled(enabled) {
+            return None;
+        }
+        self.state = CompressionState::Proposed {
+            current,
+            proposed: enabled,
+        };
+        Some(Frame::new(FrameType::Ping).with_header(COMPRESSION_REQUEST_HEADER, on_off(enabled)))
+    }
+
+    /// Compress an outgoing DATA frame if compression is on or a dictionary is in use
+    ///
+    /// Its headers are replaced by a template reference if the peer holds a
+    /// header table.
+    pub fn outgoing(&mut self, frame: Frame) -> Result<Frame, VstpError> {
+        let frame = self.header_table.outgoing(frame);
+        if frame.typ != FrameType::Data || frame.flags.is_compressed() {
+            return Ok(frame);
+        }
+        let dictionary = self
+            .send_dictionary
+            .and_then(|id| self.dictionaries.get(&id));
+        if dictionary.is_none() && (!self.enabled() || frame.payload.len() < self.min_size) {
+            return Ok(frame);
+        }
+        let original = frame.payload.len() as u64;
+        let compressed = match dictionary {
+            Some(dictionary) => frame.compress_with_dictionary(dictionary)?,
+            None => frame.compress()?,
+        };
+        self.bytes_in += original;
+        self.bytes_out += compressed.payload.len() as u64;
+        Ok(compressed)
+    }
+
+    /// Handle an incoming frame: decompress it or act on a negotiation message
+    ///
+    /// Headers sent as a template reference are restored in full.
+    pub fn incoming(&mut self, frame: Frame, max_size: usize) -> Result<Incoming, VstpError> {
+        if let Some(answer) = self.header_table.negotiate(&frame) {
+            return Ok(answer.map_or(Incoming::Settled, Incoming::Reply));
+        }
+        if frame.typ == FrameType::Ping {
+            if let Some(requested) = frame
+                .get_header(COMPRESSION_REQUEST_HEADER)
+                .and_then(parse_on_off)
+            {
+                return Ok(Incoming::Reply(self.answer(requested)));
+            }
+        }
+        if frame.typ == FrameType::Pong {
+            if let Some(answer) = frame.get_header(COMPRESSION_HEADER).and_then(parse_on_off) {
+                if let CompressionState::Proposed { current, proposed } = self.state {
+                    self.state = CompressionState::Settled(if answer == proposed {
+                        proposed
+                    } else {
+                        current
+                    });
+                }
+                return Ok(Incoming::Settled);
+            }
+        }
+        let frame = match dictionary_id(&frame)? {
+            Some(id) if frame.flags.is_compressed() => {
+                let dictionary = self
+                    .dictionaries
+                    .get(&id)
+                    .ok_or(VstpError::UnknownDictionary(id))?;
+                frame.decompress_with_dictionary(max_size, dictionary)?
+            }
+            _ => frame.decompress(max_size)?,
+        };
+        Ok(Incoming::Frame(self.header_table.incoming(frame)?))
+    }
+
+    fn answer(&mut self, requested: bool) -> Frame {
+        // Switching off is always accepted
+        let agreed = requested && self.accept_compression;
+        self.state = CompressionState::Settled(agreed);
+        Frame::new(FrameType::Pong).with_header(COMPRESSION_HEADER, on_off(agreed))
+    }
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn parse_on_off(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 1024 * 1024;
+
+    fn reply(incoming: Incoming) -> Frame {
+        match incoming {
+            Incoming::Reply(frame) => frame,
+            other => panic!("Expected a reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let frame = Frame::new(FrameType::Data).with_payload(vec![b'a'; 4096]);
+        let compressed = frame.clone().compress().unwrap();
+        assert!(compressed.flags.contains(Flags::COMP));
+        assert!(compressed.payload.len() < 100);
+        assert_eq!(compressed.decompress(MAX).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_decompress_enforces_limit() {
+        let bomb = Frame::new(FrameType::Data)
+            .with_payload(vec![0; 10_000])
+            .compress()
+            .unwrap();
+        assert!(matches!(
+            bomb.decompress(1000),
+            Err(VstpError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_negotiation_takes_effect_after_agreement() {
+        let mut a = CompressionControl::new();
+        let mut b = CompressionControl::new();
+
+        let proposal = a.propose(true).unwrap();
+        assert!(!a.enabled());
+        let answer = reply(b.incoming(proposal, MAX).unwrap());
+        assert!(b.enabled());
+        assert!(matches!(
+            a.incoming(answer, MAX).unwrap(),
+            Incoming::Settled
+        ));
+        assert_eq!(a.state(), CompressionState::Settled(true));
+
+        // A peer that refuses leaves the proposer where it was
+        let mut c = CompressionControl::new().refuse_compression();
+        let mut d = CompressionControl::new();
+        let answer = reply(c.incoming(d.propose(true).unwrap(), MAX).unwrap());
+        d.incoming(answer, MAX).unwrap();
+        assert!(!c.enabled() && !d.enabled());
+    }
+
+    /// Small JSON documents that differ only in their values
+    fn readings(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| {
+                format!(
+                    r#"{{"sensor":"greenhouse-{}","kind":"temperature","unit":"celsius","value":{}.{},"battery":{},"status":"ok","ts":{}}}"#,
+                    i % 17,
+                    15 + i % 13,
+                    i % 10,
+                    100 - i % 37,
+                    1_700_000_000 + i * 7
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dictionary_beats_per_frame_deflate() {
+        let corpus = readings(1200);
+        let (training, payloads) = corpus.split_at(1000);
+        let dictionary = Dictionary::train(7, training, 4096).unwrap();
+
+        let (mut original, mut deflated, mut with_dictionary) = (0, 0, 0);
+        for payload in payloads {
+            let frame = Frame::new(FrameType::Data).with_payload(payload.clone());
+            original += payload.len();
+            deflated += frame.clone().compress().unwrap().payload.len();
+
+            let compressed = frame.clone().compress_with_dictionary(&dictionary).unwrap();
+            assert_eq!(compressed.get_header(COMP_DICT_HEADER), Some("7"));
+            with_dictionary += compressed.payload.len();
+            assert_eq!(
+                compressed
+                    .decompress_with_dictionary(MAX, &dictionary)
+                    .unwrap(),
+                frame
+            );
+        }
+        assert!(
+            original > 4 * with_dictionary,
+            "{} vs {}",
+            original,
+            with_dictionary
+        );
+        assert!(
+            deflated > 3 * with_dictionary,
+            "{} vs {}",
+            deflated,
+            with_dictionary
+        );
+    }
+
+    #[test]
+    fn test_unknown_dictionary_is_rejected() {
+        let mut sender = CompressionControl::new();
+        sender.register_dictionary(3, b"shared content".to_vec());
+        sender.use_dictionary(Some(3)).unwrap();
+        let frame = sender
+            .outgoing(Frame::new(FrameType::Data).with_payload(b"shared content".to_vec()))
+            .unwrap();
+
+        let mut receiver = CompressionControl::new();
+        assert!(matches!(
+            receiver.incoming(frame.clone(), MAX),
+            Err(VstpError::UnknownDictionary(3))
+        ));
+        assert!(matches!(
+            receiver.use_dictionary(Some(3)),
+            Err(VstpError::UnknownDictionary(3))
+        ));
+
+        receiver.register_dictionary(3, b"shared content".to_vec());
+        match receiver.incoming(frame, MAX).unwrap() {
+            Incoming::Frame(frame) => assert_eq!(frame.payload, b"shared content"),
+            other => panic!("Expected a frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dictionaries_advertised_as_capabilities() {
+        let mut control = CompressionControl::new();
+        control.register_dictionary(12, vec![1, 2, 3]);
+        control.register_dictionary(4, vec![4, 5, 6]);
+        let capabilities = control.dictionary_capabilities();
+        assert_eq!(capabilities, ["comp-dict:4", "comp-dict:12"]);
+        let header = format!("health-echo,{}", capabilities.join(","));
+        assert_eq!(peer_dictionaries(&header), [4, 12]);
+    }
+
+    #[test]
+    fn test_outgoing_follows_setting_and_size() {
+        let mut control = CompressionControl::new().with_min_size(100);
+        let big = Frame::new(FrameType::Data).with_payload(vec![b'x'; 1000]);
+        let small = Frame::new(FrameType::Data).with_payload(vec![b'x'; 10]);
+        assert_eq!(control.outgoing(big.clone()).unwrap(), big);
+
+        control.state = CompressionState::Settled(true);
+        assert!(control.outgoing(big).unwrap().flags.contains(Flags::COMP));
+        assert_eq!(control.outgoing(small.clone()).unwrap(), small);
+        assert!(control.observed_ratio().unwrap() < 0.1);
+    }
+}
@@ -0,0 +1,615 @@
+//! Built-in connectivity diagnostics
+//!
+//! [`self_test`] walks through the layers of a VSTP connection one step at a
+//! time (socket, TLS if configured, handshake, keepalive, payload echo and, for
+//! UDP, fragmentation)
+//! so that "is it the network or the app?" can be answered from a single report.
+//!
+//! Servers opt into the echo steps by answering with [`health_reply`], which
+//! also produces the WELCOME and PONG replies the handshake and ping steps need.
+//!
+//! [`ident`] asks a server what it runs, from the identification headers of
+//! its WELCOME, and [`sniff_protocol`] names the protocol of a peer that
+//! answered with something other than VSTP.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+
+use crate::easy::{
+    check_handshake_reply, CAPABILITIES_HEADER, SERVER_SOFTWARE_HEADER, VSTP_VERSIONS_HEADER,
+};
+use crate::tcp::VstpTcpClient;
+use crate::types::{Frame, FrameType, VstpError};
+use crate::udp::VstpUdpClient;
+
+/// Capability advertised in the WELCOME `capabilities` header by servers that echo health probes
+pub const HEALTH_ECHO_CAPABILITY: &str = "health-echo";
+
+/// Header marking a DATA frame as a health echo probe
+pub const HEALTH_ECHO_HEADER: &str = "health-echo";
+
+/// Bytes of a foreign peer's first message shown in [`VstpError::NotVstp`]
+const SNIFF_PREFIX_LEN: usize = 16;
+
+/// Guess the protocol of a peer from the first bytes it sent
+///
+/// Recognizes HTTP requests and responses, TLS records (a client speaking
+/// TLS to a plaintext port, or a TLS server answering one) and SSH banners.
+pub fn sniff_protocol(bytes: &[u8]) -> Option<&'static str> {
+    const HTTP_METHODS: [&[u8]; 7] = [
+        b"GET ",
+        b"POST ",
+        b"PUT ",
+        b"HEAD ",
+        b"DELETE ",
+        b"OPTIONS ",
+        b"PATCH ",
+    ];
+    match bytes {
+        [b'H', b'T', b'T', b'P', b'/', ..] => Some("an HTTP response"),
+        _ if HTTP_METHODS.iter().any(|m| bytes.starts_with(m)) => Some("an HTTP request"),
+        [0x16, 0x03, _, _, _, 0x01, ..] => Some("a TLS ClientHello (the peer expects TLS)"),
+        [0x16, 0x03, ..] => Some("a TLS handshake (the peer expects TLS)"),
+        [0x15, 0x03, ..] => Some("a TLS alert (the peer expects TLS)"),
+        [b'S', b'S', b'H', b'-', ..] => Some("an SSH banner"),
+        _ => None,
+    }
+}
+
+/// [`VstpError::NotVstp`] for a peer whose first bytes were `bytes`
+pub(crate) fn not_vstp(bytes: &[u8]) -> VstpError {
+    let prefix = &bytes[..bytes.len().min(SNIFF_PREFIX_LEN)];
+    let hex: Vec<String> = prefix.iter().map(|b| format!("{:02x}", b)).collect();
+    let text: String = prefix
+        .iter()
+        .map(|&b| match b {
+            b' '..=b'~' => b as char,
+            _ => '.',
+        })
+        .collect();
+    VstpError::NotVstp {
+        looks_like: sniff_protocol(bytes),
+        received: format!("{}  |{}|", hex.join(" "), text),
+    }
+}
+
+/// What a server says about itself in its WELCOME
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerIdent {
+    /// Name and version of the server implementation, e.g. `vstp/0.2.0`
+    pub software: Option<String>,
+    /// Frame format versions the server speaks
+    pub versions: Vec<u8>,
+    /// Optional features the server supports
+    pub capabilities: Vec<String>,
+}
+
+impl ServerIdent {
+    pub fn from_welcome(welcome: &Frame) -> Self {
+        let list = |header| {
+            welcome
+                .get_header(header)
+                .into_iter()
+                .flat_map(|list| list.split(','))
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        Self {
+            software: welcome
+                .get_header(SERVER_SOFTWARE_HEADER)
+                .map(str::to_string),
+            versions: list(VSTP_VERSIONS_HEADER)
+                .iter()
+                .filter_map(|v| v.parse().ok())
+                .collect(),
+            capabilities: list(CAPABILITIES_HEADER),
+        }
+    }
+}
+
+impl fmt::Display for ServerIdent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let versions: Vec<String> = self.versions.iter().map(u8::to_string).collect();
+        let or_unknown = |items: &[String]| match items {
+            [] => "unknown".to_string(),
+            items => items.join(", "),
+        };
+        writeln!(
+            f,
+            "server-software: {}",
+            self.software.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(f, "vstp-versions:   {}", or_unknown(&versions))?;
+        write!(f, "capabilities:    {}", or_unknown(&self.capabilities))
+    }
+}
+
+/// Ask the TCP server at `addr` to identify itself
+///
+/// Sends a bare HELLO and reads the identification headers of the WELCOME.
+/// A server requiring auth turns the HELLO down and can't be identified;
+/// a peer that isn't a VSTP server yields [`VstpError::NotVstp`].
+pub async fn ident(addr: SocketAddr, timeout: Duration) -> Result<ServerIdent, VstpError> {
+    tokio::time::timeout(timeout, async {
+        let mut client = VstpTcpClient::connect(&addr.to_string()).await?;
+        client.send(Frame::new(FrameType::Hello)).await?;
+        loop {
+            match client.recv().await? {
+                Some(reply) if matches!(reply.typ, FrameType::Welcome | FrameType::Err) => {
+                    check_handshake_reply(&reply)?;
+                    return Ok(ServerIdent::from_welcome(&reply));
+                }
+                Some(_) => continue,
+                None => return Err(VstpError::ConnectionClosed),
+            }
+        }
+    })
+    .await
+    .map_err(|_| VstpError::Timeout)?
+}
+
+/// Target of a diagnostic run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+}
+
+impl Endpoint {
+    /// Address of the server under test
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Endpoint::Tcp(addr) | Endpoint::Udp(addr) => *addr,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Endpoint::Udp(addr) => write!(f, "udp://{}", addr),
+        }
+    }
+}
+
+/// Options controlling a diagnostic run
+#[derive(Debug, Clone)]
+pub struct DiagnosticOptions {
+    /// Timeout applied to each individual step
+    pub step_timeout: Duration,
+    /// Number of PING round trips to sample
+    pub ping_samples: usize,
+    /// Payload size of the echo step
+    pub echo_size: usize,
+    /// Payload sizes of the UDP fragmentation round trips
+    pub fragment_sizes: [usize; 2],
+    /// Run the `tls-handshake` step of a TCP test with this config; `None`
+    /// skips it and talks plain TCP
+    #[cfg(feature = "tls")]
+    pub tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+    /// Name to check the server's TLS certificate against; `None` uses the
+    /// target's IP address
+    #[cfg(feature = "tls")]
+    pub tls_server_name: Option<String>,
+}
+
+impl Default for DiagnosticOptions {
+    fn default() -> Self {
+        Self {
+            step_timeout: Duration::from_secs(5),
+            ping_samples: 5,
+            echo_size: 64 * 1024,
+            fragment_sizes: [4 * 1024, 32 * 1024],
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_server_name: None,
+        }
+    }
+}
+
+/// Outcome of a single diagnostic step
+#[derive(Debug)]
+pub enum StepStatus {
+    Passed,
+    Failed(VstpError),
+    Skipped(String),
+}
+
+/// Result of one diagnostic step
+#[derive(Debug)]
+pub struct DiagnosticStep {
+    pub name: String,
+    pub status: StepStatus,
+    pub elapsed: Duration,
+    pub detail: String,
+}
+
+impl DiagnosticStep {
+    /// Whether the step passed
+    pub fn passed(&self) -> bool {
+        matches!(self.status, StepStatus::Passed)
+    }
+}
+
+/// Minimum, average and maximum of the sampled PING round trips
+#[derive(Debug, Clone, Copy)]
+pub struct RttSummary {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+/// Full report of a diagnostic run
+#[derive(Debug)]
+pub struct DiagnosticReport {
+    pub endpoint: Endpoint,
+    pub steps: Vec<DiagnosticStep>,
+    /// Protocol version carried by the server's WELCOME
+    pub negotiated_version: Option<u8>,
+    /// Capabilities advertised by the server's WELCOME
+    pub capabilities: Vec<String>,
+    pub ping_rtt: Option<RttSummary>,
+}
+
+impl DiagnosticReport {
+    /// Whether no step failed (skipped steps don't count as failures)
+    pub fn passed(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| !matches!(step.status, StepStatus::Failed(_)))
+    }
+
+    /// Look up a step by name
+    pub fn step(&self, name: &str) -> Option<&DiagnosticStep> {
+        self.steps.iter().find(|step| step.name == name)
+    }
+
+    fn push(&mut self, name: &str, result: Result<String, VstpError>, start: Instant) {
+        let (status, detail) = match result {
+            Ok(detail) => (StepStatus::Passed, detail),
+            Err(e) => (StepStatus::Failed(e), String::new()),
+        };
+        self.steps.push(DiagnosticStep {
+            name: name.to_string(),
+            status,
+            elapsed: start.elapsed(),
+            detail,
+        });
+    }
+
+    fn skip(&mut self, name: &str, reason: &str) {
+        self.steps.push(DiagnosticStep {
+            name: name.to_string(),
+            status: StepStatus::Skipped(reason.to_string()),
+            elapsed: Duration::ZERO,
+            detail: String::new(),
+        });
+    }
+
+    fn skip_rest(&mut self, target: &Endpoint, reason: &str) {
+        let mut remaining = vec!["handshake", "ping", "echo"];
+        match target {
+            Endpoint::Tcp(_) => remaining.insert(0, "tls-handshake"),
+            Endpoint::Udp(_) => remaining.push("fragmentation"),
+        }
+        for name in remaining {
+            if self.step(name).is_none() {
+                self.skip(name, reason);
+            }
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "VSTP diagnostics for {}", self.endpoint)?;
+        for step in &self.steps {
+            let status = match &step.status {
+                StepStatus::Passed => "ok".to_string(),
+                StepStatus::Failed(e) => format!("FAILED: {}", e),
+                StepStatus::Skipped(reason) => format!("skipped: {}", reason),
+            };
+            write!(f, "  {:<20} {:>10.2?}  {}", step.name, step.elapsed, status)?;
+            if !step.detail.is_empty() {
+                write!(f, " ({})", step.detail)?;
+            }
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "result: {}",
+            if self.passed() { "all checks passed" } else { "some checks failed" }
+        )
+    }
+}
+
+/// Build the reply a server should send to a diagnostic frame, if any
+///
+/// HELLO is answered with a WELCOME advertising [`HEALTH_ECHO_CAPABILITY`],
+/// PING with a PONG carrying the same headers, and DATA frames marked with the
+/// [`HEALTH_ECHO_HEADER`] are echoed back unchanged.
+pub fn health_reply(frame: &Frame) -> Option<Frame> {
+    match frame.typ {
+        FrameType::Hello => Some(
+            Frame::new(FrameType::Welcome).with_header("capabilities", HEALTH_ECHO_CAPABILITY),
+        ),
+        FrameType::Ping => {
+            let mut pong = Frame::new(FrameType::Pong);
+            pong.headers = frame.headers.clone();
+            Some(pong)
+        }
+        FrameType::Data if frame.get_header(HEALTH_ECHO_HEADER).is_some() => {
+            let mut echo = Frame::new(FrameType::Data).with_payload(frame.payload.clone());
+            echo.headers = frame.headers.clone();
+            Some(echo)
+        }
+        _ => None,
+    }
+}
+
+enum Connection {
+    Tcp(Box<VstpTcpClient>),
+    Udp(Box<VstpUdpClient>, SocketAddr),
+}
+
+impl Connection {
+    /// Send `frame` and wait for the first reply of type `expect`
+    async fn exchange(
+        &mut self,
+        frame: Frame,
+        expect: FrameType,
+        step_timeout: Duration,
+    ) -> Result<Frame, VstpError> {
+        tokio::time::timeout(step_timeout, async {
+            match self {
+                Connection::Tcp(client) => {
+                    client.send(frame).await?;
+                    loop {
+                        let reply = client.recv().await?.ok_or(VstpError::ConnectionClosed)?;
+                        if let Some(reply) = check_reply(reply, expect)? {
+                            return Ok(reply);
+                        }
+                    }
+                }
+                Connection::Udp(client, dest) => {
+                    client.send(frame, *dest).await?;
+                    loop {
+                        let (reply, from) = client.recv().await?;
+                        if from != *dest {
+                            continue;
+                        }
+                        if let Some(reply) = check_reply(reply, expect)? {
+                            return Ok(reply);
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| VstpError::Timeout)?
+    }
+}
+
+/// Turn ERR replies into errors and skip frames of other types
+fn check_reply(reply: Frame, expect: FrameType) -> Result<Option<Frame>, VstpError> {
+    if reply.typ == FrameType::Err {
+        return Err(VstpError::ServerError(
+            String::from_utf8_lossy(&reply.payload).into_owned(),
+        ));
+    }
+    Ok((reply.typ == expect).then_some(reply))
+}
+
+/// Run the diagnostic sequence against `target`
+///
+/// Steps run in order and stop at the first failure that makes the rest
+/// meaningless (no socket, no handshake); later steps are then reported as
+/// skipped. The returned report never errors itself.
+pub async fn self_test(target: Endpoint, opts: DiagnosticOptions) -> DiagnosticReport {
+    let mut report = DiagnosticReport {
+        endpoint: target,
+        steps: Vec::new(),
+        negotiated_version: None,
+        capabilities: Vec::new(),
+        ping_rtt: None,
+    };
+
+    // Socket setup
+    let start = Instant::now();
+    let mut conn = match target {
+        Endpoint::Tcp(addr) => {
+            let connected = tokio::time::timeout(opts.step_timeout, TcpStream::connect(addr))
+                .await
+                .map_err(|_| VstpError::Timeout)
+                .and_then(|r| Ok(r?));
+            let socket = match connected {
+                Ok(socket) => {
+                    report.push("tcp-connect", Ok(String::new()), start);
+                    socket
+                }
+                Err(e) => {
+                    report.push("tcp-connect", Err(e), start);
+                    report.skip_rest(&target, "no connection");
+                    return report;
+                }
+            };
+            match secure(socket, &target, &opts, &mut report).await {
+                Some(client) => Connection::Tcp(Box::new(client)),
+                None => {
+                    report.skip_rest(&target, "TLS handshake failed");
+                    return report;
+                }
+            }
+        }
+        Endpoint::Udp(addr) => {
+            let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            match VstpUdpClient::bind(local).await {
+                Ok(client) => {
+                    report.push("udp-bind", Ok(String::new()), start);
+                    Connection::Udp(Box::new(client), addr)
+                }
+                Err(e) => {
+                    report.push("udp-bind", Err(e), start);
+                    report.skip_rest(&target, "no connection");
+                    return report;
+                }
+            }
+        }
+    };
+
+    // HELLO / WELCOME
+    let start = Instant::now();
+    match conn
+        .exchange(Frame::new(FrameType::Hello), FrameType::Welcome, opts.step_timeout)
+        .await
+    {
+        Ok(welcome) => {
+            report.negotiated_version = Some(welcome.version);
+            report.capabilities = welcome
+                .get_header("capabilities")
+                .map(|caps| caps.split(',').map(|c| c.trim().to_string()).collect())
+                .unwrap_or_default();
+            let detail = format!(
+                "version {}, capabilities [{}]",
+                welcome.version,
+                report.capabilities.join(", ")
+            );
+            report.push("handshake", Ok(detail), start);
+        }
+        Err(e) => {
+            report.push("handshake", Err(e), start);
+            report.skip_rest(&target, "handshake failed");
+            return report;
+        }
+    }
+
+    // PING RTT samples
+    let start = Instant::now();
+    let mut samples = Vec::with_capacity(opts.ping_samples);
+    let mut ping_error = None;
+    for seq in 0..opts.ping_samples.max(1) {
+        let ping = Frame::new(FrameType::Ping).with_header("ping-seq", &seq.to_string());
+        let sent_at = Instant::now();
+        match conn.exchange(ping, FrameType::Pong, opts.step_timeout).await {
+            Ok(_) => samples.push(sent_at.elapsed()),
+            Err(e) => {
+                ping_error = Some(e);
+                break;
+            }
+        }
+    }
+    match ping_error {
+        Some(e) => report.push("ping", Err(e), start),
+        None => {
+            let summary = RttSummary {
+                min: samples.iter().copied().min().unwrap_or_default(),
+                avg: samples.iter().sum::<Duration>() / samples.len() as u32,
+                max: samples.iter().copied().max().unwrap_or_default(),
+            };
+            let detail = format!(
+                "n={} min={:.2?} avg={:.2?} max={:.2?}",
+                samples.len(),
+                summary.min,
+                summary.avg,
+                summary.max
+            );
+            report.ping_rtt = Some(summary);
+            report.push("ping", Ok(detail), start);
+        }
+    }
+
+    // Payload echo
+    if report.capabilities.iter().any(|c| c == HEALTH_ECHO_CAPABILITY) {
+        let start = Instant::now();
+        let result = echo(&mut conn, opts.echo_size, opts.step_timeout).await;
+        report.push("echo", result, start);
+    } else {
+        report.skip("echo", "server does not advertise health-echo");
+    }
+
+    // Fragmentation round trips
+    if let Endpoint::Udp(_) = target {
+        for size in opts.fragment_sizes {
+            let name = format!("fragmentation-{}", size);
+            if report.capabilities.iter().any(|c| c == HEALTH_ECHO_CAPABILITY) {
+                let start = Instant::now();
+                let result = echo(&mut conn, size, opts.step_timeout).await;
+                report.push(&name, result, start);
+            } else {
+                report.skip(&name, "server does not advertise health-echo");
+            }
+        }
+    }
+
+    report
+}
+
+/// Run the `tls-handshake` step on `socket` if `opts` configure TLS,
+/// returning the client to go on with unless the handshake failed
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn secure(
+    socket: TcpStream,
+    target: &Endpoint,
+    opts: &DiagnosticOptions,
+    report: &mut DiagnosticReport,
+) -> Option<VstpTcpClient> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &opts.tls {
+        let start = Instant::now();
+        let addr = target.addr().to_string();
+        let handshake = async {
+            let server_name = match &opts.tls_server_name {
+                Some(name) => rustls::pki_types::ServerName::try_from(name.clone())
+                    .map_err(|e| VstpError::Tls(e.to_string()))?,
+                None => crate::tcp::tls::server_name(&addr)?,
+            };
+            tokio::time::timeout(
+                opts.step_timeout,
+                VstpTcpClient::tls_over(socket, &addr, tls.clone(), server_name, false),
+            )
+            .await
+            .map_err(|_| VstpError::Timeout)?
+        };
+        return match handshake.await {
+            Ok(client) => {
+                let detail = if client.tls_resumed() { "resumed" } else { "full" };
+                report.push("tls-handshake", Ok(detail.to_string()), start);
+                Some(client)
+            }
+            Err(e) => {
+                report.push("tls-handshake", Err(e), start);
+                None
+            }
+        };
+    }
+    report.skip("tls-handshake", "TLS not configured");
+    Some(VstpTcpClient::plain(socket))
+}
+
+/// Echo a patterned payload of `size` bytes and verify it comes back intact
+async fn echo(
+    conn: &mut Connection,
+    size: usize,
+    step_timeout: Duration,
+) -> Result<String, VstpError> {
+    let payload: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    let probe = Frame::new(FrameType::Data)
+        .with_header(HEALTH_ECHO_HEADER, "1")
+        .with_payload(payload.clone());
+    let reply = conn.exchange(probe, FrameType::Data, step_timeout).await?;
+    if reply.payload != payload {
+        return Err(VstpError::Protocol(format!(
+            "Echo mismatch: sent {} bytes, got {} bytes back",
+            payload.len(),
+            reply.payload.len()
+        )));
+    }
+    Ok(format!("{} bytes", size))
+}
@@ -0,0 +1,258 @@
+//! Payloads sealed end to end, behind the `e2e` feature
+//!
+//! TLS protects a frame from one hop to the next and ends at each of them,
+//! so a relay between client and server, e.g. a proxy or a UDP to TCP
+//! bridge, reads every payload it forwards. A sealed frame is encrypted by
+//! its sender to the X25519 public key of the final recipient instead, and
+//! only that recipient can open it:
+//!
+//! - [`Frame::seal_for`] replaces the payload with its ciphertext, using a
+//!   fresh ephemeral key pair per frame. The shared secret goes through
+//!   HKDF-SHA256 into a ChaCha20-Poly1305 key. The frame carries an
+//!   [`E2E_HEADER`] of `v1`, with the ephemeral public key and the nonce in
+//!   [`E2E_KEY_HEADER`] and [`E2E_NONCE_HEADER`].
+//! - [`Frame::seal_headers_for`] moves the headers named into the sealed body
+//!   as well, and [`Frame::open`] puts them back.
+//! - [`Frame::open`] decrypts with the recipient's [`StaticSecret`], failing
+//!   with [`VstpError::Unsealing`] for the wrong key or a frame whose
+//!   payload, type or e2e headers were changed on the way.
+//!
+//! Every other header stays readable, so intermediaries route on them and
+//! forward sealed frames untouched, without ever holding a key. They may add
+//! headers of their own too; those aren't covered by the seal.
+//!
+//! How recipients' public keys are handed out is up to the application. The
+//! easy API has [`VstpClient::send_sealed`] and
+//! [`VstpClient::receive_opened`], and servers given a
+//! [`ServerOptions::e2e_key`] open sealed requests before the handler runs.
+//! Replies go out as handlers return them.
+//!
+//! ```
+//! use vstp::e2e::KeyPair;
+//! use vstp::{Frame, FrameType};
+//!
+//! let recipient = KeyPair::generate();
+//! let sealed = Frame::new(FrameType::Data)
+//!     .with_payload(b"for your eyes only".to_vec())
+//!     .seal_for(&recipient.public_key());
+//! assert_ne!(sealed.payload, b"for your eyes only");
+//!
+//! let opened = sealed.open(recipient.secret()).unwrap();
+//! assert_eq!(opened.payload, b"for your eyes only");
+//! ```
+//!
+//! [`VstpClient::send_sealed`]: crate::easy::VstpClient::send_sealed
+//! [`VstpClient::receive_opened`]: crate::easy::VstpClient::receive_opened
+//! [`ServerOptions::e2e_key`]: crate::easy::ServerOptions::e2e_key
+
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::types::{Frame, Header, VstpError};
+
+/// Header marking a sealed frame, with the seal's version as its value
+pub const E2E_HEADER: &str = "e2e";
+
+/// Header carrying the 32-byte ephemeral public key a frame was sealed with
+pub const E2E_KEY_HEADER: &str = "e2e-key";
+
+/// Header carrying the 12-byte nonce a frame was sealed with
+pub const E2E_NONCE_HEADER: &str = "e2e-nonce";
+
+/// The only seal version so far
+const VERSION: &str = "v1";
+
+/// HKDF info string binding derived keys to this use
+const KDF_INFO: &[u8] = b"vstp e2e v1";
+
+/// A recipient's X25519 key pair
+///
+/// Senders seal frames to the [`public_key`](KeyPair::public_key); the
+/// [`secret`](KeyPair::secret) opens them. Debug output shows the public
+/// key only.
+#[derive(Clone)]
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    /// A fresh key pair from the operating system's random number generator
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// The key pair of an existing `secret`
+    pub fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn secret(&self) -> &StaticSecret {
+        &self.secret
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Frame {
+    /// Seal the payload so that only the holder of `recipient`'s secret can
+    /// read it, see [`e2e`](crate::e2e)
+    pub fn seal_for(self, recipient: &PublicKey) -> Frame {
+        self.seal_headers_for(recipient, &[])
+    }
+
+    /// Seal the payload along with the headers named in `headers`, which
+    /// leave the frame until it is [opened](Frame::open)
+    pub fn seal_headers_for(mut self, recipient: &PublicKey, headers: &[&str]) -> Frame {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let nonce: [u8; 12] = rand::random();
+
+        let (sealed, kept) = self
+            .take_headers()
+            .into_iter()
+            .partition(|h| headers.iter().any(|name| h.key == name.as_bytes()));
+        self.headers = kept;
+        self.headers.push(Header::from_str(E2E_HEADER, VERSION));
+        self.headers.push(Header::new(
+            E2E_KEY_HEADER.as_bytes().to_vec(),
+            ephemeral_public.as_bytes().to_vec(),
+        ));
+        self.headers
+            .push(Header::new(E2E_NONCE_HEADER.as_bytes().to_vec(), nonce.to_vec()));
+
+        let body = sealed_body(&sealed, &self.payload);
+        let cipher = cipher(&ephemeral, recipient, &ephemeral_public, recipient);
+        let aad = associated_data(&self, ephemeral_public.as_bytes(), &nonce);
+        self.payload = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &body, aad: &aad })
+            .expect("ChaCha20-Poly1305 seals any payload that fits in memory");
+        self
+    }
+
+    /// Whether the frame carries a payload sealed with [`seal_for`](Frame::seal_for)
+    pub fn is_sealed(&self) -> bool {
+        self.get_header(E2E_HEADER).is_some()
+    }
+
+    /// Open a frame sealed to the public half of `with`, restoring its
+    /// payload and the headers sealed along with it
+    ///
+    /// Fails with [`VstpError::Unsealing`] if the frame isn't sealed, was
+    /// sealed to another key, or was changed after sealing.
+    pub fn open(mut self, with: &StaticSecret) -> Result<Frame, VstpError> {
+        match self.get_header(E2E_HEADER) {
+            Some(VERSION) => {}
+            Some(other) => {
+                return Err(VstpError::Unsealing(format!("unknown seal version {}", other)))
+            }
+            None => return Err(VstpError::Unsealing("frame isn't sealed".to_string())),
+        }
+        let ephemeral_public: [u8; 32] = self
+            .get_binary_header(E2E_KEY_HEADER)
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| VstpError::Unsealing("missing ephemeral key".to_string()))?;
+        let nonce: [u8; 12] = self
+            .get_binary_header(E2E_NONCE_HEADER)
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or_else(|| VstpError::Unsealing("missing nonce".to_string()))?;
+
+        let ephemeral_public = PublicKey::from(ephemeral_public);
+        let recipient = PublicKey::from(with);
+        let cipher = cipher(with, &ephemeral_public, &ephemeral_public, &recipient);
+        let aad = associated_data(&self, ephemeral_public.as_bytes(), &nonce);
+        let body = cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &self.payload, aad: &aad })
+            .map_err(|_| VstpError::Unsealing("wrong key or altered frame".to_string()))?;
+
+        let (headers, payload) = split_body(&body)
+            .ok_or_else(|| VstpError::Unsealing("malformed sealed body".to_string()))?;
+        self.headers.retain(|h| {
+            ![E2E_HEADER, E2E_KEY_HEADER, E2E_NONCE_HEADER]
+                .iter()
+                .any(|k| h.key == k.as_bytes())
+        });
+        self.headers.extend(headers);
+        self.payload = payload.to_vec();
+        Ok(self)
+    }
+}
+
+/// Cipher keyed by the shared secret of `secret` and `peer`, salted with both
+/// public keys so that a key is never derived twice for different parties
+fn cipher(
+    secret: &StaticSecret,
+    peer: &PublicKey,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> ChaCha20Poly1305 {
+    let shared = secret.diffie_hellman(peer);
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(KDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// What the seal covers besides the body: the frame type and the e2e headers
+fn associated_data(frame: &Frame, ephemeral: &[u8; 32], nonce: &[u8; 12]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + VERSION.len() + 32 + 12);
+    aad.push(frame.typ.to_u8());
+    aad.extend_from_slice(VERSION.as_bytes());
+    aad.extend_from_slice(ephemeral);
+    aad.extend_from_slice(nonce);
+    aad
+}
+
+/// `[COUNT (u16)] ([KEY_LEN (u32)] [VALUE_LEN (u32)] [KEY] [VALUE])* [PAYLOAD]`
+fn sealed_body(headers: &[Header], payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(headers.len() as u16).to_be_bytes());
+    for header in headers {
+        body.extend_from_slice(&(header.key.len() as u32).to_be_bytes());
+        body.extend_from_slice(&(header.value.len() as u32).to_be_bytes());
+        body.extend_from_slice(&header.key);
+        body.extend_from_slice(&header.value);
+    }
+    body.extend_from_slice(payload);
+    body
+}
+
+/// The headers and payload of a [`sealed_body`]
+fn split_body(body: &[u8]) -> Option<(Vec<Header>, &[u8])> {
+    let (count, mut rest) = body.split_first_chunk::<2>()?;
+    let mut headers = Vec::new();
+    for _ in 0..u16::from_be_bytes(*count) {
+        let (key_len, after) = rest.split_first_chunk::<4>()?;
+        let (value_len, after) = after.split_first_chunk::<4>()?;
+        let key_len = u32::from_be_bytes(*key_len) as usize;
+        let value_len = u32::from_be_bytes(*value_len) as usize;
+        if after.len() < key_len.checked_add(value_len)? {
+            return None;
+        }
+        let (key, after) = after.split_at(key_len);
+        let (value, after) = after.split_at(value_len);
+        headers.push(Header::new(key.to_vec(), value.to_vec()));
+        rest = after;
+    }
+    Some((headers, rest))
+}
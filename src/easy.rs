@@ -1,13 +1,44 @@
+use crate::chunk::{
+    CHUNK_HASH_HEADER, FIN_HEADER, PAYLOAD_HASH_HEADER, SEQ_HEADER, STREAM_ID_HEADER, TOTAL_HEADER,
+};
+use crate::clock::{plain_ping_sent_ms, stamp_server_time, ClockSync};
+use crate::flow::WindowCredit;
+use crate::idempotency::{IdempotencyStore, SingleFlight};
+use crate::meta::FrameMeta;
+use crate::migration::{
+    format_session_id, parse_session_id, SessionExtensions, SessionRegistry, REDIRECT_TO_HEADER,
+    SESSION_ID_HEADER,
+};
+use crate::pubsub::{
+    Delivery, PublishRequest, Published, SubscribeRequest, PUBLISH_METHOD, SUBSCRIBE_METHOD,
+};
+use crate::router::{Router, CALL_ID_HEADER, METHOD_HEADER, STREAM_CANCEL_HEADER, STREAM_END_HEADER};
+use crate::schema::{self, VstpMessage, SCHEMA_VERSION_HEADER};
+use crate::shaping::SendShaper;
+use crate::tcp::quota::{QuotaRemaining, QuotaTracker, SessionQuota};
+use crate::tcp::server::farewell;
+use crate::types::{DisconnectReason, ErrorCode, DEFAULT_MAX_HEADERS, VSTP_VERSION, VSTP_VERSION_2};
+use crate::usage::{Meter, Quota, UsageRecorder, ANONYMOUS};
 use crate::{Flags, Frame, FrameType, VstpError};
+pub use crate::types::ERROR_CODE_HEADER;
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, Stream, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime};
 use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 const DEFAULT_PREFERRED_MARGIN_MS: f64 = 5.0;
 const DEFAULT_PEER_PREF_TTL: Duration = Duration::from_secs(120);
+/// Redirects one call follows before giving up, so two instances pointing
+/// at each other can't keep it going forever
+const MAX_REDIRECTS: usize = 3;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum TransportKind {
@@ -144,18 +175,668 @@ impl AutoClientState {
     }
 }
 
+/// How often a UDP client resends its HELLO while waiting for a reply
+const UDP_HELLO_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Options for the handshake performed by [`VstpClient::connect_tcp_with_options`]
+/// and [`VstpClient::connect_udp_with_options`]
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Token to present to servers that require one
+    pub auth_token: Option<String>,
+    /// How long to wait for the TCP connection to be established;
+    /// [`VstpError::ConnectTimeout`] if it isn't
+    pub connect_timeout: Duration,
+    /// How long to wait for the server's WELCOME once connected;
+    /// [`VstpError::HandshakeTimeout`] if it doesn't come
+    pub handshake_timeout: Duration,
+    /// Re-estimate the server's clock this often with a PING; the PONG is
+    /// picked up by [`VstpClient::receive`]
+    pub clock_sync_interval: Option<Duration>,
+    /// Highest frame format version to offer; TCP sessions switch to the
+    /// version the server picks once the WELCOME arrives
+    pub max_frame_version: u8,
+    /// Service to ask the server for, see [`VstpServer::service`]
+    pub service: Option<String>,
+    /// Response format to ask for with the HELLO's `content-type`, which
+    /// handlers see as [`NegotiatedParams::format`]
+    pub format: Option<String>,
+    /// Whether requests wait out a pause the server asks for, see
+    /// [`VstpClient::events`]; when false they fail with [`VstpError::Backoff`]
+    pub wait_out_backoff: bool,
+    /// Cap on the bytes per second the client sends, handshake included;
+    /// see [`shaping`](crate::shaping)
+    pub max_send_bps: Option<u64>,
+    /// When a TCP client finds its connection gone, connect and handshake
+    /// again once and retry, see [`VstpClient::reconnect`]
+    ///
+    /// UDP has no connection to lose, so
+    /// [`connect_udp_with_options`](VstpClient::connect_udp_with_options)
+    /// refuses options asking for this.
+    pub auto_reconnect: bool,
+    /// Other servers a TCP client may use, in order of preference after
+    /// the address it is connected with
+    ///
+    /// Connecting and reconnecting go down the list from the preferred
+    /// server until one completes the handshake. A server that answers
+    /// ERR `ShuttingDown` with a `retry-after-ms` is left for the next one
+    /// at once, instead of waiting out its pause; see [`VstpClient::events`].
+    pub fallback_addrs: Vec<String>,
+    /// Most headers a frame sent by the client may carry; frames with more
+    /// fail with [`VstpError::TooManyHeaders`] before anything is sent
+    pub max_headers: usize,
+    /// Give each [`VstpClient::call`] of a TCP client a fresh
+    /// [`IDEMPOTENCY_KEY_HEADER`], so that with `auto_reconnect` a call
+    /// whose reply is lost with the connection can be sent again; see
+    /// [`idempotency`](crate::idempotency)
+    pub idempotency_keys: bool,
+    /// Trace [`VstpClient::call`]s in OpenTelemetry client spans and send
+    /// their trace context along, see [`otel`](crate::otel)
+    #[cfg(feature = "otel")]
+    pub propagate_trace_context: bool,
+    /// Connect over TLS with this config, see [`tls`](crate::tcp::tls);
+    /// `None` for plain TCP. UDP clients ignore it.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    /// Name to check the server's TLS certificate against; `None` uses the
+    /// host part of the address
+    #[cfg(feature = "tls")]
+    pub tls_server_name: Option<String>,
+    /// How long the TLS handshake may take once the TCP connection is up;
+    /// [`VstpError::TlsHandshakeTimeout`] if it doesn't complete
+    #[cfg(feature = "tls")]
+    pub tls_handshake_timeout: Duration,
+    /// Send the HELLO as TLS early data when resuming a session, saving the
+    /// handshake's round trip; see [early data](crate::tcp::tls#early-data)
+    ///
+    /// Whoever records the HELLO can then replay it, auth token included.
+    /// Off by default.
+    #[cfg(feature = "tls")]
+    pub allow_early_data: bool,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            auth_token: None,
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+            clock_sync_interval: None,
+            max_frame_version: VSTP_VERSION_2,
+            service: None,
+            format: None,
+            wait_out_backoff: true,
+            max_send_bps: None,
+            auto_reconnect: false,
+            fallback_addrs: Vec::new(),
+            max_headers: DEFAULT_MAX_HEADERS,
+            idempotency_keys: false,
+            #[cfg(feature = "otel")]
+            propagate_trace_context: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_server_name: None,
+            #[cfg(feature = "tls")]
+            tls_handshake_timeout: Duration::from_secs(5),
+            #[cfg(feature = "tls")]
+            allow_early_data: false,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Connect over TLS with `config`
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: impl Into<Arc<rustls::ClientConfig>>) -> Self {
+        self.tls = Some(config.into());
+        self
+    }
+
+    /// Connect to `addr` and run the TLS handshake if there is one, each
+    /// within its own timeout
+    async fn connect_tcp(&self, addr: &str) -> Result<crate::tcp::VstpTcpClient, VstpError> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let name = self.tls_server_name.as_deref().unwrap_or(addr);
+            let server_name = crate::tcp::tls::server_name(name)?;
+            let socket = self
+                .bounded_connect(tokio::net::TcpStream::connect(addr))
+                .await?;
+            let handshake = crate::tcp::VstpTcpClient::tls_over(
+                socket,
+                addr,
+                tls.clone(),
+                server_name,
+                self.allow_early_data,
+            );
+            return tokio::time::timeout(self.tls_handshake_timeout, handshake)
+                .await
+                .map_err(|_| VstpError::TlsHandshakeTimeout {
+                    after: self.tls_handshake_timeout,
+                })?;
+        }
+        self.bounded_connect(crate::tcp::VstpTcpClient::connect(addr))
+            .await
+    }
+
+    /// Run `connect` within `connect_timeout`
+    async fn bounded_connect<T, E: Into<VstpError>>(
+        &self,
+        connect: impl std::future::Future<Output = Result<T, E>>,
+    ) -> Result<T, VstpError> {
+        match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(connected) => connected.map_err(Into::into),
+            Err(_) => Err(VstpError::ConnectTimeout {
+                after: self.connect_timeout,
+            }),
+        }
+    }
+
+    fn hello(&self) -> Frame {
+        let versions: Vec<String> = (VSTP_VERSION..=self.max_frame_version)
+            .map(|v| v.to_string())
+            .collect();
+        let mut hello = Frame::new(FrameType::Hello)
+            .with_header(PROTOCOL_VERSION_HEADER, &VSTP_VERSION.to_string())
+            .with_header(SUPPORTED_VERSIONS_HEADER, &versions.join(","));
+        if let Some(service) = &self.service {
+            hello = hello.with_header(SERVICE_HEADER, service);
+        }
+        if let Some(format) = &self.format {
+            hello = hello.with_header("content-type", format);
+        }
+        match &self.auth_token {
+            Some(token) => hello.with_header(AUTH_TOKEN_HEADER, token),
+            None => hello,
+        }
+    }
+}
+
+fn is_handshake_reply(frame: &Frame) -> bool {
+    matches!(frame.typ, FrameType::Welcome | FrameType::Err)
+}
+
+/// Turn an ERR reply to a HELLO into [`VstpError::HandshakeRejected`]
+pub(crate) fn check_handshake_reply(reply: &Frame) -> Result<(), VstpError> {
+    if reply.typ == FrameType::Welcome {
+        return Ok(());
+    }
+    let code = reply.get_header(ERROR_CODE_HEADER).unwrap_or("").to_string();
+    let message = String::from_utf8_lossy(&reply.payload).into_owned();
+    Err(VstpError::HandshakeRejected { code, message })
+}
+
+/// Connect to `addr` and handshake with `hello`, as `options` say
+async fn tcp_handshake(
+    addr: &str,
+    hello: Frame,
+    options: &ConnectOptions,
+    clock: &ClockSync,
+    send_shaper: Option<Arc<SendShaper>>,
+) -> Result<(crate::tcp::VstpTcpClient, Negotiated, Frame), VstpError> {
+    let mut client = options.connect_tcp(addr).await?;
+    client.set_send_shaper(send_shaper);
+
+    let sent_at = clock.local_now();
+    let reply = tokio::time::timeout(options.handshake_timeout, async {
+        client.send(hello).await?;
+        loop {
+            match client.recv().await? {
+                Some(frame) if is_handshake_reply(&frame) => return Ok(frame),
+                Some(_) => continue,
+                None => return Err(VstpError::ConnectionClosed),
+            }
+        }
+    })
+    .await
+    .map_err(|_| VstpError::HandshakeTimeout {
+        after: options.handshake_timeout,
+    })??;
+    check_handshake_reply(&reply)?;
+    clock.record_reply(sent_at, &reply);
+    let version = negotiated_version(&reply);
+    if !(VSTP_VERSION..=options.max_frame_version).contains(&version) {
+        return Err(VstpError::InvalidVersion {
+            expected: options.max_frame_version,
+            got: version,
+        });
+    }
+    client.set_frame_version(version);
+    let negotiated = Negotiated {
+        limits: PeerLimits::from_welcome(&reply),
+        frame_version: version,
+        tls_resumed: client.tls_resumed(),
+    };
+    Ok((client, negotiated, reply))
+}
+
+/// Send `frame` and, if `ack`, wait for the server to acknowledge it
+async fn tcp_send(
+    client: &mut crate::tcp::VstpTcpClient,
+    frame: Frame,
+    ack: bool,
+) -> Result<(), VstpError> {
+    client.send(frame).await?;
+    if !ack {
+        return Ok(());
+    }
+    let reply = client.recv().await?.ok_or(VstpError::ConnectionClosed)?;
+    if reply.frame_type() != FrameType::Ack {
+        return Err(VstpError::Protocol("Expected ACK frame".to_string()));
+    }
+    Ok(())
+}
+
+/// Whether `error` means the connection is gone
+fn connection_lost(error: &VstpError) -> bool {
+    matches!(error, VstpError::Io(_) | VstpError::ConnectionClosed)
+}
+
+/// Message limits a server announced in its WELCOME
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerLimits {
+    /// Largest DATA payload the server accepts in one frame; `None` if it didn't say
+    pub max_message_bytes: Option<usize>,
+    /// Whether the server reassembles messages split with [`Frame::chunk_payload`]
+    pub fragmentation: bool,
+}
+
+impl PeerLimits {
+    fn from_welcome(welcome: &Frame) -> Self {
+        Self {
+            max_message_bytes: welcome
+                .get_header(MAX_MESSAGE_BYTES_HEADER)
+                .and_then(|v| v.parse().ok()),
+            fragmentation: welcome
+                .get_header(CAPABILITIES_HEADER)
+                .into_iter()
+                .flat_map(|list| list.split(','))
+                .any(|c| c.trim() == FRAGMENTATION_CAPABILITY),
+        }
+    }
+
+    /// The frames to send for `frame`: itself if it fits, otherwise its chunks
+    ///
+    /// Chunks carry every header and flag of `frame`.
+    fn fit(&self, frame: Frame) -> Result<Vec<Frame>, VstpError> {
+        let limit = match self.max_message_bytes {
+            Some(limit) if frame.payload.len() > limit => limit,
+            _ => return Ok(vec![frame]),
+        };
+        if !self.fragmentation || limit == 0 {
+            return Err(VstpError::TooLargeForPeer {
+                size: frame.payload.len(),
+                limit,
+            });
+        }
+        Ok(Frame::chunk_payload(&frame.payload, limit)
+            .into_iter()
+            .map(|mut chunk| {
+                chunk.flags = frame.flags;
+                chunk.headers.extend(frame.headers.iter().cloned());
+                chunk
+            })
+            .collect())
+    }
+}
+
+/// Frame format version a WELCOME settles on
+///
+/// Servers that predate version negotiation leave it at version 1.
+fn negotiated_version(welcome: &Frame) -> u8 {
+    welcome
+        .get_header(PROTOCOL_VERSION_HEADER)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(VSTP_VERSION)
+}
+
+/// Something that happened to a [`VstpClient`], see [`VstpClient::events`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// The server answered with `code` and asked for no new requests for `retry_after`
+    BackingOff {
+        code: ErrorCode,
+        retry_after: Duration,
+    },
+    /// The pause is over and requests go out again
+    Resumed,
+    /// The client reconnected and the new session settled on other limits
+    /// or another frame version than the last one
+    CapabilitiesChanged {
+        limits: PeerLimits,
+        frame_version: u8,
+    },
+}
+
+/// Pause on new requests asked for by a draining or rate limited server
+#[derive(Debug)]
+struct Backoff {
+    until: std::sync::Mutex<Option<tokio::time::Instant>>,
+    resumed: Notify,
+    wait: bool,
+    events: broadcast::Sender<ClientEvent>,
+}
+
+impl Backoff {
+    fn new(wait: bool) -> Self {
+        Self {
+            until: std::sync::Mutex::new(None),
+            resumed: Notify::new(),
+            wait,
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    /// Start a pause if `reply` is a `ShuttingDown` or `RateLimited` ERR with
+    /// a `retry-after-ms`, returning when it ends
+    fn note(&self, reply: &Frame) -> Option<tokio::time::Instant> {
+        let code = reply.error_code()?;
+        if !matches!(code, ErrorCode::ShuttingDown | ErrorCode::RateLimited) {
+            return None;
+        }
+        let retry_after = reply.retry_after()?;
+        let until = tokio::time::Instant::now() + retry_after;
+        let until = {
+            let mut pause = self.until.lock().unwrap();
+            let until = pause.map_or(until, |current| current.max(until));
+            *pause = Some(until);
+            until
+        };
+        let _ = self
+            .events
+            .send(ClientEvent::BackingOff { code, retry_after });
+        Some(until)
+    }
+
+    /// Wait for the pause to end, or fail with [`VstpError::Backoff`] if the
+    /// client doesn't wait
+    async fn ready(&self) -> Result<(), VstpError> {
+        loop {
+            let resumed = self.resumed.notified();
+            tokio::pin!(resumed);
+            resumed.as_mut().enable();
+
+            let Some(until) = *self.until.lock().unwrap() else {
+                return Ok(());
+            };
+            if until <= tokio::time::Instant::now() {
+                self.end(until);
+                return Ok(());
+            }
+            if !self.wait {
+                return Err(VstpError::Backoff { until });
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(until) => {}
+                _ = resumed => {}
+            }
+        }
+    }
+
+    /// End the pause, if it still ends at `until`
+    fn end(&self, until: tokio::time::Instant) {
+        let mut pause = self.until.lock().unwrap();
+        if *pause == Some(until) {
+            *pause = None;
+            let _ = self.events.send(ClientEvent::Resumed);
+        }
+    }
+
+    fn resume(&self) {
+        let until = *self.until.lock().unwrap();
+        if let Some(until) = until {
+            self.end(until);
+        }
+        self.resumed.notify_waiters();
+    }
+}
+
+/// Whether a client still takes operations, shared by its clones
+#[derive(Debug, Default)]
+struct Lifecycle {
+    /// Set once [`VstpClient::shutdown`] starts; new operations are refused
+    closing: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    /// Cancelled when the operations still running are given up on
+    closed: CancellationToken,
+}
+
+impl Lifecycle {
+    /// Count an operation in, unless the client is shutting down
+    fn enter(&self) -> Result<InFlight<'_>, VstpError> {
+        if self.closing.load(Ordering::Acquire) {
+            return Err(VstpError::Closed);
+        }
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        Ok(InFlight(self))
+    }
+
+    /// Wait until no operation is running
+    async fn drained(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.in_flight.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// An operation counted by [`Lifecycle::enter`], counted out on drop
+struct InFlight<'a>(&'a Lifecycle);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Send `frame` on whatever connection `inner` has, as is
+async fn send_control(
+    inner: &mut ClientType,
+    frame: Frame,
+    server_addr: SocketAddr,
+) -> Result<(), VstpError> {
+    match inner {
+        ClientType::Tcp(client) => client.send(frame).await,
+        ClientType::Udp(client) => client.send(frame, server_addr).await,
+        ClientType::Auto(auto) => match (&mut auto.tcp, &auto.udp) {
+            (Some(tcp), _) => tcp.send(frame).await,
+            (None, Some(udp)) => udp.send(frame, server_addr).await,
+            (None, None) => Ok(()),
+        },
+    }
+}
+
+/// Say BYE on whatever connection `inner` has and close it
+async fn say_bye(inner: &mut ClientType, server_addr: SocketAddr) -> Result<(), VstpError> {
+    let bye = Frame::new(FrameType::Bye);
+    match inner {
+        ClientType::Tcp(client) => client.close().await,
+        ClientType::Udp(client) => client.send(bye, server_addr).await,
+        ClientType::Auto(auto) => match (&mut auto.tcp, &auto.udp) {
+            (Some(tcp), _) => tcp.close().await,
+            (None, Some(udp)) => udp.send(bye, server_addr).await,
+            (None, None) => Ok(()),
+        },
+    }
+}
+
+/// What the handshake of the current connection settled on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Negotiated {
+    limits: PeerLimits,
+    frame_version: u8,
+    /// Whether the connection's TLS handshake resumed an earlier session
+    tls_resumed: bool,
+}
+
+impl Negotiated {
+    fn new(limits: PeerLimits) -> Self {
+        Self {
+            limits,
+            frame_version: VSTP_VERSION,
+            tls_resumed: false,
+        }
+    }
+}
+
+/// Where and how a TCP client connects, to do it again
+#[derive(Debug)]
+struct Dial {
+    /// The preferred server, then [`ConnectOptions::fallback_addrs`]
+    addrs: Vec<String>,
+    options: ConnectOptions,
+    /// Index in `addrs` of the server connected to
+    current: AtomicUsize,
+    /// Set when that server is shutting down, for the next connection to
+    /// go to another one
+    leave_current: AtomicBool,
+    /// Id of the session the last WELCOME opened, claimed again by the next
+    /// HELLO, see [`migration`](crate::migration)
+    session_id: std::sync::Mutex<Option<crate::SessionId>>,
+    /// Where the server said the session moved, tried before `addrs` until
+    /// it can't be reached or is shutting down
+    moved_to: std::sync::Mutex<Option<String>>,
+}
+
+impl Dial {
+    fn new(addr: String, options: ConnectOptions) -> Self {
+        let mut addrs = vec![addr];
+        addrs.extend(options.fallback_addrs.iter().cloned());
+        Self {
+            addrs,
+            options,
+            current: AtomicUsize::new(0),
+            leave_current: AtomicBool::new(false),
+            session_id: std::sync::Mutex::new(None),
+            moved_to: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Handshake with the server the session moved to, if it did, or else
+    /// with the first server in `addrs` that completes it, starting after
+    /// the current one if it is shutting down
+    async fn handshake(
+        &self,
+        clock: &ClockSync,
+        send_shaper: Option<Arc<SendShaper>>,
+    ) -> Result<(crate::tcp::VstpTcpClient, Negotiated, Frame), VstpError> {
+        let mut leaving = self.leave_current.swap(false, Ordering::Relaxed);
+        let moved_to = self.moved_to.lock().unwrap().clone();
+        if let Some(addr) = moved_to {
+            if leaving {
+                // It is the server the session moved to that is shutting down
+                leaving = false;
+            } else {
+                let hello = self.hello();
+                match tcp_handshake(&addr, hello, &self.options, clock, send_shaper.clone()).await {
+                    Ok(connected) => return Ok(self.opened(connected)),
+                    Err(e) => tracing::debug!("Following the session to {} failed: {}", addr, e),
+                }
+            }
+            *self.moved_to.lock().unwrap() = None;
+        }
+        let start = match leaving {
+            true => self.current.load(Ordering::Relaxed) + 1,
+            false => 0,
+        };
+        let mut last_error = None;
+        for offset in 0..self.addrs.len() {
+            let index = (start + offset) % self.addrs.len();
+            let addr = &self.addrs[index];
+            let hello = self.hello();
+            match tcp_handshake(addr, hello, &self.options, clock, send_shaper.clone()).await {
+                Ok(connected) => {
+                    self.current.store(index, Ordering::Relaxed);
+                    return Ok(self.opened(connected));
+                }
+                Err(e) => {
+                    tracing::debug!("Connecting to {} failed: {}", addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("a preferred server"))
+    }
+
+    /// The HELLO to send, claiming the last session
+    fn hello(&self) -> Frame {
+        let hello = self.options.hello();
+        match *self.session_id.lock().unwrap() {
+            Some(id) => hello.with_header(SESSION_ID_HEADER, &format_session_id(id)),
+            None => hello,
+        }
+    }
+
+    /// Note the session the WELCOME of `connected` opened
+    fn opened<T>(&self, connected: (T, Negotiated, Frame)) -> (T, Negotiated, Frame) {
+        let id = connected.2.get_header(SESSION_ID_HEADER).and_then(parse_session_id);
+        *self.session_id.lock().unwrap() = id;
+        connected
+    }
+
+    /// The server connected to
+    fn current_addr(&self) -> &str {
+        &self.addrs[self.current.load(Ordering::Relaxed)]
+    }
+}
+
 /// A simplified client that handles both TCP and UDP connections
+///
+/// Clones share the connection. Over TCP, what each handle sends reaches
+/// the server's handler in the order it was sent, see
+/// [`tcp`](crate::tcp#ordering) for the exact guarantee.
 #[derive(Clone)]
 pub struct VstpClient {
     inner: Arc<Mutex<ClientType>>,
     server_addr: SocketAddr,
     timeout: Duration,
+    clock: Arc<ClockSync>,
+    negotiated: Arc<std::sync::Mutex<Negotiated>>,
+    /// WELCOME of the current connection; `None` in auto mode
+    welcome: Arc<std::sync::Mutex<Option<Frame>>>,
+    /// `None` for clients that can't reconnect
+    dial: Option<Arc<Dial>>,
+    backoff: Arc<Backoff>,
+    send_shaper: Option<Arc<SendShaper>>,
+    lifecycle: Arc<Lifecycle>,
+    abandoned_calls: Arc<AbandonedCalls>,
+    max_headers: usize,
+    #[cfg(feature = "otel")]
+    propagate_trace_context: bool,
+}
+
+impl Drop for VstpClient {
+    /// The last handle says BYE on its way out, without waiting for it to go
+    /// out, unless [`VstpClient::shutdown`] already did
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.inner) > 1 || self.lifecycle.closed.is_cancelled() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        self.lifecycle.closing.store(true, Ordering::Release);
+        let inner = self.inner.clone();
+        let server_addr = self.server_addr;
+        runtime.spawn(async move {
+            let _ = say_bye(&mut *inner.lock().await, server_addr).await;
+        });
+    }
 }
 
 enum ClientType {
     Tcp(crate::tcp::VstpTcpClient),
     Udp(crate::udp::VstpUdpClient),
-    Auto(AutoClientInner),
+    Auto(Box<AutoClientInner>),
 }
 
 struct AutoClientInner {
@@ -168,34 +849,164 @@ struct AutoClientInner {
 }
 
 impl VstpClient {
-    /// Connect to a TCP server with automatic TLS
+    /// Connect to a TCP server and complete the handshake
     pub async fn connect_tcp(addr: impl Into<String>) -> Result<Self, VstpError> {
-        let addr_str = addr.into();
-        let server_addr = addr_str
-            .parse()
-            .map_err(|e| VstpError::Protocol(format!("Invalid address: {}", e)))?;
-        let client = crate::tcp::VstpTcpClient::connect(&addr_str).await?;
+        Self::connect_tcp_with_options(addr, ConnectOptions::default()).await
+    }
 
-        Ok(Self {
+    /// Connect to a TCP server over TLS with `config` and complete the handshake
+    ///
+    /// See [`tls`](crate::tcp::tls) for configs, e.g. one trusting a
+    /// self-signed certificate.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tcp_tls(
+        addr: impl Into<String>,
+        config: impl Into<Arc<rustls::ClientConfig>>,
+    ) -> Result<Self, VstpError> {
+        Self::connect_tcp_with_options(addr, ConnectOptions::default().with_tls(config)).await
+    }
+
+    /// Connect to a TCP server and open a session with its service `service`
+    ///
+    /// A server that doesn't offer the service and has no default rejects
+    /// the handshake with error code `ServiceNotFound`.
+    pub async fn connect_tcp_service(
+        addr: impl Into<String>,
+        service: impl Into<String>,
+    ) -> Result<Self, VstpError> {
+        let options = ConnectOptions {
+            service: Some(service.into()),
+            ..ConnectOptions::default()
+        };
+        Self::connect_tcp_with_options(addr, options).await
+    }
+
+    /// Connect to a TCP server, presenting the credentials in `options`
+    ///
+    /// Returns once the server has welcomed the session. Each phase is
+    /// bounded and fails with its own error: [`VstpError::ConnectTimeout`] or
+    /// an I/O error while connecting, [`VstpError::TlsHandshakeTimeout`] or
+    /// [`VstpError::TlsHandshake`] during TLS, [`VstpError::HandshakeTimeout`] or
+    /// [`VstpError::HandshakeRejected`] while waiting for the WELCOME.
+    /// Dropping the future at any point closes the half-open connection.
+    pub async fn connect_tcp_with_options(
+        addr: impl Into<String>,
+        options: ConnectOptions,
+    ) -> Result<Self, VstpError> {
+        let dial = Dial::new(addr.into(), options.clone());
+        for addr in &dial.addrs {
+            addr.parse::<SocketAddr>()
+                .map_err(|e| VstpError::Protocol(format!("Invalid address: {}", e)))?;
+        }
+        let send_shaper = options
+            .max_send_bps
+            .map(|bps| Arc::new(SendShaper::new(bps)));
+        let clock = ClockSync::new();
+        let (client, negotiated, welcome) = dial.handshake(&clock, send_shaper.clone()).await?;
+        let server_addr = dial.current_addr().parse().expect("checked above");
+
+        let client = Self {
             inner: Arc::new(Mutex::new(ClientType::Tcp(client))),
             server_addr,
             timeout: DEFAULT_TIMEOUT,
-        })
+            clock: Arc::new(clock),
+            negotiated: Arc::new(std::sync::Mutex::new(negotiated)),
+            welcome: Arc::new(std::sync::Mutex::new(Some(welcome))),
+            backoff: Arc::new(Backoff::new(options.wait_out_backoff)),
+            send_shaper,
+            lifecycle: Arc::default(),
+            abandoned_calls: Arc::default(),
+            max_headers: options.max_headers,
+            #[cfg(feature = "otel")]
+            propagate_trace_context: options.propagate_trace_context,
+            dial: Some(Arc::new(dial)),
+        };
+        if let Some(every) = options.clock_sync_interval {
+            client.spawn_clock_sync(every);
+        }
+        Ok(client)
     }
 
-    /// Create a UDP client bound to any port
+    /// Create a UDP client bound to any port and complete the handshake
     pub async fn connect_udp(server_addr: impl Into<String>) -> Result<Self, VstpError> {
+        Self::connect_udp_with_options(server_addr, ConnectOptions::default()).await
+    }
+
+    /// Create a UDP client, presenting the credentials in `options`
+    ///
+    /// The HELLO is resent until a reply arrives or the handshake times out
+    /// with [`VstpError::HandshakeTimeout`]. There is no connection to set up,
+    /// so `connect_timeout` doesn't apply, and none to reconnect, so
+    /// [`ConnectOptions::auto_reconnect`] is refused.
+    pub async fn connect_udp_with_options(
+        server_addr: impl Into<String>,
+        options: ConnectOptions,
+    ) -> Result<Self, VstpError> {
+        if options.auto_reconnect {
+            return Err(VstpError::Protocol(
+                "auto_reconnect is available only for TCP clients".to_string(),
+            ));
+        }
         let addr_str = server_addr.into();
         let server_addr = addr_str
             .parse()
             .map_err(|e| VstpError::Protocol(format!("Invalid address: {}", e)))?;
-        let client = crate::udp::VstpUdpClient::bind("0.0.0.0:0").await?;
+        let config = crate::udp::client::UdpConfig {
+            max_send_bps: options.max_send_bps,
+            ..Default::default()
+        };
+        let mut client = crate::udp::VstpUdpClient::bind_with_config("0.0.0.0:0", config).await?;
+        let send_shaper = client.send_shaper();
 
-        Ok(Self {
+        let clock = ClockSync::new();
+        let (reply, sent_at) = tokio::time::timeout(options.handshake_timeout, async {
+            loop {
+                let sent_at = clock.local_now();
+                client.send(options.hello(), server_addr).await?;
+                let wait = tokio::time::sleep(UDP_HELLO_INTERVAL);
+                tokio::pin!(wait);
+                loop {
+                    tokio::select! {
+                        received = client.recv() => {
+                            let (frame, from) = received?;
+                            if from == server_addr && is_handshake_reply(&frame) {
+                                return Ok::<_, VstpError>((frame, sent_at));
+                            }
+                        }
+                        _ = &mut wait => break,
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| VstpError::HandshakeTimeout {
+            after: options.handshake_timeout,
+        })??;
+        check_handshake_reply(&reply)?;
+        clock.record_reply(sent_at, &reply);
+
+        let client = Self {
             inner: Arc::new(Mutex::new(ClientType::Udp(client))),
             server_addr,
             timeout: DEFAULT_TIMEOUT,
-        })
+            clock: Arc::new(clock),
+            negotiated: Arc::new(std::sync::Mutex::new(Negotiated::new(
+                PeerLimits::from_welcome(&reply),
+            ))),
+            welcome: Arc::new(std::sync::Mutex::new(Some(reply))),
+            dial: None,
+            backoff: Arc::new(Backoff::new(options.wait_out_backoff)),
+            send_shaper,
+            lifecycle: Arc::default(),
+            abandoned_calls: Arc::default(),
+            max_headers: options.max_headers,
+            #[cfg(feature = "otel")]
+            propagate_trace_context: options.propagate_trace_context,
+        };
+        if let Some(every) = options.clock_sync_interval {
+            client.spawn_clock_sync(every);
+        }
+        Ok(client)
     }
 
     /// Connect with automatic transport probing and adaptive switching.
@@ -295,16 +1106,29 @@ impl VstpClient {
         };
 
         Ok(Self {
-            inner: Arc::new(Mutex::new(ClientType::Auto(AutoClientInner {
+            inner: Arc::new(Mutex::new(ClientType::Auto(Box::new(AutoClientInner {
                 tcp: tcp_client_opt,
                 udp: udp_client_opt,
                 state: AutoClientState::new(initial),
                 cfg,
                 fault: AutoFaultInjection::default(),
                 op_counter: 0,
-            }))),
+            })))),
             server_addr: parsed_addr,
             timeout: DEFAULT_TIMEOUT,
+            clock: Arc::new(ClockSync::new()),
+            negotiated: Arc::new(std::sync::Mutex::new(
+                Negotiated::new(PeerLimits::default()),
+            )),
+            welcome: Arc::default(),
+            dial: None,
+            backoff: Arc::new(Backoff::new(true)),
+            send_shaper: None,
+            lifecycle: Arc::default(),
+            abandoned_calls: Arc::default(),
+            max_headers: DEFAULT_MAX_HEADERS,
+            #[cfg(feature = "otel")]
+            propagate_trace_context: false,
         })
     }
 
@@ -313,6 +1137,190 @@ impl VstpClient {
         self.timeout = timeout;
     }
 
+    /// Message limits the server announced during the handshake
+    ///
+    /// Auto mode clients don't handshake and report no limits.
+    pub fn peer_limits(&self) -> PeerLimits {
+        self.negotiated.lock().unwrap().limits
+    }
+
+    /// Whether the current connection resumed a TLS session instead of
+    /// running a full handshake; false without TLS
+    pub fn tls_resumed(&self) -> bool {
+        self.negotiated.lock().unwrap().tls_resumed
+    }
+
+    /// The WELCOME the server answered the current connection's HELLO
+    /// with, e.g. to read what its [`ServerOptions::on_accept`] put in it
+    ///
+    /// `None` for clients connected with [`connect_auto`](VstpClient::connect_auto).
+    pub fn welcome(&self) -> Option<Frame> {
+        self.welcome.lock().unwrap().clone()
+    }
+
+    /// Id the server gave the current session, which a reconnection claims
+    /// again after the session [moved](crate::migration)
+    ///
+    /// `None` for servers that don't hand out ids and for clients that can't
+    /// reconnect.
+    pub fn session_id(&self) -> Option<crate::SessionId> {
+        *self.dial.as_ref()?.session_id.lock().unwrap()
+    }
+
+    /// Replace the TCP connection with a new one and handshake again
+    ///
+    /// Nothing negotiated on the old connection carries over: the frame
+    /// version, compression and the server's limits all come from the new
+    /// WELCOME, so a server that was rolled back to an older release is
+    /// spoken to on its own terms. If they differ from the last session's,
+    /// [`ClientEvent::CapabilitiesChanged`] is sent to
+    /// [`events`](VstpClient::events). With [`ConnectOptions::auto_reconnect`]
+    /// this happens by itself when the connection turns out to be gone.
+    ///
+    /// Only clients connected with [`connect_tcp`](VstpClient::connect_tcp)
+    /// and friends can reconnect.
+    pub async fn reconnect(&self) -> Result<(), VstpError> {
+        let mut inner = self.inner.lock().await;
+        self.redial(&mut inner).await
+    }
+
+    async fn redial(&self, inner: &mut ClientType) -> Result<(), VstpError> {
+        let Some(dial) = &self.dial else {
+            return Err(VstpError::Protocol(
+                "Reconnecting is available only for TCP clients".to_string(),
+            ));
+        };
+        let (client, negotiated, welcome) =
+            dial.handshake(&self.clock, self.send_shaper.clone()).await?;
+        *inner = ClientType::Tcp(client);
+        *self.welcome.lock().unwrap() = Some(welcome);
+        let previous = std::mem::replace(&mut *self.negotiated.lock().unwrap(), negotiated);
+        if previous != negotiated {
+            let _ = self.backoff.events.send(ClientEvent::CapabilitiesChanged {
+                limits: negotiated.limits,
+                frame_version: negotiated.frame_version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reconnect to the next server if the current one said it is shutting
+    /// down, ending the pause it asked for; whether the client moved
+    async fn leave_draining_server(&self) -> Result<bool, VstpError> {
+        let leaving = self
+            .dial
+            .as_ref()
+            .is_some_and(|dial| dial.leave_current.load(Ordering::Relaxed));
+        if !leaving {
+            return Ok(false);
+        }
+        let mut inner = self.inner.lock().await;
+        self.redial(&mut inner).await?;
+        self.backoff.resume();
+        Ok(true)
+    }
+
+    fn auto_reconnect(&self) -> bool {
+        self.dial
+            .as_ref()
+            .is_some_and(|dial| dial.options.auto_reconnect)
+    }
+
+    fn idempotency_keys(&self) -> bool {
+        self.dial
+            .as_ref()
+            .is_some_and(|dial| dial.options.idempotency_keys)
+    }
+
+    /// The shaper applying [`ConnectOptions::max_send_bps`], with the current send rate
+    pub fn send_shaper(&self) -> Option<Arc<SendShaper>> {
+        self.send_shaper.clone()
+    }
+
+    /// Best guess at the server's current time, see [`crate::clock`]
+    pub fn estimated_server_time(&self) -> SystemTime {
+        self.clock.estimated_server_time()
+    }
+
+    /// The estimate of the server's clock this client keeps
+    pub fn clock(&self) -> &ClockSync {
+        &self.clock
+    }
+
+    /// Send a PING to re-estimate the server's clock
+    ///
+    /// The estimate is updated when [`receive`](VstpClient::receive) comes
+    /// across the PONG.
+    pub async fn sync_clock(&self) -> Result<(), VstpError> {
+        self.send_raw(self.clock.ping()).await
+    }
+
+    /// Send a PING and wait up to `wait` for the PONG, for TCP clients
+    /// nobody is reading from; anything else arriving first is dropped
+    pub(crate) async fn heartbeat(&self, wait: Duration) -> Result<(), VstpError> {
+        let mut inner = self.inner.lock().await;
+        let ClientType::Tcp(client) = &mut *inner else {
+            return Err(VstpError::Protocol("not a TCP client".to_string()));
+        };
+        client.send(self.clock.ping()).await?;
+        tokio::time::timeout(wait, async {
+            loop {
+                match client.recv().await? {
+                    Some(frame) if frame.typ == FrameType::Pong => {
+                        self.clock.record_pong(&frame);
+                        return Ok(());
+                    }
+                    Some(_) => continue,
+                    None => return Err(VstpError::ConnectionClosed),
+                }
+            }
+        })
+        .await
+        .map_err(|_| VstpError::Timeout)?
+    }
+
+    fn spawn_clock_sync(&self, every: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        let server_addr = self.server_addr;
+        let timeout = self.timeout;
+        let clock = self.clock.clone();
+        let negotiated = self.negotiated.clone();
+        let welcome = self.welcome.clone();
+        let dial = self.dial.clone();
+        let backoff = self.backoff.clone();
+        let lifecycle = self.lifecycle.clone();
+        let abandoned_calls = self.abandoned_calls.clone();
+        let max_headers = self.max_headers;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                // Stop once every handle to the client is gone
+                let Some(inner) = inner.upgrade() else { break };
+                let client = VstpClient {
+                    inner,
+                    server_addr,
+                    timeout,
+                    clock: clock.clone(),
+                    negotiated: negotiated.clone(),
+                    welcome: welcome.clone(),
+                    dial: dial.clone(),
+                    backoff: backoff.clone(),
+                    send_shaper: None,
+                    lifecycle: lifecycle.clone(),
+                    abandoned_calls: abandoned_calls.clone(),
+                    max_headers,
+                    #[cfg(feature = "otel")]
+                    propagate_trace_context: false,
+                };
+                if client.sync_clock().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     /// Update runtime fault injection values for auto mode.
     pub async fn set_auto_fault_injection(
         &self,
@@ -357,112 +1365,550 @@ impl VstpClient {
     }
 
     /// Send any serializable data to the server
+    ///
+    /// Data larger than the server's [`PeerLimits::max_message_bytes`] is sent
+    /// in chunks if the server reassembles them, and otherwise fails with
+    /// [`VstpError::TooLargeForPeer`] before anything is sent.
     pub async fn send<T: Serialize>(&self, data: T) -> Result<(), VstpError> {
-        let payload = serde_json::to_vec(&data)
-            .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
-        let frame = Frame::new(FrameType::Data)
-            .with_header("content-type", "application/json")
-            .with_payload(payload);
-
-        let mut inner = self.inner.lock().await;
-        match &mut *inner {
-            ClientType::Tcp(client) => tokio::time::timeout(self.timeout, client.send(frame))
-                .await
-                .map_err(|_| VstpError::Timeout)?
-                .map_err(|e| VstpError::Protocol(format!("Send error: {}", e)))?,
-            ClientType::Udp(client) => {
-                tokio::time::timeout(self.timeout, client.send(frame, self.server_addr))
-                    .await
-                    .map_err(|_| VstpError::Timeout)?
-                    .map_err(|e| VstpError::Protocol(format!("Send error: {}", e)))?
-            }
-            ClientType::Auto(auto) => {
-                self.auto_send_with_fallback(auto, frame, false).await?;
-            }
-        }
-        Ok(())
+        self.guarded(async {
+            self.backoff.ready().await?;
+            let payload = serde_json::to_vec(&data)
+                .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+            let frame = Frame::new(FrameType::Data)
+                .with_header("content-type", "application/json")
+                .with_payload(payload);
+            self.send_fitted(frame, false).await
+        })
+        .await
     }
 
-    /// Send a raw frame directly
-    pub async fn send_raw(&self, frame: Frame) -> Result<(), VstpError> {
+    /// [`send`](VstpClient::send) `data` sealed to `recipient`, so that
+    /// only the holder of its secret can read it; see [`e2e`](crate::e2e)
+    #[cfg(feature = "e2e")]
+    pub async fn send_sealed<T: Serialize>(
+        &self,
+        data: T,
+        recipient: &crate::e2e::PublicKey,
+    ) -> Result<(), VstpError> {
+        self.guarded(async {
+            self.backoff.ready().await?;
+            let payload = serde_json::to_vec(&data)
+                .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+            let frame = Frame::new(FrameType::Data)
+                .with_header("content-type", "application/json")
+                .with_payload(payload)
+                .seal_for(recipient);
+            self.send_fitted(frame, false).await
+        })
+        .await
+    }
+
+    /// [`receive`](VstpClient::receive) data sealed to the public half of
+    /// `key`, failing with [`VstpError::Unsealing`] if it doesn't open
+    #[cfg(feature = "e2e")]
+    pub async fn receive_opened<T: DeserializeOwned>(
+        &self,
+        key: &crate::e2e::StaticSecret,
+    ) -> Result<T, VstpError> {
+        self.guarded(async {
+            let frame = self.next_frame(false).await?;
+            if frame.typ == FrameType::Err {
+                return Err(self.reply_error(&frame));
+            }
+            let frame = frame.open(key)?;
+            serde_json::from_slice(frame.payload())
+                .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))
+        })
+        .await
+    }
+
+    /// Send `frame` in chunks if it is over the server's limit, waiting for
+    /// an ACK of each if `ack` is set
+    ///
+    /// If the connection is gone and gets replaced, the frame is split again
+    /// for the new session and sent from the start.
+    async fn send_fitted(&self, frame: Frame, ack: bool) -> Result<(), VstpError> {
+        frame.check_header_count(self.max_headers)?;
         let mut inner = self.inner.lock().await;
-        match &mut *inner {
-            ClientType::Tcp(client) => tokio::time::timeout(self.timeout, client.send(frame))
-                .await
-                .map_err(|_| VstpError::Timeout)?
-                .map_err(|e| VstpError::Protocol(format!("Send error: {}", e)))?,
-            ClientType::Udp(client) => {
-                tokio::time::timeout(self.timeout, client.send(frame, self.server_addr))
-                    .await
-                    .map_err(|_| VstpError::Timeout)?
-                    .map_err(|e| VstpError::Protocol(format!("Send error: {}", e)))?
+        let mut retry = self.auto_reconnect().then(|| frame.clone());
+        let mut pending = Some(frame);
+        'session: while let Some(frame) = pending.take() {
+            for chunk in self.peer_limits().fit(frame)? {
+                let result = match &mut *inner {
+                    ClientType::Tcp(client) => {
+                        tokio::time::timeout(self.timeout, tcp_send(client, chunk, ack))
+                            .await
+                            .map_err(|_| VstpError::Timeout)?
+                    }
+                    ClientType::Udp(client) if ack => {
+                        let send = client.send_with_ack(chunk, self.server_addr);
+                        tokio::time::timeout(self.timeout, send)
+                            .await
+                            .map_err(|_| VstpError::Timeout)?
+                    }
+                    ClientType::Udp(client) => {
+                        tokio::time::timeout(self.timeout, client.send(chunk, self.server_addr))
+                            .await
+                            .map_err(|_| VstpError::Timeout)?
+                            .map_err(|e| VstpError::Protocol(format!("Send error: {}", e)))
+                    }
+                    ClientType::Auto(auto) => self.auto_send_with_fallback(auto, chunk, ack).await,
+                };
+                match result {
+                    Ok(()) => {}
+                    Err(e) if retry.is_some() && connection_lost(&e) => {
+                        self.redial(&mut inner).await?;
+                        #[cfg(feature = "otel")]
+                        crate::otel::record_retry("reconnect");
+                        pending = retry.take();
+                        continue 'session;
+                    }
+                    Err(e) if !ack && matches!(*inner, ClientType::Tcp(_)) => {
+                        return Err(VstpError::Protocol(format!("Send error: {}", e)))
+                    }
+                    Err(e) => return Err(e),
+                }
             }
-            ClientType::Auto(auto) => {
-                self.auto_send_with_fallback(auto, frame, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a raw frame directly
+    ///
+    /// A frame with a TTL gets its `expires-at-ms` recomputed against the
+    /// estimated server clock.
+    pub async fn send_raw(&self, frame: Frame) -> Result<(), VstpError> {
+        self.guarded(self.send_frame(frame)).await
+    }
+
+    async fn send_frame(&self, mut frame: Frame) -> Result<(), VstpError> {
+        frame.check_header_count(self.max_headers)?;
+        if let Some(ttl) = frame.ttl() {
+            frame = frame.with_ttl_at(ttl, self.clock.estimated_server_time());
+        }
+        let mut inner = self.inner.lock().await;
+        let mut retry = self.auto_reconnect().then(|| frame.clone());
+        let mut pending = Some(frame);
+        while let Some(frame) = pending.take() {
+            match &mut *inner {
+                ClientType::Tcp(client) => {
+                    match tokio::time::timeout(self.timeout, client.send(frame))
+                        .await
+                        .map_err(|_| VstpError::Timeout)?
+                    {
+                        Ok(()) => {}
+                        Err(e) if retry.is_some() && connection_lost(&e) => {
+                            self.redial(&mut inner).await?;
+                            pending = retry.take();
+                        }
+                        Err(e) => return Err(VstpError::Protocol(format!("Send error: {}", e))),
+                    }
+                }
+                ClientType::Udp(client) => {
+                    tokio::time::timeout(self.timeout, client.send(frame, self.server_addr))
+                        .await
+                        .map_err(|_| VstpError::Timeout)?
+                        .map_err(|e| VstpError::Protocol(format!("Send error: {}", e)))?
+                }
+                ClientType::Auto(auto) => {
+                    self.auto_send_with_fallback(auto, frame, false).await?;
+                }
             }
         }
         Ok(())
     }
 
-    /// Receive data and automatically deserialize it
-    pub async fn receive<T: DeserializeOwned>(&self) -> Result<T, VstpError> {
+    /// Receive the next frame as-is
+    ///
+    /// ERR frames are returned like any other frame; PONGs are still consumed.
+    pub async fn receive_raw(&self) -> Result<Frame, VstpError> {
+        self.guarded(self.receive_frame()).await
+    }
+
+    async fn receive_frame(&self) -> Result<Frame, VstpError> {
+        self.next_frame(false).await
+    }
+
+    /// The next frame for the caller; with `resend`, a connection found gone
+    /// is replaced and reported as [`VstpError::ConnectionClosed`], for the
+    /// request awaiting a reply to be sent again
+    async fn next_frame(&self, resend: bool) -> Result<Frame, VstpError> {
         let mut inner = self.inner.lock().await;
-        let frame = match &mut *inner {
-            ClientType::Tcp(client) => tokio::time::timeout(self.timeout, client.recv())
-                .await
-                .map_err(|_| VstpError::Timeout)?
-                .map_err(|e| VstpError::Protocol(format!("Receive error: {}", e)))?
-                .ok_or_else(|| VstpError::Protocol("Connection closed".to_string()))?,
-            ClientType::Udp(client) => {
-                let (frame, _) = tokio::time::timeout(self.timeout, client.recv())
-                    .await
-                    .map_err(|_| VstpError::Timeout)?
-                    .map_err(|e| VstpError::Protocol(format!("Receive error: {}", e)))?;
-                frame
+        let mut may_redial = self.auto_reconnect();
+        loop {
+            let frame = match &mut *inner {
+                ClientType::Tcp(client) => {
+                    let received = tokio::time::timeout(self.timeout, client.recv())
+                        .await
+                        .map_err(|_| VstpError::Timeout)?;
+                    match received {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) if may_redial => {
+                            self.redial(&mut inner).await?;
+                            if resend {
+                                return Err(VstpError::ConnectionClosed);
+                            }
+                            may_redial = false;
+                            continue;
+                        }
+                        Err(e) if may_redial && connection_lost(&e) => {
+                            self.redial(&mut inner).await?;
+                            if resend {
+                                return Err(VstpError::ConnectionClosed);
+                            }
+                            may_redial = false;
+                            continue;
+                        }
+                        Ok(None) => {
+                            return Err(VstpError::Protocol("Connection closed".to_string()))
+                        }
+                        Err(e) => return Err(VstpError::Protocol(format!("Receive error: {}", e))),
+                    }
+                }
+                ClientType::Udp(client) => {
+                    let (frame, _) = tokio::time::timeout(self.timeout, client.recv())
+                        .await
+                        .map_err(|_| VstpError::Timeout)?
+                        .map_err(|e| VstpError::Protocol(format!("Receive error: {}", e)))?;
+                    frame
+                }
+                ClientType::Auto(auto) => {
+                    self.auto_recv_with_fallback(auto).await?
+                }
+            };
+            // PONGs answer clock sync PINGs, they aren't for the caller
+            if frame.typ == FrameType::Pong {
+                self.clock.record_pong(&frame);
+                continue;
             }
-            ClientType::Auto(auto) => {
-                self.auto_recv_with_fallback(auto).await?
+            if self.abandoned_calls.skip(&frame) {
+                continue;
             }
-        };
+            return Ok(frame);
+        }
+    }
+
+    /// Receive data and automatically deserialize it
+    ///
+    /// An ERR asking the client to back off starts a pause on new requests
+    /// and yields [`VstpError::Backoff`].
+    pub async fn receive<T: DeserializeOwned>(&self) -> Result<T, VstpError> {
+        self.guarded(self.receive_reply(false)).await
+    }
+
+    /// The next reply, deserialized; `resend` as for [`next_frame`](Self::next_frame)
+    async fn receive_reply<T: DeserializeOwned>(&self, resend: bool) -> Result<T, VstpError> {
+        let frame = self.next_frame(resend).await?;
+        if frame.typ == FrameType::Err {
+            return Err(self.reply_error(&frame));
+        }
 
         serde_json::from_slice(frame.payload())
             .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))
     }
 
-    /// Send data and wait for acknowledgment
-    pub async fn send_with_ack<T: Serialize>(&self, data: T) -> Result<(), VstpError> {
+    /// The error an ERR reply stands for
+    fn reply_error(&self, frame: &Frame) -> VstpError {
+        if frame.error_code() == Some(ErrorCode::Redirect) {
+            if let Some(to) = frame.get_header(REDIRECT_TO_HEADER) {
+                if let Some(dial) = &self.dial {
+                    *dial.moved_to.lock().unwrap() = Some(to.to_string());
+                }
+                return VstpError::Redirected { to: to.to_string() };
+            }
+        }
+        if let Some(until) = self.backoff.note(frame) {
+            let fallback = self.dial.as_ref().filter(|dial| dial.addrs.len() > 1);
+            if let (Some(dial), Some(ErrorCode::ShuttingDown)) = (fallback, frame.error_code()) {
+                dial.leave_current.store(true, Ordering::Relaxed);
+            }
+            return VstpError::Backoff { until };
+        }
+        if let Some(mismatch) = schema::mismatch_error(frame) {
+            return mismatch;
+        }
+        if frame.error_code() == Some(ErrorCode::Forbidden) {
+            let message = String::from_utf8_lossy(frame.payload());
+            let prefix = format!("{}: ", ErrorCode::Forbidden);
+            let reason = message.strip_prefix(prefix.as_str()).unwrap_or(&message);
+            return VstpError::Forbidden(reason.to_string());
+        }
+        VstpError::ServerError(String::from_utf8_lossy(frame.payload()).into_owned())
+    }
+
+    /// Call `method` on a server running a [`Router`] and wait for the response
+    ///
+    /// Requests over the server's limit are handled as in [`VstpClient::send`].
+    /// A request the server turns away with a `retry-after-ms` is sent again
+    /// once the pause is over, unless [`ConnectOptions::wait_out_backoff`] is off.
+    /// If the server is shutting down and there are
+    /// [`fallback_addrs`](ConnectOptions::fallback_addrs), it is sent to the
+    /// next server at once instead. One turned away because the session
+    /// [moved](crate::migration) is sent again to the instance it moved to.
+    ///
+    /// With [`ConnectOptions::auto_reconnect`], a request carrying an
+    /// [`IDEMPOTENCY_KEY_HEADER`] whose connection is lost before the reply
+    /// arrives is sent again once on the new connection; see
+    /// [`ConnectOptions::idempotency_keys`].
+    pub async fn call<T: Serialize, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        data: T,
+    ) -> Result<R, VstpError> {
         let payload = serde_json::to_vec(&data)
             .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
         let frame = Frame::new(FrameType::Data)
             .with_header("content-type", "application/json")
-            .with_flag(Flags::REQ_ACK)
+            .with_header(METHOD_HEADER, method)
             .with_payload(payload);
+        self.call_frame(frame).await
+    }
 
-        let mut inner = self.inner.lock().await;
-        match &mut *inner {
-            ClientType::Tcp(client) => tokio::time::timeout(self.timeout, async {
-                client.send(frame).await?;
-                let ack = client
-                    .recv()
-                    .await?
-                    .ok_or_else(|| VstpError::Protocol("Connection closed".to_string()))?;
-                if ack.frame_type() != FrameType::Ack {
-                    return Err(VstpError::Protocol("Expected ACK frame".to_string()));
+    /// Call a [`Router::stream_route`] and read its responses as they arrive
+    ///
+    /// The request goes out when the stream is first polled, tagged with a
+    /// fresh [`CALL_ID_HEADER`] the server copies onto each frame of the
+    /// response. The stream ends after the server's [`STREAM_END_HEADER`]
+    /// frame. An ERR frame, e.g. from a handler failing partway, is yielded
+    /// as the last item, and so is a failure to send or receive. A server
+    /// answering with one plain reply, e.g. for a method without a streaming
+    /// route, ends the stream after it.
+    ///
+    /// Dropping the stream before its end sends the server a
+    /// [`STREAM_CANCEL_HEADER`] frame for the call, which stops it. The
+    /// client skips the call's frames still on their way, so other requests
+    /// on the connection get their own replies.
+    pub fn call_server_stream<'a, T: Serialize, R: DeserializeOwned + 'a>(
+        &'a self,
+        method: &str,
+        data: T,
+    ) -> impl Stream<Item = Result<R, VstpError>> + Unpin + 'a {
+        self.call_stream_frames(method, data).map(|frame| {
+            serde_json::from_slice(frame?.payload())
+                .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))
+        })
+    }
+
+    /// The response frames of a [`call_server_stream`](VstpClient::call_server_stream)
+    fn call_stream_frames<'a, T: Serialize>(
+        &'a self,
+        method: &str,
+        data: T,
+    ) -> impl Stream<Item = Result<Frame, VstpError>> + Unpin + 'a {
+        let call_id = format!("{:016x}", rand::random::<u64>());
+        let request = serde_json::to_vec(&data)
+            .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))
+            .map(|payload| {
+                Frame::new(FrameType::Data)
+                    .with_header("content-type", "application/json")
+                    .with_header(METHOD_HEADER, method)
+                    .with_header(CALL_ID_HEADER, &call_id)
+                    .with_payload(payload)
+            });
+        let call = StreamedCall {
+            client: self,
+            call_id,
+            request: Some(request),
+            done: false,
+            finished: false,
+        };
+        Box::pin(futures::stream::unfold(call, |mut call| async move {
+            let frame = call.next().await?;
+            Some((frame, call))
+        }))
+    }
+
+    /// Publish `message` on `topic` of the server's [`PubSub`](crate::pubsub::PubSub);
+    /// how many subscriptions it was handed to
+    pub async fn publish<T: Serialize>(&self, topic: &str, message: T) -> Result<usize, VstpError> {
+        let request = PublishRequest {
+            topic: topic.to_string(),
+            message: serde_json::to_value(message)
+                .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?,
+        };
+        let published: Published = self.call(PUBLISH_METHOD, request).await?;
+        Ok(published.delivered)
+    }
+
+    /// Subscribe to `topic` of the server's [`PubSub`](crate::pubsub::PubSub),
+    /// yielding the messages published from now on
+    ///
+    /// The subscription lasts until the stream is dropped, as for
+    /// [`call_server_stream`](VstpClient::call_server_stream).
+    pub fn subscribe<'a, T: DeserializeOwned + 'a>(
+        &'a self,
+        topic: &str,
+    ) -> impl Stream<Item = Result<Delivery<T>, VstpError>> + Unpin + 'a {
+        self.subscribe_with_backfill(topic, 0)
+    }
+
+    /// Subscribe to `topic` like [`subscribe`](VstpClient::subscribe), first
+    /// yielding up to `backfill` of the messages the server retained, see
+    /// [Retained messages](crate::pubsub#retained-messages)
+    pub fn subscribe_with_backfill<'a, T: DeserializeOwned + 'a>(
+        &'a self,
+        topic: &str,
+        backfill: usize,
+    ) -> impl Stream<Item = Result<Delivery<T>, VstpError>> + Unpin + 'a {
+        let request = SubscribeRequest {
+            topic: topic.to_string(),
+            backfill,
+        };
+        self.call_stream_frames(SUBSCRIBE_METHOD, request)
+            .map(|frame| Delivery::from_frame(frame?))
+    }
+
+    /// Call `M::METHOD` with `message`, tagged with its schema version
+    ///
+    /// A route serving another version answers with
+    /// [`VstpError::SchemaMismatch`], see [`schema`].
+    pub async fn call_typed<M: VstpMessage, R: DeserializeOwned>(
+        &self,
+        message: M,
+    ) -> Result<R, VstpError> {
+        let payload = serde_json::to_vec(&message)
+            .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+        let frame = Frame::new(FrameType::Data)
+            .with_header("content-type", "application/json")
+            .with_header(METHOD_HEADER, M::METHOD)
+            .with_header(SCHEMA_VERSION_HEADER, &M::SCHEMA_VERSION.to_string())
+            .with_payload(payload);
+        self.call_frame(frame).await
+    }
+
+    /// Send the request `frame` for its method, traced if configured
+    async fn call_frame<R: DeserializeOwned>(&self, mut frame: Frame) -> Result<R, VstpError> {
+        if self.idempotency_keys() && frame.get_header(IDEMPOTENCY_KEY_HEADER).is_none() {
+            let key = format!("{:032x}", rand::random::<u128>());
+            frame = frame.with_header(IDEMPOTENCY_KEY_HEADER, &key);
+        }
+        #[cfg(feature = "otel")]
+        if self.propagate_trace_context {
+            use opentelemetry::context::FutureExt;
+            let method = frame.get_header(METHOD_HEADER).unwrap_or_default();
+            let cx = crate::otel::client_span(method, &frame, self.server_addr);
+            let frame = crate::otel::inject(&cx, frame);
+            let result = self.exchange(frame).with_context(cx.clone()).await;
+            crate::otel::end_span(&cx, result.as_ref().err());
+            return result;
+        }
+        self.exchange(frame).await
+    }
+
+    /// Send the request `frame` and wait for the response, as for [`VstpClient::call`]
+    async fn exchange<R: DeserializeOwned>(&self, frame: Frame) -> Result<R, VstpError> {
+        // Only a request the server can tell apart from a new one is safe to send twice
+        let mut resend =
+            self.auto_reconnect() && frame.get_header(IDEMPOTENCY_KEY_HEADER).is_some();
+        let mut redirects = 0;
+        self.guarded(async {
+            loop {
+                self.backoff.ready().await?;
+                self.send_fitted(frame.clone(), false).await?;
+                match self.receive_reply(resend).await {
+                    Err(VstpError::ConnectionClosed) if resend => {
+                        #[cfg(feature = "otel")]
+                        crate::otel::record_retry("reconnect");
+                        resend = false;
+                        continue;
+                    }
+                    // The server turned the request away unhandled, so it can go again
+                    Err(VstpError::Redirected { .. })
+                        if self.dial.is_some() && redirects < MAX_REDIRECTS =>
+                    {
+                        redirects += 1;
+                        self.reconnect().await?;
+                        #[cfg(feature = "otel")]
+                        crate::otel::record_retry("redirect");
+                        continue;
+                    }
+                    Err(VstpError::Backoff { until }) => {
+                        if self.leave_draining_server().await? {
+                            #[cfg(feature = "otel")]
+                            crate::otel::record_retry("reconnect");
+                            continue;
+                        }
+                        if !self.backoff.wait {
+                            return Err(VstpError::Backoff { until });
+                        }
+                        #[cfg(feature = "otel")]
+                        crate::otel::record_retry("backoff");
+                        continue;
+                    }
+                    result => return result,
                 }
-                Ok(())
-            })
-            .await
-            .map_err(|_| VstpError::Timeout)??,
-            ClientType::Udp(client) => {
-                tokio::time::timeout(self.timeout, client.send_with_ack(frame, self.server_addr))
-                    .await
-                    .map_err(|_| VstpError::Timeout)??
-            }
-            ClientType::Auto(auto) => {
-                self.auto_send_with_fallback(auto, frame, true).await?;
             }
+        })
+        .await
+    }
+
+    /// Run `op` unless the client is shutting down, failing it with
+    /// [`VstpError::Closed`] if [`shutdown`](VstpClient::shutdown) gives up on it
+    async fn guarded<T>(
+        &self,
+        op: impl std::future::Future<Output = Result<T, VstpError>>,
+    ) -> Result<T, VstpError> {
+        let _in_flight = self.lifecycle.enter()?;
+        tokio::select! {
+            result = op => result,
+            _ = self.lifecycle.closed.cancelled() => Err(VstpError::Closed),
         }
-        Ok(())
+    }
+
+    /// Stop taking operations, let the running ones finish, then say BYE
+    ///
+    /// Sends, receives and calls started after this fail with
+    /// [`VstpError::Closed`], on every clone of the client. Those already
+    /// running get up to `grace` to finish; any still running then fail with
+    /// [`VstpError::Closed`] too, which unlike a network error means the
+    /// request may or may not have reached the server. Then the client sends
+    /// a BYE and closes the connection.
+    pub async fn shutdown(&self, grace: Duration) -> Result<(), VstpError> {
+        self.lifecycle.closing.store(true, Ordering::Release);
+        let _ = tokio::time::timeout(grace, self.lifecycle.drained()).await;
+        self.lifecycle.closed.cancel();
+
+        let mut inner = self.inner.lock().await;
+        tokio::time::timeout(self.timeout, say_bye(&mut inner, self.server_addr))
+            .await
+            .map_err(|_| VstpError::Timeout)?
+    }
+
+    /// Pauses and resumptions of this client's requests
+    ///
+    /// A server that is shutting down or rate limiting the client can answer
+    /// with error code `ShuttingDown` or `RateLimited` and a `retry-after-ms`
+    /// header. The client then holds off new requests for that long, instead
+    /// of letting the application retry at once, and sends
+    /// [`ClientEvent::BackingOff`] here, e.g. to show a "reconnecting" notice.
+    /// [`ClientEvent::Resumed`] follows once requests go out again, early if
+    /// the client moved on to one of its
+    /// [`fallback_addrs`](ConnectOptions::fallback_addrs).
+    ///
+    /// [`ClientEvent::CapabilitiesChanged`] tells of a reconnection to a
+    /// server that negotiated differently than before.
+    pub fn events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.backoff.events.subscribe()
+    }
+
+    /// End a pause the server asked for early; waiting requests go out at once
+    pub fn resume(&self) {
+        self.backoff.resume();
+    }
+
+    /// Send data and wait for acknowledgment
+    pub async fn send_with_ack<T: Serialize>(&self, data: T) -> Result<(), VstpError> {
+        self.guarded(async {
+            self.backoff.ready().await?;
+            let payload = serde_json::to_vec(&data)
+                .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+            let frame = Frame::new(FrameType::Data)
+                .with_header("content-type", "application/json")
+                .with_flag(Flags::REQ_ACK)
+                .with_payload(payload);
+            self.send_fitted(frame, true).await
+        })
+        .await
     }
 
     fn maybe_switch_transport(auto: &mut AutoClientInner) {
@@ -682,356 +2128,2302 @@ impl VstpClient {
         Ok((frame, start.elapsed()))
     }
 
-    async fn apply_fault(
-        &self,
-        transport: TransportKind,
-        auto: &AutoClientInner,
-    ) -> Result<(), VstpError> {
-        let (delay_ms, fail_every_n) = match transport {
-            TransportKind::Tcp => (auto.fault.tcp_delay_ms, auto.fault.tcp_fail_every_n),
-            TransportKind::Udp => (auto.fault.udp_delay_ms, auto.fault.udp_fail_every_n),
-        };
-        if delay_ms > 0 {
-            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
-        }
-        if fail_every_n > 0 && auto.op_counter % fail_every_n == 0 {
-            return Err(VstpError::Timeout);
+    async fn apply_fault(
+        &self,
+        transport: TransportKind,
+        auto: &AutoClientInner,
+    ) -> Result<(), VstpError> {
+        let (delay_ms, fail_every_n) = match transport {
+            TransportKind::Tcp => (auto.fault.tcp_delay_ms, auto.fault.tcp_fail_every_n),
+            TransportKind::Udp => (auto.fault.udp_delay_ms, auto.fault.udp_fail_every_n),
+        };
+        if delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+        if fail_every_n > 0 && auto.op_counter.is_multiple_of(fail_every_n) {
+            return Err(VstpError::Timeout);
+        }
+        Ok(())
+    }
+}
+
+/// A simplified server that handles connections and message routing
+pub struct VstpServer {
+    inner: ServerType,
+    message_tx: mpsc::Sender<ServerMessage>,
+    message_rx: mpsc::Receiver<ServerMessage>,
+    timeout: Duration,
+    options: ServerOptions,
+    stats: Arc<ServerStats>,
+    services: HashMap<String, Service>,
+    sessions: SessionRegistry,
+}
+
+/// A service registered with [`VstpServer::service`]
+struct Service {
+    router: Router,
+    /// `None` to use the server's options
+    options: Option<ServerOptions>,
+}
+
+enum ServerType {
+    Tcp(Box<crate::tcp::VstpTcpServer>),
+    Udp(Box<crate::udp::VstpUdpServer>),
+    Auto(AutoServerInner),
+}
+
+struct AutoServerInner {
+    tcp: Arc<crate::tcp::VstpTcpServer>,
+    udp: Arc<crate::udp::VstpUdpServer>,
+    cfg: AutoSwitchConfig,
+    peer_preference: Arc<Mutex<HashMap<SocketAddr, PeerPreference>>>,
+}
+
+#[derive(Clone, Copy)]
+struct PeerPreference {
+    transport: TransportKind,
+    last_seen: Instant,
+}
+
+struct ServerMessage {
+    frame: Frame,
+    client_addr: SocketAddr,
+    /// Service the session picked; `None` for the default
+    service: Option<String>,
+    /// Identity the session authenticated as, if any
+    identity: Option<String>,
+    /// Id the session's WELCOME gave it
+    session: Option<crate::SessionId>,
+    /// Receive metadata of the frame completing the message
+    meta: FrameMeta,
+    params: Arc<NegotiatedParams>,
+    /// What was left of the session's quota when the request arrived
+    quota: Option<QuotaRemaining>,
+    /// Cancelled when the session ends
+    closed: CancellationToken,
+    extensions: SessionExtensions,
+    response_tx: mpsc::Sender<Frame>,
+}
+
+/// What a session's HELLO and WELCOME settled on, for handlers to adapt to
+///
+/// Read it in a handler with [`current_negotiated_params`].
+///
+/// Peers from before negotiation send a bare HELLO, with no capability
+/// headers at all. Their sessions get the version 1 defaults rather than an
+/// error: version 1 frames, no compression, [`DEFAULT_CONTENT_TYPE`] and no
+/// flow window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiatedParams {
+    /// Frame format version of the session; UDP sessions stay on version 1
+    pub version: u8,
+    /// Whether payload compression is currently on
+    pub compression: bool,
+    /// Content type the HELLO asked for in its `content-type` header, e.g. `application/json`
+    pub format: Option<String>,
+    /// Credit granted by the HELLO's [`flow`](crate::flow) window headers
+    pub peer_window: Option<WindowCredit>,
+    /// Service the HELLO picked; `None` for the default
+    pub service: Option<String>,
+    /// Largest message the server accepts, as announced on the WELCOME
+    pub max_message_bytes: Option<usize>,
+    /// Whether the server reassembles chunked messages
+    pub fragments: bool,
+}
+
+impl NegotiatedParams {
+    /// Parameters of a session that hasn't completed a handshake
+    fn defaults(options: &ServerOptions) -> Self {
+        Self {
+            version: VSTP_VERSION,
+            compression: false,
+            format: None,
+            peer_window: None,
+            service: None,
+            max_message_bytes: options.max_message_bytes,
+            fragments: options.accept_fragments,
+        }
+    }
+
+    /// Content type replies should use: [`format`](Self::format), or
+    /// [`DEFAULT_CONTENT_TYPE`] if the HELLO didn't ask for one
+    pub fn content_type(&self) -> &str {
+        self.format.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE)
+    }
+
+    /// Parameters settled by `hello` and the `welcome` answering it
+    fn from_handshake(hello: &Frame, welcome: &Frame, session: &Session) -> Self {
+        Self {
+            version: negotiated_version(welcome),
+            format: hello.get_header("content-type").map(str::to_string),
+            peer_window: WindowCredit::from_frame(hello),
+            service: session.service.clone(),
+            ..Self::defaults(&session.options)
+        }
+    }
+}
+
+tokio::task_local! {
+    static FRAME_META: FrameMeta;
+    static NEGOTIATED_PARAMS: Arc<NegotiatedParams>;
+    static PEER_ADDR: SocketAddr;
+    static SESSION_CLOSED: CancellationToken;
+    static DEADLINE: tokio::time::Instant;
+    static QUOTA_REMAINING: Option<QuotaRemaining>;
+    static SESSION_EXTENSIONS: SessionExtensions;
+    static IDENTITY: Option<String>;
+    static SESSION_ID: Option<crate::SessionId>;
+}
+
+/// Negotiated parameters of the session the current handler is serving
+///
+/// Available inside handlers run by [`VstpServer`]; `None` anywhere else.
+/// Sessions share one copy, so this is a reference count increment.
+pub fn current_negotiated_params() -> Option<Arc<NegotiatedParams>> {
+    NEGOTIATED_PARAMS.try_with(Arc::clone).ok()
+}
+
+/// Run a handler call for `msg` with its [`current_frame_meta`], [`current_negotiated_params`],
+/// [`current_peer_addr`], [`current_session_token`], [`current_quota_remaining`],
+/// [`current_session_extensions`], [`current_identity`] and [`current_session_id`]
+async fn in_context<F: std::future::Future>(msg: &ServerMessage, call: F) -> F::Output {
+    let call = IDENTITY.scope(msg.identity.clone(), call);
+    let call = SESSION_ID.scope(msg.session, call);
+    let call = SESSION_EXTENSIONS.scope(msg.extensions.clone(), call);
+    let call = QUOTA_REMAINING.scope(msg.quota, call);
+    let call = NEGOTIATED_PARAMS.scope(msg.params.clone(), call);
+    let call = PEER_ADDR.scope(msg.client_addr, call);
+    let call = SESSION_CLOSED.scope(msg.closed.clone(), call);
+    FRAME_META.scope(msg.meta.clone(), call).await
+}
+
+/// Address of the client whose request the current handler is serving
+///
+/// Available inside handlers run by [`VstpServer`]; `None` anywhere else.
+pub fn current_peer_addr() -> Option<SocketAddr> {
+    PEER_ADDR.try_with(|addr| *addr).ok()
+}
+
+/// Token cancelled when the session the current handler is serving ends
+///
+/// Hand it to work the handler spawns so it stops once its client is gone,
+/// e.g. with [`CancellationToken::run_until_cancelled`]. A TCP session ends
+/// when its connection closes; as it reads the next frame only after sending
+/// a reply, a client leaving mid-call is noticed once the handler returns. A
+/// UDP session ends when its address sends a new HELLO, or when it is
+/// dropped, see [`ServerOptions::udp_session_idle`].
+/// For a call tagged with a [`CALL_ID_HEADER`], e.g. a streamed one, the
+/// token is also cancelled when the client cancels the call.
+///
+/// Available inside handlers run by [`VstpServer`]; `None` anywhere else.
+/// Cancelling the returned token doesn't end the session.
+pub fn current_session_token() -> Option<CancellationToken> {
+    SESSION_CLOSED.try_with(CancellationToken::child_token).ok()
+}
+
+/// What was left of the session's quota when the current handler's request
+/// arrived, e.g. to warn the client in a
+/// [`QUOTA_REMAINING_HEADER`](crate::tcp::quota::QUOTA_REMAINING_HEADER)
+///
+/// The request itself is counted. TCP sessions have the quota of the
+/// server's [`TcpServerConfig::session_quota`](crate::tcp::TcpServerConfig::session_quota),
+/// UDP sessions the one in [`ServerOptions::udp_session_quota`]. `None`
+/// without a quota and outside handlers run by [`VstpServer`].
+pub fn current_quota_remaining() -> Option<QuotaRemaining> {
+    QUOTA_REMAINING.try_with(|quota| *quota).ok().flatten()
+}
+
+/// Values kept for the session the current handler is serving, which can
+/// move with it to another instance, see [`migration`](crate::migration)
+///
+/// Available inside handlers run by [`VstpServer`]; `None` anywhere else.
+pub fn current_session_extensions() -> Option<SessionExtensions> {
+    SESSION_EXTENSIONS.try_with(SessionExtensions::clone).ok()
+}
+
+/// Identity the session the current handler is serving authenticated as,
+/// see [`ServerOptions::api_keys`]
+///
+/// `None` for sessions without one and outside handlers run by [`VstpServer`].
+pub fn current_identity() -> Option<String> {
+    IDENTITY.try_with(Option::clone).ok().flatten()
+}
+
+/// Id of the session the current handler is serving, as in its WELCOME's
+/// [`SESSION_ID_HEADER`]
+///
+/// `None` before the session's HELLO and outside handlers run by [`VstpServer`].
+pub fn current_session_id() -> Option<crate::SessionId> {
+    SESSION_ID.try_with(|id| *id).ok().flatten()
+}
+
+/// When the current handler call runs out of time
+///
+/// Set from [`ServerOptions::handler_timeout`] or the route's
+/// [`Router::timeout`], so a handler can pass what is left on to its own
+/// calls. `None` outside handlers run by [`VstpServer`] and for calls
+/// without a time limit.
+pub fn current_deadline() -> Option<tokio::time::Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// Run `call` for at most `limit`, exposing its [`current_deadline`]
+///
+/// On timeout, returns the limit that ran out.
+async fn with_deadline<F: std::future::Future>(
+    limit: Option<Duration>,
+    call: F,
+) -> Result<F::Output, Duration> {
+    // A limit too far out to represent is no limit
+    let Some((limit, deadline)) =
+        limit.and_then(|limit| Some((limit, tokio::time::Instant::now().checked_add(limit)?)))
+    else {
+        return Ok(call.await);
+    };
+    tokio::time::timeout_at(deadline, DEADLINE.scope(deadline, call))
+        .await
+        .map_err(|_| limit)
+}
+
+/// Receive metadata of the request the current handler is serving
+///
+/// Available inside handlers run by [`VstpServer`]; `None` anywhere else.
+/// For a chunked message it describes the chunk that completed it.
+pub fn current_frame_meta() -> Option<FrameMeta> {
+    FRAME_META.try_with(FrameMeta::clone).ok()
+}
+
+/// Options controlling how [`VstpServer`] runs handlers
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Maximum time a single handler call may take.
+    ///
+    /// When it runs out the handler future is dropped, the client receives an
+    /// ERR frame with error code `DeadlineExceeded` and the session carries on.
+    /// A route's [`Router::timeout`] takes its place for that route.
+    pub handler_timeout: Option<Duration>,
+    /// Token clients must present in their HELLO before sending data
+    pub auth_token: Option<String>,
+    /// Highest frame format version to agree to with TCP clients; UDP
+    /// sessions always use version 1
+    pub max_frame_version: u8,
+    /// Largest DATA payload accepted in one frame
+    ///
+    /// Announced in the WELCOME so clients can check before sending. Larger
+    /// frames are answered with an ERR frame with error code `FrameTooLarge`.
+    pub max_message_bytes: Option<usize>,
+    /// Reassemble messages that clients split with [`Frame::chunk_payload`],
+    /// announced as the `frag` capability
+    ///
+    /// A reassembled message may be up to [`MAX_REASSEMBLED_BYTES`] long.
+    pub accept_fragments: bool,
+    /// Most chunked messages a session may have partly received at once;
+    /// 16 by default
+    ///
+    /// A chunk starting one more is answered with an ERR frame with error
+    /// code `RateLimited`.
+    pub max_open_chunked: usize,
+    /// How long a partly received chunked message waits for its next chunk
+    /// before it is dropped; 30 seconds by default
+    ///
+    /// Checked whenever the session receives a chunk, so a session that
+    /// stops sending keeps its pending chunks until it ends.
+    pub chunk_idle_timeout: Duration,
+    /// Request headers copied onto every handler reply, e.g. `tenant-id` or `trace-id`
+    ///
+    /// A header the reply already sets is left alone. Empty by default.
+    pub echo_headers: Vec<String>,
+    /// Tokens accepted in a HELLO's auth token besides `auth_token`, each
+    /// mapped to the identity it authenticates for [`usage`](crate::usage)
+    pub api_keys: HashMap<String, String>,
+    /// Told about every request a handler answered
+    pub usage: Option<Arc<dyn UsageRecorder>>,
+    /// Requests and bytes each identity may use per period
+    pub quota: Option<Quota>,
+    /// Announced in every WELCOME as `server-software`, e.g. `myapp/1.4 (vstp/0.2.0)`
+    ///
+    /// Defaults to `vstp/<crate version>`; `None` leaves the header out.
+    pub server_software: Option<String>,
+    /// Span the server's sessions and handlers run under; none by default
+    ///
+    /// Taken from the options given to [`VstpServer::set_options`]; the
+    /// span in a service's own options is ignored.
+    pub span: Span,
+    /// Run a handler once for requests that share an [`IDEMPOTENCY_KEY_HEADER`]
+    ///
+    /// A request whose key matches one still being handled, on any session
+    /// of the same service, doesn't run the handler: it waits for that
+    /// request's reply and gets a copy, marked with [`COALESCED_HEADER`].
+    /// The key alone decides, payloads aren't compared, so clients must
+    /// derive keys from everything the reply depends on. Once the reply is
+    /// out the key is free again; nothing is cached. Off by default.
+    pub single_flight: bool,
+    /// Keep the replies to requests with an [`IDEMPOTENCY_KEY_HEADER`] here
+    /// and answer the same key from the same identity with them, see
+    /// [`idempotency`](crate::idempotency)
+    ///
+    /// Implies [`single_flight`](Self::single_flight), with keys told apart
+    /// by identity. A router made [`idempotent`](Router::idempotent) uses
+    /// its own store instead.
+    pub idempotency: Option<Arc<dyn IdempotencyStore>>,
+    /// Keep the bytes each request arrived as, for handlers to read from
+    /// [`current_frame_meta`]'s [`raw_bytes`](FrameMeta::raw_bytes)
+    ///
+    /// TCP sessions only. Costs a copy of every frame, so off by default.
+    pub keep_raw_bytes: bool,
+    /// Fill in the WELCOME of each session, e.g. with the server's version,
+    /// a message of the day or feature flags
+    ///
+    /// Called once a HELLO has passed the auth token checks; rejected
+    /// HELLOs never reach it. The payload and headers of the frame it
+    /// returns go out on the WELCOME, except headers the server sets itself
+    /// for the handshake, which win. The session's DATA only reaches the
+    /// handler after the WELCOME is sent. `None` sends an empty WELCOME.
+    pub on_accept: Option<WelcomeProvider>,
+    /// How long a UDP session may go without a frame from its peer before
+    /// it is dropped; 5 minutes by default
+    ///
+    /// Sessions with streamed calls still running are kept. Like
+    /// [`span`](Self::span), taken from the options given to
+    /// [`VstpServer::set_options`].
+    pub udp_session_idle: Duration,
+    /// Most UDP sessions kept at once; 10 000 by default
+    ///
+    /// A peer opening one more drops the session heard from least recently.
+    /// Taken from the options given to [`VstpServer::set_options`].
+    pub max_udp_sessions: usize,
+    /// Hard limits on each UDP session, after which it is closed with an
+    /// ERR `QuotaExceeded` and a BYE; `None` (the default) for no limits
+    ///
+    /// The UDP counterpart of a TCP server's
+    /// [`session_quota`](crate::tcp::TcpServerConfig::session_quota), see
+    /// [`quota`](crate::tcp::quota). Bytes out are the handlers' replies as
+    /// encoded. A new HELLO starts the session, and its quota, afresh.
+    /// Taken from the options given to [`VstpServer::set_options`].
+    pub udp_session_quota: Option<SessionQuota>,
+    /// Key that opens requests sealed to its public half before they reach
+    /// the handler; see [`e2e`](crate::e2e)
+    ///
+    /// Requests that don't open are answered with ERR `BadRequest`. Sealed
+    /// requests reach the handler as they are if there is no key.
+    #[cfg(feature = "e2e")]
+    pub e2e_key: Option<crate::e2e::KeyPair>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            handler_timeout: None,
+            auth_token: None,
+            max_frame_version: VSTP_VERSION_2,
+            max_message_bytes: None,
+            accept_fragments: false,
+            max_open_chunked: 16,
+            chunk_idle_timeout: Duration::from_secs(30),
+            echo_headers: Vec::new(),
+            api_keys: HashMap::new(),
+            usage: None,
+            quota: None,
+            server_software: Some(format!("vstp/{}", env!("CARGO_PKG_VERSION"))),
+            span: Span::none(),
+            single_flight: false,
+            idempotency: None,
+            keep_raw_bytes: false,
+            on_accept: None,
+            udp_session_idle: Duration::from_secs(300),
+            max_udp_sessions: 10_000,
+            udp_session_quota: None,
+            #[cfg(feature = "e2e")]
+            e2e_key: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Whether a HELLO must carry an accepted auth token
+    fn requires_auth(&self) -> bool {
+        self.auth_token.is_some() || !self.api_keys.is_empty()
+    }
+
+    /// Identity a HELLO's auth token maps to, if it is an API key
+    fn identity(&self, hello: &Frame) -> Option<String> {
+        let token = hello.get_header(AUTH_TOKEN_HEADER)?;
+        self.api_keys.get(token).cloned()
+    }
+}
+
+/// A HELLO the server accepted, for [`ServerOptions::on_accept`] to welcome
+#[derive(Debug, Clone)]
+pub struct Accepted {
+    /// Address the session's client connected from
+    pub peer: SocketAddr,
+    /// Identity the HELLO's API key maps to, see [`ServerOptions::api_keys`]
+    pub identity: Option<String>,
+    pub hello: Frame,
+}
+
+type WelcomeFn = Arc<dyn Fn(Accepted) -> BoxFuture<'static, Frame> + Send + Sync>;
+
+/// Builds the WELCOME for each accepted HELLO, see [`ServerOptions::on_accept`]
+#[derive(Clone)]
+pub struct WelcomeProvider(WelcomeFn);
+
+impl WelcomeProvider {
+    pub fn new<F, Fut>(provider: F) -> Self
+    where
+        F: Fn(Accepted) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Frame> + Send + 'static,
+    {
+        Self(Arc::new(move |accepted| Box::pin(provider(accepted))))
+    }
+
+    /// `welcome` with the payload and headers of the provider's frame added
+    async fn fill(&self, welcome: Frame, accepted: Accepted) -> Frame {
+        let provided = (self.0)(accepted).await;
+        let mut welcome = welcome.with_payload(provided.payload);
+        for header in provided.headers {
+            if !welcome.headers.iter().any(|own| own.key == header.key) {
+                welcome.headers.push(header);
+            }
+        }
+        welcome
+    }
+}
+
+impl fmt::Debug for WelcomeProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WelcomeProvider")
+    }
+}
+
+/// Counters maintained while a [`VstpServer`] is serving
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    timed_out_handlers: AtomicU64,
+    quota_closed_udp_sessions: AtomicU64,
+}
+
+impl ServerStats {
+    /// Number of handler calls cancelled by `handler_timeout`
+    pub fn timed_out_handlers(&self) -> u64 {
+        self.timed_out_handlers.load(Ordering::Relaxed)
+    }
+
+    /// Number of UDP sessions closed for using up their
+    /// [`udp_session_quota`](ServerOptions::udp_session_quota)
+    pub fn quota_closed_udp_sessions(&self) -> u64 {
+        self.quota_closed_udp_sessions.load(Ordering::Relaxed)
+    }
+}
+
+/// Header carrying the protocol version a HELLO asks for
+pub const PROTOCOL_VERSION_HEADER: &str = "protocol-version";
+
+/// Header carrying the client's auth token on a HELLO
+pub const AUTH_TOKEN_HEADER: &str = "auth-token";
+
+/// Header naming the service a HELLO's session is for, e.g. `billing`
+///
+/// See [`VstpServer::service`]. Servers without a service of that name use
+/// their default, if they have one.
+pub const SERVICE_HEADER: &str = "service";
+
+/// Header listing the frame format versions a HELLO's sender can use, e.g. `1,2`
+///
+/// The WELCOME's `protocol-version` names the one the server picked. The
+/// HELLO and WELCOME themselves are always version 1 frames.
+pub const SUPPORTED_VERSIONS_HEADER: &str = "supported-versions";
+
+/// Header carrying the server's [`ServerOptions::max_message_bytes`] on a WELCOME
+pub const MAX_MESSAGE_BYTES_HEADER: &str = "max-message-bytes";
+
+/// Header listing the optional features a WELCOME's sender supports, comma-separated
+pub const CAPABILITIES_HEADER: &str = "capabilities";
+
+/// Header naming the implementation behind a WELCOME, see [`ServerOptions::server_software`]
+pub const SERVER_SOFTWARE_HEADER: &str = "server-software";
+
+/// Header listing every frame format version a WELCOME's sender speaks, e.g. `1,2`
+///
+/// Unlike `protocol-version`, which names the version picked for the
+/// session, this doesn't depend on what the HELLO offered.
+pub const VSTP_VERSIONS_HEADER: &str = "vstp-versions";
+
+/// Header naming a request for [`ServerOptions::single_flight`] and
+/// [`ServerOptions::idempotency`], e.g. an order ID
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Header set to `true` on a reply shared from a request with the same
+/// [`IDEMPOTENCY_KEY_HEADER`] that was already in flight
+pub const COALESCED_HEADER: &str = "x-coalesced";
+
+/// Content type of sessions whose HELLO has no `content-type`
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// Capability of servers that reassemble chunked messages
+pub const FRAGMENTATION_CAPABILITY: &str = "frag";
+
+/// Longest message a server with [`ServerOptions::accept_fragments`] reassembles
+pub const MAX_REASSEMBLED_BYTES: usize = 16 * 1024 * 1024;
+
+/// What a server session should do with a received frame
+enum Admission {
+    /// Pass the frame on to the handler
+    Deliver,
+    /// Send this reply instead; `false` means the session should end
+    Reply(Frame, bool),
+}
+
+/// Answer HELLOs and PINGs, and keep unauthenticated peers away from the handler
+fn admit(
+    frame: &Frame,
+    options: &ServerOptions,
+    max_frame_version: u8,
+    authenticated: &mut bool,
+) -> Admission {
+    if frame.typ == FrameType::Ping {
+        let mut pong = Frame::new(FrameType::Pong);
+        pong.headers = frame.headers.clone();
+        return Admission::Reply(stamp_server_time(pong), true);
+    }
+    if frame.typ == FrameType::Hello {
+        let reply = handshake_reply(frame, options, max_frame_version);
+        *authenticated = reply.typ == FrameType::Welcome;
+        return Admission::Reply(reply, *authenticated);
+    }
+    if !*authenticated {
+        return Admission::Reply(
+            Frame::coded_error(ErrorCode::Unauthorized, "handshake required"),
+            false,
+        );
+    }
+    Admission::Deliver
+}
+
+/// WELCOME for an acceptable HELLO, otherwise an ERR explaining why not
+fn handshake_reply(hello: &Frame, options: &ServerOptions, max_frame_version: u8) -> Frame {
+    if let Some(version) = hello.get_header(PROTOCOL_VERSION_HEADER) {
+        if version.parse::<u8>().ok() != Some(VSTP_VERSION) {
+            return Frame::coded_error(
+                ErrorCode::UnsupportedVersion,
+                &format!("requested version {}, server speaks {}", version, VSTP_VERSION),
+            );
+        }
+    }
+    if options.requires_auth() {
+        let token = hello.get_header(AUTH_TOKEN_HEADER);
+        let accepted = token.is_some_and(|token| {
+            options.auth_token.as_deref() == Some(token) || options.api_keys.contains_key(token)
+        });
+        if !accepted {
+            return Frame::coded_error(ErrorCode::Unauthorized, "invalid auth token");
+        }
+    }
+    let versions = VSTP_VERSION..=max_frame_version.min(VSTP_VERSION_2);
+    // The highest version both sides can use; clients that don't say stay on 1
+    let version = hello
+        .get_header(SUPPORTED_VERSIONS_HEADER)
+        .into_iter()
+        .flat_map(|list| list.split(','))
+        .filter_map(|v| v.trim().parse::<u8>().ok())
+        .filter(|v| versions.contains(v))
+        .max()
+        .unwrap_or(VSTP_VERSION);
+    let mut welcome =
+        Frame::new(FrameType::Welcome).with_header(PROTOCOL_VERSION_HEADER, &version.to_string());
+    if let Some(software) = &options.server_software {
+        let versions: Vec<String> = versions.map(|v| v.to_string()).collect();
+        welcome = welcome
+            .with_header(SERVER_SOFTWARE_HEADER, software)
+            .with_header(VSTP_VERSIONS_HEADER, &versions.join(","));
+    }
+    if let Some(limit) = options.max_message_bytes {
+        welcome = welcome.with_header(MAX_MESSAGE_BYTES_HEADER, &limit.to_string());
+    }
+    if options.accept_fragments {
+        welcome = welcome.with_header(CAPABILITIES_HEADER, FRAGMENTATION_CAPABILITY);
+    }
+    stamp_server_time(welcome)
+}
+
+/// The options of each service a server offers, for picking one at HELLO time
+struct Services {
+    named: HashMap<String, ServerOptions>,
+    /// Used by sessions that name no service or an unknown one
+    default: Option<ServerOptions>,
+    sessions: SessionRegistry,
+}
+
+impl Services {
+    /// A server without named services
+    fn single(options: ServerOptions, sessions: SessionRegistry) -> Self {
+        Self {
+            named: HashMap::new(),
+            default: Some(options),
+            sessions,
+        }
+    }
+
+    /// The named service a TLS client's SNI server name picks, if any
+    fn for_server_name(&self, server_name: Option<&str>) -> Option<String> {
+        server_name
+            .filter(|name| self.named.contains_key(*name))
+            .map(str::to_string)
+    }
+
+    /// Name and options of the service `name` selects, `None` if there is none
+    fn select(&self, name: Option<&str>) -> Option<(Option<String>, &ServerOptions)> {
+        match name.and_then(|name| self.named.get_key_value(name)) {
+            Some((name, options)) => Some((Some(name.clone()), options)),
+            None => self.default.as_ref().map(|options| (None, options)),
+        }
+    }
+}
+
+/// Server-side state of one client session
+struct Session {
+    /// Service the HELLO picked; `None` for the default
+    service: Option<String>,
+    /// Service the client's TLS server name picked, which its HELLO can't
+    /// switch away from
+    sni_service: Option<String>,
+    options: ServerOptions,
+    authenticated: bool,
+    /// Identity the HELLO's API key maps to
+    identity: Option<String>,
+    params: Arc<NegotiatedParams>,
+    intake: Intake,
+    /// Cancelled when the session is dropped, see [`current_session_token`]
+    closed: CancellationToken,
+    /// Calls tagged with a [`CALL_ID_HEADER`] whose replies are being
+    /// forwarded, with the token that cancels each
+    calls: HashMap<String, (CancellationToken, tokio::task::JoinHandle<()>)>,
+    /// Id the WELCOME gave the session, see [`migration`](crate::migration)
+    id: Option<crate::SessionId>,
+    extensions: SessionExtensions,
+    registry: SessionRegistry,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.closed.cancel();
+        if let Some(id) = self.id {
+            self.registry.close(id);
+        }
+    }
+}
+
+impl Session {
+    /// A session on the service named by the client's TLS `server_name`, or
+    /// else on the default service until a HELLO picks another
+    ///
+    /// Without either nothing is accepted before the HELLO.
+    fn new(services: &Services, server_name: Option<&str>) -> Self {
+        let sni_service = services.for_server_name(server_name);
+        let selected = services.select(sni_service.as_deref()).map(|(_, options)| options);
+        let options = selected.cloned().unwrap_or_default();
+        Self {
+            service: sni_service.clone(),
+            sni_service,
+            authenticated: selected.is_some() && !options.requires_auth(),
+            identity: None,
+            params: Arc::new(NegotiatedParams::defaults(&options)),
+            intake: Intake::new(&options),
+            options,
+            closed: CancellationToken::new(),
+            calls: HashMap::new(),
+            id: None,
+            extensions: SessionExtensions::default(),
+            registry: services.sessions.clone(),
+        }
+    }
+
+    /// The ERR sending the client on, if the session moved to another instance
+    fn redirect(&mut self) -> Option<Frame> {
+        let to = self.registry.moved_to(self.id?)?;
+        self.authenticated = false;
+        let redirect = Frame::coded_error(ErrorCode::Redirect, &format!("session moved to {}", to));
+        Some(redirect.with_header(REDIRECT_TO_HEADER, &to))
+    }
+
+    /// Give the session an id for `hello`, taking over the restored session
+    /// it claims if there is one, and announce it on `welcome`
+    fn register(&mut self, hello: &Frame, welcome: Frame) -> Frame {
+        if let Some(id) = self.id.take() {
+            self.registry.close(id);
+        }
+        let claimed = hello.get_header(SESSION_ID_HEADER).and_then(parse_session_id);
+        let (id, extensions) = self.registry.open(
+            claimed,
+            self.identity.as_deref(),
+            self.service.as_deref(),
+        );
+        self.id = Some(id);
+        self.extensions = extensions;
+        welcome.with_header(SESSION_ID_HEADER, &format_session_id(id))
+    }
+
+    /// Whether `frame` is a client's [`STREAM_CANCEL_HEADER`] frame, cancelling
+    /// the call it names if its replies are still being forwarded
+    fn cancel_call(&mut self, frame: &Frame) -> bool {
+        if frame.get_header(STREAM_CANCEL_HEADER).is_none() {
+            return false;
+        }
+        let call = frame.get_header(CALL_ID_HEADER).and_then(|id| self.calls.remove(id));
+        if let Some((call, _)) = call {
+            call.cancel();
+        }
+        true
+    }
+
+    /// The id of the call `frame` opens, with the token its handler gets as
+    /// the session's, if it has a [`CALL_ID_HEADER`]
+    ///
+    /// The token is cancelled when the client cancels the call as well as
+    /// when the session ends.
+    fn open_call(&mut self, frame: &Frame) -> Option<(String, CancellationToken)> {
+        let id = frame.get_header(CALL_ID_HEADER)?;
+        Some((id.to_string(), self.closed.child_token()))
+    }
+
+    /// Forward the replies to a call [`open_call`](Session::open_call)
+    /// returned from a spawned task, see [`forward_call`]
+    fn spawn_call<F, Fut>(
+        &mut self,
+        (id, call): (String, CancellationToken),
+        replies: mpsc::Receiver<Frame>,
+        send: F,
+    ) where
+        F: FnMut(Frame) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = bool> + Send + 'static,
+    {
+        let pump = forward_call(id.clone(), call.clone(), self.closed.clone(), replies, send);
+        self.calls.retain(|_, (_, pump)| !pump.is_finished());
+        self.calls.insert(id, (call, spawn_in_span(pump)));
+    }
+
+    /// Whether the replies to any call are still being forwarded
+    fn calls_open(&mut self) -> bool {
+        self.calls.retain(|_, (_, pump)| !pump.is_finished());
+        !self.calls.is_empty()
+    }
+
+    /// Keep the parameters' compression setting in step with the connection's
+    fn note_compression(&mut self, enabled: bool) {
+        if self.params.compression != enabled {
+            Arc::make_mut(&mut self.params).compression = enabled;
+        }
+    }
+
+    /// Switch to the service a HELLO names, then [`admit`] the frame,
+    /// filling in the WELCOME if the service has [`ServerOptions::on_accept`]
+    async fn admit(
+        &mut self,
+        frame: &Frame,
+        services: &Services,
+        udp: bool,
+        peer: SocketAddr,
+    ) -> Admission {
+        if let Some(redirect) = self.redirect() {
+            return Admission::Reply(redirect, false);
+        }
+        if frame.typ == FrameType::Hello {
+            let name = frame.get_header(SERVICE_HEADER);
+            if let (Some(sni), Some(name)) = (&self.sni_service, name) {
+                if name != sni {
+                    self.authenticated = false;
+                    return Admission::Reply(
+                        Frame::coded_error(
+                            ErrorCode::ServiceNotFound,
+                            &format!("no service {:?} at TLS server name {:?}", name, sni),
+                        ),
+                        false,
+                    );
+                }
+            }
+            let name = name.or(self.sni_service.as_deref());
+            let Some((service, options)) = services.select(name) else {
+                self.authenticated = false;
+                return Admission::Reply(
+                    Frame::coded_error(
+                        ErrorCode::ServiceNotFound,
+                        &format!("no service {:?}", name.unwrap_or("")),
+                    ),
+                    false,
+                );
+            };
+            self.service = service;
+            self.options = options.clone();
+            self.identity = options.identity(frame);
+            self.intake = Intake::new(options);
+        }
+        // UDP sessions stay on version 1
+        let max_frame_version = if udp {
+            VSTP_VERSION
+        } else {
+            self.options.max_frame_version
+        };
+        let mut admission = admit(
+            frame,
+            &self.options,
+            max_frame_version,
+            &mut self.authenticated,
+        );
+        if let Admission::Reply(reply, keep_open) = admission {
+            if reply.typ != FrameType::Welcome {
+                return Admission::Reply(reply, keep_open);
+            }
+            let reply = match &self.options.on_accept {
+                Some(provider) => {
+                    let accepted = Accepted {
+                        peer,
+                        identity: self.identity.clone(),
+                        hello: frame.clone(),
+                    };
+                    provider.fill(reply, accepted).await
+                }
+                None => reply,
+            };
+            let reply = self.register(frame, reply);
+            self.params = Arc::new(NegotiatedParams::from_handshake(frame, &reply, self));
+            admission = Admission::Reply(reply, keep_open);
+        }
+        admission
+    }
+}
+
+/// What [`Intake::receive`] made of a DATA frame
+enum Received {
+    /// A whole message for the handler
+    Message(Frame),
+    /// A chunk of a message that isn't complete yet
+    Partial,
+    /// Send this ERR back instead
+    Rejected(Frame),
+}
+
+/// Applies [`ServerOptions::max_message_bytes`] and reassembles chunked messages
+struct Intake {
+    max_message_bytes: Option<usize>,
+    accept_fragments: bool,
+    max_open: usize,
+    idle_timeout: Duration,
+    /// Chunks received so far, by sender and stream id, with when the last arrived
+    pending: HashMap<(SocketAddr, String), (Vec<Frame>, Instant)>,
+    /// Key that opens sealed messages
+    #[cfg(feature = "e2e")]
+    e2e_key: Option<crate::e2e::KeyPair>,
+}
+
+impl Intake {
+    fn new(options: &ServerOptions) -> Self {
+        Self {
+            max_message_bytes: options.max_message_bytes,
+            accept_fragments: options.accept_fragments,
+            max_open: options.max_open_chunked,
+            idle_timeout: options.chunk_idle_timeout,
+            pending: HashMap::new(),
+            #[cfg(feature = "e2e")]
+            e2e_key: options.e2e_key.clone(),
+        }
+    }
+
+    fn receive(&mut self, frame: Frame, peer: SocketAddr) -> Received {
+        match self.gather(frame, peer) {
+            #[cfg(feature = "e2e")]
+            Received::Message(message) if message.is_sealed() => self.open(message),
+            received => received,
+        }
+    }
+
+    /// Open a sealed message, if the server holds a key
+    #[cfg(feature = "e2e")]
+    fn open(&self, message: Frame) -> Received {
+        let Some(key) = &self.e2e_key else {
+            return Received::Message(message);
+        };
+        match message.open(key.secret()) {
+            Ok(message) => Received::Message(message),
+            Err(e) => Received::Rejected(Frame::coded_error(ErrorCode::BadRequest, &e.to_string())),
+        }
+    }
+
+    /// The message `frame` completes, if any
+    fn gather(&mut self, frame: Frame, peer: SocketAddr) -> Received {
+        if let Some(limit) = self.max_message_bytes {
+            if frame.payload.len() > limit {
+                return Received::Rejected(Frame::coded_error(
+                    ErrorCode::FrameTooLarge,
+                    &format!(
+                        "message of {} bytes exceeds limit of {}",
+                        frame.payload.len(),
+                        limit
+                    ),
+                ));
+            }
+        }
+        let stream_id = match frame.get_header(STREAM_ID_HEADER) {
+            Some(id) if self.accept_fragments => id.to_string(),
+            _ => return Received::Message(frame),
+        };
+
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        self.pending
+            .retain(|_, (_, last)| now.saturating_duration_since(*last) < idle_timeout);
+        let key = (peer, stream_id);
+        if !self.pending.contains_key(&key) && self.pending.len() >= self.max_open {
+            return Received::Rejected(Frame::coded_error(
+                ErrorCode::RateLimited,
+                &format!("{} chunked messages already open", self.max_open),
+            ));
+        }
+        let (chunks, last) = self.pending.entry(key.clone()).or_insert((Vec::new(), now));
+        *last = now;
+        chunks.push(frame);
+        let buffered: usize = chunks.iter().map(|c| c.payload.len()).sum();
+        if buffered > MAX_REASSEMBLED_BYTES {
+            self.pending.remove(&key);
+            return Received::Rejected(Frame::coded_error(
+                ErrorCode::FrameTooLarge,
+                &format!("chunked message exceeds limit of {}", MAX_REASSEMBLED_BYTES),
+            ));
+        }
+        let total: usize = chunks[0]
+            .get_header(TOTAL_HEADER)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if chunks.len() < total {
+            return Received::Partial;
+        }
+
+        let chunks = self
+            .pending
+            .remove(&key)
+            .map(|(chunks, _)| chunks)
+            .unwrap_or_default();
+        match Frame::reassemble(&chunks) {
+            Ok(payload) => {
+                let mut message = chunks[0].clone();
+                message.headers.retain(|h| {
+                    ![
+                        STREAM_ID_HEADER,
+                        SEQ_HEADER,
+                        TOTAL_HEADER,
+                        FIN_HEADER,
+                        CHUNK_HASH_HEADER,
+                        PAYLOAD_HASH_HEADER,
+                    ]
+                    .iter()
+                    .any(|k| h.key == k.as_bytes())
+                });
+                message.payload = payload;
+                Received::Message(message)
+            }
+            Err(e) => Received::Rejected(Frame::coded_error(
+                ErrorCode::MalformedFrame,
+                &e.to_string(),
+            )),
+        }
+    }
+}
+
+impl VstpServer {
+    fn from_inner(inner: ServerType) -> Self {
+        let (tx, rx) = mpsc::channel(100);
+        Self {
+            inner,
+            message_tx: tx,
+            message_rx: rx,
+            timeout: DEFAULT_TIMEOUT,
+            options: ServerOptions::default(),
+            stats: Arc::new(ServerStats::default()),
+            services: HashMap::new(),
+            sessions: SessionRegistry::default(),
+        }
+    }
+
+    /// Create a new TCP server, serving plain TCP unless given
+    /// [`with_tls`](VstpServer::with_tls)
+    pub async fn bind_tcp(addr: impl Into<String>) -> Result<Self, VstpError> {
+        let addr_str = addr.into();
+        let server = crate::tcp::VstpTcpServer::bind(&addr_str).await?;
+        Ok(Self::from_inner(ServerType::Tcp(Box::new(server))))
+    }
+
+    /// Create a new TCP server serving TLS with `config`
+    #[cfg(feature = "tls")]
+    pub async fn bind_tcp_tls(
+        addr: impl Into<String>,
+        config: impl Into<Arc<rustls::ServerConfig>>,
+    ) -> Result<Self, VstpError> {
+        Self::bind_tcp(addr).await?.with_tls(config)
+    }
+
+    /// Create a new UDP server
+    pub async fn bind_udp(addr: impl Into<String>) -> Result<Self, VstpError> {
+        let addr_str = addr.into();
+        let server = crate::udp::VstpUdpServer::bind(&addr_str).await?;
+        Ok(Self::from_inner(ServerType::Udp(Box::new(server))))
+    }
+
+    /// Serve TCP on an already bound listener, e.g. one passed in by systemd socket activation
+    ///
+    /// See [`VstpTcpServer::from_std_listener`](crate::tcp::VstpTcpServer::from_std_listener).
+    pub fn from_tcp_listener(listener: std::net::TcpListener) -> Result<Self, VstpError> {
+        let server = crate::tcp::VstpTcpServer::from_std_listener(listener)?;
+        Ok(Self::from_inner(ServerType::Tcp(Box::new(server))))
+    }
+
+    /// Serve UDP on an already bound socket
+    ///
+    /// See [`VstpUdpServer::from_std_socket`](crate::udp::VstpUdpServer::from_std_socket).
+    pub fn from_udp_socket(socket: std::net::UdpSocket) -> Result<Self, VstpError> {
+        let server = crate::udp::VstpUdpServer::from_std_socket(socket)?;
+        Ok(Self::from_inner(ServerType::Udp(Box::new(server))))
+    }
+
+    pub async fn bind_auto(addr: impl Into<String>) -> Result<Self, VstpError> {
+        Self::bind_auto_with_config(addr, AutoSwitchConfig::default()).await
+    }
+
+    pub async fn bind_auto_with_config(
+        addr: impl Into<String>,
+        cfg: AutoSwitchConfig,
+    ) -> Result<Self, VstpError> {
+        let addr_str = addr.into();
+        let tcp = crate::tcp::VstpTcpServer::bind(&addr_str).await?;
+        let udp = crate::udp::VstpUdpServer::bind(&addr_str).await?;
+        Ok(Self::from_inner(ServerType::Auto(AutoServerInner {
+            tcp: Arc::new(tcp),
+            udp: Arc::new(udp),
+            cfg,
+            peer_preference: Arc::new(Mutex::new(HashMap::new())),
+        })))
+    }
+
+    /// Serve TCP sessions over TLS with `config`, see [`tls`](crate::tcp::tls)
+    ///
+    /// UDP traffic of a UDP or auto server stays as it is. Fails with
+    /// [`VstpError::Tls`] for a config the TCP server refuses, see
+    /// [`VstpTcpServer::set_tls`](crate::tcp::VstpTcpServer::set_tls).
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        self,
+        config: impl Into<Arc<rustls::ServerConfig>>,
+    ) -> Result<Self, VstpError> {
+        let config = Some(config.into());
+        match &self.inner {
+            ServerType::Tcp(server) => server.set_tls(config)?,
+            ServerType::Auto(auto) => auto.tcp.set_tls(config)?,
+            ServerType::Udp(_) => {}
+        }
+        Ok(self)
+    }
+
+    /// Set operation timeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set handler options
+    pub fn set_options(&mut self, options: ServerOptions) {
+        self.options = options;
+    }
+
+    /// Offer `router` as the service `name`, under the server's options
+    ///
+    /// Clients pick a service with the `service` header of their HELLO, see
+    /// [`VstpClient::connect_tcp_service`], and the whole session is
+    /// dispatched through its router. Serve with [`VstpServer::serve_services`]
+    /// or, to have a default, [`VstpServer::serve_router`].
+    ///
+    /// Over TLS, a client whose SNI server name is `name` is put on this
+    /// service as soon as it connects, and a HELLO naming another service is
+    /// refused with ERR `ServiceNotFound`.
+    pub fn service(self, name: impl Into<String>, router: Router) -> Self {
+        self.add_service(name.into(), router, None)
+    }
+
+    /// Offer `router` as the service `name`, with its own auth token, limits
+    /// and handler timeout
+    pub fn service_with_options(
+        self,
+        name: impl Into<String>,
+        router: Router,
+        options: ServerOptions,
+    ) -> Self {
+        self.add_service(name.into(), router, Some(options))
+    }
+
+    fn add_service(mut self, name: String, router: Router, options: Option<ServerOptions>) -> Self {
+        self.services.insert(name, Service { router, options });
+        self
+    }
+
+    /// Counters for this server, readable while it is serving
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    /// The server's sessions, to move them to another instance, see
+    /// [`migration`](crate::migration)
+    pub fn sessions(&self) -> SessionRegistry {
+        self.sessions.clone()
+    }
+
+    /// Start the server and handle incoming messages with the provided handler
+    ///
+    /// Each TCP session's requests are handled one at a time, in the order
+    /// they arrived; different sessions are handled concurrently.
+    pub async fn serve<F, Fut, T, R>(mut self, handler: F) -> Result<(), VstpError>
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<R, VstpError>> + Send,
+        T: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let validate: PayloadCheck = Arc::new(|payload: &[u8]| {
+            serde_json::from_slice::<T>(payload)
+                .map(drop)
+                .map_err(|e| e.to_string())
+        });
+        spawn_transports(
+            self.inner,
+            self.message_tx.clone(),
+            self.timeout,
+            Arc::new(Services::single(self.options.clone(), self.sessions.clone())),
+            validate,
+            &self.options,
+            self.stats.clone(),
+        );
+
+        let echo: Arc<[String]> = self.options.echo_headers.clone().into();
+        let meter = Arc::new(Meter::new(self.options.usage.clone(), self.options.quota));
+        let flights = single_flight(&self.options);
+        while let Some(msg) = self.message_rx.recv().await {
+            if over_quota(&meter, &msg, &echo) {
+                continue;
+            }
+            let handler = handler.clone();
+            let handler_timeout = self.options.handler_timeout;
+            let stats = self.stats.clone();
+            let echo = echo.clone();
+            let meter = meter.clone();
+            let flights = flights.clone();
+            let handle = async move {
+                let Ok(data) = serde_json::from_slice::<T>(msg.frame.payload()) else {
+                    return;
+                };
+                let started = handler_start(&msg);
+                let run = async {
+                    let call = in_context(&msg, handler(data));
+                    match with_deadline(handler_timeout, call).await {
+                        Ok(Ok(response)) => {
+                            serde_json::to_vec(&response).ok().map(|response_data| {
+                                Frame::new(FrameType::Data).with_payload(response_data)
+                            })
+                        }
+                        Ok(Err(_)) => None,
+                        Err(limit) => Some(deadline_exceeded(
+                            &stats,
+                            msg.client_addr,
+                            msg.frame.get_header(METHOD_HEADER),
+                            limit,
+                        )),
+                    }
+                };
+                let reply = coalesce(flights.as_ref(), &msg, run).await;
+                let bytes_out = reply.as_ref().map_or(0, |reply| reply.payload.len());
+                meter_reply(&meter, &msg, bytes_out, started);
+                if let Some(reply) = reply {
+                    let reply = echo_headers(&msg.frame, reply, &echo);
+                    let _ = msg.response_tx.send(reply).await;
+                }
+            };
+            tokio::spawn(handle.instrument(self.options.span.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Start the server and dispatch incoming requests through `router`
+    ///
+    /// Every request gets a reply: the handler's response, or an ERR frame
+    /// if the method is unknown or the handler failed. Sessions whose HELLO
+    /// names a registered [`service`](VstpServer::service) use its router
+    /// instead.
+    pub async fn serve_router(self, router: Router) -> Result<(), VstpError> {
+        self.dispatch(Some(router)).await
+    }
+
+    /// Start the server and dispatch each session through the router of the
+    /// service its HELLO names
+    ///
+    /// HELLOs naming no registered service are answered with an ERR frame
+    /// with error code `ServiceNotFound`.
+    pub async fn serve_services(self) -> Result<(), VstpError> {
+        self.dispatch(None).await
+    }
+
+    /// Serve the registered services, and `default` to sessions that pick none
+    async fn dispatch(mut self, default: Option<Router>) -> Result<(), VstpError> {
+        let mut services = Services {
+            named: HashMap::new(),
+            default: default.as_ref().map(|_| self.options.clone()),
+            sessions: self.sessions.clone(),
+        };
+        let mut routes = HashMap::new();
+        if let Some(router) = default {
+            routes.insert(None, Arc::new(Endpoint::new(router, self.options.clone())));
+        }
+        for (name, service) in self.services.drain() {
+            let options = service.options.unwrap_or_else(|| self.options.clone());
+            routes.insert(
+                Some(name.clone()),
+                Arc::new(Endpoint::new(service.router, options.clone())),
+            );
+            services.named.insert(name, options);
+        }
+        spawn_transports(
+            self.inner,
+            self.message_tx.clone(),
+            self.timeout,
+            Arc::new(services),
+            Arc::new(|_: &[u8]| Ok(())),
+            &self.options,
+            self.stats.clone(),
+        );
+
+        while let Some(msg) = self.message_rx.recv().await {
+            let Some(endpoint) = routes.get(&msg.service).cloned() else {
+                continue;
+            };
+            if over_quota(&endpoint.meter, &msg, &endpoint.options.echo_headers) {
+                continue;
+            }
+            let stats = self.stats.clone();
+            if let Some(frames) = endpoint.router.handle_stream(&msg.frame) {
+                let pump = stream_replies(endpoint, msg, frames, stats);
+                tokio::spawn(pump.instrument(self.options.span.clone()));
+                continue;
+            }
+            let handle = async move {
+                let started = handler_start(&msg);
+                let run = async {
+                    let router = &endpoint.router;
+                    let call = in_context(&msg, router.handle_once(&msg.frame));
+                    let limit = router.timeout_for(&msg.frame, endpoint.options.handler_timeout);
+                    Some(match with_deadline(limit, call).await {
+                        Ok(reply) => reply,
+                        Err(limit) => deadline_exceeded(
+                            &stats,
+                            msg.client_addr,
+                            router.method_of(&msg.frame),
+                            limit,
+                        ),
+                    })
+                };
+                // Only a leader that panicked leaves its followers without a reply
+                let Some(reply) = coalesce(endpoint.flights.as_ref(), &msg, run).await else {
+                    return;
+                };
+                meter_reply(&endpoint.meter, &msg, reply.payload.len(), started);
+                let reply = echo_headers(&msg.frame, reply, &endpoint.options.echo_headers);
+                let _ = msg.response_tx.send(reply).await;
+            };
+            tokio::spawn(handle.instrument(self.options.span.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+/// A router with the options, meter and requests in flight of the service it serves
+struct Endpoint {
+    router: Router,
+    options: ServerOptions,
+    meter: Meter,
+    flights: Option<Arc<SingleFlight>>,
+}
+
+impl Endpoint {
+    fn new(router: Router, options: ServerOptions) -> Self {
+        // The router's own store takes the place of the service's
+        let flights = router.idempotency().cloned();
+        Self {
+            router,
+            meter: Meter::new(options.usage.clone(), options.quota),
+            flights: flights.or_else(|| single_flight(&options)),
+            options,
+        }
+    }
+}
+
+/// What serves [`ServerOptions::single_flight`] and
+/// [`ServerOptions::idempotency`] for a service, if either is set
+fn single_flight(options: &ServerOptions) -> Option<Arc<SingleFlight>> {
+    let enabled = options.single_flight || options.idempotency.is_some();
+    enabled.then(|| Arc::new(SingleFlight::new(options.idempotency.clone())))
+}
+
+/// Run `call` for `msg`, or share the reply of the request with its
+/// idempotency key that is already running or, with a store, has finished
+async fn coalesce<F>(
+    flights: Option<&Arc<SingleFlight>>,
+    msg: &ServerMessage,
+    call: F,
+) -> Option<Frame>
+where
+    F: std::future::Future<Output = Option<Frame>>,
+{
+    let Some(flights) = flights else {
+        return call.await;
+    };
+    let identity = msg.identity.as_deref().unwrap_or(ANONYMOUS);
+    flights.run(identity, &msg.frame, call).await
+}
+
+/// Count `msg` against its sender's quota, answering it right away if it is over
+fn over_quota(meter: &Meter, msg: &ServerMessage, echo: &[String]) -> bool {
+    let identity = msg.identity.as_deref().unwrap_or(ANONYMOUS);
+    let bytes_in = msg.frame.payload.len() as u64;
+    let Err(reason) = meter.admit(identity, bytes_in, SystemTime::now()) else {
+        return false;
+    };
+    let reply = Frame::coded_error(ErrorCode::QuotaExceeded, &reason);
+    // The channel is fresh and holds one frame, so this can't fail for lack of room
+    let _ = msg
+        .response_tx
+        .try_send(echo_headers(&msg.frame, reply, echo));
+    true
+}
+
+/// Note when the handler for `msg` starts, logging how long the message waited for it
+fn handler_start(msg: &ServerMessage) -> Instant {
+    let started = Instant::now();
+    tracing::debug!(
+        "Handling request from {} after {:?} in queue",
+        msg.client_addr,
+        started.saturating_duration_since(msg.meta.received_at)
+    );
+    started
+}
+
+/// Report a handled request, and the size of its replies' payloads, to `meter`
+fn meter_reply(meter: &Meter, msg: &ServerMessage, bytes_out: usize, started: Instant) {
+    meter.finish(
+        msg.identity.as_deref().unwrap_or(ANONYMOUS),
+        msg.frame.get_header(METHOD_HEADER).unwrap_or_default(),
+        msg.frame.payload.len() as u64,
+        bytes_out as u64,
+        started.saturating_duration_since(msg.meta.received_at),
+        started.elapsed(),
+    );
+}
+
+/// Send a streaming route's `frames` to the session of `msg` as they come
+///
+/// The route's [`Router::timeout`], or else `handler_timeout`, bounds the
+/// wait for each frame. The stream is dropped as soon as the session ends.
+async fn stream_replies(
+    endpoint: Arc<Endpoint>,
+    msg: ServerMessage,
+    mut frames: BoxStream<'static, Frame>,
+    stats: Arc<ServerStats>,
+) {
+    let started = handler_start(&msg);
+    let mut bytes_out = 0;
+    let router = &endpoint.router;
+    let handler_timeout = router.timeout_for(&msg.frame, endpoint.options.handler_timeout);
+    let pump = async {
+        loop {
+            let next = match handler_timeout {
+                Some(limit) => tokio::time::timeout(limit, frames.next())
+                    .await
+                    .map_err(|_| limit),
+                None => Ok(frames.next().await),
+            };
+            let (frame, last) = match next {
+                Ok(Some(frame)) => (frame, false),
+                Ok(None) => break,
+                Err(limit) => {
+                    let method = router.method_of(&msg.frame);
+                    let reply = deadline_exceeded(&stats, msg.client_addr, method, limit);
+                    let id = msg.frame.get_header(CALL_ID_HEADER).unwrap_or_default();
+                    (reply.with_header(CALL_ID_HEADER, id), true)
+                }
+            };
+            bytes_out += frame.payload.len();
+            let frame = echo_headers(&msg.frame, frame, &endpoint.options.echo_headers);
+            if msg.response_tx.send(frame).await.is_err() || last {
+                break;
+            }
+        }
+    };
+    tokio::select! {
+        _ = in_context(&msg, pump) => {}
+        _ = msg.closed.cancelled() => {}
+    }
+    meter_reply(&endpoint.meter, &msg, bytes_out, started);
+}
+
+/// Calls made with [`VstpClient::call_server_stream`] whose streams were
+/// dropped before their end, by [`CALL_ID_HEADER`]
+#[derive(Default)]
+struct AbandonedCalls(std::sync::Mutex<HashSet<String>>);
+
+impl AbandonedCalls {
+    /// Whether `frame` belongs to an abandoned call, forgetting the call
+    /// once its last frame is seen
+    fn skip(&self, frame: &Frame) -> bool {
+        let Some(id) = frame.get_header(CALL_ID_HEADER) else {
+            return false;
+        };
+        let mut calls = self.0.lock().unwrap();
+        if !calls.contains(id) {
+            return false;
+        }
+        if frame.typ == FrameType::Err || frame.get_header(STREAM_END_HEADER).is_some() {
+            calls.remove(id);
+        }
+        true
+    }
+}
+
+/// A call in progress for [`VstpClient::call_server_stream`]
+struct StreamedCall<'a> {
+    client: &'a VstpClient,
+    call_id: String,
+    /// The request until it is sent
+    request: Option<Result<Frame, VstpError>>,
+    /// No more items are to be yielded
+    done: bool,
+    /// The server's last frame for the call has arrived
+    finished: bool,
+}
+
+impl StreamedCall<'_> {
+    /// The next response frame, `None` once the stream is over
+    async fn next(&mut self) -> Option<Result<Frame, VstpError>> {
+        if self.done {
+            return None;
+        }
+        let client = self.client;
+        let result = client.guarded(self.step()).await;
+        if result.is_err() {
+            self.done = true;
+        }
+        result.transpose()
+    }
+
+    async fn step(&mut self) -> Result<Option<Frame>, VstpError> {
+        if let Some(request) = self.request.take() {
+            // Nothing of a call that didn't go out is coming back
+            self.finished = true;
+            self.client.backoff.ready().await?;
+            self.client.send_fitted(request?, false).await?;
+            self.finished = false;
+        }
+        loop {
+            let frame = self.client.next_frame(false).await?;
+            match frame.get_header(CALL_ID_HEADER) {
+                Some(id) if id != self.call_id => continue,
+                Some(_) => {}
+                // A plain reply, which is the whole answer
+                None => self.done = true,
+            }
+            if frame.typ == FrameType::Err {
+                self.done = true;
+                self.finished = true;
+                return Err(self.client.reply_error(&frame));
+            }
+            if frame.get_header(STREAM_END_HEADER).is_some() {
+                self.done = true;
+                self.finished = true;
+                return Ok(None);
+            }
+            self.finished |= self.done;
+            return Ok(Some(frame));
+        }
+    }
+}
+
+impl Drop for StreamedCall<'_> {
+    /// Skip the rest of an unfinished call and ask the server to stop it,
+    /// without waiting for the request to go out
+    fn drop(&mut self) {
+        if self.request.is_some() || self.finished {
+            return;
         }
-        Ok(())
+        let cancel = Frame::new(FrameType::Data)
+            .with_header(CALL_ID_HEADER, &self.call_id)
+            .with_header(STREAM_CANCEL_HEADER, "true");
+        let mut calls = self.client.abandoned_calls.0.lock().unwrap();
+        calls.insert(std::mem::take(&mut self.call_id));
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        // Weak, so the last client handle still says BYE when it goes
+        let inner = Arc::downgrade(&self.client.inner);
+        let server_addr = self.client.server_addr;
+        runtime.spawn(async move {
+            if let Some(inner) = inner.upgrade() {
+                let _ = send_control(&mut *inner.lock().await, cancel, server_addr).await;
+            }
+        });
     }
 }
 
-/// A simplified server that handles connections and message routing
-pub struct VstpServer {
-    inner: ServerType,
-    message_tx: mpsc::Sender<ServerMessage>,
-    message_rx: mpsc::Receiver<ServerMessage>,
-    timeout: Duration,
+/// Checks a request payload before it is queued, returning why it was rejected
+type PayloadCheck = Arc<dyn Fn(&[u8]) -> Result<(), String> + Send + Sync>;
+
+/// Spawn `task` under the span current where it's spawned
+fn spawn_in_span<F>(task: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(task.in_current_span())
 }
 
-enum ServerType {
-    Tcp(crate::tcp::VstpTcpServer),
-    Udp(crate::udp::VstpUdpServer),
-    Auto(AutoServerInner),
+/// The sessions of a UDP transport, by peer address
+///
+/// Drops sessions idle for longer than [`ServerOptions::udp_session_idle`],
+/// and the least recently active one when a new peer would go over
+/// [`ServerOptions::max_udp_sessions`].
+struct UdpSessions {
+    sessions: HashMap<SocketAddr, UdpSession>,
+    idle: Duration,
+    max: usize,
+    last_sweep: Instant,
+    quota: Option<SessionQuota>,
+    stats: Arc<ServerStats>,
 }
 
-struct AutoServerInner {
-    tcp: Arc<crate::tcp::VstpTcpServer>,
-    udp: Arc<crate::udp::VstpUdpServer>,
-    cfg: AutoSwitchConfig,
-    peer_preference: Arc<Mutex<HashMap<SocketAddr, PeerPreference>>>,
+/// A UDP peer's session, with when it was last heard from and its usage of
+/// [`ServerOptions::udp_session_quota`]
+struct UdpSession {
+    session: Session,
+    seen: Instant,
+    quota: Option<QuotaTracker>,
+    sent: Option<SentBytes>,
 }
 
-#[derive(Clone, Copy)]
-struct PeerPreference {
-    transport: TransportKind,
-    last_seen: Instant,
+impl UdpSession {
+    fn new(session: Session, quota: Option<SessionQuota>, now: Instant) -> Self {
+        Self {
+            session,
+            seen: now,
+            quota: quota.map(QuotaTracker::new),
+            sent: quota.map(|quota| SentBytes {
+                bytes: Arc::new(AtomicU64::new(0)),
+                max: quota.max_bytes_out,
+            }),
+        }
+    }
+
+    /// Count a frame `wire_len` bytes long from the peer, or name the limit
+    /// of the quota that is used up
+    fn charge(&mut self, wire_len: usize) -> Result<(), &'static str> {
+        let Some(quota) = &mut self.quota else {
+            return Ok(());
+        };
+        quota.received(wire_len);
+        match quota.exceeded(self.sent.as_ref().map_or(0, SentBytes::get)) {
+            Some(limit) => Err(limit),
+            None => Ok(()),
+        }
+    }
+
+    fn quota_remaining(&self) -> Option<QuotaRemaining> {
+        let bytes_out = self.sent.as_ref().map_or(0, SentBytes::get);
+        self.quota.as_ref().map(|quota| quota.remaining(bytes_out))
+    }
 }
 
-struct ServerMessage {
-    data: Vec<u8>,
-    _client_addr: SocketAddr,
-    response_tx: mpsc::Sender<Vec<u8>>,
+/// Reply bytes sent to a UDP session with a quota, shared with the tasks
+/// forwarding its streamed calls
+#[derive(Clone)]
+struct SentBytes {
+    bytes: Arc<AtomicU64>,
+    /// The quota's `max_bytes_out`
+    max: Option<u64>,
 }
 
-impl VstpServer {
-    /// Create a new TCP server with automatic TLS
-    pub async fn bind_tcp(addr: impl Into<String>) -> Result<Self, VstpError> {
-        let addr_str = addr.into();
-        let server = crate::tcp::VstpTcpServer::bind(&addr_str).await?;
-        let (tx, rx) = mpsc::channel(100);
-        Ok(Self {
-            inner: ServerType::Tcp(server),
-            message_tx: tx,
-            message_rx: rx,
-            timeout: DEFAULT_TIMEOUT,
-        })
+impl SentBytes {
+    fn get(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
     }
+}
 
-    /// Create a new UDP server
-    pub async fn bind_udp(addr: impl Into<String>) -> Result<Self, VstpError> {
-        let addr_str = addr.into();
-        let server = crate::udp::VstpUdpServer::bind(&addr_str).await?;
-        let (tx, rx) = mpsc::channel(100);
-        Ok(Self {
-            inner: ServerType::Udp(server),
-            message_tx: tx,
-            message_rx: rx,
-            timeout: DEFAULT_TIMEOUT,
-        })
+/// Send a handler's `reply` to the UDP session at `addr`, counting it
+/// against the session's quota if it has one
+///
+/// Once the quota's `max_bytes_out` is used up nothing more is sent; the
+/// session is closed when its peer sends its next frame.
+async fn send_reply(
+    server: &crate::udp::VstpUdpServer,
+    reply: Frame,
+    addr: SocketAddr,
+    sent: Option<&SentBytes>,
+) {
+    let Some(sent) = sent else {
+        let _ = server.send(reply, addr).await;
+        return;
+    };
+    if sent.max.is_some_and(|max| sent.get() >= max) {
+        return;
+    }
+    if let Ok(bytes) = server.send_sized(reply, addr).await {
+        sent.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
     }
+}
 
-    pub async fn bind_auto(addr: impl Into<String>) -> Result<Self, VstpError> {
-        Self::bind_auto_with_config(addr, AutoSwitchConfig::default()).await
+impl UdpSessions {
+    fn new(options: &ServerOptions, stats: Arc<ServerStats>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            idle: options.udp_session_idle,
+            max: options.max_udp_sessions.max(1),
+            last_sweep: Instant::now(),
+            quota: options.udp_session_quota,
+            stats,
+        }
     }
 
-    pub async fn bind_auto_with_config(
-        addr: impl Into<String>,
-        cfg: AutoSwitchConfig,
-    ) -> Result<Self, VstpError> {
-        let addr_str = addr.into();
-        let tcp = crate::tcp::VstpTcpServer::bind(&addr_str).await?;
-        let udp = crate::udp::VstpUdpServer::bind(&addr_str).await?;
-        let (tx, rx) = mpsc::channel(100);
-        Ok(Self {
-            inner: ServerType::Auto(AutoServerInner {
-                tcp: Arc::new(tcp),
-                udp: Arc::new(udp),
-                cfg,
-                peer_preference: Arc::new(Mutex::new(HashMap::new())),
-            }),
-            message_tx: tx,
-            message_rx: rx,
-            timeout: DEFAULT_TIMEOUT,
-        })
+    /// The session of `addr`, opened if it has none, marked as active
+    fn get(&mut self, addr: SocketAddr, services: &Services) -> &mut UdpSession {
+        let now = Instant::now();
+        // Sweeping walks every session, so at most twice per idle period
+        if now.saturating_duration_since(self.last_sweep) >= self.idle / 2 {
+            self.last_sweep = now;
+            let idle = self.idle;
+            self.sessions.retain(|_, entry| {
+                now.saturating_duration_since(entry.seen) <= idle || entry.session.calls_open()
+            });
+        }
+        if !self.sessions.contains_key(&addr) && self.sessions.len() >= self.max {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, entry)| entry.seen)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+        let quota = self.quota;
+        let entry = self
+            .sessions
+            .entry(addr)
+            .or_insert_with(|| UdpSession::new(Session::new(services, None), quota, now));
+        entry.seen = now;
+        entry
     }
 
-    /// Set operation timeout
-    pub fn set_timeout(&mut self, timeout: Duration) {
-        self.timeout = timeout;
+    fn remove(&mut self, addr: &SocketAddr) {
+        self.sessions.remove(addr);
     }
 
-    /// Start the server and handle incoming messages with the provided handler
-    pub async fn serve<F, Fut, T, R>(mut self, handler: F) -> Result<(), VstpError>
-    where
-        F: Fn(T) -> Fut + Send + Sync + 'static,
-        Fut: std::future::Future<Output = Result<R, VstpError>> + Send,
-        T: DeserializeOwned + Send + 'static,
-        R: Serialize + Send + 'static,
-    {
-        let handler = Arc::new(handler);
+    /// Drop the session of `addr` for using up the `limit` of its quota,
+    /// sending the ERR and BYE that say so
+    async fn close_for_quota(
+        &mut self,
+        server: &crate::udp::VstpUdpServer,
+        addr: SocketAddr,
+        limit: &str,
+    ) {
+        self.sessions.remove(&addr);
+        self.stats
+            .quota_closed_udp_sessions
+            .fetch_add(1, Ordering::Relaxed);
+        for frame in farewell(&DisconnectReason::QuotaExceeded(limit.to_string())) {
+            let _ = server.send(frame, addr).await;
+        }
+    }
+}
 
-        match self.inner {
-            ServerType::Tcp(server) => {
-                let tx = self.message_tx.clone();
-                let timeout = self.timeout;
+/// Forward the replies to call `id` through `send`, which returns whether
+/// the session still takes them
+///
+/// Stops early once `call` is cancelled. If it wasn't for `session` ending,
+/// the client cancelled the call, which then gets a [`STREAM_END_HEADER`]
+/// frame so the client knows nothing more of it is coming.
+async fn forward_call<F, Fut>(
+    id: String,
+    call: CancellationToken,
+    session: CancellationToken,
+    mut replies: mpsc::Receiver<Frame>,
+    mut send: F,
+) where
+    F: FnMut(Frame) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    loop {
+        let reply = tokio::select! {
+            reply = replies.recv() => reply,
+            _ = call.cancelled() => break,
+        };
+        let Some(reply) = reply else {
+            return;
+        };
+        if !send(reply).await {
+            return;
+        }
+    }
+    if !session.is_cancelled() {
+        let end = Frame::new(FrameType::Data)
+            .with_header(STREAM_END_HEADER, "true")
+            .with_header(CALL_ID_HEADER, &id);
+        send(end).await;
+    }
+}
 
-                tokio::spawn(async move {
-                    loop {
-                        let mut client = server.accept().await?;
-                        let tx = tx.clone();
+/// The next request on a TCP session, sending the replies `outbox` holds
+/// for its calls while waiting
+///
+/// `None` once the connection is closed or fails.
+async fn next_request(
+    client: &mut crate::tcp::VstpTcpConnection,
+    outbox: &mut mpsc::Receiver<Frame>,
+    calls_open: bool,
+) -> Option<(Frame, FrameMeta)> {
+    loop {
+        while let Ok(reply) = outbox.try_recv() {
+            client.send(reply).await.ok()?;
+        }
+        // Only race the read against replies while calls may still send some
+        if !calls_open {
+            return client.recv_with_meta().await.ok().flatten();
+        }
+        tokio::select! {
+            Some(reply) = outbox.recv() => client.send(reply).await.ok()?,
+            next = client.recv_with_meta() => return next.ok().flatten(),
+        }
+    }
+}
+
+/// Accept frames on every transport of `inner` and queue them for the dispatcher
+///
+/// On TCP and UDP, payloads that `validate` rejects are answered right away.
+/// Every task runs under the span of `options`, which also bound the UDP sessions.
+fn spawn_transports(
+    inner: ServerType,
+    tx: mpsc::Sender<ServerMessage>,
+    timeout: Duration,
+    services: Arc<Services>,
+    validate: PayloadCheck,
+    options: &ServerOptions,
+    stats: Arc<ServerStats>,
+) {
+    let _entered = options.span.enter();
+    match inner {
+        ServerType::Tcp(server) => {
+            spawn_in_span(async move {
+                loop {
+                    let mut client = server.accept().await?;
+                    let tx = tx.clone();
+                    let services = services.clone();
+                    let validate = validate.clone();
 
-                        tokio::spawn(async move {
-                            while let Ok(Some(frame)) = client.recv().await {
-                                if frame.get_header("x-auto-probe") == Some("1") {
+                    spawn_in_span(async move {
+                        let mut session = Session::new(&services, client.tls_server_name());
+                        client.set_keep_raw_bytes(session.options.keep_raw_bytes);
+                        let (outbox_tx, mut outbox) = mpsc::channel(16);
+                        'frames: while let Some((frame, meta)) =
+                            next_request(&mut client, &mut outbox, session.calls_open()).await
+                        {
+                            if frame.get_header("x-auto-probe") == Some("1") {
+                                continue;
+                            }
+                            let admission = session
+                                .admit(&frame, &services, false, client.peer_addr())
+                                .await;
+                            match admission {
+                                Admission::Deliver => {}
+                                Admission::Reply(reply, keep_open) => {
+                                    let welcome = reply.typ == FrameType::Welcome;
+                                    let version = negotiated_version(&reply);
+                                    if client.send(reply).await.is_err() || !keep_open {
+                                        break;
+                                    }
+                                    if welcome {
+                                        client.set_frame_version(version);
+                                        client.set_keep_raw_bytes(session.options.keep_raw_bytes);
+                                    }
                                     continue;
                                 }
-                                let (response_tx, mut response_rx) = mpsc::channel(1);
-
-                                // Try to deserialize and handle the message
-                                match serde_json::from_slice::<T>(&frame.payload()) {
-                                    Ok(_data) => {
-                                        if let Err(_) = tokio::time::timeout(
-                                            timeout,
-                                            tx.send(ServerMessage {
-                                                data: frame.payload().to_vec(),
-                                                _client_addr: client.peer_addr(),
-                                                response_tx,
-                                            }),
-                                        )
-                                        .await
-                                        {
-                                            break;
-                                        }
+                            }
+                            session.note_compression(client.compression().enabled());
+                            let frame = match session.intake.receive(frame, client.peer_addr()) {
+                                Received::Message(frame) => frame,
+                                Received::Partial => continue,
+                                Received::Rejected(reply) => {
+                                    if client.send(reply).await.is_err() {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            };
+                            if session.cancel_call(&frame) {
+                                continue;
+                            }
+                            let call = session.open_call(&frame);
+                            let closed = match &call {
+                                Some((_, call)) => call.clone(),
+                                None => session.closed.clone(),
+                            };
+                            let (response_tx, mut response_rx) = mpsc::channel(1);
 
-                                        if let Some(response) = response_rx.recv().await {
-                                            let response_frame =
-                                                Frame::new(FrameType::Data).with_payload(response);
-                                            if let Err(_) = client.send(response_frame).await {
-                                                break;
-                                            }
-                                        }
+                            // Try to deserialize and handle the message
+                            match validate(frame.payload()) {
+                                Ok(_data) => {
+                                    if tokio::time::timeout(
+                                        timeout,
+                                        tx.send(ServerMessage {
+                                            frame,
+                                            client_addr: client.peer_addr(),
+                                            quota: client.quota_remaining(),
+                                            service: session.service.clone(),
+                                            identity: session.identity.clone(),
+                                            session: session.id,
+                                            meta,
+                                            params: session.params.clone(),
+                                            closed,
+                                            extensions: session.extensions.clone(),
+                                            response_tx,
+                                        }),
+                                    )
+                                    .await
+                                    .is_err()
+                                    {
+                                        break;
                                     }
-                                    Err(e) => {
-                                        // Send error response for invalid data
-                                        let error_frame = Frame::new(FrameType::Data).with_payload(
-                                            format!("Invalid data: {}", e).into_bytes(),
-                                        );
-                                        let _ = client.send(error_frame).await;
+
+                                    if let Some(call) = call {
+                                        let outbox = outbox_tx.clone();
+                                        session.spawn_call(call, response_rx, move |reply| {
+                                            let outbox = outbox.clone();
+                                            async move { outbox.send(reply).await.is_ok() }
+                                        });
+                                        continue;
+                                    }
+
+                                    // One reply, or a streaming route's until it is done
+                                    while let Some(response_frame) = response_rx.recv().await {
+                                        if client.send(response_frame).await.is_err() {
+                                            break 'frames;
+                                        }
                                     }
                                 }
+                                Err(e) => {
+                                    // Send error response for invalid data
+                                    let error_frame = Frame::new(FrameType::Data).with_payload(
+                                        format!("Invalid data: {}", e).into_bytes(),
+                                    );
+                                    let _ = client.send(error_frame).await;
+                                }
                             }
-                            Ok::<_, VstpError>(())
-                        });
+                        }
+                        Ok::<_, VstpError>(())
+                    });
+                }
+                #[allow(unreachable_code)]
+                Ok::<_, VstpError>(())
+            });
+        }
+        ServerType::Udp(server) => {
+            let server: Arc<crate::udp::VstpUdpServer> = Arc::from(server);
+            let mut sessions = UdpSessions::new(options, stats.clone());
+            spawn_in_span(async move {
+                while let Ok((frame, addr, meta)) = server.recv_with_meta().await {
+                    if frame.get_header("x-auto-probe") == Some("1") {
+                        continue;
                     }
-                    #[allow(unreachable_code)]
-                    Ok::<_, VstpError>(())
-                });
-            }
-            ServerType::Udp(server) => {
-                let tx = self.message_tx.clone();
-                let timeout = self.timeout;
-
-                tokio::spawn(async move {
-                    while let Ok((frame, addr)) = server.recv().await {
-                        if frame.get_header("x-auto-probe") == Some("1") {
+                    // Clock sync PINGs are answered without touching the session
+                    if let Some(sent_ms) = plain_ping_sent_ms(&frame) {
+                        let _ = server.send_pong(sent_ms, addr).await;
+                        continue;
+                    }
+                    // A HELLO starts a new session, ending the address's last one
+                    if frame.typ == FrameType::Hello {
+                        sessions.remove(&addr);
+                    }
+                    let entry = sessions.get(addr, &services);
+                    if let Err(limit) = entry.charge(meta.wire_len) {
+                        sessions.close_for_quota(&server, addr, limit).await;
+                        continue;
+                    }
+                    let quota = entry.quota_remaining();
+                    let sent = entry.sent.clone();
+                    let session = &mut entry.session;
+                    let admission = session.admit(&frame, &services, true, addr).await;
+                    let service = session.service.clone();
+                    let identity = session.identity.clone();
+                    let id = session.id;
+                    let params = session.params.clone();
+                    let closed = session.closed.clone();
+                    let extensions = session.extensions.clone();
+                    let received = match admission {
+                        Admission::Deliver => session.intake.receive(frame, addr),
+                        Admission::Reply(reply, _) => {
+                            if !session.authenticated {
+                                sessions.remove(&addr);
+                            }
+                            let _ = server.send(reply, addr).await;
                             continue;
                         }
-                        let (response_tx, mut response_rx) = mpsc::channel(1);
-
-                        // Try to deserialize and handle the message
-                        match serde_json::from_slice::<T>(&frame.payload()) {
-                            Ok(_data) => {
-                                if let Err(_) = tokio::time::timeout(
-                                    timeout,
-                                    tx.send(ServerMessage {
-                                        data: frame.payload().to_vec(),
-                                        _client_addr: addr,
-                                        response_tx,
-                                    }),
-                                )
-                                .await
-                                {
-                                    break;
-                                }
+                    };
+                    let frame = match received {
+                        Received::Message(frame) => frame,
+                        Received::Partial => continue,
+                        Received::Rejected(reply) => {
+                            let _ = server.send(reply, addr).await;
+                            continue;
+                        }
+                    };
+                    if session.cancel_call(&frame) {
+                        continue;
+                    }
+                    let call = session.open_call(&frame);
+                    let closed = match &call {
+                        Some((_, call)) => call.clone(),
+                        None => closed,
+                    };
+                    let (response_tx, mut response_rx) = mpsc::channel(1);
 
-                                if let Some(response) = response_rx.recv().await {
-                                    let response_frame =
-                                        Frame::new(FrameType::Data).with_payload(response);
-                                    let _ = server.send(response_frame, addr).await;
-                                }
+                    // Try to deserialize and handle the message
+                    match validate(frame.payload()) {
+                        Ok(_data) => {
+                            if tokio::time::timeout(
+                                timeout,
+                                tx.send(ServerMessage {
+                                    frame,
+                                    client_addr: addr,
+                                    service,
+                                    identity,
+                                    session: id,
+                                    meta,
+                                    params,
+                                    quota,
+                                    closed,
+                                    extensions,
+                                    response_tx,
+                                }),
+                            )
+                            .await
+                            .is_err()
+                            {
+                                break;
+                            }
+
+                            if let Some(call) = call {
+                                let server = server.clone();
+                                session.spawn_call(call, response_rx, move |reply| {
+                                    let server = server.clone();
+                                    let sent = sent.clone();
+                                    async move {
+                                        send_reply(&server, reply, addr, sent.as_ref()).await;
+                                        true
+                                    }
+                                });
+                                continue;
                             }
-                            Err(e) => {
-                                // Send error response for invalid data
-                                let error_frame = Frame::new(FrameType::Data)
-                                    .with_payload(format!("Invalid data: {}", e).into_bytes());
-                                let _ = server.send(error_frame, addr).await;
+                            while let Some(response_frame) = response_rx.recv().await {
+                                send_reply(&server, response_frame, addr, sent.as_ref()).await;
                             }
                         }
+                        Err(e) => {
+                            // Send error response for invalid data
+                            let error_frame = Frame::new(FrameType::Data)
+                                .with_payload(format!("Invalid data: {}", e).into_bytes());
+                            let _ = server.send(error_frame, addr).await;
+                        }
                     }
-                });
-            }
-            ServerType::Auto(auto) => {
-                let tx_tcp = self.message_tx.clone();
-                let tx_udp = self.message_tx.clone();
-                let timeout = self.timeout;
-                let pref_tcp = auto.peer_preference.clone();
-                let pref_udp = auto.peer_preference.clone();
-                let ttl = auto.cfg.peer_preference_ttl;
-                let tcp_server = auto.tcp.clone();
-                let udp_server = auto.udp.clone();
-
-                tokio::spawn(async move {
-                    loop {
-                        let mut client = tcp_server.accept().await?;
-                        let tx = tx_tcp.clone();
-                        let pref = pref_tcp.clone();
-                        tokio::spawn(async move {
-                            while let Ok(Some(frame)) = client.recv().await {
-                                if frame.get_header("x-auto-probe") == Some("1") {
+                }
+            });
+        }
+        ServerType::Auto(auto) => {
+            let tx_tcp = tx.clone();
+            let tx_udp = tx.clone();
+            let pref_tcp = auto.peer_preference.clone();
+            let pref_udp = auto.peer_preference.clone();
+            let ttl = auto.cfg.peer_preference_ttl;
+            let tcp_server = auto.tcp.clone();
+            let udp_server = auto.udp.clone();
+            let tcp_services = services.clone();
+
+            spawn_in_span(async move {
+                loop {
+                    let mut client = tcp_server.accept().await?;
+                    let tx = tx_tcp.clone();
+                    let pref = pref_tcp.clone();
+                    let services = tcp_services.clone();
+                    spawn_in_span(async move {
+                        let mut session = Session::new(&services, client.tls_server_name());
+                        client.set_keep_raw_bytes(session.options.keep_raw_bytes);
+                        let (outbox_tx, mut outbox) = mpsc::channel(16);
+                        'frames: while let Some((frame, meta)) =
+                            next_request(&mut client, &mut outbox, session.calls_open()).await
+                        {
+                            if frame.get_header("x-auto-probe") == Some("1") {
+                                continue;
+                            }
+                            let admission = session
+                                .admit(&frame, &services, false, client.peer_addr())
+                                .await;
+                            match admission {
+                                Admission::Deliver => {}
+                                Admission::Reply(reply, keep_open) => {
+                                    let welcome = reply.typ == FrameType::Welcome;
+                                    let version = negotiated_version(&reply);
+                                    if client.send(reply).await.is_err() || !keep_open {
+                                        break;
+                                    }
+                                    if welcome {
+                                        client.set_frame_version(version);
+                                        client.set_keep_raw_bytes(session.options.keep_raw_bytes);
+                                    }
                                     continue;
                                 }
-                                {
-                                    let mut guard = pref.lock().await;
-                                    guard.insert(
-                                        client.peer_addr(),
-                                        PeerPreference {
-                                            transport: TransportKind::Tcp,
-                                            last_seen: Instant::now(),
-                                        },
-                                    );
-                                }
-                                let (response_tx, mut response_rx) = mpsc::channel(1);
-                                if tokio::time::timeout(
-                                    timeout,
-                                    tx.send(ServerMessage {
-                                        data: frame.payload().to_vec(),
-                                        _client_addr: client.peer_addr(),
-                                        response_tx,
-                                    }),
-                                )
-                                .await
-                                .is_err()
-                                {
-                                    break;
-                                }
-
-                                if let Some(response) = response_rx.recv().await {
-                                    let response_frame = Frame::new(FrameType::Data).with_payload(response);
-                                    if client.send(response_frame).await.is_err() {
+                            }
+                            session.note_compression(client.compression().enabled());
+                            let frame = match session.intake.receive(frame, client.peer_addr()) {
+                                Received::Message(frame) => frame,
+                                Received::Partial => continue,
+                                Received::Rejected(reply) => {
+                                    if client.send(reply).await.is_err() {
                                         break;
                                     }
+                                    continue;
                                 }
+                            };
+                            if session.cancel_call(&frame) {
+                                continue;
+                            }
+                            let call = session.open_call(&frame);
+                            let closed = match &call {
+                                Some((_, call)) => call.clone(),
+                                None => session.closed.clone(),
+                            };
+                            {
+                                let mut guard = pref.lock().await;
+                                guard.insert(
+                                    client.peer_addr(),
+                                    PeerPreference {
+                                        transport: TransportKind::Tcp,
+                                        last_seen: Instant::now(),
+                                    },
+                                );
+                            }
+                            let (response_tx, mut response_rx) = mpsc::channel(1);
+                            if tokio::time::timeout(
+                                timeout,
+                                tx.send(ServerMessage {
+                                    frame,
+                                    client_addr: client.peer_addr(),
+                                    quota: client.quota_remaining(),
+                                    service: session.service.clone(),
+                                    identity: session.identity.clone(),
+                                    session: session.id,
+                                    meta,
+                                    params: session.params.clone(),
+                                    closed,
+                                    extensions: session.extensions.clone(),
+                                    response_tx,
+                                }),
+                            )
+                            .await
+                            .is_err()
+                            {
+                                break;
+                            }
 
-                                {
-                                    let mut guard = pref.lock().await;
-                                    guard.retain(|_, v| v.last_seen.elapsed() <= ttl);
+                            if let Some(call) = call {
+                                let outbox = outbox_tx.clone();
+                                session.spawn_call(call, response_rx, move |reply| {
+                                    let outbox = outbox.clone();
+                                    async move { outbox.send(reply).await.is_ok() }
+                                });
+                                continue;
+                            }
+
+                            while let Some(response_frame) = response_rx.recv().await {
+                                if client.send(response_frame).await.is_err() {
+                                    break 'frames;
                                 }
                             }
-                        });
-                    }
-                    #[allow(unreachable_code)]
-                    Ok::<_, VstpError>(())
-                });
 
-                tokio::spawn(async move {
-                    while let Ok((frame, addr)) = udp_server.recv().await {
-                        if frame.get_header("x-auto-probe") == Some("1") {
+                            {
+                                let mut guard = pref.lock().await;
+                                guard.retain(|_, v| v.last_seen.elapsed() <= ttl);
+                            }
+                        }
+                    });
+                }
+                #[allow(unreachable_code)]
+                Ok::<_, VstpError>(())
+            });
+
+            let mut sessions = UdpSessions::new(options, stats.clone());
+            spawn_in_span(async move {
+                while let Ok((frame, addr, meta)) = udp_server.recv_with_meta().await {
+                    if frame.get_header("x-auto-probe") == Some("1") {
+                        continue;
+                    }
+                    // Clock sync PINGs are answered without touching the session
+                    if let Some(sent_ms) = plain_ping_sent_ms(&frame) {
+                        let _ = udp_server.send_pong(sent_ms, addr).await;
+                        continue;
+                    }
+                    // A HELLO starts a new session, ending the address's last one
+                    if frame.typ == FrameType::Hello {
+                        sessions.remove(&addr);
+                    }
+                    let entry = sessions.get(addr, &services);
+                    if let Err(limit) = entry.charge(meta.wire_len) {
+                        sessions.close_for_quota(&udp_server, addr, limit).await;
+                        continue;
+                    }
+                    let quota = entry.quota_remaining();
+                    let sent = entry.sent.clone();
+                    let session = &mut entry.session;
+                    let admission = session.admit(&frame, &services, true, addr).await;
+                    let service = session.service.clone();
+                    let identity = session.identity.clone();
+                    let id = session.id;
+                    let params = session.params.clone();
+                    let closed = session.closed.clone();
+                    let extensions = session.extensions.clone();
+                    let received = match admission {
+                        Admission::Deliver => session.intake.receive(frame, addr),
+                        Admission::Reply(reply, _) => {
+                            if !session.authenticated {
+                                sessions.remove(&addr);
+                            }
+                            let _ = udp_server.send(reply, addr).await;
                             continue;
                         }
-                        {
-                            let mut guard = pref_udp.lock().await;
-                            guard.insert(
-                                addr,
-                                PeerPreference {
-                                    transport: TransportKind::Udp,
-                                    last_seen: Instant::now(),
-                                },
-                            );
+                    };
+                    let frame = match received {
+                        Received::Message(frame) => frame,
+                        Received::Partial => continue,
+                        Received::Rejected(reply) => {
+                            let _ = udp_server.send(reply, addr).await;
+                            continue;
                         }
+                    };
+                    if session.cancel_call(&frame) {
+                        continue;
+                    }
+                    let call = session.open_call(&frame);
+                    let closed = match &call {
+                        Some((_, call)) => call.clone(),
+                        None => closed,
+                    };
+                    {
+                        let mut guard = pref_udp.lock().await;
+                        guard.insert(
+                            addr,
+                            PeerPreference {
+                                transport: TransportKind::Udp,
+                                last_seen: Instant::now(),
+                            },
+                        );
+                    }
 
-                        let (response_tx, mut response_rx) = mpsc::channel(1);
-                        if tokio::time::timeout(
-                            timeout,
-                            tx_udp.send(ServerMessage {
-                                data: frame.payload().to_vec(),
-                                _client_addr: addr,
-                                response_tx,
-                            }),
-                        )
-                        .await
-                        .is_err()
-                        {
-                            break;
-                        }
+                    let (response_tx, mut response_rx) = mpsc::channel(1);
+                    if tokio::time::timeout(
+                        timeout,
+                        tx_udp.send(ServerMessage {
+                            frame,
+                            client_addr: addr,
+                            service,
+                            identity,
+                            session: id,
+                            meta,
+                            params,
+                            quota,
+                            closed,
+                            extensions,
+                            response_tx,
+                        }),
+                    )
+                    .await
+                    .is_err()
+                    {
+                        break;
+                    }
 
-                        if let Some(response) = response_rx.recv().await {
-                            let response_frame = Frame::new(FrameType::Data).with_payload(response);
-                            let preferred = {
-                                let guard = pref_udp.lock().await;
-                                guard.get(&addr).copied()
-                            };
-                            let should_send_udp = preferred
-                                .map(|p| p.transport == TransportKind::Udp)
-                                .unwrap_or(true);
-                            if should_send_udp {
-                                let _ = udp_server.send(response_frame, addr).await;
+                    let send = {
+                        let pref = pref_udp.clone();
+                        let udp_server = udp_server.clone();
+                        move |reply: Frame| {
+                            let pref = pref.clone();
+                            let udp_server = udp_server.clone();
+                            let sent = sent.clone();
+                            async move {
+                                let preferred = {
+                                    let guard = pref.lock().await;
+                                    guard.get(&addr).copied()
+                                };
+                                let should_send_udp = preferred
+                                    .map(|p| p.transport == TransportKind::Udp)
+                                    .unwrap_or(true);
+                                if should_send_udp {
+                                    send_reply(&udp_server, reply, addr, sent.as_ref()).await;
+                                }
+                                true
                             }
                         }
-                    }
-                });
-            }
-        }
-
-        while let Some(msg) = self.message_rx.recv().await {
-            let handler = handler.clone();
-            tokio::spawn(async move {
-                match serde_json::from_slice::<T>(&msg.data) {
-                    Ok(data) => match handler(data).await {
-                        Ok(response) => {
-                            if let Ok(response_data) = serde_json::to_vec(&response) {
-                                let _ = msg.response_tx.send(response_data).await;
+                    };
+                    match call {
+                        Some(call) => session.spawn_call(call, response_rx, send),
+                        None => {
+                            while let Some(response_frame) = response_rx.recv().await {
+                                send(response_frame).await;
                             }
                         }
-                        Err(_) => (),
-                    },
-                    Err(_) => (),
+                    }
                 }
             });
         }
+    }
+}
 
-        Ok(())
+/// Copy the headers named in `keys` from `request` onto `reply`, unless the reply sets them
+fn echo_headers(request: &Frame, mut reply: Frame, keys: &[String]) -> Frame {
+    for key in keys {
+        if reply.get_binary_header(key).is_some() {
+            continue;
+        }
+        if let Some(value) = request.get_binary_header(key) {
+            reply = reply.with_binary_header(key, value);
+        }
     }
+    reply
+}
+
+/// Count a handler that ran out of time and build the ERR frame for its client
+fn deadline_exceeded(
+    stats: &ServerStats,
+    client_addr: SocketAddr,
+    method: Option<&str>,
+    limit: Duration,
+) -> Frame {
+    stats.timed_out_handlers.fetch_add(1, Ordering::Relaxed);
+    tracing::warn!(
+        "Handler for {} from {} exceeded {:?}",
+        method.map_or_else(|| "request".to_string(), |method| format!("method {method}")),
+        client_addr,
+        limit
+    );
+    Frame::coded_error(
+        ErrorCode::DeadlineExceeded,
+        &format!("handler exceeded {:?}", limit),
+    )
 }
 
 #[cfg(test)]
@@ -1119,6 +4511,50 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_tcp_handler_timeout() -> Result<(), VstpError> {
+        let mut server = VstpServer::bind_tcp("127.0.0.1:8086").await?;
+        server.set_options(ServerOptions {
+            handler_timeout: Some(Duration::from_millis(200)),
+            ..ServerOptions::default()
+        });
+        let stats = server.stats();
+        tokio::spawn(async move {
+            server
+                .serve(|msg: TestMessage| async move {
+                    if msg.content == "slow" {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                    Ok(msg)
+                })
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = VstpClient::connect_tcp("127.0.0.1:8086").await?;
+
+        client
+            .send(TestMessage {
+                content: "slow".to_string(),
+            })
+            .await?;
+        match client.receive::<TestMessage>().await {
+            Err(VstpError::ServerError(msg)) => assert!(msg.starts_with("DeadlineExceeded")),
+            other => panic!("Expected DeadlineExceeded, got {:?}", other),
+        }
+        assert_eq!(stats.timed_out_handlers(), 1);
+
+        // The session survives the cancelled handler
+        let fast = TestMessage {
+            content: "fast".to_string(),
+        };
+        client.send(fast.clone()).await?;
+        let response: TestMessage = client.receive().await?;
+        assert_eq!(response, fast);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_serialization_error() -> Result<(), VstpError> {
         let server = VstpServer::bind_tcp("127.0.0.1:8084").await?;
@@ -1183,8 +4619,10 @@ mod tests {
         tokio::spawn(async move { server.serve(|msg: TestMessage| async move { Ok(msg) }).await });
         tokio::time::sleep(Duration::from_millis(100)).await;
 
-        let mut cfg = AutoSwitchConfig::default();
-        cfg.probe_attempts = 1;
+        let cfg = AutoSwitchConfig {
+            probe_attempts: 1,
+            ..AutoSwitchConfig::default()
+        };
         let client = VstpClient::connect_auto_with_config("127.0.0.1:8090", cfg).await?;
         let msg = TestMessage {
             content: "auto mode".to_string(),
@@ -0,0 +1,220 @@
+//! Credit-based flow control on top of VSTP frames
+//!
+//! The receiver grants the sender credit to send more DATA frames using two
+//! headers, either of which may be omitted:
+//!
+//! - `window-frames`: number of additional frames the receiver will accept
+//! - `window-bytes`: number of additional payload bytes the receiver will accept
+//!
+//! Values are decimal ASCII and are *increments*, never absolute sizes. The
+//! initial window is advertised by putting these headers on the WELCOME (or any
+//! other frame sent before data starts flowing). A limit the receiver never
+//! advertises is unlimited.
+//!
+//! ## WINDOW_UPDATE frame format
+//!
+//! A window update is an ACK frame with no `msg-id` header, at least one of the
+//! window headers and an empty payload:
+//!
+//! ```text
+//! TYPE    = 0x07 (ACK)
+//! HEADERS = window-frames: <u64>, window-bytes: <u64>
+//! PAYLOAD = (empty)
+//! ```
+//!
+//! Peers that don't implement flow control ignore it, since it matches no
+//! outstanding `msg-id`.
+
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::types::{Frame, FrameType};
+
+/// Header carrying a frame credit increment
+pub const WINDOW_FRAMES_HEADER: &str = "window-frames";
+
+/// Header carrying a payload byte credit increment
+pub const WINDOW_BYTES_HEADER: &str = "window-bytes";
+
+/// Credit granted by a receiver; `None` means no limit is imposed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WindowCredit {
+    pub frames: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+impl WindowCredit {
+    pub fn new(frames: Option<u64>, bytes: Option<u64>) -> Self {
+        Self { frames, bytes }
+    }
+
+    /// Read the credit carried by a frame's window headers, if any
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        let frames = frame
+            .get_header(WINDOW_FRAMES_HEADER)
+            .and_then(|v| v.parse().ok());
+        let bytes = frame
+            .get_header(WINDOW_BYTES_HEADER)
+            .and_then(|v| v.parse().ok());
+        if frames.is_none() && bytes.is_none() {
+            return None;
+        }
+        Some(Self { frames, bytes })
+    }
+
+    /// Add this credit's window headers to a frame
+    pub fn apply_to(self, mut frame: Frame) -> Frame {
+        if let Some(frames) = self.frames {
+            frame = frame.with_header(WINDOW_FRAMES_HEADER, &frames.to_string());
+        }
+        if let Some(bytes) = self.bytes {
+            frame = frame.with_header(WINDOW_BYTES_HEADER, &bytes.to_string());
+        }
+        frame
+    }
+}
+
+/// Build a WINDOW_UPDATE frame granting `credit`
+pub fn window_update(credit: WindowCredit) -> Frame {
+    credit.apply_to(Frame::new(FrameType::Ack))
+}
+
+/// Check whether a frame is a WINDOW_UPDATE
+pub fn is_window_update(frame: &Frame) -> bool {
+    frame.typ == FrameType::Ack
+        && frame.get_header("msg-id").is_none()
+        && WindowCredit::from_frame(frame).is_some()
+}
+
+/// Sender-side enforcement of the receiver's window
+///
+/// Call [`FlowController::acquire`] before sending each DATA frame and feed
+/// every received frame to [`FlowController::handle_frame`]. `acquire` pauses
+/// while the window is exhausted and resumes once a window update arrives.
+/// The controller is `Sync`, so the receive side can run in another task.
+#[derive(Debug)]
+pub struct FlowController {
+    available: Mutex<WindowCredit>,
+    notify: Notify,
+}
+
+impl FlowController {
+    /// Create a controller with the receiver's initial window
+    pub fn new(initial: WindowCredit) -> Self {
+        Self {
+            available: Mutex::new(initial),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Credit currently available to the sender
+    pub fn available(&self) -> WindowCredit {
+        *self.available.lock().unwrap()
+    }
+
+    /// Add credit and wake any paused senders
+    pub fn grant(&self, credit: WindowCredit) {
+        {
+            let mut available = self.available.lock().unwrap();
+            if let (Some(current), Some(extra)) = (available.frames, credit.frames) {
+                available.frames = Some(current.saturating_add(extra));
+            }
+            if let (Some(current), Some(extra)) = (available.bytes, credit.bytes) {
+                available.bytes = Some(current.saturating_add(extra));
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Apply the credit carried by a received frame, returning whether it had any
+    pub fn handle_frame(&self, frame: &Frame) -> bool {
+        match WindowCredit::from_frame(frame) {
+            Some(credit) => {
+                self.grant(credit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take credit for one frame with `payload_len` bytes if the window allows it
+    pub fn try_acquire(&self, payload_len: usize) -> bool {
+        let mut available = self.available.lock().unwrap();
+        let frames_ok = available.frames.is_none_or(|f| f >= 1);
+        let bytes_ok = available.bytes.is_none_or(|b| b >= payload_len as u64);
+        if !(frames_ok && bytes_ok) {
+            return false;
+        }
+        if let Some(frames) = available.frames.as_mut() {
+            *frames -= 1;
+        }
+        if let Some(bytes) = available.bytes.as_mut() {
+            *bytes -= payload_len as u64;
+        }
+        true
+    }
+
+    /// Wait until the window allows one frame with `payload_len` bytes, then take the credit
+    pub async fn acquire(&self, payload_len: usize) {
+        loop {
+            let notified = self.notify.notified();
+            if self.try_acquire(payload_len) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Receiver-side bookkeeping that decides when to send window updates
+///
+/// Credit is returned to the sender once half of the window has been consumed,
+/// so the sender can keep going without stalling on every frame.
+#[derive(Debug, Clone)]
+pub struct ReceiveWindow {
+    window: WindowCredit,
+    consumed_frames: u64,
+    consumed_bytes: u64,
+}
+
+impl ReceiveWindow {
+    pub fn new(window: WindowCredit) -> Self {
+        Self {
+            window,
+            consumed_frames: 0,
+            consumed_bytes: 0,
+        }
+    }
+
+    /// Credit to advertise up front, e.g. on the WELCOME
+    pub fn initial_credit(&self) -> WindowCredit {
+        self.window
+    }
+
+    /// Record that the application consumed `frame`, returning a window update to send if due
+    pub fn consume(&mut self, frame: &Frame) -> Option<Frame> {
+        self.consumed_frames += 1;
+        self.consumed_bytes += frame.payload.len() as u64;
+
+        let frames_due = self
+            .window
+            .frames
+            .is_some_and(|w| self.consumed_frames >= (w / 2).max(1));
+        let bytes_due = self
+            .window
+            .bytes
+            .is_some_and(|w| self.consumed_bytes >= (w / 2).max(1));
+        if !(frames_due || bytes_due) {
+            return None;
+        }
+
+        let credit = WindowCredit {
+            frames: self.window.frames.map(|_| self.consumed_frames),
+            bytes: self.window.bytes.map(|_| self.consumed_bytes),
+        };
+        self.consumed_frames = 0;
+        self.consumed_bytes = 0;
+        Some(window_update(credit))
+    }
+}
@@ -1,17 +1,106 @@
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use bytes::{BufMut, Bytes, BytesMut};
 use crc_any::CRC;
+use sha2::{Digest, Sha256};
+
+use crate::clock::{PING_SENT_MS_HEADER, SERVER_TIME_MS_HEADER};
+use crate::types::{
+    Flags, Frame, FrameType, Header, VstpError, VSTP_MAGIC, VSTP_VERSION, VSTP_VERSION_2,
+};
+
+/// Fixed header size of a version 1 frame, up to and including PAY_LEN
+const V1_FIXED_LEN: usize = 11;
+
+/// Fixed header size of a version 2 frame, up to and including PAY_LEN
+const V2_FIXED_LEN: usize = 14;
+
+/// Header naming the message an ACK is for
+const MSG_ID_KEY: &[u8] = b"msg-id";
+
+/// Digits of the longest `u64`
+const U64_DIGITS: usize = 20;
+
+/// Most bytes [`encode_ack_into`] writes
+pub const ACK_FRAME_MAX_LEN: usize = V1_FIXED_LEN + 2 + MSG_ID_KEY.len() + U64_DIGITS + 4;
+
+/// Most bytes [`encode_pong_into`] writes
+pub const PONG_FRAME_MAX_LEN: usize = V1_FIXED_LEN
+    + 2
+    + PING_SENT_MS_HEADER.len()
+    + U64_DIGITS
+    + 2
+    + SERVER_TIME_MS_HEADER.len()
+    + U64_DIGITS
+    + 4;
+
+/// Check a frame's trailer is computed with
+///
+/// The trailer covers every byte of the frame before it. Frames carry
+/// [`Flags::SHA256`] when they use a SHA-256 trailer, so decoders always know
+/// which one to verify; a codec only picks which one it sends.
+///
+/// CRC-32 costs 4 bytes per frame and is fast, and catches the burst errors
+/// and bit flips of a noisy link. SHA-256 truncated to 128 bits costs 16
+/// bytes and several times the CPU per byte, but makes it practically
+/// impossible for corruption, e.g. by a buggy middlebox rewriting bytes, to
+/// go unnoticed. Neither is a MAC: anyone who can change a frame can
+/// recompute its trailer, so use TLS against tampering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Integrity {
+    #[default]
+    Crc32,
+    /// SHA-256, truncated to its first 16 bytes
+    Sha256,
+}
+
+impl Integrity {
+    /// The check a frame with `flags` carries
+    pub fn of(flags: Flags) -> Self {
+        if flags.contains(Flags::SHA256) {
+            Integrity::Sha256
+        } else {
+            Integrity::Crc32
+        }
+    }
 
-use crate::types::{Flags, Frame, FrameType, Header, VstpError, VSTP_MAGIC, VSTP_VERSION};
+    /// Size of the trailer in bytes
+    pub fn trailer_len(self) -> usize {
+        match self {
+            Integrity::Crc32 => 4,
+            Integrity::Sha256 => 16,
+        }
+    }
+
+    /// Flags with this check's bit set or cleared
+    pub fn apply(self, flags: Flags) -> Flags {
+        match self {
+            Integrity::Crc32 => flags - Flags::SHA256,
+            Integrity::Sha256 => flags | Flags::SHA256,
+        }
+    }
+}
 
 /// Encode a VSTP frame into bytes according to the wire format specification
+///
+/// The layout follows `frame.version`; see [`testvectors`](crate::testvectors)
+/// for both versions.
+///
+/// The encoding is faithful: headers keep their order, duplicates included,
+/// and flags and version are written as the frame has them, unknown flag
+/// bits too. So for every frame [`try_decode_frame`] accepts, encoding it
+/// again gives back exactly the bytes it was decoded from, and anything
+/// computed over a frame's bytes, e.g. a signature, holds for its
+/// re-encoding as well. To get the same bytes however the headers were
+/// ordered, use [`encode_frame_canonical`]. Codecs do change a frame before encoding it, see
+/// [`VstpFrameCodec`](crate::VstpFrameCodec); to pass on a received frame
+/// untouched, keep its [`raw_bytes`](crate::meta::FrameMeta::raw_bytes).
 pub fn encode_frame(frame: &Frame) -> Result<Bytes, VstpError> {
     let mut buf = BytesMut::new();
 
     // Fixed header: [MAGIC (2B)] [VER (1B)] [TYPE (1B)] [FLAGS (1B)]
     buf.put_slice(&VSTP_MAGIC);
     buf.put_u8(frame.version);
-    buf.put_u8(frame.typ as u8);
+    buf.put_u8(frame.typ.to_u8());
     buf.put_u8(frame.flags.bits());
 
     // Encode headers first to calculate total header length
@@ -32,46 +121,197 @@ pub fn encode_frame(frame: &Frame) -> Result<Bytes, VstpError> {
         header_data.put_slice(&header.value);
     }
 
-    // Write header length (little-endian) and payload length (big-endian)
-    buf.put_u16_le(header_data.len() as u16);
-    // Write payload length in big-endian manually
     let payload_len = frame.payload.len() as u32;
-    buf.put_u8((payload_len >> 24) as u8);
-    buf.put_u8((payload_len >> 16) as u8);
-    buf.put_u8((payload_len >> 8) as u8);
-    buf.put_u8(payload_len as u8);
+    match frame.version {
+        VSTP_VERSION => {
+            // Write header length (little-endian) and payload length (big-endian)
+            if header_data.len() > u16::MAX as usize {
+                return Err(VstpError::Protocol(
+                    "Header section too long for version 1".to_string(),
+                ));
+            }
+            buf.put_u16_le(header_data.len() as u16);
+            // Write payload length in big-endian manually
+            buf.put_u8((payload_len >> 24) as u8);
+            buf.put_u8((payload_len >> 16) as u8);
+            buf.put_u8((payload_len >> 8) as u8);
+            buf.put_u8(payload_len as u8);
+        }
+        VSTP_VERSION_2 => {
+            // [EXT (1B), reserved, 0] [HDR_LEN (4B BE)] [PAY_LEN (4B BE)]
+            buf.put_u8(0);
+            buf.put_u32(header_data.len() as u32);
+            buf.put_u32(payload_len);
+        }
+        other => {
+            return Err(VstpError::InvalidVersion {
+                expected: VSTP_VERSION,
+                got: other,
+            })
+        }
+    }
 
     // Write headers and payload
     buf.put_slice(&header_data);
     buf.put_slice(&frame.payload);
 
-    // Calculate CRC over the entire frame (excluding CRC field)
-    let mut crc = CRC::crc32();
-    crc.digest(&buf);
-    let crc_value = crc.get_crc() as u32;
-
-    // Write CRC (big-endian)
-    buf.put_u8((crc_value >> 24) as u8);
-    buf.put_u8((crc_value >> 16) as u8);
-    buf.put_u8((crc_value >> 8) as u8);
-    buf.put_u8(crc_value as u8);
+    match Integrity::of(frame.flags) {
+        Integrity::Crc32 => {
+            // Calculate CRC over the entire frame (excluding CRC field)
+            let mut crc = CRC::crc32();
+            crc.digest(&buf);
+            let crc_value = crc.get_crc() as u32;
+
+            // Write CRC (big-endian)
+            buf.put_u8((crc_value >> 24) as u8);
+            buf.put_u8((crc_value >> 16) as u8);
+            buf.put_u8((crc_value >> 8) as u8);
+            buf.put_u8(crc_value as u8);
+        }
+        Integrity::Sha256 => {
+            let digest = Sha256::digest(&buf);
+            buf.put_slice(&digest[..Integrity::Sha256.trailer_len()]);
+        }
+    }
 
     Ok(buf.freeze())
 }
 
+/// Encode a frame with its headers in canonical order, see [`Frame::canonicalize`]
+///
+/// Frames with the same headers in any order encode to the same bytes, for
+/// hashing, signing or caching them. Everything but the header order is
+/// encoded as [`encode_frame`] would.
+pub fn encode_frame_canonical(frame: &Frame) -> Result<Bytes, VstpError> {
+    let mut frame = frame.clone();
+    frame.canonicalize();
+    encode_frame(&frame)
+}
+
+/// Write the ACK for message `msg_id` into `buf`, returning its length
+///
+/// The bytes are those [`encode_frame`] gives for an ACK with just a
+/// `msg-id` header, but nothing is allocated, so servers under load can ACK
+/// from a stack buffer of [`ACK_FRAME_MAX_LEN`] bytes. Panics if `buf` is
+/// shorter than the frame.
+pub fn encode_ack_into(msg_id: u64, buf: &mut [u8]) -> usize {
+    let mut id = itoa::Buffer::new();
+    encode_bare_into(
+        FrameType::Ack,
+        &[(MSG_ID_KEY, id.format(msg_id).as_bytes())],
+        buf,
+    )
+}
+
+/// Write the PONG answering a clock sync PING into `buf`, returning its length
+///
+/// The bytes are those [`encode_frame`] gives for a PONG carrying the PING's
+/// `ping-sent-ms` followed by `server-time-ms`, as servers answer
+/// [`ClockSync::ping`](crate::clock::ClockSync::ping). Like
+/// [`encode_ack_into`] it allocates nothing; `buf` needs at most
+/// [`PONG_FRAME_MAX_LEN`] bytes.
+pub fn encode_pong_into(ping_sent_ms: u64, server_time_ms: u64, buf: &mut [u8]) -> usize {
+    let mut sent = itoa::Buffer::new();
+    let mut now = itoa::Buffer::new();
+    let sent = sent.format(ping_sent_ms).as_bytes();
+    let now = now.format(server_time_ms).as_bytes();
+    encode_bare_into(
+        FrameType::Pong,
+        &[
+            (PING_SENT_MS_HEADER.as_bytes(), sent),
+            (SERVER_TIME_MS_HEADER.as_bytes(), now),
+        ],
+        buf,
+    )
+}
+
+/// Encode a version 1 frame with CRC, no flags and no payload, whose
+/// headers are known to fit, straight into `buf`
+fn encode_bare_into(typ: FrameType, headers: &[(&[u8], &[u8])], buf: &mut [u8]) -> usize {
+    let header_len: usize = headers.iter().map(|(k, v)| 2 + k.len() + v.len()).sum();
+    let crc_at = V1_FIXED_LEN + header_len;
+    let buf = &mut buf[..crc_at + 4];
+
+    buf[..2].copy_from_slice(&VSTP_MAGIC);
+    buf[2] = VSTP_VERSION;
+    buf[3] = typ.to_u8();
+    buf[4] = Flags::empty().bits();
+    buf[5..7].copy_from_slice(&(header_len as u16).to_le_bytes());
+    buf[7..V1_FIXED_LEN].copy_from_slice(&0u32.to_be_bytes());
+
+    let mut at = V1_FIXED_LEN;
+    for (key, value) in headers {
+        buf[at] = key.len() as u8;
+        buf[at + 1] = value.len() as u8;
+        at += 2;
+        buf[at..at + key.len()].copy_from_slice(key);
+        at += key.len();
+        buf[at..at + value.len()].copy_from_slice(value);
+        at += value.len();
+    }
+
+    let mut crc = CRC::crc32();
+    crc.digest(&buf[..crc_at]);
+    buf[crc_at..].copy_from_slice(&(crc.get_crc() as u32).to_be_bytes());
+    buf.len()
+}
+
+/// Size of the frame starting at `buf`, once its length fields have arrived
+///
+/// `None` while they haven't, or if they don't belong to a frame
+/// [`try_decode_frame`] would accept.
+pub(crate) fn declared_frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < V1_FIXED_LEN || buf[..2] != VSTP_MAGIC {
+        return None;
+    }
+    let (fixed_len, header_len, payload_len) = match buf[2] {
+        VSTP_VERSION => (
+            V1_FIXED_LEN,
+            (&buf[5..7]).read_u16::<LittleEndian>().ok()? as usize,
+            (&buf[7..11]).read_u32::<BigEndian>().ok()? as usize,
+        ),
+        VSTP_VERSION_2 if buf.len() >= V2_FIXED_LEN => (
+            V2_FIXED_LEN,
+            (&buf[6..10]).read_u32::<BigEndian>().ok()? as usize,
+            (&buf[10..14]).read_u32::<BigEndian>().ok()? as usize,
+        ),
+        _ => return None,
+    };
+    Some(
+        fixed_len
+            .saturating_add(header_len)
+            .saturating_add(payload_len)
+            .saturating_add(Integrity::of(Flags::from_bits_retain(buf[4])).trailer_len()),
+    )
+}
+
 /// Try to decode a VSTP frame from a buffer
+///
+/// A frame whose declared size is over `max_frame_size` is rejected with
+/// [`VstpError::FrameTooLarge`] as soon as its length fields have arrived,
+/// without waiting for the headers and payload it claims to carry.
 pub fn try_decode_frame(
     buf: &mut BytesMut,
     max_frame_size: usize,
+) -> Result<Option<Frame>, VstpError> {
+    try_decode_frame_observed(buf, max_frame_size, |_| {})
+}
+
+/// [`try_decode_frame`], handing the frame's bytes to `observe` once they have
+/// all arrived, before the CRC is checked
+pub(crate) fn try_decode_frame_observed(
+    buf: &mut BytesMut,
+    max_frame_size: usize,
+    observe: impl FnOnce(&[u8]),
 ) -> Result<Option<Frame>, VstpError> {
     // Need at least 11 bytes for fixed header + lengths
-    if buf.len() < 11 {
+    if buf.len() < V1_FIXED_LEN {
         return Ok(None);
     }
 
     // Check magic bytes
     if buf[0] != VSTP_MAGIC[0] || buf[1] != VSTP_MAGIC[1] {
-        return Err(VstpError::Protocol("Invalid magic bytes".to_string()));
+        return Err(VstpError::InvalidMagic([buf[0], buf[1]]));
     }
 
     // Parse fixed header
@@ -79,21 +319,49 @@ pub fn try_decode_frame(
     let frame_type = buf[3];
     let flags = buf[4];
 
-    // Validate version
-    if version != VSTP_VERSION {
-        return Err(VstpError::Protocol("Unsupported version".to_string()));
-    }
-
-    // Parse lengths
-    let header_len = (&buf[5..7]).read_u16::<LittleEndian>().unwrap() as usize;
-    let payload_len = (&buf[7..11]).read_u32::<BigEndian>().unwrap() as usize;
+    // Parse lengths, whose layout depends on the version
+    let (fixed_len, header_len, payload_len) = match version {
+        VSTP_VERSION => (
+            V1_FIXED_LEN,
+            (&buf[5..7]).read_u16::<LittleEndian>().unwrap() as usize,
+            (&buf[7..11]).read_u32::<BigEndian>().unwrap() as usize,
+        ),
+        VSTP_VERSION_2 => {
+            if buf.len() < V2_FIXED_LEN {
+                return Ok(None);
+            }
+            if buf[5] != 0 {
+                return Err(VstpError::Protocol(format!(
+                    "Unsupported extension byte 0x{:02x}",
+                    buf[5]
+                )));
+            }
+            (
+                V2_FIXED_LEN,
+                (&buf[6..10]).read_u32::<BigEndian>().unwrap() as usize,
+                (&buf[10..14]).read_u32::<BigEndian>().unwrap() as usize,
+            )
+        }
+        _ => {
+            return Err(VstpError::InvalidVersion {
+                expected: VSTP_VERSION,
+                got: version,
+            })
+        }
+    };
 
-    // Calculate total frame size
-    let total_size = 11 + header_len + payload_len + 4; // +4 for CRC
+    // Calculate total frame size; the lengths are untrusted, so don't let them overflow
+    let total_size = fixed_len
+        .saturating_add(header_len)
+        .saturating_add(payload_len)
+        .saturating_add(Integrity::of(Flags::from_bits_retain(flags)).trailer_len());
 
-    // Check size limits
+    // Reject on the declared size alone, before buffering any of the frame
     if total_size > max_frame_size {
-        return Err(VstpError::Protocol("Frame too large".to_string()));
+        return Err(VstpError::FrameTooLarge {
+            size: total_size,
+            limit: max_frame_size,
+        });
     }
 
     // Check if we have enough data
@@ -103,41 +371,43 @@ pub fn try_decode_frame(
 
     // Extract the complete frame
     let frame_data = buf.split_to(total_size);
-
-    // Verify CRC
-    let expected_crc = (&frame_data[total_size - 4..])
-        .read_u32::<BigEndian>()
-        .unwrap();
-    let mut crc = CRC::crc32();
-    crc.digest(&frame_data[..total_size - 4]);
-    let calculated_crc = crc.get_crc() as u32;
-
-    if expected_crc != calculated_crc {
-        return Err(VstpError::CrcMismatch {
-            expected: expected_crc,
-            got: calculated_crc,
-        });
+    observe(&frame_data);
+
+    // Verify the trailer
+    let integrity = Integrity::of(Flags::from_bits_retain(flags));
+    let (body, trailer) = frame_data.split_at(total_size - integrity.trailer_len());
+    match integrity {
+        Integrity::Crc32 => {
+            let expected_crc = (&trailer[..]).read_u32::<BigEndian>().unwrap();
+            let mut crc = CRC::crc32();
+            crc.digest(body);
+            let calculated_crc = crc.get_crc() as u32;
+
+            if expected_crc != calculated_crc {
+                return Err(VstpError::CrcMismatch {
+                    expected: expected_crc,
+                    got: calculated_crc,
+                });
+            }
+        }
+        Integrity::Sha256 => {
+            if Sha256::digest(body)[..trailer.len()] != *trailer {
+                return Err(VstpError::DigestMismatch);
+            }
+        }
     }
 
-    // Parse frame type
-    let typ = match frame_type {
-        0x01 => FrameType::Hello,
-        0x02 => FrameType::Welcome,
-        0x03 => FrameType::Data,
-        0x04 => FrameType::Ping,
-        0x05 => FrameType::Pong,
-        0x06 => FrameType::Bye,
-        0x07 => FrameType::Ack,
-        0x08 => FrameType::Err,
-        _ => return Err(VstpError::Protocol("Invalid frame type".to_string())),
-    };
+    // Types VSTP doesn't define are kept, so relays can pass them on
+    let typ = FrameType::from_byte(frame_type);
 
-    // Parse headers
+    // Parse headers; none may run past the header section, or re-encoding
+    // the frame wouldn't give back its bytes
     let mut headers = Vec::new();
-    let mut header_pos = 11; // Start after fixed header
+    let mut header_pos = fixed_len; // Start after fixed header
+    let header_end = fixed_len + header_len;
 
-    while header_pos < 11 + header_len {
-        if header_pos + 2 > frame_data.len() {
+    while header_pos < header_end {
+        if header_pos + 2 > header_end {
             return Err(VstpError::Protocol("Incomplete header length".to_string()));
         }
 
@@ -145,7 +415,7 @@ pub fn try_decode_frame(
         let value_len = frame_data[header_pos + 1] as usize;
         header_pos += 2;
 
-        if header_pos + key_len + value_len > frame_data.len() {
+        if header_pos + key_len + value_len > header_end {
             return Err(VstpError::Protocol("Incomplete header value".to_string()));
         }
 
@@ -158,14 +428,15 @@ pub fn try_decode_frame(
     }
 
     // Parse payload
-    let payload_start = 11 + header_len;
+    let payload_start = fixed_len + header_len;
     let payload_end = payload_start + payload_len;
     let payload = frame_data[payload_start..payload_end].to_vec();
 
     Ok(Some(Frame {
         version,
         typ,
-        flags: Flags::from_bits(flags).unwrap_or(Flags::empty()),
+        // Unknown bits are kept for the receiver to judge, see `IngressPolicy::clamp_flags`
+        flags: Flags::from_bits_retain(flags),
         headers,
         payload,
     }))
@@ -176,6 +447,20 @@ mod tests {
     use super::*;
     use crate::types::Frame;
 
+    #[test]
+    fn test_declared_frame_len_from_length_fields() {
+        let frame = Frame::new(FrameType::Data)
+            .with_header("k", "v")
+            .with_payload(vec![1; 300]);
+        let encoded = encode_frame(&frame).unwrap();
+        assert_eq!(declared_frame_len(&encoded[..V1_FIXED_LEN - 1]), None);
+        assert_eq!(
+            declared_frame_len(&encoded[..V1_FIXED_LEN]),
+            Some(encoded.len())
+        );
+        assert_eq!(declared_frame_len(b"GET / HTTP/1.1\r\n"), None);
+    }
+
     #[test]
     fn test_basic_roundtrip() {
         let frame = Frame::new(FrameType::Hello);
@@ -0,0 +1,352 @@
+//! Sending repeated headers as references to templates the peer holds
+//!
+//! A feed of small DATA frames often repeats the same headers on every frame,
+//! and on such a feed the headers can outweigh the payloads. Once the peer
+//! agrees to hold a table of header templates, a sender stores the headers of
+//! a frame as a numbered template and later frames with the same header keys
+//! name the template and carry only the values that differ:
+//!
+//! ```text
+//!  header-table-set: 3   stream-id: 9   content-type: json   seq: 1
+//!  header-table-ref: 3                                       seq: 2
+//!  header-table-ref: 3                                       seq: 3
+//! ```
+//!
+//! The receiver rebuilds the full headers, in their original order, before
+//! anything else sees the frame. Only DATA frames are templated; frames with
+//! a repeated header key are sent as they are.
+//!
+//! ## Negotiating
+//!
+//! The table bounds the receiver's memory, so its size is the receiver's
+//! call. A sender proposes one with a PING carrying `header-table-request: n`
+//! and the receiver answers with a PONG carrying `header-table-size: m`, at
+//! most its own limit; `0` refuses. Both sides start from an empty table, and
+//! the sender stops templating from the moment it proposes until the answer
+//! arrives, so no reference can outlive the table it points into. When the
+//! table is full the sender evicts its least recently used template by
+//! setting a new one under the same number.
+//!
+//! Templates depend on frames arriving in order and none going missing, so
+//! only TCP connections use them; see
+//! [`VstpTcpClient::set_header_table`](crate::tcp::VstpTcpClient::set_header_table).
+
+use std::collections::HashSet;
+
+use crate::types::{Frame, FrameType, Header, VstpError};
+
+/// First header of a frame whose headers become template `n`
+pub const HEADER_TABLE_SET_HEADER: &str = "header-table-set";
+
+/// First header of a frame whose headers are template `n` with the values that follow
+pub const HEADER_TABLE_REF_HEADER: &str = "header-table-ref";
+
+/// Header on a PING proposing a table of `n` templates
+pub const HEADER_TABLE_REQUEST_HEADER: &str = "header-table-request";
+
+/// Header on a PONG answering a proposal with the number of templates the peer holds
+pub const HEADER_TABLE_SIZE_HEADER: &str = "header-table-size";
+
+/// Most templates a receiver holds for its peer unless told otherwise
+const DEFAULT_RECEIVE_LIMIT: usize = 64;
+
+/// What templating has saved on outgoing frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderTableStats {
+    /// DATA frames sent as a reference to a template
+    pub referenced_frames: u64,
+    /// Encoded size of the headers of outgoing DATA frames, as given
+    pub header_bytes_in: u64,
+    /// Encoded size of the same headers as sent
+    pub header_bytes_out: u64,
+}
+
+impl HeaderTableStats {
+    /// Bytes left off the wire so far, negative if setting templates cost more
+    pub fn bytes_saved(&self) -> i64 {
+        self.header_bytes_in as i64 - self.header_bytes_out as i64
+    }
+}
+
+#[derive(Debug)]
+struct Template {
+    headers: Vec<Header>,
+    last_used: u64,
+}
+
+/// Header templates of one connection, in both directions
+///
+/// Driven by [`CompressionControl`](crate::compression::CompressionControl),
+/// which connections already run every frame through.
+#[derive(Debug)]
+pub struct HeaderTable {
+    /// Templates the peer holds for us, indexed by number
+    send: Vec<Template>,
+    /// Number of templates the peer agreed to hold; 0 sends no references
+    send_size: usize,
+    /// Size asked for in a proposal not answered yet
+    proposed: Option<usize>,
+    uses: u64,
+    /// Templates held for the peer, indexed by number
+    receive: Vec<Option<Vec<Header>>>,
+    receive_limit: usize,
+    stats: HeaderTableStats,
+}
+
+impl Default for HeaderTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeaderTable {
+    /// Send full headers, and hold up to 64 templates for a peer that asks
+    pub fn new() -> Self {
+        Self {
+            send: Vec::new(),
+            send_size: 0,
+            proposed: None,
+            uses: 0,
+            receive: Vec::new(),
+            receive_limit: DEFAULT_RECEIVE_LIMIT,
+            stats: HeaderTableStats::default(),
+        }
+    }
+
+    /// Hold at most `limit` templates for the peer; 0 refuses templating
+    pub fn with_receive_limit(mut self, limit: usize) -> Self {
+        self.receive_limit = limit;
+        self
+    }
+
+    /// Number of templates the peer agreed to hold for outgoing frames
+    pub fn send_size(&self) -> usize {
+        self.send_size
+    }
+
+    /// What templating saved on the frames sent so far
+    pub fn stats(&self) -> HeaderTableStats {
+        self.stats
+    }
+
+    /// Build a proposal for the peer to hold `size` templates, 0 to stop
+    ///
+    /// Outgoing frames carry full headers until the peer answers.
+    pub fn propose(&mut self, size: usize) -> Frame {
+        self.send.clear();
+        self.send_size = 0;
+        self.proposed = Some(size);
+        Frame::new(FrameType::Ping).with_header(HEADER_TABLE_REQUEST_HEADER, &size.to_string())
+    }
+
+    /// Answer a PING proposing a table, or apply a PONG answering ours
+    ///
+    /// Returns `None` for frames that aren't about header tables, and
+    /// otherwise the answer to send, if there is one.
+    pub fn negotiate(&mut self, frame: &Frame) -> Option<Option<Frame>> {
+        match frame.typ {
+            FrameType::Ping => {
+                let requested = size_header(frame, HEADER_TABLE_REQUEST_HEADER)?;
+                let size = requested.min(self.receive_limit);
+                self.receive = (0..size).map(|_| None).collect();
+                let answer = Frame::new(FrameType::Pong)
+                    .with_header(HEADER_TABLE_SIZE_HEADER, &size.to_string());
+                Some(Some(answer))
+            }
+            FrameType::Pong => {
+                let size = size_header(frame, HEADER_TABLE_SIZE_HEADER)?;
+                // Never more than asked for, whatever the peer says
+                if let Some(proposed) = self.proposed.take() {
+                    self.send_size = size.min(proposed);
+                }
+                Some(None)
+            }
+            _ => None,
+        }
+    }
+
+    /// Replace the headers of an outgoing DATA frame with a template reference
+    /// where one fits, setting a new template where none does
+    pub fn outgoing(&mut self, mut frame: Frame) -> Frame {
+        if frame.typ != FrameType::Data {
+            return frame;
+        }
+        self.stats.header_bytes_in += encoded_len(&frame.headers);
+        if self.send_size > 0 && !frame.headers.is_empty() && distinct_keys(&frame.headers) {
+            frame.headers = self.template(std::mem::take(&mut frame.headers));
+        }
+        self.stats.header_bytes_out += encoded_len(&frame.headers);
+        frame
+    }
+
+    fn template(&mut self, headers: Vec<Header>) -> Vec<Header> {
+        self.uses += 1;
+        let same_keys = self.send.iter().position(|t| {
+            t.headers.len() == headers.len()
+                && t.headers.iter().zip(&headers).all(|(t, h)| t.key == h.key)
+        });
+        if let Some(id) = same_keys {
+            let template = &mut self.send[id];
+            let changed: Vec<Header> = template
+                .headers
+                .iter()
+                .zip(&headers)
+                .filter(|(t, h)| t.value != h.value)
+                .map(|(_, h)| h.clone())
+                .collect();
+            template.last_used = self.uses;
+            // Mostly new values: a fresh template pays off for the frames to come
+            if changed.len() * 2 <= headers.len() {
+                self.stats.referenced_frames += 1;
+                let mut sent = vec![Header::from_str(HEADER_TABLE_REF_HEADER, &id.to_string())];
+                sent.extend(changed);
+                return sent;
+            }
+            return self.set(id, headers);
+        }
+        let id = if self.send.len() < self.send_size {
+            self.send.push(Template {
+                headers: Vec::new(),
+                last_used: 0,
+            });
+            self.send.len() - 1
+        } else {
+            (0..self.send.len())
+                .min_by_key(|&id| self.send[id].last_used)
+                .unwrap_or(0)
+        };
+        self.set(id, headers)
+    }
+
+    fn set(&mut self, id: usize, headers: Vec<Header>) -> Vec<Header> {
+        self.send[id] = Template {
+            headers: headers.clone(),
+            last_used: self.uses,
+        };
+        let mut sent = vec![Header::from_str(HEADER_TABLE_SET_HEADER, &id.to_string())];
+        sent.extend(headers);
+        sent
+    }
+
+    /// Restore the full headers of an incoming frame that sets or references a template
+    pub fn incoming(&mut self, mut frame: Frame) -> Result<Frame, VstpError> {
+        let Some(first) = frame.headers.first() else {
+            return Ok(frame);
+        };
+        let setting = first.key == HEADER_TABLE_SET_HEADER.as_bytes();
+        if !setting && first.key != HEADER_TABLE_REF_HEADER.as_bytes() {
+            return Ok(frame);
+        }
+        let id = std::str::from_utf8(&first.value)
+            .ok()
+            .and_then(|id| id.parse::<usize>().ok())
+            .filter(|&id| id < self.receive.len())
+            .ok_or_else(|| {
+                VstpError::Protocol(format!(
+                    "Header template {} is outside the table of {}",
+                    String::from_utf8_lossy(&first.value),
+                    self.receive.len()
+                ))
+            })?;
+        let rest = frame.headers.split_off(1);
+        if setting {
+            self.receive[id] = Some(rest.clone());
+            frame.headers = rest;
+            return Ok(frame);
+        }
+        let template = self.receive[id]
+            .as_ref()
+            .ok_or_else(|| VstpError::Protocol(format!("Header template {} was never set", id)))?;
+        let mut headers = template.clone();
+        for header in rest {
+            match headers.iter_mut().find(|h| h.key == header.key) {
+                Some(slot) => slot.value = header.value,
+                // Added after templating, e.g. by payload compression
+                None => headers.push(header),
+            }
+        }
+        frame.headers = headers;
+        Ok(frame)
+    }
+}
+
+fn size_header(frame: &Frame, key: &str) -> Option<usize> {
+    frame.get_header(key)?.parse().ok()
+}
+
+/// Size of `headers` in an encoded frame
+fn encoded_len(headers: &[Header]) -> u64 {
+    headers
+        .iter()
+        .map(|h| 2 + h.key.len() as u64 + h.value.len() as u64)
+        .sum()
+}
+
+fn distinct_keys(headers: &[Header]) -> bool {
+    let mut seen = HashSet::new();
+    headers.iter().all(|h| seen.insert(h.key.as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two tables that agreed on `size` templates for `sender`'s frames
+    fn agreed(size: usize) -> (HeaderTable, HeaderTable) {
+        let mut sender = HeaderTable::new();
+        let mut receiver = HeaderTable::new();
+        let proposal = sender.propose(size);
+        let answer = receiver.negotiate(&proposal).unwrap().unwrap();
+        assert_eq!(sender.negotiate(&answer), Some(None));
+        (sender, receiver)
+    }
+
+    fn tick(seq: u32, stream: &str) -> Frame {
+        Frame::new(FrameType::Data)
+            .with_header("stream-id", stream)
+            .with_header("content-type", "application/json")
+            .with_header("seq", &seq.to_string())
+            .with_payload(b"{}".to_vec())
+    }
+
+    #[test]
+    fn test_references_carry_only_changed_values() {
+        let (mut sender, mut receiver) = agreed(4);
+        for seq in 0..3 {
+            let frame = tick(seq, "quotes");
+            let sent = sender.outgoing(frame.clone());
+            let expected_headers = if seq == 0 { 4 } else { 2 };
+            assert_eq!(sent.headers.len(), expected_headers);
+            assert_eq!(receiver.incoming(sent).unwrap(), frame);
+        }
+        let stats = sender.stats();
+        assert_eq!(stats.referenced_frames, 2);
+        assert!(stats.bytes_saved() > 0, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_full_table_evicts_least_recently_used() {
+        let (mut sender, mut receiver) = agreed(1);
+        let quotes = tick(0, "quotes");
+        let trades = Frame::new(FrameType::Data).with_header("venue", "x");
+        for frame in [quotes.clone(), trades.clone(), quotes.clone()] {
+            let sent = sender.outgoing(frame.clone());
+            assert_eq!(sent.headers[0].key, HEADER_TABLE_SET_HEADER.as_bytes());
+            assert_eq!(receiver.incoming(sent).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn test_receiver_limit_caps_the_table() {
+        let mut sender = HeaderTable::new();
+        let mut receiver = HeaderTable::new().with_receive_limit(0);
+        let answer = receiver.negotiate(&sender.propose(8)).unwrap().unwrap();
+        sender.negotiate(&answer);
+        assert_eq!(sender.send_size(), 0);
+
+        let frame = tick(0, "quotes");
+        assert_eq!(sender.outgoing(frame.clone()), frame);
+        let forged = Frame::new(FrameType::Data).with_header(HEADER_TABLE_REF_HEADER, "0");
+        assert!(receiver.incoming(forged).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Replaying the reply to a request that is sent again
+//!
+//! A client whose connection drops after sending a request can't tell
+//! whether the server handled it. Sending it again is only safe if the
+//! handler can't run twice, e.g. when it charges a card. A request carrying
+//! an [`IDEMPOTENCY_KEY_HEADER`] to a [`Router::idempotent`](crate::Router::idempotent)
+//! router, or to a server with
+//! [`ServerOptions::idempotency`](crate::ServerOptions::idempotency) set, has
+//! its reply kept in an [`IdempotencyStore`], keyed by the session's identity
+//! and the key. The same request sent again is answered from the store,
+//! marked with [`REPLAYED_HEADER`], without running the handler; one
+//! sent while the first is still running waits for its reply.
+//!
+//! Each reply is kept with the [`content_hash`](Frame::content_hash) of the
+//! method and payload of the request it answered. A different request
+//! reusing the key, e.g. with another amount, is refused with ERR
+//! `IdempotencyKeyReused` instead of getting the first one's reply; other
+//! headers, e.g. for tracing, may differ between sends.
+//!
+//! Handler responses and the ERR frames of failed handlers are kept. Other
+//! ERRs, e.g. for an unknown method or a handler that timed out, are not, so
+//! the request runs again when it is retried.
+//!
+//! [`MemoryIdempotencyStore`] keeps replies in the server's memory, so they
+//! are gone after a restart; implement the trait to keep them elsewhere.
+//! Identities come from [`ServerOptions::api_keys`](crate::ServerOptions::api_keys);
+//! sessions authenticated any other way share [`ANONYMOUS`](crate::usage::ANONYMOUS),
+//! so their keys must be unique across clients. So do requests passed to
+//! [`Router::handle`](crate::Router::handle) directly, which have no session.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::easy::{COALESCED_HEADER, IDEMPOTENCY_KEY_HEADER};
+use crate::router::METHOD_HEADER;
+use crate::types::{ErrorCode, Frame, FrameType, VstpError};
+
+/// Header set to `true` on a reply answered from an [`IdempotencyStore`]
+pub const REPLAYED_HEADER: &str = "x-idempotent-replay";
+
+/// A reply kept in an [`IdempotencyStore`], with the request it answered
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeptReply {
+    /// [`request_hash`] of the request
+    pub request_hash: [u8; 32],
+    pub reply: Frame,
+}
+
+/// Keeps the replies to requests with an idempotency key
+pub trait IdempotencyStore: Send + Sync + fmt::Debug {
+    /// The reply kept for `key` sent by `identity`, if it hasn't expired
+    fn get<'a>(&'a self, identity: &'a str, key: &'a str) -> BoxFuture<'a, Option<KeptReply>>;
+
+    /// Keep `reply` as the answer to `key` sent by `identity`
+    fn put<'a>(&'a self, identity: &'a str, key: &'a str, reply: KeptReply) -> BoxFuture<'a, ()>;
+}
+
+/// [`Frame::content_hash`] of `request`'s method and payload, what makes
+/// two requests with one idempotency key the same request
+pub fn request_hash(request: &Frame) -> Result<[u8; 32], VstpError> {
+    let mut content = Frame::new(request.typ).with_payload(request.payload.clone());
+    if let Some(method) = request.get_header(METHOD_HEADER) {
+        content = content.with_header(METHOD_HEADER, method);
+    }
+    content.content_hash_excluding(&[])
+}
+
+/// Whether `reply` is a handler's answer worth replaying
+fn replayable(reply: &Frame) -> bool {
+    reply.typ == FrameType::Data || reply.error_code() == Some(ErrorCode::HandlerFailed)
+}
+
+type StoreKey = (String, String);
+
+struct Entry {
+    reply: KeptReply,
+    expires_at: Instant,
+    /// Position in [`State::order`]
+    seq: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<StoreKey, Entry>,
+    /// Keys by when they were stored, oldest first
+    order: BTreeMap<u64, StoreKey>,
+    next_seq: u64,
+}
+
+/// [`IdempotencyStore`] keeping replies in memory
+///
+/// Holds at most `max_entries` replies, dropping the oldest first, each for
+/// `ttl` after it was stored.
+pub struct MemoryIdempotencyStore {
+    ttl: Duration,
+    max_entries: usize,
+    state: Mutex<State>,
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Number of replies kept, expired ones not yet dropped included
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lookup(&self, identity: &str, key: &str) -> Option<KeptReply> {
+        let mut state = self.state.lock().unwrap();
+        let store_key = (identity.to_string(), key.to_string());
+        let entry = state.entries.get(&store_key)?;
+        if entry.expires_at > Instant::now() {
+            return Some(entry.reply.clone());
+        }
+        let seq = entry.seq;
+        state.order.remove(&seq);
+        state.entries.remove(&store_key);
+        None
+    }
+
+    fn insert(&self, identity: &str, key: &str, reply: KeptReply) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let store_key = (identity.to_string(), key.to_string());
+        if let Some(old) = state.entries.remove(&store_key) {
+            state.order.remove(&old.seq);
+        }
+        while state.entries.len() >= self.max_entries {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.order.insert(seq, store_key.clone());
+        state.entries.insert(
+            store_key,
+            Entry {
+                reply,
+                expires_at: Instant::now() + self.ttl,
+                seq,
+            },
+        );
+    }
+}
+
+impl Default for MemoryIdempotencyStore {
+    /// Up to 10 000 replies for 24 hours each
+    fn default() -> Self {
+        Self::new(Duration::from_secs(24 * 60 * 60), 10_000)
+    }
+}
+
+impl fmt::Debug for MemoryIdempotencyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryIdempotencyStore")
+            .field("ttl", &self.ttl)
+            .field("max_entries", &self.max_entries)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    fn get<'a>(&'a self, identity: &'a str, key: &'a str) -> BoxFuture<'a, Option<KeptReply>> {
+        Box::pin(std::future::ready(self.lookup(identity, key)))
+    }
+
+    fn put<'a>(&'a self, identity: &'a str, key: &'a str, reply: KeptReply) -> BoxFuture<'a, ()> {
+        self.insert(identity, key, reply);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// The [`request_hash`] of a running request, and where its reply is
+/// announced to its followers
+type Flight = ([u8; 32], broadcast::Sender<Option<Frame>>);
+
+/// Requests being handled by idempotency key, and with a store the replies
+/// of finished ones
+///
+/// Behind [`Router::idempotent`](crate::Router::idempotent),
+/// [`ServerOptions::single_flight`](crate::ServerOptions::single_flight) and
+/// [`ServerOptions::idempotency`](crate::ServerOptions::idempotency).
+pub(crate) struct SingleFlight {
+    calls: Mutex<HashMap<String, Flight>>,
+    store: Option<Arc<dyn IdempotencyStore>>,
+}
+
+impl SingleFlight {
+    pub(crate) fn new(store: Option<Arc<dyn IdempotencyStore>>) -> Self {
+        Self {
+            calls: Mutex::default(),
+            store,
+        }
+    }
+
+    /// Run `call` for `request` from `identity`, or share the reply of the
+    /// request with its idempotency key that is already running or, with a
+    /// store, has finished
+    ///
+    /// `None` if `call` gives no reply, or the request shared with panicked.
+    pub(crate) async fn run<F>(&self, identity: &str, request: &Frame, call: F) -> Option<Frame>
+    where
+        F: std::future::Future<Output = Option<Frame>>,
+    {
+        let Some(key) = request.get_header(IDEMPOTENCY_KEY_HEADER) else {
+            return call.await;
+        };
+        let Ok(request_hash) = request_hash(request) else {
+            return call.await;
+        };
+        // Stored replies belong to an identity, so the same key from another is another request
+        let flight = match &self.store {
+            Some(_) => format!("{}\0{}", identity, key),
+            None => key.to_string(),
+        };
+        let follow = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&flight) {
+                Some((hash, _)) if *hash != request_hash => return Some(key_reused(key)),
+                Some((_, tx)) => Some(tx.subscribe()),
+                None => {
+                    calls.insert(flight.clone(), (request_hash, broadcast::channel(1).0));
+                    None
+                }
+            }
+        };
+        if let Some(mut leader) = follow {
+            let reply = leader.recv().await.ok().flatten()?;
+            return Some(reply.with_header(COALESCED_HEADER, "true"));
+        }
+        let mut guard = FlightGuard {
+            flights: self,
+            key: flight,
+            reply: None,
+        };
+        // Looked up as leader, so a reply stored meanwhile can't be missed
+        if let Some(store) = &self.store {
+            if let Some(kept) = store.get(identity, key).await {
+                if kept.request_hash != request_hash {
+                    guard.reply = Some(key_reused(key));
+                    return guard.reply.clone();
+                }
+                let reply = kept.reply.with_header(REPLAYED_HEADER, "true");
+                guard.reply = Some(reply.clone());
+                return Some(reply);
+            }
+        }
+        let reply = call.await;
+        if let (Some(store), Some(reply)) = (&self.store, &reply) {
+            if replayable(reply) {
+                let kept = KeptReply {
+                    request_hash,
+                    reply: reply.clone(),
+                };
+                store.put(identity, key, kept).await;
+            }
+        }
+        guard.reply = reply.clone();
+        reply
+    }
+}
+
+/// Announces the reply of a key's request to its followers when dropped
+///
+/// A leader that panicked announces `None`, so its followers don't wait forever.
+struct FlightGuard<'a> {
+    flights: &'a SingleFlight,
+    key: String,
+    reply: Option<Frame>,
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        let Ok(mut calls) = self.flights.calls.lock() else {
+            return;
+        };
+        // Sent under the lock, so no follower subscribes after the reply went out
+        if let Some((_, tx)) = calls.remove(&self.key) {
+            let _ = tx.send(self.reply.take());
+        }
+    }
+}
+
+/// The ERR refusing a request that reused `key` for different content
+fn key_reused(key: &str) -> Frame {
+    Frame::coded_error(
+        ErrorCode::IdempotencyKeyReused,
+        &format!("idempotency key {} was used for a different request", key),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(text: &str) -> KeptReply {
+        KeptReply {
+            request_hash: [0; 32],
+            reply: Frame::new(FrameType::Data).with_payload(text.as_bytes().to_vec()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replies_are_kept_per_identity() {
+        let store = MemoryIdempotencyStore::new(Duration::from_secs(60), 8);
+        store.put("alice", "k1", reply("a")).await;
+        assert_eq!(store.get("alice", "k1").await, Some(reply("a")));
+        assert_eq!(store.get("bob", "k1").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_reply_is_dropped_when_full() {
+        let store = MemoryIdempotencyStore::new(Duration::from_secs(60), 2);
+        store.put("a", "1", reply("1")).await;
+        store.put("a", "2", reply("2")).await;
+        store.put("a", "3", reply("3")).await;
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("a", "1").await, None);
+        assert_eq!(store.get("a", "3").await, Some(reply("3")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replies_expire_after_ttl() {
+        let store = MemoryIdempotencyStore::new(Duration::from_secs(60), 8);
+        store.put("a", "k", reply("x")).await;
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(store.get("a", "k").await, None);
+        assert!(store.is_empty());
+    }
+}
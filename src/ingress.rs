@@ -0,0 +1,244 @@
+//! Screening frames from untrusted peers before anything else sees them
+//!
+//! An [`IngressPolicy`] set in [`TcpServerConfig::ingress`](crate::tcp::TcpServerConfig::ingress)
+//! or [`UdpServerConfig::ingress`](crate::udp::UdpServerConfig::ingress) is
+//! applied to every frame as it is received, so headers it strips are gone
+//! before any handler or middleware runs. Each frame goes through these
+//! steps in order:
+//!
+//! 1. header keys are lowercased, if `normalize_keys` is set
+//! 2. frames of a type outside `allowed_types` are rejected
+//! 3. frames carrying a header matching `reject_headers` are rejected
+//! 4. headers matching `strip_headers` are removed
+//! 5. frames with more than `max_headers` headers, or more than
+//!    `max_header_bytes` of keys and values, are rejected
+//! 6. flag bits VSTP doesn't define are cleared, if `clamp_flags` is set
+//!
+//! Header patterns match keys ignoring ASCII case; a trailing `*` matches any
+//! key with that prefix, e.g. `x-internal-*`. TCP servers answer a rejected
+//! frame with an ERR frame with error code `PolicyViolation` and keep the
+//! connection; UDP servers drop it without a reply, since the source address
+//! is unverified.
+//!
+//! A server's `allowed_frame_types`
+//! ([`TcpServerConfig`](crate::tcp::TcpServerConfig::allowed_frame_types),
+//! [`UdpServerConfig`](crate::udp::UdpServerConfig::allowed_frame_types)) is
+//! checked earlier still, as soon as a frame is decoded: frames of other
+//! types never reach compression negotiation, probing, reassembly or
+//! ACKing. TCP servers answer them with ERR `BadRequest`; UDP servers drop
+//! them. Frames the server sends itself, like ACKs and PONGs, aren't
+//! affected.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::types::{Flags, Frame, FrameType};
+
+/// Least time between two warnings about frames of a disallowed type
+const TYPE_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rules applied to every frame a server receives
+#[derive(Debug, Clone, Default)]
+pub struct IngressPolicy {
+    /// Headers removed from frames
+    pub strip_headers: Vec<String>,
+    /// Headers that get the whole frame rejected
+    pub reject_headers: Vec<String>,
+    /// Most headers a frame may carry, counted after stripping
+    pub max_headers: Option<usize>,
+    /// Most bytes of header keys and values a frame may carry, counted after stripping
+    pub max_header_bytes: Option<usize>,
+    /// Lowercase header keys before anything else
+    pub normalize_keys: bool,
+    /// Frame types accepted; `None` accepts all
+    pub allowed_types: Option<Vec<FrameType>>,
+    /// Clear flag bits VSTP doesn't define
+    pub clamp_flags: bool,
+}
+
+/// What an [`IngressPolicy`] has done on a server
+#[derive(Debug, Default)]
+pub struct IngressStats {
+    stripped_headers: AtomicU64,
+    rejected_frames: AtomicU64,
+    normalized_keys: AtomicU64,
+    clamped_flags: AtomicU64,
+}
+
+impl IngressStats {
+    /// Headers removed by `strip_headers`
+    pub fn stripped_header_count(&self) -> u64 {
+        self.stripped_headers.load(Ordering::Relaxed)
+    }
+
+    /// Frames rejected by any rule
+    pub fn rejected_frame_count(&self) -> u64 {
+        self.rejected_frames.load(Ordering::Relaxed)
+    }
+
+    /// Header keys that had to be lowercased
+    pub fn normalized_key_count(&self) -> u64 {
+        self.normalized_keys.load(Ordering::Relaxed)
+    }
+
+    /// Frames whose unknown flag bits were cleared
+    pub fn clamped_flag_count(&self) -> u64 {
+        self.clamped_flags.load(Ordering::Relaxed)
+    }
+}
+
+impl IngressPolicy {
+    /// Apply the policy to `frame`, returning why it is rejected if it is
+    pub fn apply(&self, frame: &mut Frame, stats: &IngressStats) -> Result<(), String> {
+        let result = self.screen(frame, stats);
+        if result.is_err() {
+            stats.rejected_frames.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn screen(&self, frame: &mut Frame, stats: &IngressStats) -> Result<(), String> {
+        if self.normalize_keys {
+            for header in &mut frame.headers {
+                if header.key.iter().any(u8::is_ascii_uppercase) {
+                    header.key.make_ascii_lowercase();
+                    stats.normalized_keys.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if let Some(allowed) = &self.allowed_types {
+            if !allowed.contains(&frame.typ) {
+                return Err(format!("{:?} frames are not accepted", frame.typ));
+            }
+        }
+        if let Some(header) = frame
+            .headers
+            .iter()
+            .find(|h| matches_any(&self.reject_headers, &h.key))
+        {
+            return Err(format!(
+                "header {} is not allowed",
+                String::from_utf8_lossy(&header.key)
+            ));
+        }
+
+        let before = frame.headers.len();
+        frame
+            .headers
+            .retain(|h| !matches_any(&self.strip_headers, &h.key));
+        let stripped = before - frame.headers.len();
+        if stripped > 0 {
+            stats
+                .stripped_headers
+                .fetch_add(stripped as u64, Ordering::Relaxed);
+        }
+
+        if let Some(max) = self.max_headers {
+            if frame.headers.len() > max {
+                return Err(format!(
+                    "{} headers exceed the limit of {}",
+                    frame.headers.len(),
+                    max
+                ));
+            }
+        }
+        if let Some(max) = self.max_header_bytes {
+            let bytes = frame.header_bytes();
+            if bytes > max {
+                return Err(format!(
+                    "{} header bytes exceed the limit of {}",
+                    bytes, max
+                ));
+            }
+        }
+
+        if self.clamp_flags && !Flags::all().contains(frame.flags) {
+            frame.flags &= Flags::all();
+            stats.clamped_flags.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// A server's `allowed_frame_types`, with a count of the frames it refused
+#[derive(Debug)]
+pub(crate) struct FrameTypeFilter {
+    allowed: Option<HashSet<FrameType>>,
+    rejected: AtomicU64,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl FrameTypeFilter {
+    pub(crate) fn new(allowed: Option<HashSet<FrameType>>) -> Self {
+        Self {
+            allowed,
+            rejected: AtomicU64::new(0),
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    /// Whether a frame of type `typ` from `peer` is accepted
+    ///
+    /// Refused frames are counted and logged, with at most one warning per
+    /// second and the rest at debug level.
+    pub(crate) fn allows(&self, typ: FrameType, peer: impl fmt::Display) -> bool {
+        let Some(allowed) = &self.allowed else {
+            return true;
+        };
+        if allowed.contains(&typ) {
+            return true;
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        let warn_now = {
+            let mut last = self.last_warning.lock().unwrap();
+            let due = !last.is_some_and(|at| at.elapsed() < TYPE_WARNING_INTERVAL);
+            if due {
+                *last = Some(Instant::now());
+            }
+            due
+        };
+        if warn_now {
+            warn!("Refused a {:?} frame from {}: type not allowed", typ, peer);
+        } else {
+            debug!("Refused a {:?} frame from {}: type not allowed", typ, peer);
+        }
+        false
+    }
+
+    pub(crate) fn rejected_count(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether `key` matches one of `patterns`, ignoring ASCII case
+fn matches_any(patterns: &[String], key: &[u8]) -> bool {
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.as_bytes();
+        match pattern.strip_suffix(b"*") {
+            Some(prefix) => {
+                key.len() >= prefix.len() && key[..prefix.len()].eq_ignore_ascii_case(prefix)
+            }
+            None => key.eq_ignore_ascii_case(pattern),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns_ignore_case_and_match_prefixes() {
+        let patterns = vec!["x-internal-*".to_string(), "debug".to_string()];
+        assert!(matches_any(&patterns, b"x-internal-user"));
+        assert!(matches_any(&patterns, b"X-Internal-User"));
+        assert!(matches_any(&patterns, b"DEBUG"));
+        assert!(!matches_any(&patterns, b"debug-level"));
+        assert!(!matches_any(&patterns, b"x-intern"));
+    }
+}
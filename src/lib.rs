@@ -2,14 +2,14 @@
 //!
 //! A general-purpose, binary, extensible application-layer protocol designed to be:
 //!
-//! * **Secure by default** on TCP (TLS 1.3)
+//! * **Secure** on TCP, with TLS 1.3 behind the `tls` feature
 //! * **Fast** on UDP (no TLS initially)
 //! * **Minimal but extensible** with binary headers
 //! * **Easy to implement** across languages
 //!
 //! ## Quick Start
 //!
-//! ```rust
+//! ```rust,no_run
 //! use vstp::easy::{VstpClient, VstpServer};
 //! use serde::{Serialize, Deserialize};
 //!
@@ -18,6 +18,7 @@
 //!     content: String,
 //! }
 //!
+//! # async fn run() -> Result<(), vstp::VstpError> {
 //! // Start a TCP server
 //! let server = VstpServer::bind_tcp("127.0.0.1:8080").await?;
 //! tokio::spawn(async move {
@@ -37,7 +38,8 @@
 //! // Receive the response
 //! let response: Message = client.receive().await?;
 //! println!("Got response: {}", response.content);
-//! # Ok::<(), vstp::VstpError>(())
+//! # Ok(())
+//! # }
 //! ```
 //!
 //! ## Protocol Overview
@@ -58,16 +60,20 @@
 //! - **MAGIC**: `0x56 0x54` ("VT") to identify VSTP
 //! - **VER**: Protocol version (`0x01` for v1)
 //! - **TYPE**: Message type (Hello, Welcome, Data, etc.)
-//! - **FLAGS**: Bit flags (REQ_ACK, CRC, FRAG, COMP)
+//! - **FLAGS**: Bit flags (REQ_ACK, CRC, FRAG, COMP, SHA256)
 //! - **HDR_LEN**: Little-endian header section length
 //! - **PAY_LEN**: Big-endian payload length
 //! - **HEADERS**: Concatenated binary K/V entries
 //! - **PAYLOAD**: Raw bytes (UTF-8 text, JSON, binary, etc.)
 //! - **CHECKSUM**: CRC16-IBM over HEADERS|PAYLOAD (optional)
 //!
+//! Version 2 frames, negotiated per TCP connection during the handshake, use
+//! big-endian 32-bit lengths throughout; [`testvectors`] documents both layouts.
+//!
 //! ## Transport Modes
 //!
-//! - **TCP mode**: Reliable + encrypted (TLS 1.3 via rustls)
+//! - **TCP mode**: Reliable; encrypted with TLS 1.3 via rustls when the
+//!   `tls` feature is on and a TLS config is given, see [`tcp::tls`]
 //! - **UDP mode**: Connectionless + fast (no TLS in v0.1)
 //!
 //! ## Message Types
@@ -83,22 +89,53 @@
 //! | 0x07 | ACK     | Both            | Acknowledgement               |
 //! | 0x08 | ERR     | Both            | Error frame                   |
 
+pub mod archive;
+pub mod chunk;
+pub mod clock;
 pub mod codec;
+pub mod compression;
+pub mod diagnostics;
+#[cfg(feature = "e2e")]
+pub mod e2e;
 pub mod easy;
+pub mod flow;
 pub mod frame;
+pub mod header_table;
+pub mod idempotency;
+pub mod ingress;
+pub mod meta;
+pub mod migration;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pool;
+pub mod pubsub;
+pub mod router;
+pub mod schema;
+pub mod shaping;
+pub mod socket;
 pub mod tcp;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod testvectors;
 pub mod types;
 pub mod udp;
+pub mod usage;
 
 // Re-export main types for convenience
-pub use types::{Flags, Frame, FrameType, Header, SessionId, VstpError, VSTP_MAGIC, VSTP_VERSION};
+pub use types::{
+    DisconnectReason, ErrorCode, Flags, Frame, FrameType, Header, HeaderRatioLimit, Priority,
+    SessionId, VstpError, VSTP_MAGIC, VSTP_VERSION, VSTP_VERSION_2,
+};
 
-pub use codec::VstpFrameCodec;
-pub use frame::{encode_frame, try_decode_frame};
+pub use codec::{FrameDecoder, VstpFrameCodec, WireTap};
+pub use frame::{encode_frame, encode_frame_canonical, try_decode_frame, Integrity};
 
 // Re-export TCP and UDP modules
-pub use tcp::{VstpTcpClient, VstpTcpServer};
+pub use tcp::{VstpByteStream, VstpTcpClient, VstpTcpServer};
 pub use udp::{VstpUdpClient, VstpUdpServer};
 
 // Re-export easy-to-use API
-pub use easy::{VstpClient, VstpServer};
+pub use easy::{ConnectOptions, ServerOptions, VstpClient, VstpServer};
+pub use pool::{PoolStats, VstpClientPool};
+pub use router::Router;
+pub use schema::VstpMessage;
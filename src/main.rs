@@ -1,4 +1,211 @@
-fn main() {
-    println!("yo man welcum")
+//! `vstp` command line tool
+//!
+//! ```text
+//! vstp doctor <tcp|udp> <host:port>   run the connectivity self-test
+//! vstp ident <host:port>              show what a TCP server says it runs
+//! vstp archive dump <file>            print every record of a frame archive
+//! vstp archive grep <file> [--session <id>] [--type <type>]
+//!                                     print the records matching all filters
+//! vstp archive reindex <file>         rebuild an archive's index sidecar
+//! ```
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process::ExitCode;
+use std::time::{Duration, UNIX_EPOCH};
+
+use vstp::archive::{self, ArchiveRecord, Direction, FrameArchiveReader};
+use vstp::diagnostics::{self, DiagnosticOptions, Endpoint};
+use vstp::{FrameType, SessionId, VstpError};
+
+const USAGE: &str = "usage: vstp doctor <tcp|udp> <host:port>
+       vstp ident <host:port>
+       vstp archive dump <file>
+       vstp archive grep <file> [--session <id>] [--type <type>]
+       vstp archive reindex <file>";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("doctor") => doctor(&args[1..]).await,
+        Some("ident") => ident(&args[1..]).await,
+        Some("archive") => archive(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
 }
 
+async fn doctor(args: &[String]) -> ExitCode {
+    let (transport, addr) = match args {
+        [transport, addr] => (transport.as_str(), addr),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let addr = match resolve(addr) {
+        Some(addr) => addr,
+        None => {
+            eprintln!("cannot resolve {}", addr);
+            return ExitCode::FAILURE;
+        }
+    };
+    let endpoint = match transport {
+        "tcp" => Endpoint::Tcp(addr),
+        "udp" => Endpoint::Udp(addr),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let report = diagnostics::self_test(endpoint, DiagnosticOptions::default()).await;
+    println!("{}", report);
+
+    if report.passed() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+async fn ident(args: &[String]) -> ExitCode {
+    let [addr] = args else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let Some(addr) = resolve(addr) else {
+        eprintln!("cannot resolve {}", addr);
+        return ExitCode::FAILURE;
+    };
+
+    match diagnostics::ident(addr, Duration::from_secs(5)).await {
+        Ok(ident) => {
+            println!("{}", ident);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}: {}", addr, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn archive(args: &[String]) -> ExitCode {
+    let result = match args {
+        [command, path] if command == "dump" => dump(path, None, None),
+        [command, path, filters @ ..] if command == "grep" => match parse_filters(filters) {
+            Some((session, typ)) => dump(path, session, typ),
+            None => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        },
+        [command, path] if command == "reindex" => archive::reindex(path).map(|records| {
+            println!("indexed {} records", records);
+        }),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `--session` and `--type` filters of `archive grep`
+fn parse_filters(args: &[String]) -> Option<(Option<SessionId>, Option<FrameType>)> {
+    let (mut session, mut typ) = (None, None);
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next()?;
+        match flag.as_str() {
+            "--session" => session = Some(value.parse().ok()?),
+            "--type" => {
+                typ = Some(
+                    (0..=u8::MAX)
+                        .filter_map(FrameType::from_u8)
+                        .find(|t| format!("{:?}", t).eq_ignore_ascii_case(value))?,
+                )
+            }
+            _ => return None,
+        }
+    }
+    Some((session, typ))
+}
+
+fn dump(path: &str, session: Option<SessionId>, typ: Option<FrameType>) -> Result<(), VstpError> {
+    let mut reader = FrameArchiveReader::open(path)?;
+    let matches = |record: &ArchiveRecord| typ.is_none_or(|typ| record.frame.typ == typ);
+    // Without a session filter record numbers are positions in the archive
+    match session {
+        Some(session) => {
+            for record in reader.session(session) {
+                let record = record?;
+                if matches(&record) {
+                    print_record(None, &record);
+                }
+            }
+        }
+        None => {
+            for (n, record) in reader.iter().enumerate() {
+                let record = record?;
+                if matches(&record) {
+                    print_record(Some(n), &record);
+                }
+            }
+        }
+    }
+    if let Some(offset) = reader.corrupt_tail() {
+        eprintln!("archive ends in a corrupt record at byte {}", offset);
+    }
+    Ok(())
+}
+
+fn print_record(n: Option<usize>, record: &ArchiveRecord) {
+    let micros = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros();
+    let direction = match record.direction {
+        Direction::Inbound => "in ",
+        Direction::Outbound => "out",
+    };
+    let headers: Vec<String> = record
+        .frame
+        .headers
+        .iter()
+        .map(|h| {
+            format!(
+                "{}={}",
+                String::from_utf8_lossy(&h.key),
+                String::from_utf8_lossy(&h.value)
+            )
+        })
+        .collect();
+    let n = n.map(|n| format!("#{:<8} ", n)).unwrap_or_default();
+    println!(
+        "{}{}.{:06} session={} {} {:?} [{}] {} bytes",
+        n,
+        micros / 1_000_000,
+        micros % 1_000_000,
+        record.session_id,
+        direction,
+        record.frame.typ,
+        headers.join(" "),
+        record.frame.payload.len()
+    );
+}
+
+fn resolve(addr: &str) -> Option<SocketAddr> {
+    addr.to_socket_addrs().ok()?.next()
+}
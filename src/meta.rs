@@ -0,0 +1,68 @@
+//! When and how a received frame arrived
+//!
+//! Servers record a [`FrameMeta`] for every frame they hand to a handler, so
+//! latency can be split into time on the wire and time spent waiting for the
+//! handler:
+//!
+//! ```text
+//! queue delay = handler start - received_at
+//! ```
+//!
+//! `received_at` is taken in userspace as soon as the frame is decoded. On
+//! Linux, UDP servers with
+//! [`kernel_timestamps`](crate::udp::UdpServerConfig::kernel_timestamps) set
+//! also get the time the kernel received the datagram (`SO_TIMESTAMPNS`),
+//! which excludes the time the datagram sat in the socket buffer. Elsewhere,
+//! and for TCP, `kernel_timestamp` is `None`.
+//!
+//! Servers can also keep the exact bytes each frame arrived as, in
+//! [`raw_bytes`](FrameMeta::raw_bytes), for relays that store frames and
+//! forward them verbatim to another endpoint, e.g. so signatures over the
+//! bytes still verify there. It costs a copy of every frame, so it is off
+//! unless asked for with
+//! [`TcpServerConfig::keep_raw_bytes`](crate::tcp::TcpServerConfig::keep_raw_bytes),
+//! [`UdpServerConfig::keep_raw_bytes`](crate::udp::UdpServerConfig::keep_raw_bytes)
+//! or [`ServerOptions::keep_raw_bytes`](crate::easy::ServerOptions::keep_raw_bytes).
+
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::Bytes;
+
+use crate::easy::TransportKind;
+
+/// Receive metadata of one frame
+///
+/// Cheap to clone: the raw bytes, if kept, are reference counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameMeta {
+    /// When the frame was decoded
+    pub received_at: Instant,
+    /// When the kernel received the datagram, if it was asked to record it
+    pub kernel_timestamp: Option<SystemTime>,
+    /// Encoded size of the frame, CRC included
+    pub wire_len: usize,
+    pub transport: TransportKind,
+    /// The frame's bytes as received, trailer included, if the server keeps them
+    ///
+    /// These are the bytes on the wire: for a compressed frame they hold the
+    /// compressed payload.
+    pub raw_bytes: Option<Bytes>,
+}
+
+impl FrameMeta {
+    /// Metadata for a frame of `wire_len` bytes decoded just now
+    pub fn now(transport: TransportKind, wire_len: usize) -> Self {
+        Self {
+            received_at: Instant::now(),
+            kernel_timestamp: None,
+            wire_len,
+            transport,
+            raw_bytes: None,
+        }
+    }
+
+    /// Time since the frame was received; the queue delay when called as its handler starts
+    pub fn queue_delay(&self) -> Duration {
+        self.received_at.elapsed()
+    }
+}
@@ -0,0 +1,381 @@
+//! Moving sessions between server instances
+//!
+//! A rolling restart replaces a stateful server with a new instance. Rather
+//! than have every client build its state up again, the old instance can
+//! hand each session over:
+//!
+//! 1. Every session a [`VstpServer`] welcomes gets an id, sent to the client
+//!    in the WELCOME's [`SESSION_ID_HEADER`]. Handlers keep per-session state
+//!    in the session's [`SessionExtensions`], see
+//!    [`current_session_extensions`].
+//! 2. [`SessionRegistry::snapshot_session`] on the old instance takes the
+//!    session's [`SessionSnapshot`], whose
+//!    [`to_bytes`](SessionSnapshot::to_bytes) the deployment carries to the
+//!    new instance however it likes.
+//! 3. [`SessionRegistry::restore_session`] on the new instance holds the
+//!    session for its client.
+//! 4. The old instance answers the client's next frame with an ERR
+//!    `Redirect` naming the new address in [`REDIRECT_TO_HEADER`], and
+//!    closes the connection. A TCP [`VstpClient`] reconnects there,
+//!    presenting the id in its HELLO, and the new instance gives the session
+//!    the restored extensions. A [`VstpClient::call`] that was redirected is
+//!    sent again once the client has moved.
+//!
+//! Only extensions inserted with [`SessionExtensions::insert_snapshot`], of
+//! a type implementing [`Snapshot`], go into a snapshot. Others are left out
+//! and named in [`SessionSnapshot::dropped`], so the caller can log what was
+//! lost. A snapshot restores once, and only for a HELLO with the identity
+//! and service the session had; any other HELLO starts a new session.
+//!
+//! The negotiated parameters and flow-control window belong to the
+//! connection, so the reconnecting HELLO negotiates them afresh. Changes a
+//! handler makes to a session after its snapshot is taken are lost, and the
+//! old instance must keep serving until its clients have moved.
+//!
+//! [`VstpServer`]: crate::easy::VstpServer
+//! [`VstpClient`]: crate::easy::VstpClient
+//! [`VstpClient::call`]: crate::easy::VstpClient::call
+//! [`current_session_extensions`]: crate::easy::current_session_extensions
+
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{SessionId, VstpError};
+
+/// Header carrying a session's id, on the WELCOME that opens it and on a
+/// HELLO claiming it back, as 32 hex digits
+pub const SESSION_ID_HEADER: &str = "session-id";
+
+/// Header on an ERR `Redirect` frame carrying the address the session moved to
+pub const REDIRECT_TO_HEADER: &str = "redirect-to";
+
+/// How long a restored session waits for its client before it is dropped
+const UNCLAIMED_TTL: Duration = Duration::from_secs(300);
+
+/// The header value of session `id`
+pub(crate) fn format_session_id(id: SessionId) -> String {
+    format!("{:032x}", id)
+}
+
+/// The session id in a [`SESSION_ID_HEADER`] value
+pub(crate) fn parse_session_id(value: &str) -> Option<SessionId> {
+    SessionId::from_str_radix(value, 16).ok()
+}
+
+/// Session state that can move to another server instance
+pub trait Snapshot: Any + Send + Sync + Sized {
+    /// Name the bytes are kept under, the same on every instance
+    const NAME: &'static str;
+
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// `None` if `bytes` don't hold a value of this type
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+type Value = Box<dyn Any + Send + Sync>;
+
+/// [`Snapshot::NAME`] and encoder of an extension that implements it
+type Encoder = (&'static str, fn(&Value) -> Vec<u8>);
+
+struct Extension {
+    value: Value,
+    type_name: &'static str,
+    snapshot: Option<Encoder>,
+}
+
+fn encode<T: Snapshot>(value: &Value) -> Vec<u8> {
+    value
+        .downcast_ref::<T>()
+        .expect("extensions are kept under their own type")
+        .to_bytes()
+}
+
+#[derive(Default)]
+struct Extensions {
+    values: HashMap<TypeId, Extension>,
+    /// Bytes restored from a snapshot, by [`Snapshot::NAME`], decoded when
+    /// first read
+    restored: BTreeMap<String, Vec<u8>>,
+}
+
+/// Per-session values kept by handlers, one of each type
+///
+/// Clones share the values. See [`migration`](crate::migration) for how
+/// they move with their session.
+#[derive(Clone, Default)]
+pub struct SessionExtensions {
+    inner: Arc<Mutex<Extensions>>,
+}
+
+impl SessionExtensions {
+    /// Keep `value` for the rest of the session, replacing the one of its
+    /// type; it is left out of snapshots
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) {
+        self.keep(value, None);
+    }
+
+    /// Keep `value` for the rest of the session and in its snapshots,
+    /// replacing the one of its type
+    pub fn insert_snapshot<T: Snapshot>(&self, value: T) {
+        self.keep(value, Some((T::NAME, encode::<T> as fn(&Value) -> Vec<u8>)));
+    }
+
+    fn keep<T: Any + Send + Sync>(&self, value: T, snapshot: Option<Encoder>) {
+        let mut extensions = self.inner.lock().unwrap();
+        if let Some((name, _)) = snapshot {
+            extensions.restored.remove(name);
+        }
+        let extension = Extension {
+            value: Box::new(value),
+            type_name: std::any::type_name::<T>(),
+            snapshot,
+        };
+        extensions.values.insert(TypeId::of::<T>(), extension);
+    }
+
+    /// A copy of the value of type `T`
+    pub fn get<T: Any + Clone>(&self) -> Option<T> {
+        let extensions = self.inner.lock().unwrap();
+        extensions.values.get(&TypeId::of::<T>())?.value.downcast_ref().cloned()
+    }
+
+    /// A copy of the value of type `T`, decoding it from the session's
+    /// snapshot if it was restored on this instance
+    pub fn get_snapshot<T: Snapshot + Clone>(&self) -> Option<T> {
+        if let Some(value) = self.get::<T>() {
+            return Some(value);
+        }
+        let bytes = self.inner.lock().unwrap().restored.remove(T::NAME)?;
+        let value = T::from_bytes(&bytes)?;
+        self.insert_snapshot(value.clone());
+        Some(value)
+    }
+
+    /// Remove the value of type `T`; whether there was one
+    pub fn remove<T: Any>(&self) -> bool {
+        self.inner.lock().unwrap().values.remove(&TypeId::of::<T>()).is_some()
+    }
+
+    /// The snapshot bytes of each extension, and the type names of those
+    /// without a [`Snapshot`] impl
+    fn snapshot(&self) -> (BTreeMap<String, Vec<u8>>, Vec<String>) {
+        let extensions = self.inner.lock().unwrap();
+        // Restored values nobody read yet move on as they came
+        let mut saved = extensions.restored.clone();
+        let mut dropped = Vec::new();
+        for extension in extensions.values.values() {
+            match extension.snapshot {
+                Some((name, encode)) => {
+                    saved.insert(name.to_string(), encode(&extension.value));
+                }
+                None => dropped.push(extension.type_name.to_string()),
+            }
+        }
+        dropped.sort();
+        (saved, dropped)
+    }
+
+    fn restored(saved: BTreeMap<String, Vec<u8>>) -> Self {
+        let extensions = Extensions {
+            values: HashMap::new(),
+            restored: saved,
+        };
+        Self {
+            inner: Arc::new(Mutex::new(extensions)),
+        }
+    }
+}
+
+impl fmt::Debug for SessionExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let extensions = self.inner.lock().unwrap();
+        let types: Vec<_> = extensions.values.values().map(|e| e.type_name).collect();
+        f.debug_struct("SessionExtensions")
+            .field("types", &types)
+            .field("restored", &extensions.restored.keys())
+            .finish()
+    }
+}
+
+/// What moves to another instance with a session, see
+/// [`migration`](crate::migration)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    /// The id the session's client presents to claim it
+    pub id: SessionId,
+    /// Identity the session authenticated as, if any
+    pub identity: Option<String>,
+    /// Service the session picked; `None` for the default
+    pub service: Option<String>,
+    /// Bytes of each extension, by [`Snapshot::NAME`]
+    pub extensions: BTreeMap<String, Vec<u8>>,
+    /// Type names of the extensions left out, as they don't implement
+    /// [`Snapshot`]
+    #[serde(default)]
+    pub dropped: Vec<String>,
+}
+
+impl SessionSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("snapshots serialize")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VstpError> {
+        serde_json::from_slice(bytes)
+            .map_err(|e| VstpError::Protocol(format!("Bad session snapshot: {}", e)))
+    }
+}
+
+struct Live {
+    identity: Option<String>,
+    service: Option<String>,
+    extensions: SessionExtensions,
+    /// Where the session moved, once its snapshot is taken
+    moved_to: Option<String>,
+}
+
+#[derive(Default)]
+struct Registry {
+    live: HashMap<SessionId, Live>,
+    /// Restored sessions waiting for their client, with when they arrived
+    restored: HashMap<SessionId, (SessionSnapshot, Instant)>,
+}
+
+/// The sessions of a [`VstpServer`](crate::easy::VstpServer), from
+/// [`VstpServer::sessions`](crate::easy::VstpServer::sessions)
+///
+/// Clones share the sessions, so it can be kept while the server runs.
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl SessionRegistry {
+    /// Ids of the sessions open on this instance that haven't moved
+    pub fn session_ids(&self) -> Vec<SessionId> {
+        let registry = self.inner.lock().unwrap();
+        let mut ids: Vec<_> = registry
+            .live
+            .iter()
+            .filter(|(_, live)| live.moved_to.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Take the snapshot of session `id` and send its client to `redirect_to`
+    ///
+    /// The client is redirected on its next frame. Fails if `redirect_to`
+    /// isn't a socket address, or if there is no such session or it already
+    /// moved.
+    pub fn snapshot_session(
+        &self,
+        id: SessionId,
+        redirect_to: &str,
+    ) -> Result<SessionSnapshot, VstpError> {
+        redirect_to
+            .parse::<SocketAddr>()
+            .map_err(|_| VstpError::InvalidAddress)?;
+        let mut registry = self.inner.lock().unwrap();
+        let live = registry
+            .live
+            .get_mut(&id)
+            .filter(|live| live.moved_to.is_none())
+            .ok_or_else(|| VstpError::Protocol(format!("No session {}", format_session_id(id))))?;
+        let (extensions, dropped) = live.extensions.snapshot();
+        if !dropped.is_empty() {
+            tracing::warn!(
+                "Session {} moves without extensions {:?}",
+                format_session_id(id),
+                dropped
+            );
+        }
+        live.moved_to = Some(redirect_to.to_string());
+        Ok(SessionSnapshot {
+            id,
+            identity: live.identity.clone(),
+            service: live.service.clone(),
+            extensions,
+            dropped,
+        })
+    }
+
+    /// Hold the session in the [`SessionSnapshot::to_bytes`] `bytes` for its
+    /// client to claim; its id
+    ///
+    /// A session nobody claims is dropped after five minutes.
+    pub fn restore_session(&self, bytes: &[u8]) -> Result<SessionId, VstpError> {
+        let snapshot = SessionSnapshot::from_bytes(bytes)?;
+        let id = snapshot.id;
+        let mut registry = self.inner.lock().unwrap();
+        registry.prune();
+        registry.restored.insert(id, (snapshot, Instant::now()));
+        Ok(id)
+    }
+
+    /// Open a session for a HELLO with `identity` on `service`: the restored
+    /// session the HELLO `claimed`, if it matches, or else a new one
+    pub(crate) fn open(
+        &self,
+        claimed: Option<SessionId>,
+        identity: Option<&str>,
+        service: Option<&str>,
+    ) -> (SessionId, SessionExtensions) {
+        let mut registry = self.inner.lock().unwrap();
+        registry.prune();
+        let matches = |snapshot: &SessionSnapshot| {
+            snapshot.identity.as_deref() == identity && snapshot.service.as_deref() == service
+        };
+        let restored = claimed
+            .filter(|id| !registry.live.contains_key(id))
+            .filter(|id| registry.restored.get(id).is_some_and(|(s, _)| matches(s)))
+            .and_then(|id| registry.restored.remove(&id))
+            .map(|(snapshot, _)| snapshot);
+        let (id, extensions) = match restored {
+            Some(snapshot) => (snapshot.id, SessionExtensions::restored(snapshot.extensions)),
+            None => (rand::random(), SessionExtensions::default()),
+        };
+        let live = Live {
+            identity: identity.map(str::to_string),
+            service: service.map(str::to_string),
+            extensions: extensions.clone(),
+            moved_to: None,
+        };
+        registry.live.insert(id, live);
+        (id, extensions)
+    }
+
+    /// Where session `id` moved, if it did
+    pub(crate) fn moved_to(&self, id: SessionId) -> Option<String> {
+        let registry = self.inner.lock().unwrap();
+        registry.live.get(&id)?.moved_to.clone()
+    }
+
+    pub(crate) fn close(&self, id: SessionId) {
+        self.inner.lock().unwrap().live.remove(&id);
+    }
+}
+
+impl Registry {
+    fn prune(&mut self) {
+        self.restored.retain(|_, (_, arrived)| arrived.elapsed() < UNCLAIMED_TTL);
+    }
+}
+
+impl fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let registry = self.inner.lock().unwrap();
+        f.debug_struct("SessionRegistry")
+            .field("live", &registry.live.len())
+            .field("restored", &registry.restored.len())
+            .finish()
+    }
+}
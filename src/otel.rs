@@ -0,0 +1,212 @@
+//! OpenTelemetry spans for requests and handlers, behind the `otel` feature
+//!
+//! A client connected with [`ConnectOptions::propagate_trace_context`] wraps
+//! each [`VstpClient::call`] in a client span and sends its W3C trace context
+//! in the request's [`TRACEPARENT_HEADER`] and [`TRACESTATE_HEADER`]. A
+//! [`Router`] with an [`OtelLayer`] picks the context up and runs each handler
+//! in a server span that is its child, so both sides join the caller's
+//! distributed trace. The client span is a child of the context the call runs
+//! in, e.g. one attached with [`FutureExt::with_context`].
+//!
+//! Spans go to the global tracer provider, see
+//! [`opentelemetry::global::set_tracer_provider`], and carry:
+//!
+//! - `vstp.method`: the method called
+//! - `vstp.frame_type`: type of the request frame, e.g. `DATA`
+//! - `vstp.payload_bytes`: size of the request payload
+//! - `network.peer.address` and `network.peer.port`: the other side
+//!
+//! Server spans also record `vstp.response.payload_bytes` and end with an
+//! error status when the reply is an ERR. A request the client sends again,
+//! after reconnecting or waiting out a pause the server asked for, adds a
+//! `vstp.retry` event to its span naming the `vstp.retry.reason`.
+//!
+//! [`ConnectOptions::propagate_trace_context`]: crate::easy::ConnectOptions::propagate_trace_context
+//! [`VstpClient::call`]: crate::easy::VstpClient::call
+//! [`Router`]: crate::router::Router
+//! [`FutureExt::with_context`]: opentelemetry::context::FutureExt::with_context
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use opentelemetry::context::FutureExt;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+use crate::types::{Frame, FrameType, Header, VstpError};
+
+/// Header carrying the W3C `traceparent` of the span a request belongs to
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Header carrying the W3C `tracestate` that goes with [`TRACEPARENT_HEADER`]
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Name of the tracer VSTP spans are created with
+pub const TRACER_NAME: &str = "vstp";
+
+/// Write the trace context of `cx` into `frame`'s headers
+///
+/// Replaces any trace context the frame already had.
+pub fn inject(cx: &Context, mut frame: Frame) -> Frame {
+    TraceContextPropagator::new().inject_context(cx, &mut FrameInjector(&mut frame));
+    frame
+}
+
+/// The trace context carried in `frame`'s headers, on top of the current one
+pub fn extract(frame: &Frame) -> Context {
+    TraceContextPropagator::new().extract(&FrameExtractor(frame))
+}
+
+struct FrameInjector<'a>(&'a mut Frame);
+
+impl Injector for FrameInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.headers.retain(|h| h.key != key.as_bytes());
+        self.0.headers.push(Header::from_str(key, &value));
+    }
+}
+
+struct FrameExtractor<'a>(&'a Frame);
+
+impl Extractor for FrameExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_header(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .headers
+            .iter()
+            .filter_map(|h| std::str::from_utf8(&h.key).ok())
+            .collect()
+    }
+}
+
+/// Runs [`Router`](crate::router::Router) handlers in server spans
+///
+/// Install it with [`Router::layer`](crate::router::Router::layer).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OtelLayer {
+    _private: (),
+}
+
+impl OtelLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `handle`, which answers `request` for `method`, in a server span
+    /// continuing the request's trace
+    pub(crate) async fn wrap<F>(&self, request: &Frame, method: Option<&str>, handle: F) -> Frame
+    where
+        F: Future<Output = Frame>,
+    {
+        let parent = extract(request);
+        let mut attributes = request_attributes(method, request);
+        if let Some(peer) = crate::easy::current_peer_addr() {
+            attributes.extend(peer_attributes(peer));
+        }
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(method.unwrap_or("vstp.request").to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let reply = handle.with_context(cx.clone()).await;
+        let span = cx.span();
+        span.set_attribute(KeyValue::new(
+            "vstp.response.payload_bytes",
+            reply.payload.len() as i64,
+        ));
+        if reply.typ == FrameType::Err {
+            let message = String::from_utf8_lossy(&reply.payload).into_owned();
+            span.set_status(Status::error(message));
+        }
+        span.end();
+        reply
+    }
+}
+
+/// Start the client span of a call of `method` with `request`, sent to `peer`
+pub(crate) fn client_span(method: &str, request: &Frame, peer: SocketAddr) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let mut attributes = request_attributes(Some(method), request);
+    attributes.extend(peer_attributes(peer));
+    let span = tracer
+        .span_builder(method.to_string())
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start(&tracer);
+    Context::current_with_span(span)
+}
+
+/// End the span of `cx`, marking it failed if the call failed with `error`
+pub(crate) fn end_span(cx: &Context, error: Option<&VstpError>) {
+    let span = cx.span();
+    if let Some(error) = error {
+        span.set_status(Status::error(error.to_string()));
+    }
+    span.end();
+}
+
+/// Note on the current span that a request is sent again because of `reason`
+pub(crate) fn record_retry(reason: &'static str) {
+    Context::current().span().add_event(
+        "vstp.retry",
+        vec![KeyValue::new("vstp.retry.reason", reason)],
+    );
+}
+
+fn request_attributes(method: Option<&str>, request: &Frame) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new(
+            "vstp.frame_type",
+            format!("{:?}", request.typ).to_uppercase(),
+        ),
+        KeyValue::new("vstp.payload_bytes", request.payload.len() as i64),
+    ];
+    if let Some(method) = method {
+        attributes.push(KeyValue::new("vstp.method", method.to_string()));
+    }
+    attributes
+}
+
+fn peer_attributes(peer: SocketAddr) -> [KeyValue; 2] {
+    [
+        KeyValue::new("network.peer.address", peer.ip().to_string()),
+        KeyValue::new("network.peer.port", i64::from(peer.port())),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+    #[test]
+    fn test_trace_context_round_trips_through_headers() {
+        let remote = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::from_key_value([("vendor", "x")]).unwrap(),
+        );
+        let cx = Context::new().with_remote_span_context(remote.clone());
+        let frame = inject(&cx, Frame::new(FrameType::Data));
+        let frame = inject(&cx, frame);
+
+        assert_eq!(
+            frame.get_header(TRACEPARENT_HEADER),
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        );
+        assert_eq!(frame.get_header(TRACESTATE_HEADER), Some("vendor=x"));
+        // Injecting again replaces the headers instead of adding more
+        assert_eq!(frame.headers.len(), 2);
+        assert_eq!(extract(&frame).span().span_context(), &remote);
+    }
+}
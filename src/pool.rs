@@ -0,0 +1,228 @@
+//! A pool of TCP clients that have already finished their handshake
+//!
+//! Connecting costs a TCP handshake plus a HELLO/WELCOME round trip before the
+//! first request can go out. [`VstpClientPool::warm`] pays that up front: it
+//! opens `n` sessions and keeps them ready, so [`VstpClientPool::get`] hands
+//! out an established [`VstpClient`] without touching the network.
+//!
+//! Each client handed out is replaced in the background, and idle clients
+//! are sent a PING every [`PoolConfig::heartbeat_interval`]; ones that don't
+//! answer are dropped and replaced too. When the server can't be reached the
+//! pool keeps retrying after [`PoolConfig::retry_backoff`], so a pool
+//! outlives a server restart. When the pool is empty `get` falls back to
+//! connecting on the spot.
+//!
+//! Over TLS every connection the pool opens uses the same client config, and
+//! so the same rustls session cache: once one full handshake has been done,
+//! replacements resume the session, skipping the certificate exchange.
+//! [`PoolStats`] counts both kinds.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::try_join_all;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::easy::{ConnectOptions, VstpClient};
+use crate::types::VstpError;
+
+/// How a [`VstpClientPool`] looks after its idle clients
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// How often each idle client is sent a PING
+    pub heartbeat_interval: Duration,
+    /// How long to wait for the PONG before dropping the client
+    pub heartbeat_timeout: Duration,
+    /// Delay before trying again when opening a replacement fails
+    pub retry_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(15),
+            heartbeat_timeout: Duration::from_secs(5),
+            retry_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// What a [`VstpClientPool`] has been doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Established clients waiting to be handed out
+    pub warm: usize,
+    /// Full handshakes performed so far, failed ones, warm-up and
+    /// fallbacks in [`get`](VstpClientPool::get) included
+    pub cold_handshakes: u64,
+    /// Handshakes that resumed an earlier TLS session
+    pub resumed_handshakes: u64,
+}
+
+struct Shared {
+    addr: String,
+    /// Options for the next connection, see [`VstpClientPool::set_tls`]
+    options: Mutex<ConnectOptions>,
+    config: PoolConfig,
+    idle: Mutex<VecDeque<VstpClient>>,
+    /// How many idle clients to keep
+    target: AtomicUsize,
+    handshakes: AtomicU64,
+    resumed: AtomicU64,
+    /// Woken when a client was taken or dropped
+    wanted: Notify,
+}
+
+impl Shared {
+    async fn connect(&self) -> Result<VstpClient, VstpError> {
+        let options = self.options.lock().unwrap().clone();
+        let connected = VstpClient::connect_tcp_with_options(self.addr.clone(), options).await;
+        match &connected {
+            Ok(client) if client.tls_resumed() => &self.resumed,
+            _ => &self.handshakes,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        connected
+    }
+
+    fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Open clients until `target` are idle, retrying until it works
+    async fn replenish(&self) {
+        while self.idle_count() < self.target.load(Ordering::Relaxed) {
+            match self.connect().await {
+                Ok(client) => self.idle.lock().unwrap().push_back(client),
+                Err(e) => {
+                    warn!("Warming a connection to {} failed: {}", self.addr, e);
+                    tokio::time::sleep(self.config.retry_backoff).await;
+                }
+            }
+        }
+    }
+
+    /// PING every idle client once, dropping the ones that don't answer
+    async fn heartbeat(&self) {
+        let count = self.idle_count();
+        for _ in 0..count {
+            let Some(client) = self.idle.lock().unwrap().pop_front() else {
+                break;
+            };
+            match client.heartbeat(self.config.heartbeat_timeout).await {
+                Ok(()) => self.idle.lock().unwrap().push_back(client),
+                Err(e) => debug!("Dropping a pooled connection to {}: {}", self.addr, e),
+            }
+        }
+    }
+
+    async fn maintain(&self) {
+        loop {
+            self.replenish().await;
+            tokio::select! {
+                _ = self.wanted.notified() => {}
+                _ = tokio::time::sleep(self.config.heartbeat_interval) => self.heartbeat().await,
+            }
+        }
+    }
+}
+
+/// Established TCP clients for one server, ready before they're needed
+///
+/// Every client from [`get`](VstpClientPool::get) is the caller's to keep;
+/// the pool opens another in its place. Dropping the pool closes the idle
+/// clients but not the ones handed out.
+pub struct VstpClientPool {
+    shared: Arc<Shared>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl VstpClientPool {
+    /// A pool for the server at `addr`, connecting as `options` say
+    pub fn new(addr: impl Into<String>, options: ConnectOptions) -> Self {
+        Self::with_config(addr, options, PoolConfig::default())
+    }
+
+    /// Like [`new`](VstpClientPool::new), looking after idle clients as `config` says
+    pub fn with_config(
+        addr: impl Into<String>,
+        options: ConnectOptions,
+        config: PoolConfig,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                addr: addr.into(),
+                options: Mutex::new(options),
+                config,
+                idle: Mutex::new(VecDeque::new()),
+                target: AtomicUsize::new(0),
+                handshakes: AtomicU64::new(0),
+                resumed: AtomicU64::new(0),
+                wanted: Notify::new(),
+            }),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// Open clients until `n` are idle, and keep `n` idle from then on
+    ///
+    /// Returns once they are all established, or with the first error; the
+    /// pool then keeps trying in the background.
+    pub async fn warm(&self, n: usize) -> Result<(), VstpError> {
+        let missing = n.saturating_sub(self.shared.idle_count());
+        let opened = try_join_all((0..missing).map(|_| self.shared.connect()))
+            .await
+            .map(|clients| self.shared.idle.lock().unwrap().extend(clients));
+
+        // Only now, so the background task doesn't race the clients above
+        self.shared.target.store(n, Ordering::Relaxed);
+        self.shared.wanted.notify_one();
+        self.task.lock().unwrap().get_or_insert_with(|| {
+            let shared = self.shared.clone();
+            tokio::spawn(async move { shared.maintain().await })
+        });
+        opened
+    }
+
+    /// An established client, connecting on the spot if none is idle
+    pub async fn get(&self) -> Result<VstpClient, VstpError> {
+        let client = self.shared.idle.lock().unwrap().pop_front();
+        self.shared.wanted.notify_one();
+        match client {
+            Some(client) => Ok(client),
+            None => self.shared.connect().await,
+        }
+    }
+
+    /// Connect with the TLS config `config` from now on, e.g. one presenting
+    /// a renewed client certificate
+    ///
+    /// Clients already established, idle ones included, keep their session;
+    /// replacements and fallbacks in [`get`](VstpClientPool::get) use the
+    /// new config.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&self, config: impl Into<Arc<rustls::ClientConfig>>) {
+        self.shared.options.lock().unwrap().tls = Some(config.into());
+    }
+
+    /// How many clients are idle and how many handshakes the pool has done
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            warm: self.shared.idle_count(),
+            cold_handshakes: self.shared.handshakes.load(Ordering::Relaxed),
+            resumed_handshakes: self.shared.resumed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for VstpClientPool {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
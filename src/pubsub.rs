@@ -0,0 +1,725 @@
+//! Topics that clients publish to and subscribe to
+//!
+//! A [`PubSub`] broker adds two routes to a [`Router`]. Clients publish with
+//! [`VstpClient::publish`], which hands the message to every live
+//! subscription on its topic. [`VstpClient::subscribe`] opens a subscription:
+//! a [streamed call](crate::router#streaming-responses) yielding each message
+//! as a [`Delivery`] until the stream is dropped. The server publishes with
+//! [`PubSub::publish`].
+//!
+//! ## Retained messages
+//!
+//! With [`PubSubConfig::retain`] set, the broker keeps the last `retain`
+//! messages of each topic. [`VstpClient::subscribe_with_backfill`] asks for
+//! up to `n` of them: they come first, oldest first and marked with
+//! [`RETAINED_HEADER`], then live messages follow without a gap or a repeat.
+//! Retained messages outlive the subscriptions that saw them, but they are
+//! only kept in the server's memory and are gone after a restart.
+//! [`PubSubConfig::max_retained_bytes`] bounds them across all topics; past
+//! it, the oldest retained message of any topic is evicted first.
+//!
+//! ## Slow subscribers
+//!
+//! Each subscription buffers up to [`PubSubConfig::subscriber_buffer`]
+//! messages on the server. A publish finding the buffer full skips that
+//! subscription rather than wait for it, counted in
+//! [`PubSubStats::dropped`]. A server's
+//! [`handler_timeout`](crate::easy::ServerOptions::handler_timeout) bounds
+//! the wait for each message of a streamed call, so it also ends
+//! subscriptions that stay quiet that long.
+//!
+//! ## Wildcards
+//!
+//! Topics are `.`-separated segments, such as `alerts.disk.sda`. A
+//! subscription's topic may be a pattern: a `*` segment matches any one
+//! segment and a final `>` segment matches one or more, so `alerts.*`
+//! matches `alerts.disk` but not `alerts.disk.sda`, and `alerts.>` matches
+//! both but not `alerts`. A backfill for a pattern takes the latest retained
+//! messages across the topics it matches. Published topics can't contain
+//! `*` or `>`.
+//!
+//! ## Authorization
+//!
+//! [`PubSubConfig::can_publish`] and [`PubSubConfig::can_subscribe`] decide
+//! whether a session's identity, see
+//! [`ServerOptions::api_keys`](crate::easy::ServerOptions::api_keys), may use
+//! a topic; sessions without one ask as [`ANONYMOUS`]. A subscription is
+//! checked against its topic as given, so a pattern is allowed or denied as
+//! a whole rather than per topic it matches. Denials are answered with ERR
+//! `Forbidden`, which clients report as [`VstpError::Forbidden`], and
+//! counted in [`PubSubStats`]. Each session caches the decisions made for it
+//! until its identity changes, so a check should depend on nothing but its
+//! arguments. [`PubSub::publish`] isn't checked.
+//!
+//! [`PubSub::subscriptions`] lists the open subscriptions and
+//! [`PubSub::unsubscribe`] ends one, e.g. after revoking a key. Its stream
+//! ends with ERR `Forbidden`.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use vstp::easy::{VstpClient, VstpServer};
+//! use vstp::pubsub::{PubSub, PubSubConfig};
+//! use vstp::Router;
+//!
+//! # async fn run() -> Result<(), vstp::VstpError> {
+//! let pubsub = PubSub::new(PubSubConfig {
+//!     retain: 10,
+//!     ..PubSubConfig::default()
+//! });
+//! let server = VstpServer::bind_tcp("127.0.0.1:8080").await?;
+//! tokio::spawn(server.serve_router(pubsub.routes(Router::new())));
+//!
+//! let client = VstpClient::connect_tcp("127.0.0.1:8080").await?;
+//! let mut alerts = client.subscribe_with_backfill::<String>("alerts", 3);
+//! while let Some(alert) = alerts.next().await {
+//!     let alert = alert?;
+//!     println!("{}{}", alert.message, if alert.retained { " (earlier)" } else { "" });
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`VstpClient::publish`]: crate::easy::VstpClient::publish
+//! [`VstpClient::subscribe`]: crate::easy::VstpClient::subscribe
+//! [`VstpClient::subscribe_with_backfill`]: crate::easy::VstpClient::subscribe_with_backfill
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::easy::{current_identity, current_session_extensions, current_session_id};
+use crate::router::Router;
+use crate::types::{ErrorCode, Frame, FrameType, SessionId, VstpError};
+use crate::usage::ANONYMOUS;
+
+/// Method of the route publishing a [`PublishRequest`]
+pub const PUBLISH_METHOD: &str = "pubsub.publish";
+
+/// Method of the streaming route opening a subscription for a [`SubscribeRequest`]
+pub const SUBSCRIBE_METHOD: &str = "pubsub.subscribe";
+
+/// Header naming the topic of a delivered message
+pub const TOPIC_HEADER: &str = "topic";
+
+/// Header set to `true` on a delivered message that was retained before the
+/// subscription opened
+pub const RETAINED_HEADER: &str = "retained";
+
+/// Decisions a session caches before starting over, see
+/// [Authorization](crate::pubsub#authorization)
+const MAX_CACHED_DECISIONS: usize = 1024;
+
+/// Brokers created so far, numbering each for the session caches
+static BROKERS: AtomicU64 = AtomicU64::new(0);
+
+type TopicCheckFn = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Whether an identity may use a topic, see
+/// [Authorization](crate::pubsub#authorization)
+#[derive(Clone)]
+pub struct TopicCheck(TopicCheckFn);
+
+impl TopicCheck {
+    /// A check calling `check(identity, topic)`
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(check))
+    }
+}
+
+impl fmt::Debug for TopicCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TopicCheck")
+    }
+}
+
+/// How a [`PubSub`] broker keeps and hands out messages
+#[derive(Debug, Clone)]
+pub struct PubSubConfig {
+    /// Messages kept per topic for subscriptions asking for a backfill; 0
+    /// keeps none
+    pub retain: usize,
+    /// Bytes of retained messages kept across all topics
+    pub max_retained_bytes: usize,
+    /// Messages each subscription buffers before publishes skip it
+    pub subscriber_buffer: usize,
+    /// Who may publish to a topic; everyone if unset
+    pub can_publish: Option<TopicCheck>,
+    /// Who may subscribe to a topic or pattern; everyone if unset
+    pub can_subscribe: Option<TopicCheck>,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            retain: 0,
+            max_retained_bytes: 16 * 1024 * 1024,
+            subscriber_buffer: 256,
+            can_publish: None,
+            can_subscribe: None,
+        }
+    }
+}
+
+/// Request of the [`PUBLISH_METHOD`] route
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublishRequest {
+    pub topic: String,
+    pub message: serde_json::Value,
+}
+
+/// Reply of the [`PUBLISH_METHOD`] route
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Published {
+    /// Subscriptions the message was handed to
+    pub delivered: usize,
+}
+
+/// Request of the [`SUBSCRIBE_METHOD`] route
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    /// Topic or [pattern](crate::pubsub#wildcards) to subscribe to
+    pub topic: String,
+    /// Retained messages to send before live ones
+    #[serde(default)]
+    pub backfill: usize,
+}
+
+/// A message yielded by a subscription
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery<T> {
+    pub topic: String,
+    pub message: T,
+    /// Whether the message was retained before the subscription opened
+    pub retained: bool,
+}
+
+impl<T: DeserializeOwned> Delivery<T> {
+    /// The delivery a subscription's `frame` carries
+    pub(crate) fn from_frame(frame: Frame) -> Result<Self, VstpError> {
+        let topic = frame
+            .get_header(TOPIC_HEADER)
+            .ok_or_else(|| VstpError::Protocol("Delivery names no topic".to_string()))?
+            .to_string();
+        let message = serde_json::from_slice(frame.payload())
+            .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))?;
+        Ok(Self {
+            topic,
+            message,
+            retained: frame.get_header(RETAINED_HEADER) == Some("true"),
+        })
+    }
+}
+
+/// An open subscription, see [`PubSub::subscriptions`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    /// Id to end the subscription with, see [`PubSub::unsubscribe`]
+    pub id: u64,
+    /// Session the subscription was opened in
+    pub session: Option<SessionId>,
+    /// Identity the subscription was allowed for
+    pub identity: String,
+    /// Topic or [pattern](crate::pubsub#wildcards) subscribed to
+    pub topic: String,
+}
+
+/// Counters of a [`PubSub`] broker
+#[derive(Debug, Default)]
+pub struct PubSubStats {
+    published: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    evicted: AtomicU64,
+    denied_publishes: AtomicU64,
+    denied_subscribes: AtomicU64,
+}
+
+impl PubSubStats {
+    /// Messages published
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Messages handed to subscriptions, retained ones included
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    /// Messages a subscription missed because its buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Retained messages evicted to stay within
+    /// [`max_retained_bytes`](PubSubConfig::max_retained_bytes)
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Publishes [`can_publish`](PubSubConfig::can_publish) denied
+    pub fn denied_publishes(&self) -> u64 {
+        self.denied_publishes.load(Ordering::Relaxed)
+    }
+
+    /// Subscriptions [`can_subscribe`](PubSubConfig::can_subscribe) denied
+    pub fn denied_subscribes(&self) -> u64 {
+        self.denied_subscribes.load(Ordering::Relaxed)
+    }
+}
+
+/// What a [`TopicCheck`] is asked about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Access {
+    Publish,
+    Subscribe,
+}
+
+impl Access {
+    fn verb(self) -> &'static str {
+        match self {
+            Access::Publish => "publish to",
+            Access::Subscribe => "subscribe to",
+        }
+    }
+}
+
+/// Decisions cached in a session's extensions for the broker and identity
+/// that made them
+#[derive(Default)]
+struct Decisions {
+    broker: u64,
+    identity: String,
+    allowed: HashMap<(Access, String), bool>,
+}
+
+struct Message {
+    seq: u64,
+    topic: String,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn size(&self) -> usize {
+        self.topic.len() + self.payload.len()
+    }
+
+    fn frame(&self, retained: bool) -> Frame {
+        let frame = Frame::new(FrameType::Data)
+            .with_header(TOPIC_HEADER, &self.topic)
+            .with_payload(self.payload.clone());
+        match retained {
+            true => frame.with_header(RETAINED_HEADER, "true"),
+            false => frame,
+        }
+    }
+}
+
+struct Subscriber {
+    topic: String,
+    session: Option<SessionId>,
+    identity: String,
+    tx: mpsc::Sender<Arc<Message>>,
+    /// Cancelled when [`PubSub::unsubscribe`] ends the subscription
+    removed: CancellationToken,
+}
+
+impl Subscriber {
+    fn info(&self, id: u64) -> SubscriptionInfo {
+        SubscriptionInfo {
+            id,
+            session: self.session,
+            identity: self.identity.clone(),
+            topic: self.topic.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Topics {
+    retained: HashMap<String, VecDeque<Arc<Message>>>,
+    retained_bytes: usize,
+    subscribers: HashMap<u64, Subscriber>,
+    next_seq: u64,
+    next_subscriber: u64,
+}
+
+impl Topics {
+    /// Retain `message`, evicting what no longer fits; how many were evicted
+    fn retain(&mut self, message: Arc<Message>, config: &PubSubConfig) -> u64 {
+        let mut evicted = 0;
+        self.retained_bytes += message.size();
+        let retained = self.retained.entry(message.topic.clone()).or_default();
+        retained.push_back(message);
+        while retained.len() > config.retain {
+            let oldest = retained.pop_front().expect("over a limit of at least 0");
+            self.retained_bytes -= oldest.size();
+            evicted += 1;
+        }
+        while self.retained_bytes > config.max_retained_bytes {
+            let oldest = self
+                .retained
+                .values_mut()
+                .filter(|retained| !retained.is_empty())
+                .min_by_key(|retained| retained[0].seq)
+                .and_then(VecDeque::pop_front)
+                .expect("retained bytes come from retained messages");
+            self.retained_bytes -= oldest.size();
+            evicted += 1;
+        }
+        self.retained.retain(|_, retained| !retained.is_empty());
+        evicted
+    }
+}
+
+struct Broker {
+    id: u64,
+    config: PubSubConfig,
+    topics: Mutex<Topics>,
+    stats: PubSubStats,
+}
+
+impl Broker {
+    /// Ask the config's check for `access` whether the current session may
+    /// use `topic`, answering from the session's cache if it can
+    fn authorize(&self, access: Access, topic: &str) -> Result<String, VstpError> {
+        let identity = current_identity().unwrap_or_else(|| ANONYMOUS.to_string());
+        let check = match access {
+            Access::Publish => &self.config.can_publish,
+            Access::Subscribe => &self.config.can_subscribe,
+        };
+        let Some(check) = check else {
+            return Ok(identity);
+        };
+        let allowed = match current_session_extensions() {
+            Some(extensions) => {
+                let decisions = match extensions.get::<Arc<Mutex<Decisions>>>() {
+                    Some(decisions) => decisions,
+                    None => {
+                        let decisions = Arc::new(Mutex::new(Decisions::default()));
+                        extensions.insert(decisions.clone());
+                        decisions
+                    }
+                };
+                let key = (access, topic.to_string());
+                let cached = {
+                    let mut decisions = decisions.lock().unwrap();
+                    if decisions.broker != self.id || decisions.identity != identity {
+                        *decisions = Decisions {
+                            broker: self.id,
+                            identity: identity.clone(),
+                            allowed: HashMap::new(),
+                        };
+                    }
+                    decisions.allowed.get(&key).copied()
+                };
+                cached.unwrap_or_else(|| {
+                    let allowed = (check.0)(&identity, topic);
+                    let mut decisions = decisions.lock().unwrap();
+                    if decisions.allowed.len() >= MAX_CACHED_DECISIONS {
+                        decisions.allowed.clear();
+                    }
+                    decisions.allowed.insert(key, allowed);
+                    allowed
+                })
+            }
+            None => (check.0)(&identity, topic),
+        };
+        if allowed {
+            return Ok(identity);
+        }
+        let denied = match access {
+            Access::Publish => &self.stats.denied_publishes,
+            Access::Subscribe => &self.stats.denied_subscribes,
+        };
+        denied.fetch_add(1, Ordering::Relaxed);
+        Err(VstpError::Forbidden(format!(
+            "{} may not {} {}",
+            identity,
+            access.verb(),
+            topic
+        )))
+    }
+
+    fn publish(&self, topic: &str, payload: Vec<u8>) -> usize {
+        let mut topics = self.topics.lock().unwrap();
+        topics.next_seq += 1;
+        let message = Arc::new(Message {
+            seq: topics.next_seq,
+            topic: topic.to_string(),
+            payload,
+        });
+        let (mut delivered, mut dropped) = (0, 0);
+        for subscriber in topics.subscribers.values() {
+            if !topic_matches(&subscriber.topic, topic) {
+                continue;
+            }
+            match subscriber.tx.try_send(message.clone()) {
+                Ok(()) => delivered += 1,
+                Err(mpsc::error::TrySendError::Full(_)) => dropped += 1,
+                // The subscription is going away
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+        if self.config.retain > 0 {
+            let evicted = topics.retain(message, &self.config);
+            self.stats.evicted.fetch_add(evicted, Ordering::Relaxed);
+        }
+        self.stats.published.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .delivered
+            .fetch_add(delivered as u64, Ordering::Relaxed);
+        self.stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+        delivered
+    }
+
+    /// Open the subscription `request` asks for, if the current session may
+    fn open(
+        self: &Arc<Self>,
+        request: SubscribeRequest,
+    ) -> BoxStream<'static, Result<Frame, VstpError>> {
+        let identity = check_pattern(&request.topic)
+            .and_then(|()| self.authorize(Access::Subscribe, &request.topic));
+        match identity {
+            Ok(identity) => self
+                .subscribe(request.topic, request.backfill, identity)
+                .map(Ok)
+                .boxed(),
+            Err(e) => stream::once(async move { Err(e) }).boxed(),
+        }
+    }
+
+    /// Open a subscription to `topic`, with up to `backfill` of the retained
+    /// messages it matches taken at the same moment
+    fn subscribe(
+        self: &Arc<Self>,
+        topic: String,
+        backfill: usize,
+        identity: String,
+    ) -> BoxStream<'static, Frame> {
+        let (tx, rx) = mpsc::channel(self.config.subscriber_buffer.max(1));
+        let removed = CancellationToken::new();
+        let mut topics = self.topics.lock().unwrap();
+        let mut matched: Vec<&Arc<Message>> = topics
+            .retained
+            .iter()
+            .filter(|(name, _)| topic_matches(&topic, name))
+            .flat_map(|(_, retained)| retained)
+            .collect();
+        matched.sort_unstable_by_key(|message| message.seq);
+        let skip = matched.len().saturating_sub(backfill);
+        let retained: Vec<Frame> = matched[skip..].iter().map(|m| m.frame(true)).collect();
+        topics.next_subscriber += 1;
+        let id = topics.next_subscriber;
+        let subscriber = Subscriber {
+            topic: topic.clone(),
+            session: current_session_id(),
+            identity,
+            tx,
+            removed: removed.clone(),
+        };
+        topics.subscribers.insert(id, subscriber);
+        drop(topics);
+        self.stats
+            .delivered
+            .fetch_add(retained.len() as u64, Ordering::Relaxed);
+
+        let subscription = Subscription {
+            broker: self.clone(),
+            id,
+            rx,
+        };
+        let live = stream::unfold(Some(subscription), move |subscription| {
+            let (removed, topic) = (removed.clone(), topic.clone());
+            async move {
+                let mut subscription = subscription?;
+                tokio::select! {
+                    biased;
+                    _ = removed.cancelled() => {
+                        let reason = format!("unsubscribed from {}", topic);
+                        Some((Frame::coded_error(ErrorCode::Forbidden, &reason), None))
+                    }
+                    message = subscription.rx.recv() => {
+                        Some((message?.frame(false), Some(subscription)))
+                    }
+                }
+            }
+        });
+        stream::iter(retained).chain(live).boxed()
+    }
+}
+
+/// A subscription's receiving end, unregistered when dropped
+struct Subscription {
+    broker: Arc<Broker>,
+    id: u64,
+    rx: mpsc::Receiver<Arc<Message>>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.broker
+            .topics
+            .lock()
+            .unwrap()
+            .subscribers
+            .remove(&self.id);
+    }
+}
+
+/// A pub/sub broker, see [`pubsub`](crate::pubsub)
+///
+/// Clones share the broker, so one kept outside the server can publish and
+/// read its [`stats`](PubSub::stats) while it serves.
+#[derive(Clone)]
+pub struct PubSub {
+    broker: Arc<Broker>,
+}
+
+impl PubSub {
+    pub fn new(config: PubSubConfig) -> Self {
+        let broker = Broker {
+            id: BROKERS.fetch_add(1, Ordering::Relaxed) + 1,
+            config,
+            topics: Mutex::new(Topics::default()),
+            stats: PubSubStats::default(),
+        };
+        Self {
+            broker: Arc::new(broker),
+        }
+    }
+
+    /// `router` with the [`PUBLISH_METHOD`] and [`SUBSCRIBE_METHOD`] routes
+    /// of this broker added
+    pub fn routes(&self, router: Router) -> Router {
+        let publisher = self.broker.clone();
+        let subscriber = self.broker.clone();
+        router
+            .route(PUBLISH_METHOD, move |request: PublishRequest| {
+                let broker = publisher.clone();
+                async move {
+                    check_topic(&request.topic)?;
+                    broker.authorize(Access::Publish, &request.topic)?;
+                    let payload = serde_json::to_vec(&request.message)
+                        .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+                    let delivered = broker.publish(&request.topic, payload);
+                    Ok(Published { delivered })
+                }
+            })
+            .stream_frames_route(SUBSCRIBE_METHOD, move |request: SubscribeRequest| {
+                let broker = subscriber.clone();
+                // Opened on the first poll, which runs in the session's
+                // context for the identity to check
+                stream::once(async move { broker.open(request) })
+                    .flatten()
+                    .boxed()
+            })
+    }
+
+    /// Publish `message` on `topic`; how many subscriptions it was handed to
+    pub fn publish<T: Serialize>(&self, topic: &str, message: &T) -> Result<usize, VstpError> {
+        check_topic(topic)?;
+        let payload = serde_json::to_vec(message)
+            .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+        Ok(self.broker.publish(topic, payload))
+    }
+
+    pub fn stats(&self) -> &PubSubStats {
+        &self.broker.stats
+    }
+
+    /// The open subscriptions, oldest first
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        let topics = self.broker.topics.lock().unwrap();
+        let mut subscriptions: Vec<SubscriptionInfo> = topics
+            .subscribers
+            .iter()
+            .map(|(id, subscriber)| subscriber.info(*id))
+            .collect();
+        subscriptions.sort_unstable_by_key(|subscription| subscription.id);
+        subscriptions
+    }
+
+    /// The open subscriptions of `session`, oldest first
+    pub fn session_subscriptions(&self, session: SessionId) -> Vec<SubscriptionInfo> {
+        let mut subscriptions = self.subscriptions();
+        subscriptions.retain(|subscription| subscription.session == Some(session));
+        subscriptions
+    }
+
+    /// End subscription `id`, whose stream ends with ERR `Forbidden`; whether
+    /// it was open
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        let removed = self.broker.topics.lock().unwrap().subscribers.remove(&id);
+        match removed {
+            Some(subscriber) => {
+                subscriber.removed.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for PubSub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PubSub")
+            .field("config", &self.broker.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Whether `topic` is matched by `pattern`, see [Wildcards](crate::pubsub#wildcards)
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut names = topic.split('.');
+    for segment in pattern.split('.') {
+        match (segment, names.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (segment, Some(name)) if segment == name => {}
+            _ => return false,
+        }
+    }
+    names.next().is_none()
+}
+
+/// Check a topic to publish to
+fn check_topic(topic: &str) -> Result<(), VstpError> {
+    if topic.is_empty() {
+        return Err(VstpError::Protocol("Topic is empty".to_string()));
+    }
+    match topic.contains(['*', '>']) {
+        true => Err(VstpError::Protocol(format!(
+            "Can't publish to pattern {}",
+            topic
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Check a topic or pattern to subscribe to
+fn check_pattern(pattern: &str) -> Result<(), VstpError> {
+    if pattern.is_empty() {
+        return Err(VstpError::Protocol("Topic is empty".to_string()));
+    }
+    let segments: Vec<&str> = pattern.split('.').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let wildcard = segment.contains(['*', '>']);
+        let whole = *segment == "*" || (*segment == ">" && i == segments.len() - 1);
+        if wildcard && !whole {
+            return Err(VstpError::Protocol(format!(
+                "Malformed pattern {}",
+                pattern
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,793 @@
+//! Dispatching requests to typed handlers by method name
+//!
+//! A [`Router`] looks at the `method` header of each DATA frame and hands the
+//! JSON payload to the handler registered for that method. Serve it with
+//! [`VstpServer::serve_router`](crate::easy::VstpServer::serve_router) and
+//! call it with [`VstpClient::call`](crate::easy::VstpClient::call).
+//!
+//! Each route has its own request and response types, so one server can
+//! take differently shaped messages, e.g. next to the publish and subscribe
+//! routes of a [`PubSub`](crate::pubsub::PubSub) broker (see
+//! `examples/pubsub.rs`). Protocols that name the message kind in another
+//! header can route on that instead with [`Router::dispatch_on`].
+//!
+//! Routes registered with [`Router::route_typed`] also check the request's
+//! schema version, see [`schema`].
+//!
+//! ## Response caching
+//!
+//! Routes registered with [`Router::cached`] keep successful responses for a
+//! while and answer repeated requests without running the handler. Entries
+//! are keyed by the [`content_hash`](Frame::content_hash) of the request
+//! payload plus its schema version and the values of the route's
+//! `vary_headers`. A request without a schema version shares entries with
+//! ones naming the version the route serves, which is what it gets. Every
+//! response from a cached route carries `x-cache: hit` or `x-cache: miss`.
+//! A route keeps at most `max_entries` responses, and at most `max_bytes`
+//! of them in total if set, dropping the least recently used first.
+//!
+//! Only successful responses are stored; errors always reach the handler
+//! again on the next request. On routes cached with
+//! [`CacheConfig::allow_bust`] set, a client can skip the cache for one
+//! request by sending `x-cache-bust: 1`, which runs the handler and replaces
+//! the entry. Elsewhere the header is ignored, so clients can't make every
+//! request reach the handler.
+//! Only cache idempotent methods: a cached route's handler may not run at all.
+//!
+//! ## Streaming responses
+//!
+//! Routes registered with [`Router::stream_route`] answer one request with
+//! any number of responses, read on the client with
+//! [`VstpClient::call_server_stream`](crate::easy::VstpClient::call_server_stream).
+//! Each response is a DATA frame, and after the last one comes an empty DATA
+//! frame with [`STREAM_END_HEADER`]. A handler failing partway, with an
+//! `Err` item, ends the stream with an ERR frame `HandlerFailed` instead;
+//! the responses sent before it stand. Only requests with a
+//! [`CALL_ID_HEADER`] are streamed, and every frame of the stream carries
+//! it; others are answered with ERR `HandlerFailed`.
+//!
+//! A client stops a stream early by sending a DATA frame with
+//! [`STREAM_CANCEL_HEADER`] and the call's id. The server drops the stream
+//! and closes the call with a [`STREAM_END_HEADER`] frame.
+//!
+//! Streaming routes skip schema checks and caching, and aren't traced.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::Instant;
+
+use crate::idempotency::{IdempotencyStore, SingleFlight};
+use crate::schema::{self, VstpMessage, SCHEMA_VERSION_HEADER};
+use crate::types::{ErrorCode, Frame, FrameType, VstpError};
+use crate::usage::ANONYMOUS;
+
+/// Header naming the method a request is for
+pub const METHOD_HEADER: &str = "method";
+
+/// Header on responses from cached routes, `hit` or `miss`
+pub const CACHE_HEADER: &str = "x-cache";
+
+/// Header a client sends to bypass and refresh the cache for one request,
+/// on routes that allow it
+pub const CACHE_BUST_HEADER: &str = "x-cache-bust";
+
+/// Header on the empty DATA frame that ends a streamed response
+pub const STREAM_END_HEADER: &str = "stream-end";
+
+/// Header a client tags a streaming request with, copied onto every frame
+/// of its response
+pub const CALL_ID_HEADER: &str = "call-id";
+
+/// Header on the frame a client sends to stop the streamed response to the
+/// call named by its [`CALL_ID_HEADER`]
+pub const STREAM_CANCEL_HEADER: &str = "stream-cancel";
+
+type Handler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Result<Vec<u8>, VstpError>> + Send + Sync>;
+
+/// Starts the responses of a streaming route for a request payload
+type StreamHandler = Arc<
+    dyn Fn(Vec<u8>) -> Result<BoxStream<'static, Result<Frame, VstpError>>, VstpError>
+        + Send
+        + Sync,
+>;
+
+/// Turns the payload of an older request into one of the version a route serves
+type Upgrade = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, VstpError> + Send + Sync>;
+
+/// How a cached route stores its responses
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// How long a response stays fresh
+    pub ttl: Duration,
+    /// Entries kept for the route; the least recently used goes first
+    pub max_entries: usize,
+    /// Total response bytes kept for the route, if capped; the least
+    /// recently used entries go first, and a response larger than this
+    /// isn't kept at all
+    pub max_bytes: Option<usize>,
+    /// Request headers whose values are part of the cache key
+    pub vary_headers: Vec<String>,
+    /// Honor [`CACHE_BUST_HEADER`] on requests; off by default
+    pub allow_bust: bool,
+}
+
+impl CacheConfig {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            max_entries: 1024,
+            max_bytes: None,
+            vary_headers: Vec::new(),
+            allow_bust: false,
+        }
+    }
+}
+
+/// Routes requests to handlers by their `method` header
+///
+/// Clones share their routes' caches, so a clone kept outside the server can
+/// [`invalidate`](Router::invalidate) while it serves.
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: HashMap<String, Route>,
+    last_added: Option<String>,
+    /// Header naming the route; `None` for [`METHOD_HEADER`]
+    dispatch_header: Option<String>,
+    /// Set with [`Router::idempotent`]
+    idempotency: Option<Arc<SingleFlight>>,
+    #[cfg(feature = "otel")]
+    otel: Option<crate::otel::OtelLayer>,
+}
+
+#[derive(Clone)]
+struct Route {
+    handler: Handler,
+    /// Set for routes registered with [`Router::stream_route`]
+    stream: Option<StreamHandler>,
+    cache: Option<Arc<RouteCache>>,
+    schema: Option<RouteSchema>,
+    /// Set with [`Router::timeout`]
+    timeout: Option<Duration>,
+}
+
+/// The schema version a typed route serves and the ones it converts
+#[derive(Clone)]
+struct RouteSchema {
+    version: u32,
+    compat: HashMap<u32, Upgrade>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` for `method`, replacing any earlier handler
+    pub fn route<F, Fut, T, R>(mut self, method: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<R, VstpError>> + Send + 'static,
+        T: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let handler: Handler = Arc::new(move |payload: Vec<u8>| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let request = serde_json::from_slice::<T>(&payload)
+                    .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))?;
+                let response = handler(request).await?;
+                serde_json::to_vec(&response)
+                    .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))
+            })
+        });
+
+        let method = method.into();
+        self.routes.insert(
+            method.clone(),
+            Route {
+                handler,
+                stream: None,
+                cache: None,
+                schema: None,
+                timeout: None,
+            },
+        );
+        self.last_added = Some(method);
+        self
+    }
+
+    /// Register `handler` for `method`, streaming each item it yields back
+    /// as a response of its own
+    ///
+    /// See [Streaming responses](self#streaming-responses). A unary
+    /// [`call`](crate::easy::VstpClient::call) of the method, having no
+    /// [`CALL_ID_HEADER`], is answered with ERR `HandlerFailed`.
+    pub fn stream_route<F, S, T, R>(self, method: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(T) -> S + Send + Sync + 'static,
+        S: Stream<Item = Result<R, VstpError>> + Send + 'static,
+        T: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+    {
+        self.stream_frames_route(method, move |request: T| {
+            handler(request).map(|response| {
+                let payload = serde_json::to_vec(&response?)
+                    .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))?;
+                Ok(Frame::new(FrameType::Data).with_payload(payload))
+            })
+        })
+    }
+
+    /// Register `handler` for `method` like [`stream_route`](Router::stream_route),
+    /// with each item a whole DATA frame, e.g. to put headers on it
+    pub(crate) fn stream_frames_route<F, S, T>(
+        mut self,
+        method: impl Into<String>,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(T) -> S + Send + Sync + 'static,
+        S: Stream<Item = Result<Frame, VstpError>> + Send + 'static,
+        T: DeserializeOwned + Send + 'static,
+    {
+        let method = method.into();
+        let stream: StreamHandler = Arc::new(move |payload: Vec<u8>| {
+            let request = serde_json::from_slice::<T>(&payload)
+                .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))?;
+            Ok(handler(request).boxed())
+        });
+        let unary = format!("method {} streams its responses", method);
+        let handler: Handler = Arc::new(move |_| {
+            let unary = unary.clone();
+            Box::pin(async move { Err(VstpError::Protocol(unary)) })
+        });
+
+        self.routes.insert(
+            method.clone(),
+            Route {
+                handler,
+                stream: Some(stream),
+                cache: None,
+                schema: None,
+                timeout: None,
+            },
+        );
+        self.last_added = Some(method);
+        self
+    }
+
+    /// Register `handler` for requests of type `M` at `M::METHOD`
+    ///
+    /// Requests whose `schema-version` header isn't `M::SCHEMA_VERSION`, or a
+    /// version added with [`compat`](Router::compat), are answered with ERR
+    /// `SchemaMismatch`.
+    pub fn route_typed<M, F, Fut, R>(self, handler: F) -> Self
+    where
+        M: VstpMessage,
+        F: Fn(M) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<R, VstpError>> + Send + 'static,
+        R: Serialize + Send + 'static,
+    {
+        let mut router = self.route(M::METHOD, handler);
+        router.routes.get_mut(M::METHOD).unwrap().schema = Some(RouteSchema {
+            version: M::SCHEMA_VERSION,
+            compat: HashMap::new(),
+        });
+        router
+    }
+
+    /// Serve requests of type `O` on the typed route for `M` by converting
+    /// them with `upgrade`
+    ///
+    /// Panics unless `O` and `M` share a method and a route for `M` was
+    /// registered with [`route_typed`](Router::route_typed).
+    pub fn compat<O, M, F>(mut self, upgrade: F) -> Self
+    where
+        O: VstpMessage,
+        M: VstpMessage,
+        F: Fn(O) -> M + Send + Sync + 'static,
+    {
+        assert_eq!(O::METHOD, M::METHOD, "compat() converts within one method");
+        let schema = self
+            .routes
+            .get_mut(M::METHOD)
+            .and_then(|route| route.schema.as_mut())
+            .filter(|schema| schema.version == M::SCHEMA_VERSION)
+            .expect("compat() must follow route_typed() for the same type");
+        let upgrade: Upgrade = Arc::new(move |payload: &[u8]| {
+            let old = serde_json::from_slice::<O>(payload)
+                .map_err(|e| VstpError::Protocol(format!("Deserialization error: {}", e)))?;
+            serde_json::to_vec(&upgrade(old))
+                .map_err(|e| VstpError::Protocol(format!("Serialization error: {}", e)))
+        });
+        schema.compat.insert(O::SCHEMA_VERSION, upgrade);
+        self
+    }
+
+    /// Pick routes by the value of `header`, e.g. `type`, instead of `method`
+    pub fn dispatch_on(mut self, header: impl Into<String>) -> Self {
+        self.dispatch_header = Some(header.into());
+        self
+    }
+
+    /// Run every handler in an OpenTelemetry server span, see [`otel`](crate::otel)
+    #[cfg(feature = "otel")]
+    pub fn layer(mut self, layer: crate::otel::OtelLayer) -> Self {
+        self.otel = Some(layer);
+        self
+    }
+
+    /// Keep the replies to requests with an
+    /// [`IDEMPOTENCY_KEY_HEADER`](crate::easy::IDEMPOTENCY_KEY_HEADER) in
+    /// `store` and answer the same key with them, see [`idempotency`](crate::idempotency)
+    ///
+    /// Applies to every route. [`handle`](Router::handle) has no session
+    /// to tell clients apart, so it keeps replies under [`ANONYMOUS`].
+    /// Served by a [`VstpServer`](crate::easy::VstpServer) the router keeps
+    /// them under each session's identity instead, in place of the service's
+    /// [`ServerOptions::idempotency`](crate::ServerOptions::idempotency).
+    pub fn idempotent(mut self, store: Arc<dyn IdempotencyStore>) -> Self {
+        self.idempotency = Some(Arc::new(SingleFlight::new(Some(store))));
+        self
+    }
+
+    /// What [`idempotent`](Router::idempotent) set up, for the server to
+    /// run requests through with their sessions' identities
+    pub(crate) fn idempotency(&self) -> Option<&Arc<SingleFlight>> {
+        self.idempotency.as_ref()
+    }
+
+    /// Give the handler of the route registered last at most `limit` per call
+    ///
+    /// Replaces [`ServerOptions::handler_timeout`](crate::easy::ServerOptions::handler_timeout)
+    /// for the route, whether that is longer or shorter. Handlers read the
+    /// deadline with [`current_deadline`](crate::easy::current_deadline).
+    /// Panics if no route has been registered yet.
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        let method = self
+            .last_added
+            .as_ref()
+            .expect("timeout() must follow route()");
+        self.routes.get_mut(method).unwrap().timeout = Some(limit);
+        self
+    }
+
+    /// The method `request` is for, by the header routes are picked by
+    pub fn method_of<'a>(&self, request: &'a Frame) -> Option<&'a str> {
+        request.get_header(self.dispatch_header())
+    }
+
+    /// How long the handler for `request` may take: its route's
+    /// [`timeout`](Router::timeout) if it has one, else `default`
+    pub fn timeout_for(&self, request: &Frame, default: Option<Duration>) -> Option<Duration> {
+        self.method_of(request)
+            .and_then(|method| self.routes.get(method))
+            .and_then(|route| route.timeout)
+            .or(default)
+    }
+
+    /// Cache responses of the route registered last for `ttl`
+    pub fn cached(self, ttl: Duration) -> Self {
+        self.cached_with(CacheConfig::new(ttl))
+    }
+
+    /// Cache responses of the route registered last as described by `config`
+    ///
+    /// Panics if no route has been registered yet.
+    pub fn cached_with(mut self, config: CacheConfig) -> Self {
+        let method = self
+            .last_added
+            .as_ref()
+            .expect("cached() must follow route()");
+        let route = self.routes.get_mut(method).unwrap();
+        route.cache = Some(Arc::new(RouteCache::new(config)));
+        self
+    }
+
+    /// Drop every cached response of `method`
+    pub fn invalidate(&self, method: &str) {
+        if let Some(cache) = self.routes.get(method).and_then(|r| r.cache.as_ref()) {
+            cache.clear();
+        }
+    }
+
+    /// Run the handler for `request` and build the reply
+    ///
+    /// Unknown methods and failed handlers are answered with ERR frames, a
+    /// handler's [`VstpError::Forbidden`] with ERR `Forbidden`. On an
+    /// [`idempotent`](Router::idempotent) router, a request with a key that
+    /// was answered before gets that answer without running the handler.
+    pub async fn handle(&self, request: &Frame) -> Frame {
+        let Some(flights) = &self.idempotency else {
+            return self.handle_once(request).await;
+        };
+        let reply = flights.run(ANONYMOUS, request, async {
+            Some(self.handle_once(request).await)
+        });
+        reply.await.unwrap_or_else(|| {
+            Frame::coded_error(
+                ErrorCode::HandlerFailed,
+                "the request sharing this idempotency key failed",
+            )
+        })
+    }
+
+    /// Run the handler for `request`, without looking for an earlier reply
+    pub(crate) async fn handle_once(&self, request: &Frame) -> Frame {
+        #[cfg(feature = "otel")]
+        if let Some(layer) = &self.otel {
+            let method = request.get_header(self.dispatch_header());
+            return layer.wrap(request, method, self.reply(request)).await;
+        }
+        self.reply(request).await
+    }
+
+    /// The frames answering `request`, ending with a [`STREAM_END_HEADER`]
+    /// or an ERR frame, if it is for a [`stream_route`](Router::stream_route)
+    /// and has a [`CALL_ID_HEADER`]
+    ///
+    /// `None` for any other request, which gets one reply from
+    /// [`handle`](Router::handle).
+    pub fn handle_stream(&self, request: &Frame) -> Option<BoxStream<'static, Frame>> {
+        let method = request.get_header(self.dispatch_header())?;
+        let start = self.routes.get(method)?.stream.as_ref()?;
+        let call_id = request.get_header(CALL_ID_HEADER)?.to_string();
+        let frames = match start(request.payload.clone()) {
+            Ok(responses) => stream::unfold(Some(responses), |responses| async move {
+                let mut responses = responses?;
+                Some(match responses.next().await {
+                    // An ERR frame ends the stream as an `Err` item does
+                    Some(Ok(response)) if response.typ == FrameType::Err => (response, None),
+                    Some(Ok(response)) => (response, Some(responses)),
+                    Some(Err(e)) => (respond(Err(e)), None),
+                    None => (
+                        Frame::new(FrameType::Data).with_header(STREAM_END_HEADER, "true"),
+                        None,
+                    ),
+                })
+            })
+            .boxed(),
+            Err(e) => stream::once(async move { respond(Err(e)) }).boxed(),
+        };
+        Some(
+            frames
+                .map(move |frame| frame.with_header(CALL_ID_HEADER, &call_id))
+                .boxed(),
+        )
+    }
+
+    fn dispatch_header(&self) -> &str {
+        self.dispatch_header.as_deref().unwrap_or(METHOD_HEADER)
+    }
+
+    async fn reply(&self, request: &Frame) -> Frame {
+        let header = self.dispatch_header();
+        let Some(method) = request.get_header(header) else {
+            return Frame::coded_error(
+                ErrorCode::UnknownMethod,
+                &format!("request has no {} header", header),
+            );
+        };
+        let Some(route) = self.routes.get(method) else {
+            return Frame::coded_error(
+                ErrorCode::UnknownMethod,
+                &format!("no route for method {}", method),
+            );
+        };
+
+        let payload = match &route.schema {
+            Some(schema) => match schema.accept(method, request) {
+                Ok(payload) => payload,
+                Err(reply) => return reply,
+            },
+            None => request.payload.clone(),
+        };
+
+        let Some(cache) = &route.cache else {
+            return respond((route.handler)(payload).await);
+        };
+        let version = route.schema.as_ref().map(|schema| schema.version);
+        let Some(key) = cache.key(request, version) else {
+            return respond((route.handler)(payload).await);
+        };
+        if !cache.config.allow_bust || request.get_header(CACHE_BUST_HEADER).is_none() {
+            if let Some(response) = cache.get(&key) {
+                return Frame::new(FrameType::Data)
+                    .with_header(CACHE_HEADER, "hit")
+                    .with_payload(response);
+            }
+        }
+
+        let result = (route.handler)(payload).await;
+        if let Ok(response) = &result {
+            cache.insert(key, response.clone());
+        }
+        match respond(result) {
+            frame if frame.typ == FrameType::Data => frame.with_header(CACHE_HEADER, "miss"),
+            frame => frame,
+        }
+    }
+}
+
+impl RouteSchema {
+    /// The payload to hand the handler, converted if the request is of an
+    /// older version, or the ERR to answer with
+    fn accept(&self, method: &str, request: &Frame) -> Result<Vec<u8>, Frame> {
+        let Some(sent) = request.get_header(SCHEMA_VERSION_HEADER) else {
+            return Ok(request.payload.clone());
+        };
+        let Ok(sent) = sent.parse::<u32>() else {
+            return Err(Frame::coded_error(
+                ErrorCode::BadRequest,
+                &format!("invalid {} header: {}", SCHEMA_VERSION_HEADER, sent),
+            ));
+        };
+        if sent == self.version {
+            return Ok(request.payload.clone());
+        }
+        match self.compat.get(&sent) {
+            Some(upgrade) => upgrade(request.payload())
+                .map_err(|e| Frame::coded_error(ErrorCode::BadRequest, &e.to_string())),
+            None => Err(schema::mismatch_frame(method, sent, self.version)),
+        }
+    }
+}
+
+fn respond(result: Result<Vec<u8>, VstpError>) -> Frame {
+    match result {
+        Ok(response) => Frame::new(FrameType::Data).with_payload(response),
+        Err(VstpError::Forbidden(reason)) => Frame::coded_error(ErrorCode::Forbidden, &reason),
+        Err(e) => Frame::coded_error(ErrorCode::HandlerFailed, &e.to_string()),
+    }
+}
+
+/// SHA-256 of the payload, schema version and vary headers, so requests
+/// sharing an entry have the same content
+type CacheKey = [u8; 32];
+
+struct CacheEntry {
+    response: Vec<u8>,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys by last use, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+    /// Response bytes of all entries
+    bytes: usize,
+    clock: u64,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.response.len();
+        }
+    }
+}
+
+/// Bounded LRU of one route's responses
+struct RouteCache {
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+}
+
+impl RouteCache {
+    fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// `None` if the request can't be hashed, so isn't cached
+    ///
+    /// `version` is the schema version the route serves, which a request
+    /// without one is handled as.
+    fn key(&self, request: &Frame, version: Option<u32>) -> Option<CacheKey> {
+        let mut content = Frame::new(FrameType::Data).with_payload(request.payload.clone());
+        // The same bytes can mean different things under another schema
+        let version = request
+            .get_header(SCHEMA_VERSION_HEADER)
+            .map(str::to_string)
+            .or_else(|| version.map(|version| version.to_string()));
+        if let Some(version) = version {
+            content = content.with_header(SCHEMA_VERSION_HEADER, &version);
+        }
+        for header in &self.config.vary_headers {
+            if let Some(value) = request.get_header(header) {
+                content = content.with_header(header, value);
+            }
+        }
+        content.content_hash_excluding(&[]).ok()
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let entry = state.entries.get_mut(key)?;
+        if entry.expires_at <= Instant::now() {
+            state.remove(key);
+            return None;
+        }
+        state.clock += 1;
+        state.recency.remove(&entry.last_used);
+        state.recency.insert(state.clock, *key);
+        entry.last_used = state.clock;
+        Some(entry.response.clone())
+    }
+
+    fn insert(&self, key: CacheKey, response: Vec<u8>) {
+        let max_bytes = self.config.max_bytes.unwrap_or(usize::MAX);
+        if self.config.max_entries == 0 || response.len() > max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        while state.entries.len() >= self.config.max_entries
+            || state.bytes + response.len() > max_bytes
+        {
+            let Some((_, oldest)) = state.recency.first_key_value() else {
+                break;
+            };
+            let oldest = *oldest;
+            state.remove(&oldest);
+        }
+        state.bytes += response.len();
+        state.clock += 1;
+        let last_used = state.clock;
+        state.recency.insert(last_used, key);
+        state.entries.insert(
+            key,
+            CacheEntry {
+                response,
+                expires_at: Instant::now() + self.config.ttl,
+                last_used,
+            },
+        );
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.recency.clear();
+        state.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(payload: &[u8]) -> Frame {
+        Frame::new(FrameType::Data)
+            .with_header(METHOD_HEADER, "lookup")
+            .with_payload(payload.to_vec())
+    }
+
+    #[test]
+    fn test_least_recently_used_entry_is_evicted() {
+        let cache = RouteCache::new(CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::new(Duration::from_secs(60))
+        });
+        let (a, b, c) = (
+            cache.key(&request(b"a"), None).unwrap(),
+            cache.key(&request(b"b"), None).unwrap(),
+            cache.key(&request(b"c"), None).unwrap(),
+        );
+        cache.insert(a, b"A".to_vec());
+        cache.insert(b, b"B".to_vec());
+        assert!(cache.get(&a).is_some());
+
+        cache.insert(c, b"C".to_vec());
+        assert!(cache.get(&b).is_none());
+        assert_eq!(cache.get(&a), Some(b"A".to_vec()));
+        assert_eq!(cache.get(&c), Some(b"C".to_vec()));
+    }
+
+    #[test]
+    fn test_vary_headers_are_part_of_the_key() {
+        let cache = RouteCache::new(CacheConfig {
+            vary_headers: vec!["locale".to_string()],
+            ..CacheConfig::new(Duration::from_secs(60))
+        });
+        let en = cache.key(&request(b"x").with_header("locale", "en"), None);
+        let de = cache.key(&request(b"x").with_header("locale", "de"), None);
+        assert!(en != de);
+        assert!(
+            en == cache.key(
+                &request(b"x")
+                    .with_header("locale", "en")
+                    .with_header("other", "1"),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_schema_version_is_part_of_the_key() {
+        let cache = RouteCache::new(CacheConfig::new(Duration::from_secs(60)));
+        let v1 = cache.key(&request(b"x").with_header(SCHEMA_VERSION_HEADER, "1"), None);
+        let v2 = cache.key(&request(b"x").with_header(SCHEMA_VERSION_HEADER, "2"), None);
+        assert!(v1 != v2);
+        assert!(v1 != cache.key(&request(b"x"), None));
+    }
+
+    #[test]
+    fn test_missing_schema_version_keys_as_the_route_version() {
+        let cache = RouteCache::new(CacheConfig::new(Duration::from_secs(60)));
+        let sent = cache.key(&request(b"x").with_header(SCHEMA_VERSION_HEADER, "2"), Some(2));
+        assert!(sent == cache.key(&request(b"x"), Some(2)));
+        assert!(sent != cache.key(&request(b"x"), Some(1)));
+    }
+
+    #[test]
+    fn test_byte_cap_evicts_least_recently_used() {
+        let cache = RouteCache::new(CacheConfig {
+            max_bytes: Some(10),
+            ..CacheConfig::new(Duration::from_secs(60))
+        });
+        let (a, b, c) = (
+            cache.key(&request(b"a"), None).unwrap(),
+            cache.key(&request(b"b"), None).unwrap(),
+            cache.key(&request(b"c"), None).unwrap(),
+        );
+        cache.insert(a, vec![b'A'; 4]);
+        cache.insert(b, vec![b'B'; 4]);
+        assert!(cache.get(&a).is_some());
+
+        // 12 bytes with b, so the least recently used one goes
+        cache.insert(c, vec![b'C'; 4]);
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&a).is_some() && cache.get(&c).is_some());
+        assert_eq!(cache.state.lock().unwrap().bytes, 8);
+
+        // Replacing an entry counts only its new size
+        cache.insert(a, vec![b'A'; 6]);
+        assert_eq!(cache.state.lock().unwrap().bytes, 10);
+        assert!(cache.get(&c).is_some());
+
+        // Too large to keep at all, and nothing is evicted for it
+        cache.insert(b, vec![b'B'; 11]);
+        assert!(cache.get(&b).is_none());
+        assert_eq!(cache.state.lock().unwrap().bytes, 10);
+    }
+
+    #[test]
+    fn test_distinct_payloads_never_share_an_entry() {
+        let cache = RouteCache::new(CacheConfig {
+            max_entries: 8192,
+            ..CacheConfig::new(Duration::from_secs(60))
+        });
+        let payloads: Vec<Vec<u8>> = (0u32..4096)
+            .map(|i| i.to_le_bytes().to_vec())
+            .chain([Vec::new(), vec![0], vec![0, 0]])
+            .collect();
+        for payload in &payloads {
+            let response = [b"for ".as_slice(), payload].concat();
+            cache.insert(cache.key(&request(payload), None).unwrap(), response);
+        }
+        for payload in &payloads {
+            let response = [b"for ".as_slice(), payload].concat();
+            assert_eq!(
+                cache.get(&cache.key(&request(payload), None).unwrap()),
+                Some(response)
+            );
+        }
+        assert_eq!(
+            cache.get(&cache.key(&request(b"never sent"), None).unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Tying request types to their method and schema version
+//!
+//! With [`VstpClient::call`](crate::easy::VstpClient::call) and
+//! [`Router::route`](crate::router::Router::route) the method name is a
+//! string on each side, and a client sending a newer shape of a request than
+//! the server expects only finds out from a deserialization error. A type
+//! implementing [`VstpMessage`] names its method and schema version once:
+//!
+//! ```
+//! use serde::{Deserialize, Serialize};
+//! use vstp::VstpMessage;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct GetUser {
+//!     id: u64,
+//! }
+//!
+//! impl VstpMessage for GetUser {
+//!     const METHOD: &'static str = "users.get";
+//!     const SCHEMA_VERSION: u32 = 1;
+//! }
+//! ```
+//!
+//! [`VstpClient::call_typed`](crate::easy::VstpClient::call_typed) sends it
+//! with the `method`, `content-type` and `schema-version` headers filled in
+//! from the type, and
+//! [`Router::route_typed`](crate::router::Router::route_typed) registers a
+//! handler that only takes that version. A request of another version is
+//! answered with ERR `SchemaMismatch` naming both versions, which the client
+//! returns as [`VstpError::SchemaMismatch`]. A route can still serve older
+//! (or newer) clients by converting their requests, see
+//! [`Router::compat`](crate::router::Router::compat).
+//!
+//! Requests without a `schema-version` header, e.g. from `call`, are handed
+//! to typed routes as they are.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::router::METHOD_HEADER;
+use crate::types::{ErrorCode, Frame, VstpError};
+
+/// Header carrying the schema version of a typed request
+pub const SCHEMA_VERSION_HEADER: &str = "schema-version";
+
+/// Header on a `SchemaMismatch` ERR carrying the version the route serves
+pub const SERVED_SCHEMA_VERSION_HEADER: &str = "served-schema-version";
+
+/// A request type bound to one method and schema version
+pub trait VstpMessage: Serialize + DeserializeOwned + Send + 'static {
+    /// The method the request is sent to
+    const METHOD: &'static str;
+    /// Version of the request's shape; bump it when the shape changes
+    const SCHEMA_VERSION: u32;
+}
+
+/// The ERR answering a `method` request of version `sent` on a route serving `served`
+pub(crate) fn mismatch_frame(method: &str, sent: u32, served: u32) -> Frame {
+    let error = VstpError::SchemaMismatch {
+        method: method.to_string(),
+        sent,
+        served,
+    };
+    Frame::coded_error(ErrorCode::SchemaMismatch, &error.to_string())
+        .with_header(METHOD_HEADER, method)
+        .with_header(SCHEMA_VERSION_HEADER, &sent.to_string())
+        .with_header(SERVED_SCHEMA_VERSION_HEADER, &served.to_string())
+}
+
+/// The [`VstpError::SchemaMismatch`] an ERR frame reports, if it is one
+pub(crate) fn mismatch_error(frame: &Frame) -> Option<VstpError> {
+    if frame.error_code() != Some(ErrorCode::SchemaMismatch) {
+        return None;
+    }
+    let version = |header| frame.get_header(header)?.parse().ok();
+    Some(VstpError::SchemaMismatch {
+        method: frame.get_header(METHOD_HEADER)?.to_string(),
+        sent: version(SCHEMA_VERSION_HEADER)?,
+        served: version(SERVED_SCHEMA_VERSION_HEADER)?,
+    })
+}
@@ -0,0 +1,274 @@
+//! Capping the outbound byte rate
+//!
+//! A [`SendShaper`] is a token bucket in front of the socket: bytes are let
+//! through at up to its configured rate, with bursts of up to a tenth of a
+//! second's worth after an idle period. It is the egress-side complement to
+//! the per-server inbound limits of [`ingress`](crate::ingress) and
+//! [`usage`](crate::usage).
+//!
+//! Set `max_send_bps` in [`TcpServerConfig`](crate::tcp::TcpServerConfig),
+//! [`UdpServerConfig`](crate::udp::UdpServerConfig),
+//! [`UdpConfig`](crate::udp::client::UdpConfig) or
+//! [`ConnectOptions`](crate::ConnectOptions) to shape everything the server
+//! or client sends, control frames included. A server shares one bucket
+//! across all its connections, so the cap is on the server's total.
+//!
+//! TCP writes are split into chunks of at most one burst, each waiting for
+//! room in the bucket. A UDP datagram waits until there is room for all of
+//! it, or for a full bucket if it is larger than a burst. Either way the
+//! sender sees a slower `send`, never an error.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Smallest burst a shaper allows, so one frame header never has to be split
+const MIN_BURST_BYTES: u64 = 1024;
+
+/// Length of the window [`SendShaper::current_bps`] averages over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative while sent bytes are being paid back
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Bytes sent in the current and the previous [`RATE_WINDOW`]
+#[derive(Debug)]
+struct RateMeter {
+    window_start: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl RateMeter {
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= 2 * RATE_WINDOW {
+            self.previous = 0;
+            self.current = 0;
+            self.window_start = now;
+        } else if elapsed >= RATE_WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.window_start += RATE_WINDOW;
+        }
+    }
+}
+
+/// Token bucket limiting the bytes sent per second, with counters of what it let through
+#[derive(Debug)]
+pub struct SendShaper {
+    rate: u64,
+    burst: u64,
+    bucket: Mutex<Bucket>,
+    meter: Mutex<RateMeter>,
+    bytes_sent: AtomicU64,
+    throttled_nanos: AtomicU64,
+}
+
+impl SendShaper {
+    /// Shape to `max_bytes_per_sec` with bursts of a tenth of a second's worth
+    pub fn new(max_bytes_per_sec: u64) -> Self {
+        Self::with_burst(max_bytes_per_sec, max_bytes_per_sec / 10)
+    }
+
+    /// Shape to `max_bytes_per_sec`, letting `burst_bytes` through back to back after an idle period
+    pub fn with_burst(max_bytes_per_sec: u64, burst_bytes: u64) -> Self {
+        let burst = burst_bytes.max(MIN_BURST_BYTES);
+        let now = Instant::now();
+        Self {
+            rate: max_bytes_per_sec.max(1),
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst as f64,
+                last_refill: now,
+            }),
+            meter: Mutex::new(RateMeter {
+                window_start: now,
+                current: 0,
+                previous: 0,
+            }),
+            bytes_sent: AtomicU64::new(0),
+            throttled_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// The configured cap in bytes per second
+    pub fn max_bytes_per_sec(&self) -> u64 {
+        self.rate
+    }
+
+    /// Bytes sent over the last full second
+    pub fn current_bps(&self) -> u64 {
+        let mut meter = self.meter.lock().unwrap();
+        meter.roll(Instant::now());
+        meter.previous
+    }
+
+    /// Bytes let through since the shaper was created
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Total time senders were held back
+    pub fn throttled_time(&self) -> Duration {
+        Duration::from_nanos(self.throttled_nanos.load(Ordering::Relaxed))
+    }
+
+    /// How long to wait before sending `bytes`, `None` to send now
+    ///
+    /// Sends of more than a burst wait for a full bucket and leave it in debt.
+    fn delay(&self, bytes: usize) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        bucket.last_refill = now;
+        let needed = bytes.min(self.burst as usize) as f64;
+        if bucket.tokens >= needed {
+            return None;
+        }
+        let wait = Duration::from_secs_f64((needed - bucket.tokens) / self.rate as f64);
+        self.throttled_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        Some(wait)
+    }
+
+    /// Take `bytes` out of the bucket, which may go into debt
+    fn charge(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        let mut meter = self.meter.lock().unwrap();
+        meter.roll(Instant::now());
+        meter.current += bytes as u64;
+    }
+
+    /// Wait until `bytes` may be sent, then account for them
+    pub async fn acquire(&self, bytes: usize) {
+        while let Some(wait) = self.delay(bytes) {
+            sleep(wait).await;
+        }
+        self.charge(bytes);
+    }
+}
+
+/// A stream whose writes go through an optional [`SendShaper`]
+#[derive(Debug)]
+pub(crate) struct Shaped<S> {
+    inner: S,
+    shaper: Option<Arc<SendShaper>>,
+    wait: Option<Pin<Box<Sleep>>>,
+    /// Writes that reached the inner stream
+    writes: u64,
+}
+
+impl<S> Shaped<S> {
+    pub(crate) fn new(inner: S, shaper: Option<Arc<SendShaper>>) -> Self {
+        Self {
+            inner,
+            shaper,
+            wait: None,
+            writes: 0,
+        }
+    }
+
+    pub(crate) fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    pub(crate) fn shaper(&self) -> Option<&Arc<SendShaper>> {
+        self.shaper.as_ref()
+    }
+
+    pub(crate) fn set_shaper(&mut self, shaper: Option<Arc<SendShaper>>) {
+        self.shaper = shaper;
+        self.wait = None;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shaped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shaped<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(shaper) = &this.shaper else {
+            let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+            this.writes += 1;
+            return Poll::Ready(Ok(written));
+        };
+        let chunk = &buf[..buf.len().min(shaper.burst as usize)];
+        loop {
+            if let Some(wait) = &mut this.wait {
+                ready!(wait.as_mut().poll(cx));
+                this.wait = None;
+            }
+            match shaper.delay(chunk.len()) {
+                Some(delay) => this.wait = Some(Box::pin(sleep(delay))),
+                None => break,
+            }
+        }
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, chunk))?;
+        this.writes += 1;
+        shaper.charge(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shaper_holds_the_configured_rate() {
+        let shaper = SendShaper::with_burst(10_000, 2_000);
+        let start = Instant::now();
+        for _ in 0..50 {
+            shaper.acquire(1_000).await;
+        }
+        // The first burst goes out at once, the other 48 KB at 10 KB/s
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(4_700) && elapsed <= Duration::from_millis(4_900),
+            "took {:?}",
+            elapsed
+        );
+        assert_eq!(shaper.bytes_sent(), 50_000);
+        assert!(shaper.throttled_time() >= Duration::from_millis(4_700));
+
+        sleep(Duration::from_millis(200)).await;
+        let rate = shaper.current_bps();
+        assert!((9_000..=11_000).contains(&rate), "rate {}", rate);
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(shaper.current_bps(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Socket-level options applied when binding servers
+//!
+//! ## `SO_REUSEADDR` vs `SO_REUSEPORT`
+//!
+//! - **`SO_REUSEADDR`** on Linux and the BSDs lets a TCP listener bind a port
+//!   that still has connections in `TIME_WAIT`, which is what a restarting
+//!   service needs. For UDP on Linux it additionally allows several sockets
+//!   that all set it to bind the same address, with unicast datagrams going to
+//!   only one of them. On Windows it allows binding a port that is actively in
+//!   use by another socket, so leave it off there unless that is intended.
+//! - **`SO_REUSEPORT`** (Unix only) allows several sockets to bind the exact
+//!   same address and port. Linux (3.9+) load-balances incoming connections and
+//!   datagrams across all of them, provided they belong to the same user; the
+//!   BSDs and macOS allow the duplicate bind but don't balance unicast traffic.
+//!   Requesting it on a platform without support fails the bind.
+//!
+//! ## TCP keepalive
+//!
+//! `keepalive` turns on `SO_KEEPALIVE` for accepted TCP connections, so the
+//! kernel eventually notices a peer that vanished without sending a FIN. The
+//! duration is the idle time before the first probe; on most systems it then
+//! takes several more unanswered probes before the connection is reset.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+
+/// Backlog used for listeners bound through [`SocketOptions`]
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Options applied to a server socket before it is bound
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    /// Set `SO_REUSEADDR` to allow fast restarts on a port in `TIME_WAIT`
+    pub reuse_addr: bool,
+    /// Set `SO_REUSEPORT` to share the port between several sockets (Unix only)
+    pub reuse_port: bool,
+    /// Enable TCP keepalive on accepted connections, probing after this much idle time
+    pub keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Options with `SO_REUSEADDR` enabled
+    pub fn reuse_addr() -> Self {
+        Self {
+            reuse_addr: true,
+            ..Self::default()
+        }
+    }
+
+    fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if self.reuse_addr {
+            socket.set_reuse_address(true)?;
+        }
+        if self.reuse_port {
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Bind a UDP socket with these options
+    pub(crate) async fn bind_udp(&self, addr: impl ToSocketAddrs) -> io::Result<UdpSocket> {
+        let addr = resolve(addr).await?;
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        self.apply(&socket)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Apply per-connection options to an accepted TCP stream
+    pub(crate) fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(idle) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+
+    /// Bind a TCP listener with these options
+    pub(crate) async fn bind_tcp(&self, addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        let addr = resolve(addr).await?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        self.apply(&socket)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        TcpListener::from_std(socket.into())
+    }
+}
+
+/// Resolve an address to the first socket address it names
+async fn resolve(addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    })
+}
@@ -0,0 +1,367 @@
+//! Byte stream adapter over a VSTP connection
+//!
+//! [`VstpByteStream`] lets code written against `AsyncRead + AsyncWrite`
+//! (or blocking `Read + Write` with the `sync` feature) talk through a
+//! [`VstpTcpClient`] unchanged. Writes become DATA frames of at most
+//! [`chunk_size`](ByteStreamConfig::chunk_size) bytes following the
+//! [`chunk`](crate::chunk) convention: every frame carries the stream's
+//! `stream-id` and its `seq`, and the stream ends with an empty DATA frame
+//! carrying `fin: 1` and `total`. Reads return the payloads of the peer's
+//! DATA frames in order, and end at the peer's final chunk or BYE.
+//!
+//! Both directions use [`flow`](crate::flow) credit: the peer's initial
+//! window is read from the headers it sent before data started flowing,
+//! usually its WELCOME, and ours is advertised with a window update as soon
+//! as the stream starts. Credit is returned to the peer only as the
+//! application reads, so a slow reader throttles the writer on the other end.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::PollSender;
+use tracing::debug;
+
+use crate::chunk::{FIN_HEADER, SEQ_HEADER, STREAM_ID_HEADER, TOTAL_HEADER};
+use crate::flow::{window_update, FlowController, ReceiveWindow, WindowCredit};
+use crate::tcp::VstpTcpClient;
+use crate::types::{Frame, FrameType, Header, VstpError};
+
+/// Settings for a [`VstpByteStream`]
+#[derive(Debug, Clone)]
+pub struct ByteStreamConfig {
+    /// Largest payload of one DATA frame
+    pub chunk_size: usize,
+    /// Credit granted to the peer; a limit left as `None` is unlimited
+    pub receive_window: WindowCredit,
+    /// Chunks written but not yet sent before writes wait
+    pub write_buffer: usize,
+}
+
+impl Default for ByteStreamConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 16 * 1024,
+            receive_window: WindowCredit::new(None, Some(1024 * 1024)),
+            write_buffer: 8,
+        }
+    }
+}
+
+/// `AsyncRead + AsyncWrite` over a [`VstpTcpClient`]
+///
+/// A background task owns the client until both directions are finished,
+/// then closes it with a BYE. [`shutdown`](tokio::io::AsyncWriteExt::shutdown)
+/// ends the write direction only; reads continue until the peer ends its
+/// own. Bytes accepted by a write are sent even if the stream is dropped
+/// right after.
+pub struct VstpByteStream {
+    incoming: mpsc::UnboundedReceiver<io::Result<Frame>>,
+    control: mpsc::UnboundedSender<Frame>,
+    outgoing: PollSender<Vec<u8>>,
+    window: ReceiveWindow,
+    chunk_size: usize,
+    current: Vec<u8>,
+    offset: usize,
+    driver: JoinHandle<()>,
+}
+
+impl VstpByteStream {
+    /// Wrap `client`, whose peer sent `peer_headers` before data started flowing
+    ///
+    /// Window headers among `peer_headers` set how much the stream may send
+    /// before the peer grants more; without them sending is unlimited.
+    pub fn new(client: VstpTcpClient, peer_headers: &[Header]) -> Self {
+        Self::with_config(client, peer_headers, ByteStreamConfig::default())
+    }
+
+    pub fn with_config(
+        client: VstpTcpClient,
+        peer_headers: &[Header],
+        config: ByteStreamConfig,
+    ) -> Self {
+        let mut announced = Frame::new(FrameType::Welcome);
+        announced.headers = peer_headers.to_vec();
+        let flow = FlowController::new(WindowCredit::from_frame(&announced).unwrap_or_default());
+
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(config.write_buffer.max(1));
+        let driver = Driver {
+            client,
+            flow,
+            stream_id: rand::random::<u64>().to_string(),
+            sent: 0,
+            incoming: Some(incoming_tx),
+            received: 0,
+            control: control_rx,
+            outgoing: outgoing_rx,
+        };
+        let initial = config.receive_window;
+        Self {
+            incoming,
+            control,
+            outgoing: PollSender::new(outgoing_tx),
+            window: ReceiveWindow::new(config.receive_window),
+            chunk_size: config.chunk_size.max(1),
+            current: Vec::new(),
+            offset: 0,
+            driver: tokio::spawn(driver.run(initial)),
+        }
+    }
+
+    /// End the write direction, as [`shutdown`](tokio::io::AsyncWriteExt::shutdown) does
+    pub fn close_write(&mut self) {
+        self.outgoing.close();
+    }
+
+    /// Whether the background task has closed the connection
+    pub fn is_closed(&self) -> bool {
+        self.driver.is_finished()
+    }
+
+    /// Take the next DATA frame's payload as the current one, returning false at EOF
+    fn advance(&mut self, next: Option<io::Result<Frame>>) -> io::Result<bool> {
+        let Some(frame) = next.transpose()? else {
+            return Ok(false);
+        };
+        if let Some(update) = self.window.consume(&frame) {
+            let _ = self.control.send(update);
+        }
+        self.current = frame.payload;
+        self.offset = 0;
+        Ok(true)
+    }
+
+    fn copy_to(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.current.len() - self.offset);
+        buf[..n].copy_from_slice(&self.current[self.offset..self.offset + n]);
+        self.offset += n;
+        n
+    }
+
+    fn chunk<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[..buf.len().min(self.chunk_size)]
+    }
+}
+
+impl AsyncRead for VstpByteStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        while this.offset == this.current.len() {
+            let next = ready!(this.incoming.poll_recv(cx));
+            if !this.advance(next)? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let n = this.copy_to(buf.initialize_unfilled());
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for VstpByteStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(this.outgoing.poll_reserve(cx)).map_err(|_| write_closed())?;
+        let chunk = this.chunk(buf);
+        this.outgoing
+            .send_item(chunk.to_vec())
+            .map_err(|_| write_closed())?;
+        Poll::Ready(Ok(chunk.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().close_write();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Blocks the calling thread, so it must not be used from async code
+#[cfg(feature = "sync")]
+impl io::Read for VstpByteStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.offset == self.current.len() {
+            let next = self.incoming.blocking_recv();
+            if !self.advance(next)? {
+                return Ok(0);
+            }
+        }
+        Ok(self.copy_to(buf))
+    }
+}
+
+/// Blocks the calling thread, so it must not be used from async code
+#[cfg(feature = "sync")]
+impl io::Write for VstpByteStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk = self.chunk(buf);
+        self.outgoing
+            .get_ref()
+            .ok_or_else(write_closed)?
+            .blocking_send(chunk.to_vec())
+            .map_err(|_| write_closed())?;
+        Ok(chunk.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for VstpByteStream {
+    fn drop(&mut self) {
+        // The driver still sends what was written, then the final chunk
+        self.outgoing.close();
+    }
+}
+
+fn write_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "write side of the stream is closed",
+    )
+}
+
+/// Moves chunks between the connection and the stream's channels
+struct Driver {
+    client: VstpTcpClient,
+    flow: FlowController,
+    stream_id: String,
+    sent: usize,
+    /// `None` once the peer ended its direction
+    incoming: Option<mpsc::UnboundedSender<io::Result<Frame>>>,
+    received: usize,
+    control: mpsc::UnboundedReceiver<Frame>,
+    outgoing: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Driver {
+    async fn run(mut self, initial: WindowCredit) {
+        let result = self.pump(initial).await;
+        if let Err(e) = &result {
+            debug!("Byte stream ended with error: {}", e);
+            if let Some(incoming) = &self.incoming {
+                let _ = incoming.send(Err(io::Error::other(e.to_string())));
+            }
+        }
+        if !matches!(result, Ok(false)) {
+            let _ = self.client.close().await;
+        }
+    }
+
+    /// Returns whether the connection is still open
+    async fn pump(&mut self, initial: WindowCredit) -> Result<bool, VstpError> {
+        if initial.frames.is_some() || initial.bytes.is_some() {
+            self.client.send(window_update(initial)).await?;
+        }
+        let mut pending: Option<Vec<u8>> = None;
+        let mut writing = true;
+        while writing || self.incoming.is_some() {
+            let pending_len = pending.as_ref().map(Vec::len);
+            tokio::select! {
+                Some(update) = self.control.recv() => self.client.send(update).await?,
+                frame = self.client.recv() => match frame? {
+                    Some(frame) => {
+                        if !self.deliver(frame)? {
+                            return Ok(false);
+                        }
+                    }
+                    None => return Ok(false),
+                },
+                _ = self.flow.acquire(pending_len.unwrap_or(0)), if pending_len.is_some() => {
+                    let chunk = pending.take().unwrap_or_default();
+                    let frame = self.data(chunk);
+                    self.client.send(frame).await?;
+                }
+                chunk = self.outgoing.recv(), if writing && pending.is_none() => match chunk {
+                    Some(chunk) => pending = Some(chunk),
+                    None => {
+                        let fin = self
+                            .data(Vec::new())
+                            .with_header(TOTAL_HEADER, &self.sent.to_string())
+                            .with_header(FIN_HEADER, "1");
+                        self.client.send(fin).await?;
+                        writing = false;
+                    }
+                },
+            }
+        }
+        Ok(true)
+    }
+
+    /// Next chunk of our direction
+    fn data(&mut self, payload: Vec<u8>) -> Frame {
+        let frame = Frame::new(FrameType::Data)
+            .with_header(STREAM_ID_HEADER, &self.stream_id)
+            .with_header(SEQ_HEADER, &self.sent.to_string())
+            .with_payload(payload);
+        self.sent += 1;
+        frame
+    }
+
+    /// Handle a frame from the peer, returning false once it closed the connection
+    fn deliver(&mut self, frame: Frame) -> Result<bool, VstpError> {
+        self.flow.handle_frame(&frame);
+        match frame.typ {
+            FrameType::Bye => {
+                self.incoming = None;
+                return Ok(false);
+            }
+            FrameType::Err => {
+                return Err(VstpError::Protocol(
+                    String::from_utf8_lossy(&frame.payload).into_owned(),
+                ))
+            }
+            FrameType::Data => {}
+            _ => return Ok(true),
+        }
+        let Some(incoming) = &self.incoming else {
+            return Ok(true);
+        };
+        let seq = frame.get_header(SEQ_HEADER).and_then(|v| v.parse().ok());
+        if seq.is_some_and(|seq: usize| seq != self.received) {
+            return Err(VstpError::Protocol(format!(
+                "expected chunk {}, got {:?}",
+                self.received, seq
+            )));
+        }
+        self.received += 1;
+        if frame.get_header(FIN_HEADER) == Some("1") {
+            if !frame.payload.is_empty() {
+                let _ = incoming.send(Ok(frame));
+            }
+            self.incoming = None;
+        } else {
+            let _ = incoming.send(Ok(frame));
+        }
+        Ok(true)
+    }
+}
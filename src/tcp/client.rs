@@ -1,16 +1,33 @@
+use std::sync::Arc;
+
+use bytes::BytesMut;
 use futures::SinkExt;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, info};
 
+use crate::compression::{CompressionControl, Incoming};
+use crate::diagnostics::not_vstp;
+use crate::frame::try_decode_frame;
+use crate::shaping::{SendShaper, Shaped};
+use crate::tcp::stream::{ReadHalf, Socket, WriteHalf};
 use crate::types::{Frame, FrameType, VstpError};
-use crate::VstpFrameCodec as Codec;
+use crate::{VstpFrameCodec as Codec, WireTap};
+
+/// Largest payload a compressed frame from the server may inflate to
+const MAX_INFLATED_SIZE: usize = 8 * 1024 * 1024;
 
 /// TCP client for VSTP protocol
 pub struct VstpTcpClient {
-    framed_write: FramedWrite<tokio::net::tcp::OwnedWriteHalf, Codec>,
-    framed_read: FramedRead<tokio::net::tcp::OwnedReadHalf, Codec>,
+    framed_write: FramedWrite<Shaped<WriteHalf>, Codec>,
+    framed_read: FramedRead<ReadHalf, Codec>,
+    compression: CompressionControl,
+    /// Whether a frame has been decoded yet, to tell other protocols from corruption
+    received_any: bool,
+    /// Whether the TLS handshake resumed an earlier session
+    tls_resumed: bool,
 }
 
 impl VstpTcpClient {
@@ -18,31 +35,265 @@ impl VstpTcpClient {
     pub async fn connect(addr: &str) -> Result<Self, VstpError> {
         let socket = TcpStream::connect(addr).await?;
         info!("Connected to VSTP server at {}", addr);
+        Ok(Self::over(Socket::Plain(socket)))
+    }
+
+    /// Connect to a VSTP server over TLS, checking its certificate against
+    /// the host part of `addr`; see [`tls`](crate::tcp::tls)
+    ///
+    /// To check it against another name, e.g. when `addr` is an IP address,
+    /// use [`connect_tls_as`](VstpTcpClient::connect_tls_as), which takes the
+    /// server name as its third argument.
+    ///
+    /// Fails with [`VstpError::Tls`] if `config` offers versions below TLS
+    /// 1.3 or sets ALPN without offering `vstp`, see
+    /// [`check_client_config`](crate::tcp::tls::check_client_config), and
+    /// with [`VstpError::TlsHandshake`] if the handshake doesn't complete,
+    /// e.g. because the server's certificate is refused.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls(
+        addr: &str,
+        config: impl Into<Arc<rustls::ClientConfig>>,
+    ) -> Result<Self, VstpError> {
+        let server_name = crate::tcp::tls::server_name(addr)?;
+        Self::connect_tls_as(addr, config, server_name).await
+    }
+
+    /// Connect to a VSTP server over TLS, checking its certificate against
+    /// `server_name`, e.g. when `addr` is an IP address
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls_as(
+        addr: &str,
+        config: impl Into<Arc<rustls::ClientConfig>>,
+        server_name: rustls::pki_types::ServerName<'static>,
+    ) -> Result<Self, VstpError> {
+        Self::connect_tls_with_config(addr, server_name, config.into()).await
+    }
+
+    /// Connect to a VSTP server over TLS with a fully built rustls `config`,
+    /// e.g. one with its own cipher suites, session storage or client
+    /// certificate, checking the server's certificate against `server_name`
+    ///
+    /// Everything in `config` is the caller's, except that it must speak
+    /// TLS 1.3 only and offer `vstp` if it sets ALPN; it fails with
+    /// [`VstpError::Tls`] otherwise, before connecting.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls_with_config(
+        addr: &str,
+        server_name: rustls::pki_types::ServerName<'static>,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self, VstpError> {
+        crate::tcp::tls::check_client_config(&config)?;
+        let socket = TcpStream::connect(addr).await?;
+        Self::tls_over(socket, addr, config, server_name, false).await
+    }
 
+    /// [`connect_tls_with_config`](VstpTcpClient::connect_tls_with_config),
+    /// sending the first frames as TLS early data if `config` holds a session
+    /// to resume with this server
+    ///
+    /// Returns before the handshake completes, and the first
+    /// [`send`](VstpTcpClient::send) completes it. Early data can be
+    /// replayed, so only send frames the server may act on twice; see
+    /// [early data](crate::tcp::tls#early-data).
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls_with_early_data(
+        addr: &str,
+        server_name: rustls::pki_types::ServerName<'static>,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self, VstpError> {
+        crate::tcp::tls::check_client_config(&config)?;
+        let socket = TcpStream::connect(addr).await?;
+        Self::tls_over(socket, addr, config, server_name, true).await
+    }
+
+    /// Run the TLS handshake on the connected `socket`, leaving it to the
+    /// first send if `early_data` goes with it
+    #[cfg(feature = "tls")]
+    pub(crate) async fn tls_over(
+        socket: TcpStream,
+        addr: &str,
+        config: Arc<rustls::ClientConfig>,
+        server_name: rustls::pki_types::ServerName<'static>,
+        early_data: bool,
+    ) -> Result<Self, VstpError> {
+        crate::tcp::tls::check_client_config(&config)?;
+        let connector = match early_data {
+            true => tokio_rustls::TlsConnector::from(crate::tcp::tls::sending_early_data(config))
+                .early_data(true),
+            false => tokio_rustls::TlsConnector::from(config),
+        };
+        let mut stream = connector
+            .connect(server_name, socket)
+            .await
+            .map_err(VstpError::TlsHandshake)?;
+        // Early data goes only with a session to resume
+        let resumed = stream.get_mut().1.early_data().is_some()
+            || stream.get_ref().1.handshake_kind() == Some(rustls::HandshakeKind::Resumed);
+        info!("Connected to VSTP server at {} over TLS", addr);
+        let mut client = Self::over(Socket::Tls(Box::new(stream.into())));
+        client.tls_resumed = resumed;
+        Ok(client)
+    }
+
+    /// A client over the connected plain TCP `socket`
+    pub(crate) fn plain(socket: TcpStream) -> Self {
+        Self::over(Socket::Plain(socket))
+    }
+
+    fn over(socket: Socket) -> Self {
         let (read, write) = socket.into_split();
-        let framed_read = FramedRead::new(read, Codec::default());
-        let framed_write = FramedWrite::new(write, Codec::default());
+        Self {
+            framed_write: FramedWrite::new(Shaped::new(write, None), Codec::default()),
+            framed_read: FramedRead::new(read, Codec::default()),
+            compression: CompressionControl::new(),
+            received_any: false,
+            tls_resumed: false,
+        }
+    }
 
-        Ok(Self {
-            framed_write,
-            framed_read,
-        })
+    /// Whether the TLS handshake resumed a session from an earlier
+    /// connection with the same client config, skipping the certificate
+    /// exchange; false over plain TCP
+    ///
+    /// While early data waits for the handshake, it tells whether the
+    /// client offered a session to resume.
+    pub fn tls_resumed(&self) -> bool {
+        self.tls_resumed
     }
 
     /// Send a frame to the server
     pub async fn send(&mut self, frame: Frame) -> Result<(), VstpError> {
         debug!("Sending frame: {:?}", frame.typ);
+        let frame = self.compression.outgoing(frame)?;
         self.framed_write.send(frame).await?;
         Ok(())
     }
 
+    /// Send one already encoded frame exactly as it is, e.g. one a relay
+    /// received and kept as [`FrameMeta::raw_bytes`](crate::meta::FrameMeta::raw_bytes)
+    ///
+    /// Unlike [`send`](VstpTcpClient::send) the frame keeps its own version,
+    /// flags and trailer, and isn't compressed. Fails without sending
+    /// anything unless `bytes` hold exactly one valid frame.
+    pub async fn send_wire_bytes(&mut self, bytes: &[u8]) -> Result<(), VstpError> {
+        let mut check = BytesMut::from(bytes);
+        match try_decode_frame(&mut check, bytes.len())? {
+            Some(_) if check.is_empty() => {}
+            _ => {
+                return Err(VstpError::Protocol(
+                    "Wire bytes must hold exactly one frame".to_string(),
+                ))
+            }
+        }
+        // Frames sent before are flushed, so these bytes can't land inside one
+        self.framed_write.flush().await?;
+        self.framed_write.encoder().wire_tap().wire_out(bytes);
+        self.framed_write.get_mut().write_all(bytes).await?;
+        self.framed_write.get_mut().flush().await?;
+        Ok(())
+    }
+
     /// Receive a frame from the server
+    ///
+    /// Compressed frames are decompressed, and the server's compression
+    /// proposals are answered without being returned. If the server's first
+    /// bytes aren't a VSTP frame the error is [`VstpError::NotVstp`], naming
+    /// the protocol they look like.
     pub async fn recv(&mut self) -> Result<Option<Frame>, VstpError> {
-        let frame = self.framed_read.try_next().await?;
-        if let Some(ref frame) = frame {
+        loop {
+            let frame = match self.framed_read.try_next().await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(None),
+                Err(VstpError::InvalidMagic(_)) if !self.received_any => {
+                    return Err(not_vstp(self.framed_read.read_buffer()));
+                }
+                Err(e) => return Err(e),
+            };
+            self.received_any = true;
             debug!("Received frame: {:?}", frame.typ);
+            match self.compression.incoming(frame, MAX_INFLATED_SIZE)? {
+                Incoming::Frame(frame) => return Ok(Some(frame)),
+                Incoming::Reply(reply) => self.framed_write.send(reply).await?,
+                Incoming::Settled => {}
+            }
         }
-        Ok(frame)
+    }
+
+    /// Ask the server to switch payload compression on or off
+    ///
+    /// The setting changes once the server's answer is received by
+    /// [`recv`](VstpTcpClient::recv).
+    pub async fn set_compression(&mut self, enabled: bool) -> Result<(), VstpError> {
+        match self.compression.propose(enabled) {
+            Some(proposal) => self.framed_write.send(proposal).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Ask the server to hold `size` header templates for this client's
+    /// frames, or 0 to stop; see [`header_table`](crate::header_table)
+    ///
+    /// Frames carry full headers until the server's answer is received by
+    /// [`recv`](VstpTcpClient::recv).
+    pub async fn set_header_table(&mut self, size: usize) -> Result<(), VstpError> {
+        let proposal = self.compression.header_table_mut().propose(size);
+        self.framed_write.send(proposal).await
+    }
+
+    /// Cap the bytes per second sent from now on, see [`shaping`](crate::shaping)
+    ///
+    /// Clients sharing an uplink can share one shaper.
+    pub fn set_send_shaper(&mut self, shaper: Option<Arc<SendShaper>>) {
+        self.framed_write.get_mut().set_shaper(shaper);
+    }
+
+    /// The shaper capping this client's send rate, if any
+    pub fn send_shaper(&self) -> Option<Arc<SendShaper>> {
+        self.framed_write.get_ref().shaper().cloned()
+    }
+
+    /// Show the bytes of every frame sent and received from now on to `tap`
+    pub fn set_wire_tap(&mut self, tap: WireTap) {
+        self.framed_write.encoder_mut().set_wire_tap(tap.clone());
+        self.framed_read.decoder_mut().set_wire_tap(tap);
+    }
+
+    /// Size the read buffer for the frames expected from the server, see
+    /// [`VstpFrameCodec::set_read_buffer_capacity`](crate::VstpFrameCodec::set_read_buffer_capacity)
+    pub fn set_read_buffer_capacity(&mut self, initial: usize, max: Option<usize>) {
+        self.framed_read
+            .decoder_mut()
+            .set_read_buffer_capacity(initial, max);
+    }
+
+    /// Send subsequent frames in frame format `version`
+    ///
+    /// Frames in either supported version are always accepted from the server.
+    pub fn set_frame_version(&mut self, version: u8) {
+        self.framed_write.encoder_mut().set_version(version);
+    }
+
+    /// Hold `bytes` as compression dictionary `id`, which the server may compress against
+    pub fn register_dictionary(&mut self, id: u32, bytes: Vec<u8>) {
+        self.compression.register_dictionary(id, bytes);
+    }
+
+    /// Compress outgoing payloads against dictionary `id`, which the server must hold
+    ///
+    /// See [`compression`](crate::compression) for how dictionaries are used.
+    pub fn use_dictionary(&mut self, id: Option<u32>) -> Result<(), VstpError> {
+        self.compression.use_dictionary(id)
+    }
+
+    /// Compression setting of this connection
+    pub fn compression(&self) -> &CompressionControl {
+        &self.compression
+    }
+
+    /// Compression setting of this connection, e.g. to refuse the server's proposals
+    pub fn compression_mut(&mut self) -> &mut CompressionControl {
+        &mut self.compression
     }
 
     /// Close the connection gracefully
@@ -0,0 +1,177 @@
+//! Pulling a TCP server's frames from one stream instead of a handler
+//!
+//! [`VstpTcpServer::incoming`] accepts connections in the background and
+//! funnels the frames of every session into one [`IncomingFrames`] stream,
+//! each tagged with a [`SessionHandle`] to answer on. It suits a central
+//! event loop or an actor that owns all state:
+//!
+//! ```no_run
+//! use tokio_stream::StreamExt;
+//! use vstp::tcp::VstpTcpServer;
+//!
+//! # async fn run() -> Result<(), vstp::VstpError> {
+//! let server = VstpTcpServer::bind("127.0.0.1:8080").await?;
+//! let mut frames = server.incoming();
+//! while let Some((session, frame)) = frames.next().await {
+//!     session.send(frame).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Ordering and backpressure
+//!
+//! Frames of one session come out in the order they arrived. Frames of
+//! different sessions are interleaved as they come in, with no fairness
+//! between sessions beyond that.
+//!
+//! The stream buffers a limited number of frames, see
+//! [`VstpTcpServer::incoming_with_capacity`]. Once it is full, each session
+//! holds on to the next frame it read and stops reading its socket until
+//! there is room, so slow consumers push back on clients through TCP flow
+//! control rather than queueing without bound. Replies go out while a
+//! session waits, so answering a session never blocks on the stream.
+//!
+//! Sessions are read with [`VstpTcpConnection::poll_recv`], so
+//! `probe_after` isn't applied to them. Dropping the stream stops accepting
+//! and ends every session.
+//!
+//! [`VstpTcpServer::incoming`]: crate::tcp::VstpTcpServer::incoming
+//! [`VstpTcpServer::incoming_with_capacity`]: crate::tcp::VstpTcpServer::incoming_with_capacity
+
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, Instrument};
+
+use crate::tcp::{VstpTcpConnection, VstpTcpServer};
+use crate::types::{Frame, SessionId, VstpError};
+
+/// Frames the stream of [`VstpTcpServer::incoming`] buffers
+pub const DEFAULT_INCOMING_CAPACITY: usize = 256;
+
+/// Replies a session queues before [`SessionHandle::send`] waits
+const REPLY_QUEUE: usize = 64;
+
+/// The session a frame from [`IncomingFrames`] came from, for answering it
+///
+/// Cheap to clone, so it can be kept to send to the session later.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    id: SessionId,
+    peer_addr: SocketAddr,
+    replies: mpsc::Sender<Frame>,
+}
+
+impl SessionHandle {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Queue `frame` for the client
+    ///
+    /// Fails with [`VstpError::ConnectionClosed`] once the session is over.
+    pub async fn send(&self, frame: Frame) -> Result<(), VstpError> {
+        self.replies
+            .send(frame)
+            .await
+            .map_err(|_| VstpError::ConnectionClosed)
+    }
+
+    /// Whether the session is over
+    pub fn is_closed(&self) -> bool {
+        self.replies.is_closed()
+    }
+}
+
+/// Frames of all sessions of a server, see [`incoming`](crate::tcp::incoming)
+pub struct IncomingFrames {
+    frames: mpsc::Receiver<(SessionHandle, Frame)>,
+    acceptor: JoinHandle<()>,
+}
+
+impl IncomingFrames {
+    pub(crate) fn start(server: VstpTcpServer, capacity: usize) -> Self {
+        let (tx, frames) = mpsc::channel(capacity.max(1));
+        let span = server.span().clone();
+        let acceptor = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = server.accept() => match accepted {
+                        Ok(conn) => {
+                            let session = serve_session(conn, tx.clone());
+                            tokio::spawn(session.instrument(span.clone()));
+                        }
+                        Err(e) => span.in_scope(|| {
+                            tracing::error!("Failed to accept connection: {}", e)
+                        }),
+                    },
+                }
+            }
+        });
+        Self { frames, acceptor }
+    }
+}
+
+impl Stream for IncomingFrames {
+    type Item = (SessionHandle, Frame);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.frames.poll_recv(cx)
+    }
+}
+
+impl Drop for IncomingFrames {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+/// Pass the frames of `conn` on to `tx` and send the replies queued for it
+async fn serve_session(mut conn: VstpTcpConnection, tx: mpsc::Sender<(SessionHandle, Frame)>) {
+    let (replies, mut queued) = mpsc::channel(REPLY_QUEUE);
+    let session = SessionHandle {
+        id: conn.session_id(),
+        peer_addr: conn.peer_addr(),
+        replies,
+    };
+    // A frame read but not yet taken by the stream
+    let mut pending = None;
+    loop {
+        tokio::select! {
+            _ = tx.closed() => break,
+            permit = tx.reserve(), if pending.is_some() => match permit {
+                Ok(permit) => permit.send((session.clone(), pending.take().unwrap())),
+                Err(_) => break,
+            },
+            received = poll_fn(|cx| conn.poll_recv(cx)), if pending.is_none() => match received {
+                Ok(Some(frame)) => pending = Some(frame),
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("Session {} failed: {}", session.id, e);
+                    break;
+                }
+            },
+            Some(reply) = queued.recv() => {
+                if let Err(e) = conn.send(reply).await {
+                    debug!("Session {} reply failed: {}", session.id, e);
+                    break;
+                }
+            }
+        }
+    }
+    match conn.disconnect_reason() {
+        Some(reason) => info!("Session {} ended: {}", session.id, reason),
+        None => info!("Session {} ended", session.id),
+    }
+}
@@ -1,9 +1,50 @@
 //! TCP transport implementation for VSTP
 //!
 //! This module provides async TCP client and server implementations using the VSTP frame codec.
+//!
+//! ## Ordering
+//!
+//! Frames written to one connection reach the other end in the order they
+//! were written, each whole. Servers keep that order up to the handler:
+//! [`VstpTcpServer::run`] and [`VstpServer::serve`](crate::easy::VstpServer::serve)
+//! hand a session's frames over one at a time, reading the next only once
+//! the handler for the previous one has finished, and
+//! [`VstpTcpServer::incoming`] yields them in arrival order. Frames of
+//! different sessions are handled concurrently, in no particular order.
+//!
+//! What "written in order" means on the sending side:
+//!
+//! - A [`VstpTcpClient`] writes frames in the order of its `send` calls.
+//! - A [`VstpClient`](crate::easy::VstpClient) does too, for sends awaited
+//!   one after the other. Clones share a connection and write each frame,
+//!   chunked or not, under one lock, so frames sent concurrently from
+//!   several clones never interleave mid-frame, but their order among each
+//!   other is whichever send takes the lock first. Each clone's own frames
+//!   stay in order.
+//! - The guarantee ends with the connection. When a client reconnects, the
+//!   frame being sent is sent again on the new connection, but frames that
+//!   were still in flight on the old one may be lost.
+//!
+//! UDP makes no ordering promise at all.
+//!
+//! ## TLS
+//!
+//! With the `tls` feature, servers and clients can encrypt the stream with
+//! TLS 1.3, see [`tls`]. Plain TCP stays the default.
 
+pub mod bytestream;
 pub mod client;
+pub mod incoming;
+pub mod quota;
+pub mod reconnect;
 pub mod server;
+pub(crate) mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
 
+pub use bytestream::{ByteStreamConfig, VstpByteStream};
 pub use client::VstpTcpClient;
-pub use server::VstpTcpServer;
+pub use incoming::{IncomingFrames, SessionHandle};
+pub use quota::{QuotaRemaining, SessionQuota};
+pub use reconnect::{ReconnectConfig, ReconnectingStream, StreamEvent};
+pub use server::{TcpServerConfig, VstpTcpConnection, VstpTcpServer};
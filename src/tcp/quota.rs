@@ -0,0 +1,150 @@
+//! Hard per-session limits for TCP connections
+//!
+//! A [`SessionQuota`] in [`TcpServerConfig`](crate::tcp::TcpServerConfig)
+//! caps what one connection may do over its whole life, however slowly it
+//! does it. Once a limit is used up the server sends an ERR `QuotaExceeded`
+//! naming it, then a BYE, and the session ends with
+//! [`DisconnectReason::QuotaExceeded`](crate::types::DisconnectReason::QuotaExceeded):
+//!
+//! - `max_frames`: the frame after the last allowed one closes the session
+//!   instead of being delivered; so does the frame that goes over `max_bytes_in`
+//! - `max_bytes_out`: once this many bytes were sent, further sends fail and
+//!   the session is closed on the next receive
+//! - `max_duration`: the session is closed when it runs out, like
+//!   `max_connection_age`
+//!
+//! Bytes are counted as encoded on the wire. The ERR and BYE closing a
+//! session don't count. What is left can be read with
+//! [`VstpTcpConnection::quota_remaining`](crate::tcp::VstpTcpConnection::quota_remaining),
+//! and by handlers of the easy server with
+//! [`current_quota_remaining`](crate::easy::current_quota_remaining), e.g. to
+//! warn clients in a [`QUOTA_REMAINING_HEADER`].
+//!
+//! The easy server applies the same limits to its UDP sessions with
+//! [`ServerOptions::udp_session_quota`](crate::easy::ServerOptions::udp_session_quota).
+//! Without a connection to watch, a UDP session is checked whenever its peer
+//! sends a frame: one arriving after a limit was used up, `max_duration`
+//! included, is answered with the ERR and BYE instead of being handled.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Header a server may set on replies to tell clients what is left of their quota
+pub const QUOTA_REMAINING_HEADER: &str = "quota-remaining";
+
+/// Limits on one session; `None` leaves that one unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionQuota {
+    /// Frames the client may send
+    pub max_frames: Option<u64>,
+    /// Bytes the client may send
+    pub max_bytes_in: Option<u64>,
+    /// Bytes the server may send
+    pub max_bytes_out: Option<u64>,
+    /// How long the session may last
+    pub max_duration: Option<Duration>,
+}
+
+/// What is left of a session's quota; `None` for unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaRemaining {
+    pub frames: Option<u64>,
+    pub bytes_in: Option<u64>,
+    pub bytes_out: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+impl std::fmt::Display for QuotaRemaining {
+    /// The limited ones, e.g. `frames=3 bytes-out=1024 ms=5000`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = [
+            self.frames.map(|n| format!("frames={}", n)),
+            self.bytes_in.map(|n| format!("bytes-in={}", n)),
+            self.bytes_out.map(|n| format!("bytes-out={}", n)),
+            self.duration.map(|d| format!("ms={}", d.as_millis())),
+        ];
+        let parts: Vec<String> = parts.into_iter().flatten().collect();
+        f.write_str(&parts.join(" "))
+    }
+}
+
+/// Usage of one session against its quota
+#[derive(Debug)]
+pub(crate) struct QuotaTracker {
+    quota: SessionQuota,
+    frames: u64,
+    bytes_in: u64,
+    started: Instant,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: SessionQuota) -> Self {
+        Self {
+            quota,
+            frames: 0,
+            bytes_in: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// When the session runs out of time
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.quota.max_duration.map(|d| self.started + d)
+    }
+
+    /// Count a frame received, `wire_len` bytes long
+    pub(crate) fn received(&mut self, wire_len: usize) {
+        self.frames += 1;
+        self.bytes_in += wire_len as u64;
+    }
+
+    /// The receive limit the session went over, if any
+    pub(crate) fn inbound_exceeded(&self) -> Option<&'static str> {
+        if self.quota.max_frames.is_some_and(|max| self.frames > max) {
+            Some("frame")
+        } else if self
+            .quota
+            .max_bytes_in
+            .is_some_and(|max| self.bytes_in > max)
+        {
+            Some("inbound byte")
+        } else {
+            None
+        }
+    }
+
+    /// Whether `bytes_out` sent use up the send limit
+    pub(crate) fn outbound_exceeded(&self, bytes_out: u64) -> bool {
+        self.quota.max_bytes_out.is_some_and(|max| bytes_out >= max)
+    }
+
+    /// The limit the session used up with `bytes_out` sent, time included
+    pub(crate) fn exceeded(&self, bytes_out: u64) -> Option<&'static str> {
+        if self.deadline().is_some_and(|deadline| deadline <= Instant::now()) {
+            return Some("time");
+        }
+        self.inbound_exceeded()
+            .or_else(|| self.outbound_exceeded(bytes_out).then_some("outbound byte"))
+    }
+
+    pub(crate) fn remaining(&self, bytes_out: u64) -> QuotaRemaining {
+        QuotaRemaining {
+            frames: self
+                .quota
+                .max_frames
+                .map(|max| max.saturating_sub(self.frames)),
+            bytes_in: self
+                .quota
+                .max_bytes_in
+                .map(|max| max.saturating_sub(self.bytes_in)),
+            bytes_out: self
+                .quota
+                .max_bytes_out
+                .map(|max| max.saturating_sub(bytes_out)),
+            duration: self
+                .deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+        }
+    }
+}
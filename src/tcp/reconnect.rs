@@ -0,0 +1,225 @@
+//! A frame stream that survives dropped connections
+//!
+//! [`VstpTcpClient::reconnecting_stream`] connects, sends a HELLO and yields
+//! every frame the server sends. When the connection drops it reconnects with
+//! exponential backoff, sends the HELLO again and yields
+//! [`StreamEvent::Reconnected`] before the frames of the new connection, so
+//! consumers can tell where a gap may be.
+//!
+//! A server that is shutting down can say so with an ERR frame with error
+//! code `ShuttingDown`. The stream then yields [`StreamEvent::Draining`],
+//! drops the connection and waits the ERR's `retry-after-ms` before it
+//! reconnects, instead of hammering the server while it goes away.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::{self, BoxStream, Stream};
+use tracing::{debug, warn};
+
+use crate::tcp::VstpTcpClient;
+use crate::types::{ErrorCode, Frame, FrameType, VstpError};
+
+/// How a [`ReconnectingStream`] (re)connects
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Delay after the first failed connection attempt
+    pub initial_backoff: Duration,
+    /// Longest delay between attempts; the delay doubles up to this
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts in a row; `None` retries forever
+    pub max_attempts: Option<usize>,
+    /// Frame sent first on every connection
+    pub hello: Frame,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
+            hello: Frame::new(FrameType::Hello),
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Send a HELLO carrying `payload` on every connection
+    pub fn with_hello_payload(mut self, payload: Vec<u8>) -> Self {
+        self.hello = Frame::new(FrameType::Hello).with_payload(payload);
+        self
+    }
+
+    /// Delay before the attempt following failed attempt number `attempt` (from 0)
+    fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u32 << attempt.min(20);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// What a [`ReconnectingStream`] yields
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// A frame from the server
+    Frame(Frame),
+    /// The connection dropped and a new one is up, after `attempts` attempts.
+    /// Frames sent by the server in between are lost.
+    Reconnected { attempts: usize },
+    /// The server is shutting down; the stream reconnects after `retry_after`,
+    /// or [`ReconnectConfig::initial_backoff`] if the server didn't say
+    Draining { retry_after: Duration },
+}
+
+/// Frames from a server, across reconnections
+///
+/// Ends with an error only when [`ReconnectConfig::max_attempts`] runs out;
+/// otherwise it never ends. Dropping it closes the connection.
+pub struct ReconnectingStream {
+    inner: BoxStream<'static, Result<StreamEvent, VstpError>>,
+}
+
+struct State {
+    addr: String,
+    config: ReconnectConfig,
+    client: Option<VstpTcpClient>,
+    connected_before: bool,
+    gave_up: bool,
+    /// Wait before connecting again, as asked by a draining server
+    pause: Option<Duration>,
+}
+
+impl VstpTcpClient {
+    /// Stream frames from `addr`, reconnecting with the default configuration
+    pub fn reconnecting_stream(addr: impl Into<String>) -> ReconnectingStream {
+        Self::reconnecting_stream_with_config(addr, ReconnectConfig::default())
+    }
+
+    /// Stream frames from `addr`, reconnecting as described by `config`
+    ///
+    /// Nothing happens until the stream is first polled.
+    pub fn reconnecting_stream_with_config(
+        addr: impl Into<String>,
+        config: ReconnectConfig,
+    ) -> ReconnectingStream {
+        let state = State {
+            addr: addr.into(),
+            config,
+            client: None,
+            connected_before: false,
+            gave_up: false,
+            pause: None,
+        };
+        ReconnectingStream {
+            inner: Box::pin(stream::unfold(state, |mut state| async move {
+                let event = state.next_event().await?;
+                Some((event, state))
+            })),
+        }
+    }
+}
+
+impl State {
+    async fn next_event(&mut self) -> Option<Result<StreamEvent, VstpError>> {
+        loop {
+            if self.gave_up {
+                return None;
+            }
+            let Some(client) = self.client.as_mut() else {
+                let attempts = match self.connect().await {
+                    Ok(attempts) => attempts,
+                    Err(e) => {
+                        self.gave_up = true;
+                        return Some(Err(e));
+                    }
+                };
+                if std::mem::replace(&mut self.connected_before, true) {
+                    return Some(Ok(StreamEvent::Reconnected { attempts }));
+                }
+                continue;
+            };
+            match client.recv().await {
+                Ok(Some(frame)) if frame.error_code() == Some(ErrorCode::ShuttingDown) => {
+                    let retry_after = frame.retry_after().unwrap_or(self.config.initial_backoff);
+                    debug!(
+                        "{} is shutting down, reconnecting in {:?}",
+                        self.addr, retry_after
+                    );
+                    self.client = None;
+                    self.pause = Some(retry_after);
+                    return Some(Ok(StreamEvent::Draining { retry_after }));
+                }
+                Ok(Some(frame)) => return Some(Ok(StreamEvent::Frame(frame))),
+                Ok(None) => debug!("Connection to {} closed", self.addr),
+                Err(e) => warn!("Connection to {} failed: {}", self.addr, e),
+            }
+            self.client = None;
+        }
+    }
+
+    /// Connect and send the HELLO, retrying with backoff; returns the attempts it took
+    async fn connect(&mut self) -> Result<usize, VstpError> {
+        if let Some(pause) = self.pause.take() {
+            tokio::time::sleep(pause).await;
+        }
+        let mut attempt = 0;
+        loop {
+            let result = async {
+                let mut client = VstpTcpClient::connect(&self.addr).await?;
+                client.send(self.config.hello.clone()).await?;
+                Ok::<_, VstpError>(client)
+            }
+            .await;
+            match result {
+                Ok(client) => {
+                    self.client = Some(client);
+                    return Ok(attempt + 1);
+                }
+                Err(e) => {
+                    if self
+                        .config
+                        .max_attempts
+                        .is_some_and(|max| attempt + 1 >= max)
+                    {
+                        return Err(e);
+                    }
+                    let delay = self.config.backoff(attempt);
+                    debug!(
+                        "Connecting to {} failed ({}), retrying in {:?}",
+                        self.addr, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Stream for ReconnectingStream {
+    type Item = Result<StreamEvent, VstpError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            ..ReconnectConfig::default()
+        };
+        let delays: Vec<_> = (0..6).map(|a| config.backoff(a).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(config.backoff(usize::MAX), Duration::from_secs(1));
+    }
+}
@@ -1,89 +1,1195 @@
-use futures::SinkExt;
-use std::future::Future;
+use futures::{Sink, SinkExt, Stream};
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::runtime::Handle;
 use tokio::sync::Mutex;
-use tokio_stream::StreamExt;
-use tokio_util::codec::Framed;
-use tracing::info;
+#[cfg(feature = "tls")]
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout_at, Instant, Sleep};
+use tokio_util::codec::{Encoder, Framed, FramedParts};
+use tracing::{debug, info, warn, Instrument, Span};
 
-use crate::types::{Frame, SessionId, VstpError};
-use crate::VstpFrameCodec as Codec;
+use crate::codec::DEFAULT_READ_BUFFER_CAPACITY;
+use crate::compression::{CompressionControl, Incoming};
+use crate::diagnostics::not_vstp;
+use crate::easy::TransportKind;
+use crate::frame::declared_frame_len;
+use crate::ingress::{FrameTypeFilter, IngressPolicy, IngressStats};
+use crate::meta::FrameMeta;
+use crate::shaping::{SendShaper, Shaped};
+use crate::socket::SocketOptions;
+use crate::tcp::incoming::{IncomingFrames, DEFAULT_INCOMING_CAPACITY};
+use crate::tcp::quota::{QuotaRemaining, QuotaTracker, SessionQuota};
+use crate::tcp::stream::Socket;
+use crate::types::{
+    DisconnectReason, ErrorCode, Frame, FrameType, HeaderRatioLimit, SessionId, VstpError,
+};
+use crate::{VstpFrameCodec as Codec, WireTap};
 
 /// TCP connection handler
+///
+/// Besides the async [`send`](VstpTcpConnection::send) and
+/// [`recv`](VstpTcpConnection::recv), a connection can be driven from an
+/// event loop that never awaits: poll it with [`poll_recv`], queue frames with
+/// [`poll_send_ready`] and [`start_send`], and call [`drive`] once per tick to
+/// flush them and pick up whatever arrived.
+///
+/// The poll methods still need a Tokio reactor. From a thread the runtime
+/// doesn't own, enter it first:
+///
+/// ```no_run
+/// # fn tick(conn: &mut vstp::tcp::VstpTcpConnection, handle: &tokio::runtime::Handle) {
+/// use std::time::Duration;
+///
+/// let _guard = handle.enter();
+/// for frame in conn.drive(Duration::from_millis(2)).unwrap() {
+///     println!("{:?}", frame.typ);
+/// }
+/// # }
+/// ```
+///
+/// Payload compression starts off and is switched with
+/// [`set_compression`](VstpTcpConnection::set_compression); see
+/// [`compression`](crate::compression) for the negotiation.
+///
+/// [`poll_recv`]: VstpTcpConnection::poll_recv
+/// [`poll_send_ready`]: VstpTcpConnection::poll_send_ready
+/// [`start_send`]: VstpTcpConnection::start_send
+/// [`drive`]: VstpTcpConnection::drive
 pub struct VstpTcpConnection {
-    framed: Framed<TcpStream, Codec>,
+    framed: Framed<Shaped<Socket>, Codec>,
     session_id: SessionId,
     peer_addr: std::net::SocketAddr,
+    /// Server name the client sent with SNI in its TLS handshake
+    tls_server_name: Option<String>,
+    probe: Option<(Duration, Duration)>,
+    disconnect_reason: Option<DisconnectReason>,
+    max_frame_size: usize,
+    compression: CompressionControl,
+    /// Answers to compression proposals and the like, waiting to be queued
+    control_replies: VecDeque<Frame>,
+    /// Whether a queued answer still has to be flushed
+    control_unflushed: bool,
+    ingress: Option<(Arc<IngressPolicy>, Arc<IngressStats>)>,
+    frame_types: Arc<FrameTypeFilter>,
+    /// Fires once the session has lived for the server's `max_connection_age`
+    /// or the `max_duration` of its quota, whichever is shorter
+    max_age: Option<Pin<Box<Sleep>>>,
+    /// Why the session ends once `max_age` fires
+    expiry: DisconnectReason,
+    /// Why the session ends, while the frames saying so are on their way
+    retiring: Option<DisconnectReason>,
+    quota: Option<QuotaTracker>,
+    /// Sessions the server closed for their quota
+    quota_closed: Arc<AtomicU64>,
+    /// `partial_frame_timeout` and `partial_frame_min_bps` of the server
+    partial_frame_limit: Option<(Duration, u64)>,
+    /// Fires when the frame partly in the read buffer is due
+    partial_frame: Option<Pin<Box<Sleep>>>,
+    /// Whether `partial_frame` includes the time for the frame's declared length
+    partial_frame_sized: bool,
+    /// Whether the frame in the read buffer took too long
+    stalled: bool,
+    /// Sessions the server closed for stalling mid-frame
+    stalled_closed: Arc<AtomicU64>,
+    /// Receive metadata of the last frame read from the socket
+    last_meta: Option<FrameMeta>,
+    /// Bytes still to be decoded that arrived as TLS early data
+    #[cfg(feature = "tls")]
+    early_data_left: usize,
+    /// The server's span, which the connection logs under
+    span: Span,
 }
 
 impl VstpTcpConnection {
     /// Send a frame to the client
+    ///
+    /// Fails once the session used up the `max_bytes_out` of its quota.
     pub async fn send(&mut self, frame: Frame) -> Result<(), VstpError> {
+        self.check_send_quota()?;
+        let frame = self.compression.outgoing(frame)?;
         self.framed.send(frame).await?;
         Ok(())
     }
 
+    /// Send several frames to the client in one write
+    ///
+    /// Each frame is encoded as [`send`](VstpTcpConnection::send) would, so
+    /// the client decodes them one by one, but they reach the socket
+    /// together instead of in a write each. A send rate cap may still split
+    /// them. Fails before writing anything once the quota is used up.
+    pub async fn send_batch(&mut self, frames: Vec<Frame>) -> Result<(), VstpError> {
+        let mut batch = bytes::BytesMut::new();
+        for frame in frames {
+            self.check_send_quota()?;
+            let frame = self.compression.outgoing(frame)?;
+            self.framed.codec_mut().encode(frame, &mut batch)?;
+        }
+        self.framed.write_buffer_mut().extend_from_slice(&batch);
+        self.framed.flush().await
+    }
+
+    /// Ask the client to switch payload compression on or off
+    ///
+    /// The setting changes once the client's answer is received.
+    pub async fn set_compression(&mut self, enabled: bool) -> Result<(), VstpError> {
+        match self.compression.propose(enabled) {
+            Some(proposal) => self.framed.send(proposal).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Ask the client to hold `size` header templates for this connection's
+    /// frames, or 0 to stop; see [`header_table`](crate::header_table)
+    ///
+    /// Frames carry full headers until the client's answer is received.
+    pub async fn set_header_table(&mut self, size: usize) -> Result<(), VstpError> {
+        let proposal = self.compression.header_table_mut().propose(size);
+        self.framed.send(proposal).await
+    }
+
+    /// Send subsequent frames in frame format `version`
+    ///
+    /// Frames in either supported version are always accepted from the client.
+    pub fn set_frame_version(&mut self, version: u8) {
+        self.framed.codec_mut().set_version(version);
+    }
+
+    /// Hold `bytes` as compression dictionary `id`, which the client may compress against
+    pub fn register_dictionary(&mut self, id: u32, bytes: Vec<u8>) {
+        self.compression.register_dictionary(id, bytes);
+    }
+
+    /// Compress outgoing payloads against dictionary `id`, which the client must hold
+    ///
+    /// See [`compression`](crate::compression) for how dictionaries are used.
+    pub fn use_dictionary(&mut self, id: Option<u32>) -> Result<(), VstpError> {
+        self.compression.use_dictionary(id)
+    }
+
+    /// Compression setting of this connection
+    pub fn compression(&self) -> &CompressionControl {
+        &self.compression
+    }
+
+    /// Compression setting of this connection, e.g. to refuse the client's proposals
+    pub fn compression_mut(&mut self) -> &mut CompressionControl {
+        &mut self.compression
+    }
+
     /// Receive a frame from the client
+    ///
+    /// Returns `Ok(None)` once the session is over; [`disconnect_reason`]
+    /// then tells whether the peer closed it, stopped answering probes or
+    /// the session reached its maximum age or used up its quota.
+    ///
+    /// [`disconnect_reason`]: VstpTcpConnection::disconnect_reason
     pub async fn recv(&mut self) -> Result<Option<Frame>, VstpError> {
-        let frame = self.framed.next().await.transpose()?;
-        Ok(frame)
+        let span = self.span.clone();
+        self.recv_probing().instrument(span).await
+    }
+
+    async fn recv_probing(&mut self) -> Result<Option<Frame>, VstpError> {
+        let Some((probe_after, probe_timeout)) = self.probe else {
+            return poll_fn(|cx| self.poll_recv(cx)).await;
+        };
+
+        let mut probing = false;
+        loop {
+            if let Some(reason) = self.over_limit() {
+                return self.retire(reason).await;
+            }
+            let wait = if probing { probe_timeout } else { probe_after };
+            let mut until = Instant::now() + wait;
+            if let Some(age) = &self.max_age {
+                until = until.min(age.deadline());
+            }
+            match timeout_at(until, poll_fn(|cx| self.poll_frame(cx))).await {
+                Ok(Some(Ok(frame))) => {
+                    let frame = self.stamp(frame);
+                    if let Some(reason) = self.over_limit() {
+                        return self.retire(reason).await;
+                    }
+                    if !self.frame_types.allows(frame.typ, self.peer_addr) {
+                        if let Err(e) = self.framed.send(type_refusal(frame.typ)).await {
+                            return self.fail(e);
+                        }
+                        continue;
+                    }
+                    let frame = match self.compression.incoming(frame, self.max_frame_size) {
+                        Ok(Incoming::Frame(frame)) => frame,
+                        Ok(Incoming::Reply(reply)) => {
+                            if let Err(e) = self.framed.send(reply).await {
+                                return self.fail(e);
+                            }
+                            continue;
+                        }
+                        Ok(Incoming::Settled) => continue,
+                        Err(e) => return self.fail(e),
+                    };
+                    // Any traffic proves the peer is alive; swallow the probe's PONG
+                    if probing && frame.typ == FrameType::Pong {
+                        probing = false;
+                        continue;
+                    }
+                    match self.screen(frame) {
+                        Ok(frame) => return Ok(Some(frame)),
+                        Err(reply) => {
+                            if let Err(e) = self.framed.send(reply).await {
+                                return self.fail(e);
+                            }
+                        }
+                    }
+                }
+                Ok(Some(Err(e))) => return self.fail(e),
+                Ok(None) if self.stalled => return self.retire(DisconnectReason::Stalled).await,
+                Ok(None) => return self.end(DisconnectReason::Closed),
+                Err(_) if self.aged_out() => {
+                    return self.retire(self.expiry.clone()).await;
+                }
+                Err(_) if probing => {
+                    warn!(
+                        "Session {} did not answer PING within {:?}",
+                        self.session_id, probe_timeout
+                    );
+                    return self.end(DisconnectReason::Unreachable);
+                }
+                Err(_) => {
+                    debug!("Session {} idle, sending PING", self.session_id);
+                    if let Err(e) = self.framed.send(Frame::new(FrameType::Ping)).await {
+                        warn!("Session {} probe failed: {}", self.session_id, e);
+                        return self.end(DisconnectReason::Unreachable);
+                    }
+                    probing = true;
+                }
+            }
+        }
+    }
+
+    /// Poll for the next frame from the client
+    ///
+    /// Like [`recv`](VstpTcpConnection::recv), but without idle probing.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Frame>, VstpError>> {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        loop {
+            // Keep answering compression proposals while waiting for data
+            let flushed = match self.poll_control_reply(cx) {
+                Poll::Ready(Ok(())) => true,
+                Poll::Ready(Err(e)) => return Poll::Ready(self.fail(e)),
+                Poll::Pending => false,
+            };
+            if let Some(reason) = self.retiring.take() {
+                if !flushed {
+                    self.retiring = Some(reason);
+                    return Poll::Pending;
+                }
+                return Poll::Ready(self.end(reason));
+            }
+            if let Some(age) = &mut self.max_age {
+                if age.as_mut().poll(cx).is_ready() {
+                    self.start_retiring(self.expiry.clone());
+                    continue;
+                }
+            }
+            if let Some(reason) = self.over_limit() {
+                self.start_retiring(reason);
+                continue;
+            }
+            let frame = match ready!(self.poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    let frame = self.stamp(frame);
+                    if self.over_limit().is_some() {
+                        continue;
+                    }
+                    frame
+                }
+                Some(Err(e)) => return Poll::Ready(self.fail(e)),
+                None if self.stalled => {
+                    self.start_retiring(DisconnectReason::Stalled);
+                    continue;
+                }
+                None => return Poll::Ready(self.end(DisconnectReason::Closed)),
+            };
+            if !self.frame_types.allows(frame.typ, self.peer_addr) {
+                self.control_replies.push_back(type_refusal(frame.typ));
+                continue;
+            }
+            match self.compression.incoming(frame, self.max_frame_size) {
+                Ok(Incoming::Frame(frame)) => match self.screen(frame) {
+                    Ok(frame) => return Poll::Ready(Ok(Some(frame))),
+                    Err(reply) => self.control_replies.push_back(reply),
+                },
+                Ok(Incoming::Reply(reply)) => self.control_replies.push_back(reply),
+                Ok(Incoming::Settled) => {}
+                Err(e) => return Poll::Ready(self.fail(e)),
+            }
+        }
+    }
+
+    /// Poll the socket for the next frame, like `framed.poll_next`
+    ///
+    /// Once the read buffer holds any part of a frame, the rest must arrive
+    /// in time or this ends with `None` and sets `stalled`. The deadline
+    /// grows by the time the frame takes at the minimum rate as soon as its
+    /// length fields are in.
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Frame, VstpError>>> {
+        if self.stalled {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(next) = Pin::new(&mut self.framed).poll_next(cx) {
+            self.partial_frame = None;
+            self.partial_frame_sized = false;
+            return Poll::Ready(next);
+        }
+        let Some((timeout, min_bps)) = self.partial_frame_limit else {
+            return Poll::Pending;
+        };
+        if self.framed.read_buffer().is_empty() {
+            return Poll::Pending;
+        }
+        if self.partial_frame.is_none() {
+            // A deadline past what `Instant` can hold is no deadline
+            let Some(deadline) = Instant::now().checked_add(timeout) else {
+                return Poll::Pending;
+            };
+            self.partial_frame = Some(Box::pin(sleep_until(deadline)));
+        }
+        if !self.partial_frame_sized {
+            if let Some(len) = declared_frame_len(self.framed.read_buffer()) {
+                self.partial_frame_sized = true;
+                if min_bps > 0 {
+                    let transfer = Duration::from_secs_f64(len as f64 / min_bps as f64);
+                    let deadline = self.partial_frame.as_mut().expect("armed above");
+                    match deadline.deadline().checked_add(transfer) {
+                        Some(later) => deadline.as_mut().reset(later),
+                        None => {
+                            self.partial_frame = None;
+                            self.partial_frame_limit = None;
+                            return Poll::Pending;
+                        }
+                    }
+                }
+            }
+        }
+        let due = self
+            .partial_frame
+            .as_mut()
+            .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+        if !due {
+            return Poll::Pending;
+        }
+        warn!("Session {} did not finish a frame in time", self.session_id);
+        self.partial_frame = None;
+        self.stalled = true;
+        Poll::Ready(None)
+    }
+
+    /// Record the receive metadata of the `frame` just decoded, marking it
+    /// if it arrived as TLS early data
+    fn stamp(&mut self, frame: Frame) -> Frame {
+        let wire_len = self.framed.codec().last_frame_len();
+        if let Some(quota) = &mut self.quota {
+            quota.received(wire_len);
+        }
+        self.last_meta = Some(FrameMeta {
+            raw_bytes: self.framed.codec_mut().take_last_frame_bytes(),
+            ..FrameMeta::now(TransportKind::Tcp, wire_len)
+        });
+        #[cfg(feature = "tls")]
+        if self.early_data_left > 0 {
+            self.early_data_left = self.early_data_left.saturating_sub(wire_len);
+            return frame.with_header(crate::tcp::tls::EARLY_DATA_HEADER, "1");
+        }
+        frame
+    }
+
+    /// [`recv`](VstpTcpConnection::recv) a frame along with its receive metadata
+    pub async fn recv_with_meta(&mut self) -> Result<Option<(Frame, FrameMeta)>, VstpError> {
+        let Some(frame) = self.recv().await? else {
+            return Ok(None);
+        };
+        let meta = self
+            .last_meta
+            .clone()
+            .unwrap_or_else(|| FrameMeta::now(TransportKind::Tcp, 0));
+        Ok(Some((frame, meta)))
+    }
+
+    /// Receive metadata of the frame [`recv`](VstpTcpConnection::recv) or
+    /// [`poll_recv`](VstpTcpConnection::poll_recv) returned last
+    pub fn frame_meta(&self) -> Option<FrameMeta> {
+        self.last_meta.clone()
+    }
+
+    /// Keep the bytes of frames received from now on as [`FrameMeta::raw_bytes`]
+    ///
+    /// See [`TcpServerConfig::keep_raw_bytes`].
+    pub fn set_keep_raw_bytes(&mut self, keep: bool) {
+        self.framed.codec_mut().set_keep_raw_bytes(keep);
+    }
+
+    /// Apply the server's [`IngressPolicy`], returning the ERR for a rejected frame
+    fn screen(&self, mut frame: Frame) -> Result<Frame, Frame> {
+        let Some((policy, stats)) = &self.ingress else {
+            return Ok(frame);
+        };
+        match policy.apply(&mut frame, stats) {
+            Ok(()) => Ok(frame),
+            Err(reason) => {
+                debug!("Session {} rejected a frame: {}", self.session_id, reason);
+                Err(Frame::coded_error(ErrorCode::PolicyViolation, &reason))
+            }
+        }
+    }
+
+    /// Queue and flush the answers to compression proposals and the like, if there are any
+    fn poll_control_reply(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), VstpError>> {
+        while !self.control_replies.is_empty() {
+            ready!(Pin::new(&mut self.framed).poll_ready(cx))?;
+            if let Some(reply) = self.control_replies.pop_front() {
+                Pin::new(&mut self.framed).start_send(reply)?;
+                self.control_unflushed = true;
+            }
+        }
+        if self.control_unflushed {
+            ready!(Pin::new(&mut self.framed).poll_flush(cx))?;
+            self.control_unflushed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Poll until the connection can accept a frame through [`start_send`](VstpTcpConnection::start_send)
+    pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), VstpError>> {
+        Pin::new(&mut self.framed).poll_ready(cx)
+    }
+
+    /// Queue a frame for sending; call only after [`poll_send_ready`](VstpTcpConnection::poll_send_ready) returned ready
+    ///
+    /// The frame goes out on the next [`poll_flush`](VstpTcpConnection::poll_flush)
+    /// or [`drive`](VstpTcpConnection::drive).
+    pub fn start_send(&mut self, frame: Frame) -> Result<(), VstpError> {
+        self.check_send_quota()?;
+        let frame = self.compression.outgoing(frame)?;
+        Pin::new(&mut self.framed).start_send(frame)
+    }
+
+    /// Poll until every queued frame has been written to the socket
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), VstpError>> {
+        Pin::new(&mut self.framed).poll_flush(cx)
+    }
+
+    /// Flush queued frames and collect incoming ones for at most `budget`
+    ///
+    /// Blocks the calling thread, so it must not be called from async code.
+    /// It uses the runtime of the current context, which a thread outside
+    /// the runtime gets from [`Handle::enter`]. Returns early once the
+    /// session ends, after which [`disconnect_reason`](VstpTcpConnection::disconnect_reason) is set.
+    pub fn drive(&mut self, budget: Duration) -> Result<Vec<Frame>, VstpError> {
+        let deadline = Instant::now() + budget;
+        Handle::current().block_on(async {
+            let mut frames = Vec::new();
+            match timeout_at(deadline, self.framed.flush()).await {
+                Ok(result) => result?,
+                Err(_) => return Ok(frames),
+            }
+            while let Ok(next) = timeout_at(deadline, poll_fn(|cx| self.poll_recv(cx))).await {
+                match next? {
+                    Some(frame) => frames.push(frame),
+                    None => break,
+                }
+            }
+            Ok(frames)
+        })
+    }
+
+    /// Writes made to the socket so far, e.g. to see what batching saves
+    pub fn socket_writes(&self) -> u64 {
+        self.framed.get_ref().writes()
+    }
+
+    /// Id the server gave this session
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
     }
 
     /// Get the peer address
     pub fn peer_addr(&self) -> std::net::SocketAddr {
         self.peer_addr
     }
+
+    /// The server name the client asked for with SNI, if it connected over
+    /// TLS and sent one
+    pub fn tls_server_name(&self) -> Option<&str> {
+        self.tls_server_name.as_deref()
+    }
+
+    /// Why the session ended, once [`recv`](VstpTcpConnection::recv) has returned `Ok(None)` or an error
+    pub fn disconnect_reason(&self) -> Option<&DisconnectReason> {
+        self.disconnect_reason.as_ref()
+    }
+
+    /// What is left of the session's [`SessionQuota`]; `None` without one
+    pub fn quota_remaining(&self) -> Option<QuotaRemaining> {
+        let bytes_out = self.framed.codec().bytes_encoded();
+        self.quota.as_ref().map(|quota| quota.remaining(bytes_out))
+    }
+
+    fn check_send_quota(&self) -> Result<(), VstpError> {
+        let bytes_out = self.framed.codec().bytes_encoded();
+        match &self.quota {
+            Some(quota) if quota.outbound_exceeded(bytes_out) => Err(VstpError::Protocol(
+                "session outbound byte quota used up".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn aged_out(&self) -> bool {
+        self.max_age
+            .as_ref()
+            .is_some_and(|age| age.deadline() <= Instant::now())
+    }
+
+    /// Why the session must end now, if it must
+    fn over_limit(&self) -> Option<DisconnectReason> {
+        if self.aged_out() {
+            return Some(self.expiry.clone());
+        }
+        let quota = self.quota.as_ref()?;
+        let limit = quota.inbound_exceeded().or_else(|| {
+            quota
+                .outbound_exceeded(self.framed.codec().bytes_encoded())
+                .then_some("outbound byte")
+        })?;
+        Some(DisconnectReason::QuotaExceeded(limit.to_string()))
+    }
+
+    /// Queue the frames ending the session for `reason`, for [`poll_recv`](VstpTcpConnection::poll_recv)
+    fn start_retiring(&mut self, reason: DisconnectReason) {
+        debug!("Session {} ending: {}", self.session_id, reason);
+        self.max_age = None;
+        self.control_replies.extend(farewell(&reason));
+        self.retiring = Some(reason);
+    }
+
+    /// Send the frames ending the session for `reason` and end it
+    async fn retire(&mut self, reason: DisconnectReason) -> Result<Option<Frame>, VstpError> {
+        debug!("Session {} ending: {}", self.session_id, reason);
+        self.max_age = None;
+        for frame in farewell(&reason) {
+            if let Err(e) = self.framed.send(frame).await {
+                debug!("Session {} BYE failed: {}", self.session_id, e);
+                break;
+            }
+        }
+        self.end(reason)
+    }
+
+    fn end(&mut self, reason: DisconnectReason) -> Result<Option<Frame>, VstpError> {
+        match reason {
+            DisconnectReason::QuotaExceeded(_) => {
+                self.quota_closed.fetch_add(1, Ordering::Relaxed);
+            }
+            DisconnectReason::Stalled => {
+                self.stalled_closed.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        self.disconnect_reason = Some(reason);
+        Ok(None)
+    }
+
+    fn fail(&mut self, e: VstpError) -> Result<Option<Frame>, VstpError> {
+        // Garbage before the first frame is most likely another protocol
+        let e = match e {
+            VstpError::InvalidMagic(_) if self.last_meta.is_none() => {
+                not_vstp(self.framed.read_buffer())
+            }
+            e => e,
+        };
+        self.disconnect_reason = Some(DisconnectReason::Error(e.to_string()));
+        Err(e)
+    }
+}
+
+/// Frames telling the client why its session ends: a BYE, after an ERR
+/// `QuotaExceeded` if it used up its quota or `DeadlineExceeded` if it
+/// stalled mid-frame
+pub(crate) fn farewell(reason: &DisconnectReason) -> Vec<Frame> {
+    let bye = Frame::new(FrameType::Bye);
+    match reason {
+        DisconnectReason::QuotaExceeded(limit) => vec![
+            Frame::coded_error(
+                ErrorCode::QuotaExceeded,
+                &format!("session {} quota used up", limit),
+            ),
+            bye,
+        ],
+        DisconnectReason::Stalled => vec![
+            Frame::coded_error(ErrorCode::DeadlineExceeded, "frame not completed in time"),
+            bye,
+        ],
+        _ => vec![bye],
+    }
+}
+
+/// ERR answering a frame of a type the server doesn't accept
+fn type_refusal(typ: FrameType) -> Frame {
+    Frame::coded_error(
+        ErrorCode::BadRequest,
+        &format!("{:?} frames are not accepted", typ),
+    )
+}
+
+/// Configuration for TCP server
+#[derive(Debug, Clone)]
+pub struct TcpServerConfig {
+    /// Socket options applied when binding and to accepted connections
+    pub socket: SocketOptions,
+    /// Send a PING after a session has been idle this long; `None` disables probing.
+    ///
+    /// Clients must answer with a PONG (or any other frame) to stay connected.
+    pub probe_after: Option<Duration>,
+    /// How long to wait for an answer to a probe before dropping the session
+    pub probe_timeout: Duration,
+    /// Largest frame accepted from clients, in bytes
+    pub max_frame_size: usize,
+    /// Bytes each connection's read buffer starts with; set it just above
+    /// the usual encoded frame size so large frames don't make it grow.
+    ///
+    /// See [`VstpFrameCodec::set_read_buffer_capacity`](crate::VstpFrameCodec::set_read_buffer_capacity).
+    pub read_buffer_capacity: usize,
+    /// Give a read buffer that grew past this many bytes back down to
+    /// `read_buffer_capacity` once it drains; `None` (the default) keeps it
+    pub max_read_buffer_capacity: Option<usize>,
+    /// Close sessions that send a frame whose headers dwarf its payload;
+    /// `None` (the default) turns the check off.
+    ///
+    /// Checked as frames are decoded, see [`HeaderRatioLimit`].
+    pub max_header_to_payload_ratio: Option<HeaderRatioLimit>,
+    /// Callbacks given the bytes of every frame sent and received
+    pub wire_tap: WireTap,
+    /// Rules applied to every frame received; see [`ingress`](crate::ingress)
+    pub ingress: Option<IngressPolicy>,
+    /// Close sessions with a BYE once they are this old, however busy they are.
+    ///
+    /// Makes long-lived clients reconnect, so load balancers get to spread
+    /// them again. `None` lets sessions live forever.
+    pub max_connection_age: Option<Duration>,
+    /// Frame types accepted from clients; `None` accepts all.
+    ///
+    /// Checked before anything else looks at a frame; others are answered
+    /// with ERR `BadRequest`, see [`ingress`](crate::ingress).
+    pub allowed_frame_types: Option<HashSet<FrameType>>,
+    /// Cap on the bytes per second sent to all clients together; `None` for no cap.
+    ///
+    /// See [`shaping`](crate::shaping).
+    pub max_send_bps: Option<u64>,
+    /// Hard limits on each session, after which it is closed with an ERR
+    /// `QuotaExceeded` and a BYE; `None` for no limits.
+    ///
+    /// See [`quota`](crate::tcp::quota).
+    pub session_quota: Option<SessionQuota>,
+    /// Close sessions that start sending a frame and don't finish it within
+    /// this long, plus the time the frame takes at `partial_frame_min_bps`;
+    /// `None` (the default) waits forever.
+    ///
+    /// The clock starts with the frame's first byte, and the allowance for
+    /// its size is added once its length fields have arrived. A
+    /// peer stalling mid-frame would otherwise pin its read buffer, and the
+    /// stream can't be resynchronized after a lost frame, so the session is
+    /// closed with an ERR `DeadlineExceeded` and a BYE, and ends with
+    /// [`DisconnectReason::Stalled`].
+    pub partial_frame_timeout: Option<Duration>,
+    /// Slowest upload, in bytes per second, that `partial_frame_timeout`
+    /// makes room for; 0 gives every frame just the timeout.
+    ///
+    /// A 1 MiB frame at the default 64 KiB/s gets 16 seconds on top.
+    pub partial_frame_min_bps: u64,
+    /// Keep each frame's bytes as [`FrameMeta::raw_bytes`], e.g. to forward
+    /// frames verbatim; off by default, as it copies every frame.
+    ///
+    /// Connections can switch it with [`VstpTcpConnection::set_keep_raw_bytes`].
+    pub keep_raw_bytes: bool,
+    /// Span the server and its connections log under, e.g. to tell apart
+    /// the logs of several servers in one process; none by default.
+    ///
+    /// Covers binding, accepting, [`run`](VstpTcpServer::run) and the
+    /// connections' receive paths. Handlers run under it too.
+    pub span: Span,
+    /// Run a TLS handshake on every accepted connection before reading
+    /// frames from it; `None` (the default) serves plain TCP.
+    ///
+    /// Binding fails with [`VstpError::Tls`] for a config that accepts
+    /// versions below TLS 1.3 or sets ALPN without offering `vstp`.
+    ///
+    /// See [`tls`](crate::tcp::tls).
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// How long a client gets to complete the TLS handshake
+    #[cfg(feature = "tls")]
+    pub tls_handshake_timeout: Duration,
+    /// Most TLS handshakes run at once; further connections wait in the
+    /// listen backlog until one finishes.
+    ///
+    /// Handshakes run in their own tasks, so a slow client only holds up
+    /// others once this many are pending.
+    #[cfg(feature = "tls")]
+    pub max_pending_tls_handshakes: usize,
+    /// Take frames a resuming client sends as TLS early data, up to
+    /// `max_frame_size` bytes, before its handshake completes
+    ///
+    /// Early data can be replayed, so such frames carry an
+    /// [`EARLY_DATA_HEADER`](crate::tcp::tls::EARLY_DATA_HEADER); see
+    /// [early data](crate::tcp::tls#early-data). Off by default.
+    #[cfg(feature = "tls")]
+    pub accept_early_data: bool,
+}
+
+impl TcpServerConfig {
+    /// `tls` as handshakes use it, taking early data if asked to
+    #[cfg(feature = "tls")]
+    fn handshake_tls(
+        &self,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    ) -> Option<Arc<rustls::ServerConfig>> {
+        if !self.accept_early_data {
+            return tls;
+        }
+        let max = u32::try_from(self.max_frame_size).unwrap_or(u32::MAX);
+        tls.map(|tls| crate::tcp::tls::with_early_data(tls, max))
+    }
 }
 
+impl Default for TcpServerConfig {
+    fn default() -> Self {
+        Self {
+            // Matches `TcpListener::bind`, which sets SO_REUSEADDR on Unix
+            socket: SocketOptions {
+                reuse_addr: cfg!(unix),
+                keepalive: Some(Duration::from_secs(60)),
+                ..SocketOptions::default()
+            },
+            probe_after: None,
+            probe_timeout: Duration::from_secs(10),
+            max_frame_size: 8 * 1024 * 1024,
+            read_buffer_capacity: DEFAULT_READ_BUFFER_CAPACITY,
+            max_read_buffer_capacity: None,
+            max_header_to_payload_ratio: None,
+            wire_tap: WireTap::default(),
+            ingress: None,
+            max_connection_age: None,
+            allowed_frame_types: None,
+            max_send_bps: None,
+            session_quota: None,
+            partial_frame_timeout: None,
+            partial_frame_min_bps: 64 * 1024,
+            keep_raw_bytes: false,
+            span: Span::none(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_handshake_timeout: Duration::from_secs(10),
+            #[cfg(feature = "tls")]
+            max_pending_tls_handshakes: 128,
+            #[cfg(feature = "tls")]
+            accept_early_data: false,
+        }
+    }
+}
+
+/// Outcome of a TLS handshake: the socket with the early data it carried
+#[cfg(feature = "tls")]
+type Handshake = (Result<(Socket, Vec<u8>), VstpError>, SocketAddr);
+
 /// TCP server for VSTP protocol
 pub struct VstpTcpServer {
     listener: TcpListener,
+    config: TcpServerConfig,
     next_session_id: Arc<Mutex<u128>>,
+    ingress: Option<Arc<IngressPolicy>>,
+    ingress_stats: Arc<IngressStats>,
+    frame_types: Arc<FrameTypeFilter>,
+    send_shaper: Option<Arc<SendShaper>>,
+    quota_closed: Arc<AtomicU64>,
+    stalled_closed: Arc<AtomicU64>,
+    /// The TLS config new handshakes use, swapped by [`set_tls`](VstpTcpServer::set_tls)
+    #[cfg(feature = "tls")]
+    tls: std::sync::RwLock<Option<Arc<rustls::ServerConfig>>>,
+    #[cfg(feature = "tls")]
+    tls_failures: AtomicU64,
+    #[cfg(feature = "tls")]
+    handshakes: Mutex<JoinSet<Handshake>>,
 }
 
 impl VstpTcpServer {
+    fn from_parts(listener: TcpListener, config: TcpServerConfig) -> Self {
+        Self {
+            listener,
+            ingress: config.ingress.clone().map(Arc::new),
+            frame_types: Arc::new(FrameTypeFilter::new(config.allowed_frame_types.clone())),
+            send_shaper: config
+                .max_send_bps
+                .map(|bps| Arc::new(SendShaper::new(bps))),
+            #[cfg(feature = "tls")]
+            tls: std::sync::RwLock::new(config.handshake_tls(config.tls.clone())),
+            config,
+            next_session_id: Arc::new(Mutex::new(1)),
+            ingress_stats: Arc::new(IngressStats::default()),
+            quota_closed: Arc::new(AtomicU64::new(0)),
+            stalled_closed: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "tls")]
+            tls_failures: AtomicU64::new(0),
+            #[cfg(feature = "tls")]
+            handshakes: Mutex::new(JoinSet::new()),
+        }
+    }
+
     /// Bind to the specified address
     pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, VstpError> {
         let listener = TcpListener::bind(addr).await?;
         info!("VSTP TCP server bound to {}", listener.local_addr()?);
 
-        Ok(Self {
-            listener,
-            next_session_id: Arc::new(Mutex::new(1)),
-        })
+        Ok(Self::from_parts(listener, TcpServerConfig::default()))
+    }
+
+    /// Bind to the specified address with custom configuration
+    pub async fn bind_with_config(
+        addr: impl ToSocketAddrs,
+        config: TcpServerConfig,
+    ) -> Result<Self, VstpError> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &config.tls {
+            crate::tcp::tls::check_server_config(tls)?;
+        }
+        let listener = config.socket.bind_tcp(addr).await?;
+        let local_addr = listener.local_addr()?;
+        config
+            .span
+            .in_scope(|| info!("VSTP TCP server bound to {} with custom config", local_addr));
+
+        Ok(Self::from_parts(listener, config))
+    }
+
+    /// Bind to the specified address and serve TLS with `config`, see
+    /// [`tls`](crate::tcp::tls)
+    ///
+    /// Fails with [`VstpError::Tls`] if `config` accepts versions below TLS
+    /// 1.3 or sets ALPN without offering `vstp`, see
+    /// [`check_server_config`](crate::tcp::tls::check_server_config).
+    #[cfg(feature = "tls")]
+    pub async fn bind_tls(
+        addr: impl ToSocketAddrs,
+        config: impl Into<Arc<rustls::ServerConfig>>,
+    ) -> Result<Self, VstpError> {
+        let config = TcpServerConfig {
+            tls: Some(config.into()),
+            ..TcpServerConfig::default()
+        };
+        Self::bind_with_config(addr, config).await
+    }
+
+    /// Serve TLS with `config` to connections accepted from now on, or
+    /// plain TCP with `None`
+    ///
+    /// Can be called while the server is accepting, e.g. to roll over to a
+    /// renewed certificate; see [`watch_pem_files`](crate::tcp::tls::watch_pem_files).
+    /// Handshakes already under way and established sessions keep the
+    /// config they started with. Refuses configs as
+    /// [`bind_tls`](VstpTcpServer::bind_tls) does, keeping the current one.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&self, config: Option<Arc<rustls::ServerConfig>>) -> Result<(), VstpError> {
+        if let Some(tls) = &config {
+            crate::tcp::tls::check_server_config(tls)?;
+        }
+        *self.tls.write().unwrap() = self.config.handshake_tls(config);
+        Ok(())
+    }
+
+    /// The TLS config new connections are served with, if any
+    #[cfg(feature = "tls")]
+    pub fn tls_config(&self) -> Option<Arc<rustls::ServerConfig>> {
+        self.tls.read().unwrap().clone()
+    }
+
+    /// Take over an already bound listener, e.g. one inherited from a supervisor
+    ///
+    /// The listener is switched to non-blocking mode. Bind-time socket
+    /// options are whatever the listener already has.
+    pub fn from_std_listener(listener: std::net::TcpListener) -> Result<Self, VstpError> {
+        Self::from_std_listener_with_config(listener, TcpServerConfig::default())
+    }
+
+    /// Take over an already bound listener with custom configuration
+    pub fn from_std_listener_with_config(
+        listener: std::net::TcpListener,
+        config: TcpServerConfig,
+    ) -> Result<Self, VstpError> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &config.tls {
+            crate::tcp::tls::check_server_config(tls)?;
+        }
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let local_addr = listener.local_addr()?;
+        config
+            .span
+            .in_scope(|| info!("VSTP TCP server adopted listener on {}", local_addr));
+
+        Ok(Self::from_parts(listener, config))
+    }
+
+    /// Give up the listener so it can be handed to another process
+    pub fn into_std(self) -> Result<std::net::TcpListener, VstpError> {
+        Ok(self.listener.into_std()?)
+    }
+
+    /// Bind with `SO_REUSEADDR` set, for fast restarts
+    pub async fn bind_reuseaddr(addr: impl ToSocketAddrs) -> Result<Self, VstpError> {
+        let defaults = TcpServerConfig::default();
+        let config = TcpServerConfig {
+            socket: SocketOptions {
+                reuse_addr: true,
+                ..defaults.socket
+            },
+            ..defaults
+        };
+        Self::bind_with_config(addr, config).await
+    }
+
+    /// Accept the next connection, secured with TLS if the server serves it
+    #[cfg(feature = "tls")]
+    async fn next_socket(&self) -> Result<(Socket, SocketAddr, Vec<u8>), VstpError> {
+        let mut pending = self.handshakes.lock().await;
+        let room = self.config.max_pending_tls_handshakes.max(1);
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept(), if pending.len() < room => {
+                    let (socket, addr) = accepted?;
+                    self.config.socket.apply_to_stream(&socket)?;
+                    let Some(tls) = self.tls_config() else {
+                        return Ok((Socket::Plain(socket), addr, Vec::new()));
+                    };
+                    let limit = self.config.tls_handshake_timeout;
+                    pending.spawn(async move {
+                        let acceptor = tokio_rustls::TlsAcceptor::from(tls);
+                        let accepted = tokio::time::timeout(limit, acceptor.accept(socket))
+                            .await
+                            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+                            .and_then(|mut stream| {
+                                // Reads of the stream skip the early data
+                                let mut early_data = Vec::new();
+                                if let Some(mut early) = stream.get_mut().1.early_data() {
+                                    std::io::Read::read_to_end(&mut early, &mut early_data)?;
+                                }
+                                Ok((Socket::Tls(Box::new(stream.into())), early_data))
+                            })
+                            .map_err(VstpError::TlsHandshake);
+                        (accepted, addr)
+                    });
+                }
+                Some(done) = pending.join_next() => match done {
+                    Ok((Ok((socket, early_data)), addr)) => {
+                        return Ok((socket, addr, early_data))
+                    }
+                    Ok((Err(e), addr)) => {
+                        self.tls_failures.fetch_add(1, Ordering::Relaxed);
+                        self.config
+                            .span
+                            .in_scope(|| warn!("TLS handshake with {} failed: {}", addr, e));
+                    }
+                    Err(e) => {
+                        self.tls_failures.fetch_add(1, Ordering::Relaxed);
+                        self.config
+                            .span
+                            .in_scope(|| warn!("TLS handshake task failed: {}", e));
+                    }
+                },
+            }
+        }
+    }
+
+    /// Accept the next connection
+    #[cfg(not(feature = "tls"))]
+    async fn next_socket(&self) -> Result<(Socket, SocketAddr, Vec<u8>), VstpError> {
+        let (socket, addr) = self.listener.accept().await?;
+        self.config.socket.apply_to_stream(&socket)?;
+        Ok((Socket::Plain(socket), addr, Vec::new()))
     }
 
     /// Accept a new client connection
+    ///
+    /// With TLS on, handshakes run in the background, several at once, and
+    /// this returns the first connection to finish one. Connections whose
+    /// handshake fails are logged and dropped.
     pub async fn accept(&self) -> Result<VstpTcpConnection, VstpError> {
-        let (socket, addr) = self.listener.accept().await?;
+        let (socket, addr, early_data) = self.next_socket().await?;
+        #[cfg(feature = "tls")]
+        let tls_server_name = socket.tls_server_name().map(str::to_string);
+        #[cfg(not(feature = "tls"))]
+        let tls_server_name = None;
         let session_id = {
             let mut id_guard = self.next_session_id.lock().await;
             *id_guard += 1;
             *id_guard
         };
 
-        info!("New connection from {} (session {})", addr, session_id);
+        self.config
+            .span
+            .in_scope(|| info!("New connection from {} (session {})", addr, session_id));
+
+        let quota = self.config.session_quota.map(QuotaTracker::new);
+        let age_deadline = self
+            .config
+            .max_connection_age
+            .map(|age| Instant::now() + age);
+        let quota_deadline = quota.as_ref().and_then(QuotaTracker::deadline);
+        let time_up = || DisconnectReason::QuotaExceeded("time".to_string());
+        let (deadline, expiry) = match (age_deadline, quota_deadline) {
+            (Some(age), Some(time)) if time < age => (Some(time), time_up()),
+            (Some(age), _) => (Some(age), DisconnectReason::MaxAge),
+            (None, Some(time)) => (Some(time), time_up()),
+            (None, None) => (None, DisconnectReason::MaxAge),
+        };
 
+        let mut codec =
+            Codec::new(self.config.max_frame_size).with_wire_tap(self.config.wire_tap.clone());
+        codec.set_keep_raw_bytes(self.config.keep_raw_bytes);
+        codec.set_max_header_to_payload_ratio(self.config.max_header_to_payload_ratio);
+        codec.set_read_buffer_capacity(
+            self.config.read_buffer_capacity,
+            self.config.max_read_buffer_capacity,
+        );
+        // Frames sent as TLS early data are read first
+        let socket = Shaped::new(socket, self.send_shaper.clone());
+        let mut parts = FramedParts::new::<Frame>(socket, codec);
+        parts.read_buf = bytes::BytesMut::with_capacity(self.config.read_buffer_capacity);
+        parts.read_buf.extend_from_slice(&early_data);
         Ok(VstpTcpConnection {
-            framed: Framed::new(socket, Codec::default()),
+            framed: Framed::from_parts(parts),
+            #[cfg(feature = "tls")]
+            early_data_left: early_data.len(),
             session_id,
             peer_addr: addr,
+            tls_server_name,
+            probe: self
+                .config
+                .probe_after
+                .map(|after| (after, self.config.probe_timeout)),
+            disconnect_reason: None,
+            max_frame_size: self.config.max_frame_size,
+            compression: CompressionControl::new(),
+            control_replies: VecDeque::new(),
+            control_unflushed: false,
+            ingress: self
+                .ingress
+                .clone()
+                .map(|policy| (policy, self.ingress_stats.clone())),
+            frame_types: self.frame_types.clone(),
+            max_age: deadline.map(|deadline| Box::pin(sleep_until(deadline))),
+            expiry,
+            retiring: None,
+            quota,
+            quota_closed: self.quota_closed.clone(),
+            partial_frame_limit: self
+                .config
+                .partial_frame_timeout
+                .map(|timeout| (timeout, self.config.partial_frame_min_bps)),
+            partial_frame: None,
+            partial_frame_sized: false,
+            stalled: false,
+            stalled_closed: self.stalled_closed.clone(),
+            last_meta: None,
+            span: self.config.span.clone(),
         })
     }
 
+    /// What the [`IngressPolicy`] has done across all connections
+    pub fn ingress_stats(&self) -> Arc<IngressStats> {
+        self.ingress_stats.clone()
+    }
+
+    /// Number of frames refused across all connections because of their type
+    pub fn rejected_frame_type_count(&self) -> u64 {
+        self.frame_types.rejected_count()
+    }
+
+    /// Number of sessions closed because they used up their [`SessionQuota`]
+    pub fn quota_closed_count(&self) -> u64 {
+        self.quota_closed.load(Ordering::Relaxed)
+    }
+
+    /// Number of sessions closed for not finishing a frame within
+    /// [`partial_frame_timeout`](TcpServerConfig::partial_frame_timeout)
+    pub fn stalled_count(&self) -> u64 {
+        self.stalled_closed.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections dropped because their TLS handshake failed or
+    /// took longer than [`tls_handshake_timeout`](TcpServerConfig::tls_handshake_timeout)
+    #[cfg(feature = "tls")]
+    pub fn tls_handshake_failures(&self) -> u64 {
+        self.tls_failures.load(Ordering::Relaxed)
+    }
+
+    /// The shaper applying `max_send_bps`, with the current send rate
+    pub fn send_shaper(&self) -> Option<Arc<SendShaper>> {
+        self.send_shaper.clone()
+    }
+
+    /// Span the server logs under
+    pub fn span(&self) -> &Span {
+        &self.config.span
+    }
+
     /// Get the local address this server is bound to
     pub fn local_addr(&self) -> Result<std::net::SocketAddr, VstpError> {
-        self.listener.local_addr().map_err(|e| VstpError::Io(e))
+        self.listener.local_addr().map_err(VstpError::Io)
+    }
+
+    /// Accept connections in the background and pull their frames from one stream
+    ///
+    /// An alternative to [`run`](VstpTcpServer::run) for event loops that
+    /// would rather not hand out callbacks; see [`incoming`](crate::tcp::incoming)
+    /// for ordering and backpressure.
+    pub fn incoming(self) -> IncomingFrames {
+        self.incoming_with_capacity(DEFAULT_INCOMING_CAPACITY)
+    }
+
+    /// [`incoming`](VstpTcpServer::incoming), buffering up to `capacity` frames
+    pub fn incoming_with_capacity(self, capacity: usize) -> IncomingFrames {
+        IncomingFrames::start(self, capacity)
     }
 
     /// Run the server with the provided handler function
+    ///
+    /// Sessions run concurrently; within one, the handler gets frames one at
+    /// a time in the order they arrived, see [`tcp`](crate::tcp#ordering).
     pub async fn run<F, Fut>(self, handler: F) -> Result<(), VstpError>
     where
         F: Fn(SessionId, Frame) -> Fut + Send + Sync + Clone + 'static,
         Fut: Future<Output = ()> + Send,
     {
-        info!("VSTP TCP server starting...");
+        self.run_with_meta(move |session_id, frame, _| handler(session_id, frame))
+            .await
+    }
+
+    /// Run the server, handing each frame's [`FrameMeta`] to the handler along with it
+    pub async fn run_with_meta<F, Fut>(self, handler: F) -> Result<(), VstpError>
+    where
+        F: Fn(SessionId, Frame, FrameMeta) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let span = self.config.span.clone();
+        span.in_scope(|| info!("VSTP TCP server starting..."));
 
         loop {
             match self.accept().await {
@@ -91,17 +1197,33 @@ impl VstpTcpServer {
                     let handler = handler.clone();
                     let session_id = conn.session_id;
 
-                    tokio::spawn(async move {
-                        while let Ok(Some(frame)) = conn.recv().await {
-                            handler(session_id, frame).await;
+                    let session = async move {
+                        while let Ok(Some((frame, meta))) = conn.recv_with_meta().await {
+                            debug!(
+                                "Session {} handling frame after {:?} in queue",
+                                session_id,
+                                meta.queue_delay()
+                            );
+                            handler(session_id, frame, meta).await;
                         }
-                        info!("Session {} ended", session_id);
-                    });
+                        match conn.disconnect_reason() {
+                            Some(reason) => info!("Session {} ended: {}", session_id, reason),
+                            None => info!("Session {} ended", session_id),
+                        }
+                    };
+                    tokio::spawn(session.instrument(span.clone()));
                 }
                 Err(e) => {
-                    tracing::error!("Failed to accept connection: {}", e);
+                    span.in_scope(|| tracing::error!("Failed to accept connection: {}", e));
                 }
             }
         }
     }
 }
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for VstpTcpServer {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.listener.as_raw_fd()
+    }
+}
@@ -0,0 +1,117 @@
+//! The socket under a TCP connection, plain or wrapped in TLS
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+#[cfg(feature = "tls")]
+type TlsStream = tokio_rustls::TlsStream<TcpStream>;
+
+/// A connected socket, read and written by one owner
+pub(crate) enum Socket {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+/// The read side of a [`Socket`] split in two
+pub(crate) enum ReadHalf {
+    Plain(OwnedReadHalf),
+    #[cfg(feature = "tls")]
+    Tls(tokio::io::ReadHalf<Box<TlsStream>>),
+}
+
+/// The write side of a [`Socket`] split in two
+pub(crate) enum WriteHalf {
+    Plain(OwnedWriteHalf),
+    #[cfg(feature = "tls")]
+    Tls(tokio::io::WriteHalf<Box<TlsStream>>),
+}
+
+impl Socket {
+    /// The server name a TLS client asked for with SNI, on the server's end
+    /// of a connection
+    #[cfg(feature = "tls")]
+    pub fn tls_server_name(&self) -> Option<&str> {
+        match self {
+            Socket::Tls(stream) => match &**stream {
+                tokio_rustls::TlsStream::Server(stream) => stream.get_ref().1.server_name(),
+                tokio_rustls::TlsStream::Client(_) => None,
+            },
+            Socket::Plain(_) => None,
+        }
+    }
+
+    /// Halves that can be read and written independently
+    ///
+    /// A plain socket splits without a lock; TLS halves share one.
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        match self {
+            Socket::Plain(socket) => {
+                let (read, write) = socket.into_split();
+                (ReadHalf::Plain(read), WriteHalf::Plain(write))
+            }
+            #[cfg(feature = "tls")]
+            Socket::Tls(stream) => {
+                let (read, write) = tokio::io::split(stream);
+                (ReadHalf::Tls(read), WriteHalf::Tls(write))
+            }
+        }
+    }
+}
+
+/// Forward a poll method to whichever socket a value holds
+macro_rules! delegate {
+    ($ty:ident, $this:expr, $inner:ident => $call:expr) => {
+        match $this.get_mut() {
+            $ty::Plain($inner) => $call,
+            #[cfg(feature = "tls")]
+            $ty::Tls($inner) => $call,
+        }
+    };
+}
+
+macro_rules! impl_read {
+    ($ty:ident) => {
+        impl AsyncRead for $ty {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                delegate!($ty, self, inner => Pin::new(inner).poll_read(cx, buf))
+            }
+        }
+    };
+}
+
+macro_rules! impl_write {
+    ($ty:ident) => {
+        impl AsyncWrite for $ty {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                delegate!($ty, self, inner => Pin::new(inner).poll_write(cx, buf))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                delegate!($ty, self, inner => Pin::new(inner).poll_flush(cx))
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                delegate!($ty, self, inner => Pin::new(inner).poll_shutdown(cx))
+            }
+        }
+    };
+}
+
+impl_read!(Socket);
+impl_read!(ReadHalf);
+impl_write!(Socket);
+impl_write!(WriteHalf);
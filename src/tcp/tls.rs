@@ -0,0 +1,574 @@
+//! TLS 1.3 for the TCP transport, behind the `tls` feature
+//!
+//! A server bound with [`VstpTcpServer::bind_tls`] runs the TLS handshake on
+//! every connection it accepts before reading frames from it, and a client
+//! connected with [`VstpTcpClient::connect_tls`] does the same from its end,
+//! checking the certificate against the host in the address, or against a
+//! given server name with [`VstpTcpClient::connect_tls_as`].
+//! Frames then go through the encrypted stream exactly as they would over
+//! plain TCP. The easy API takes the same configs with
+//! [`VstpServer::with_tls`] and [`ConnectOptions::with_tls`].
+//!
+//! Any [`rustls`] config works. The helpers here build ones that speak TLS
+//! 1.3 only:
+//!
+//! - [`TlsConfig::self_signed`] makes a throwaway certificate, with a server
+//!   config presenting it and a client config trusting it alone, e.g. for
+//!   tests or a private deployment without a CA. It needs the
+//!   `tls-self-signed` feature, which pulls in a certificate generator.
+//! - [`TlsConfig::from_pem`] does the same for an existing certificate.
+//! - [`server_config`] presents a PEM certificate chain.
+//! - [`client_config`] verifies servers against PEM root certificates, the
+//!   usual way, including their name.
+//! - [`pinned_client_config`] trusts exactly the certificates given, whatever
+//!   name they carry and whoever signed them.
+//!
+//! A server's config can be swapped while it runs with
+//! [`VstpTcpServer::set_tls`], and [`watch_pem_files`] does so whenever the
+//! PEM files of a renewed certificate change on disk. New handshakes use the
+//! new config; sessions already set up keep theirs. The [`PemWatcher`] it
+//! returns tells when the certificate in use expires, for alerting.
+//! [`watch_pem_files_with`] hands the files to any other reload, e.g. a
+//! [`VstpClientPool::set_tls`] with a renewed client certificate.
+//!
+//! Servers refuse configs that would accept a version below TLS 1.3, or that
+//! set ALPN without offering [`ALPN_PROTOCOL`]; see [`check_server_config`].
+//! Clients refuse such configs the same way before connecting, see
+//! [`check_client_config`]. Anything else, e.g. cipher suites or a client
+//! certificate, is up to the config, which
+//! [`VstpTcpClient::connect_tls_with_config`] takes fully built.
+//!
+//! # Early data
+//!
+//! A client resuming a session can send its first frames along with the
+//! ClientHello, as TLS 1.3 early ("0-RTT") data, instead of waiting out the
+//! handshake: [`VstpTcpClient::connect_tls_with_early_data`], or
+//! [`ConnectOptions::allow_early_data`] for the HELLO of the easy API.
+//! Servers only take it with [`TcpServerConfig::accept_early_data`] set, or
+//! with a config whose own `max_early_data_size` isn't zero; otherwise the
+//! client sends the same frames again once the handshake completes.
+//!
+//! Early data can be replayed. Whoever records the first flight of a
+//! connection can send it to the server again, and the server can't tell the
+//! copy from the original. With the default session cache a ticket is good
+//! for one resumption, so a single server refuses the replay, but with a
+//! stateless `ticketer`, e.g. one shared by several servers, each of them may
+//! act on it. Servers therefore mark every frame that arrived as early data,
+//! even in part, with an [`EARLY_DATA_HEADER`] of `1`. Handlers should refuse
+//! such frames unless acting on them twice is harmless, e.g. reads, or
+//! requests carrying an [`IDEMPOTENCY_KEY_HEADER`](crate::easy::IDEMPOTENCY_KEY_HEADER).
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), vstp::VstpError> {
+//! use vstp::tcp::tls::TlsConfig;
+//! use vstp::tcp::{VstpTcpClient, VstpTcpServer};
+//!
+//! let tls = TlsConfig::self_signed()?;
+//! let server = VstpTcpServer::bind_tls("127.0.0.1:6000", tls.server_config()).await?;
+//! let client = VstpTcpClient::connect_tls("127.0.0.1:6000", tls.client_config()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`VstpTcpServer::bind_tls`]: crate::tcp::VstpTcpServer::bind_tls
+//! [`VstpTcpServer::set_tls`]: crate::tcp::VstpTcpServer::set_tls
+//! [`VstpTcpClient::connect_tls`]: crate::tcp::VstpTcpClient::connect_tls
+//! [`VstpTcpClient::connect_tls_as`]: crate::tcp::VstpTcpClient::connect_tls_as
+//! [`VstpTcpClient::connect_tls_with_config`]: crate::tcp::VstpTcpClient::connect_tls_with_config
+//! [`VstpServer::with_tls`]: crate::easy::VstpServer::with_tls
+//! [`VstpClientPool::set_tls`]: crate::pool::VstpClientPool::set_tls
+//! [`ConnectOptions::with_tls`]: crate::easy::ConnectOptions::with_tls
+//! [`ConnectOptions::allow_early_data`]: crate::easy::ConnectOptions::allow_early_data
+//! [`TcpServerConfig::accept_early_data`]: crate::tcp::TcpServerConfig::accept_early_data
+//! [`VstpTcpClient::connect_tls_with_early_data`]: crate::tcp::VstpTcpClient::connect_tls_with_early_data
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::Acceptor;
+use rustls::{
+    CertificateError, CipherSuite, ClientConfig, ClientConnection, ConfigBuilder, DigitallySignedStruct,
+    ProtocolVersion, RootCertStore, ServerConfig, ServerConnection, SignatureScheme,
+    WantsVerifier,
+};
+
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::tcp::VstpTcpServer;
+use crate::types::VstpError;
+
+/// ALPN protocol name of VSTP, which a config setting ALPN has to offer
+pub const ALPN_PROTOCOL: &[u8] = b"vstp";
+
+/// Header a server adds, with the value `1`, to frames that arrived as TLS
+/// early data, which may be replays; see [early data](self#early-data)
+pub const EARLY_DATA_HEADER: &str = "early-data";
+
+/// Names [`TlsConfig::self_signed`] issues its certificate for
+#[cfg(feature = "tls-self-signed")]
+const LOCAL_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// A certificate with a server config presenting it and a client config
+/// trusting exactly it
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+    certificate: CertificateDer<'static>,
+}
+
+impl TlsConfig {
+    /// A fresh self-signed certificate for `localhost`, `127.0.0.1` and `::1`
+    #[cfg(feature = "tls-self-signed")]
+    pub fn self_signed() -> Result<Self, VstpError> {
+        Self::self_signed_for(LOCAL_NAMES.map(String::from))
+    }
+
+    /// A fresh self-signed certificate for `names`, host names or IP addresses
+    #[cfg(feature = "tls-self-signed")]
+    pub fn self_signed_for(names: impl Into<Vec<String>>) -> Result<Self, VstpError> {
+        let issued = rcgen::generate_simple_self_signed(names).map_err(tls_error)?;
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(issued.key_pair.serialize_der());
+        Self::from_der(vec![issued.cert.der().clone()], key.into())
+    }
+
+    /// Present the PEM certificate chain `chain`, signed with the PEM `key`
+    ///
+    /// The client config trusts the chain's first certificate.
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Self, VstpError> {
+        Self::from_der(certificates(chain)?, private_key(key)?)
+    }
+
+    fn from_der(
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, VstpError> {
+        let certificate = chain
+            .first()
+            .cloned()
+            .ok_or_else(|| VstpError::Tls("no certificate given".to_string()))?;
+        let server = tls13(ServerConfig::builder_with_provider(provider()))?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(tls_error)?;
+        let client = pinned_client_config([certificate.clone()])?;
+        Ok(Self {
+            server: Arc::new(server),
+            client: Arc::new(client),
+            certificate,
+        })
+    }
+
+    /// Config for [`VstpTcpServer::bind_tls`](crate::tcp::VstpTcpServer::bind_tls)
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.server.clone()
+    }
+
+    /// Config for [`VstpTcpClient::connect_tls`](crate::tcp::VstpTcpClient::connect_tls),
+    /// trusting only this certificate
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.client.clone()
+    }
+
+    /// The certificate the server presents, e.g. to pin it elsewhere with
+    /// [`pinned_client_config`]
+    pub fn certificate(&self) -> &CertificateDer<'static> {
+        &self.certificate
+    }
+}
+
+/// A TLS 1.3 server config presenting the PEM certificate chain `chain`,
+/// signed with the PEM `key`
+pub fn server_config(chain: &[u8], key: &[u8]) -> Result<ServerConfig, VstpError> {
+    tls13(ServerConfig::builder_with_provider(provider()))?
+        .with_no_client_auth()
+        .with_single_cert(certificates(chain)?, private_key(key)?)
+        .map_err(tls_error)
+}
+
+/// Serve the certificate chain in the PEM file `chain`, signed with the PEM
+/// file `key`, and switch `server` to them again whenever they change
+///
+/// The files are read every `interval`. Once their contents change, a
+/// [`server_config`] is built from them and handed to
+/// [`VstpTcpServer::set_tls`], so connections accepted from then on get the
+/// new certificate while established sessions carry on with the old one.
+/// Files that can't be read or don't hold a usable certificate and key,
+/// e.g. halfway through being replaced, are logged as an error and the
+/// server keeps the config it has; they are tried again once they change.
+///
+/// The first read happens at once. Abort the returned watcher to stop
+/// watching.
+pub fn watch_pem_files(
+    server: Arc<VstpTcpServer>,
+    chain: impl Into<PathBuf>,
+    key: impl Into<PathBuf>,
+    interval: Duration,
+) -> PemWatcher {
+    watch_pem_files_with(chain, key, interval, move |chain, key| {
+        server.set_tls(Some(Arc::new(server_config(chain, key)?)))
+    })
+}
+
+/// Read the PEM files `chain` and `key` every `interval` and hand their
+/// contents to `reload` whenever they change
+///
+/// This is [`watch_pem_files`] for anything other than a server, e.g. a
+/// [`VstpClientPool`](crate::pool::VstpClientPool) presenting a client
+/// certificate that is renewed on disk:
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # fn run(pool: Arc<vstp::pool::VstpClientPool>, roots: rustls::RootCertStore) {
+/// use std::time::Duration;
+/// use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+/// use rustls::{version::TLS13, ClientConfig};
+/// use vstp::{tcp::tls::watch_pem_files_with, VstpError};
+///
+/// let tls_error = |e: &dyn std::fmt::Display| VstpError::Tls(e.to_string());
+/// let watcher = watch_pem_files_with(
+///     "client.pem",
+///     "client.key",
+///     Duration::from_secs(60),
+///     move |chain, key| {
+///         let chain = CertificateDer::pem_slice_iter(chain)
+///             .collect::<Result<Vec<_>, _>>()
+///             .map_err(|e| tls_error(&e))?;
+///         let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| tls_error(&e))?;
+///         let config = ClientConfig::builder_with_protocol_versions(&[&TLS13])
+///             .with_root_certificates(roots.clone())
+///             .with_client_auth_cert(chain, key)
+///             .map_err(|e| tls_error(&e))?;
+///         pool.set_tls(config);
+///         Ok(())
+///     },
+/// );
+/// # }
+/// ```
+///
+/// A reload failing keeps whatever it replaces, as with
+/// [`watch_pem_files`]: the error is logged and the files are tried again
+/// once they change. [`PemWatcher::cert_not_after`] follows the chains
+/// reloaded successfully.
+pub fn watch_pem_files_with<F>(
+    chain: impl Into<PathBuf>,
+    key: impl Into<PathBuf>,
+    interval: Duration,
+    reload: F,
+) -> PemWatcher
+where
+    F: Fn(&[u8], &[u8]) -> Result<(), VstpError> + Send + 'static,
+{
+    let (chain, key) = (chain.into(), key.into());
+    let not_after = Arc::new(Mutex::new(None));
+    let loaded = not_after.clone();
+    let task = tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        let mut seen = None;
+        let mut unreadable = false;
+        loop {
+            ticks.tick().await;
+            let files = match (tokio::fs::read(&chain).await, tokio::fs::read(&key).await) {
+                (Ok(chain), Ok(key)) => (chain, key),
+                (Err(e), _) | (_, Err(e)) => {
+                    // Once per outage, not on every tick
+                    if !unreadable {
+                        error!("Keeping the TLS certificate, reading {:?} failed: {}", chain, e);
+                        unreadable = true;
+                    }
+                    continue;
+                }
+            };
+            unreadable = false;
+            if seen.as_ref() == Some(&files) {
+                continue;
+            }
+            match reload(&files.0, &files.1) {
+                Ok(()) => {
+                    info!("Loaded the TLS certificate in {:?}", chain);
+                    *loaded.lock().unwrap() = leaf_not_after(&files.0);
+                }
+                Err(e) => error!("Keeping the TLS certificate, {:?} is unusable: {}", chain, e),
+            }
+            seen = Some(files);
+        }
+    });
+    PemWatcher { task, not_after }
+}
+
+/// The task of [`watch_pem_files`] or [`watch_pem_files_with`]
+///
+/// Dropping it leaves the task running; [`abort`](PemWatcher::abort) it to
+/// stop watching.
+#[derive(Debug)]
+pub struct PemWatcher {
+    task: JoinHandle<()>,
+    not_after: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl PemWatcher {
+    /// When the leaf certificate loaded last stops being valid
+    ///
+    /// `None` until a certificate has loaded, or if the last one loaded
+    /// couldn't be parsed for it.
+    pub fn cert_not_after(&self) -> Option<SystemTime> {
+        *self.not_after.lock().unwrap()
+    }
+
+    /// Stop watching; whatever was loaded last stays in use
+    pub fn abort(&self) {
+        self.task.abort();
+    }
+}
+
+/// The not-after time of the first certificate in the PEM `chain`
+fn leaf_not_after(chain: &[u8]) -> Option<SystemTime> {
+    let leaf = certificates(chain).ok()?.into_iter().next()?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(&leaf).ok()?;
+    let seconds = u64::try_from(parsed.validity().not_after.timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// A TLS 1.3 client config accepting servers whose certificate chains up to
+/// one of the PEM certificates in `roots` and names the server
+pub fn client_config(roots: &[u8]) -> Result<ClientConfig, VstpError> {
+    let mut store = RootCertStore::empty();
+    for root in certificates(roots)? {
+        store.add(root).map_err(tls_error)?;
+    }
+    Ok(tls13(ClientConfig::builder_with_provider(provider()))?
+        .with_root_certificates(store)
+        .with_no_client_auth())
+}
+
+/// A TLS 1.3 client config accepting only servers presenting one of `pins`
+/// as their own certificate
+///
+/// Neither the certificate's name nor its issuer is checked: the pin is the
+/// server's identity. The server still has to prove it holds the key.
+pub fn pinned_client_config(
+    pins: impl IntoIterator<Item = CertificateDer<'static>>,
+) -> Result<ClientConfig, VstpError> {
+    let verifier = PinnedCertificates {
+        pins: pins.into_iter().collect(),
+        provider: provider(),
+    };
+    Ok(tls13(ClientConfig::builder_with_provider(provider()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Check that a server `config` speaks TLS 1.3 only and, if it sets ALPN,
+/// offers [`ALPN_PROTOCOL`]; [`VstpError::Tls`] if not
+///
+/// rustls doesn't say which versions a config enables, so each older version
+/// rustls was built with is offered to it in memory, and the config fails if
+/// it would go ahead with one.
+pub fn check_server_config(config: &Arc<ServerConfig>) -> Result<(), VstpError> {
+    check_alpn(&config.alpn_protocols)?;
+    if let Some(version) = older_version_accepted(config) {
+        return Err(VstpError::Tls(format!(
+            "config accepts {:?}, VSTP needs TLS 1.3",
+            version
+        )));
+    }
+    Ok(())
+}
+
+/// Check that a client `config` offers TLS 1.3 only and, if it sets ALPN,
+/// offers [`ALPN_PROTOCOL`]; [`VstpError::Tls`] if not
+///
+/// As with [`check_server_config`], the versions are found by trying the
+/// config: its ClientHello is built in memory and read back.
+pub fn check_client_config(config: &Arc<ClientConfig>) -> Result<(), VstpError> {
+    check_alpn(&config.alpn_protocols)?;
+    if offers_older_versions(config) {
+        return Err(VstpError::Tls("config offers TLS 1.2, VSTP needs TLS 1.3".to_string()));
+    }
+    Ok(())
+}
+
+/// `config`, taking up to `max` bytes of early data
+pub(crate) fn with_early_data(config: Arc<ServerConfig>, max: u32) -> Arc<ServerConfig> {
+    if config.max_early_data_size >= max {
+        return config;
+    }
+    let mut config = (*config).clone();
+    config.max_early_data_size = max;
+    Arc::new(config)
+}
+
+/// `config`, sending early data
+pub(crate) fn sending_early_data(config: Arc<ClientConfig>) -> Arc<ClientConfig> {
+    if config.enable_early_data {
+        return config;
+    }
+    let mut config = (*config).clone();
+    config.enable_early_data = true;
+    Arc::new(config)
+}
+
+fn check_alpn(alpn: &[Vec<u8>]) -> Result<(), VstpError> {
+    if !alpn.is_empty() && !alpn.iter().any(|protocol| protocol == ALPN_PROTOCOL) {
+        let offered: Vec<_> = alpn.iter().map(|p| String::from_utf8_lossy(p)).collect();
+        return Err(VstpError::Tls(format!(
+            "ALPN offers {:?} but not \"vstp\"",
+            offered
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the ClientHello of `config` offers a version below TLS 1.3
+///
+/// rustls lists the cipher suites of every version it has in the hello
+/// whatever the config enables, but adds the renegotiation SCSV only when
+/// it offers TLS 1.2.
+fn offers_older_versions(config: &Arc<ClientConfig>) -> bool {
+    let hello_suites = || {
+        let name = ServerName::try_from("vstp.invalid").ok()?;
+        let mut client = ClientConnection::new(config.clone(), name).ok()?;
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).ok()?;
+        let mut acceptor = Acceptor::default();
+        acceptor.read_tls(&mut hello.as_slice()).ok()?;
+        let accepted = acceptor.accept().ok()??;
+        Some(accepted.client_hello().cipher_suites().to_vec())
+    };
+    hello_suites()
+        .is_some_and(|suites| suites.contains(&CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV))
+}
+
+/// The first version below TLS 1.3 `config` goes ahead with when a client
+/// offers only that version
+fn older_version_accepted(config: &Arc<ServerConfig>) -> Option<ProtocolVersion> {
+    rustls::ALL_VERSIONS
+        .iter()
+        .filter(|version| version.version != ProtocolVersion::TLSv1_3)
+        .find_map(|version| {
+            let verifier = PinnedCertificates {
+                pins: Vec::new(),
+                provider: config.crypto_provider().clone(),
+            };
+            let client = ClientConfig::builder_with_provider(config.crypto_provider().clone())
+                .with_protocol_versions(&[*version])
+                .ok()?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth();
+            let name = ServerName::try_from("vstp.invalid").ok()?;
+            let mut client = ClientConnection::new(Arc::new(client), name).ok()?;
+            let mut server = ServerConnection::new(config.clone()).ok()?;
+            let mut hello = Vec::new();
+            client.write_tls(&mut hello).ok()?;
+            server.read_tls(&mut hello.as_slice()).ok()?;
+            server.process_new_packets().ok()?;
+            server
+                .protocol_version()
+                .filter(|negotiated| *negotiated != ProtocolVersion::TLSv1_3)
+        })
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn tls13<S: rustls::ConfigSide>(
+    builder: ConfigBuilder<S, rustls::WantsVersions>,
+) -> Result<ConfigBuilder<S, WantsVerifier>, VstpError> {
+    builder
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tls_error)
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, VstpError> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    if certificates.is_empty() {
+        return Err(VstpError::Tls("no PEM certificate found".to_string()));
+    }
+    Ok(certificates)
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, VstpError> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(tls_error)
+}
+
+fn tls_error(e: impl std::fmt::Display) -> VstpError {
+    VstpError::Tls(e.to_string())
+}
+
+/// The server name a client checks the certificate of `addr`'s server against
+pub(crate) fn server_name(addr: &str) -> Result<ServerName<'static>, VstpError> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _port)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(tls_error)
+}
+
+/// Accepts the pinned certificates and nothing else
+#[derive(Debug)]
+struct PinnedCertificates {
+    pins: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificates {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pins.iter().any(|pin| pin == end_entity) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,445 @@
+//! Helpers for loopback integration tests (requires the `test-util` feature)
+//!
+//! Spawn a server on an ephemeral port with a reply function, connect a
+//! client and exchange frames with a timeout:
+//!
+//! ```
+//! use vstp::testing::{echo, spawn_tcp_server, tcp_roundtrip};
+//! use vstp::{Frame, FrameType};
+//!
+//! # #[tokio::main] async fn main() -> Result<(), vstp::VstpError> {
+//! let server = spawn_tcp_server(echo).await?;
+//! let mut client = server.tcp_client().await?;
+//! let reply = tcp_roundtrip(&mut client, Frame::new(FrameType::Data).with_payload_text("hi")).await?;
+//! assert_eq!(reply.payload, b"hi");
+//! # Ok(()) }
+//! ```
+//!
+//! [`LossyUdpProxy`] sits between a UDP client and server and drops, delays
+//! or corrupts datagrams, for exercising retransmission and reassembly. [`FrameTap`]
+//! sits between a TCP client and server and records the frames going by.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use bytes::BytesMut;
+
+use crate::codec::FrameDecoder;
+use crate::frame::{encode_frame, try_decode_frame};
+use crate::tcp::{VstpTcpClient, VstpTcpServer};
+use crate::types::{Frame, VstpError};
+use crate::udp::{VstpUdpClient, VstpUdpServer};
+
+/// How long the round-trip helpers wait for a reply
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reply function that sends every frame straight back
+pub fn echo(frame: Frame) -> Option<Frame> {
+    Some(frame)
+}
+
+/// A server running in the background on an ephemeral loopback port
+///
+/// The server stops when this is dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Connect a TCP client to this server
+    pub async fn tcp_client(&self) -> Result<VstpTcpClient, VstpError> {
+        VstpTcpClient::connect(&self.addr.to_string()).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Start a TCP server that answers each frame with `reply`, if it returns one
+pub async fn spawn_tcp_server<F>(reply: F) -> Result<TestServer, VstpError>
+where
+    F: Fn(Frame) -> Option<Frame> + Send + Sync + 'static,
+{
+    let server = VstpTcpServer::bind("127.0.0.1:0").await?;
+    let addr = server.local_addr()?;
+    let reply = Arc::new(reply);
+
+    let task = tokio::spawn(async move {
+        while let Ok(mut conn) = server.accept().await {
+            let reply = reply.clone();
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    if let Some(response) = reply(frame) {
+                        if conn.send(response).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(TestServer { addr, task })
+}
+
+/// Start a UDP server that answers each frame with `reply`, if it returns one
+pub async fn spawn_udp_server<F>(reply: F) -> Result<TestServer, VstpError>
+where
+    F: Fn(Frame) -> Option<Frame> + Send + Sync + 'static,
+{
+    let server = VstpUdpServer::bind("127.0.0.1:0").await?;
+    let addr = server.local_addr()?;
+
+    let task = tokio::spawn(async move {
+        while let Ok((frame, from)) = server.recv().await {
+            if let Some(response) = reply(frame) {
+                let _ = server.send(response, from).await;
+            }
+        }
+    });
+
+    Ok(TestServer { addr, task })
+}
+
+/// Bind a UDP client on an ephemeral loopback port
+pub async fn udp_client() -> Result<VstpUdpClient, VstpError> {
+    VstpUdpClient::bind("127.0.0.1:0").await
+}
+
+/// Send a frame over TCP and wait up to [`TEST_TIMEOUT`] for the next frame back
+pub async fn tcp_roundtrip(client: &mut VstpTcpClient, frame: Frame) -> Result<Frame, VstpError> {
+    client.send(frame).await?;
+    timeout(TEST_TIMEOUT, client.recv())
+        .await
+        .map_err(|_| VstpError::Timeout)??
+        .ok_or(VstpError::ConnectionClosed)
+}
+
+/// Send a frame over UDP and wait up to [`TEST_TIMEOUT`] for a frame from `dest`
+pub async fn udp_roundtrip(
+    client: &mut VstpUdpClient,
+    dest: SocketAddr,
+    frame: Frame,
+) -> Result<Frame, VstpError> {
+    client.send(frame, dest).await?;
+    timeout(TEST_TIMEOUT, async {
+        loop {
+            let (frame, from) = client.recv().await?;
+            if from == dest {
+                return Ok(frame);
+            }
+        }
+    })
+    .await
+    .map_err(|_| VstpError::Timeout)?
+}
+
+/// Loss settings for a [`LossyUdpProxy`]
+#[derive(Debug, Clone)]
+pub struct LossConfig {
+    /// Probability of dropping each datagram, in either direction
+    pub drop_rate: f64,
+    /// Seed for the drop decisions, so failures are reproducible
+    pub seed: u64,
+    /// How long each forwarded datagram is held, in either direction
+    pub latency: Duration,
+}
+
+impl Default for LossConfig {
+    fn default() -> Self {
+        Self {
+            drop_rate: 0.0,
+            seed: 0,
+            latency: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LossState {
+    rng: SmallRng,
+    drop_rate: f64,
+    drop_next: u64,
+    corrupt_next: u64,
+    latency: Duration,
+}
+
+impl LossState {
+    fn should_drop(&mut self) -> bool {
+        if self.drop_next > 0 {
+            self.drop_next -= 1;
+            return true;
+        }
+        self.drop_rate > 0.0 && self.rng.gen_bool(self.drop_rate.min(1.0))
+    }
+}
+
+/// UDP relay that forwards datagrams to a server, dropping or delaying some of them
+///
+/// Point the client at [`addr`](LossyUdpProxy::addr) instead of the server.
+/// Replies are sent back to the most recent client, so use one proxy per
+/// client.
+pub struct LossyUdpProxy {
+    addr: SocketAddr,
+    state: Arc<Mutex<LossState>>,
+    forwarded: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+impl LossyUdpProxy {
+    /// Start relaying to `upstream`
+    pub async fn start(upstream: SocketAddr, config: LossConfig) -> Result<Self, VstpError> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        let state = Arc::new(Mutex::new(LossState {
+            rng: SmallRng::seed_from_u64(config.seed),
+            drop_rate: config.drop_rate,
+            drop_next: 0,
+            corrupt_next: 0,
+            latency: config.latency,
+        }));
+        let forwarded = Arc::new(AtomicU64::new(0));
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let task = tokio::spawn(relay(
+            Arc::new(socket),
+            upstream,
+            state.clone(),
+            forwarded.clone(),
+            dropped.clone(),
+        ));
+
+        Ok(Self {
+            addr,
+            state,
+            forwarded,
+            dropped,
+            task,
+        })
+    }
+
+    /// Address clients should send to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Drop the next `count` datagrams regardless of the drop rate
+    pub fn drop_next(&self, count: u64) {
+        self.state.lock().unwrap().drop_next += count;
+    }
+
+    /// Corrupt the payload of the next `count` frames with a payload on their way to the server
+    ///
+    /// The frame is re-encoded with a valid trailer, like a middlebox that
+    /// rewrites frames would, so only checks over the payload notice.
+    pub fn corrupt_next(&self, count: u64) {
+        self.state.lock().unwrap().corrupt_next += count;
+    }
+
+    /// Change the random drop rate
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.state.lock().unwrap().drop_rate = drop_rate;
+    }
+
+    /// Change the latency added to each datagram
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Number of datagrams relayed
+    pub fn forwarded(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Number of datagrams dropped
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for LossyUdpProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn relay(
+    socket: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    state: Arc<Mutex<LossState>>,
+    forwarded: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut buf = vec![0u8; 65536];
+    let mut client = None;
+
+    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+        let dest = if from == upstream {
+            match client {
+                Some(client) => client,
+                None => continue,
+            }
+        } else {
+            client = Some(from);
+            upstream
+        };
+
+        let (latency, corrupted) = {
+            let mut state = state.lock().unwrap();
+            if state.should_drop() {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let corrupted = if dest == upstream && state.corrupt_next > 0 {
+                corrupt(&buf[..len])
+            } else {
+                None
+            };
+            if corrupted.is_some() {
+                state.corrupt_next -= 1;
+            }
+            (state.latency, corrupted)
+        };
+        let datagram = corrupted.unwrap_or_else(|| buf[..len].to_vec());
+        if latency.is_zero() {
+            if socket.send_to(&datagram, dest).await.is_ok() {
+                forwarded.fetch_add(1, Ordering::Relaxed);
+            }
+            continue;
+        }
+        let (socket, forwarded) = (socket.clone(), forwarded.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            if socket.send_to(&datagram, dest).await.is_ok() {
+                forwarded.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// `datagram` re-encoded with the first payload byte flipped; `None` if it
+/// doesn't hold a frame with a payload
+fn corrupt(datagram: &[u8]) -> Option<Vec<u8>> {
+    let mut frame = try_decode_frame(&mut BytesMut::from(datagram), usize::MAX).ok()??;
+    *frame.payload.first_mut()? ^= 0xff;
+    encode_frame(&frame).ok().map(|bytes| bytes.to_vec())
+}
+
+/// Which way a frame recorded by a [`FrameTap`] was travelling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapDirection {
+    ToServer,
+    ToClient,
+}
+
+type Tapped = Arc<Mutex<Vec<(TapDirection, Frame)>>>;
+
+/// TCP relay that forwards bytes unchanged and records the frames in them
+///
+/// Point the client at [`addr`](FrameTap::addr) instead of the server. The
+/// recorded frames keep the `version` they were encoded in. Only the first
+/// connection is relayed, so use one tap per client.
+pub struct FrameTap {
+    addr: SocketAddr,
+    frames: Tapped,
+    task: JoinHandle<()>,
+}
+
+impl FrameTap {
+    /// Start relaying to `upstream`
+    pub async fn start(upstream: SocketAddr) -> Result<Self, VstpError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let frames = Tapped::default();
+
+        let tapped = frames.clone();
+        let task = tokio::spawn(async move {
+            let Ok((client, _)) = listener.accept().await else {
+                return;
+            };
+            let Ok(server) = TcpStream::connect(upstream).await else {
+                return;
+            };
+            let (client_read, client_write) = client.into_split();
+            let (server_read, server_write) = server.into_split();
+            tokio::join!(
+                pipe(client_read, server_write, TapDirection::ToServer, tapped.clone()),
+                pipe(server_read, client_write, TapDirection::ToClient, tapped),
+            );
+        });
+
+        Ok(Self { addr, frames, task })
+    }
+
+    /// Address clients should connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Frames seen so far, in the order they were decoded
+    pub fn frames(&self) -> Vec<(TapDirection, Frame)> {
+        self.frames.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FrameTap {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn pipe(
+    mut from: OwnedReadHalf,
+    mut to: OwnedWriteHalf,
+    direction: TapDirection,
+    tapped: Tapped,
+) {
+    let mut decoder = FrameDecoder::default();
+    let mut decoding = true;
+    let mut buf = vec![0u8; 65536];
+
+    while let Ok(len) = from.read(&mut buf).await {
+        if len == 0 {
+            break;
+        }
+        // Record before relaying, so a frame has been recorded by the time
+        // its receiver sees it. Keep relaying even if the stream stops making sense.
+        if decoding {
+            decoder.push(&buf[..len]);
+            loop {
+                match decoder.next_frame() {
+                    Ok(Some(frame)) => tapped.lock().unwrap().push((direction, frame)),
+                    Ok(None) => break,
+                    Err(_) => {
+                        decoding = false;
+                        break;
+                    }
+                }
+            }
+        }
+        if to.write_all(&buf[..len]).await.is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown().await;
+}
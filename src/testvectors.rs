@@ -0,0 +1,127 @@
+//! Reference encodings for checking other VSTP implementations
+//!
+//! Each [`TestVector`] pairs a frame with its exact bytes on the wire. An
+//! implementation should produce `encoded` from the frame and decode
+//! `encoded` back into it.
+//!
+//! ## Version 1 (`VER = 0x01`)
+//!
+//! ```text
+//! MAGIC (2B) | VER (1B) | TYPE (1B) | FLAGS (1B) | HDR_LEN (2B LE) | PAY_LEN (4B BE)
+//! HEADERS | PAYLOAD | CRC32 (4B BE)
+//! ```
+//!
+//! Note the mixed byte order: HDR_LEN is little-endian while every other
+//! multi-byte field is big-endian. The header section is limited to 64 KiB.
+//!
+//! ## Version 2 (`VER = 0x02`)
+//!
+//! ```text
+//! MAGIC (2B) | VER (1B) | TYPE (1B) | FLAGS (1B) | EXT (1B) | HDR_LEN (4B BE) | PAY_LEN (4B BE)
+//! HEADERS | PAYLOAD | CRC32 (4B BE)
+//! ```
+//!
+//! All lengths are big-endian and HDR_LEN is 32 bits wide. EXT is reserved
+//! and must be zero; receivers reject frames with any other value.
+//!
+//! ## Common to both
+//!
+//! Each header entry is `KEY_LEN (1B) | VALUE_LEN (1B) | KEY | VALUE`. The
+//! CRC is CRC-32 (IEEE) over every byte before it.
+//!
+//! Frames with the `SHA256` flag (`0x40`) end in the first 16 bytes of the
+//! SHA-256 digest of every byte before them instead of the CRC. The digest
+//! costs 12 more bytes per frame and more CPU, in exchange for catching any
+//! accidental corruption; [`Integrity`](crate::Integrity) has the details.
+//!
+//! Connections start in version 1. Version 2 is used on TCP only after the
+//! handshake agrees on it: the HELLO lists the versions the client can use in
+//! `supported-versions` (e.g. `1,2`) and the WELCOME names the server's pick
+//! in `protocol-version`. Both are version 1 frames, so a peer that only
+//! knows version 1 ignores the list, answers with version 1 and the session
+//! stays there. Receivers that support version 2 accept either version on
+//! every frame.
+
+use crate::types::{Flags, Frame, FrameType, VSTP_VERSION, VSTP_VERSION_2};
+
+/// A frame and its expected encoding
+#[derive(Debug, Clone, Copy)]
+pub struct TestVector {
+    pub name: &'static str,
+    /// Builds the frame the bytes decode to
+    pub frame: fn() -> Frame,
+    pub encoded: &'static [u8],
+}
+
+/// Every reference encoding, for both frame format versions
+pub const VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "v1 empty PING",
+        frame: || ping(VSTP_VERSION),
+        encoded: &[
+            0x56, 0x54, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xd8, 0x42, 0x9a,
+            0x00,
+        ],
+    },
+    TestVector {
+        name: "v1 DATA with a header and payload",
+        frame: || data(VSTP_VERSION),
+        encoded: &[
+            0x56, 0x54, 0x01, 0x03, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x02, 0x06, 0x02, 0x6d,
+            0x73, 0x67, 0x2d, 0x69, 0x64, 0x34, 0x32, 0x68, 0x69, 0x4a, 0xe7, 0xf9, 0x1f,
+        ],
+    },
+    TestVector {
+        name: "v2 empty PING",
+        frame: || ping(VSTP_VERSION_2),
+        encoded: &[
+            0x56, 0x54, 0x02, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x9b, 0xa3, 0x49, 0x16,
+        ],
+    },
+    TestVector {
+        name: "v2 DATA with a header and payload",
+        frame: || data(VSTP_VERSION_2),
+        encoded: &[
+            0x56, 0x54, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x02,
+            0x06, 0x02, 0x6d, 0x73, 0x67, 0x2d, 0x69, 0x64, 0x34, 0x32, 0x68, 0x69, 0xb7, 0x14,
+            0x42, 0xdf,
+        ],
+    },
+    TestVector {
+        name: "v1 empty PING with a SHA-256 trailer",
+        frame: || sha256(ping(VSTP_VERSION)),
+        encoded: &[
+            0x56, 0x54, 0x01, 0x04, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x02, 0xc3,
+            0x16, 0x51, 0xee, 0x82, 0x3a, 0x11, 0xda, 0xd7, 0x6e, 0xee, 0xf4, 0x98, 0xc0,
+        ],
+    },
+    TestVector {
+        name: "v1 DATA with a header, payload and SHA-256 trailer",
+        frame: || sha256(data(VSTP_VERSION)),
+        encoded: &[
+            0x56, 0x54, 0x01, 0x03, 0x40, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x02, 0x06, 0x02, 0x6d,
+            0x73, 0x67, 0x2d, 0x69, 0x64, 0x34, 0x32, 0x68, 0x69, 0x00, 0x35, 0xf0, 0xbb, 0x39,
+            0xed, 0x76, 0x6e, 0x62, 0x2e, 0xdb, 0x70, 0xc7, 0xd1, 0x89, 0xe5,
+        ],
+    },
+];
+
+fn sha256(mut frame: Frame) -> Frame {
+    frame.flags |= Flags::SHA256;
+    frame
+}
+
+fn ping(version: u8) -> Frame {
+    let mut frame = Frame::new(FrameType::Ping);
+    frame.version = version;
+    frame
+}
+
+fn data(version: u8) -> Frame {
+    let mut frame = Frame::new(FrameType::Data)
+        .with_header("msg-id", "42")
+        .with_payload(b"hi".to_vec());
+    frame.version = version;
+    frame
+}
@@ -1,13 +1,273 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use bitflags::bitflags;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
+use tokio::time::Instant;
 
 /// VSTP protocol constants
 pub const VSTP_MAGIC: [u8; 2] = [0x56, 0x54]; // "VT"
 pub const VSTP_VERSION: u8 = 0x01;
+/// Frame format with big-endian u32 lengths, negotiated per connection
+pub const VSTP_VERSION_2: u8 = 0x02;
 
 /// Session identifier for tracking connections
 pub type SessionId = u128;
 
+/// Header carrying the machine-readable code of an ERR frame
+pub const ERROR_CODE_HEADER: &str = "error-code";
+
+/// Header on an ERR frame telling the sender how many milliseconds to wait
+/// before trying again, see [`Frame::retry_after`]
+pub const RETRY_AFTER_MS_HEADER: &str = "retry-after-ms";
+
+/// Header carrying the numeric value of an ERR frame's [`ErrorCode`]
+pub const ERROR_NUMBER_HEADER: &str = "error-num";
+
+/// Most headers a frame may carry unless configured otherwise, see
+/// [`Frame::check_header_count`]
+pub const DEFAULT_MAX_HEADERS: usize = 64;
+
+/// Error codes carried in the `error-code` header of ERR frames
+///
+/// These are the names of the crate-defined [`ErrorCode`]s.
+pub mod error_codes {
+    /// The HELLO carried a missing or wrong auth token
+    pub const UNAUTHORIZED: &str = "Unauthorized";
+    /// The HELLO asked for a protocol version the server doesn't speak
+    pub const UNSUPPORTED_VERSION: &str = "UnsupportedVersion";
+    /// A handler didn't finish within the server's handler timeout
+    pub const DEADLINE_EXCEEDED: &str = "DeadlineExceeded";
+    /// A datagram didn't start with the VSTP magic bytes
+    pub const BAD_MAGIC: &str = "BadMagic";
+    /// A frame failed its CRC check
+    pub const BAD_CRC: &str = "BadCrc";
+    /// A frame was larger than the receiver accepts
+    pub const FRAME_TOO_LARGE: &str = "FrameTooLarge";
+    /// A frame could not be decoded for any other reason
+    pub const MALFORMED_FRAME: &str = "MalformedFrame";
+    /// A request named no method, or one the server has no route for
+    pub const UNKNOWN_METHOD: &str = "UnknownMethod";
+    /// A handler returned an error instead of a response
+    pub const HANDLER_FAILED: &str = "HandlerFailed";
+    /// The HELLO named a service the server doesn't offer
+    pub const SERVICE_NOT_FOUND: &str = "ServiceNotFound";
+    /// The sender is over a rate limit and should back off
+    pub const RATE_LIMITED: &str = "RateLimited";
+    /// The receiver failed in a way that isn't the sender's fault
+    pub const INTERNAL: &str = "Internal";
+    /// A frame broke the receiver's [`IngressPolicy`](crate::ingress::IngressPolicy)
+    pub const POLICY_VIOLATION: &str = "PolicyViolation";
+    /// The sender used up its [`Quota`](crate::usage::Quota) for the current period
+    pub const QUOTA_EXCEEDED: &str = "QuotaExceeded";
+    /// A frame of a type the receiver doesn't accept
+    pub const BAD_REQUEST: &str = "BadRequest";
+    /// The receiver is shutting down and takes no new requests
+    pub const SHUTTING_DOWN: &str = "ShuttingDown";
+    /// A typed request's schema version isn't one the route serves
+    pub const SCHEMA_MISMATCH: &str = "SchemaMismatch";
+    /// A request reused the idempotency key of a different request
+    pub const IDEMPOTENCY_KEY_REUSED: &str = "IdempotencyKeyReused";
+    /// The session moved to the server instance in the `redirect-to` header
+    pub const REDIRECT: &str = "Redirect";
+    /// The sender's identity may not do what it asked
+    pub const FORBIDDEN: &str = "Forbidden";
+}
+
+/// Standard ERR codes with stable numeric values
+///
+/// Numbers `1..=999` are reserved for codes defined by this crate, which
+/// never change meaning; `1000..=65535` are free for applications, see
+/// [`ErrorCode::User`]. ERR frames built with [`Frame::coded_error`] carry the
+/// name in `error-code` and the number in `error-num`, so peers that only
+/// know the names keep working.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The HELLO carried a missing or wrong auth token
+    Unauthorized,
+    /// The HELLO asked for a protocol version the server doesn't speak
+    UnsupportedVersion,
+    /// A handler didn't finish within the server's handler timeout
+    DeadlineExceeded,
+    /// A datagram didn't start with the VSTP magic bytes
+    BadMagic,
+    /// A frame failed its CRC check
+    BadCrc,
+    /// A frame was larger than the receiver accepts
+    FrameTooLarge,
+    /// A frame could not be decoded for any other reason
+    MalformedFrame,
+    /// A request named no method, or one the server has no route for
+    UnknownMethod,
+    /// A handler returned an error instead of a response
+    HandlerFailed,
+    /// The HELLO named a service the server doesn't offer
+    ServiceNotFound,
+    /// The sender is over a rate limit and should back off
+    RateLimited,
+    /// The receiver failed in a way that isn't the sender's fault
+    Internal,
+    /// A frame broke the receiver's [`IngressPolicy`](crate::ingress::IngressPolicy)
+    PolicyViolation,
+    /// The sender used up its [`Quota`](crate::usage::Quota) for the current
+    /// period, or its session's [`SessionQuota`](crate::tcp::quota::SessionQuota)
+    QuotaExceeded,
+    /// A frame of a type the receiver doesn't accept
+    BadRequest,
+    /// The receiver is shutting down and takes no new requests
+    ShuttingDown,
+    /// A typed request's schema version isn't one the route serves, see
+    /// [`schema`](crate::schema)
+    SchemaMismatch,
+    /// A request reused the idempotency key of a different request, see
+    /// [`idempotency`](crate::idempotency)
+    IdempotencyKeyReused,
+    /// The session moved to another server instance, see
+    /// [`migration`](crate::migration)
+    Redirect,
+    /// The sender's identity may not do what it asked, e.g. a handler
+    /// returned [`VstpError::Forbidden`]
+    Forbidden,
+    /// An application-defined code, at least [`ErrorCode::USER_MIN`]
+    User(u16),
+}
+
+impl ErrorCode {
+    /// Lowest number available to [`ErrorCode::User`]
+    pub const USER_MIN: u16 = 1000;
+
+    /// Every crate-defined code
+    pub const STANDARD: [ErrorCode; 20] = [
+        ErrorCode::Unauthorized,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::DeadlineExceeded,
+        ErrorCode::BadMagic,
+        ErrorCode::BadCrc,
+        ErrorCode::FrameTooLarge,
+        ErrorCode::MalformedFrame,
+        ErrorCode::UnknownMethod,
+        ErrorCode::HandlerFailed,
+        ErrorCode::ServiceNotFound,
+        ErrorCode::RateLimited,
+        ErrorCode::Internal,
+        ErrorCode::PolicyViolation,
+        ErrorCode::QuotaExceeded,
+        ErrorCode::BadRequest,
+        ErrorCode::ShuttingDown,
+        ErrorCode::SchemaMismatch,
+        ErrorCode::IdempotencyKeyReused,
+        ErrorCode::Redirect,
+        ErrorCode::Forbidden,
+    ];
+
+    /// An application-defined code; `None` if `number` is in the reserved range
+    pub fn user(number: u16) -> Option<Self> {
+        (number >= Self::USER_MIN).then_some(ErrorCode::User(number))
+    }
+
+    /// The code's stable number
+    pub fn number(self) -> u16 {
+        match self {
+            ErrorCode::Unauthorized => 1,
+            ErrorCode::UnsupportedVersion => 2,
+            ErrorCode::DeadlineExceeded => 3,
+            ErrorCode::BadMagic => 4,
+            ErrorCode::BadCrc => 5,
+            ErrorCode::FrameTooLarge => 6,
+            ErrorCode::MalformedFrame => 7,
+            ErrorCode::UnknownMethod => 8,
+            ErrorCode::HandlerFailed => 9,
+            ErrorCode::ServiceNotFound => 10,
+            ErrorCode::RateLimited => 11,
+            ErrorCode::Internal => 12,
+            ErrorCode::PolicyViolation => 13,
+            ErrorCode::QuotaExceeded => 14,
+            ErrorCode::BadRequest => 15,
+            ErrorCode::ShuttingDown => 16,
+            ErrorCode::SchemaMismatch => 17,
+            ErrorCode::IdempotencyKeyReused => 18,
+            ErrorCode::Redirect => 19,
+            ErrorCode::Forbidden => 20,
+            ErrorCode::User(number) => number,
+        }
+    }
+
+    /// The code with number `number`, if it is a crate-defined or user code
+    pub fn from_number(number: u16) -> Option<Self> {
+        if number >= Self::USER_MIN {
+            return Some(ErrorCode::User(number));
+        }
+        Self::STANDARD
+            .into_iter()
+            .find(|code| code.number() == number)
+    }
+
+    /// The name sent in the `error-code` header; `None` for user codes
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            ErrorCode::Unauthorized => error_codes::UNAUTHORIZED,
+            ErrorCode::UnsupportedVersion => error_codes::UNSUPPORTED_VERSION,
+            ErrorCode::DeadlineExceeded => error_codes::DEADLINE_EXCEEDED,
+            ErrorCode::BadMagic => error_codes::BAD_MAGIC,
+            ErrorCode::BadCrc => error_codes::BAD_CRC,
+            ErrorCode::FrameTooLarge => error_codes::FRAME_TOO_LARGE,
+            ErrorCode::MalformedFrame => error_codes::MALFORMED_FRAME,
+            ErrorCode::UnknownMethod => error_codes::UNKNOWN_METHOD,
+            ErrorCode::HandlerFailed => error_codes::HANDLER_FAILED,
+            ErrorCode::ServiceNotFound => error_codes::SERVICE_NOT_FOUND,
+            ErrorCode::RateLimited => error_codes::RATE_LIMITED,
+            ErrorCode::Internal => error_codes::INTERNAL,
+            ErrorCode::PolicyViolation => error_codes::POLICY_VIOLATION,
+            ErrorCode::QuotaExceeded => error_codes::QUOTA_EXCEEDED,
+            ErrorCode::BadRequest => error_codes::BAD_REQUEST,
+            ErrorCode::ShuttingDown => error_codes::SHUTTING_DOWN,
+            ErrorCode::SchemaMismatch => error_codes::SCHEMA_MISMATCH,
+            ErrorCode::IdempotencyKeyReused => error_codes::IDEMPOTENCY_KEY_REUSED,
+            ErrorCode::Redirect => error_codes::REDIRECT,
+            ErrorCode::Forbidden => error_codes::FORBIDDEN,
+            ErrorCode::User(_) => return None,
+        })
+    }
+
+    /// The crate-defined code called `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::STANDARD
+            .into_iter()
+            .find(|code| code.name() == Some(name))
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    /// The name, or the number for user codes
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{}", self.number()),
+        }
+    }
+}
+
+/// Header carrying a frame's scheduling priority (`0`..`3`)
+pub const PRIORITY_HEADER: &str = "priority";
+
+/// Header carrying a frame's time-to-live in milliseconds
+pub const TTL_MS_HEADER: &str = "ttl-ms";
+
+/// Header carrying a frame's absolute expiry as unix milliseconds
+pub const EXPIRES_AT_MS_HEADER: &str = "expires-at-ms";
+
+/// Headers [`Frame::content_hash`] leaves out, as they differ between sends
+/// of the same content: trace context, per-call and per-message ids, and
+/// the absolute expiry
+pub const VOLATILE_HEADERS: &[&str] = &[
+    "traceparent",
+    "tracestate",
+    "msg-id",
+    "call-id",
+    "idempotency-key",
+    EXPIRES_AT_MS_HEADER,
+];
+
 /// Header key-value pair
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Header {
@@ -29,20 +289,51 @@ impl Header {
 }
 
 /// VSTP frame types
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// Type bytes VSTP doesn't define decode as [`FrameType::Extension`] and
+/// encode back to the same byte, so a relay passes on frames of extensions
+/// and newer dialects untouched. Servers that want none of them can list
+/// the types they take in
+/// [`TcpServerConfig::allowed_frame_types`](crate::tcp::TcpServerConfig::allowed_frame_types).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FrameType {
-    Hello = 0x01,
-    Welcome = 0x02,
-    Data = 0x03,
-    Ping = 0x04,
-    Pong = 0x05,
-    Bye = 0x06,
-    Ack = 0x07,
-    Err = 0x08,
+    Hello,
+    Welcome,
+    Data,
+    Ping,
+    Pong,
+    Bye,
+    Ack,
+    Err,
+    /// A type byte VSTP doesn't define, e.g. an extension type from 0x80 up
+    Extension(u8),
 }
 
 impl FrameType {
+    /// Lowest type byte reserved for extensions
+    pub const EXTENSION_MIN: u8 = 0x80;
+
+    /// The type with byte `value`, including extension types
+    pub fn from_byte(value: u8) -> Self {
+        Self::from_u8(value).unwrap_or(FrameType::Extension(value))
+    }
+
+    /// The type byte on the wire
+    pub fn to_u8(self) -> u8 {
+        match self {
+            FrameType::Hello => 0x01,
+            FrameType::Welcome => 0x02,
+            FrameType::Data => 0x03,
+            FrameType::Ping => 0x04,
+            FrameType::Pong => 0x05,
+            FrameType::Bye => 0x06,
+            FrameType::Ack => 0x07,
+            FrameType::Err => 0x08,
+            FrameType::Extension(value) => value,
+        }
+    }
+
+    /// The type VSTP defines with byte `value`; `None` for any other byte
     pub fn from_u8(value: u8) -> Option<Self> {
         match value {
             0x01 => Some(FrameType::Hello),
@@ -58,6 +349,41 @@ impl FrameType {
     }
 }
 
+/// Scheduling priority of a frame, `P0` being the most urgent
+///
+/// Frames without a `priority` header are treated as [`Priority::P2`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    P0 = 0,
+    P1 = 1,
+    #[default]
+    P2 = 2,
+    P3 = 3,
+}
+
+impl Priority {
+    /// Parse a `priority` header value
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value.trim() {
+            "0" => Some(Priority::P0),
+            "1" => Some(Priority::P1),
+            "2" => Some(Priority::P2),
+            "3" => Some(Priority::P3),
+            _ => None,
+        }
+    }
+
+    /// Value used in the `priority` header
+    pub fn as_header(self) -> &'static str {
+        match self {
+            Priority::P0 => "0",
+            Priority::P1 => "1",
+            Priority::P2 => "2",
+            Priority::P3 => "3",
+        }
+    }
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Flags: u8 {
@@ -65,6 +391,57 @@ bitflags! {
         const CRC     = 0b0000_0010;
         const FRAG    = 0b0001_0000;
         const COMP    = 0b0010_0000;
+        /// The trailer is a truncated SHA-256 digest instead of a CRC-32, see [`Integrity`](crate::Integrity)
+        const SHA256  = 0b0100_0000;
+    }
+}
+
+impl Flags {
+    /// Every flag this version of the protocol defines
+    pub const fn all_known() -> Self {
+        Self::all()
+    }
+
+    /// Whether the sender asked for an ACK
+    pub fn is_reliable(self) -> bool {
+        self.contains(Flags::REQ_ACK)
+    }
+
+    /// Whether the payload is compressed
+    pub fn is_compressed(self) -> bool {
+        self.contains(Flags::COMP)
+    }
+
+    /// Whether the CRC flag is set
+    pub fn is_checked(self) -> bool {
+        self.contains(Flags::CRC)
+    }
+
+    /// Flags from their names, e.g. `["REQ_ACK", "CRC"]`
+    ///
+    /// Fails on a name that isn't a flag.
+    pub fn from_names(names: &[&str]) -> Result<Self, VstpError> {
+        names.iter().try_fold(Flags::empty(), |flags, name| {
+            Flags::from_name(name)
+                .map(|flag| flags | flag)
+                .ok_or_else(|| VstpError::Protocol(format!("unknown flag {:?}", name)))
+        })
+    }
+}
+
+impl std::fmt::Display for Flags {
+    /// Flag names joined by ` | `, e.g. `REQ_ACK | CRC`; unknown bits in hex
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        bitflags::parser::to_writer(self, f)
+    }
+}
+
+impl std::str::FromStr for Flags {
+    type Err = VstpError;
+
+    /// Parse what [`Display`](std::fmt::Display) writes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        bitflags::parser::from_str(s).map_err(|e| VstpError::Protocol(format!("bad flags: {}", e)))
     }
 }
 
@@ -89,16 +466,96 @@ impl Frame {
         }
     }
 
+    /// ERR frame with a machine-readable code and a human-readable message
+    pub fn error(code: &str, message: &str) -> Self {
+        Frame::new(FrameType::Err)
+            .with_header(ERROR_CODE_HEADER, code)
+            .with_payload(format!("{}: {}", code, message).into_bytes())
+    }
+
+    /// ERR frame for a standard or user-defined [`ErrorCode`]
+    pub fn coded_error(code: ErrorCode, message: &str) -> Self {
+        Frame::error(&code.to_string(), message)
+            .with_header(ERROR_NUMBER_HEADER, &code.number().to_string())
+    }
+
+    /// The [`ErrorCode`] of an ERR frame, by number or else by name
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        if self.typ != FrameType::Err {
+            return None;
+        }
+        match self.get_header(ERROR_NUMBER_HEADER) {
+            Some(number) => number.parse().ok().and_then(ErrorCode::from_number),
+            None => ErrorCode::from_name(self.get_header(ERROR_CODE_HEADER)?),
+        }
+    }
+
+    /// Ask the receiver of this ERR frame to wait `delay` before trying again
+    pub fn with_retry_after(self, delay: Duration) -> Self {
+        self.with_header(RETRY_AFTER_MS_HEADER, &delay.as_millis().to_string())
+    }
+
+    /// How long the sender of this ERR frame asked to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        if self.typ != FrameType::Err {
+            return None;
+        }
+        let ms = self.get_header(RETRY_AFTER_MS_HEADER)?.trim().parse().ok()?;
+        Some(Duration::from_millis(ms))
+    }
+
     pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
         self.payload = payload;
         self
     }
 
+    /// Set a UTF-8 text payload
+    pub fn with_payload_text(mut self, text: &str) -> Self {
+        self.payload = text.as_bytes().to_vec();
+        self
+    }
+
     pub fn with_header(mut self, key: &str, value: &str) -> Self {
         self.headers.push(Header::from_str(key, value));
         self
     }
 
+    /// Add a header with a raw binary value (need not be valid UTF-8)
+    pub fn with_binary_header(mut self, key: &str, value: &[u8]) -> Self {
+        self.headers
+            .push(Header::new(key.as_bytes().to_vec(), value.to_vec()));
+        self
+    }
+
+    /// Give the frame a time-to-live, after which it should be dropped rather than delivered
+    ///
+    /// Sets both `ttl-ms` and `expires-at-ms`, replacing any existing values.
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.with_ttl_at(ttl, SystemTime::now())
+    }
+
+    /// Like [`with_ttl`](Frame::with_ttl), counting the absolute expiry from `now`
+    ///
+    /// Pass an estimate of the receiver's clock, such as
+    /// [`ClockSync::estimated_server_time`](crate::clock::ClockSync::estimated_server_time),
+    /// when the local clock can't be trusted.
+    pub fn with_ttl_at(mut self, ttl: Duration, now: SystemTime) -> Self {
+        let expires_at = now + ttl;
+        let expires_at_ms = expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.set_header(TTL_MS_HEADER, &ttl.as_millis().to_string());
+        self.set_header(EXPIRES_AT_MS_HEADER, &expires_at_ms.to_string());
+        self
+    }
+
+    /// Set the frame's scheduling priority
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.set_header(PRIORITY_HEADER, priority.as_header());
+        self
+    }
+
     pub fn with_flag(mut self, flag: Flags) -> Self {
         self.flags |= flag;
         self
@@ -108,6 +565,108 @@ impl Frame {
         &self.payload
     }
 
+    /// Payload as UTF-8 text
+    pub fn payload_text(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.payload)
+    }
+
+    /// Move the payload out, leaving the frame with an empty one
+    pub fn take_payload(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.payload)
+    }
+
+    /// Move the headers out, leaving the frame with none
+    pub fn take_headers(&mut self) -> Vec<Header> {
+        std::mem::take(&mut self.headers)
+    }
+
+    /// Sort the headers by key, bytewise, so that frames differing only in
+    /// header order become equal
+    ///
+    /// Headers with the same key are sorted by value, bytewise, so the order
+    /// they were added in is lost: [`get_header`](Frame::get_header) may
+    /// find a different one afterwards. Nothing else changes. See
+    /// [`encode_frame_canonical`](crate::encode_frame_canonical).
+    pub fn canonicalize(&mut self) {
+        self.headers
+            .sort_by(|a, b| a.key.cmp(&b.key).then_with(|| a.value.cmp(&b.value)));
+    }
+
+    /// SHA-256 of the frame's canonical encoding without its
+    /// [`VOLATILE_HEADERS`], for recognizing the same content sent again
+    ///
+    /// Fails if the frame can't be encoded.
+    pub fn content_hash(&self) -> Result<[u8; 32], VstpError> {
+        self.content_hash_excluding(VOLATILE_HEADERS)
+    }
+
+    /// SHA-256 of the frame's canonical encoding without the headers keyed
+    /// `excluded`
+    pub fn content_hash_excluding(&self, excluded: &[&str]) -> Result<[u8; 32], VstpError> {
+        let mut frame = self.clone();
+        frame
+            .headers
+            .retain(|h| !excluded.iter().any(|key| h.key == key.as_bytes()));
+        let encoded = crate::frame::encode_frame_canonical(&frame)?;
+        Ok(Sha256::digest(&encoded).into())
+    }
+
+    /// Fail with [`VstpError::TooManyHeaders`] if the frame carries more than `limit` headers
+    ///
+    /// Peers may cap the number of headers, e.g. with
+    /// [`IngressPolicy::max_headers`](crate::ingress::IngressPolicy::max_headers);
+    /// checking before sending turns their rejection into a local error.
+    pub fn check_header_count(&self, limit: usize) -> Result<(), VstpError> {
+        if self.headers.len() > limit {
+            return Err(VstpError::TooManyHeaders {
+                count: self.headers.len(),
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Bytes of header keys and values the frame carries
+    pub fn header_bytes(&self) -> usize {
+        self.headers
+            .iter()
+            .map(|h| h.key.len() + h.value.len())
+            .sum()
+    }
+
+    /// [`header_bytes`](Frame::header_bytes) per payload byte, counting an
+    /// empty payload as one byte
+    pub fn header_to_payload_ratio(&self) -> f64 {
+        self.header_bytes() as f64 / self.payload.len().max(1) as f64
+    }
+
+    /// Check that the payload matches the declared `content-type`
+    ///
+    /// JSON types (`application/json` and `+json` suffixes) must parse as
+    /// JSON, and `text/*` must be UTF-8. Other or missing content types are
+    /// accepted as-is. Nothing calls this implicitly; run it before sending
+    /// if you want malformed payloads caught early.
+    pub fn validate(&self) -> Result<(), VstpError> {
+        let Some(content_type) = self.get_header("content-type") else {
+            return Ok(());
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if mime == "application/json" || mime.ends_with("+json") {
+            serde_json::from_slice::<serde::de::IgnoredAny>(&self.payload)
+                .map_err(|e| VstpError::InvalidPayload(format!("{}: {}", mime, e)))?;
+        } else if mime.starts_with("text/") {
+            self.payload_text()
+                .map_err(|e| VstpError::InvalidPayload(format!("{}: {}", mime, e)))?;
+        }
+        Ok(())
+    }
+
     pub fn frame_type(&self) -> FrameType {
         self.typ
     }
@@ -118,6 +677,141 @@ impl Frame {
             .find(|h| h.key == key_bytes)
             .and_then(|h| std::str::from_utf8(&h.value).ok())
     }
+
+    /// Get the raw bytes of a header value, whether or not it is UTF-8
+    pub fn get_binary_header(&self, key: &str) -> Option<&[u8]> {
+        let key_bytes = key.as_bytes();
+        self.headers
+            .iter()
+            .find(|h| h.key == key_bytes)
+            .map(|h| h.value.as_slice())
+    }
+
+    /// Scheduling priority from the `priority` header
+    pub fn priority(&self) -> Priority {
+        self.get_header(PRIORITY_HEADER)
+            .and_then(Priority::from_header)
+            .unwrap_or_default()
+    }
+
+    /// Time-to-live from the `ttl-ms` header
+    pub fn ttl(&self) -> Option<Duration> {
+        self.get_header(TTL_MS_HEADER)
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+    }
+
+    /// Absolute expiry from the `expires-at-ms` header
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.get_header(EXPIRES_AT_MS_HEADER)
+            .and_then(|v| v.parse().ok())
+            .map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+    }
+
+    /// Instant after which the frame is expired, given when it was received
+    ///
+    /// `ttl-ms` is counted from `received_at` and wins over `expires-at-ms`,
+    /// so clock skew between peers only matters for frames carrying the
+    /// absolute timestamp alone.
+    pub fn deadline(&self, received_at: Instant) -> Option<Instant> {
+        self.deadline_at(received_at, SystemTime::now())
+    }
+
+    /// Like [`deadline`](Frame::deadline), judging `expires-at-ms` against `now`
+    /// instead of the local clock
+    pub fn deadline_at(&self, received_at: Instant, now: SystemTime) -> Option<Instant> {
+        if let Some(ttl) = self.ttl() {
+            return Some(received_at + ttl);
+        }
+        let expires_at = self.expires_at()?;
+        let remaining = expires_at
+            .duration_since(now)
+            .unwrap_or(Duration::ZERO);
+        Some(Instant::now() + remaining)
+    }
+
+    /// Check whether the frame has outlived its TTL, given when it was received
+    pub fn is_expired(&self, received_at: Instant) -> bool {
+        self.deadline(received_at)
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Replace every header named `key` with a single new value
+    pub(crate) fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.retain(|h| h.key != key.as_bytes());
+        self.headers.push(Header::from_str(key, value));
+    }
+}
+
+/// Rejects frames whose headers dwarf their payload
+///
+/// Piling headers around a tiny payload is a common way to amplify or hide
+/// abusive traffic, and stays within absolute size limits. Frames with at
+/// most `min_header_bytes` of headers always pass, so small control frames
+/// like PING or HELLO aren't caught.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderRatioLimit {
+    /// Most header bytes allowed per payload byte
+    pub max_ratio: u32,
+    /// Header bytes a frame may carry whatever its payload
+    pub min_header_bytes: usize,
+}
+
+impl Default for HeaderRatioLimit {
+    fn default() -> Self {
+        Self {
+            max_ratio: 16,
+            min_header_bytes: 1024,
+        }
+    }
+}
+
+impl HeaderRatioLimit {
+    /// Fail with [`VstpError::SuspiciousFrame`] if `frame` breaks the limit
+    pub fn check(&self, frame: &Frame) -> Result<(), VstpError> {
+        let header_bytes = frame.header_bytes();
+        let payload_bytes = frame.payload.len();
+        let allowed = (payload_bytes.max(1)).saturating_mul(self.max_ratio as usize);
+        if header_bytes > self.min_header_bytes && header_bytes > allowed {
+            return Err(VstpError::SuspiciousFrame {
+                header_bytes,
+                payload_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Why a session ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer closed the connection
+    Closed,
+    /// The peer stopped answering liveness probes, or a probe could not be sent
+    Unreachable,
+    /// The connection failed with an error
+    Error(String),
+    /// The server closed the session after its maximum lifetime
+    MaxAge,
+    /// The server closed the session once it used up the named limit of its
+    /// [`SessionQuota`](crate::tcp::quota::SessionQuota)
+    QuotaExceeded(String),
+    /// The peer started a frame and didn't finish it within the server's
+    /// [`partial_frame_timeout`](crate::tcp::server::TcpServerConfig::partial_frame_timeout)
+    Stalled,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Closed => write!(f, "closed by peer"),
+            DisconnectReason::Unreachable => write!(f, "peer unreachable"),
+            DisconnectReason::Error(e) => write!(f, "error: {}", e),
+            DisconnectReason::MaxAge => write!(f, "maximum connection age reached"),
+            DisconnectReason::QuotaExceeded(limit) => write!(f, "session {} quota used up", limit),
+            DisconnectReason::Stalled => write!(f, "peer stalled mid-frame"),
+        }
+    }
 }
 
 /// VSTP error types
@@ -138,18 +832,46 @@ pub enum VstpError {
     #[error("Invalid magic bytes: expected {:?}, got {:?}", VSTP_MAGIC, .0)]
     InvalidMagic([u8; 2]),
 
+    /// The first bytes from a peer weren't VSTP; see [`diagnostics::sniff_protocol`](crate::diagnostics::sniff_protocol)
+    #[error(
+        "Peer doesn't speak VSTP{}: {received}",
+        .looks_like.map(|what| format!(", received what looks like {}", what)).unwrap_or_default()
+    )]
+    NotVstp {
+        looks_like: Option<&'static str>,
+        received: String,
+    },
+
     #[error("CRC mismatch: expected {expected}, got {got}")]
     CrcMismatch { expected: u32, got: u32 },
 
+    #[error("SHA-256 digest mismatch")]
+    DigestMismatch,
+
     #[error("Incomplete frame: need {needed} more bytes")]
     Incomplete { needed: usize },
 
     #[error("Frame too large: {size} bytes exceeds limit of {limit}")]
     FrameTooLarge { size: usize, limit: usize },
 
+    #[error("Message of {size} bytes exceeds the peer's limit of {limit}")]
+    TooLargeForPeer { size: usize, limit: usize },
+
     #[error("Operation timed out")]
     Timeout,
 
+    #[error("Connecting timed out after {after:?}")]
+    ConnectTimeout { after: Duration },
+
+    #[error("No reply to the HELLO within {after:?}")]
+    HandshakeTimeout { after: Duration },
+
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+
+    #[error("Frame expired before it could be delivered")]
+    Expired,
+
     #[error("Invalid address")]
     InvalidAddress,
 
@@ -167,4 +889,98 @@ pub enum VstpError {
 
     #[error("Server error: {0}")]
     ServerError(String),
+
+    #[error("Handshake rejected ({code}): {message}")]
+    HandshakeRejected { code: String, message: String },
+
+    #[error("Unknown compression dictionary {0}")]
+    UnknownDictionary(u32),
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("Server asked to hold off requests until {until:?}")]
+    Backoff { until: Instant },
+
+    #[error("Client is shut down")]
+    Closed,
+
+    #[error("{count} headers exceed the limit of {limit}")]
+    TooManyHeaders { count: usize, limit: usize },
+
+    #[error("{method} was sent as schema version {sent}, the server serves version {served}")]
+    SchemaMismatch {
+        method: String,
+        sent: u32,
+        served: u32,
+    },
+
+    #[error("Suspicious frame: {header_bytes} header bytes around a {payload_bytes} byte payload")]
+    SuspiciousFrame {
+        header_bytes: usize,
+        payload_bytes: usize,
+    },
+
+    /// A TLS configuration was unusable
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    /// The TLS handshake failed, e.g. because a certificate was refused or
+    /// the peer doesn't speak TLS
+    #[error("TLS handshake failed: {0}")]
+    TlsHandshake(std::io::Error),
+
+    /// The TLS handshake didn't complete in time
+    #[error("TLS handshake timed out after {after:?}")]
+    TlsHandshakeTimeout { after: Duration },
+
+    /// A sealed payload couldn't be opened, see [`e2e`](crate::e2e)
+    #[error("Cannot open sealed payload: {0}")]
+    Unsealing(String),
+
+    /// The server moved the session to another instance, see
+    /// [`migration`](crate::migration)
+    #[error("Session moved to {to}")]
+    Redirected { to: String },
+
+    /// The sender's identity may not do what it asked; a handler returning
+    /// it is answered with ERR `Forbidden`, which a client reports as it
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+}
+
+impl VstpError {
+    /// Whether retrying the same operation could succeed
+    ///
+    /// Rejections that depend only on what the client sent, such as a bad
+    /// auth token or an unsupported version, fail the same way every time.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            VstpError::HandshakeRejected { code, .. } => !matches!(
+                code.as_str(),
+                error_codes::UNAUTHORIZED
+                    | error_codes::UNSUPPORTED_VERSION
+                    | error_codes::SERVICE_NOT_FOUND
+            ),
+            VstpError::InvalidAddress
+            | VstpError::InvalidVersion { .. }
+            | VstpError::InvalidPayload(_)
+            | VstpError::FrameTooLarge { .. }
+            | VstpError::TooLargeForPeer { .. }
+            | VstpError::TooManyHeaders { .. }
+            | VstpError::SchemaMismatch { .. }
+            | VstpError::SuspiciousFrame { .. }
+            | VstpError::UnknownDictionary(_)
+            | VstpError::NotVstp { .. }
+            | VstpError::Tls(_)
+            | VstpError::Unsealing(_)
+            | VstpError::Forbidden(_)
+            | VstpError::Cancelled
+            | VstpError::Closed
+            | VstpError::Expired => false,
+            // TLS refusals come as `InvalidData`; anything else broke the connection
+            VstpError::TlsHandshake(e) => e.kind() != std::io::ErrorKind::InvalidData,
+            _ => true,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,335 @@
+//! Coalescing the ACKs a UDP server sends
+//!
+//! A peer flooding a server with `REQ_ACK` frames would get one ACK datagram
+//! back per frame, doubling the packet rate. With
+//! [`UdpServerConfig::ack_delay`](crate::udp::UdpServerConfig::ack_delay) set,
+//! the server holds each peer's ACKs back and sends them together as one ACK
+//! frame listing their ids in a [`MSG_IDS_HEADER`]. A peer's pending ACKs go
+//! out as soon as any of these happens:
+//!
+//! - the server has read every datagram waiting on its socket, so a peer
+//!   sending one frame at a time is ACKed as fast as before, and only bursts
+//!   are batched
+//! - `ack_delay` has passed since the first of them was held back, e.g.
+//!   while the application is busy with a frame instead of receiving
+//! - [`max_ack_batch`](crate::udp::UdpServerConfig::max_ack_batch) are pending
+//! - a frame with priority [`P0`](crate::types::Priority::P0) arrives; it is
+//!   ACKed at once, together with anything pending for the same peer
+//!
+//! A batch of one is sent as a plain ACK with a `msg-id` header, so peers
+//! that don't know the batched form still see their ACKs unless they burst.
+//!
+//! # Retransmission timeouts
+//!
+//! A held-back ACK reaches its sender up to `ack_delay` later, which counts
+//! against the sender's ACK timeout: keep `ack_delay` well below the
+//! shortest timeout clients use, or they retransmit frames the server
+//! already has. The default of 5 ms is a tenth of the 50 ms
+//! [`min_rto`](crate::udp::client::UdpConfig::min_rto) floor of
+//! [`VstpUdpClient`](crate::udp::VstpUdpClient). The delay also shows up in
+//! the client's round-trip estimate, which only raises its timeout further.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::codec::WireTap;
+use crate::frame::{encode_ack_into, encode_frame, ACK_FRAME_MAX_LEN};
+use crate::shaping::SendShaper;
+use crate::types::{Frame, FrameType, VstpError};
+use crate::udp::server::{send_from, AckSource, OBSERVED_ADDR_HEADER};
+
+/// Header on a batched ACK listing the `msg-id`s it acknowledges
+///
+/// Comma separated ids and inclusive ranges of consecutive ones, in
+/// ascending order, e.g. `3-7,9,12-13`; see [`encode_msg_ids`].
+pub const MSG_IDS_HEADER: &str = "msg-ids";
+
+/// Write `ids` in the [`MSG_IDS_HEADER`] format, collapsing runs into ranges
+pub fn encode_msg_ids(ids: &[u64]) -> String {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut encoded = String::new();
+    let mut rest = ids.as_slice();
+    while let Some(&start) = rest.first() {
+        let run = 1 + rest
+            .windows(2)
+            .take_while(|pair| pair[0].checked_add(1) == Some(pair[1]))
+            .count();
+        if !encoded.is_empty() {
+            encoded.push(',');
+        }
+        encoded.push_str(&start.to_string());
+        if run > 1 {
+            encoded.push('-');
+            encoded.push_str(&rest[run - 1].to_string());
+        }
+        rest = &rest[run..];
+    }
+    encoded
+}
+
+/// Parse a [`MSG_IDS_HEADER`] value into the ranges of ids it lists
+///
+/// `None` if any part of it is malformed.
+pub fn decode_msg_ids(value: &str) -> Option<Vec<RangeInclusive<u64>>> {
+    value
+        .split(',')
+        .map(|part| match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+                (start <= end).then_some(start..=end)
+            }
+            None => part.trim().parse().ok().map(|id| id..=id),
+        })
+        .collect()
+}
+
+/// Whether `frame` is an ACK for `msg_id`, plain or batched
+pub fn acknowledges(frame: &Frame, msg_id: u64) -> bool {
+    if frame.typ != FrameType::Ack {
+        return false;
+    }
+    if frame.get_header("msg-id").and_then(|id| id.parse().ok()) == Some(msg_id) {
+        return true;
+    }
+    frame
+        .get_header(MSG_IDS_HEADER)
+        .and_then(decode_msg_ids)
+        .is_some_and(|ranges| ranges.iter().any(|range| range.contains(&msg_id)))
+}
+
+/// Writes ACK datagrams the way a server's config asks for
+#[derive(Clone)]
+pub(crate) struct AckWriter {
+    pub wire_tap: WireTap,
+    pub source: AckSource,
+    pub observed_addr: bool,
+    pub shaper: Option<Arc<SendShaper>>,
+}
+
+impl AckWriter {
+    /// The ACK frame for `ids` sent to `dest`, encoded
+    fn encode(&self, ids: &[u64], dest: SocketAddr) -> Result<Bytes, VstpError> {
+        let ack = Frame::new(FrameType::Ack);
+        let mut ack = match ids {
+            [msg_id] => ack.with_header("msg-id", &msg_id.to_string()),
+            _ => ack.with_header(MSG_IDS_HEADER, &encode_msg_ids(ids)),
+        };
+        if self.observed_addr {
+            ack = ack.with_header(OBSERVED_ADDR_HEADER, &dest.to_string());
+        }
+        encode_frame(&ack)
+    }
+
+    /// Send one ACK for `ids` to `dest`, from the address `source` picks
+    pub async fn send(
+        &self,
+        socket: &UdpSocket,
+        ids: &[u64],
+        dest: SocketAddr,
+        arrived_on: Option<IpAddr>,
+    ) -> Result<(), VstpError> {
+        // Plain ACKs are written into a stack buffer, as there can be one per frame
+        let mut plain = [0; ACK_FRAME_MAX_LEN];
+        let built;
+        let encoded: &[u8] = match ids {
+            [msg_id] if !self.observed_addr => {
+                let len = encode_ack_into(*msg_id, &mut plain);
+                &plain[..len]
+            }
+            _ => {
+                built = self.encode(ids, dest)?;
+                &built
+            }
+        };
+        self.wire_tap.wire_out(encoded);
+
+        if let Some(shaper) = &self.shaper {
+            shaper.acquire(encoded.len()).await;
+        }
+        let source = match self.source {
+            AckSource::Routing => None,
+            AckSource::ArrivalAddress => arrived_on,
+            AckSource::Address(addr) => Some(addr),
+        };
+        match source {
+            Some(source) => send_from(socket, encoded, dest, source).await,
+            None => {
+                socket.send_to(encoded, dest).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// ACKs held back for one peer
+struct Pending {
+    ids: Vec<u64>,
+    /// Local address the latest of the frames arrived on
+    arrived_on: Option<IpAddr>,
+}
+
+/// Holds back a server's ACKs and sends each peer's together
+///
+/// Sends on its own handle to the server's socket, so the timers flushing
+/// ACKs that waited `delay` don't keep the server alive. ACKs still held
+/// back when it is dropped are sent right away, from whichever address
+/// routing picks.
+pub(crate) struct AckBatcher {
+    socket: UdpSocket,
+    writer: AckWriter,
+    delay: Duration,
+    max_batch: usize,
+    pending: Mutex<HashMap<SocketAddr, Pending>>,
+}
+
+impl AckBatcher {
+    /// A batcher sending on a duplicate of `socket`
+    pub fn new(
+        socket: &UdpSocket,
+        writer: AckWriter,
+        delay: Duration,
+        max_batch: usize,
+    ) -> Result<Arc<Self>, VstpError> {
+        let duplicate: std::net::UdpSocket = socket2::SockRef::from(socket).try_clone()?.into();
+        Ok(Arc::new(Self {
+            socket: UdpSocket::from_std(duplicate)?,
+            writer,
+            delay,
+            max_batch: max_batch.max(1),
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Hold back the ACK for `msg_id` from `dest`, or send it along with
+    /// the rest of `dest`'s if that makes a full batch or it is `urgent`
+    pub async fn queue(
+        self: &Arc<Self>,
+        msg_id: u64,
+        dest: SocketAddr,
+        arrived_on: Option<IpAddr>,
+        urgent: bool,
+    ) -> Result<(), VstpError> {
+        let ready = {
+            let mut pending = self.pending.lock().unwrap();
+            let peer = pending.entry(dest).or_insert_with(|| Pending {
+                ids: Vec::new(),
+                arrived_on,
+            });
+            peer.ids.push(msg_id);
+            peer.arrived_on = arrived_on;
+            if urgent || peer.ids.len() >= self.max_batch {
+                pending.remove(&dest)
+            } else {
+                if peer.ids.len() == 1 {
+                    self.flush_later(dest);
+                }
+                None
+            }
+        };
+        match ready {
+            Some(peer) => self.send(dest, peer).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send every peer's pending ACKs
+    pub async fn flush(&self) {
+        let ready: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.is_empty() {
+                return;
+            }
+            pending.drain().collect()
+        };
+        for (dest, peer) in ready {
+            let _ = self.send(dest, peer).await;
+        }
+    }
+
+    /// Send `dest`'s pending ACKs once `delay` has passed
+    fn flush_later(self: &Arc<Self>, dest: SocketAddr) {
+        let batcher: Weak<Self> = Arc::downgrade(self);
+        let delay = self.delay;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(batcher) = batcher.upgrade() else {
+                return;
+            };
+            let peer = batcher.pending.lock().unwrap().remove(&dest);
+            if let Some(peer) = peer {
+                let _ = batcher.send(dest, peer).await;
+            }
+        });
+    }
+
+    async fn send(&self, dest: SocketAddr, peer: Pending) -> Result<(), VstpError> {
+        let result = self
+            .writer
+            .send(&self.socket, &peer.ids, dest, peer.arrived_on)
+            .await;
+        if let Err(e) = &result {
+            debug!("Failed to send {} ACKs to {}: {}", peer.ids.len(), dest, e);
+        }
+        result
+    }
+}
+
+impl Drop for AckBatcher {
+    fn drop(&mut self) {
+        let pending = std::mem::take(self.pending.get_mut().unwrap());
+        // Sent without going through the runtime, which may be shutting down
+        let socket = socket2::SockRef::from(&self.socket);
+        for (dest, peer) in pending {
+            let Ok(encoded) = self.writer.encode(&peer.ids, dest) else {
+                continue;
+            };
+            self.writer.wire_tap.wire_out(&encoded);
+            let _ = socket.send_to(&encoded, &dest.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msg_ids_collapse_into_ranges() {
+        assert_eq!(
+            encode_msg_ids(&[9, 3, 4, 5, 6, 7, 13, 12, 5]),
+            "3-7,9,12-13"
+        );
+        assert_eq!(encode_msg_ids(&[u64::MAX, 1]), "1,18446744073709551615");
+        assert_eq!(encode_msg_ids(&[]), "");
+    }
+
+    #[test]
+    fn test_msg_ids_round_trip() {
+        let ranges = decode_msg_ids("3-7,9,12-13").unwrap();
+        assert_eq!(ranges, vec![3..=7, 9..=9, 12..=13]);
+        assert_eq!(decode_msg_ids("7-3"), None);
+        assert_eq!(decode_msg_ids("1,x"), None);
+    }
+
+    #[test]
+    fn test_acknowledges_plain_and_batched_acks() {
+        let plain = Frame::new(FrameType::Ack).with_header("msg-id", "4");
+        let batched = Frame::new(FrameType::Ack).with_header(MSG_IDS_HEADER, "1-3,8");
+        assert!(acknowledges(&plain, 4));
+        assert!(!acknowledges(&plain, 3));
+        assert!(acknowledges(&batched, 2));
+        assert!(acknowledges(&batched, 8));
+        assert!(!acknowledges(&batched, 4));
+        let data = Frame::new(FrameType::Data).with_header("msg-id", "4");
+        assert!(!acknowledges(&data, 4));
+    }
+}
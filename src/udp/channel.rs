@@ -0,0 +1,353 @@
+//! Ordered, exactly-once delivery over UDP
+//!
+//! [`ReliableChannel`] numbers each frame it sends and keeps retransmitting
+//! it until the peer confirms the frame was *processed*, not merely received.
+//! On the server, [`ReliableReceiver`] hands frames to the handler strictly in
+//! sequence order, holds back frames that arrive ahead of a gap, and records
+//! the last processed sequence number in a [`SeqStore`]. Retransmissions of
+//! frames that were already processed are acknowledged again but never
+//! re-applied, including after a server restart if the store is persistent.
+//!
+//! ## Headers
+//!
+//! - `channel-id`: identifies the channel; the store is keyed by it
+//! - `channel-seq`: sequence number of a DATA frame, starting at 1
+//! - `processed`: on an ACK, the highest sequence number the handler has
+//!   finished with; it acknowledges every frame up to and including it
+//!
+//! A sender that restarts from sequence 1 must use a new channel id, or its
+//! frames are taken for duplicates.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::{debug, warn};
+
+use crate::types::{Frame, FrameType, VstpError};
+use crate::udp::{VstpUdpClient, VstpUdpServer};
+
+/// Header carrying the channel identifier
+pub const CHANNEL_ID_HEADER: &str = "channel-id";
+
+/// Header carrying a frame's position in the channel, starting at 1
+pub const CHANNEL_SEQ_HEADER: &str = "channel-seq";
+
+/// Header carrying the highest processed sequence number on an ACK
+pub const PROCESSED_HEADER: &str = "processed";
+
+/// Frames held back per channel while waiting for a gap to fill
+const MAX_BUFFERED_PER_CHANNEL: usize = 256;
+
+/// Configuration for a [`ReliableChannel`]
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    /// Identifier the receiver tracks this channel's progress under
+    pub channel_id: String,
+    /// How often unprocessed frames are retransmitted
+    pub retry_interval: Duration,
+    /// How long a frame may stay unprocessed before the channel fails
+    pub delivery_timeout: Duration,
+    /// Frames sent but not yet processed; further sends wait their turn
+    pub max_in_flight: usize,
+}
+
+impl ChannelConfig {
+    pub fn new(channel_id: impl Into<String>) -> Self {
+        Self {
+            channel_id: channel_id.into(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ChannelConfig {
+    /// Defaults with a random channel id
+    fn default() -> Self {
+        Self {
+            channel_id: format!("{:016x}", rand::random::<u64>()),
+            retry_interval: Duration::from_millis(200),
+            delivery_timeout: Duration::from_secs(10),
+            max_in_flight: 64,
+        }
+    }
+}
+
+/// Where a [`ReliableReceiver`] keeps the last processed sequence number of each channel
+///
+/// Back it with durable storage to survive restarts.
+pub trait SeqStore: Send + Sync {
+    /// Last processed sequence number, or 0 if the channel is new
+    fn load(&self, channel_id: &str) -> Result<u64, VstpError>;
+
+    /// Record that `seq` has been processed
+    fn store(&self, channel_id: &str, seq: u64) -> Result<(), VstpError>;
+}
+
+/// In-memory [`SeqStore`], which only survives restarts of the receiver, not of the process
+#[derive(Debug, Default)]
+pub struct MemorySeqStore {
+    seqs: Mutex<HashMap<String, u64>>,
+}
+
+impl SeqStore for MemorySeqStore {
+    fn load(&self, channel_id: &str) -> Result<u64, VstpError> {
+        Ok(self.seqs.lock().unwrap().get(channel_id).copied().unwrap_or(0))
+    }
+
+    fn store(&self, channel_id: &str, seq: u64) -> Result<(), VstpError> {
+        self.seqs.lock().unwrap().insert(channel_id.to_string(), seq);
+        Ok(())
+    }
+}
+
+type Outcome = oneshot::Sender<Result<(), VstpError>>;
+
+/// Completion of a frame sent through a [`ReliableChannel`]
+///
+/// Resolves once the receiver's handler has processed the frame, or with
+/// [`VstpError::Timeout`] if the channel failed first.
+pub struct Delivery {
+    rx: oneshot::Receiver<Result<(), VstpError>>,
+}
+
+impl Future for Delivery {
+    type Output = Result<(), VstpError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|outcome| outcome.unwrap_or(Err(VstpError::ConnectionClosed)))
+    }
+}
+
+/// Sending half of an ordered, exactly-once channel
+///
+/// The channel takes over the client; frames other than the receiver's
+/// `processed` ACKs are discarded. Once a frame goes unprocessed for
+/// `delivery_timeout`, it and every later frame fail with
+/// [`VstpError::Timeout`].
+pub struct ReliableChannel {
+    tx: mpsc::UnboundedSender<(Frame, Outcome)>,
+}
+
+impl ReliableChannel {
+    pub fn new(client: VstpUdpClient, dest: SocketAddr, config: ChannelConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(drive_channel(client, dest, config, rx));
+        Self { tx }
+    }
+
+    /// Queue a frame for delivery
+    ///
+    /// Frames are processed in the order they are passed to `send`.
+    pub fn send(&self, frame: Frame) -> Delivery {
+        let (done, rx) = oneshot::channel();
+        // If the task is gone, `done` is dropped and the delivery fails
+        let _ = self.tx.send((frame, done));
+        Delivery { rx }
+    }
+}
+
+struct InFlight {
+    frame: Frame,
+    done: Outcome,
+    sent_at: Instant,
+}
+
+async fn drive_channel(
+    mut client: VstpUdpClient,
+    dest: SocketAddr,
+    config: ChannelConfig,
+    mut rx: mpsc::UnboundedReceiver<(Frame, Outcome)>,
+) {
+    let mut queued: VecDeque<(Frame, Outcome)> = VecDeque::new();
+    let mut in_flight: BTreeMap<u64, InFlight> = BTreeMap::new();
+    let mut next_seq = 1u64;
+    let mut accepting = true;
+    let mut retry = interval(config.retry_interval);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        while in_flight.len() < config.max_in_flight.max(1) {
+            let Some((frame, done)) = queued.pop_front() else {
+                break;
+            };
+            let frame = frame
+                .with_header(CHANNEL_ID_HEADER, &config.channel_id)
+                .with_header(CHANNEL_SEQ_HEADER, &next_seq.to_string());
+            if let Err(e) = client.send(frame.clone(), dest).await {
+                debug!("Channel {} send failed: {}", config.channel_id, e);
+            }
+            in_flight.insert(
+                next_seq,
+                InFlight {
+                    frame,
+                    done,
+                    sent_at: Instant::now(),
+                },
+            );
+            next_seq += 1;
+        }
+        if !accepting && queued.is_empty() && in_flight.is_empty() {
+            return;
+        }
+
+        tokio::select! {
+            msg = rx.recv(), if accepting => match msg {
+                Some(msg) => queued.push_back(msg),
+                None => accepting = false,
+            },
+            received = client.recv() => {
+                let Ok((frame, from)) = received else { continue };
+                if from != dest
+                    || frame.typ != FrameType::Ack
+                    || frame.get_header(CHANNEL_ID_HEADER) != Some(config.channel_id.as_str())
+                {
+                    continue;
+                }
+                let Some(processed) = frame
+                    .get_header(PROCESSED_HEADER)
+                    .and_then(|v| v.parse::<u64>().ok())
+                else {
+                    continue;
+                };
+                while let Some(entry) = in_flight.first_entry() {
+                    if *entry.key() > processed {
+                        break;
+                    }
+                    let _ = entry.remove().done.send(Ok(()));
+                }
+            }
+            _ = retry.tick() => {
+                let stalled = in_flight
+                    .values()
+                    .next()
+                    .is_some_and(|oldest| oldest.sent_at.elapsed() >= config.delivery_timeout);
+                if stalled {
+                    warn!(
+                        "Channel {} to {} failed: no progress within {:?}",
+                        config.channel_id, dest, config.delivery_timeout
+                    );
+                    break;
+                }
+                for entry in in_flight.values() {
+                    let _ = client.send(entry.frame.clone(), dest).await;
+                }
+            }
+        }
+    }
+
+    // The channel failed; nothing sent now or later can be delivered in order
+    for entry in in_flight.into_values() {
+        let _ = entry.done.send(Err(VstpError::Timeout));
+    }
+    for (_, done) in queued {
+        let _ = done.send(Err(VstpError::Timeout));
+    }
+    while let Some((_, done)) = rx.recv().await {
+        let _ = done.send(Err(VstpError::Timeout));
+    }
+}
+
+/// Per-channel progress on the receiving side
+struct ChannelState {
+    last_processed: u64,
+    buffered: BTreeMap<u64, Frame>,
+}
+
+/// Receiving half of an ordered, exactly-once channel
+///
+/// Frames carrying channel headers reach the handler once each, in sequence
+/// order per channel. The handler runs to completion before the next frame
+/// is taken, and the `processed` ACK goes out only after it returns. Frames
+/// without channel headers are passed straight to the handler.
+pub struct ReliableReceiver {
+    server: VstpUdpServer,
+    store: Arc<dyn SeqStore>,
+    channels: HashMap<String, ChannelState>,
+}
+
+impl ReliableReceiver {
+    pub fn new(server: VstpUdpServer, store: Arc<dyn SeqStore>) -> Self {
+        Self {
+            server,
+            store,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// The underlying server
+    pub fn server(&self) -> &VstpUdpServer {
+        &self.server
+    }
+
+    /// Receive frames and feed them to `handler` until the socket fails
+    pub async fn run<F, Fut>(mut self, handler: F) -> Result<(), VstpError>
+    where
+        F: Fn(SocketAddr, Frame) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            let (frame, from) = self.server.recv().await?;
+            let (Some(channel_id), Some(seq)) = (
+                frame.get_header(CHANNEL_ID_HEADER).map(str::to_string),
+                frame
+                    .get_header(CHANNEL_SEQ_HEADER)
+                    .and_then(|v| v.parse::<u64>().ok()),
+            ) else {
+                handler(from, frame).await;
+                continue;
+            };
+
+            let state = match self.channels.get_mut(&channel_id) {
+                Some(state) => state,
+                None => {
+                    let last_processed = self.store.load(&channel_id)?;
+                    self.channels.entry(channel_id.clone()).or_insert(ChannelState {
+                        last_processed,
+                        buffered: BTreeMap::new(),
+                    })
+                }
+            };
+
+            if seq > state.last_processed + 1 {
+                // Wait for the gap to fill; the sender retransmits everything unprocessed
+                if state.buffered.len() < MAX_BUFFERED_PER_CHANNEL
+                    || state.buffered.contains_key(&seq)
+                {
+                    state.buffered.insert(seq, frame);
+                }
+            } else if seq == state.last_processed + 1 {
+                let mut next = Some(frame);
+                while let Some(frame) = next {
+                    handler(from, frame).await;
+                    let seq = state.last_processed + 1;
+                    if let Err(e) = self.store.store(&channel_id, seq) {
+                        // Without a record the frame might be applied again; don't confirm it
+                        warn!("Channel {}: failed to store seq {}: {}", channel_id, seq, e);
+                        break;
+                    }
+                    state.last_processed = seq;
+                    next = state.buffered.remove(&(seq + 1));
+                }
+                state.buffered.retain(|&s, _| s > state.last_processed);
+            } else {
+                debug!("Channel {}: duplicate of seq {}", channel_id, seq);
+            }
+
+            let ack = Frame::new(FrameType::Ack)
+                .with_header(CHANNEL_ID_HEADER, &channel_id)
+                .with_header(PROCESSED_HEADER, &state.last_processed.to_string());
+            if let Err(e) = self.server.send(ack, from).await {
+                debug!("Channel {}: failed to send ACK to {}: {}", channel_id, from, e);
+            }
+        }
+    }
+}
@@ -1,15 +1,33 @@
+use std::future::Future;
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
 use tokio::net::UdpSocket;
-use tokio::time::timeout;
-use tracing::{debug, info};
-
-use crate::frame::{encode_frame, try_decode_frame};
-use crate::types::{Flags, Frame, FrameType, Header, VstpError};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::codec::WireTap;
+use crate::frame::encode_frame;
+use crate::shaping::SendShaper;
+use crate::types::{Flags, Frame, FrameType, Header, VstpError, TTL_MS_HEADER};
+use crate::udp::acks::acknowledges;
+use crate::udp::dedup::{dedup_key, DedupConfig};
+use crate::udp::failover::DestinationGroup;
+use crate::udp::inbox::{Inbox, OverflowPolicy};
+use crate::udp::pacing::{Pacer, PacingConfig};
 use crate::udp::reassembly::{
-    fragment_payload, extract_fragment_info, add_fragment_headers,
-    ReassemblyManager, MAX_DATAGRAM_SIZE,
+    extract_fragment_info, fragment_frame, strip_fragment_headers, ReassemblyManager,
+    ReassemblyProgress, MAX_DATAGRAM_SIZE, REASSEMBLED_FROM_HEADER,
 };
+use crate::udp::rtt::{PeerRtt, PeerTable};
 
 /// Configuration for UDP client
 #[derive(Debug, Clone)]
@@ -20,12 +38,49 @@ pub struct UdpConfig {
     pub retry_delay: Duration,
     /// Maximum retry delay (exponential backoff cap)
     pub max_retry_delay: Duration,
-    /// Timeout for ACK responses
+    /// Timeout for ACK responses until a round trip to the peer is measured.
+    ///
+    /// Afterwards each peer's timeout follows its measured round-trip time,
+    /// within `min_rto` and `max_rto`; see [`rtt`](crate::udp::rtt).
     pub ack_timeout: Duration,
+    /// Shortest ACK timeout the round-trip estimate may produce
+    pub min_rto: Duration,
+    /// Longest ACK timeout, including after backing off on retransmission
+    pub max_rto: Duration,
+    /// Randomize each retry delay by up to this fraction in either direction
+    /// (`0.0` to `1.0`), so clients that lost packets together don't all
+    /// retransmit in lockstep
+    pub jitter_fraction: f64,
+    /// Seed for the jitter RNG; `None` seeds from OS entropy
+    pub jitter_seed: Option<u64>,
     /// Whether to use CRC validation
     pub use_crc: bool,
     /// Whether to allow fragmentation
     pub allow_frag: bool,
+    /// Pace outgoing datagrams and send them in priority order.
+    ///
+    /// With pacing, [`VstpUdpClient::send`] returns once the frame is queued.
+    pub pacing: Option<PacingConfig>,
+    /// Cap on the bytes per second sent; `None` for no cap.
+    ///
+    /// Unlike `pacing`, sends wait in line instead of being queued; see
+    /// [`shaping`](crate::shaping). Not applied to paced sends.
+    pub max_send_bps: Option<u64>,
+    /// Tag reassembled frames with a `reassembled-from` header holding the
+    /// fragment count; read it with [`reassembled_from`](crate::udp::reassembly::reassembled_from)
+    pub mark_reassembled: bool,
+    /// Told about every fragment received, for showing the progress of
+    /// large frames; `None` by default
+    pub on_reassembly_progress: Option<ReassemblyProgress>,
+    /// Callbacks given the bytes of every frame sent and received
+    pub wire_tap: WireTap,
+    /// What the receive queue does when it is full; see
+    /// [`VstpUdpClient::start_receiver`]
+    pub receive_overflow: OverflowPolicy,
+    /// Drop retransmitted `REQ_ACK` frames a peer already got through.
+    ///
+    /// Only applied by the receive pump. Off by default.
+    pub dedup: Option<DedupConfig>,
 }
 
 impl Default for UdpConfig {
@@ -35,18 +90,139 @@ impl Default for UdpConfig {
             retry_delay: Duration::from_millis(100),
             max_retry_delay: Duration::from_secs(5),
             ack_timeout: Duration::from_secs(2),
+            min_rto: Duration::from_millis(50),
+            max_rto: Duration::from_secs(10),
+            jitter_fraction: 0.0,
+            jitter_seed: None,
             use_crc: true,
             allow_frag: true,
+            pacing: None,
+            max_send_bps: None,
+            mark_reassembled: false,
+            on_reassembly_progress: None,
+            wire_tap: WireTap::default(),
+            receive_overflow: OverflowPolicy::default(),
+            dedup: None,
+        }
+    }
+}
+
+/// Exponential backoff with optional jitter for ACK retransmissions
+#[derive(Debug, Clone)]
+pub struct RetryBackoff {
+    base: Duration,
+    max: Duration,
+    jitter_fraction: f64,
+    rng: SmallRng,
+}
+
+impl RetryBackoff {
+    /// Backoff using the retry settings of a client configuration
+    pub fn from_config(config: &UdpConfig) -> Self {
+        let rng = match config.jitter_seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        Self {
+            base: config.retry_delay,
+            max: config.max_retry_delay,
+            jitter_fraction: config.jitter_fraction.clamp(0.0, 1.0),
+            rng,
+        }
+    }
+
+    /// Delay before retrying after failed attempt number `attempt` (from 0)
+    pub fn delay(&mut self, attempt: usize) -> Duration {
+        let delay = self.base.as_millis() as u64 * (2_u64.pow(attempt as u32));
+        let delay = Duration::from_millis(delay.min(self.max.as_millis() as u64));
+        if self.jitter_fraction == 0.0 {
+            return delay;
         }
+        let factor = 1.0 + self.rng.gen_range(-self.jitter_fraction..=self.jitter_fraction);
+        delay.mul_f64(factor).min(self.max)
+    }
+}
+
+/// Cancels a reliable send started with [`VstpUdpClient::send_reliable`]
+///
+/// Cheap to clone and usable from any task.
+#[derive(Debug, Clone)]
+pub struct SendHandle {
+    msg_id: u64,
+    token: CancellationToken,
+}
+
+impl SendHandle {
+    /// The `msg-id` the frame is sent under
+    pub fn msg_id(&self) -> u64 {
+        self.msg_id
+    }
+
+    /// Stop retransmitting; the send resolves with [`VstpError::Cancelled`]
+    ///
+    /// This only stops *this* side. The peer may already have received (and
+    /// acted on) an earlier transmission, and its ACK may still arrive. If
+    /// that ACK is processed before the cancellation is noticed, the send
+    /// completes normally; otherwise the late ACK is returned by a later
+    /// [`recv`](VstpUdpClient::recv) like any other frame. Cancelling a send
+    /// that already finished does nothing.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+/// A reliable send in progress; resolves once the frame is acknowledged
+///
+/// Resolves to the response frame if the peer piggybacked one on the ACK.
+/// Take a [`handle`](ReliableSend::handle) before awaiting to cancel it from
+/// elsewhere. Dropping it also stops the retransmissions.
+pub struct ReliableSend<'a> {
+    handle: SendHandle,
+    future: BoxFuture<'a, Result<Option<Frame>, VstpError>>,
+}
+
+impl ReliableSend<'_> {
+    pub fn handle(&self) -> SendHandle {
+        self.handle.clone()
+    }
+}
+
+impl Future for ReliableSend<'_> {
+    type Output = Result<Option<Frame>, VstpError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// Task draining the socket into an [`Inbox`]
+struct ReceivePump {
+    inbox: Arc<Inbox>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ReceivePump {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
 /// VSTP UDP Client
 pub struct VstpUdpClient {
-    socket: UdpSocket,
+    socket: Arc<UdpSocket>,
     config: UdpConfig,
-    reassembly: ReassemblyManager,
+    backoff: RetryBackoff,
+    pacer: Option<Pacer>,
+    send_shaper: Option<Arc<SendShaper>>,
+    rtt: PeerTable,
+    reassembly: Arc<ReassemblyManager>,
     next_msg_id: u64,
+    next_frag_id: AtomicU8,
+    pump: Option<ReceivePump>,
 }
 
 impl VstpUdpClient {
@@ -55,12 +231,7 @@ impl VstpUdpClient {
         let socket = UdpSocket::bind(local_addr).await?;
         info!("VSTP UDP client bound to {}", local_addr);
 
-        Ok(Self {
-            socket,
-            config: UdpConfig::default(),
-            reassembly: ReassemblyManager::new(),
-            next_msg_id: 1,
-        })
+        Ok(Self::from_parts(socket, UdpConfig::default()))
     }
 
     /// Create a new UDP client with custom configuration
@@ -68,16 +239,67 @@ impl VstpUdpClient {
         let socket = UdpSocket::bind(local_addr).await?;
         info!("VSTP UDP client bound to {} with custom config", local_addr);
 
-        Ok(Self {
+        Ok(Self::from_parts(socket, config))
+    }
+
+    fn from_parts(socket: UdpSocket, config: UdpConfig) -> Self {
+        let socket = Arc::new(socket);
+        let pacer = config
+            .pacing
+            .clone()
+            .map(|pacing| Pacer::spawn(socket.clone(), pacing));
+        Self {
             socket,
+            backoff: RetryBackoff::from_config(&config),
+            send_shaper: config
+                .max_send_bps
+                .map(|bps| Arc::new(SendShaper::new(bps))),
+            rtt: PeerTable::new(config.ack_timeout, config.min_rto, config.max_rto),
+            reassembly: Arc::new(
+                config
+                    .on_reassembly_progress
+                    .clone()
+                    .map_or_else(ReassemblyManager::new, |progress| {
+                        ReassemblyManager::new().with_progress(progress)
+                    }),
+            ),
             config,
-            reassembly: ReassemblyManager::new(),
+            pacer,
             next_msg_id: 1,
-        })
+            next_frag_id: AtomicU8::new(0),
+            pump: None,
+        }
+    }
+
+    /// Keep reading the socket in the background, queueing up to `buffer` frames
+    ///
+    /// Afterwards [`recv`](VstpUdpClient::recv) and friends take frames from
+    /// the queue, and waiting for an ACK no longer consumes frames meant for
+    /// the application. When the queue is full,
+    /// [`UdpConfig::receive_overflow`] decides which frame is dropped. ACKs
+    /// and `ack-for` responses are queued apart, so a flood of other frames
+    /// can't push out the one a send is waiting for. Does nothing if the
+    /// receiver is already running.
+    pub fn start_receiver(&mut self, buffer: usize) {
+        if self.pump.is_some() {
+            return;
+        }
+        let inbox = Arc::new(Inbox::new(buffer, self.config.receive_overflow));
+        let task = tokio::spawn(pump(
+            self.socket.clone(),
+            self.reassembly.clone(),
+            self.config.clone(),
+            inbox.clone(),
+        ));
+        self.pump = Some(ReceivePump { inbox, task });
     }
 
     /// Send a frame to the specified destination
     pub async fn send(&self, frame: Frame, dest: SocketAddr) -> Result<(), VstpError> {
+        if let Some(pacer) = &self.pacer {
+            return self.send_paced(pacer, frame, dest);
+        }
+
         let encoded = encode_frame(&frame)?;
 
         // Check if we need fragmentation
@@ -86,15 +308,109 @@ impl VstpUdpClient {
         }
 
         // Send as single datagram
-        self.socket.send_to(&encoded, dest).await?;
+        self.config.wire_tap.wire_out(&encoded);
+        self.send_datagram(&encoded, dest).await?;
         debug!("Sent frame to {} ({} bytes)", dest, encoded.len());
         Ok(())
     }
 
     /// Send a frame with ACK reliability
     pub async fn send_with_ack(&mut self, frame: Frame, dest: SocketAddr) -> Result<(), VstpError> {
+        self.send_reliable(frame, dest).await?;
+        Ok(())
+    }
+
+    /// Send a frame with ACK reliability, cancellably
+    ///
+    /// Like [`send_with_ack`](VstpUdpClient::send_with_ack), but the returned
+    /// future hands out a [`SendHandle`] that stops the retransmissions.
+    pub fn send_reliable(&mut self, frame: Frame, dest: SocketAddr) -> ReliableSend<'_> {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+        let handle = SendHandle {
+            msg_id,
+            token: CancellationToken::new(),
+        };
+        let token = handle.token.clone();
+        let future = Box::pin(async move {
+            tokio::select! {
+                biased;
+                _ = token.cancelled() => {
+                    debug!("Message {} cancelled", msg_id);
+                    Err(VstpError::Cancelled)
+                }
+                result = self.retransmit_until_acked(msg_id, frame, Route::To(dest)) => {
+                    result.map(|(response, _)| response)
+                }
+            }
+        });
+        ReliableSend { handle, future }
+    }
+
+    /// Send a frame with ACK reliability to the active destination of `group`
+    ///
+    /// ACK timeouts count against the destination they happened at. Once
+    /// one trips, the retransmissions go to the group's next destination;
+    /// see [`failover`](crate::udp::failover). Gives up after `max_retries`
+    /// retransmissions in all, like [`send_with_ack`](VstpUdpClient::send_with_ack).
+    /// Returns the destination that acknowledged the frame.
+    pub async fn send_with_ack_group(
+        &mut self,
+        frame: Frame,
+        group: &DestinationGroup,
+    ) -> Result<SocketAddr, VstpError> {
         let msg_id = self.next_msg_id;
         self.next_msg_id += 1;
+        let (_, dest) = self
+            .retransmit_until_acked(msg_id, frame, Route::Group(group))
+            .await?;
+        Ok(dest)
+    }
+
+    /// Send a frame with ACK reliability and wait for the application response.
+    ///
+    /// After the ACK arrives, keeps listening for up to `response_timeout` for a
+    /// DATA frame carrying the same `msg-id` (or the request's `request-id`).
+    /// A DATA frame with a matching `ack-for` header counts as both the ACK and
+    /// the response. Returns `Ok(None)` if only the ACK arrived in time.
+    pub async fn send_with_ack_and_response(
+        &mut self,
+        frame: Frame,
+        dest: SocketAddr,
+        response_timeout: Duration,
+    ) -> Result<Option<Frame>, VstpError> {
+        let request_id = frame.get_header("request-id").map(str::to_string);
+        let send = self.send_reliable(frame, dest);
+        let msg_id = send.handle().msg_id();
+        let piggybacked = send.await?;
+        if piggybacked.is_some() {
+            return Ok(piggybacked);
+        }
+
+        let response = self
+            .recv_matching(
+                |frame, addr| addr == dest && is_response_to(frame, msg_id, request_id.as_deref()),
+                response_timeout,
+            )
+            .await;
+        match response {
+            Ok((frame, _)) => Ok(Some(frame)),
+            Err(VstpError::Timeout) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send a frame under `msg_id` and retry until it is acknowledged.
+    ///
+    /// Returns the response frame if the peer piggybacked one on the ACK,
+    /// and the destination that acknowledged it.
+    async fn retransmit_until_acked(
+        &mut self,
+        msg_id: u64,
+        frame: Frame,
+        route: Route<'_>,
+    ) -> Result<(Option<Frame>, SocketAddr), VstpError> {
+        let request_id = frame.get_header("request-id").map(str::to_string);
 
         // Add message ID header for ACK tracking
         let mut frame_with_id = frame;
@@ -106,22 +422,49 @@ impl VstpUdpClient {
         // Set REQ_ACK flag
         frame_with_id.flags.insert(Flags::REQ_ACK);
 
+        // Frames with a TTL are not retried once they expire
+        let deadline = frame_with_id.deadline(Instant::now());
+
         // Try sending with retries
         for attempt in 0..=self.config.max_retries {
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    debug!("Message {} expired after {} attempts", msg_id, attempt);
+                    return Err(VstpError::Expired);
+                }
+                // Retransmissions only carry the time that is left
+                frame_with_id.set_header(TTL_MS_HEADER, &remaining.as_millis().to_string());
+            }
+
             // Send the frame
+            let dest = route.dest();
+            let rto = self.rtt.rto(dest);
             self.send(frame_with_id.clone(), dest).await?;
+            let sent_at = Instant::now();
 
             // Wait for ACK
-            match self.wait_for_ack(msg_id, dest).await {
-                Ok(_) => {
+            match self
+                .wait_for_ack(msg_id, request_id.as_deref(), dest, rto)
+                .await
+            {
+                Ok(response) => {
                     debug!("Received ACK for message {} from {}", msg_id, dest);
-                    return Ok(());
+                    // An ACK after a retransmission can't tell which copy it answers
+                    if attempt == 0 {
+                        self.rtt.sample(dest, sent_at.elapsed());
+                    }
+                    self.rtt.clear_missed_acks(dest);
+                    return Ok((response, dest));
                 }
                 Err(_) if attempt < self.config.max_retries => {
-                    let delay = self.calculate_retry_delay(attempt);
+                    self.missed_ack(&route, dest);
+                    self.rtt.timed_out(dest);
+                    let delay = self.backoff.delay(attempt);
                     debug!(
-                        "ACK timeout for message {} (attempt {}/{}), retrying in {:?}",
+                        "ACK timeout for message {} after {:?} (attempt {}/{}), retrying in {:?}",
                         msg_id,
+                        rto,
                         attempt + 1,
                         self.config.max_retries + 1,
                         delay
@@ -129,6 +472,7 @@ impl VstpUdpClient {
                     tokio::time::sleep(delay).await;
                 }
                 Err(e) => {
+                    self.missed_ack(&route, dest);
                     debug!(
                         "Failed to receive ACK for message {} after {} attempts: {}",
                         msg_id,
@@ -143,63 +487,92 @@ impl VstpUdpClient {
         Err(VstpError::Timeout)
     }
 
+    /// Count a missed ACK from `dest`, tripping it if `route` is a group
+    /// that has had enough of it
+    fn missed_ack(&mut self, route: &Route<'_>, dest: SocketAddr) {
+        let missed = self.rtt.missed_ack(dest);
+        if let Route::Group(group) = route {
+            if missed >= group.policy().failures_to_trip {
+                self.rtt.clear_missed_acks(dest);
+                group.trip(dest);
+            }
+        }
+    }
+
     /// Receive a frame from any source
     pub async fn recv(&mut self) -> Result<(Frame, SocketAddr), VstpError> {
-        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE * 2]; // Extra space for headers
-
-        loop {
-            let (len, from_addr) = self.socket.recv_from(&mut buf).await?;
-            let data = &buf[..len];
-
-            debug!("Received {} bytes from {}", len, from_addr);
-
-            // Try to decode as a complete frame first
-            let mut buf = bytes::BytesMut::from(data);
-            match try_decode_frame(&mut buf, 65536) {
-                Ok(Some(frame)) => {
-                    // Check if this is a fragmented frame
-                    if let Some(fragment) = extract_fragment_info(&frame) {
-                        // Handle fragmentation
-                        if let Some(assembled_data) =
-                            self.reassembly.add_fragment(from_addr, fragment).await?
-                        {
-                            // Reassemble the complete frame
-                            let mut complete_frame = frame;
-                            complete_frame.payload = assembled_data;
-                            // Remove fragment headers
-                            complete_frame.headers.retain(|h| {
-                                h.key != b"frag-id"
-                                    && h.key != b"frag-index"
-                                    && h.key != b"frag-total"
-                            });
-                            return Ok((complete_frame, from_addr));
-                        } else {
-                            // Fragment received, continue waiting for more
-                            continue;
-                        }
-                    } else {
-                        // Complete frame received
-                        return Ok((frame, from_addr));
-                    }
-                }
-                Ok(None) => {
-                    // Incomplete frame, continue waiting
-                    continue;
-                }
-                Err(_) => {
-                    // Invalid frame, continue waiting
-                    continue;
+        match &self.pump {
+            Some(pump) => Ok(pump.inbox.recv_matching(|_, _| true).await),
+            None => read_frame(&self.socket, &self.reassembly, &self.config).await,
+        }
+    }
+
+    /// Receive a frame from any source, giving up with [`VstpError::Timeout`]
+    pub async fn recv_timeout(&mut self, wait: Duration) -> Result<(Frame, SocketAddr), VstpError> {
+        self.recv_matching(|_, _| true, wait).await
+    }
+
+    /// Wait up to `wait` for a frame satisfying `pred`
+    ///
+    /// With the receiver running, other frames stay queued for later reads.
+    /// Without it, frames that don't match are read and discarded.
+    pub async fn recv_matching<F>(
+        &mut self,
+        mut pred: F,
+        wait: Duration,
+    ) -> Result<(Frame, SocketAddr), VstpError>
+    where
+        F: FnMut(&Frame, SocketAddr) -> bool,
+    {
+        let receive = async {
+            if let Some(pump) = &self.pump {
+                return Ok(pump.inbox.recv_matching(pred).await);
+            }
+            loop {
+                let (frame, from) =
+                    read_frame(&self.socket, &self.reassembly, &self.config).await?;
+                if pred(&frame, from) {
+                    return Ok((frame, from));
                 }
             }
+        };
+        timeout(wait, receive)
+            .await
+            .unwrap_or(Err(VstpError::Timeout))
+    }
+
+    /// Queue a frame's datagrams on the pacer, fragmenting it if necessary
+    fn send_paced(&self, pacer: &Pacer, frame: Frame, dest: SocketAddr) -> Result<(), VstpError> {
+        let priority = frame.priority();
+        let encoded = encode_frame(&frame)?;
+
+        if encoded.len() > MAX_DATAGRAM_SIZE && self.config.allow_frag {
+            let frag_id = self.next_frag_id.fetch_add(1, Ordering::Relaxed);
+            for frag_frame in fragment_frame(&frame, frag_id)? {
+                let frag_encoded = encode_frame(&frag_frame)?;
+                self.config.wire_tap.wire_out(&frag_encoded);
+                pacer.push(priority, frag_encoded, dest);
+            }
+        } else {
+            self.config.wire_tap.wire_out(&encoded);
+            pacer.push(priority, encoded, dest);
         }
+        Ok(())
+    }
+
+    /// Send one datagram once `max_send_bps` allows it
+    async fn send_datagram(&self, datagram: &[u8], dest: SocketAddr) -> Result<(), VstpError> {
+        if let Some(shaper) = &self.send_shaper {
+            shaper.acquire(datagram.len()).await;
+        }
+        self.socket.send_to(datagram, dest).await?;
+        Ok(())
     }
 
     /// Send a fragmented frame
     async fn send_fragmented(&self, frame: Frame, dest: SocketAddr) -> Result<(), VstpError> {
-        let encoded = encode_frame(&frame)?;
-        let frag_id = (self.next_msg_id % 256) as u8;
-
-        let fragments = fragment_payload(&encoded, frag_id)?;
+        let frag_id = self.next_frag_id.fetch_add(1, Ordering::Relaxed);
+        let fragments = fragment_frame(&frame, frag_id)?;
 
         info!(
             "Sending fragmented frame to {} ({} fragments)",
@@ -207,18 +580,15 @@ impl VstpUdpClient {
             fragments.len()
         );
 
-        for fragment in fragments {
-            let mut frag_frame = frame.clone();
-            add_fragment_headers(&mut frag_frame, &fragment);
-            frag_frame.flags.insert(Flags::FRAG);
-
-            let frag_encoded = encode_frame(&frag_frame)?;
-            self.socket.send_to(&frag_encoded, dest).await?;
+        for (index, frag_frame) in fragments.iter().enumerate() {
+            let frag_encoded = encode_frame(frag_frame)?;
+            self.config.wire_tap.wire_out(&frag_encoded);
+            self.send_datagram(&frag_encoded, dest).await?;
 
             debug!(
                 "Sent fragment {}/{} to {}",
-                fragment.frag_index + 1,
-                fragment.frag_total,
+                index + 1,
+                fragments.len(),
                 dest
             );
         }
@@ -227,52 +597,208 @@ impl VstpUdpClient {
     }
 
     /// Wait for an ACK for a specific message ID
-    async fn wait_for_ack(&mut self, msg_id: u64, from_addr: SocketAddr) -> Result<(), VstpError> {
-        let start_time = Instant::now();
-
-        while start_time.elapsed() < self.config.ack_timeout {
-            match timeout(Duration::from_millis(100), self.recv()).await {
-                Ok(Ok((frame, addr))) if addr == from_addr => {
-                    // Check if this is an ACK for our message
-                    if frame.typ == FrameType::Ack {
-                        for header in &frame.headers {
-                            if header.key == b"msg-id" {
-                                if let Ok(ack_msg_id) = std::str::from_utf8(&header.value)
-                                    .map_err(|e| {
-                                        VstpError::Protocol(format!("Invalid UTF-8: {}", e))
-                                    })?
-                                    .parse::<u64>()
-                                {
-                                    if ack_msg_id == msg_id {
-                                        return Ok(());
-                                    }
-                                }
-                            }
+    ///
+    /// A response to the message also acknowledges it; in that case the
+    /// response frame is returned so it isn't lost.
+    async fn wait_for_ack(
+        &mut self,
+        msg_id: u64,
+        request_id: Option<&str>,
+        from_addr: SocketAddr,
+        ack_timeout: Duration,
+    ) -> Result<Option<Frame>, VstpError> {
+        let (frame, _) = self
+            .recv_matching(
+                |frame, addr| {
+                    addr == from_addr
+                        && (acknowledges(frame, msg_id)
+                            || is_response_to(frame, msg_id, request_id))
+                },
+                ack_timeout,
+            )
+            .await?;
+        Ok((frame.typ != FrameType::Ack).then_some(frame))
+    }
+
+    /// Get the local address this client is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, VstpError> {
+        self.socket.local_addr().map_err(VstpError::Io)
+    }
+
+    /// Round-trip estimate and current ACK timeout for `dest`, `None` if no
+    /// reliable frame was sent there yet
+    pub fn peer_rtt(&self, dest: SocketAddr) -> Option<PeerRtt> {
+        self.rtt.stats(dest)
+    }
+
+    /// Forget the round-trip estimate for `dest`, e.g. after its path changed
+    ///
+    /// The next reliable send to `dest` waits `ack_timeout` again.
+    pub fn reset_rtt(&mut self, dest: SocketAddr) {
+        self.rtt.reset(dest);
+    }
+
+    /// The shaper applying `max_send_bps`, with the current send rate
+    pub fn send_shaper(&self) -> Option<Arc<SendShaper>> {
+        self.send_shaper.clone()
+    }
+
+    /// Frames the receive queue dropped because it was full
+    pub fn dropped_frame_count(&self) -> u64 {
+        self.pump
+            .as_ref()
+            .map_or(0, |pump| pump.inbox.dropped_count())
+    }
+
+    /// Retransmitted frames the receive pump dropped as duplicates
+    pub fn duplicate_frame_count(&self) -> u64 {
+        self.pump
+            .as_ref()
+            .map_or(0, |pump| pump.inbox.duplicate_count())
+    }
+
+    /// Frames waiting in the receive queue
+    pub fn queued_frame_count(&self) -> usize {
+        self.pump.as_ref().map_or(0, |pump| pump.inbox.len())
+    }
+
+    /// Get the number of active reassembly sessions
+    pub async fn reassembly_session_count(&self) -> usize {
+        self.reassembly.session_count().await
+    }
+}
+
+/// Where a reliable send goes
+enum Route<'a> {
+    To(SocketAddr),
+    /// The active destination of the group, at each transmission
+    Group(&'a DestinationGroup),
+}
+
+impl Route<'_> {
+    fn dest(&self) -> SocketAddr {
+        match self {
+            Route::To(dest) => *dest,
+            Route::Group(group) => group.active(),
+        }
+    }
+}
+
+/// Read the socket until a complete frame arrives, reassembling fragments
+async fn read_frame(
+    socket: &UdpSocket,
+    reassembly: &ReassemblyManager,
+    config: &UdpConfig,
+) -> Result<(Frame, SocketAddr), VstpError> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE * 2]; // Extra space for headers
+
+    loop {
+        let (len, from_addr) = socket.recv_from(&mut buf).await?;
+        let data = &buf[..len];
+
+        debug!("Received {} bytes from {}", len, from_addr);
+
+        // Try to decode as a complete frame first
+        let mut buf = bytes::BytesMut::from(data);
+        match config.wire_tap.decode(&mut buf, 65536) {
+            Ok(Some(frame)) => {
+                // Check if this is a fragmented frame
+                if let Some(fragment) = extract_fragment_info(&frame) {
+                    let frag_total = fragment.frag_total;
+                    // Handle fragmentation
+                    if let Some(assembled_data) =
+                        reassembly.add_fragment(from_addr, fragment).await?
+                    {
+                        // Reassemble the complete frame
+                        let mut complete_frame = frame;
+                        complete_frame.payload = assembled_data;
+                        // Remove fragment headers
+                        strip_fragment_headers(&mut complete_frame);
+                        if config.mark_reassembled {
+                            complete_frame
+                                .set_header(REASSEMBLED_FROM_HEADER, &frag_total.to_string());
                         }
+                        return Ok((complete_frame, from_addr));
+                    } else {
+                        // Fragment received, continue waiting for more
+                        continue;
                     }
+                } else {
+                    // Complete frame received
+                    return Ok((frame, from_addr));
                 }
-                Ok(Ok(_)) => continue,    // Frame from different address
-                Ok(Err(e)) => return Err(e),
-                Err(_) => continue,       // Timeout, continue waiting
+            }
+            Ok(None) => {
+                // Incomplete frame, continue waiting
+                continue;
+            }
+            Err(_) => {
+                // Invalid frame, continue waiting
+                continue;
             }
         }
-
-        Err(VstpError::Timeout)
     }
+}
 
-    /// Calculate retry delay with exponential backoff
-    fn calculate_retry_delay(&self, attempt: usize) -> Duration {
-        let delay = self.config.retry_delay.as_millis() as u64 * (2_u64.pow(attempt as u32));
-        Duration::from_millis(delay.min(self.config.max_retry_delay.as_millis() as u64))
+/// Drain `socket` into `inbox` until the client is dropped
+async fn pump(
+    socket: Arc<UdpSocket>,
+    reassembly: Arc<ReassemblyManager>,
+    config: UdpConfig,
+    inbox: Arc<Inbox>,
+) {
+    loop {
+        match read_frame(&socket, &reassembly, &config).await {
+            Ok((frame, from)) => {
+                if let Some(dedup) = &config.dedup {
+                    if is_duplicate(dedup, &frame, from).await {
+                        inbox.record_duplicate();
+                        continue;
+                    }
+                }
+                inbox.push(frame, from);
+            }
+            Err(e) => debug!("Receive pump read failed: {}", e),
+        }
     }
+}
 
-    /// Get the local address this client is bound to
-    pub fn local_addr(&self) -> Result<SocketAddr, VstpError> {
-        self.socket.local_addr().map_err(|e| VstpError::Io(e))
+/// Check a `REQ_ACK` frame against the dedup store, recording it if it is new
+///
+/// If the store fails, the frame is treated as new.
+async fn is_duplicate(dedup: &DedupConfig, frame: &Frame, from: SocketAddr) -> bool {
+    if !frame.flags.is_reliable() {
+        return false;
     }
+    let Some(msg_id) = header_u64(frame, "msg-id") else {
+        return false;
+    };
+    let key = dedup_key(from, msg_id);
+    match dedup.store.get(&key).await {
+        Ok(Some(_)) => {
+            debug!("Dropped duplicate of message {} from {}", msg_id, from);
+            return true;
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Dedup lookup for {} failed: {}", key, e),
+    }
+    if let Err(e) = dedup.store.insert(key, dedup.ttl).await {
+        warn!("Recording message {} from {} failed: {}", msg_id, from, e);
+    }
+    false
+}
 
-    /// Get the number of active reassembly sessions
-    pub async fn reassembly_session_count(&self) -> usize {
-        self.reassembly.session_count().await
+/// Parse a numeric header value
+fn header_u64(frame: &Frame, key: &str) -> Option<u64> {
+    frame.get_header(key)?.parse().ok()
+}
+
+/// Check whether `frame` is a DATA response to the message `msg_id`
+fn is_response_to(frame: &Frame, msg_id: u64, request_id: Option<&str>) -> bool {
+    if frame.typ != FrameType::Data {
+        return false;
     }
-}
\ No newline at end of file
+    header_u64(frame, "ack-for") == Some(msg_id)
+        || header_u64(frame, "msg-id") == Some(msg_id)
+        || (request_id.is_some() && frame.get_header("request-id") == request_id)
+}
@@ -0,0 +1,186 @@
+//! Dropping retransmitted frames the server already delivered
+//!
+//! A client that sends with [`send_with_ack`](crate::udp::VstpUdpClient::send_with_ack)
+//! retransmits until it sees the ACK, so a lost ACK means the server receives
+//! the frame again. With [`UdpServerConfig::dedup`](crate::udp::UdpServerConfig::dedup)
+//! set, the server records each delivered `REQ_ACK` frame under its sender
+//! and `msg-id` and, for `ttl`, ACKs repeats without delivering them again.
+//!
+//! The record is made when the frame is handed to the application, before it
+//! is processed: a server that crashes in between loses the frame rather
+//! than processing it twice. Back the [`DedupStore`] with durable storage
+//! (Redis, sled, ...) to keep recognizing retransmissions across restarts;
+//! the default [`MemoryDedupStore`] forgets them when the process exits.
+//!
+//! ## Frames without a `msg-id`
+//!
+//! Senders too simple to number their frames may still retransmit them.
+//! With [`UdpServerConfig::dedup_by_content_hash`](crate::udp::UdpServerConfig::dedup_by_content_hash)
+//! set to a window, the server also records each DATA frame that has no
+//! `msg-id` under its sender and its [`Frame::content_hash`], and drops
+//! copies from the same sender within the window. Copies may order their
+//! headers differently or differ in [`VOLATILE_HEADERS`](crate::types::VOLATILE_HEADERS),
+//! e.g. carry a fresh trace context.
+//!
+//! A sender that legitimately repeats a frame, e.g. a sensor reporting an
+//! unchanged reading, loses the repeats that fall within the window. Keep
+//! the window just longer than the sender's retransmission period, or tell
+//! such frames apart by content, e.g. with a sequence number or timestamp
+//! in the payload or a header.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+
+use crate::types::{Frame, VstpError};
+
+/// Inserts between sweeps of expired entries in a [`MemoryDedupStore`]
+const SWEEP_EVERY: usize = 1024;
+
+/// Key a frame from `peer` with message id `msg_id` is recorded under
+pub fn dedup_key(peer: SocketAddr, msg_id: u64) -> String {
+    format!("{}/{}", peer, msg_id)
+}
+
+/// Key `frame` from `peer` is recorded under when deduplicated by content
+///
+/// `None` if the frame can't be encoded.
+pub fn content_dedup_key(peer: SocketAddr, frame: &Frame) -> Option<String> {
+    let digest = frame.content_hash().ok()?;
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!("{}/sha256:{}", peer, hex))
+}
+
+/// Where a UDP server records the frames it has delivered
+///
+/// Entries only need to be kept for their `ttl`; a store may drop them any
+/// time after that.
+pub trait DedupStore: Send + Sync {
+    /// When `key` was recorded, if it was and hasn't expired
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<SystemTime>, VstpError>>;
+
+    /// Record `key` for `ttl`
+    fn insert<'a>(&'a self, key: String, ttl: Duration) -> BoxFuture<'a, Result<(), VstpError>>;
+}
+
+/// Duplicate detection settings of a UDP server
+#[derive(Clone)]
+pub struct DedupConfig {
+    pub store: Arc<dyn DedupStore>,
+    /// How long a delivered frame is remembered; should exceed the longest
+    /// time a client keeps retransmitting
+    pub ttl: Duration,
+}
+
+impl DedupConfig {
+    /// Remember delivered frames in memory for `ttl`
+    pub fn in_memory(ttl: Duration) -> Self {
+        Self::with_store(Arc::new(MemoryDedupStore::new()), ttl)
+    }
+
+    pub fn with_store(store: Arc<dyn DedupStore>, ttl: Duration) -> Self {
+        Self { store, ttl }
+    }
+}
+
+impl fmt::Debug for DedupConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DedupConfig")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+/// In-memory [`DedupStore`], which only survives restarts of the server, not of the process
+#[derive(Debug, Default)]
+pub struct MemoryDedupStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Recorded at, expires at
+    entries: HashMap<String, (SystemTime, Instant)>,
+    inserts: usize,
+}
+
+impl MemoryDedupStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of entries held, including expired ones not yet swept
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl DedupStore for MemoryDedupStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<SystemTime>, VstpError>> {
+        let state = self.state.lock().unwrap();
+        let recorded = state
+            .entries
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(recorded_at, _)| *recorded_at);
+        Box::pin(async move { Ok(recorded) })
+    }
+
+    fn insert<'a>(&'a self, key: String, ttl: Duration) -> BoxFuture<'a, Result<(), VstpError>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.inserts += 1;
+        if state.inserts.is_multiple_of(SWEEP_EVERY) {
+            state.entries.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        state.entries.insert(key, (SystemTime::now(), now + ttl));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FrameType;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_store_entries_expire() {
+        let store = MemoryDedupStore::new();
+        let key = dedup_key("127.0.0.1:4000".parse().unwrap(), 7);
+        assert_eq!(key, "127.0.0.1:4000/7");
+        assert!(store.get(&key).await.unwrap().is_none());
+
+        store
+            .insert(key.clone(), Duration::from_secs(30))
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(store.get(&key).await.unwrap().is_some());
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(store.get(&key).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_content_key_covers_headers_and_payload() {
+        let peer = "127.0.0.1:4000".parse().unwrap();
+        let frame = Frame::new(FrameType::Data).with_payload(b"21.5C".to_vec());
+        let key = content_dedup_key(peer, &frame).unwrap();
+        assert!(key.starts_with("127.0.0.1:4000/sha256:"));
+        assert_eq!(content_dedup_key(peer, &frame.clone()), Some(key.clone()));
+        let other = "127.0.0.1:4001".parse().unwrap();
+        assert_ne!(content_dedup_key(other, &frame), Some(key.clone()));
+        let traced = frame.clone().with_header("traceparent", "00-abc-def-01");
+        assert_eq!(content_dedup_key(peer, &traced), Some(key.clone()));
+        let tagged = frame.clone().with_header("seq", "2");
+        assert_ne!(content_dedup_key(peer, &tagged), Some(key));
+    }
+}
@@ -0,0 +1,290 @@
+//! Failing over between UDP destinations that stop acknowledging
+//!
+//! A [`DestinationGroup`] lists the destinations a client may send to, in
+//! priority order, e.g. a primary and a secondary collector.
+//! [`VstpUdpClient::send_with_ack_group`] sends to the group's active
+//! destination, the first one that isn't tripped:
+//!
+//! - every ACK timeout counts against the destination it was sent to, in the
+//!   client's round-trip table; [`FailoverPolicy::failures_to_trip`] of them
+//!   in a row trip it, and the frame is sent again to the next destination
+//! - once something is tripped, a background task sends each tripped
+//!   destination a PING every [`FailoverPolicy::probe_interval`]; one that
+//!   answers with a PONG in time is restored, and the group goes back to it
+//!   if it comes first
+//!
+//! When every destination is tripped, frames go to the first one again.
+//! [`DestinationGroup::subscribe`] follows the active destination and the
+//! trips and restores.
+//!
+//! Probes are sent from a socket of their own, so their PONGs never reach the
+//! client. The destination has to answer PINGs, as
+//! [reflectors](crate::udp::reflector) do.
+//!
+//! [`VstpUdpClient::send_with_ack_group`]: crate::udp::VstpUdpClient::send_with_ack_group
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+use crate::frame::{encode_frame, try_decode_frame};
+use crate::types::{Frame, FrameType};
+
+/// When a [`DestinationGroup`] gives up on a destination and checks on it again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailoverPolicy {
+    /// ACK timeouts in a row that trip a destination
+    pub failures_to_trip: u32,
+    /// How often tripped destinations are probed; also how long a probe
+    /// waits for its PONG
+    pub probe_interval: Duration,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self {
+            failures_to_trip: 3,
+            probe_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A change in which destinations of a group are usable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverEvent {
+    /// The destination ran out of ACK timeouts and is no longer sent to
+    Tripped(SocketAddr),
+    /// The tripped destination answered a probe and is sent to again
+    Restored(SocketAddr),
+}
+
+/// Where a [`DestinationGroup`] sends, as seen by [`DestinationGroup::subscribe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupStatus {
+    /// Destination frames are sent to
+    pub active: SocketAddr,
+    /// Tripped destinations, in priority order
+    pub tripped: Vec<SocketAddr>,
+    /// The change that led here; `None` before the first one
+    pub last_event: Option<FailoverEvent>,
+}
+
+/// Destinations to send to in priority order, failing over between them
+///
+/// Clones share their state, so a clone kept elsewhere sees the same trips.
+#[derive(Debug, Clone)]
+pub struct DestinationGroup {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    destinations: Vec<SocketAddr>,
+    policy: FailoverPolicy,
+    /// Whether each destination is tripped, by index
+    tripped: Mutex<Vec<bool>>,
+    status: watch::Sender<GroupStatus>,
+    prober: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(prober) = self.prober.get_mut().unwrap().take() {
+            prober.abort();
+        }
+    }
+}
+
+impl DestinationGroup {
+    /// A group sending to `destinations`, the first one preferred
+    ///
+    /// Panics if `destinations` is empty.
+    pub fn new(destinations: Vec<SocketAddr>, policy: FailoverPolicy) -> Self {
+        let primary = *destinations
+            .first()
+            .expect("a destination group needs a destination");
+        let (status, _) = watch::channel(GroupStatus {
+            active: primary,
+            tripped: Vec::new(),
+            last_event: None,
+        });
+        Self {
+            shared: Arc::new(Shared {
+                tripped: Mutex::new(vec![false; destinations.len()]),
+                destinations,
+                policy,
+                status,
+                prober: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn destinations(&self) -> &[SocketAddr] {
+        &self.shared.destinations
+    }
+
+    pub fn policy(&self) -> FailoverPolicy {
+        self.shared.policy
+    }
+
+    /// Destination frames are sent to now
+    pub fn active(&self) -> SocketAddr {
+        self.shared.status.borrow().active
+    }
+
+    /// Follow the active destination and the trips and restores
+    pub fn subscribe(&self) -> watch::Receiver<GroupStatus> {
+        self.shared.status.subscribe()
+    }
+
+    /// Stop sending to `dest` until it answers a probe
+    ///
+    /// Does nothing if `dest` isn't in the group or is tripped already.
+    pub(crate) fn trip(&self, dest: SocketAddr) {
+        if !self.shared.set_tripped(dest, true) {
+            return;
+        }
+        info!("Destination {} tripped, sending to {}", dest, self.active());
+        let mut prober = self.shared.prober.lock().unwrap();
+        if prober.is_none() {
+            *prober = Some(tokio::spawn(probe_tripped(Arc::downgrade(&self.shared))));
+        }
+    }
+}
+
+impl Shared {
+    /// Mark `dest` tripped or restored and publish the new status; false if
+    /// nothing changed
+    fn set_tripped(&self, dest: SocketAddr, tripped: bool) -> bool {
+        let Some(index) = self.destinations.iter().position(|d| *d == dest) else {
+            return false;
+        };
+        let mut flags = self.tripped.lock().unwrap();
+        if flags[index] == tripped {
+            return false;
+        }
+        flags[index] = tripped;
+        let active = self
+            .destinations
+            .iter()
+            .zip(flags.iter())
+            .find(|(_, tripped)| !**tripped)
+            .map_or(self.destinations[0], |(dest, _)| *dest);
+        let event = if tripped {
+            FailoverEvent::Tripped(dest)
+        } else {
+            FailoverEvent::Restored(dest)
+        };
+        self.status.send_replace(GroupStatus {
+            active,
+            tripped: self.tripped_destinations(&flags),
+            last_event: Some(event),
+        });
+        true
+    }
+
+    fn tripped_destinations(&self, flags: &[bool]) -> Vec<SocketAddr> {
+        self.destinations
+            .iter()
+            .zip(flags)
+            .filter(|(_, tripped)| **tripped)
+            .map(|(dest, _)| *dest)
+            .collect()
+    }
+}
+
+/// Probe the tripped destinations of a group until it is dropped
+async fn probe_tripped(shared: Weak<Shared>) {
+    loop {
+        let Some(interval) = shared.upgrade().map(|s| s.policy.probe_interval) else {
+            return;
+        };
+        tokio::time::sleep(interval).await;
+        let Some(group) = shared.upgrade() else {
+            return;
+        };
+        let tripped = group.tripped_destinations(&group.tripped.lock().unwrap());
+        let probes = tripped.iter().map(|dest| probe(*dest, interval));
+        let answers = futures::future::join_all(probes).await;
+        for (dest, answered) in tripped.into_iter().zip(answers) {
+            if answered && group.set_tripped(dest, false) {
+                info!("Destination {} answered a probe and is restored", dest);
+            }
+        }
+    }
+}
+
+/// Whether `dest` answers a PING with a PONG within `wait`
+async fn probe(dest: SocketAddr, wait: Duration) -> bool {
+    let local = if dest.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let answered = async {
+        let socket = UdpSocket::bind(local).await?;
+        socket
+            .send_to(&encode_frame(&Frame::new(FrameType::Ping))?, dest)
+            .await?;
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            let pong = try_decode_frame(&mut BytesMut::from(&buf[..len]), 65536)
+                .ok()
+                .flatten()
+                .is_some_and(|frame| frame.typ == FrameType::Pong);
+            if from == dest && pong {
+                return Ok::<_, crate::types::VstpError>(());
+            }
+        }
+    };
+    match timeout(wait, answered).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            debug!("Probe of {} failed: {}", dest, e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn test_active_follows_priority_order() {
+        let group =
+            DestinationGroup::new(vec![addr(1), addr(2), addr(3)], FailoverPolicy::default());
+        let status = group.subscribe();
+        assert_eq!(group.active(), addr(1));
+
+        group.trip(addr(1));
+        group.trip(addr(2));
+        assert_eq!(group.active(), addr(3));
+        assert_eq!(status.borrow().tripped, [addr(1), addr(2)]);
+
+        group.shared.set_tripped(addr(1), false);
+        assert_eq!(group.active(), addr(1));
+        assert_eq!(
+            status.borrow().last_event,
+            Some(FailoverEvent::Restored(addr(1)))
+        );
+
+        // With everything tripped the primary is tried again
+        group.trip(addr(1));
+        group.trip(addr(3));
+        assert_eq!(group.active(), addr(1));
+    }
+}
@@ -0,0 +1,211 @@
+//! Buffered receiving for the UDP client
+//!
+//! [`VstpUdpClient::start_receiver`](crate::udp::VstpUdpClient::start_receiver)
+//! spawns a task that keeps reading the socket, so frames that arrive while
+//! nobody is in `recv` wait in a bounded queue instead of the OS buffer.
+//! Reads for a specific frame, like waiting for an ACK, take just that frame
+//! and leave the rest queued in arrival order.
+//!
+//! ACKs and responses carrying an `ack-for` header are queued apart from
+//! other frames, so a full queue never costs a sender the ACK it waits for.
+//! Their queue has the same capacity and always drops its oldest entry,
+//! one nobody has waited for since.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::types::{Frame, FrameType};
+
+/// What the receive queue does with a frame when it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued frame to make room
+    #[default]
+    DropOldest,
+    /// Drop the frame that just arrived
+    DropNewest,
+}
+
+/// A received frame with its position in arrival order
+type Queued = (u64, Frame, SocketAddr);
+
+#[derive(Debug, Default)]
+struct Queues {
+    frames: VecDeque<Queued>,
+    /// ACKs and `ack-for` responses, kept out of the way of `frames` overflowing
+    replies: VecDeque<Queued>,
+    next_seq: u64,
+}
+
+/// Bounded queue of received frames, filled by the receive pump
+#[derive(Debug)]
+pub(crate) struct Inbox {
+    queues: Mutex<Queues>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    notify: Notify,
+    dropped: AtomicU64,
+    duplicates: AtomicU64,
+}
+
+impl Inbox {
+    pub(crate) fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            queues: Mutex::new(Queues::default()),
+            capacity: capacity.max(1),
+            overflow,
+            notify: Notify::new(),
+            dropped: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn push(&self, frame: Frame, from: SocketAddr) {
+        {
+            let mut queues = self.queues.lock().unwrap();
+            let seq = queues.next_seq;
+            queues.next_seq += 1;
+            let reply = is_reply(&frame);
+            let (queue, overflow) = if reply {
+                (&mut queues.replies, OverflowPolicy::DropOldest)
+            } else {
+                (&mut queues.frames, self.overflow)
+            };
+            if queue.len() >= self.capacity {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                match overflow {
+                    OverflowPolicy::DropOldest => {
+                        queue.pop_front();
+                    }
+                    OverflowPolicy::DropNewest => return,
+                }
+            }
+            queue.push_back((seq, frame, from));
+        }
+        self.notify.notify_one();
+    }
+
+    /// Take the oldest queued frame satisfying `pred`, if any
+    fn take<F>(&self, pred: &mut F) -> Option<(Frame, SocketAddr)>
+    where
+        F: FnMut(&Frame, SocketAddr) -> bool,
+    {
+        let mut queues = self.queues.lock().unwrap();
+        let Queues { frames, replies, .. } = &mut *queues;
+        let mut first = |queue: &VecDeque<Queued>| {
+            queue
+                .iter()
+                .position(|(_, frame, from)| pred(frame, *from))
+                .map(|index| (queue[index].0, index))
+        };
+        let queue = match (first(frames), first(replies)) {
+            (Some(frame), Some(reply)) if reply.0 < frame.0 => Some((replies, reply.1)),
+            (Some(frame), _) => Some((frames, frame.1)),
+            (None, Some(reply)) => Some((replies, reply.1)),
+            (None, None) => None,
+        };
+        let (queue, index) = queue?;
+        queue.remove(index).map(|(_, frame, from)| (frame, from))
+    }
+
+    /// Wait for a frame satisfying `pred`
+    ///
+    /// Only one reader may wait at a time.
+    pub(crate) async fn recv_matching<F>(&self, mut pred: F) -> (Frame, SocketAddr)
+    where
+        F: FnMut(&Frame, SocketAddr) -> bool,
+    {
+        loop {
+            if let Some(received) = self.take(&mut pred) {
+                return received;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let queues = self.queues.lock().unwrap();
+        queues.frames.len() + queues.replies.len()
+    }
+
+    pub(crate) fn record_duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn duplicate_count(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether `frame` answers something the client sent: an ACK, or a
+/// response carrying an `ack-for` header
+fn is_reply(frame: &Frame) -> bool {
+    frame.typ == FrameType::Ack || frame.get_header("ack-for").is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(n: u8) -> Frame {
+        Frame::new(FrameType::Data).with_payload(vec![n])
+    }
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        for (policy, kept) in [
+            (OverflowPolicy::DropOldest, [2, 3]),
+            (OverflowPolicy::DropNewest, [1, 2]),
+        ] {
+            let inbox = Inbox::new(2, policy);
+            for n in 1..=3 {
+                inbox.push(numbered(n), from);
+            }
+            assert_eq!(inbox.dropped_count(), 1);
+            for n in kept {
+                assert_eq!(inbox.recv_matching(|_, _| true).await.0.payload, [n]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matching_leaves_other_frames_queued() {
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let inbox = Inbox::new(8, OverflowPolicy::default());
+        for n in 1..=3 {
+            inbox.push(numbered(n), from);
+        }
+        let (frame, _) = inbox.recv_matching(|f, _| f.payload == [2]).await;
+        assert_eq!(frame.payload, [2]);
+        assert_eq!(inbox.len(), 2);
+        assert_eq!(inbox.recv_matching(|_, _| true).await.0.payload, [1]);
+    }
+
+    #[tokio::test]
+    async fn test_replies_survive_a_full_queue() {
+        let from: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let inbox = Inbox::new(2, OverflowPolicy::DropNewest);
+        inbox.push(numbered(1), from);
+        inbox.push(numbered(2), from);
+        inbox.push(Frame::new(FrameType::Ack).with_header("msg-id", "7"), from);
+        inbox.push(numbered(3), from);
+        assert_eq!(inbox.dropped_count(), 1);
+
+        let (ack, _) = inbox.recv_matching(|f, _| f.typ == FrameType::Ack).await;
+        assert_eq!(ack.get_header("msg-id"), Some("7"));
+        // Replies keep their place in arrival order for plain reads
+        inbox.push(numbered(4).with_header("ack-for", "8"), from);
+        for n in [1, 2, 4] {
+            assert_eq!(inbox.recv_matching(|_, _| true).await.0.payload, [n]);
+        }
+    }
+}
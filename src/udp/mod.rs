@@ -3,9 +3,27 @@
 //! This module provides async UDP client and server implementations with
 //! fragmentation, CRC validation, and optional ACK reliability.
 
+pub mod acks;
+pub mod channel;
 pub mod client;
+pub mod dedup;
+pub mod failover;
+pub mod inbox;
+pub mod pacing;
+pub mod peers;
 pub mod server;
 pub mod reassembly;
+pub mod reflector;
+pub mod rtt;
 
-pub use client::VstpUdpClient;
-pub use server::VstpUdpServer;
+pub use acks::MSG_IDS_HEADER;
+pub use channel::{ChannelConfig, Delivery, MemorySeqStore, ReliableChannel, ReliableReceiver, SeqStore};
+pub use client::{ReliableSend, SendHandle, VstpUdpClient};
+pub use dedup::{DedupConfig, DedupStore, MemoryDedupStore};
+pub use failover::{DestinationGroup, FailoverEvent, FailoverPolicy, GroupStatus};
+pub use inbox::OverflowPolicy;
+pub use pacing::{PacedQueue, PacingConfig};
+pub use peers::PeerStateStats;
+pub use reflector::{PathProbeConfig, PathReport, ReflectorConfig, RttHistogram};
+pub use rtt::{PeerRtt, RttEstimator};
+pub use server::{AckSource, ShardStrategy, UdpServerConfig, VstpUdpServer, OBSERVED_ADDR_HEADER};
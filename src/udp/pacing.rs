@@ -0,0 +1,251 @@
+//! Priority-aware send pacing for UDP
+//!
+//! A token bucket limits the outgoing byte rate. Datagrams wait in a queue
+//! ordered by [`Priority`] (FIFO within a priority), so when the link is
+//! saturated urgent frames go out first. [`Priority::P0`] may also overdraw
+//! the bucket by a small budget, so it doesn't wait for bulk traffic that was
+//! already sent to be paid back.
+//!
+//! When the queue is full the newest datagram of the lowest priority is
+//! dropped to make room, or the incoming one if nothing queued ranks lower.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::types::Priority;
+use crate::udp::reassembly::MAX_DATAGRAM_SIZE;
+
+/// Bounded queue ordered by priority, FIFO within a priority
+#[derive(Debug)]
+pub(crate) struct PriorityQueue<T> {
+    entries: BTreeMap<(Priority, u64), T>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl<T> PriorityQueue<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            capacity: capacity.max(1),
+            next_seq: 0,
+        }
+    }
+
+    /// Queue an item, returning the item dropped to make room, if any
+    pub(crate) fn push(&mut self, priority: Priority, item: T) -> Option<T> {
+        let mut dropped = None;
+        if self.entries.len() >= self.capacity {
+            match self.entries.last_key_value() {
+                Some((&(lowest, _), _)) if lowest > priority => {
+                    dropped = self.entries.pop_last().map(|(_, v)| v);
+                }
+                _ => return Some(item),
+            }
+        }
+        self.entries.insert((priority, self.next_seq), item);
+        self.next_seq += 1;
+        dropped
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        self.entries.pop_first().map(|(_, v)| v)
+    }
+
+    pub(crate) fn peek(&self) -> Option<(Priority, &T)> {
+        self.entries
+            .first_key_value()
+            .map(|(&(priority, _), v)| (priority, v))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Configuration for paced sending
+#[derive(Debug, Clone)]
+pub struct PacingConfig {
+    /// Sustained send rate in bytes per second
+    pub rate_bytes_per_sec: u64,
+    /// Bytes that may be sent back to back after an idle period
+    pub burst_bytes: u64,
+    /// How far `P0` datagrams may overdraw the bucket
+    pub p0_bypass_bytes: u64,
+    /// Maximum number of datagrams waiting to be sent
+    pub max_queued: usize,
+}
+
+impl PacingConfig {
+    /// Pace to `rate_bytes_per_sec` with a 100ms burst and a few datagrams of `P0` bypass
+    pub fn new(rate_bytes_per_sec: u64) -> Self {
+        Self {
+            rate_bytes_per_sec,
+            burst_bytes: (rate_bytes_per_sec / 10).max(MAX_DATAGRAM_SIZE as u64),
+            p0_bypass_bytes: 4 * MAX_DATAGRAM_SIZE as u64,
+            max_queued: 1024,
+        }
+    }
+}
+
+/// Send queue with priority ordering and token bucket pacing
+///
+/// This only schedules; callers move ready datagrams to a socket. It is what
+/// [`VstpUdpClient`](crate::udp::VstpUdpClient) uses when
+/// [`UdpConfig::pacing`](crate::udp::client::UdpConfig::pacing) is set.
+#[derive(Debug)]
+pub struct PacedQueue {
+    config: PacingConfig,
+    queue: PriorityQueue<(Bytes, SocketAddr)>,
+    tokens: f64,
+    last_refill: Instant,
+    dropped: u64,
+}
+
+impl PacedQueue {
+    pub fn new(config: PacingConfig) -> Self {
+        Self {
+            queue: PriorityQueue::new(config.max_queued),
+            tokens: config.burst_bytes as f64,
+            last_refill: Instant::now(),
+            dropped: 0,
+            config,
+        }
+    }
+
+    /// Queue an encoded datagram, returning `false` if a datagram was dropped
+    pub fn push(&mut self, priority: Priority, datagram: Bytes, dest: SocketAddr) -> bool {
+        match self.queue.push(priority, (datagram, dest)) {
+            Some(_) => {
+                self.dropped += 1;
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Take the next datagram if the pacing budget allows sending it now
+    pub fn pop_ready(&mut self) -> Option<(Bytes, SocketAddr)> {
+        self.refill();
+        let (priority, (datagram, _)) = self.queue.peek()?;
+        let len = datagram.len();
+        if self.tokens < self.required(priority, len) {
+            return None;
+        }
+        self.tokens -= len as f64;
+        self.queue.pop()
+    }
+
+    /// How long until the next datagram may be sent, or `None` if the queue is empty
+    pub fn next_ready_in(&mut self) -> Option<Duration> {
+        self.refill();
+        let (priority, (datagram, _)) = self.queue.peek()?;
+        let deficit = self.required(priority, datagram.len()) - self.tokens;
+        if deficit <= 0.0 {
+            return Some(Duration::ZERO);
+        }
+        Some(Duration::from_secs_f64(
+            deficit / self.config.rate_bytes_per_sec.max(1) as f64,
+        ))
+    }
+
+    /// Number of datagrams waiting
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.len() == 0
+    }
+
+    /// Number of datagrams dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Bucket level needed to send a datagram of `len` bytes at `priority`
+    fn required(&self, priority: Priority, len: usize) -> f64 {
+        // A datagram larger than the burst would otherwise never fit
+        let needed = len.min(self.config.burst_bytes as usize) as f64;
+        if priority == Priority::P0 {
+            needed - self.config.p0_bypass_bytes as f64
+        } else {
+            needed
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.config.rate_bytes_per_sec as f64)
+            .min(self.config.burst_bytes as f64);
+    }
+}
+
+/// Background task draining a [`PacedQueue`] into a socket
+pub(crate) struct Pacer {
+    queue: Arc<Mutex<PacedQueue>>,
+    notify: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl Pacer {
+    pub(crate) fn spawn(socket: Arc<UdpSocket>, config: PacingConfig) -> Self {
+        let queue = Arc::new(Mutex::new(PacedQueue::new(config)));
+        let notify = Arc::new(Notify::new());
+        let task = tokio::spawn(drain(socket, queue.clone(), notify.clone()));
+        Self {
+            queue,
+            notify,
+            task,
+        }
+    }
+
+    pub(crate) fn push(&self, priority: Priority, datagram: Bytes, dest: SocketAddr) {
+        if !self.queue.lock().unwrap().push(priority, datagram, dest) {
+            warn!("Paced send queue full, dropped a datagram");
+        }
+        self.notify.notify_one();
+    }
+}
+
+impl Drop for Pacer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn drain(socket: Arc<UdpSocket>, queue: Arc<Mutex<PacedQueue>>, notify: Arc<Notify>) {
+    loop {
+        let (ready, wait) = {
+            let mut queue = queue.lock().unwrap();
+            match queue.pop_ready() {
+                Some(ready) => (Some(ready), None),
+                None => (None, queue.next_ready_in()),
+            }
+        };
+
+        match (ready, wait) {
+            (Some((datagram, dest)), _) => {
+                if let Err(e) = socket.send_to(&datagram, dest).await {
+                    debug!("Paced send to {} failed: {}", dest, e);
+                }
+            }
+            // A newly queued P0 datagram may be sendable before the head was
+            (None, Some(wait)) => {
+                let _ = tokio::time::timeout(wait, notify.notified()).await;
+            }
+            (None, None) => notify.notified().await,
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Bounded per-peer state of a UDP server
+//!
+//! [`VstpUdpServer`](crate::udp::VstpUdpServer) keeps what it knows about
+//! each peer, so far the frames it is reassembling, in one entry per address
+//! of a single map. The map is bounded by
+//! [`UdpServerConfig::max_peers`](crate::udp::UdpServerConfig::max_peers) and
+//! [`UdpServerConfig::max_total_peer_memory_bytes`](crate::udp::UdpServerConfig::max_total_peer_memory_bytes),
+//! so the worst case is known however many addresses send to the server.
+//! Each part of a peer's state reports the bytes it holds, and the map adds
+//! them up along with the cost of the entry itself.
+//!
+//! When a limit would be exceeded, the least recently seen idle peers are
+//! evicted first. Peers in the middle of a transfer, with fragments waiting
+//! for the rest of their frame, are only evicted when evicting every idle
+//! peer isn't enough; the limits themselves are never exceeded. An evicted
+//! peer starts afresh the next time it sends, so its half-received frames
+//! are lost and must be sent again.
+//!
+//! The [`dedup`](crate::udp::dedup) store is shared by all peers and bounded
+//! by its TTL, so it isn't counted here.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::types::VstpError;
+use crate::udp::reassembly::{Fragment, PeerReassembly};
+
+/// Bytes an entry costs before any of its state: the entry, its key and its
+/// place in the recency order
+const PEER_OVERHEAD_BYTES: usize =
+    size_of::<PeerState>() + 2 * size_of::<SocketAddr>() + 2 * size_of::<u64>();
+
+/// How often every peer's reassembly is checked for expired frames
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Per-peer state a subsystem keeps, and what it costs
+pub(crate) trait PeerMemory {
+    /// Bytes held for the peer
+    fn bytes(&self) -> usize;
+
+    /// Whether the peer is in the middle of a transfer this state is part of
+    fn in_progress(&self) -> bool;
+}
+
+impl PeerMemory for PeerReassembly {
+    fn bytes(&self) -> usize {
+        PeerReassembly::bytes(self)
+    }
+
+    fn in_progress(&self) -> bool {
+        self.len() > 0
+    }
+}
+
+/// What a UDP server keeps about one peer
+#[derive(Debug, Default)]
+pub(crate) struct PeerState {
+    pub(crate) reassembly: PeerReassembly,
+    /// [`bytes`](Self::bytes) when the entry was last accounted
+    accounted_bytes: usize,
+    /// Frames being reassembled when the entry was last accounted
+    accounted_sessions: usize,
+    /// Position in [`Table::recency`]
+    last_used: u64,
+}
+
+impl PeerState {
+    fn parts(&self) -> [&dyn PeerMemory; 1] {
+        [&self.reassembly]
+    }
+
+    fn bytes(&self) -> usize {
+        PEER_OVERHEAD_BYTES + self.parts().iter().map(|part| part.bytes()).sum::<usize>()
+    }
+
+    fn busy(&self) -> bool {
+        self.parts().iter().any(|part| part.in_progress())
+    }
+}
+
+/// What a UDP server's per-peer state holds, and what it has evicted
+///
+/// See [`VstpUdpServer::peer_state_stats`](crate::udp::VstpUdpServer::peer_state_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStateStats {
+    /// Peers with state
+    pub peers: usize,
+    /// Bytes their state takes
+    pub bytes: usize,
+    /// Most peers held at once
+    pub peak_peers: usize,
+    /// Most bytes held at once
+    pub peak_bytes: usize,
+    /// Idle peers evicted to stay within the limits
+    pub evicted_idle: u64,
+    /// Peers evicted in the middle of a transfer, as evicting idle ones wasn't enough
+    pub evicted_busy: u64,
+}
+
+struct Table {
+    peers: HashMap<SocketAddr, PeerState>,
+    /// Peers by last use, oldest first
+    recency: BTreeMap<u64, SocketAddr>,
+    clock: u64,
+    /// Frames being reassembled from all peers
+    sessions: usize,
+    last_sweep: Instant,
+    stats: PeerStateStats,
+}
+
+impl Table {
+    /// The state of `addr`, created if it has none, marked as used just now
+    fn touch(&mut self, addr: SocketAddr) -> &mut PeerState {
+        self.clock += 1;
+        let clock = self.clock;
+        let peer = self.peers.entry(addr).or_insert_with(|| PeerState {
+            accounted_bytes: PEER_OVERHEAD_BYTES,
+            ..PeerState::default()
+        });
+        if peer.last_used == 0 {
+            self.stats.peers += 1;
+            self.stats.bytes += PEER_OVERHEAD_BYTES;
+        } else {
+            self.recency.remove(&peer.last_used);
+        }
+        peer.last_used = clock;
+        self.recency.insert(clock, addr);
+        peer
+    }
+
+    /// Bring the totals up to date with what the state of `addr` holds now
+    fn account(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        let (bytes, sessions) = (peer.bytes(), peer.reassembly.len());
+        self.stats.bytes = self.stats.bytes - peer.accounted_bytes + bytes;
+        self.sessions = self.sessions - peer.accounted_sessions + sessions;
+        peer.accounted_bytes = bytes;
+        peer.accounted_sessions = sessions;
+    }
+
+    fn remove(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.peers.remove(&addr) else {
+            return;
+        };
+        self.recency.remove(&peer.last_used);
+        self.stats.peers -= 1;
+        self.stats.bytes -= peer.accounted_bytes;
+        self.sessions -= peer.accounted_sessions;
+    }
+
+    /// Drop every peer's expired frames, at most once per [`SWEEP_INTERVAL`]
+    fn sweep(&mut self) {
+        if self.last_sweep.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = Instant::now();
+        let mut swept = Vec::new();
+        for (addr, peer) in self.peers.iter_mut().filter(|(_, peer)| peer.busy()) {
+            peer.reassembly.expire();
+            swept.push(*addr);
+        }
+        for addr in swept {
+            self.account(addr);
+        }
+    }
+
+    fn over(&self, max_peers: usize, max_bytes: usize) -> bool {
+        self.stats.peers > max_peers || self.stats.bytes > max_bytes
+    }
+
+    /// The least recently used peer other than `keep` that is idle, or if
+    /// `busy`, that is in the middle of a transfer
+    fn oldest(&self, keep: SocketAddr, busy: bool) -> Option<SocketAddr> {
+        self.recency
+            .values()
+            .find(|addr| **addr != keep && self.peers[*addr].busy() == busy)
+            .copied()
+    }
+
+    /// Evict peers until the limits hold again, sparing `keep` as long as possible
+    fn evict(&mut self, keep: SocketAddr, max_peers: usize, max_bytes: usize) {
+        while self.over(max_peers, max_bytes) {
+            if let Some(addr) = self.oldest(keep, false) {
+                self.remove(addr);
+                self.stats.evicted_idle += 1;
+            } else if let Some(addr) = self.oldest(keep, true) {
+                self.remove(addr);
+                self.stats.evicted_busy += 1;
+            } else {
+                // `keep` alone is too much
+                let counter = match self.peers.get(&keep).is_some_and(PeerState::busy) {
+                    true => &mut self.stats.evicted_busy,
+                    false => &mut self.stats.evicted_idle,
+                };
+                *counter += 1;
+                self.remove(keep);
+            }
+        }
+        self.stats.peak_peers = self.stats.peak_peers.max(self.stats.peers);
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.bytes);
+    }
+}
+
+/// The state of every peer of a UDP server, in one bounded map
+pub(crate) struct PeerTable {
+    max_peers: usize,
+    max_bytes: usize,
+    table: Mutex<Table>,
+}
+
+impl PeerTable {
+    pub(crate) fn new(max_peers: usize, max_bytes: usize) -> Self {
+        Self {
+            max_peers,
+            max_bytes,
+            table: Mutex::new(Table {
+                peers: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                sessions: 0,
+                last_sweep: Instant::now(),
+                stats: PeerStateStats::default(),
+            }),
+        }
+    }
+
+    /// Note that `addr` sent a frame
+    pub(crate) fn touch(&self, addr: SocketAddr) {
+        let mut table = self.table.lock().unwrap();
+        table.touch(addr);
+        table.evict(addr, self.max_peers, self.max_bytes);
+    }
+
+    /// Add a fragment from `addr` to the frame it belongs to, as
+    /// [`PeerReassembly::add`] does
+    ///
+    /// Fails if it would start a frame while `max_sessions` frames are
+    /// already being reassembled from all peers together.
+    pub(crate) fn add_fragment(
+        &self,
+        addr: SocketAddr,
+        fragment: Fragment,
+        max_sessions: usize,
+    ) -> Result<(u8, Option<Vec<u8>>), VstpError> {
+        let mut table = self.table.lock().unwrap();
+        table.sweep();
+        let sessions = table.sessions;
+        let peer = table.touch(addr);
+        let added = if !peer.reassembly.has(fragment.frag_id) && sessions >= max_sessions {
+            Err(VstpError::Protocol(
+                "Too many reassembly sessions".to_string(),
+            ))
+        } else {
+            peer.reassembly.add(addr, fragment)
+        };
+        table.account(addr);
+        table.evict(addr, self.max_peers, self.max_bytes);
+        added
+    }
+
+    /// Frames being reassembled from all peers
+    pub(crate) fn reassembly_sessions(&self) -> usize {
+        self.table.lock().unwrap().sessions
+    }
+
+    pub(crate) fn stats(&self) -> PeerStateStats {
+        self.table.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u32) -> SocketAddr {
+        SocketAddr::from(([10, (n >> 16) as u8, (n >> 8) as u8, n as u8], 4000))
+    }
+
+    fn fragment(frag_id: u8, frag_index: u8) -> Fragment {
+        Fragment {
+            frag_id,
+            frag_index,
+            frag_total: 4,
+            data: vec![0; 1000],
+        }
+    }
+
+    /// One of the 32 small fragments of the good peer's frame
+    fn small_fragment(frag_index: u8) -> Fragment {
+        Fragment {
+            frag_id: 9,
+            frag_index,
+            frag_total: 32,
+            data: vec![1; 100],
+        }
+    }
+
+    #[test]
+    fn test_spray_from_many_addresses_stays_within_limits() {
+        let max_bytes = 64 * 1024;
+        let table = PeerTable::new(100, max_bytes);
+        let good = addr(u32::MAX);
+        let mut next = 0;
+
+        for n in 0..10_000 {
+            // The good peer keeps sending its frame, a fragment now and then
+            if n % 500 == 0 {
+                let added = table
+                    .add_fragment(good, small_fragment(next), 1000)
+                    .unwrap();
+                assert_eq!(added, (next + 1, None));
+                next += 1;
+            }
+            table.touch(addr(n));
+            if n % 100 == 0 {
+                // Some of the spray starts frames it never finishes
+                let _ = table.add_fragment(addr(n), fragment(7, 0), 1000);
+            }
+            let stats = table.stats();
+            assert!(
+                stats.peers <= 100 && stats.bytes <= max_bytes,
+                "{:?}",
+                stats
+            );
+        }
+
+        // None of it was evicted, so the frame completes
+        while next < 31 {
+            table
+                .add_fragment(good, small_fragment(next), 1000)
+                .unwrap();
+            next += 1;
+        }
+        let (received, assembled) = table.add_fragment(good, small_fragment(31), 1000).unwrap();
+        assert_eq!(received, 32);
+        assert_eq!(assembled.map(|payload| payload.len()), Some(3200));
+
+        let stats = table.stats();
+        assert!(stats.evicted_idle > 9_000, "{:?}", stats);
+        assert!(stats.peak_peers <= 100 && stats.peak_bytes <= max_bytes);
+    }
+
+    #[test]
+    fn test_busy_peers_go_only_when_idle_ones_are_not_enough() {
+        let table = PeerTable::new(3, usize::MAX);
+        table.add_fragment(addr(1), fragment(1, 0), 1000).unwrap();
+        table.touch(addr(2));
+        table.touch(addr(3));
+
+        // The busy peer is the oldest, but an idle one goes
+        table.touch(addr(4));
+        let stats = table.stats();
+        assert_eq!(
+            (stats.peers, stats.evicted_idle, stats.evicted_busy),
+            (3, 1, 0)
+        );
+        assert_eq!(table.reassembly_sessions(), 1);
+
+        // With only busy peers left, the oldest busy one goes
+        table.add_fragment(addr(3), fragment(1, 0), 1000).unwrap();
+        table.add_fragment(addr(4), fragment(1, 0), 1000).unwrap();
+        table.add_fragment(addr(5), fragment(1, 0), 1000).unwrap();
+        let stats = table.stats();
+        assert_eq!((stats.peers, stats.evicted_busy), (3, 1));
+        assert_eq!(table.reassembly_sessions(), 3);
+    }
+
+    #[test]
+    fn test_accounting_follows_reassembly() {
+        let table = PeerTable::new(10, usize::MAX);
+        table.touch(addr(1));
+        assert_eq!(table.stats().bytes, PEER_OVERHEAD_BYTES);
+
+        table.add_fragment(addr(1), fragment(1, 0), 1000).unwrap();
+        assert!(table.stats().bytes > PEER_OVERHEAD_BYTES + 1000);
+        for index in 1..4 {
+            table
+                .add_fragment(addr(1), fragment(1, index), 1000)
+                .unwrap();
+        }
+        assert_eq!(table.stats().bytes, PEER_OVERHEAD_BYTES);
+        assert_eq!(table.reassembly_sessions(), 0);
+    }
+}
@@ -1,13 +1,15 @@
 //! Fragmentation and reassembly for UDP frames
 
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tracing::{debug, warn};
 
-use crate::types::{Frame, VstpError};
+use crate::frame::encode_frame;
+use crate::types::{Flags, Frame, Header, VstpError};
 
 /// Maximum size for a single UDP datagram (recommended MTU)
 pub const MAX_DATAGRAM_SIZE: usize = 1200;
@@ -21,6 +23,18 @@ pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
 /// Maximum number of concurrent reassembly sessions
 pub const MAX_REASSEMBLY_SESSIONS: usize = 1000;
 
+/// Header added to reassembled frames when enabled, carrying the number of fragments
+pub const REASSEMBLED_FROM_HEADER: &str = "reassembled-from";
+
+/// Header carrying the identifier shared by all fragments of a frame
+pub const FRAG_ID_HEADER: &str = "frag-id";
+
+/// Header carrying a fragment's position, starting at 0
+pub const FRAG_INDEX_HEADER: &str = "frag-index";
+
+/// Header carrying the number of fragments in the frame
+pub const FRAG_TOTAL_HEADER: &str = "frag-total";
+
 /// A fragment of a larger frame
 #[derive(Debug, Clone)]
 pub struct Fragment {
@@ -30,6 +44,33 @@ pub struct Fragment {
     pub data: Vec<u8>,
 }
 
+type ProgressCallback = Arc<dyn Fn(SocketAddr, u8, u8, u8) + Send + Sync>;
+
+/// Callback told about each fragment added to a frame being reassembled
+///
+/// It is called with the sender, the fragment ID, the number of fragments
+/// received so far and the total, after every new fragment including the
+/// last, before the frame is delivered. Duplicates don't count. The callback
+/// runs on the receive path and should return quickly.
+#[derive(Clone)]
+pub struct ReassemblyProgress(ProgressCallback);
+
+impl ReassemblyProgress {
+    pub fn new(callback: impl Fn(SocketAddr, u8, u8, u8) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub(crate) fn report(&self, from_addr: SocketAddr, frag_id: u8, received: u8, total: u8) {
+        (self.0)(from_addr, frag_id, received, total)
+    }
+}
+
+impl fmt::Debug for ReassemblyProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReassemblyProgress")
+    }
+}
+
 /// A reassembly session for a fragmented frame
 #[derive(Debug)]
 struct ReassemblySession {
@@ -64,6 +105,13 @@ impl ReassemblySession {
         Ok(())
     }
 
+    fn received(&self) -> usize {
+        self.received_fragments
+            .iter()
+            .filter(|f| f.is_some())
+            .count()
+    }
+
     fn is_complete(&self) -> bool {
         self.received_fragments.iter().all(|f| f.is_some())
     }
@@ -74,10 +122,8 @@ impl ReassemblySession {
         }
 
         let mut result = Vec::new();
-        for fragment in &self.received_fragments {
-            if let Some(data) = fragment {
-                result.extend_from_slice(data);
-            }
+        for data in self.received_fragments.iter().flatten() {
+            result.extend_from_slice(data);
         }
         Ok(result)
     }
@@ -87,85 +133,147 @@ impl ReassemblySession {
     }
 }
 
+/// Frames being reassembled from one peer, by fragment ID
+#[derive(Debug, Default)]
+pub(crate) struct PeerReassembly {
+    sessions: HashMap<u8, ReassemblySession>,
+}
+
+impl PeerReassembly {
+    /// Add a fragment from `from_addr`, returning how many fragments of its
+    /// frame are in and the frame's payload once they all are
+    pub(crate) fn add(
+        &mut self,
+        from_addr: SocketAddr,
+        fragment: Fragment,
+    ) -> Result<(u8, Option<Vec<u8>>), VstpError> {
+        let frag_id = fragment.frag_id;
+        let session = self
+            .sessions
+            .entry(frag_id)
+            .or_insert_with(|| ReassemblySession::new(frag_id, fragment.frag_total, from_addr));
+
+        session.add_fragment(fragment.frag_index, fragment.data)?;
+        let received = session.received() as u8;
+        if !session.is_complete() {
+            debug!(
+                "Fragment {}/{} received from {}",
+                fragment.frag_index + 1,
+                fragment.frag_total,
+                from_addr
+            );
+            return Ok((received, None));
+        }
+        let assembled_data = session.assemble()?;
+        self.sessions.remove(&frag_id);
+        debug!(
+            "Successfully reassembled fragmented frame from {}",
+            from_addr
+        );
+        Ok((received, Some(assembled_data)))
+    }
+
+    /// Whether a frame with `frag_id` is being reassembled
+    pub(crate) fn has(&self, frag_id: u8) -> bool {
+        self.sessions.contains_key(&frag_id)
+    }
+
+    /// Drop frames whose fragments took longer than [`REASSEMBLY_TIMEOUT`]
+    pub(crate) fn expire(&mut self) {
+        self.sessions.retain(|_, session| {
+            if session.is_expired() {
+                warn!(
+                    "Expired reassembly session for frag_id {} from {}",
+                    session.frag_id, session.from_addr
+                );
+            }
+            !session.is_expired()
+        });
+    }
+
+    /// Number of frames being reassembled
+    pub(crate) fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Bytes held in received fragments and the slots for missing ones
+    pub(crate) fn bytes(&self) -> usize {
+        self.sessions
+            .values()
+            .map(|session| {
+                std::mem::size_of::<ReassemblySession>()
+                    + session.received_fragments.len() * std::mem::size_of::<Option<Vec<u8>>>()
+                    + session
+                        .received_fragments
+                        .iter()
+                        .flatten()
+                        .map(Vec::len)
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+}
+
 /// Manages reassembly of fragmented UDP frames
 #[derive(Debug)]
 pub struct ReassemblyManager {
-    sessions: Arc<Mutex<HashMap<(SocketAddr, u8), ReassemblySession>>>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, PeerReassembly>>>,
+    progress: Option<ReassemblyProgress>,
 }
 
 impl ReassemblyManager {
     pub fn new() -> Self {
         Self {
             sessions: Arc::new(Mutex::new(HashMap::new())),
+            progress: None,
         }
     }
 
+    /// Report each fragment added to `progress`
+    pub fn with_progress(mut self, progress: ReassemblyProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
     /// Add a fragment to the reassembly manager
     pub async fn add_fragment(
         &self,
         from_addr: SocketAddr,
         fragment: Fragment,
     ) -> Result<Option<Vec<u8>>, VstpError> {
-        let key = (from_addr, fragment.frag_id);
+        let (frag_id, frag_total) = (fragment.frag_id, fragment.frag_total);
         let mut sessions = self.sessions.lock().await;
 
         // Clean up expired sessions first
-        self.cleanup_expired(&mut sessions).await;
+        sessions.retain(|_, peer| {
+            peer.expire();
+            peer.len() > 0
+        });
 
         // Check if we have too many sessions
-        if sessions.len() >= MAX_REASSEMBLY_SESSIONS {
+        if sessions.values().map(PeerReassembly::len).sum::<usize>() >= MAX_REASSEMBLY_SESSIONS {
             return Err(VstpError::Protocol(
                 "Too many reassembly sessions".to_string(),
             ));
         }
 
-        let session = sessions.entry(key).or_insert_with(|| {
-            ReassemblySession::new(fragment.frag_id, fragment.frag_total, from_addr)
-        });
-
-        session.add_fragment(fragment.frag_index, fragment.data)?;
-
-        if session.is_complete() {
-            let assembled_data = session.assemble()?;
-            sessions.remove(&key);
-            debug!(
-                "Successfully reassembled fragmented frame from {}",
-                from_addr
-            );
-            Ok(Some(assembled_data))
-        } else {
-            debug!(
-                "Fragment {}/{} received from {}",
-                fragment.frag_index + 1,
-                fragment.frag_total,
-                from_addr
-            );
-            Ok(None)
+        let peer = sessions.entry(from_addr).or_default();
+        let (received, assembled) = peer.add(from_addr, fragment)?;
+        if peer.len() == 0 {
+            sessions.remove(&from_addr);
         }
-    }
+        drop(sessions);
 
-    /// Clean up expired reassembly sessions
-    async fn cleanup_expired(&self, sessions: &mut HashMap<(SocketAddr, u8), ReassemblySession>) {
-        let expired_keys: Vec<_> = sessions
-            .iter()
-            .filter(|(_, session)| session.is_expired())
-            .map(|(key, _)| *key)
-            .collect();
-
-        for key in expired_keys {
-            if let Some(session) = sessions.remove(&key) {
-                warn!(
-                    "Expired reassembly session for frag_id {} from {}",
-                    session.frag_id, session.from_addr
-                );
-            }
+        if let Some(progress) = &self.progress {
+            progress.report(from_addr, frag_id, received, frag_total);
         }
+        Ok(assembled)
     }
 
     /// Get the number of active reassembly sessions
     pub async fn session_count(&self) -> usize {
         let sessions = self.sessions.lock().await;
-        sessions.len()
+        sessions.values().map(PeerReassembly::len).sum()
     }
 }
 
@@ -180,9 +288,13 @@ pub fn fragment_payload(payload: &[u8], frag_id: u8) -> Result<Vec<Fragment>, Vs
     if payload.len() <= MAX_DATAGRAM_SIZE {
         return Ok(vec![]); // No fragmentation needed
     }
+    chunk_payload(payload, MAX_DATAGRAM_SIZE, frag_id)
+}
 
-    let total_fragments = ((payload.len() + MAX_DATAGRAM_SIZE - 1) / MAX_DATAGRAM_SIZE) as u8;
-    if total_fragments > MAX_FRAGMENTS as u8 {
+/// Cut a payload into `chunk_size` pieces, refusing more than [`MAX_FRAGMENTS`]
+fn chunk_payload(payload: &[u8], chunk_size: usize, frag_id: u8) -> Result<Vec<Fragment>, VstpError> {
+    let total_fragments = payload.len().div_ceil(chunk_size);
+    if total_fragments > MAX_FRAGMENTS {
         return Err(VstpError::Protocol(format!(
             "Payload too large: {} fragments needed (max {})",
             total_fragments, MAX_FRAGMENTS
@@ -190,11 +302,11 @@ pub fn fragment_payload(payload: &[u8], frag_id: u8) -> Result<Vec<Fragment>, Vs
     }
 
     let mut fragments = Vec::new();
-    for (i, chunk) in payload.chunks(MAX_DATAGRAM_SIZE).enumerate() {
+    for (i, chunk) in payload.chunks(chunk_size).enumerate() {
         fragments.push(Fragment {
             frag_id,
             frag_index: i as u8,
-            frag_total: total_fragments,
+            frag_total: total_fragments as u8,
             data: chunk.to_vec(),
         });
     }
@@ -202,52 +314,125 @@ pub fn fragment_payload(payload: &[u8], frag_id: u8) -> Result<Vec<Fragment>, Vs
     Ok(fragments)
 }
 
+/// Split a frame into FRAG-flagged frames, one per payload chunk
+///
+/// Every fragment carries the original headers plus the `frag-*` headers, so
+/// the receiver can rebuild the frame from any fragment and the joined payload.
+/// Chunks are sized so each encoded fragment, headers included, fits in
+/// [`MAX_DATAGRAM_SIZE`]. Returns an empty vector if the frame already fits in
+/// a single datagram, and an error if its headers alone leave no room.
+pub fn fragment_frame(frame: &Frame, frag_id: u8) -> Result<Vec<Frame>, VstpError> {
+    if encode_frame(frame)?.len() <= MAX_DATAGRAM_SIZE {
+        return Ok(vec![]);
+    }
+
+    let empty_fragment = |headers: &[Header]| Frame {
+        version: frame.version,
+        typ: frame.typ,
+        flags: frame.flags | Flags::FRAG,
+        headers: headers.to_vec(),
+        payload: Vec::new(),
+    };
+
+    // Widest possible frag-* values, so the overhead holds for every index
+    let mut template = empty_fragment(&frame.headers);
+    add_fragment_headers(
+        &mut template,
+        &Fragment {
+            frag_id: u8::MAX,
+            frag_index: u8::MAX,
+            frag_total: u8::MAX,
+            data: Vec::new(),
+        },
+    );
+    let overhead = encode_frame(&template)?.len();
+    let room = match MAX_DATAGRAM_SIZE.checked_sub(overhead) {
+        Some(room) if room > 0 => room,
+        _ => {
+            return Err(VstpError::Protocol(format!(
+                "Frame headers too large to fragment: {} bytes per fragment (max {})",
+                overhead, MAX_DATAGRAM_SIZE
+            )))
+        }
+    };
+
+    Ok(chunk_payload(&frame.payload, room, frag_id)?
+        .into_iter()
+        .map(|fragment| {
+            let mut frag_frame = empty_fragment(&frame.headers);
+            add_fragment_headers(&mut frag_frame, &fragment);
+            frag_frame.payload = fragment.data;
+            frag_frame
+        })
+        .collect())
+}
+
 /// Extract fragment information from frame headers
 pub fn extract_fragment_info(frame: &Frame) -> Option<Fragment> {
-    // Look for fragment headers
-    for header in &frame.headers {
-        if header.key == b"frag-id" {
-            if let Some(frag_index_header) = frame.headers.iter().find(|h| h.key == b"frag-index") {
-                if let Some(frag_total_header) =
-                    frame.headers.iter().find(|h| h.key == b"frag-total")
-                {
-                    if let (Ok(frag_id), Ok(frag_index), Ok(frag_total)) = (
-                        std::str::from_utf8(&header.value)
-                            .unwrap_or("0")
-                            .parse::<u8>(),
-                        std::str::from_utf8(&frag_index_header.value)
-                            .unwrap_or("0")
-                            .parse::<u8>(),
-                        std::str::from_utf8(&frag_total_header.value)
-                            .unwrap_or("1")
-                            .parse::<u8>(),
-                    ) {
-                        return Some(Fragment {
-                            frag_id,
-                            frag_index,
-                            frag_total,
-                            data: frame.payload.clone(),
-                        });
-                    }
-                }
-            }
-        }
+    Some(Fragment {
+        frag_id: frame.fragment_id()?,
+        frag_index: frame.fragment_index()?,
+        frag_total: frame.fragment_total()?,
+        data: frame.payload.clone(),
+    })
+}
+
+impl Frame {
+    /// Whether this frame is one fragment of a larger frame
+    ///
+    /// True when all three `frag-*` headers are present and valid, which is
+    /// also what the UDP receivers use to decide whether to reassemble.
+    pub fn is_fragment(&self) -> bool {
+        self.fragment_id().is_some()
+            && self.fragment_index().is_some()
+            && self.fragment_total().is_some()
     }
-    None
+
+    /// Identifier shared by all fragments of the original frame
+    pub fn fragment_id(&self) -> Option<u8> {
+        self.get_header(FRAG_ID_HEADER)?.parse().ok()
+    }
+
+    /// Position of this fragment, starting at 0
+    pub fn fragment_index(&self) -> Option<u8> {
+        self.get_header(FRAG_INDEX_HEADER)?.parse().ok()
+    }
+
+    /// Number of fragments the original frame was split into
+    pub fn fragment_total(&self) -> Option<u8> {
+        self.get_header(FRAG_TOTAL_HEADER)?.parse().ok()
+    }
+}
+
+/// Remove the `frag-*` headers from a reassembled frame
+pub(crate) fn strip_fragment_headers(frame: &mut Frame) {
+    frame.headers.retain(|h| {
+        h.key != FRAG_ID_HEADER.as_bytes()
+            && h.key != FRAG_INDEX_HEADER.as_bytes()
+            && h.key != FRAG_TOTAL_HEADER.as_bytes()
+    });
+}
+
+/// Number of fragments a received frame was reassembled from
+///
+/// Only set when the receiver has `mark_reassembled` enabled; `None` otherwise
+/// and for frames that arrived in a single datagram.
+pub fn reassembled_from(frame: &Frame) -> Option<u8> {
+    frame.get_header(REASSEMBLED_FROM_HEADER)?.parse().ok()
 }
 
 /// Add fragment headers to a frame
 pub fn add_fragment_headers(frame: &mut Frame, fragment: &Fragment) {
-    frame.headers.push(crate::types::Header {
-        key: b"frag-id".to_vec(),
+    frame.headers.push(Header {
+        key: FRAG_ID_HEADER.as_bytes().to_vec(),
         value: fragment.frag_id.to_string().into_bytes(),
     });
-    frame.headers.push(crate::types::Header {
-        key: b"frag-index".to_vec(),
+    frame.headers.push(Header {
+        key: FRAG_INDEX_HEADER.as_bytes().to_vec(),
         value: fragment.frag_index.to_string().into_bytes(),
     });
-    frame.headers.push(crate::types::Header {
-        key: b"frag-total".to_vec(),
+    frame.headers.push(Header {
+        key: FRAG_TOTAL_HEADER.as_bytes().to_vec(),
         value: fragment.frag_total.to_string().into_bytes(),
     });
 }
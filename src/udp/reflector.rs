@@ -0,0 +1,393 @@
+//! Reflector mode for measuring path latency and loss
+//!
+//! A reflector is a UDP server without application logic. Started with
+//! [`VstpUdpServer::run_reflector`], it answers:
+//!
+//! - PING with a PONG carrying the PING's headers plus `reflected-at-ms` and
+//!   `server-time-ms`
+//! - DATA marked `echo: true` with the same frame plus `reflected-at-ms`
+//!
+//! and silently drops everything else. Reflectors are meant for the open
+//! internet, where source addresses can be spoofed, so replies are rate
+//! limited per source IP and in total, and by default a reply is never larger
+//! than the datagram that caused it. Requests make room for the headers the
+//! reflector adds with a `padding` header, which is stripped from the reply.
+//!
+//! [`VstpUdpClient::measure_path`] drives a reflector and summarizes loss,
+//! round trips and reordering in a [`PathReport`].
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, info, warn};
+
+use crate::clock::{stamp_server_time, unix_ms};
+use crate::frame::encode_frame;
+use crate::types::{Frame, FrameType, VstpError};
+use crate::udp::{VstpUdpClient, VstpUdpServer};
+
+/// Header marking a DATA frame for the reflector to echo, with value `true`
+pub const ECHO_HEADER: &str = "echo";
+
+/// Header the reflector adds to every reply: its clock as unix milliseconds
+pub const REFLECTED_AT_MS_HEADER: &str = "reflected-at-ms";
+
+/// Header whose value only takes up room; removed from reflected replies
+pub const PADDING_HEADER: &str = "padding";
+
+/// Header numbering the probes of a [`VstpUdpClient::measure_path`] run
+pub const PROBE_SEQ_HEADER: &str = "probe-seq";
+
+/// Header identifying a [`VstpUdpClient::measure_path`] run
+pub const PROBE_RUN_HEADER: &str = "probe-run";
+
+/// Padding that covers the headers a reflector adds to a PONG
+const PROBE_PADDING: usize = 64;
+
+/// Limits of a reflector
+#[derive(Debug, Clone)]
+pub struct ReflectorConfig {
+    /// Replies per second to any one source IP
+    pub max_replies_per_peer: u32,
+    /// Replies per second across all sources
+    pub max_replies_per_sec: u32,
+    /// Refuse requests whose reply would be more than this many times the
+    /// size of the request; `None` disables the guard
+    pub max_amplification: Option<f64>,
+}
+
+impl Default for ReflectorConfig {
+    fn default() -> Self {
+        Self {
+            max_replies_per_peer: 100,
+            max_replies_per_sec: 10_000,
+            max_amplification: Some(1.0),
+        }
+    }
+}
+
+/// Replies sent in the current one-second window
+struct RateWindow {
+    started: Instant,
+    total: u32,
+    per_peer: HashMap<IpAddr, u32>,
+}
+
+impl RateWindow {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            total: 0,
+            per_peer: HashMap::new(),
+        }
+    }
+
+    /// Count a reply to `peer` if both limits allow it
+    fn admit(&mut self, peer: IpAddr, config: &ReflectorConfig) -> bool {
+        if self.started.elapsed() >= Duration::from_secs(1) {
+            *self = Self::new();
+        }
+        let sent = self.per_peer.entry(peer).or_insert(0);
+        if self.total >= config.max_replies_per_sec || *sent >= config.max_replies_per_peer {
+            return false;
+        }
+        *sent += 1;
+        self.total += 1;
+        true
+    }
+}
+
+/// The reply to a reflector request, or `None` for frames it refuses
+fn reflect(request: &Frame) -> Option<Frame> {
+    let mut reply = match request.typ {
+        FrameType::Ping => stamp_server_time(Frame::new(FrameType::Pong)),
+        FrameType::Data if request.get_header(ECHO_HEADER) == Some("true") => {
+            Frame::new(FrameType::Data).with_payload(request.payload.clone())
+        }
+        _ => return None,
+    };
+    let kept = request
+        .headers
+        .iter()
+        .filter(|h| h.key != PADDING_HEADER.as_bytes());
+    let added = std::mem::take(&mut reply.headers);
+    reply.headers = kept.cloned().chain(added).collect();
+    Some(reply.with_header(
+        REFLECTED_AT_MS_HEADER,
+        &unix_ms(SystemTime::now()).to_string(),
+    ))
+}
+
+/// Whether `reply` is more than `factor` times the size of `request` on the wire
+fn amplifies(request: &Frame, reply: &Frame, factor: f64) -> bool {
+    match (encode_frame(request), encode_frame(reply)) {
+        (Ok(request), Ok(reply)) => reply.len() as f64 > request.len() as f64 * factor,
+        _ => true,
+    }
+}
+
+impl VstpUdpServer {
+    /// Answer PINGs and `echo: true` DATA frames and nothing else
+    ///
+    /// Runs until receiving fails for good; see [`reflector`](crate::udp::reflector)
+    /// for what is answered and how replies are limited.
+    pub async fn run_reflector(&self, config: ReflectorConfig) -> Result<(), VstpError> {
+        info!("VSTP UDP reflector starting ({:?})", config);
+        let mut window = RateWindow::new();
+        loop {
+            let (request, addr) = match self.recv().await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("UDP receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Some(reply) = reflect(&request) else {
+                debug!("Refused {:?} from {}", request.typ, addr);
+                continue;
+            };
+            if let Some(factor) = config.max_amplification {
+                if amplifies(&request, &reply, factor) {
+                    debug!("Refused {:?} from {}: reply too large", request.typ, addr);
+                    continue;
+                }
+            }
+            if !window.admit(addr.ip(), &config) {
+                debug!("Rate limited reply to {}", addr);
+                continue;
+            }
+            if let Err(e) = self.send(reply, addr).await {
+                debug!("Failed to reflect to {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// How [`VstpUdpClient::measure_path_with`] probes
+#[derive(Debug, Clone)]
+pub struct PathProbeConfig {
+    /// Time between probes
+    pub interval: Duration,
+    /// How long to wait for replies after the last probe
+    pub linger: Duration,
+}
+
+impl Default for PathProbeConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(20),
+            linger: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Round-trip times of the probes that came back
+#[derive(Debug, Clone, Default)]
+pub struct RttHistogram {
+    /// Sorted ascending
+    samples: Vec<Duration>,
+}
+
+impl RttHistogram {
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        Self { samples }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.first().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.last().copied()
+    }
+
+    /// The round trip that a fraction `q` (0.0 to 1.0) of the probes beat or matched
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let last = self.samples.len().checked_sub(1)?;
+        let index = (q.clamp(0.0, 1.0) * last as f64).round() as usize;
+        Some(self.samples[index])
+    }
+
+    /// Counts per bucket, each bucket labelled with its exclusive upper bound
+    ///
+    /// Bounds double from 1ms; empty buckets past the slowest sample are omitted.
+    pub fn buckets(&self) -> Vec<(Duration, usize)> {
+        let mut buckets = Vec::new();
+        let mut bound = Duration::from_millis(1);
+        let mut rest = &self.samples[..];
+        while !rest.is_empty() {
+            let count = rest.partition_point(|rtt| *rtt < bound);
+            buckets.push((bound, count));
+            rest = &rest[count..];
+            bound *= 2;
+        }
+        buckets
+    }
+}
+
+/// What [`VstpUdpClient::measure_path`] found out about a path
+#[derive(Debug, Clone)]
+pub struct PathReport {
+    /// Probes sent
+    pub sent: usize,
+    /// Distinct probes answered
+    pub received: usize,
+    /// Fraction of probes that went unanswered, in either direction
+    pub loss: f64,
+    pub rtt_histogram: RttHistogram,
+    /// Replies that arrived after a reply to a later probe
+    pub reorder_count: usize,
+}
+
+impl VstpUdpClient {
+    /// Probe the reflector at `addr` with `samples` PINGs
+    pub async fn measure_path(
+        &mut self,
+        addr: SocketAddr,
+        samples: usize,
+    ) -> Result<PathReport, VstpError> {
+        self.measure_path_with(addr, samples, PathProbeConfig::default())
+            .await
+    }
+
+    /// Probe the reflector at `addr` with `samples` PINGs, as described by `config`
+    ///
+    /// Other frames received meanwhile are discarded.
+    pub async fn measure_path_with(
+        &mut self,
+        addr: SocketAddr,
+        samples: usize,
+        config: PathProbeConfig,
+    ) -> Result<PathReport, VstpError> {
+        let mut tally = ProbeTally {
+            addr,
+            run: rand::random::<u64>().to_string(),
+            sent_at: Vec::with_capacity(samples),
+            answered: HashSet::new(),
+            rtts: Vec::new(),
+            highest: None,
+            reorder_count: 0,
+        };
+        let padding = "0".repeat(PROBE_PADDING);
+
+        for seq in 0..samples {
+            let probe = Frame::new(FrameType::Ping)
+                .with_header(PROBE_RUN_HEADER, &tally.run)
+                .with_header(PROBE_SEQ_HEADER, &seq.to_string())
+                .with_header(PADDING_HEADER, &padding);
+            tally.sent_at.push(Instant::now());
+            self.send(probe, addr).await?;
+
+            let next = Instant::now() + config.interval;
+            while let Ok(received) = timeout_at(next, self.recv()).await {
+                let (reply, from) = received?;
+                tally.record(&reply, from);
+            }
+        }
+        let done = Instant::now() + config.linger;
+        while tally.answered.len() < samples {
+            let Ok(received) = timeout_at(done, self.recv()).await else {
+                break;
+            };
+            let (reply, from) = received?;
+            tally.record(&reply, from);
+        }
+
+        let received = tally.answered.len();
+        Ok(PathReport {
+            sent: samples,
+            received,
+            loss: if samples == 0 {
+                0.0
+            } else {
+                1.0 - received as f64 / samples as f64
+            },
+            rtt_histogram: RttHistogram::from_samples(tally.rtts),
+            reorder_count: tally.reorder_count,
+        })
+    }
+}
+
+/// Replies collected so far by a [`VstpUdpClient::measure_path`] run
+struct ProbeTally {
+    addr: SocketAddr,
+    run: String,
+    /// Send time of each probe, indexed by sequence number
+    sent_at: Vec<Instant>,
+    answered: HashSet<usize>,
+    rtts: Vec<Duration>,
+    highest: Option<usize>,
+    reorder_count: usize,
+}
+
+impl ProbeTally {
+    fn record(&mut self, reply: &Frame, from: SocketAddr) {
+        if from != self.addr
+            || reply.typ != FrameType::Pong
+            || reply.get_header(PROBE_RUN_HEADER) != Some(self.run.as_str())
+        {
+            return;
+        }
+        let Some(seq) = reply
+            .get_header(PROBE_SEQ_HEADER)
+            .and_then(|s| s.parse::<usize>().ok())
+        else {
+            return;
+        };
+        let Some(sent_at) = self.sent_at.get(seq) else {
+            return;
+        };
+        if !self.answered.insert(seq) {
+            return;
+        }
+        self.rtts.push(sent_at.elapsed());
+        if self.highest.is_some_and(|highest| seq < highest) {
+            self.reorder_count += 1;
+        }
+        self.highest = self.highest.max(Some(seq));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reflect_strips_padding_and_refuses_the_rest() {
+        let ping = Frame::new(FrameType::Ping)
+            .with_header(PROBE_SEQ_HEADER, "3")
+            .with_header(PADDING_HEADER, &"0".repeat(PROBE_PADDING));
+        let pong = reflect(&ping).unwrap();
+        assert_eq!(pong.typ, FrameType::Pong);
+        assert_eq!(pong.get_header(PROBE_SEQ_HEADER), Some("3"));
+        assert_eq!(pong.get_header(PADDING_HEADER), None);
+        assert!(pong.get_header(REFLECTED_AT_MS_HEADER).is_some());
+        assert!(!amplifies(&ping, &pong, 1.0));
+
+        assert!(reflect(&Frame::new(FrameType::Hello)).is_none());
+        assert!(reflect(&Frame::new(FrameType::Data).with_payload(b"x".to_vec())).is_none());
+    }
+
+    #[test]
+    fn test_histogram_buckets_double() {
+        let ms = Duration::from_millis;
+        let histogram = RttHistogram::from_samples(vec![ms(5), ms(0), ms(1), ms(3), ms(2)]);
+        assert_eq!(
+            histogram.buckets(),
+            [(ms(1), 1), (ms(2), 1), (ms(4), 2), (ms(8), 1)]
+        );
+        assert_eq!(histogram.percentile(0.5), Some(ms(2)));
+        assert_eq!(histogram.max(), Some(ms(5)));
+    }
+}
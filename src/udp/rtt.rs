@@ -0,0 +1,247 @@
+//! Retransmission timeouts from measured round trips
+//!
+//! [`VstpUdpClient`](crate::udp::VstpUdpClient) waits for an ACK for one
+//! retransmission timeout (RTO) before sending a frame again. The RTO comes
+//! from the round trips measured to the same destination, following RFC 6298:
+//!
+//! ```text
+//! first sample R:  SRTT = R, RTTVAR = R / 2
+//! later samples:   RTTVAR = 3/4 RTTVAR + 1/4 |SRTT - R|
+//!                  SRTT   = 7/8 SRTT + 1/8 R
+//! RTO = SRTT + max(1ms, 4 RTTVAR), clamped to [min_rto, max_rto]
+//! ```
+//!
+//! Until the first sample the RTO is the client's `ack_timeout`. Each
+//! timeout doubles the RTO, up to `max_rto`, until a new sample arrives.
+//! Only frames ACKed on their first transmission are sampled, since an ACK
+//! of a retransmitted frame can't be matched to the transmission it answers.
+//!
+//! Estimates are kept per destination address, so talking to a new address
+//! starts over from `ack_timeout`; [`VstpUdpClient::reset_rtt`] does the
+//! same for a known address whose path changed.
+//!
+//! [`VstpUdpClient::reset_rtt`]: crate::udp::VstpUdpClient::reset_rtt
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Clock granularity term of the RTO
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Most destinations a client keeps estimates for
+const MAX_PEERS: usize = 1024;
+
+/// RFC 6298 round-trip estimator for one destination
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
+}
+
+impl RttEstimator {
+    /// An estimator without samples, starting at `initial_rto`
+    pub fn new(initial_rto: Duration, min_rto: Duration, max_rto: Duration) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: initial_rto.clamp(min_rto, max_rto),
+            min_rto,
+            max_rto,
+        }
+    }
+
+    /// Take a round-trip sample
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or_default();
+        self.rto = (srtt + (self.rttvar * 4).max(GRANULARITY)).clamp(self.min_rto, self.max_rto);
+    }
+
+    /// Double the RTO after a timeout
+    pub fn back_off(&mut self) {
+        self.rto = (self.rto * 2).min(self.max_rto);
+    }
+
+    /// Smoothed round-trip time, `None` before the first sample
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Round-trip time variation
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// Current retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+}
+
+/// Round-trip statistics of one destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerRtt {
+    /// Smoothed round-trip time, `None` before the first sample
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    /// How long the next transmission waits for its ACK
+    pub rto: Duration,
+    /// Round trips measured
+    pub samples: u64,
+    /// Transmissions after the first, for any frame
+    pub retransmits: u64,
+}
+
+#[derive(Debug)]
+struct Peer {
+    estimator: RttEstimator,
+    samples: u64,
+    retransmits: u64,
+    /// ACK timeouts since the last ACK, for [`failover`](crate::udp::failover)
+    missed_acks: u32,
+    last_used: Instant,
+}
+
+/// Round-trip estimators of the destinations a client sends reliable frames to
+#[derive(Debug)]
+pub(crate) struct PeerTable {
+    peers: HashMap<SocketAddr, Peer>,
+    initial_rto: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
+}
+
+impl PeerTable {
+    pub(crate) fn new(initial_rto: Duration, min_rto: Duration, max_rto: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            initial_rto,
+            min_rto,
+            max_rto,
+        }
+    }
+
+    fn peer(&mut self, addr: SocketAddr) -> &mut Peer {
+        if self.peers.len() >= MAX_PEERS && !self.peers.contains_key(&addr) {
+            let stalest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, peer)| peer.last_used)
+                .map(|(addr, _)| *addr);
+            if let Some(stalest) = stalest {
+                self.peers.remove(&stalest);
+            }
+        }
+        let (initial, min, max) = (self.initial_rto, self.min_rto, self.max_rto);
+        let peer = self.peers.entry(addr).or_insert_with(|| Peer {
+            estimator: RttEstimator::new(initial, min, max),
+            samples: 0,
+            retransmits: 0,
+            missed_acks: 0,
+            last_used: Instant::now(),
+        });
+        peer.last_used = Instant::now();
+        peer
+    }
+
+    /// How long to wait for an ACK from `addr`
+    pub(crate) fn rto(&mut self, addr: SocketAddr) -> Duration {
+        self.peer(addr).estimator.rto()
+    }
+
+    pub(crate) fn sample(&mut self, addr: SocketAddr, rtt: Duration) {
+        let peer = self.peer(addr);
+        peer.estimator.sample(rtt);
+        peer.samples += 1;
+    }
+
+    /// Note that an ACK from `addr` timed out and the frame is sent again
+    pub(crate) fn timed_out(&mut self, addr: SocketAddr) {
+        let peer = self.peer(addr);
+        peer.estimator.back_off();
+        peer.retransmits += 1;
+    }
+
+    /// Count an ACK from `addr` that never came; returns how many in a row did
+    pub(crate) fn missed_ack(&mut self, addr: SocketAddr) -> u32 {
+        let peer = self.peer(addr);
+        peer.missed_acks += 1;
+        peer.missed_acks
+    }
+
+    /// Start counting missed ACKs from `addr` over, e.g. after one arrived
+    pub(crate) fn clear_missed_acks(&mut self, addr: SocketAddr) {
+        self.peer(addr).missed_acks = 0;
+    }
+
+    pub(crate) fn reset(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+    }
+
+    pub(crate) fn stats(&self, addr: SocketAddr) -> Option<PeerRtt> {
+        self.peers.get(&addr).map(|peer| PeerRtt {
+            srtt: peer.estimator.srtt(),
+            rttvar: peer.estimator.rttvar(),
+            rto: peer.estimator.rto(),
+            samples: peer.samples,
+            retransmits: peer.retransmits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_estimator_follows_rfc_6298() {
+        let mut rtt = RttEstimator::new(ms(1000), ms(10), ms(5000));
+        assert_eq!(rtt.rto(), ms(1000));
+
+        rtt.sample(ms(300));
+        assert_eq!(rtt.srtt(), Some(ms(300)));
+        assert_eq!(rtt.rttvar(), ms(150));
+        assert_eq!(rtt.rto(), ms(900));
+
+        rtt.sample(ms(300));
+        // RTTVAR = 3/4 * 150 + 1/4 * 0, SRTT unchanged
+        assert_eq!(rtt.rttvar(), Duration::from_micros(112_500));
+        assert_eq!(rtt.rto(), ms(750));
+
+        rtt.back_off();
+        assert_eq!(rtt.rto(), ms(1500));
+        for _ in 0..5 {
+            rtt.back_off();
+        }
+        assert_eq!(rtt.rto(), ms(5000));
+    }
+
+    #[test]
+    fn test_estimator_clamps_to_min_rto() {
+        let mut rtt = RttEstimator::new(ms(1000), ms(50), ms(5000));
+        for _ in 0..20 {
+            rtt.sample(Duration::from_micros(200));
+        }
+        assert_eq!(rtt.rto(), ms(50));
+    }
+}
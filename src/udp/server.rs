@@ -1,13 +1,33 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use std::future::Future;
+use std::time::{Duration, SystemTime};
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tracing::{debug, info, warn};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, info, warn, Instrument, Span};
 
-use crate::frame::{encode_frame, try_decode_frame};
-use crate::types::{Flags, Frame, FrameType, Header, VstpError, VSTP_VERSION};
-use crate::udp::reassembly::{extract_fragment_info, ReassemblyManager, MAX_DATAGRAM_SIZE};
+use crate::clock::unix_ms;
+use crate::codec::WireTap;
+use crate::easy::TransportKind;
+use crate::frame::{encode_frame, encode_pong_into, PONG_FRAME_MAX_LEN};
+use crate::ingress::{FrameTypeFilter, IngressPolicy, IngressStats};
+use crate::meta::FrameMeta;
+use crate::shaping::SendShaper;
+use crate::socket::SocketOptions;
+use crate::types::{ErrorCode, Frame, FrameType, Header, HeaderRatioLimit, Priority, VstpError};
+use crate::udp::acks::{AckBatcher, AckWriter};
+use crate::udp::dedup::{content_dedup_key, dedup_key, DedupConfig, MemoryDedupStore};
+use crate::udp::pacing::PriorityQueue;
+use crate::udp::peers::{PeerStateStats, PeerTable};
+use crate::udp::reassembly::{
+    extract_fragment_info, fragment_frame, strip_fragment_headers, ReassemblyProgress,
+    MAX_DATAGRAM_SIZE, REASSEMBLED_FROM_HEADER,
+};
 
 /// Configuration for UDP server
 #[derive(Debug, Clone)]
@@ -18,6 +38,107 @@ pub struct UdpServerConfig {
     pub allow_frag: bool,
     /// Maximum number of concurrent reassembly sessions
     pub max_reassembly_sessions: usize,
+    /// Most peers the server keeps state for; the least recently seen idle
+    /// ones are evicted first, see [`peers`](crate::udp::peers)
+    pub max_peers: usize,
+    /// Most bytes the state of all peers together may take, half-received
+    /// frames included, see [`peers`](crate::udp::peers)
+    pub max_total_peer_memory_bytes: usize,
+    /// Size of each receive buffer; larger datagrams are truncated and dropped
+    pub recv_buffer_size: usize,
+    /// Socket options applied when binding
+    pub socket: SocketOptions,
+    /// Whether to ACK `REQ_ACK` frames as soon as they are received.
+    ///
+    /// Disable this to piggyback the ACK on the response sent via
+    /// [`VstpUdpServer::respond`] instead of sending a separate ACK datagram.
+    pub auto_ack: bool,
+    /// Whether to drop frames whose TTL ran out instead of returning them.
+    ///
+    /// Expired frames are still ACKed so the sender stops retransmitting them.
+    pub drop_expired: bool,
+    /// Frames that may wait for each worker in [`VstpUdpServer::run_workers`]
+    pub worker_queue_depth: usize,
+    /// Tag reassembled frames with a `reassembled-from` header holding the
+    /// fragment count; read it with [`reassembled_from`](crate::udp::reassembly::reassembled_from)
+    pub mark_reassembled: bool,
+    /// Told about every fragment received, for showing the progress of
+    /// large frames; `None` by default
+    pub on_reassembly_progress: Option<ReassemblyProgress>,
+    /// Answer datagrams that fail to decode with an ERR frame naming the
+    /// problem (see [`error_codes`](crate::types::error_codes)), at most this
+    /// many per second across all senders.
+    ///
+    /// Off by default: the reply goes to an unverified source address, which
+    /// an attacker can spoof to reflect traffic at someone else.
+    pub error_replies_per_sec: Option<u32>,
+    /// Recognize retransmitted `REQ_ACK` frames and ACK them without
+    /// delivering them again; see [`dedup`](crate::udp::dedup). `None` (the
+    /// default) delivers every copy.
+    ///
+    /// Frames are recognized by sender address and `msg-id`, and
+    /// [`VstpUdpClient`](crate::udp::VstpUdpClient) numbers its messages
+    /// from 1, so a client restarted on the same address within the window
+    /// has its first frames ACKed and dropped. Only turn it on for senders
+    /// whose ids don't repeat, or that keep their address.
+    pub dedup: Option<DedupConfig>,
+    /// Also drop byte-identical copies of a DATA frame without a `msg-id`
+    /// from the same sender arriving within this window; `None` (the
+    /// default) delivers them all.
+    ///
+    /// For senders that retransmit but don't number their frames. Frames
+    /// that are identical on purpose are dropped too, see
+    /// [`dedup`](crate::udp::dedup#frames-without-a-msg-id). Recorded in the
+    /// store of `dedup` if set, otherwise in memory.
+    pub dedup_by_content_hash: Option<Duration>,
+    /// Callbacks given the bytes of every frame sent and received
+    pub wire_tap: WireTap,
+    /// Rules applied to every complete frame received; see [`ingress`](crate::ingress)
+    pub ingress: Option<IngressPolicy>,
+    /// Ask the kernel to timestamp received datagrams (`SO_TIMESTAMPNS`, Linux only)
+    ///
+    /// The timestamps show up as [`FrameMeta::kernel_timestamp`]. Elsewhere,
+    /// or if the socket refuses, frames only get the userspace `received_at`.
+    pub kernel_timestamps: bool,
+    /// Keep each datagram's bytes as [`FrameMeta::raw_bytes`], e.g. to
+    /// forward frames verbatim; off by default, as it copies every datagram
+    pub keep_raw_bytes: bool,
+    /// Source address of the ACKs sent for `REQ_ACK` frames; see [`AckSource`]
+    pub ack_source: AckSource,
+    /// Tell clients the address their frame came from, as seen by the
+    /// server, in each ACK's [`OBSERVED_ADDR_HEADER`]
+    ///
+    /// For debugging NATs and load balancers. Off by default.
+    pub ack_observed_addr: bool,
+    /// Longest a `REQ_ACK` frame's ACK is held back to be sent together
+    /// with the peer's next ones, see [`acks`](crate::udp::acks); `None`
+    /// ACKs every frame on its own.
+    ///
+    /// Keep it well below the ACK timeout of clients. 5 ms by default.
+    pub ack_delay: Option<Duration>,
+    /// Most ACKs sent together in one datagram when `ack_delay` is set
+    pub max_ack_batch: usize,
+    /// Frame types accepted from clients; `None` accepts all.
+    ///
+    /// Checked as soon as a datagram is decoded, before reassembly and
+    /// ACKing; others are dropped without a reply, see [`ingress`](crate::ingress).
+    pub allowed_frame_types: Option<HashSet<FrameType>>,
+    /// Drop datagrams holding a frame whose headers dwarf its payload, as
+    /// undecodable; `None` (the default) turns the check off.
+    ///
+    /// Checked per datagram, so each fragment of a large frame is judged on
+    /// its own; see [`HeaderRatioLimit`].
+    pub max_header_to_payload_ratio: Option<HeaderRatioLimit>,
+    /// Cap on the bytes per second sent to all clients together; `None` for no cap.
+    ///
+    /// See [`shaping`](crate::shaping).
+    pub max_send_bps: Option<u64>,
+    /// Span the server logs under, e.g. to tell apart the logs of several
+    /// servers in one process; none by default.
+    ///
+    /// Covers binding, [`send`](VstpUdpServer::send), receiving and the
+    /// `run` methods. Handlers run under it too.
+    pub span: Span,
 }
 
 impl Default for UdpServerConfig {
@@ -26,16 +147,129 @@ impl Default for UdpServerConfig {
             use_crc: true,
             allow_frag: true,
             max_reassembly_sessions: 1000,
+            max_peers: 65_536,
+            max_total_peer_memory_bytes: 64 * 1024 * 1024,
+            recv_buffer_size: MAX_DATAGRAM_SIZE * 2, // Extra space for headers
+            socket: SocketOptions::default(),
+            auto_ack: true,
+            drop_expired: false,
+            worker_queue_depth: 1024,
+            mark_reassembled: false,
+            on_reassembly_progress: None,
+            error_replies_per_sec: None,
+            dedup: None,
+            dedup_by_content_hash: None,
+            wire_tap: WireTap::default(),
+            ingress: None,
+            kernel_timestamps: false,
+            keep_raw_bytes: false,
+            ack_source: AckSource::default(),
+            ack_observed_addr: false,
+            ack_delay: Some(Duration::from_millis(5)),
+            max_ack_batch: 32,
+            allowed_frame_types: None,
+            max_header_to_payload_ratio: None,
+            max_send_bps: None,
+            span: Span::none(),
         }
     }
 }
 
+/// Header on ACKs naming the address the acknowledged frame came from, see
+/// [`UdpServerConfig::ack_observed_addr`]
+pub const OBSERVED_ADDR_HEADER: &str = "observed-addr";
+
+/// Which local address [`VstpUdpServer`] sends its ACKs from
+///
+/// Clients only accept an ACK from the address they sent the frame to. A
+/// server bound to a wildcard address on a host with several addresses
+/// leaves the source of its datagrams to the kernel's routing, which may
+/// pick another address than the one a client used, e.g. that of another
+/// interface; the client then drops the ACK and retransmits until it gives
+/// up. The other choices set the source of each ACK explicitly, while still
+/// sending it from the server's socket and port.
+///
+/// Setting the source uses `IP_PKTINFO` and `IPV6_PKTINFO`, so it is only
+/// supported on Linux; elsewhere every choice acts as `Routing`. Replies
+/// sent with [`VstpUdpServer::send`] or [`VstpUdpServer::respond`], also
+/// those doubling as ACKs, always leave the source to routing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AckSource {
+    /// Let the kernel's routing pick the source address
+    #[default]
+    Routing,
+    /// The local address each frame was sent to, which is always the one the
+    /// client expects
+    ArrivalAddress,
+    /// A fixed local address, e.g. the one a NAT or load balancer forwards
+    /// clients' traffic to
+    Address(IpAddr),
+}
+
+/// How [`VstpUdpServer::run_workers`] assigns incoming frames to workers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShardStrategy {
+    /// Hash the peer's address so each peer is always handled by the same
+    /// worker, one frame at a time, in arrival order.
+    ///
+    /// Throughput per peer is limited to one worker, and a slow handler call
+    /// delays every other peer that hashes to the same worker.
+    #[default]
+    PeerHash,
+    /// Hand frames to workers in turn, regardless of peer.
+    ///
+    /// Spreads a single busy peer across all workers, but frames from one
+    /// peer may be handled concurrently and complete out of order.
+    RoundRobin,
+}
+
+impl ShardStrategy {
+    /// Index of the worker that handles a frame from `peer`
+    ///
+    /// `sequence` is the number of frames dispatched so far.
+    pub fn worker_for(&self, peer: SocketAddr, sequence: u64, workers: usize) -> usize {
+        match self {
+            ShardStrategy::PeerHash => {
+                let mut hasher = DefaultHasher::new();
+                peer.hash(&mut hasher);
+                (hasher.finish() % workers as u64) as usize
+            }
+            ShardStrategy::RoundRobin => (sequence % workers as u64) as usize,
+        }
+    }
+}
+
+/// ERR replies sent in the current one-second window
+struct ReplyWindow {
+    started: Instant,
+    sent: u32,
+}
+
 /// VSTP UDP Server
 pub struct VstpUdpServer {
     socket: UdpSocket,
     config: UdpServerConfig,
-    reassembly: ReassemblyManager,
-    next_session_id: Arc<Mutex<u128>>,
+    peers: PeerTable,
+    next_frag_id: AtomicU8,
+    buffers: BufferPool,
+    truncated_datagrams: AtomicU64,
+    expired_frames: AtomicU64,
+    dropped_frames: AtomicU64,
+    decode_errors: AtomicU64,
+    duplicate_frames: AtomicU64,
+    error_replies: Mutex<ReplyWindow>,
+    ingress_stats: Arc<IngressStats>,
+    frame_types: FrameTypeFilter,
+    /// Whether the socket reports kernel receive timestamps
+    kernel_timestamps: bool,
+    /// Whether the socket reports the local address datagrams were sent to
+    arrival_addresses: bool,
+    send_shaper: Option<Arc<SendShaper>>,
+    acks: AckWriter,
+    /// Holds back ACKs when `ack_delay` is set
+    ack_batcher: Option<Arc<AckBatcher>>,
+    /// Where frames are recorded when `dedup_by_content_hash` is set
+    content_dedup: Option<DedupConfig>,
 }
 
 impl VstpUdpServer {
@@ -44,92 +278,506 @@ impl VstpUdpServer {
         let socket = UdpSocket::bind(addr).await?;
         info!("VSTP UDP server bound to {}", addr);
 
-        Ok(Self {
-            socket,
-            config: UdpServerConfig::default(),
-            reassembly: ReassemblyManager::new(),
-            next_session_id: Arc::new(Mutex::new(1)),
-        })
+        Self::from_parts(socket, UdpServerConfig::default())
     }
 
     /// Create a new UDP server with custom configuration
     pub async fn bind_with_config(addr: &str, config: UdpServerConfig) -> Result<Self, VstpError> {
-        let socket = UdpSocket::bind(addr).await?;
-        info!("VSTP UDP server bound to {} with custom config", addr);
+        let socket = config.socket.bind_udp(addr).await?;
+        config
+            .span
+            .in_scope(|| info!("VSTP UDP server bound to {} with custom config", addr));
 
+        Self::from_parts(socket, config)
+    }
+
+    /// Create a new UDP server with `SO_REUSEADDR` set, for fast restarts
+    pub async fn bind_reuseaddr(addr: &str) -> Result<Self, VstpError> {
+        let config = UdpServerConfig {
+            socket: SocketOptions::reuse_addr(),
+            ..UdpServerConfig::default()
+        };
+        Self::bind_with_config(addr, config).await
+    }
+
+    /// Take over an already bound socket, e.g. one inherited from a supervisor
+    ///
+    /// The socket is switched to non-blocking mode. Bind-time socket options
+    /// are whatever the socket already has.
+    pub fn from_std_socket(socket: std::net::UdpSocket) -> Result<Self, VstpError> {
+        Self::from_std_socket_with_config(socket, UdpServerConfig::default())
+    }
+
+    /// Take over an already bound socket with custom configuration
+    pub fn from_std_socket_with_config(
+        socket: std::net::UdpSocket,
+        config: UdpServerConfig,
+    ) -> Result<Self, VstpError> {
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        let local_addr = socket.local_addr()?;
+        config
+            .span
+            .in_scope(|| info!("VSTP UDP server adopted socket on {}", local_addr));
+
+        Self::from_parts(socket, config)
+    }
+
+    /// Give up the socket so it can be handed to another process
+    pub fn into_std(self) -> Result<std::net::UdpSocket, VstpError> {
+        Ok(self.socket.into_std()?)
+    }
+
+    fn from_parts(socket: UdpSocket, config: UdpServerConfig) -> Result<Self, VstpError> {
+        let kernel_timestamps = config.kernel_timestamps && enable_kernel_timestamps(&socket);
+        let arrival_addresses =
+            config.ack_source == AckSource::ArrivalAddress && enable_arrival_addresses(&socket);
+        if config.ack_source != AckSource::Routing && !cfg!(target_os = "linux") {
+            config
+                .span
+                .in_scope(|| warn!("ACK source addresses can only be set on Linux"));
+        }
+        let send_shaper = config
+            .max_send_bps
+            .map(|bps| Arc::new(SendShaper::new(bps)));
+        let acks = AckWriter {
+            wire_tap: config.wire_tap.clone(),
+            source: config.ack_source,
+            observed_addr: config.ack_observed_addr,
+            shaper: send_shaper.clone(),
+        };
+        let ack_batcher = match config.ack_delay {
+            Some(delay) if config.auto_ack => Some(AckBatcher::new(
+                &socket,
+                acks.clone(),
+                delay,
+                config.max_ack_batch,
+            )?),
+            _ => None,
+        };
+        let content_dedup = config.dedup_by_content_hash.map(|window| match &config.dedup {
+            Some(dedup) => DedupConfig::with_store(dedup.store.clone(), window),
+            None => DedupConfig::with_store(Arc::new(MemoryDedupStore::new()), window),
+        });
         Ok(Self {
             socket,
+            peers: PeerTable::new(config.max_peers, config.max_total_peer_memory_bytes),
+            next_frag_id: AtomicU8::new(0),
+            buffers: BufferPool::new(config.recv_buffer_size),
+            truncated_datagrams: AtomicU64::new(0),
+            expired_frames: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            decode_errors: AtomicU64::new(0),
+            duplicate_frames: AtomicU64::new(0),
+            error_replies: Mutex::new(ReplyWindow {
+                started: Instant::now(),
+                sent: 0,
+            }),
+            ingress_stats: Arc::new(IngressStats::default()),
+            frame_types: FrameTypeFilter::new(config.allowed_frame_types.clone()),
+            kernel_timestamps,
+            arrival_addresses,
+            send_shaper,
+            acks,
+            ack_batcher,
+            content_dedup,
             config,
-            reassembly: ReassemblyManager::new(),
-            next_session_id: Arc::new(Mutex::new(1)),
         })
     }
 
+    /// What the [`IngressPolicy`] has done so far
+    pub fn ingress_stats(&self) -> Arc<IngressStats> {
+        self.ingress_stats.clone()
+    }
+
     /// Get the local address this server is bound to
     pub fn local_addr(&self) -> Result<SocketAddr, VstpError> {
-        self.socket.local_addr().map_err(|e| VstpError::Io(e))
+        self.socket.local_addr().map_err(VstpError::Io)
+    }
+
+    /// Span the server logs under
+    pub fn span(&self) -> &Span {
+        &self.config.span
     }
 
-    /// Send a frame to a specific address
+    /// Send a frame to a specific address, fragmenting it if necessary
     pub async fn send(&self, frame: Frame, dest: SocketAddr) -> Result<(), VstpError> {
+        self.send_sized(frame, dest).await.map(drop)
+    }
+
+    /// [`send`](VstpUdpServer::send), returning the bytes put on the wire
+    pub(crate) async fn send_sized(
+        &self,
+        frame: Frame,
+        dest: SocketAddr,
+    ) -> Result<usize, VstpError> {
+        self.send_frame(frame, dest)
+            .instrument(self.config.span.clone())
+            .await
+    }
+
+    async fn send_frame(&self, frame: Frame, dest: SocketAddr) -> Result<usize, VstpError> {
         let encoded = encode_frame(&frame)?;
-        self.socket.send_to(&encoded, dest).await?;
+
+        if encoded.len() > MAX_DATAGRAM_SIZE && self.config.allow_frag {
+            let frag_id = self.next_frag_id.fetch_add(1, Ordering::Relaxed);
+            let mut sent = 0;
+            for frag_frame in fragment_frame(&frame, frag_id)? {
+                let frag_encoded = encode_frame(&frag_frame)?;
+                self.config.wire_tap.wire_out(&frag_encoded);
+                self.send_datagram(&frag_encoded, dest).await?;
+                sent += frag_encoded.len();
+            }
+            debug!("Sent fragmented frame to {}", dest);
+            return Ok(sent);
+        }
+
+        self.config.wire_tap.wire_out(&encoded);
+        self.send_datagram(&encoded, dest).await?;
+        Ok(encoded.len())
+    }
+
+    /// Send one datagram once `max_send_bps` allows it
+    async fn send_datagram(&self, datagram: &[u8], dest: SocketAddr) -> Result<(), VstpError> {
+        if let Some(shaper) = &self.send_shaper {
+            shaper.acquire(datagram.len()).await;
+        }
+        self.socket.send_to(datagram, dest).await?;
         Ok(())
     }
 
     /// Receive a frame from any client
     pub async fn recv(&self) -> Result<(Frame, SocketAddr), VstpError> {
-        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE * 2]; // Extra space for headers
+        let (frame, from_addr, _) = self.recv_with_meta().await?;
+        Ok((frame, from_addr))
+    }
+
+    /// Receive a frame from any client, with its receive metadata
+    ///
+    /// The metadata of a frame reassembled from fragments describes the
+    /// datagram that completed it.
+    pub async fn recv_with_meta(&self) -> Result<(Frame, SocketAddr, FrameMeta), VstpError> {
+        self.recv_frame().instrument(self.config.span.clone()).await
+    }
+
+    async fn recv_frame(&self) -> Result<(Frame, SocketAddr, FrameMeta), VstpError> {
+        let mut buf = self.buffers.take();
 
         loop {
-            let (len, from_addr) = self.socket.recv_from(&mut buf).await?;
+            let Datagram {
+                len,
+                from: from_addr,
+                truncated,
+                kernel_timestamp,
+                arrived_on,
+            } = self.recv_datagram(&mut buf).await?;
+            let received_at = Instant::now();
+            let meta = FrameMeta {
+                received_at: received_at.into_std(),
+                kernel_timestamp,
+                wire_len: len,
+                transport: TransportKind::Udp,
+                raw_bytes: None,
+            };
+            if truncated {
+                self.truncated_datagrams.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Dropped oversized datagram from {} ({} bytes, receive buffer is {} bytes)",
+                    from_addr,
+                    len,
+                    buf.len()
+                );
+                let error = VstpError::FrameTooLarge {
+                    size: len,
+                    limit: buf.len(),
+                };
+                self.reply_decode_error(&error, from_addr).await;
+                continue;
+            }
             let data = &buf[..len];
             debug!("Received {} bytes from {}", len, from_addr);
+            let meta = FrameMeta {
+                raw_bytes: self
+                    .config
+                    .keep_raw_bytes
+                    .then(|| bytes::Bytes::copy_from_slice(data)),
+                ..meta
+            };
 
             // Try to decode the frame
             let mut buf = bytes::BytesMut::from(data);
-            match try_decode_frame(&mut buf, 65536) {
+            match self.config.wire_tap.decode(&mut buf, 65536) {
                 Ok(Some(frame)) => {
+                    if let Some(limit) = &self.config.max_header_to_payload_ratio {
+                        if let Err(e) = limit.check(&frame) {
+                            self.reply_decode_error(&e, from_addr).await;
+                            continue;
+                        }
+                    }
+                    if !self.frame_types.allows(frame.typ, from_addr) {
+                        continue;
+                    }
                     // Check if this is a fragmented frame
                     if let Some(fragment) = extract_fragment_info(&frame) {
+                        let (frag_id, frag_total) = (fragment.frag_id, fragment.frag_total);
                         // Handle fragmentation
-                        if let Some(assembled_data) = self.reassembly.add_fragment(from_addr, fragment).await? {
+                        let max_sessions = self.config.max_reassembly_sessions;
+                        let (received, assembled) =
+                            self.peers.add_fragment(from_addr, fragment, max_sessions)?;
+                        if let Some(progress) = &self.config.on_reassembly_progress {
+                            progress.report(from_addr, frag_id, received, frag_total);
+                        }
+                        if let Some(assembled_data) = assembled {
                             // Reassemble the complete frame
                             let mut complete_frame = frame;
                             complete_frame.payload = assembled_data;
                             // Remove fragment headers
-                            complete_frame.headers.retain(|h| {
-                                h.key != b"frag-id" && h.key != b"frag-index" && h.key != b"frag-total"
-                            });
-
-                            // Send ACK if requested
-                            if complete_frame.flags.contains(Flags::REQ_ACK) {
-                                if let Some(msg_id) = self.extract_msg_id(&complete_frame) {
-                                    let _ = self.send_ack(msg_id, from_addr).await;
-                                }
+                            strip_fragment_headers(&mut complete_frame);
+                            if self.config.mark_reassembled {
+                                complete_frame.set_header(REASSEMBLED_FROM_HEADER, &frag_total.to_string());
                             }
 
-                            return Ok((complete_frame, from_addr));
+                            if !self
+                                .accept(&mut complete_frame, received_at, from_addr, arrived_on)
+                                .await
+                            {
+                                continue;
+                            }
+                            return Ok((complete_frame, from_addr, meta));
                         }
                         // Fragment received, continue waiting for more
                         continue;
                     } else {
-                        // Send ACK if requested
-                        if frame.flags.contains(Flags::REQ_ACK) {
-                            if let Some(msg_id) = self.extract_msg_id(&frame) {
-                                let _ = self.send_ack(msg_id, from_addr).await;
-                            }
+                        self.peers.touch(from_addr);
+                        let mut frame = frame;
+                        if !self
+                            .accept(&mut frame, received_at, from_addr, arrived_on)
+                            .await
+                        {
+                            continue;
                         }
-
-                        return Ok((frame, from_addr));
+                        return Ok((frame, from_addr, meta));
                     }
                 }
-                Ok(None) => continue, // Incomplete frame
-                Err(_) => continue,   // Invalid frame
+                Ok(None) => {
+                    // A datagram must hold a whole frame
+                    let error = VstpError::Protocol("datagram holds an incomplete frame".to_string());
+                    self.reply_decode_error(&error, from_addr).await;
+                    continue;
+                }
+                Err(e) => {
+                    self.reply_decode_error(&e, from_addr).await;
+                    continue;
+                }
             }
         }
     }
 
+    /// Receive one datagram, reporting whether it was truncated by the buffer,
+    /// and when the kernel received it and where it was sent, if the socket
+    /// was asked to record that
+    ///
+    /// On Linux `MSG_TRUNC` makes the kernel report the real datagram length.
+    /// Elsewhere a datagram that exactly fills the buffer is assumed truncated.
+    #[cfg(target_os = "linux")]
+    async fn recv_datagram(&self, buf: &mut [u8]) -> Result<Datagram, VstpError> {
+        use tokio::io::Interest;
+
+        loop {
+            let result = self.socket.try_io(Interest::READABLE, || {
+                if self.kernel_timestamps || self.arrival_addresses {
+                    return recv_with_control(&self.socket, buf);
+                }
+                let sock = socket2::SockRef::from(&self.socket);
+                // SAFETY: `u8` and `MaybeUninit<u8>` share a layout, and the
+                // buffer is already initialized, so the kernel may write into it.
+                let uninit = unsafe {
+                    &mut *(buf as *mut [u8] as *mut [std::mem::MaybeUninit<u8>])
+                };
+                sock.recv_from_with_flags(uninit, libc::MSG_TRUNC)
+                    .map(|(len, addr)| (len, addr, None, None))
+            });
+            match result {
+                Ok((len, addr, kernel_timestamp, arrived_on)) => {
+                    return Ok(Datagram {
+                        len,
+                        from: addr.as_socket().ok_or(VstpError::InvalidAddress)?,
+                        truncated: len > buf.len(),
+                        kernel_timestamp,
+                        arrived_on,
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.flush_acks().await;
+                    self.socket.readable().await?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn recv_datagram(&self, buf: &mut [u8]) -> Result<Datagram, VstpError> {
+        let (len, from) = match self.socket.try_recv_from(buf) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                self.flush_acks().await;
+                self.socket.recv_from(buf).await?
+            }
+            result => result?,
+        };
+        Ok(Datagram {
+            len,
+            from,
+            truncated: len == buf.len(),
+            kernel_timestamp: None,
+            arrived_on: None,
+        })
+    }
+
+    /// Send the ACKs held back, as the socket has no more datagrams waiting
+    async fn flush_acks(&self) {
+        if let Some(batcher) = &self.ack_batcher {
+            batcher.flush().await;
+        }
+    }
+
+    /// Number of datagrams dropped because they didn't fit the receive buffer
+    pub fn truncated_datagram_count(&self) -> u64 {
+        self.truncated_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames dropped because of their type
+    pub fn rejected_frame_type_count(&self) -> u64 {
+        self.frame_types.rejected_count()
+    }
+
+    /// The shaper applying `max_send_bps`, with the current send rate
+    pub fn send_shaper(&self) -> Option<Arc<SendShaper>> {
+        self.send_shaper.clone()
+    }
+
+    /// Number of datagrams that could not be decoded, including truncated ones
+    pub fn decode_error_count(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// Count a datagram that failed to decode and tell the sender, if enabled and within the rate limit
+    async fn reply_decode_error(&self, error: &VstpError, from_addr: SocketAddr) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+        debug!("Dropped undecodable datagram from {}: {}", from_addr, error);
+
+        let Some(per_sec) = self.config.error_replies_per_sec else {
+            return;
+        };
+        {
+            let mut window = self.error_replies.lock().unwrap();
+            if window.started.elapsed() >= Duration::from_secs(1) {
+                window.started = Instant::now();
+                window.sent = 0;
+            }
+            if window.sent >= per_sec {
+                return;
+            }
+            window.sent += 1;
+        }
+
+        let code = match error {
+            VstpError::InvalidMagic(_) => ErrorCode::BadMagic,
+            VstpError::CrcMismatch { .. } | VstpError::DigestMismatch => ErrorCode::BadCrc,
+            VstpError::FrameTooLarge { .. } => ErrorCode::FrameTooLarge,
+            VstpError::InvalidVersion { .. } => ErrorCode::UnsupportedVersion,
+            VstpError::SuspiciousFrame { .. } => ErrorCode::PolicyViolation,
+            _ => ErrorCode::MalformedFrame,
+        };
+        let reply = Frame::coded_error(code, &error.to_string());
+        if let Err(e) = self.send(reply, from_addr).await {
+            debug!("Failed to send ERR to {}: {}", from_addr, e);
+        }
+    }
+
+    /// Screen, ACK if requested and decide whether to deliver a complete frame
+    ///
+    /// Frames the [`IngressPolicy`] rejects are neither ACKed nor answered.
+    async fn accept(
+        &self,
+        frame: &mut Frame,
+        received_at: Instant,
+        from_addr: SocketAddr,
+        arrived_on: Option<IpAddr>,
+    ) -> bool {
+        if let Some(policy) = &self.config.ingress {
+            if let Err(reason) = policy.apply(frame, &self.ingress_stats) {
+                debug!("Rejected a frame from {}: {}", from_addr, reason);
+                return false;
+            }
+        }
+        let msg_id = if frame.flags.is_reliable() {
+            self.extract_msg_id(frame)
+        } else {
+            None
+        };
+        if let Some(msg_id) = msg_id {
+            if self.config.auto_ack {
+                let urgent = frame.priority() == Priority::P0;
+                let _ = self.send_ack(msg_id, from_addr, arrived_on, urgent).await;
+            }
+        }
+        if self.drop_if_expired(frame, received_at, from_addr) {
+            return false;
+        }
+        match (msg_id, &self.config.dedup, &self.content_dedup) {
+            (Some(msg_id), Some(dedup), _) => {
+                !self.is_duplicate(dedup, dedup_key(from_addr, msg_id)).await
+            }
+            (None, _, Some(dedup)) if frame.typ == FrameType::Data => {
+                match content_dedup_key(from_addr, frame) {
+                    Some(key) => !self.is_duplicate(dedup, key).await,
+                    None => true,
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// Check `key` against the dedup store, recording it if it is new
+    ///
+    /// If the store fails, the frame is treated as new.
+    async fn is_duplicate(&self, dedup: &DedupConfig, key: String) -> bool {
+        match dedup.store.get(&key).await {
+            Ok(Some(_)) => {
+                self.duplicate_frames.fetch_add(1, Ordering::Relaxed);
+                debug!("Dropped duplicate of {}", key);
+                return true;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Dedup lookup for {} failed: {}", key, e),
+        }
+        if let Err(e) = dedup.store.insert(key.clone(), dedup.ttl).await {
+            warn!("Recording {} failed: {}", key, e);
+        }
+        false
+    }
+
+    /// Number of retransmitted frames dropped as duplicates
+    pub fn duplicate_frame_count(&self) -> u64 {
+        self.duplicate_frames.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames dropped because their TTL ran out
+    pub fn expired_frame_count(&self) -> u64 {
+        self.expired_frames.load(Ordering::Relaxed)
+    }
+
+    /// Count and report an expired frame if `drop_expired` is enabled
+    fn drop_if_expired(&self, frame: &Frame, received_at: Instant, from_addr: SocketAddr) -> bool {
+        if !self.config.drop_expired || !frame.is_expired(received_at) {
+            return false;
+        }
+        self.expired_frames.fetch_add(1, Ordering::Relaxed);
+        debug!("Dropped expired frame from {}", from_addr);
+        true
+    }
+
     /// Extract message ID from frame headers
     fn extract_msg_id(&self, frame: &Frame) -> Option<u64> {
         for header in &frame.headers {
@@ -142,25 +790,79 @@ impl VstpUdpServer {
         None
     }
 
-    /// Send an ACK for a received message
-    async fn send_ack(&self, msg_id: u64, dest: SocketAddr) -> Result<(), VstpError> {
-        let ack_frame = Frame {
-            version: VSTP_VERSION,
-            typ: FrameType::Ack,
-            flags: Flags::empty(),
-            headers: vec![Header {
+    /// Send an ACK for a received message, from the address `ack_source`
+    /// picks, or hold it back for a batch unless it is `urgent`
+    async fn send_ack(
+        &self,
+        msg_id: u64,
+        dest: SocketAddr,
+        arrived_on: Option<IpAddr>,
+        urgent: bool,
+    ) -> Result<(), VstpError> {
+        match &self.ack_batcher {
+            Some(batcher) => batcher.queue(msg_id, dest, arrived_on, urgent).await,
+            None => {
+                self.acks
+                    .send(&self.socket, &[msg_id], dest, arrived_on)
+                    .await
+            }
+        }
+    }
+
+    /// Answer a clock sync PING that sent `ping_sent_ms` and nothing else,
+    /// without allocating
+    pub(crate) async fn send_pong(
+        &self,
+        ping_sent_ms: u64,
+        dest: SocketAddr,
+    ) -> Result<(), VstpError> {
+        let mut pong = [0; PONG_FRAME_MAX_LEN];
+        let len = encode_pong_into(ping_sent_ms, unix_ms(SystemTime::now()), &mut pong);
+        self.config.wire_tap.wire_out(&pong[..len]);
+        self.send_datagram(&pong[..len], dest).await
+    }
+
+    /// Send a response to a request frame received from `dest`.
+    ///
+    /// The request's `msg-id` and `request-id` headers are copied onto the
+    /// response so the client can correlate it. If the request asked for an
+    /// ACK and `auto_ack` is disabled, the response also carries an `ack-for`
+    /// header and doubles as the ACK.
+    pub async fn respond(
+        &self,
+        request: &Frame,
+        mut response: Frame,
+        dest: SocketAddr,
+    ) -> Result<(), VstpError> {
+        if let Some(msg_id) = self.extract_msg_id(request) {
+            response.headers.push(Header {
                 key: b"msg-id".to_vec(),
                 value: msg_id.to_string().into_bytes(),
-            }],
-            payload: Vec::new(),
-        };
+            });
+            if !self.config.auto_ack && request.flags.is_reliable() {
+                response.headers.push(Header {
+                    key: b"ack-for".to_vec(),
+                    value: msg_id.to_string().into_bytes(),
+                });
+            }
+        }
+        if let Some(request_id) = request.get_header("request-id") {
+            response = response.with_header("request-id", request_id);
+        }
 
-        self.send(ack_frame, dest).await
+        self.send(response, dest).await
     }
 
     /// Get the number of active reassembly sessions
     pub async fn reassembly_session_count(&self) -> usize {
-        self.reassembly.session_count().await
+        self.peers.reassembly_sessions()
+    }
+
+    /// How many peers the server keeps state for, the bytes it takes and
+    /// how many were evicted to stay within `max_peers` and
+    /// `max_total_peer_memory_bytes`
+    pub fn peer_state_stats(&self) -> PeerStateStats {
+        self.peers.stats()
     }
 
     /// Run the UDP server with a frame handler.
@@ -169,19 +871,450 @@ impl VstpUdpServer {
         F: Fn(SocketAddr, Frame) -> Fut + Send + Sync + Clone + 'static,
         Fut: Future<Output = ()> + Send,
     {
-        info!("VSTP UDP server starting...");
+        self.run_with_meta(move |addr, frame, _| handler(addr, frame))
+            .await
+    }
+
+    /// Run the UDP server, handing each frame's [`FrameMeta`] to the handler along with it
+    pub async fn run_with_meta<F, Fut>(self, handler: F) -> Result<(), VstpError>
+    where
+        F: Fn(SocketAddr, Frame, FrameMeta) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let span = self.config.span.clone();
+        span.in_scope(|| info!("VSTP UDP server starting..."));
         loop {
-            match self.recv().await {
-                Ok((frame, addr)) => {
+            match self.recv_with_meta().await {
+                Ok((frame, addr, meta)) => {
                     let h = handler.clone();
-                    tokio::spawn(async move {
+                    let handle = async move {
+                        debug!(
+                            "Handling frame from {} after {:?} in queue",
+                            addr,
+                            meta.queue_delay()
+                        );
+                        h(addr, frame, meta).await;
+                    };
+                    tokio::spawn(handle.instrument(span.clone()));
+                }
+                Err(e) => {
+                    span.in_scope(|| warn!("UDP receive failed: {}", e));
+                }
+            }
+        }
+    }
+
+    /// Run the UDP server with a fixed pool of `workers`, each handling its
+    /// frames sequentially.
+    ///
+    /// Unlike [`run`](VstpUdpServer::run), which spawns a task per frame,
+    /// this bounds concurrency and, with [`ShardStrategy::PeerHash`], keeps
+    /// each peer's frames of equal priority in arrival order.
+    ///
+    /// Frames waiting for a busy worker are taken highest [`Priority`] first.
+    /// Once `worker_queue_depth` frames are waiting, the newest
+    /// lowest-priority frame is dropped (see [`dropped_frame_count`]).
+    ///
+    /// [`dropped_frame_count`]: VstpUdpServer::dropped_frame_count
+    pub async fn run_workers<F, Fut>(
+        &self,
+        workers: usize,
+        strategy: ShardStrategy,
+        handler: F,
+    ) -> Result<(), VstpError>
+    where
+        F: Fn(SocketAddr, Frame) -> Fut + Send + Sync + Clone + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let workers = workers.max(1);
+        let span = self.config.span.clone();
+        span.in_scope(|| {
+            info!(
+                "VSTP UDP server starting with {} workers ({:?})...",
+                workers, strategy
+            )
+        });
+
+        let queues: Vec<_> = (0..workers)
+            .map(|_| {
+                let queue = Arc::new(WorkerQueue::new(self.config.worker_queue_depth));
+                let h = handler.clone();
+                let worker_queue = queue.clone();
+                let work = async move {
+                    loop {
+                        let (addr, frame) = worker_queue.pop().await;
                         h(addr, frame).await;
-                    });
+                    }
+                };
+                let task = tokio::spawn(work.instrument(span.clone()));
+                (queue, AbortOnDrop(task))
+            })
+            .collect();
+
+        let mut sequence = 0u64;
+        loop {
+            match self.recv().await {
+                Ok((frame, addr)) => {
+                    let worker = strategy.worker_for(addr, sequence, workers);
+                    sequence = sequence.wrapping_add(1);
+                    if queues[worker].0.push(addr, frame) {
+                        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                        span.in_scope(|| {
+                            debug!("Worker {} queue full, dropped lowest-priority frame", worker)
+                        });
+                    }
                 }
                 Err(e) => {
-                    warn!("UDP receive failed: {}", e);
+                    span.in_scope(|| warn!("UDP receive failed: {}", e));
                 }
             }
         }
     }
-}
\ No newline at end of file
+
+    /// Number of frames dropped because a worker queue was full
+    pub fn dropped_frame_count(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for VstpUdpServer {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// Turn on `SO_TIMESTAMPNS`, returning whether the socket accepted it
+#[cfg(target_os = "linux")]
+fn enable_kernel_timestamps(socket: &UdpSocket) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let on: libc::c_int = 1;
+    // SAFETY: the option value is a live `c_int` of the length passed.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        warn!(
+            "Kernel receive timestamps unavailable: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    result == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_kernel_timestamps(_socket: &UdpSocket) -> bool {
+    false
+}
+
+/// What the kernel said about one received datagram
+struct Datagram {
+    len: usize,
+    from: SocketAddr,
+    /// Whether it was larger than the buffer
+    truncated: bool,
+    kernel_timestamp: Option<SystemTime>,
+    /// Local address it was sent to, if the socket reports it
+    arrived_on: Option<IpAddr>,
+}
+
+/// Have the socket report the local address each datagram was sent to
+#[cfg(target_os = "linux")]
+fn enable_arrival_addresses(socket: &UdpSocket) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, option) = match socket.local_addr() {
+        Ok(SocketAddr::V4(_)) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        Ok(SocketAddr::V6(_)) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+        Err(_) => return false,
+    };
+    let on: libc::c_int = 1;
+    // SAFETY: the option value is a live `c_int` of the length passed.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        warn!(
+            "Arrival addresses unavailable, ACKs leave their source to routing: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    result == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_arrival_addresses(_socket: &UdpSocket) -> bool {
+    false
+}
+
+/// `sendmsg` with an `IP_PKTINFO` or `IPV6_PKTINFO` control message setting
+/// the datagram's source address
+#[cfg(target_os = "linux")]
+pub(crate) async fn send_from(
+    socket: &UdpSocket,
+    datagram: &[u8],
+    dest: SocketAddr,
+    source: IpAddr,
+) -> Result<(), VstpError> {
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::Interest;
+
+    // The control message has to match the family of the destination
+    let source = match (dest, source) {
+        (SocketAddr::V4(_), IpAddr::V6(v6)) => {
+            IpAddr::V4(v6.to_ipv4_mapped().ok_or(VstpError::InvalidAddress)?)
+        }
+        (SocketAddr::V6(_), IpAddr::V4(v4)) => IpAddr::V6(v4.to_ipv6_mapped()),
+        (_, source) => source,
+    };
+    let dest = socket2::SockAddr::from(dest);
+    let send = || {
+        // SAFETY: all-zero is a valid `msghdr`. Every pointer in `msg` refers
+        // to a live local for the length given, and the control message is
+        // written within the buffer `CMSG_SPACE` sized for it.
+        unsafe {
+            let mut iov = libc::iovec {
+                iov_base: datagram.as_ptr() as *mut libc::c_void,
+                iov_len: datagram.len(),
+            };
+            // u64s keep the control buffer aligned for `cmsghdr`
+            let mut control = [0u64; 8];
+            let info_len = match source {
+                IpAddr::V4(_) => std::mem::size_of::<libc::in_pktinfo>(),
+                IpAddr::V6(_) => std::mem::size_of::<libc::in6_pktinfo>(),
+            } as u32;
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = dest.as_ptr() as *mut libc::c_void;
+            msg.msg_namelen = dest.len();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(info_len) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_len = libc::CMSG_LEN(info_len) as _;
+            match source {
+                IpAddr::V4(v4) => {
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                    let info = libc::in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: libc::in_addr {
+                            s_addr: u32::from(v4).to_be(),
+                        },
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    };
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo, info);
+                }
+                IpAddr::V6(v6) => {
+                    (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                    (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                    let info = libc::in6_pktinfo {
+                        ipi6_addr: libc::in6_addr {
+                            s6_addr: v6.octets(),
+                        },
+                        ipi6_ifindex: 0,
+                    };
+                    std::ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo,
+                        info,
+                    );
+                }
+            }
+
+            if libc::sendmsg(socket.as_raw_fd(), &msg, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    };
+    socket.async_io(Interest::WRITABLE, send).await?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn send_from(
+    socket: &UdpSocket,
+    datagram: &[u8],
+    dest: SocketAddr,
+    _source: IpAddr,
+) -> Result<(), VstpError> {
+    socket.send_to(datagram, dest).await?;
+    Ok(())
+}
+
+/// `recvmsg` with `MSG_TRUNC`, picking the `SCM_TIMESTAMPNS` and
+/// `IP_PKTINFO` or `IPV6_PKTINFO` control messages out of the reply
+#[cfg(target_os = "linux")]
+fn recv_with_control(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, socket2::SockAddr, Option<SystemTime>, Option<IpAddr>)> {
+    use std::os::unix::io::AsRawFd;
+    use std::time::UNIX_EPOCH;
+
+    // SAFETY: all-zero is a valid `sockaddr_storage` and `msghdr`. Every
+    // pointer in `msg` refers to a live local for the length given, and the
+    // control messages are only read within what the kernel filled in.
+    unsafe {
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64s keep the control buffer aligned for `cmsghdr`; room for a
+        // timestamp and an IPv6 packet info
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_TRUNC);
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut timestamp = None;
+        let mut arrived_on = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                    let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+                    timestamp =
+                        Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                }
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = std::ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    // `s_addr` is in network byte order
+                    arrived_on = Some(IpAddr::from(info.ipi_addr.s_addr.to_ne_bytes()));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = std::ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    arrived_on = Some(IpAddr::from(info.ipi6_addr.s6_addr));
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        let addr = socket2::SockAddr::new(addr, msg.msg_namelen);
+        Ok((len as usize, addr, timestamp, arrived_on))
+    }
+}
+
+/// Frames waiting for one `run_workers` worker, highest priority first
+struct WorkerQueue {
+    queue: Mutex<PriorityQueue<(SocketAddr, Frame)>>,
+    notify: Notify,
+}
+
+impl WorkerQueue {
+    fn new(depth: usize) -> Self {
+        Self {
+            queue: Mutex::new(PriorityQueue::new(depth)),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Queue a frame, returning whether a frame had to be dropped
+    fn push(&self, addr: SocketAddr, frame: Frame) -> bool {
+        let priority = frame.priority();
+        let dropped = self.queue.lock().unwrap().push(priority, (addr, frame));
+        self.notify.notify_one();
+        dropped.is_some()
+    }
+
+    async fn pop(&self) -> (SocketAddr, Frame) {
+        loop {
+            if let Some(item) = self.queue.lock().unwrap().pop() {
+                return item;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Aborts a worker task when `run_workers` returns or is cancelled
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Pool of reusable receive buffers
+struct BufferPool {
+    buffer_size: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Upper bound on idle buffers kept by the pool
+const MAX_POOLED_BUFFERS: usize = 32;
+
+impl BufferPool {
+    fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Take a buffer from the pool, allocating one if none are free
+    fn take(&self) -> PooledBuffer<'_> {
+        let buf = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; self.buffer_size]);
+        PooledBuffer { pool: self, buf }
+    }
+}
+
+/// A receive buffer that returns itself to its pool when dropped
+struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Vec<u8>,
+}
+
+impl std::ops::Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl std::ops::DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap();
+        if free.len() < MAX_POOLED_BUFFERS {
+            free.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
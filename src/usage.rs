@@ -0,0 +1,283 @@
+//! Usage accounting and quotas for [`VstpServer`](crate::VstpServer)
+//!
+//! Every request a server handles can be reported to a [`UsageRecorder`] with
+//! the identity of the client, the method, the bytes in each direction and
+//! how long the handler took. [`MemoryUsageRecorder`] adds these up per
+//! identity and method, and can hand the totals to an exporter periodically.
+//!
+//! A [`Quota`] caps the requests and bytes each identity may use per period.
+//! Requests over the cap are answered with an ERR frame with error code
+//! `QuotaExceeded` without reaching the handler. Identities come from
+//! [`ServerOptions::api_keys`](crate::ServerOptions::api_keys); sessions
+//! authenticated any other way count as [`ANONYMOUS`].
+//!
+//! Quota usage lives in the server's memory, so it starts over when the
+//! server restarts.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::task::JoinHandle;
+
+/// Identity of sessions that didn't authenticate with an API key
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Receives one report per handled request
+pub trait UsageRecorder: Send + Sync + fmt::Debug {
+    /// `bytes_in` and `bytes_out` are payload sizes of the request and reply;
+    /// `queue_delay` is how long the request waited for its handler after it
+    /// was received, and `duration` how long the handler took
+    fn record(
+        &self,
+        identity: &str,
+        method: &str,
+        bytes_in: u64,
+        bytes_out: u64,
+        queue_delay: Duration,
+        duration: Duration,
+    );
+}
+
+/// Totals for one identity and method
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRow {
+    pub identity: String,
+    pub method: String,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Time spent waiting for the handler
+    pub queue_delay: Duration,
+    /// Time spent in the handler
+    pub duration: Duration,
+}
+
+/// [`UsageRecorder`] adding up usage in memory
+#[derive(Debug, Default)]
+pub struct MemoryUsageRecorder {
+    rows: Mutex<HashMap<(String, String), UsageRow>>,
+}
+
+impl MemoryUsageRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Totals so far, sorted by identity and method
+    pub fn snapshot(&self) -> Vec<UsageRow> {
+        sorted(self.rows.lock().unwrap().values().cloned().collect())
+    }
+
+    /// Totals so far, starting over from zero
+    pub fn drain(&self) -> Vec<UsageRow> {
+        sorted(
+            self.rows
+                .lock()
+                .unwrap()
+                .drain()
+                .map(|(_, row)| row)
+                .collect(),
+        )
+    }
+
+    /// Every `interval`, [`drain`](MemoryUsageRecorder::drain) the totals and
+    /// pass them to `export`, e.g. to write them to a database
+    ///
+    /// Intervals with no usage are skipped. Abort the returned task to stop.
+    pub fn flush_every<F>(self: &Arc<Self>, interval: Duration, export: F) -> JoinHandle<()>
+    where
+        F: Fn(Vec<UsageRow>) + Send + 'static,
+    {
+        let recorder = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let rows = recorder.drain();
+                if !rows.is_empty() {
+                    export(rows);
+                }
+            }
+        })
+    }
+}
+
+impl UsageRecorder for MemoryUsageRecorder {
+    fn record(
+        &self,
+        identity: &str,
+        method: &str,
+        bytes_in: u64,
+        bytes_out: u64,
+        queue_delay: Duration,
+        duration: Duration,
+    ) {
+        let mut rows = self.rows.lock().unwrap();
+        let row = rows
+            .entry((identity.to_string(), method.to_string()))
+            .or_insert_with(|| UsageRow {
+                identity: identity.to_string(),
+                method: method.to_string(),
+                requests: 0,
+                bytes_in: 0,
+                bytes_out: 0,
+                queue_delay: Duration::ZERO,
+                duration: Duration::ZERO,
+            });
+        row.requests += 1;
+        row.bytes_in += bytes_in;
+        row.bytes_out += bytes_out;
+        row.queue_delay += queue_delay;
+        row.duration += duration;
+    }
+}
+
+fn sorted(mut rows: Vec<UsageRow>) -> Vec<UsageRow> {
+    rows.sort_by(|a, b| (&a.identity, &a.method).cmp(&(&b.identity, &b.method)));
+    rows
+}
+
+/// Requests and bytes each identity may use per period
+///
+/// Periods start at multiples of `period` since the UNIX epoch, so a daily
+/// quota resets at midnight UTC. A request is refused once an identity has
+/// made `max_requests` requests, or moved `max_bytes` bytes of payload in
+/// either direction, in the current period; the request that crosses the
+/// byte limit still goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// `None` for no limit
+    pub max_requests: Option<u64>,
+    /// `None` for no limit
+    pub max_bytes: Option<u64>,
+    pub period: Duration,
+}
+
+impl Quota {
+    /// A quota resetting at midnight UTC
+    pub fn daily(max_requests: Option<u64>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_requests,
+            max_bytes,
+            period: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Usage counted against a [`Quota`] in one period
+#[derive(Debug, Default)]
+struct Spent {
+    period: u64,
+    requests: u64,
+    bytes: u64,
+}
+
+/// Applies a server's quota and reports to its recorder
+#[derive(Debug, Default)]
+pub(crate) struct Meter {
+    recorder: Option<Arc<dyn UsageRecorder>>,
+    quota: Option<Quota>,
+    spent: Mutex<HashMap<String, Spent>>,
+}
+
+impl Meter {
+    pub(crate) fn new(recorder: Option<Arc<dyn UsageRecorder>>, quota: Option<Quota>) -> Self {
+        Self {
+            recorder,
+            quota,
+            spent: Mutex::default(),
+        }
+    }
+
+    /// Count a request against `identity`'s quota, returning why it is refused if it is
+    pub(crate) fn admit(
+        &self,
+        identity: &str,
+        bytes_in: u64,
+        now: SystemTime,
+    ) -> Result<(), String> {
+        let Some(quota) = &self.quota else {
+            return Ok(());
+        };
+        let period = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+            / quota.period.as_secs().max(1);
+        let mut spent = self.spent.lock().unwrap();
+        let spent = spent.entry(identity.to_string()).or_default();
+        if spent.period != period {
+            *spent = Spent {
+                period,
+                ..Spent::default()
+            };
+        }
+        if quota.max_requests.is_some_and(|max| spent.requests >= max) {
+            return Err(format!("{} is out of requests for this period", identity));
+        }
+        if quota.max_bytes.is_some_and(|max| spent.bytes >= max) {
+            return Err(format!("{} is out of bytes for this period", identity));
+        }
+        spent.requests += 1;
+        spent.bytes += bytes_in;
+        Ok(())
+    }
+
+    /// Report a handled request and count its reply against the quota
+    pub(crate) fn finish(
+        &self,
+        identity: &str,
+        method: &str,
+        bytes_in: u64,
+        bytes_out: u64,
+        queue_delay: Duration,
+        duration: Duration,
+    ) {
+        if self.quota.is_some() {
+            if let Some(spent) = self.spent.lock().unwrap().get_mut(identity) {
+                spent.bytes += bytes_out;
+            }
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(identity, method, bytes_in, bytes_out, queue_delay, duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_resets_at_period_boundary() {
+        let quota = Quota {
+            max_requests: Some(2),
+            max_bytes: None,
+            period: Duration::from_secs(60),
+        };
+        let meter = Meter::new(None, Some(quota));
+        let start = UNIX_EPOCH + Duration::from_secs(600);
+        assert!(meter.admit("alice", 0, start).is_ok());
+        assert!(meter
+            .admit("alice", 0, start + Duration::from_secs(30))
+            .is_ok());
+        assert!(meter
+            .admit("alice", 0, start + Duration::from_secs(59))
+            .is_err());
+        assert!(meter.admit("bob", 0, start).is_ok());
+        assert!(meter
+            .admit("alice", 0, start + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_byte_quota_counts_both_directions() {
+        let meter = Meter::new(None, Some(Quota::daily(None, Some(100))));
+        let now = SystemTime::now();
+        assert!(meter.admit("alice", 60, now).is_ok());
+        meter.finish("alice", "get", 60, 30, Duration::ZERO, Duration::ZERO);
+        // 90 bytes so far: the next request may cross the limit, the one after may not
+        assert!(meter.admit("alice", 20, now).is_ok());
+        assert!(meter.admit("alice", 1, now).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+//! Counting heap allocations of the ACK and PONG fast paths
+//!
+//! The counting allocator is global to this test binary, so allocations are
+//! counted per thread.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use vstp::{
+    encode_frame,
+    frame::{encode_ack_into, encode_pong_into, ACK_FRAME_MAX_LEN, PONG_FRAME_MAX_LEN},
+    Frame, FrameType,
+};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations `f` makes per call, over `calls` calls
+fn allocations_per_call(calls: u64, mut f: impl FnMut(u64)) -> f64 {
+    let before = ALLOCATIONS.with(Cell::get);
+    for i in 0..calls {
+        f(i);
+    }
+    (ALLOCATIONS.with(Cell::get) - before) as f64 / calls as f64
+}
+
+#[test]
+fn test_ack_and_pong_fast_paths_do_not_allocate() {
+    let general = allocations_per_call(1_000, |id| {
+        let ack = Frame::new(FrameType::Ack).with_header("msg-id", &id.to_string());
+        std::hint::black_box(encode_frame(&ack).unwrap());
+    });
+    let ack = allocations_per_call(1_000, |id| {
+        let mut buf = [0; ACK_FRAME_MAX_LEN];
+        std::hint::black_box(encode_ack_into(id, &mut buf));
+    });
+    let pong = allocations_per_call(1_000, |ms| {
+        let mut buf = [0; PONG_FRAME_MAX_LEN];
+        std::hint::black_box(encode_pong_into(ms, ms + 1, &mut buf));
+    });
+    println!(
+        "allocations per ACK: general {}, fast path {}",
+        general, ack
+    );
+    assert!(general >= 1.0);
+    assert_eq!((ack, pong), (0.0, 0.0));
+}
@@ -0,0 +1,150 @@
+//! Tests for frame archive files
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use vstp::archive::{
+    index_path, reindex, ArchiveRecord, Direction, FrameArchiveReader, FrameArchiveWriter,
+};
+use vstp::{Frame, FrameType};
+
+const RECORDS: usize = 10_000;
+const SESSIONS: u128 = 7;
+
+/// A path in the temp directory unique to this process and `name`
+fn archive_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("vstp-{}-{}.vsar", name, std::process::id()))
+}
+
+fn cleanup(path: &PathBuf) {
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(index_path(path));
+}
+
+/// Record `n` of the test archives
+fn record(n: usize) -> ArchiveRecord {
+    let typ = if n.is_multiple_of(10) {
+        FrameType::Ping
+    } else {
+        FrameType::Data
+    };
+    ArchiveRecord {
+        timestamp: UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_000 + n as u64),
+        session_id: n as u128 % SESSIONS,
+        direction: if n.is_multiple_of(2) {
+            Direction::Inbound
+        } else {
+            Direction::Outbound
+        },
+        frame: Frame::new(typ)
+            .with_header("n", &n.to_string())
+            .with_payload(format!("payload {}", n).into_bytes()),
+    }
+}
+
+fn write_archive(path: &PathBuf, with_index: bool) {
+    let mut writer = FrameArchiveWriter::create(path).unwrap();
+    if with_index {
+        writer = writer.with_index();
+    }
+    for n in 0..RECORDS {
+        writer.append(&record(n)).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+#[test]
+fn test_archive_round_trip_and_random_access() {
+    let path = archive_path("round-trip");
+    write_archive(&path, true);
+    assert!(index_path(&path).exists());
+
+    let mut reader = FrameArchiveReader::open(&path).unwrap();
+    assert_eq!(reader.len(), RECORDS);
+    assert_eq!(reader.corrupt_tail(), None);
+
+    for (n, read) in reader.iter().enumerate() {
+        assert_eq!(read.unwrap(), record(n));
+    }
+    for n in [9_999, 0, 1_234, 5_000, 1_235, 7] {
+        assert_eq!(reader.get(n).unwrap(), Some(record(n)));
+    }
+    assert_eq!(reader.get(RECORDS).unwrap(), None);
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_archive_filters_by_session() {
+    let path = archive_path("sessions");
+    write_archive(&path, false);
+    assert!(!index_path(&path).exists());
+
+    let mut reader = FrameArchiveReader::open(&path).unwrap();
+    assert_eq!(
+        reader.sessions().collect::<Vec<_>>(),
+        (0..SESSIONS).collect::<Vec<_>>()
+    );
+
+    let records: Vec<ArchiveRecord> = reader.session(3).map(Result::unwrap).collect();
+    let expected: Vec<ArchiveRecord> = (0..RECORDS)
+        .filter(|n| *n as u128 % SESSIONS == 3)
+        .map(record)
+        .collect();
+    assert_eq!(records, expected);
+    assert_eq!(reader.session(42).count(), 0);
+
+    // A rebuilt sidecar gives the same answers
+    assert_eq!(reindex(&path).unwrap(), RECORDS);
+    let mut reader = FrameArchiveReader::open(&path).unwrap();
+    assert_eq!(reader.session_len(3), expected.len());
+    assert_eq!(
+        reader.session(3).last().unwrap().unwrap(),
+        *expected.last().unwrap()
+    );
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_archive_skips_corrupt_tail() {
+    let path = archive_path("corrupt");
+    let mut writer = FrameArchiveWriter::create(&path).unwrap().with_index();
+    for n in 0..100 {
+        writer.append(&record(n)).unwrap();
+    }
+    writer.finish().unwrap();
+    let intact_len = std::fs::metadata(&path).unwrap().len();
+
+    // A crash mid-write leaves half a record: a length, a bad CRC and a few bytes
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0, 0, 0, 60, 1, 2, 3, 4, 0x56, 0x54])
+        .unwrap();
+    drop(file);
+
+    let mut reader = FrameArchiveReader::open(&path).unwrap();
+    assert_eq!(reader.len(), 100);
+    assert_eq!(reader.corrupt_tail(), Some(intact_len));
+    assert_eq!(reader.iter().count(), 100);
+
+    // Appending cuts the damaged record off first
+    let mut writer = FrameArchiveWriter::open(&path).unwrap();
+    writer.append(&record(100)).unwrap();
+    writer.finish().unwrap();
+    let mut reader = FrameArchiveReader::open(&path).unwrap();
+    assert_eq!(reader.corrupt_tail(), None);
+    assert_eq!(reader.len(), 101);
+    assert_eq!(reader.get(100).unwrap(), Some(record(100)));
+
+    cleanup(&path);
+}
+
+#[test]
+fn test_archive_rejects_other_files() {
+    let path = archive_path("not-an-archive");
+    std::fs::write(&path, b"GET / HTTP/1.1\r\n").unwrap();
+    assert!(FrameArchiveReader::open(&path).is_err());
+    cleanup(&path);
+}
@@ -0,0 +1,170 @@
+//! Tests for tunnelling byte streams through VSTP
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use vstp::chunk::{FIN_HEADER, SEQ_HEADER, STREAM_ID_HEADER, TOTAL_HEADER};
+use vstp::flow::{FlowController, ReceiveWindow, WindowCredit};
+use vstp::tcp::{ByteStreamConfig, VstpByteStream, VstpTcpConnection};
+use vstp::{Frame, FrameType, VstpTcpClient, VstpTcpServer};
+
+/// Echo the client's byte stream back, adding `trailer` once the client ends its direction
+///
+/// Credit goes back to the client only as frames are echoed, so the client's
+/// writer can't get ahead of its own reader by more than both windows.
+async fn echo(mut conn: VstpTcpConnection, window: WindowCredit, trailer: &'static [u8]) {
+    let hello = conn.recv().await.unwrap().unwrap();
+    assert_eq!(hello.typ, FrameType::Hello);
+    conn.send(window.apply_to(Frame::new(FrameType::Welcome)))
+        .await
+        .unwrap();
+
+    let mut receive = ReceiveWindow::new(window);
+    let mut flow: Option<FlowController> = None;
+    let mut pending = VecDeque::new();
+    let mut sent = 0;
+    while let Some(frame) = conn.recv().await.unwrap() {
+        match frame.typ {
+            FrameType::Ack => match &flow {
+                Some(flow) => {
+                    flow.handle_frame(&frame);
+                }
+                None => flow = WindowCredit::from_frame(&frame).map(FlowController::new),
+            },
+            FrameType::Data if frame.get_header(FIN_HEADER) == Some("1") => {
+                pending.push_back((
+                    Frame::new(FrameType::Data).with_payload(trailer.to_vec()),
+                    false,
+                ));
+                pending.push_back((frame, true));
+            }
+            FrameType::Data => pending.push_back((frame, false)),
+            FrameType::Bye => break,
+            _ => {}
+        }
+
+        let flow = flow.as_ref().expect("client advertises its window first");
+        while let Some((frame, fin)) = pending.front() {
+            let fin = *fin;
+            if !fin && !flow.try_acquire(frame.payload.len()) {
+                break;
+            }
+            let (frame, _) = pending.pop_front().unwrap();
+            if let Some(update) = receive.consume(&frame) {
+                conn.send(update).await.unwrap();
+            }
+            let mut echoed = Frame::new(FrameType::Data)
+                .with_header(STREAM_ID_HEADER, "echo")
+                .with_header(SEQ_HEADER, &sent.to_string())
+                .with_payload(frame.payload);
+            sent += 1;
+            if fin {
+                echoed = echoed
+                    .with_header(TOTAL_HEADER, &sent.to_string())
+                    .with_header(FIN_HEADER, "1");
+            }
+            conn.send(echoed).await.unwrap();
+        }
+    }
+}
+
+/// Connect a byte stream to an [`echo`] server
+async fn echo_stream(
+    window: WindowCredit,
+    trailer: &'static [u8],
+    config: ByteStreamConfig,
+) -> VstpByteStream {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let conn = server.accept().await.unwrap();
+        echo(conn, window, trailer).await;
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    client.send_hello().await.unwrap();
+    let welcome = client.recv().await.unwrap().unwrap();
+    assert_eq!(welcome.typ, FrameType::Welcome);
+    VstpByteStream::with_config(client, &welcome.headers, config)
+}
+
+#[tokio::test]
+async fn test_byte_stream_tunnels_10mb() {
+    let mut data = vec![0u8; 10 * 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut data);
+    let expected = Sha256::digest(&data);
+
+    let config = ByteStreamConfig {
+        receive_window: WindowCredit::new(Some(32), None),
+        ..ByteStreamConfig::default()
+    };
+    let stream = echo_stream(WindowCredit::new(None, Some(256 * 1024)), b"", config).await;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let write = tokio::spawn(async move {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+    let mut echoed = Vec::new();
+    timeout(Duration::from_secs(60), reader.read_to_end(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    write.await.unwrap();
+
+    assert_eq!(echoed.len(), 10 * 1024 * 1024);
+    assert_eq!(Sha256::digest(&echoed), expected);
+}
+
+#[tokio::test]
+async fn test_byte_stream_half_close() {
+    let mut stream = echo_stream(
+        WindowCredit::new(Some(4), None),
+        b" -- done",
+        ByteStreamConfig::default(),
+    )
+    .await;
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert_eq!(
+        stream.write(b"late").await.unwrap_err().kind(),
+        std::io::ErrorKind::BrokenPipe
+    );
+
+    // The peer keeps sending after our direction ended
+    let mut echoed = String::new();
+    timeout(Duration::from_secs(5), stream.read_to_string(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, "hello -- done");
+
+    // Both directions are done, so the connection closes
+    timeout(Duration::from_secs(5), async {
+        while !stream.is_closed() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_byte_stream_blocking_io() {
+    let mut stream = echo_stream(WindowCredit::default(), b"!", ByteStreamConfig::default()).await;
+    let echoed = tokio::task::spawn_blocking(move || {
+        std::io::Write::write_all(&mut stream, b"line one\nline two\n").unwrap();
+        stream.close_write();
+        let mut echoed = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut echoed).unwrap();
+        echoed
+    })
+    .await
+    .unwrap();
+    assert_eq!(echoed, "line one\nline two\n!");
+}
@@ -150,9 +150,11 @@ async fn test_auto_mode_adaptive_startup_with_udp() {
 
     tokio::time::sleep(Duration::from_millis(100)).await;
 
-    let mut cfg = AutoSwitchConfig::default();
-    cfg.probe_attempts = 1;
-    cfg.probe_timeout = Duration::from_millis(300);
+    let cfg = AutoSwitchConfig {
+        probe_attempts: 1,
+        probe_timeout: Duration::from_millis(300),
+        ..AutoSwitchConfig::default()
+    };
     let client = VstpClient::connect_auto_with_config("127.0.0.1:8091", cfg)
         .await
         .unwrap();
@@ -38,7 +38,7 @@ async fn test_complex_frame_with_all_features() {
 
     let tcp_handle = tokio::spawn(async move {
         tcp_server
-            .run(|session_id, frame| async move {
+            .run(|_session_id, frame| async move {
                 // Verify all headers are preserved
                 assert_eq!(frame.headers.len(), 10);
                 assert_eq!(frame.payload.len(), 10000);
@@ -137,8 +137,7 @@ async fn test_udp_massive_payload_fragmentation() {
         client.send_with_ack(massive_frame, udp_addr),
     )
     .await;
-    if result.is_ok() {
-        let send_result = result.unwrap();
+    if let Ok(send_result) = result {
         if send_result.is_ok() {
             println!("✅ Massive payload sent and ACK received!");
         } else {
@@ -468,8 +467,7 @@ async fn test_mixed_transport_complex_data() {
         udp_client.send_with_ack(udp_frame, udp_addr),
     )
     .await;
-    if result.is_ok() {
-        let send_result = result.unwrap();
+    if let Ok(send_result) = result {
         if send_result.is_ok() {
             println!("✅ UDP transfer completed with ACK!");
         } else {
@@ -0,0 +1,173 @@
+//! Tests for the built-in connectivity diagnostics
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use vstp::diagnostics::{self, health_reply, DiagnosticOptions, Endpoint, StepStatus};
+use vstp::easy::{VstpClient, VstpServer};
+use vstp::{VstpError, VstpTcpServer, VstpUdpServer};
+
+fn assert_plausible(report: &diagnostics::DiagnosticReport) {
+    for step in &report.steps {
+        assert!(
+            step.elapsed < Duration::from_secs(5),
+            "step {} took {:?}",
+            step.name,
+            step.elapsed
+        );
+    }
+    let rtt = report.ping_rtt.expect("ping RTT should be recorded");
+    assert!(rtt.min <= rtt.avg && rtt.avg <= rtt.max);
+    assert!(rtt.max < Duration::from_secs(1), "loopback RTT {:?}", rtt.max);
+}
+
+#[tokio::test]
+async fn test_self_test_tcp() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let server_handle = tokio::spawn(async move {
+        loop {
+            let mut conn = server.accept().await.unwrap();
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    if let Some(reply) = health_reply(&frame) {
+                        conn.send(reply).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    let report = diagnostics::self_test(Endpoint::Tcp(server_addr), DiagnosticOptions::default()).await;
+    println!("{}", report);
+
+    assert!(report.passed(), "{}", report);
+    for name in ["tcp-connect", "handshake", "ping", "echo"] {
+        assert!(report.step(name).unwrap().passed(), "step {} failed", name);
+    }
+    assert!(matches!(
+        report.step("tls-handshake").unwrap().status,
+        StepStatus::Skipped(_)
+    ));
+    assert_eq!(report.negotiated_version, Some(vstp::VSTP_VERSION));
+    assert_eq!(report.capabilities, vec!["health-echo".to_string()]);
+    assert_plausible(&report);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_self_test_udp() {
+    let server = Arc::new(VstpUdpServer::bind("127.0.0.1:0").await.unwrap());
+    let server_addr = server.local_addr().unwrap();
+
+    let responder = server.clone();
+    let server_handle = tokio::spawn(async move {
+        while let Ok((frame, addr)) = responder.recv().await {
+            if let Some(reply) = health_reply(&frame) {
+                responder.send(reply, addr).await.unwrap();
+            }
+        }
+    });
+
+    let report = diagnostics::self_test(Endpoint::Udp(server_addr), DiagnosticOptions::default()).await;
+    println!("{}", report);
+
+    assert!(report.passed(), "{}", report);
+    for name in [
+        "udp-bind",
+        "handshake",
+        "ping",
+        "echo",
+        "fragmentation-4096",
+        "fragmentation-32768",
+    ] {
+        assert!(report.step(name).unwrap().passed(), "step {} failed", name);
+    }
+    assert_plausible(&report);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_self_test_reports_refused_connection() {
+    // Grab a free port and release it so nothing is listening there
+    let addr = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    let report = diagnostics::self_test(Endpoint::Tcp(addr), DiagnosticOptions::default()).await;
+
+    assert!(!report.passed());
+    assert!(matches!(
+        report.step("tcp-connect").unwrap().status,
+        StepStatus::Failed(vstp::VstpError::Io(_))
+    ));
+    assert!(matches!(
+        report.step("ping").unwrap().status,
+        StepStatus::Skipped(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_http_server_is_named_in_decode_error() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    });
+
+    let err = match VstpClient::connect_tcp(addr.to_string()).await {
+        Err(e) => e,
+        Ok(_) => panic!("an HTTP server shouldn't complete a handshake"),
+    };
+    assert!(matches!(err, VstpError::NotVstp { .. }), "got {:?}", err);
+    let message = err.to_string();
+    assert!(message.contains("an HTTP response"), "{}", message);
+    assert!(message.contains("48 54 54 50 2f 31 2e 31"), "{}", message);
+    assert!(message.contains("|HTTP/1.1 400 Bad|"), "{}", message);
+}
+
+#[test]
+fn test_sniff_protocol() {
+    let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+    assert!(diagnostics::sniff_protocol(&client_hello)
+        .unwrap()
+        .contains("TLS ClientHello"));
+    assert_eq!(
+        diagnostics::sniff_protocol(b"GET / HTTP/1.1\r\n"),
+        Some("an HTTP request")
+    );
+    assert_eq!(
+        diagnostics::sniff_protocol(b"SSH-2.0-OpenSSH_9.6"),
+        Some("an SSH banner")
+    );
+    assert_eq!(diagnostics::sniff_protocol(&[0x56, 0x54, 0x02]), None);
+}
+
+#[tokio::test]
+async fn test_ident_reports_server_software() {
+    let server = VstpServer::bind_tcp("127.0.0.1:8111").await.unwrap();
+    let handle =
+        tokio::spawn(async move { server.serve(|msg: String| async move { Ok(msg) }).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let ident = diagnostics::ident("127.0.0.1:8111".parse().unwrap(), Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(
+        ident.software.as_deref(),
+        Some(concat!("vstp/", env!("CARGO_PKG_VERSION")))
+    );
+    assert_eq!(ident.versions, [1, 2]);
+    assert!(ident.to_string().contains("vstp-versions:   1, 2"));
+
+    handle.abort();
+}
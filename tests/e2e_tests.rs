@@ -0,0 +1,171 @@
+//! Tests for payloads sealed end to end
+#![cfg(feature = "e2e")]
+
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use vstp::{
+    e2e::{KeyPair, E2E_HEADER},
+    easy::{ServerOptions, VstpClient, VstpServer},
+    tcp::{TcpServerConfig, VstpTcpServer},
+    testing::{FrameTap, TapDirection},
+    Frame, FrameType, VstpError, WireTap,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Note {
+    text: String,
+}
+
+const SECRET_TEXT: &str = "meet at the north gate";
+
+fn note() -> Note {
+    Note {
+        text: SECRET_TEXT.to_string(),
+    }
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+#[test]
+fn test_sealed_frames_open_only_with_the_recipients_key() {
+    let recipient = KeyPair::generate();
+    let sealed = Frame::new(FrameType::Data)
+        .with_header("route", "billing")
+        .with_header("account", "42")
+        .with_payload(SECRET_TEXT.as_bytes().to_vec())
+        .seal_headers_for(&recipient.public_key(), &["account"]);
+
+    assert!(sealed.is_sealed());
+    assert_eq!(sealed.get_header(E2E_HEADER), Some("v1"));
+    assert_eq!(sealed.get_header("route"), Some("billing"));
+    assert_eq!(sealed.get_header("account"), None);
+    assert!(!contains(&sealed.payload, SECRET_TEXT));
+
+    let opened = sealed.clone().open(recipient.secret()).unwrap();
+    assert_eq!(opened.payload, SECRET_TEXT.as_bytes());
+    assert_eq!(opened.get_header("route"), Some("billing"));
+    assert_eq!(opened.get_header("account"), Some("42"));
+    assert!(!opened.is_sealed());
+
+    let stranger = KeyPair::generate();
+    assert!(matches!(
+        sealed.clone().open(stranger.secret()),
+        Err(VstpError::Unsealing(_))
+    ));
+
+    let mut tampered = sealed.clone();
+    tampered.payload[0] ^= 1;
+    assert!(matches!(
+        tampered.open(recipient.secret()),
+        Err(VstpError::Unsealing(_))
+    ));
+    let mut retyped = sealed.clone();
+    retyped.typ = FrameType::Ping;
+    assert!(matches!(
+        retyped.open(recipient.secret()),
+        Err(VstpError::Unsealing(_))
+    ));
+
+    // Headers added on the way aren't covered by the seal
+    let relayed = sealed.with_header("x-relay", "edge-1");
+    assert!(relayed.open(recipient.secret()).is_ok());
+
+    let plain = Frame::new(FrameType::Data).with_payload(b"plain".to_vec());
+    assert!(matches!(
+        plain.open(recipient.secret()),
+        Err(VstpError::Unsealing(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_server_opens_requests_sealed_through_a_relay() -> Result<(), VstpError> {
+    let key = KeyPair::generate();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    server.set_options(ServerOptions {
+        e2e_key: Some(key.clone()),
+        ..ServerOptions::default()
+    });
+    tokio::spawn(server.serve(|note: Note| async move { Ok(note) }));
+
+    let tap = FrameTap::start(addr).await?;
+    let client = VstpClient::connect_tcp(tap.addr().to_string()).await?;
+    client.send_sealed(note(), &key.public_key()).await?;
+    let reply: Note = client.receive().await?;
+    assert_eq!(reply, note());
+
+    // The relay forwarded the request without being able to read it
+    let requests: Vec<Frame> = tap
+        .frames()
+        .into_iter()
+        .filter(|(direction, frame)| {
+            *direction == TapDirection::ToServer && frame.typ == FrameType::Data
+        })
+        .map(|(_, frame)| frame)
+        .collect();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].is_sealed());
+    assert!(!contains(&requests[0].payload, SECRET_TEXT));
+
+    // Sealed to someone else, the request is refused
+    client
+        .send_sealed(note(), &KeyPair::generate().public_key())
+        .await?;
+    assert!(matches!(
+        client.receive::<Note>().await,
+        Err(VstpError::ServerError(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wire_tap_sees_only_ciphertext() -> Result<(), VstpError> {
+    let tapped = Arc::new(Mutex::new(Vec::new()));
+    let tap = tapped.clone();
+    let config = TcpServerConfig {
+        wire_tap: WireTap::new().on_wire_in(move |b| tap.lock().unwrap().extend_from_slice(b)),
+        ..TcpServerConfig::default()
+    };
+    // An intermediary without keys, sending DATA frames back as they came
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config).await?;
+    let addr = server.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok(mut conn) = server.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    let reply = match frame.typ {
+                        FrameType::Hello => Frame::new(FrameType::Welcome),
+                        FrameType::Data => frame,
+                        _ => continue,
+                    };
+                    let _ = conn.send(reply).await;
+                }
+            });
+        }
+    });
+
+    let own = KeyPair::generate();
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+    client.send_sealed(note(), &own.public_key()).await?;
+    let echoed: Note = client.receive_opened(own.secret()).await?;
+    assert_eq!(echoed, note());
+
+    let wire = tapped.lock().unwrap().clone();
+    assert!(contains(&wire, E2E_HEADER));
+    assert!(!contains(&wire, SECRET_TEXT));
+
+    client.send_sealed(note(), &own.public_key()).await?;
+    assert!(matches!(
+        client
+            .receive_opened::<Note>(KeyPair::generate().secret())
+            .await,
+        Err(VstpError::Unsealing(_))
+    ));
+    Ok(())
+}
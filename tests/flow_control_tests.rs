@@ -0,0 +1,131 @@
+//! Tests for credit-based flow control
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use vstp::flow::{
+    is_window_update, window_update, FlowController, ReceiveWindow, WindowCredit,
+};
+use vstp::{Frame, FrameType, VstpTcpClient, VstpTcpServer};
+
+#[tokio::test]
+async fn test_sender_pauses_until_window_update() {
+    let controller = Arc::new(FlowController::new(WindowCredit::new(Some(2), None)));
+
+    assert!(controller.try_acquire(100));
+    assert!(controller.try_acquire(100));
+    assert!(!controller.try_acquire(100));
+
+    let waiter = controller.clone();
+    let blocked = tokio::spawn(async move { waiter.acquire(100).await });
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!blocked.is_finished(), "sender must pause on an exhausted window");
+
+    let update = window_update(WindowCredit::new(Some(1), None));
+    assert!(is_window_update(&update));
+    assert!(controller.handle_frame(&update));
+
+    timeout(Duration::from_secs(1), blocked).await.unwrap().unwrap();
+    assert_eq!(controller.available().frames, Some(0));
+}
+
+#[tokio::test]
+async fn test_byte_window() {
+    let controller = FlowController::new(WindowCredit::new(None, Some(1000)));
+
+    assert!(controller.try_acquire(600));
+    assert!(!controller.try_acquire(600), "only 400 bytes of credit left");
+    assert!(controller.try_acquire(400));
+
+    controller.grant(WindowCredit::new(Some(5), Some(600)));
+    // The frame limit was never advertised, so it stays unlimited
+    assert_eq!(controller.available(), WindowCredit::new(None, Some(600)));
+    assert!(controller.try_acquire(600));
+}
+
+#[test]
+fn test_window_update_format() {
+    let update = window_update(WindowCredit::new(Some(8), Some(65536)));
+    assert_eq!(update.typ, FrameType::Ack);
+    assert_eq!(update.get_header("window-frames"), Some("8"));
+    assert_eq!(update.get_header("window-bytes"), Some("65536"));
+    assert!(update.payload.is_empty());
+    assert_eq!(
+        WindowCredit::from_frame(&update),
+        Some(WindowCredit::new(Some(8), Some(65536)))
+    );
+
+    // A msg-id ACK is not a window update
+    let ack = Frame::new(FrameType::Ack).with_header("msg-id", "1");
+    assert!(!is_window_update(&ack));
+}
+
+#[test]
+fn test_receive_window_returns_credit_at_half() {
+    let mut window = ReceiveWindow::new(WindowCredit::new(Some(4), None));
+    let frame = Frame::new(FrameType::Data).with_payload(vec![0; 10]);
+
+    assert!(window.consume(&frame).is_none());
+    let update = window.consume(&frame).expect("update after half the window");
+    assert_eq!(
+        WindowCredit::from_frame(&update),
+        Some(WindowCredit::new(Some(2), None))
+    );
+    assert!(window.consume(&frame).is_none());
+}
+
+#[tokio::test]
+async fn test_fast_producer_respects_slow_consumer_window() {
+    const WINDOW: u64 = 4;
+    const TOTAL: usize = 20;
+
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let consumer = tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        let mut window = ReceiveWindow::new(WindowCredit::new(Some(WINDOW), None));
+        let mut granted = WINDOW;
+        let mut received = 0u64;
+
+        let hello = conn.recv().await.unwrap().unwrap();
+        assert_eq!(hello.typ, FrameType::Hello);
+        let welcome = window.initial_credit().apply_to(Frame::new(FrameType::Welcome));
+        conn.send(welcome).await.unwrap();
+
+        while received < TOTAL as u64 {
+            let frame = conn.recv().await.unwrap().unwrap();
+            received += 1;
+            assert!(received <= granted, "sender exceeded the advertised window");
+
+            // Slow consumer
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if let Some(update) = window.consume(&frame) {
+                granted += WindowCredit::from_frame(&update).unwrap().frames.unwrap();
+                conn.send(update).await.unwrap();
+            }
+        }
+        received
+    });
+
+    let mut client = VstpTcpClient::connect(&server_addr.to_string()).await.unwrap();
+    client.send_hello().await.unwrap();
+    let welcome = client.recv().await.unwrap().unwrap();
+    let controller = FlowController::new(WindowCredit::from_frame(&welcome).unwrap());
+
+    let mut paused = 0;
+    for i in 0..TOTAL {
+        let frame = Frame::new(FrameType::Data).with_payload(format!("item {}", i).into_bytes());
+        while !controller.try_acquire(frame.payload.len()) {
+            paused += 1;
+            let update = client.recv().await.unwrap().unwrap();
+            controller.handle_frame(&update);
+        }
+        client.send(frame).await.unwrap();
+    }
+
+    let received = timeout(Duration::from_secs(5), consumer).await.unwrap().unwrap();
+    assert_eq!(received, TOTAL as u64);
+    assert!(paused > 0, "producer should have been throttled");
+}
@@ -1,5 +1,18 @@
 use bytes::{BufMut, BytesMut};
-use vstp::{encode_frame, try_decode_frame, Flags, Frame, FrameType, Header};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use vstp::{
+    encode_frame, encode_frame_canonical,
+    frame::{encode_ack_into, encode_pong_into, ACK_FRAME_MAX_LEN, PONG_FRAME_MAX_LEN},
+    try_decode_frame,
+    types::{
+        error_codes, DEFAULT_MAX_HEADERS, ERROR_CODE_HEADER, ERROR_NUMBER_HEADER, VOLATILE_HEADERS,
+        VSTP_MAGIC, VSTP_VERSION_2,
+    },
+    ErrorCode, Flags, Frame, FrameType, Header, HeaderRatioLimit, Integrity, VstpError,
+};
 
 #[test]
 fn test_basic_frame_roundtrip() {
@@ -39,6 +52,104 @@ fn test_frame_with_payload() {
     assert_eq!(decoded.payload, payload);
 }
 
+#[test]
+fn test_header_count_limit_boundary() {
+    let mut frame = Frame::new(FrameType::Data);
+    for i in 0..DEFAULT_MAX_HEADERS {
+        frame = frame.with_header(&format!("h{}", i), "v");
+    }
+    assert!(frame.check_header_count(DEFAULT_MAX_HEADERS).is_ok());
+
+    let frame = frame.with_header("one-more", "v");
+    match frame.check_header_count(DEFAULT_MAX_HEADERS) {
+        Err(err @ VstpError::TooManyHeaders { .. }) => {
+            assert!(!err.is_retryable());
+            assert_eq!(
+                err.to_string(),
+                format!("65 headers exceed the limit of {}", DEFAULT_MAX_HEADERS)
+            );
+        }
+        other => panic!("Expected TooManyHeaders, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_header_ratio_limit() {
+    let limit = HeaderRatioLimit::default();
+    let padded = |payload: &[u8]| {
+        (0..8)
+            .fold(Frame::new(FrameType::Data), |frame, i| {
+                frame.with_header(&format!("x-pad-{}", i), &"p".repeat(200))
+            })
+            .with_payload(payload.to_vec())
+    };
+
+    let wrapped = padded(b"hi");
+    assert_eq!(wrapped.header_bytes(), 8 * (7 + 200));
+    assert_eq!(wrapped.header_to_payload_ratio(), 828.0);
+    match limit.check(&wrapped) {
+        Err(err @ VstpError::SuspiciousFrame { .. }) => {
+            assert!(!err.is_retryable());
+            assert!(matches!(
+                err,
+                VstpError::SuspiciousFrame {
+                    header_bytes: 1656,
+                    payload_bytes: 2
+                }
+            ));
+        }
+        other => panic!("Expected SuspiciousFrame, got {:?}", other),
+    }
+
+    // The same headers pass around a payload of proportionate size
+    assert!(limit.check(&padded(&[0; 200])).is_ok());
+    // Small control frames pass whatever their payload
+    let ping = Frame::new(FrameType::Ping).with_header("session", "abc");
+    assert!(limit.check(&ping).is_ok());
+}
+
+#[test]
+fn test_ack_and_pong_fast_paths_match_encode_frame() {
+    for id in [0, 7, 42, 1_000_000, u64::MAX] {
+        let mut buf = [0; ACK_FRAME_MAX_LEN];
+        let len = encode_ack_into(id, &mut buf);
+        let ack = Frame::new(FrameType::Ack).with_header("msg-id", &id.to_string());
+        assert_eq!(&buf[..len], &encode_frame(&ack).unwrap()[..]);
+
+        let mut buf = [0; PONG_FRAME_MAX_LEN];
+        let len = encode_pong_into(id, u64::MAX - id, &mut buf);
+        let pong = Frame::new(FrameType::Pong)
+            .with_header("ping-sent-ms", &id.to_string())
+            .with_header("server-time-ms", &(u64::MAX - id).to_string());
+        assert_eq!(&buf[..len], &encode_frame(&pong).unwrap()[..]);
+    }
+    // The longest ones fill the buffers exactly
+    assert_eq!(
+        encode_ack_into(u64::MAX, &mut [0; ACK_FRAME_MAX_LEN]),
+        ACK_FRAME_MAX_LEN
+    );
+    assert_eq!(
+        encode_pong_into(u64::MAX, u64::MAX, &mut [0; PONG_FRAME_MAX_LEN]),
+        PONG_FRAME_MAX_LEN
+    );
+}
+
+#[test]
+fn test_take_payload_and_headers() {
+    let mut frame = Frame::new(FrameType::Data)
+        .with_header("route", "orders")
+        .with_payload(b"forward me".to_vec());
+
+    assert_eq!(frame.take_payload(), b"forward me");
+    assert_eq!(
+        frame.take_headers(),
+        vec![Header::from_str("route", "orders")]
+    );
+    assert!(frame.payload.is_empty());
+    assert!(frame.headers.is_empty());
+    assert_eq!(frame.typ, FrameType::Data);
+}
+
 #[test]
 fn test_frame_with_flags() {
     let frame = Frame::new(FrameType::Data)
@@ -78,6 +189,38 @@ fn test_all_frame_types() {
     }
 }
 
+#[test]
+fn test_unknown_frame_type_roundtrips_verbatim() {
+    let frame = Frame {
+        version: VSTP_VERSION_2,
+        typ: FrameType::from_byte(0x9a),
+        flags: Flags::CRC | Flags::REQ_ACK,
+        headers: vec![
+            Header::from_str("ext", "telemetry"),
+            Header::new(vec![0x01, 0xfe], vec![]),
+        ],
+        payload: vec![0, 1, 2, 3],
+    };
+    assert_eq!(frame.typ, FrameType::Extension(0x9a));
+
+    let encoded = encode_frame(&frame).unwrap();
+    let decoded = try_decode_frame(&mut BytesMut::from(&encoded[..]), 1024)
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded, frame);
+    assert_eq!(encode_frame(&decoded).unwrap(), encoded);
+
+    // Every byte VSTP doesn't define survives, not only the extension range
+    for byte in 0..=u8::MAX {
+        let typ = FrameType::from_byte(byte);
+        assert_eq!(typ.to_u8(), byte);
+        assert_eq!(
+            matches!(typ, FrameType::Extension(_)),
+            FrameType::from_u8(byte).is_none()
+        );
+    }
+}
+
 #[test]
 fn test_large_payload() {
     let payload = vec![0x42; 10000]; // 10KB payload
@@ -115,6 +258,37 @@ fn test_crc_validation() {
     assert!(result.is_err());
 }
 
+#[test]
+fn test_sha256_integrity() {
+    let frame = Frame::new(FrameType::Data)
+        .with_flag(Flags::SHA256)
+        .with_header("test", "value")
+        .with_payload(b"payload".to_vec());
+    assert_eq!(Integrity::of(frame.flags), Integrity::Sha256);
+
+    let encoded = encode_frame(&frame).unwrap();
+    let mut buf = BytesMut::from(&encoded[..]);
+    assert_eq!(try_decode_frame(&mut buf, 1024).unwrap().unwrap(), frame);
+    assert!(buf.is_empty());
+
+    // Corruption anywhere, trailer included, is caught
+    for index in [5, encoded.len() / 2, encoded.len() - 1] {
+        let mut corrupted = encoded.to_vec();
+        corrupted[index] ^= 0x01;
+        let mut buf = BytesMut::from(&corrupted[..]);
+        assert!(matches!(
+            try_decode_frame(&mut buf, 1024),
+            Err(VstpError::DigestMismatch)
+        ));
+    }
+
+    // Flipping the flag changes which trailer the decoder expects
+    let mut stripped = encoded.to_vec();
+    stripped[4] &= !Flags::SHA256.bits();
+    let mut buf = BytesMut::from(&stripped[..]);
+    assert!(try_decode_frame(&mut buf, 1024).is_err());
+}
+
 #[test]
 fn test_incomplete_frame() {
     let frame = Frame::new(FrameType::Hello);
@@ -150,6 +324,31 @@ fn test_frame_size_limit() {
     assert!(result.is_err());
 }
 
+#[test]
+fn test_oversized_frame_rejected_from_length_fields() {
+    // Only the fixed header has arrived; it claims a 4 GiB payload
+    let mut buf = BytesMut::new();
+    buf.put_slice(&VSTP_MAGIC);
+    buf.put_slice(&[0x01, 0x03, 0x00]);
+    buf.put_u16_le(0);
+    buf.put_u32(u32::MAX);
+    let result = try_decode_frame(&mut buf, 1024);
+    assert!(matches!(
+        result,
+        Err(VstpError::FrameTooLarge { size, limit: 1024 }) if size == 11 + u32::MAX as usize + 4
+    ));
+    assert_eq!(buf.len(), 11);
+
+    // The same holds for the wider v2 length fields
+    let mut buf = BytesMut::new();
+    buf.put_slice(&VSTP_MAGIC);
+    buf.put_slice(&[VSTP_VERSION_2, 0x03, 0x00, 0x00]);
+    buf.put_u32(u32::MAX);
+    buf.put_u32(u32::MAX);
+    let result = try_decode_frame(&mut buf, 1024);
+    assert!(matches!(result, Err(VstpError::FrameTooLarge { .. })));
+}
+
 #[test]
 fn test_header_validation() {
     // Test header key too long
@@ -270,3 +469,372 @@ fn test_complex_frame() {
     assert!(payload_str.contains("Hello, VSTP!"));
     assert!(payload_str.contains("1234567890"));
 }
+
+#[test]
+fn test_binary_header_roundtrip() {
+    let uuid: [u8; 16] = [
+        0x00, 0xff, 0xfe, 0x80, 0x00, 0xc3, 0x28, 0xa0, 0xa1, 0x00, 0xe2, 0x28, 0xa1, 0xf0, 0x90,
+        0x00,
+    ];
+    let timestamp = 1_700_000_000_123u64.to_be_bytes();
+    let frame = Frame::new(FrameType::Data)
+        .with_binary_header("uuid", &uuid)
+        .with_binary_header("ts", &timestamp)
+        .with_binary_header("nul", &[0x00])
+        .with_header("content-type", "application/octet-stream")
+        .with_payload(b"binary headers".to_vec());
+
+    let encoded = encode_frame(&frame).unwrap();
+    let mut buf = BytesMut::from(&encoded[..]);
+    let decoded = try_decode_frame(&mut buf, 1024).unwrap().unwrap();
+
+    assert_eq!(frame, decoded);
+    assert_eq!(decoded.get_binary_header("uuid"), Some(&uuid[..]));
+    assert_eq!(decoded.get_binary_header("ts"), Some(&timestamp[..]));
+    assert_eq!(decoded.get_binary_header("nul"), Some(&[0x00][..]));
+    // Non-UTF-8 values are not visible through the string accessor
+    assert_eq!(decoded.get_header("uuid"), None);
+    assert_eq!(
+        decoded.get_binary_header("content-type"),
+        Some(&b"application/octet-stream"[..])
+    );
+}
+
+#[test]
+fn test_payload_text() {
+    let frame = Frame::new(FrameType::Data).with_payload_text("héllo");
+    assert_eq!(frame.payload, "héllo".as_bytes());
+    assert_eq!(frame.payload_text().unwrap(), "héllo");
+
+    let frame = Frame::new(FrameType::Data).with_payload(vec![0xff, 0xfe]);
+    assert!(frame.payload_text().is_err());
+}
+
+#[test]
+fn test_validate_content_type() {
+    let json = Frame::new(FrameType::Data)
+        .with_header("content-type", "application/json; charset=utf-8")
+        .with_payload_text(r#"{"temp": 21.5}"#);
+    assert!(json.validate().is_ok());
+
+    let broken_json = Frame::new(FrameType::Data)
+        .with_header("content-type", "application/json")
+        .with_payload_text(r#"{"temp": "#);
+    assert!(matches!(
+        broken_json.validate(),
+        Err(vstp::VstpError::InvalidPayload(_))
+    ));
+
+    let problem_json = Frame::new(FrameType::Data)
+        .with_header("content-type", "application/problem+json")
+        .with_payload_text("not json");
+    assert!(problem_json.validate().is_err());
+
+    let text = Frame::new(FrameType::Data)
+        .with_header("content-type", "text/plain")
+        .with_payload(vec![0xc3, 0x28]);
+    assert!(text.validate().is_err());
+
+    // Unknown or missing content types are not checked
+    let binary = Frame::new(FrameType::Data)
+        .with_header("content-type", "application/octet-stream")
+        .with_payload(vec![0xc3, 0x28]);
+    assert!(binary.validate().is_ok());
+    assert!(Frame::new(FrameType::Data)
+        .with_payload_text("{")
+        .validate()
+        .is_ok());
+}
+
+#[test]
+fn test_reference_vectors() {
+    for vector in vstp::testvectors::VECTORS {
+        let frame = (vector.frame)();
+        assert_eq!(&encode_frame(&frame).unwrap()[..], vector.encoded, "{}", vector.name);
+
+        let mut buf = BytesMut::from(vector.encoded);
+        let decoded = try_decode_frame(&mut buf, 1024).unwrap().unwrap();
+        assert_eq!(decoded, frame, "{}", vector.name);
+        assert!(buf.is_empty());
+    }
+}
+
+#[test]
+fn test_v2_header_section_beyond_64k() {
+    let value = "m".repeat(255);
+    let mut frame = (0..300).fold(Frame::new(FrameType::Data), |frame, i| {
+        frame.with_header(&format!("sig-{}", i), &value)
+    });
+    assert!(encode_frame(&frame).is_err());
+
+    frame.version = vstp::VSTP_VERSION_2;
+    let encoded = encode_frame(&frame).unwrap();
+    assert!(encoded.len() > 64 * 1024);
+    let mut buf = BytesMut::from(&encoded[..]);
+    assert_eq!(try_decode_frame(&mut buf, 1024 * 1024).unwrap().unwrap(), frame);
+}
+
+#[test]
+fn test_v2_reserved_extension_byte() {
+    let mut frame = Frame::new(FrameType::Ping);
+    frame.version = vstp::VSTP_VERSION_2;
+    let mut encoded = encode_frame(&frame).unwrap().to_vec();
+    encoded[5] = 0x01;
+
+    let mut buf = BytesMut::from(&encoded[..]);
+    assert!(try_decode_frame(&mut buf, 1024).is_err());
+}
+
+#[test]
+fn test_codec_encodes_in_its_version() {
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let mut codec = vstp::VstpFrameCodec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(Frame::new(FrameType::Ping), &mut buf).unwrap();
+    codec.set_version(vstp::VSTP_VERSION_2);
+    codec.encode(Frame::new(FrameType::Ping), &mut buf).unwrap();
+
+    // One decoder reads both
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().version, vstp::VSTP_VERSION);
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap().version, vstp::VSTP_VERSION_2);
+}
+
+#[test]
+fn test_standard_error_codes_are_stable_and_distinct() {
+    let mut numbers = HashSet::new();
+    let mut names = HashSet::new();
+    for code in ErrorCode::STANDARD {
+        assert!(code.number() > 0 && code.number() < ErrorCode::USER_MIN);
+        assert!(numbers.insert(code.number()));
+        let name = code.name().unwrap();
+        assert!(names.insert(name));
+        assert_eq!(ErrorCode::from_number(code.number()), Some(code));
+        assert_eq!(ErrorCode::from_name(name), Some(code));
+    }
+    // Numbers are part of the protocol and must never change
+    assert_eq!(ErrorCode::Unauthorized.number(), 1);
+    assert_eq!(ErrorCode::Internal.number(), 12);
+
+    assert_eq!(ErrorCode::user(999), None);
+    assert_eq!(ErrorCode::user(1000), Some(ErrorCode::User(1000)));
+    assert_eq!(ErrorCode::from_number(500), None);
+    assert_eq!(ErrorCode::User(4242).name(), None);
+}
+
+#[test]
+fn test_coded_error_roundtrip() {
+    let frame = Frame::coded_error(ErrorCode::RateLimited, "slow down");
+    let mut buf = BytesMut::from(&encode_frame(&frame).unwrap()[..]);
+    let decoded = try_decode_frame(&mut buf, 1024).unwrap().unwrap();
+    assert_eq!(decoded.error_code(), Some(ErrorCode::RateLimited));
+    assert_eq!(decoded.get_header(ERROR_CODE_HEADER), Some("RateLimited"));
+    assert_eq!(decoded.get_header(ERROR_NUMBER_HEADER), Some("11"));
+
+    let user = Frame::coded_error(ErrorCode::User(4242), "out of stock");
+    assert_eq!(user.get_header(ERROR_CODE_HEADER), Some("4242"));
+    assert_eq!(user.error_code(), Some(ErrorCode::User(4242)));
+
+    // ERRs from peers that only send the name are still recognized
+    let named = Frame::error(error_codes::BAD_CRC, "checksum");
+    assert_eq!(named.error_code(), Some(ErrorCode::BadCrc));
+    assert_eq!(Frame::error("Custom", "?").error_code(), None);
+    assert_eq!(Frame::new(FrameType::Data).error_code(), None);
+}
+
+#[test]
+fn test_flags_helpers_and_names() {
+    let flags = Flags::from_names(&["REQ_ACK", "CRC"]).unwrap();
+    assert_eq!(flags, Flags::REQ_ACK | Flags::CRC);
+    assert!(flags.is_reliable() && flags.is_checked() && !flags.is_compressed());
+    assert!(Flags::all_known().contains(Flags::COMP | Flags::FRAG | Flags::SHA256));
+    assert_eq!(Flags::from_names(&[]).unwrap(), Flags::empty());
+
+    assert_eq!(flags.to_string(), "REQ_ACK | CRC");
+    assert_eq!("REQ_ACK | CRC".parse::<Flags>().unwrap(), flags);
+    let unknown = Flags::from_bits_retain(0x80) | Flags::COMP;
+    assert_eq!(unknown.to_string().parse::<Flags>().unwrap(), unknown);
+
+    assert!(matches!(
+        Flags::from_names(&["REQ_ACK", "FAST"]),
+        Err(VstpError::Protocol(message)) if message.contains("FAST")
+    ));
+    assert!("REQ_ACK | FAST".parse::<Flags>().is_err());
+}
+
+#[test]
+fn test_header_running_past_header_section_is_rejected() {
+    // The header claims 4 bytes of value, but the header section ends after 1
+    let mut body = BytesMut::new();
+    body.put_slice(&VSTP_MAGIC);
+    body.put_u8(1);
+    body.put_u8(FrameType::Data.to_u8());
+    body.put_u8(Flags::SHA256.bits());
+    body.put_u16_le(3);
+    body.put_u32(3);
+    body.put_slice(&[1, 4, b'k', b'a', b'b', b'c']);
+    let digest = Sha256::digest(&body);
+    body.put_slice(&digest[..Integrity::Sha256.trailer_len()]);
+
+    assert!(matches!(
+        try_decode_frame(&mut body, 1024),
+        Err(VstpError::Protocol(message)) if message.contains("header")
+    ));
+}
+
+fn arbitrary_frame() -> impl Strategy<Value = Frame> {
+    let header = (vec(any::<u8>(), 0..32), vec(any::<u8>(), 0..64))
+        .prop_map(|(key, value)| Header { key, value });
+    (
+        prop_oneof![Just(1u8), Just(VSTP_VERSION_2)],
+        1u8..=8,
+        any::<u8>(),
+        vec(header, 0..8),
+        vec(any::<u8>(), 0..512),
+    )
+        .prop_map(|(version, typ, flags, headers, payload)| Frame {
+            version,
+            typ: FrameType::from_u8(typ).unwrap(),
+            flags: Flags::from_bits_retain(flags),
+            headers,
+            payload,
+        })
+}
+
+/// A frame, and its headers in another order
+fn shuffled_frame() -> impl Strategy<Value = (Frame, Vec<Header>)> {
+    arbitrary_frame().prop_flat_map(|frame| {
+        let headers = Just(frame.headers.clone()).prop_shuffle();
+        (Just(frame), headers)
+    })
+}
+
+/// A version 1 frame with any bytes in its header section and a valid trailer
+fn arbitrary_wire_frame() -> impl Strategy<Value = Vec<u8>> {
+    (
+        1u8..=8,
+        any::<u8>(),
+        vec(any::<u8>(), 0..64),
+        vec(any::<u8>(), 0..64),
+    )
+        .prop_map(|(typ, flags, header_section, payload)| {
+            let flags = Flags::from_bits_retain(flags);
+            let mut body = BytesMut::new();
+            body.put_slice(&VSTP_MAGIC);
+            body.put_u8(1);
+            body.put_u8(typ);
+            body.put_u8(flags.bits());
+            body.put_u16_le(header_section.len() as u16);
+            body.put_u32(payload.len() as u32);
+            body.put_slice(&header_section);
+            body.put_slice(&payload);
+            let trailer = match Integrity::of(flags) {
+                Integrity::Crc32 => crc32(&body).to_be_bytes().to_vec(),
+                Integrity::Sha256 => {
+                    Sha256::digest(&body)[..Integrity::Sha256.trailer_len()].to_vec()
+                }
+            };
+            body.put_slice(&trailer);
+            body.to_vec()
+        })
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn test_canonicalize_sorts_by_key_then_value() {
+    let mut frame = Frame::new(FrameType::Data)
+        .with_header("tag", "b")
+        .with_header("method", "put")
+        .with_header("tag", "a");
+    frame.canonicalize();
+    let order: Vec<_> = frame
+        .headers
+        .iter()
+        .map(|h| (h.key.as_slice(), h.value.as_slice()))
+        .collect();
+    assert_eq!(
+        order,
+        [
+            (&b"method"[..], &b"put"[..]),
+            (b"tag", b"a"),
+            (b"tag", b"b"),
+        ]
+    );
+}
+
+#[test]
+fn test_content_hash_excludes_only_listed_headers() {
+    let frame = Frame::new(FrameType::Data).with_payload(b"order 42".to_vec());
+    let hash = frame.content_hash().unwrap();
+    let traced = frame.clone().with_header("traceparent", "00-abc-def-01");
+    assert_eq!(traced.content_hash().unwrap(), hash);
+
+    let tenant = frame.clone().with_header("tenant", "acme");
+    assert_ne!(tenant.content_hash().unwrap(), hash);
+    assert_eq!(
+        tenant.content_hash_excluding(&["tenant"]).unwrap(),
+        frame.content_hash_excluding(&["tenant"]).unwrap()
+    );
+    assert_ne!(
+        traced.content_hash_excluding(&[]).unwrap(),
+        frame.content_hash_excluding(&[]).unwrap()
+    );
+}
+
+proptest! {
+    #[test]
+    fn prop_decode_then_encode_gives_back_the_bytes(frame in arbitrary_frame()) {
+        let bytes = encode_frame(&frame).unwrap();
+        let mut buf = BytesMut::from(&bytes[..]);
+        let decoded = try_decode_frame(&mut buf, usize::MAX).unwrap().unwrap();
+        prop_assert!(buf.is_empty());
+        prop_assert_eq!(&decoded, &frame);
+        prop_assert_eq!(encode_frame(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn prop_every_accepted_frame_is_canonical(bytes in arbitrary_wire_frame()) {
+        let mut buf = BytesMut::from(&bytes[..]);
+        if let Ok(Some(frame)) = try_decode_frame(&mut buf, usize::MAX) {
+            prop_assert_eq!(&encode_frame(&frame).unwrap()[..], &bytes[..]);
+        }
+    }
+
+    #[test]
+    fn prop_header_order_doesnt_change_canonical_bytes((frame, headers) in shuffled_frame()) {
+        let shuffled = Frame { headers, ..frame.clone() };
+        prop_assert_eq!(
+            encode_frame_canonical(&shuffled).unwrap(),
+            encode_frame_canonical(&frame).unwrap()
+        );
+        prop_assert_eq!(shuffled.content_hash().unwrap(), frame.content_hash().unwrap());
+    }
+
+    #[test]
+    fn prop_volatile_headers_dont_change_content_hash(
+        mut frame in arbitrary_frame(),
+        volatile in 0..VOLATILE_HEADERS.len(),
+        value in vec(any::<u8>(), 0..64),
+        at in any::<prop::sample::Index>(),
+    ) {
+        let hash = frame.content_hash().unwrap();
+        let at = at.index(frame.headers.len() + 1);
+        let key = VOLATILE_HEADERS[volatile].as_bytes().to_vec();
+        frame.headers.insert(at, Header { key, value });
+        prop_assert_eq!(frame.content_hash().unwrap(), hash);
+    }
+}
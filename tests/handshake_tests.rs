@@ -0,0 +1,1054 @@
+//! Tests for the HELLO/WELCOME handshake performed by the easy client
+
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vstp::{
+    chunk::{FIN_HEADER, STREAM_ID_HEADER},
+    clock::SERVER_TIME_MS_HEADER,
+    easy::{
+        current_negotiated_params, Accepted, ClientEvent, ConnectOptions, PeerLimits,
+        ServerOptions, VstpClient, VstpServer, WelcomeProvider, CAPABILITIES_HEADER,
+        DEFAULT_CONTENT_TYPE, ERROR_CODE_HEADER, FRAGMENTATION_CAPABILITY,
+        MAX_MESSAGE_BYTES_HEADER, PROTOCOL_VERSION_HEADER, SUPPORTED_VERSIONS_HEADER,
+    },
+    flow::WindowCredit,
+    tcp::{VstpTcpClient, VstpTcpServer},
+    testing::{FrameTap, TapDirection},
+    types::error_codes,
+    ErrorCode, Flags, Frame, FrameType, VstpError, VSTP_VERSION, VSTP_VERSION_2,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Note {
+    text: String,
+}
+
+async fn spawn_echo_server(addr: &str, auth_token: Option<&str>, udp: bool) {
+    let mut server = if udp {
+        VstpServer::bind_udp(addr).await.unwrap()
+    } else {
+        VstpServer::bind_tcp(addr).await.unwrap()
+    };
+    server.set_options(ServerOptions {
+        auth_token: auth_token.map(str::to_string),
+        ..ServerOptions::default()
+    });
+    tokio::spawn(async move { server.serve(|note: Note| async move { Ok(note) }).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+fn with_token(token: &str) -> ConnectOptions {
+    ConnectOptions {
+        auth_token: Some(token.to_string()),
+        ..ConnectOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn test_refused_socket_is_io_error() {
+    // Bind and drop a listener to find a port nobody is listening on
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    match VstpClient::connect_tcp(addr.to_string()).await {
+        Err(VstpError::Io(e)) => assert_eq!(e.kind(), ErrorKind::ConnectionRefused),
+        Err(other) => panic!("Expected Io(ConnectionRefused), got {:?}", other),
+        Ok(_) => panic!("Expected Io(ConnectionRefused), got a connection"),
+    }
+}
+
+#[tokio::test]
+async fn test_bad_token_is_rejected() {
+    spawn_echo_server("127.0.0.1:8093", Some("secret"), false).await;
+
+    match VstpClient::connect_tcp_with_options("127.0.0.1:8093", with_token("wrong")).await {
+        Err(err @ VstpError::HandshakeRejected { .. }) => {
+            assert!(!err.is_retryable());
+            if let VstpError::HandshakeRejected { code, .. } = err {
+                assert_eq!(code, error_codes::UNAUTHORIZED);
+            }
+        }
+        Err(other) => panic!("Expected HandshakeRejected, got {:?}", other),
+        Ok(_) => panic!("Expected HandshakeRejected, got a connection"),
+    }
+
+    // Leaving the token out is rejected the same way
+    match VstpClient::connect_tcp("127.0.0.1:8093").await {
+        Err(VstpError::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, error_codes::UNAUTHORIZED)
+        }
+        Err(other) => panic!("Expected HandshakeRejected, got {:?}", other),
+        Ok(_) => panic!("Expected HandshakeRejected, got a connection"),
+    }
+}
+
+#[tokio::test]
+async fn test_bad_token_is_rejected_over_udp() {
+    spawn_echo_server("127.0.0.1:8094", Some("secret"), true).await;
+
+    match VstpClient::connect_udp_with_options("127.0.0.1:8094", with_token("wrong")).await {
+        Err(VstpError::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, error_codes::UNAUTHORIZED)
+        }
+        Err(other) => panic!("Expected HandshakeRejected, got {:?}", other),
+        Ok(_) => panic!("Expected HandshakeRejected, got a connection"),
+    }
+}
+
+#[tokio::test]
+async fn test_unsupported_version_is_rejected() {
+    // A server that only speaks some other protocol version
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        while let Ok(Some(frame)) = conn.recv().await {
+            if frame.typ == FrameType::Hello {
+                let reply = Frame::new(FrameType::Err)
+                    .with_header(ERROR_CODE_HEADER, error_codes::UNSUPPORTED_VERSION)
+                    .with_payload(b"server speaks version 2".to_vec());
+                conn.send(reply).await.unwrap();
+            }
+        }
+    });
+
+    match VstpClient::connect_tcp(addr.to_string()).await {
+        Err(err @ VstpError::HandshakeRejected { .. }) => {
+            assert!(!err.is_retryable());
+            if let VstpError::HandshakeRejected { code, message } = err {
+                assert_eq!(code, error_codes::UNSUPPORTED_VERSION);
+                assert_eq!(message, "server speaks version 2");
+            }
+        }
+        Err(other) => panic!("Expected HandshakeRejected, got {:?}", other),
+        Ok(_) => panic!("Expected HandshakeRejected, got a connection"),
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_success() -> Result<(), VstpError> {
+    spawn_echo_server("127.0.0.1:8095", Some("secret"), false).await;
+    spawn_echo_server("127.0.0.1:8096", Some("secret"), true).await;
+    spawn_echo_server("127.0.0.1:8097", None, false).await;
+
+    let note = Note {
+        text: "hello".to_string(),
+    };
+    for client in [
+        VstpClient::connect_tcp_with_options("127.0.0.1:8095", with_token("secret")).await?,
+        VstpClient::connect_udp_with_options("127.0.0.1:8096", with_token("secret")).await?,
+        VstpClient::connect_tcp("127.0.0.1:8097").await?,
+    ] {
+        client.send(note.clone()).await?;
+        let echoed: Note = client.receive().await?;
+        assert_eq!(echoed, note);
+    }
+    Ok(())
+}
+
+/// A server whose clock runs `skew` ahead of ours, answering PINGs with a
+/// further hour of skew and forwarding DATA frames with its own receive time
+fn spawn_skewed_server(
+    server: VstpTcpServer,
+    skew: Duration,
+) -> tokio::sync::mpsc::UnboundedReceiver<(Frame, SystemTime)> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        while let Ok(Some(frame)) = conn.recv().await {
+            let server_now = |skew: Duration| SystemTime::now() + skew;
+            let stamp = |skew| {
+                server_now(skew)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+                    .to_string()
+            };
+            match frame.typ {
+                FrameType::Hello => {
+                    let welcome = Frame::new(FrameType::Welcome)
+                        .with_header(SERVER_TIME_MS_HEADER, &stamp(skew));
+                    conn.send(welcome).await.unwrap();
+                }
+                FrameType::Ping => {
+                    let skew = skew + Duration::from_secs(3600);
+                    let mut pong = Frame::new(FrameType::Pong);
+                    pong.headers = frame.headers.clone();
+                    conn.send(pong.with_header(SERVER_TIME_MS_HEADER, &stamp(skew)))
+                        .await
+                        .unwrap();
+                    let note = serde_json::to_vec(&Note {
+                        text: "after".to_string(),
+                    })
+                    .unwrap();
+                    conn.send(Frame::new(FrameType::Data).with_payload(note))
+                        .await
+                        .unwrap();
+                }
+                _ => tx.send((frame, server_now(skew))).unwrap(),
+            }
+        }
+    });
+    rx
+}
+
+fn seconds_apart(a: SystemTime, b: SystemTime) -> f64 {
+    match a.duration_since(b) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_estimates_server_clock() {
+    spawn_echo_server("127.0.0.1:8098", None, false).await;
+    let client = VstpClient::connect_tcp("127.0.0.1:8098").await.unwrap();
+    assert!(client.clock().sample().is_some());
+    assert!(seconds_apart(client.estimated_server_time(), SystemTime::now()).abs() < 1.0);
+}
+
+#[tokio::test]
+async fn test_ttl_uses_corrected_clock() {
+    // From the server's point of view, our clock is two hours behind
+    let skew = Duration::from_secs(2 * 3600);
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut received = spawn_skewed_server(server, skew);
+
+    let client = VstpClient::connect_tcp(addr.to_string()).await.unwrap();
+    let ahead = seconds_apart(client.estimated_server_time(), SystemTime::now());
+    assert!((ahead - skew.as_secs_f64()).abs() < 1.0);
+
+    let frame = Frame::new(FrameType::Data).with_ttl(Duration::from_secs(30));
+    client.send_raw(frame).await.unwrap();
+    let (mut frame, server_now) = received.recv().await.unwrap();
+
+    // Judged by expires-at-ms alone on the server's clock, the frame is still fresh
+    frame.headers.retain(|h| h.key != b"ttl-ms");
+    let received_at = tokio::time::Instant::now();
+    assert!(frame.deadline_at(received_at, server_now).unwrap() > received_at + Duration::from_secs(25));
+
+    // Stamped with the uncorrected local clock it would already be expired
+    let mut naive = Frame::new(FrameType::Data).with_ttl(Duration::from_secs(30));
+    naive.headers.retain(|h| h.key != b"ttl-ms");
+    assert!(naive.deadline_at(received_at, server_now).unwrap() <= tokio::time::Instant::now());
+}
+
+#[tokio::test]
+async fn test_pong_resyncs_clock() {
+    let skew = Duration::from_secs(3600);
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let _received = spawn_skewed_server(server, skew);
+
+    let client = VstpClient::connect_tcp(addr.to_string()).await.unwrap();
+    client.sync_clock().await.unwrap();
+
+    // The PONG is consumed on the way to the next application frame
+    let note: Note = client.receive().await.unwrap();
+    assert_eq!(note.text, "after");
+    let ahead = seconds_apart(client.estimated_server_time(), SystemTime::now());
+    assert!((ahead - 2.0 * 3600.0).abs() < 1.0);
+}
+
+#[tokio::test]
+async fn test_udp_server_pongs_clock_pings_like_the_general_encoder() {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    drop(socket);
+    spawn_echo_server(&addr.to_string(), None, true).await;
+
+    let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    // A plain clock PING takes the fast path; one with a padded value doesn't
+    for sent_ms in ["1700000000000", "01700000000000"] {
+        let ping = Frame::new(FrameType::Ping).with_header("ping-sent-ms", sent_ms);
+        let encoded = vstp::encode_frame(&ping).unwrap();
+        peer.send_to(&encoded, addr).await.unwrap();
+        let mut buf = [0; 256];
+        let len = tokio::time::timeout(Duration::from_secs(2), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut datagram = bytes::BytesMut::from(&buf[..len]);
+        let pong = vstp::try_decode_frame(&mut datagram, len).unwrap().unwrap();
+        assert_eq!(pong.typ, FrameType::Pong);
+        assert_eq!(pong.get_header("ping-sent-ms"), Some(sent_ms));
+        assert!(pong.get_header(SERVER_TIME_MS_HEADER).is_some());
+        assert_eq!(pong.headers.len(), 2);
+        assert_eq!(&buf[..len], &vstp::encode_frame(&pong).unwrap()[..]);
+    }
+}
+
+/// Send a note through `tap` and return the frame versions seen after the WELCOME
+async fn versions_after_welcome(client: &VstpClient, tap: &FrameTap) -> Vec<(TapDirection, u8)> {
+    let note = Note {
+        text: "versioned".to_string(),
+    };
+    client.send(note.clone()).await.unwrap();
+    let echoed: Note = client.receive().await.unwrap();
+    assert_eq!(echoed, note);
+
+    let frames = tap.frames();
+    let hello = &frames[0].1;
+    assert_eq!(hello.typ, FrameType::Hello);
+    assert_eq!(hello.version, VSTP_VERSION);
+    let welcome = frames
+        .iter()
+        .position(|(_, frame)| frame.typ == FrameType::Welcome)
+        .unwrap();
+    assert_eq!(frames[welcome].1.version, VSTP_VERSION);
+    frames[welcome + 1..]
+        .iter()
+        .map(|(direction, frame)| (*direction, frame.version))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_v2_negotiated_with_v2_server() {
+    spawn_echo_server("127.0.0.1:8100", None, false).await;
+    let tap = FrameTap::start("127.0.0.1:8100".parse().unwrap()).await.unwrap();
+    let client = VstpClient::connect_tcp(tap.addr().to_string()).await.unwrap();
+
+    let hello = &tap.frames()[0].1;
+    assert_eq!(hello.get_header(SUPPORTED_VERSIONS_HEADER), Some("1,2"));
+    assert_eq!(
+        versions_after_welcome(&client, &tap).await,
+        [
+            (TapDirection::ToServer, VSTP_VERSION_2),
+            (TapDirection::ToClient, VSTP_VERSION_2)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_v1_only_server_keeps_session_on_v1() {
+    // A server from before version negotiation: it ignores supported-versions
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        while let Ok(Some(frame)) = conn.recv().await {
+            assert_eq!(frame.version, VSTP_VERSION);
+            let reply = match frame.typ {
+                FrameType::Hello => {
+                    Frame::new(FrameType::Welcome).with_header(PROTOCOL_VERSION_HEADER, "1")
+                }
+                _ => frame,
+            };
+            conn.send(reply).await.unwrap();
+        }
+    });
+    let tap = FrameTap::start(addr).await.unwrap();
+    let client = VstpClient::connect_tcp(tap.addr().to_string()).await.unwrap();
+
+    assert_eq!(
+        versions_after_welcome(&client, &tap).await,
+        [
+            (TapDirection::ToServer, VSTP_VERSION),
+            (TapDirection::ToClient, VSTP_VERSION)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_v1_only_client_keeps_session_on_v1() {
+    spawn_echo_server("127.0.0.1:8101", None, false).await;
+    let tap = FrameTap::start("127.0.0.1:8101".parse().unwrap()).await.unwrap();
+    let options = ConnectOptions {
+        max_frame_version: VSTP_VERSION,
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(tap.addr().to_string(), options)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        versions_after_welcome(&client, &tap).await,
+        [
+            (TapDirection::ToServer, VSTP_VERSION),
+            (TapDirection::ToClient, VSTP_VERSION)
+        ]
+    );
+}
+
+async fn spawn_limited_server(addr: &str, accept_fragments: bool, udp: bool) {
+    let mut server = if udp {
+        VstpServer::bind_udp(addr).await.unwrap()
+    } else {
+        VstpServer::bind_tcp(addr).await.unwrap()
+    };
+    server.set_options(ServerOptions {
+        max_message_bytes: Some(64),
+        accept_fragments,
+        ..ServerOptions::default()
+    });
+    tokio::spawn(async move { server.serve(|note: Note| async move { Ok(note) }).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+/// A raw client of a TCP echo server reassembling chunked messages under `options`
+async fn chunking_session(options: ServerOptions) -> Result<VstpTcpClient, VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    server.set_options(ServerOptions {
+        accept_fragments: true,
+        ..options
+    });
+    tokio::spawn(server.serve(|note: Note| async move { Ok(note) }));
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await?;
+    client.send(Frame::new(FrameType::Hello)).await?;
+    client.recv().await?.expect("WELCOME");
+    Ok(client)
+}
+
+/// A note split into two chunks
+fn note_chunks(text: &str) -> Vec<Frame> {
+    let note = Note {
+        text: text.to_string(),
+    };
+    let payload = serde_json::to_vec(&note).unwrap();
+    Frame::chunk_payload(&payload, payload.len().div_ceil(2))
+}
+
+async fn recv_note(client: &mut VstpTcpClient) -> Result<String, VstpError> {
+    let reply = client.recv().await?.expect("reply");
+    Ok(serde_json::from_slice::<Note>(&reply.payload).unwrap().text)
+}
+
+#[tokio::test]
+async fn test_chunked_messages_open_at_once_are_limited() -> Result<(), VstpError> {
+    let mut client = chunking_session(ServerOptions {
+        max_open_chunked: 2,
+        ..ServerOptions::default()
+    })
+    .await?;
+    let [one, two, three] = ["one", "two", "three"].map(note_chunks);
+    client.send(one[0].clone()).await?;
+    client.send(two[0].clone()).await?;
+    client.send(three[0].clone()).await?;
+    let refusal = client.recv().await?.expect("ERR");
+    assert_eq!(refusal.error_code(), Some(ErrorCode::RateLimited));
+
+    // Finishing an open message frees its place
+    client.send(one[1].clone()).await?;
+    assert_eq!(recv_note(&mut client).await?, "one");
+    for chunk in three {
+        client.send(chunk).await?;
+    }
+    assert_eq!(recv_note(&mut client).await?, "three");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stalled_chunked_message_is_dropped() -> Result<(), VstpError> {
+    let mut client = chunking_session(ServerOptions {
+        max_open_chunked: 1,
+        chunk_idle_timeout: Duration::from_millis(200),
+        ..ServerOptions::default()
+    })
+    .await?;
+    let stalled = note_chunks("stalled");
+    client.send(stalled[0].clone()).await?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // The stalled message no longer holds the session's only place
+    for chunk in note_chunks("fresh") {
+        client.send(chunk).await?;
+    }
+    assert_eq!(recv_note(&mut client).await?, "fresh");
+
+    // Its first chunk is gone, so the last one alone completes nothing
+    client.send(stalled[1].clone()).await?;
+    let plain = Note {
+        text: "plain".to_string(),
+    };
+    client
+        .send(Frame::new(FrameType::Data).with_payload(serde_json::to_vec(&plain).unwrap()))
+        .await?;
+    assert_eq!(recv_note(&mut client).await?, "plain");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_over_peer_limit_fails_locally() {
+    spawn_limited_server("127.0.0.1:8102", false, false).await;
+    let client = VstpClient::connect_tcp("127.0.0.1:8102").await.unwrap();
+    assert_eq!(
+        client.peer_limits(),
+        PeerLimits {
+            max_message_bytes: Some(64),
+            fragmentation: false,
+        }
+    );
+
+    let big = Note {
+        text: "x".repeat(200),
+    };
+    match client.send(big.clone()).await {
+        Err(err @ VstpError::TooLargeForPeer { .. }) => {
+            assert!(!err.is_retryable());
+            if let VstpError::TooLargeForPeer { size, limit } = err {
+                assert_eq!(size, serde_json::to_vec(&big).unwrap().len());
+                assert_eq!(limit, 64);
+            }
+        }
+        other => panic!("Expected TooLargeForPeer, got {:?}", other),
+    }
+
+    // Nothing was sent, so the session carries on with small messages
+    let small = Note {
+        text: "hi".to_string(),
+    };
+    client.send(small.clone()).await.unwrap();
+    assert_eq!(client.receive::<Note>().await.unwrap(), small);
+}
+
+#[tokio::test]
+async fn test_frame_over_header_limit_fails_locally() -> Result<(), VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    tokio::spawn(server.serve(|note: Note| async move { Ok(note) }));
+    let tap = FrameTap::start(addr).await?;
+    let options = ConnectOptions {
+        max_headers: 3,
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(tap.addr().to_string(), options).await?;
+
+    let note = Note {
+        text: "hi".to_string(),
+    };
+    let frame = |headers: usize| {
+        (0..headers).fold(
+            Frame::new(FrameType::Data).with_payload(serde_json::to_vec(&note).unwrap()),
+            |frame, i| frame.with_header(&format!("tag-{}", i), "x"),
+        )
+    };
+    match client.send_raw(frame(4)).await {
+        Err(VstpError::TooManyHeaders { count: 4, limit: 3 }) => {}
+        other => panic!("Expected TooManyHeaders, got {:?}", other),
+    }
+    // At the limit the frame goes out and the session carries on
+    client.send_raw(frame(3)).await?;
+    assert_eq!(client.receive::<Note>().await?, note);
+    let sent = tap
+        .frames()
+        .into_iter()
+        .filter(|(dir, f)| *dir == TapDirection::ToServer && f.typ == FrameType::Data)
+        .count();
+    assert_eq!(sent, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_over_peer_limit_is_fragmented() {
+    spawn_limited_server("127.0.0.1:8103", true, false).await;
+    spawn_limited_server("127.0.0.1:8104", true, true).await;
+    let clients = [
+        VstpClient::connect_tcp("127.0.0.1:8103").await.unwrap(),
+        VstpClient::connect_udp("127.0.0.1:8104").await.unwrap(),
+    ];
+
+    let big = Note {
+        text: "x".repeat(500),
+    };
+    for client in clients {
+        assert!(client.peer_limits().fragmentation);
+        client.send(big.clone()).await.unwrap();
+        assert_eq!(client.receive::<Note>().await.unwrap(), big);
+    }
+}
+
+/// A server that turns the first request away with ERR `ShuttingDown` and
+/// `retry_after`, then echoes requests, reporting when each arrived
+async fn spawn_draining_server(
+    retry_after: Duration,
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<Instant>) {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        let mut draining = true;
+        while let Ok(Some(frame)) = conn.recv().await {
+            let reply = match frame.typ {
+                FrameType::Hello => Frame::new(FrameType::Welcome),
+                FrameType::Data if draining => {
+                    draining = false;
+                    Frame::coded_error(ErrorCode::ShuttingDown, "draining")
+                        .with_retry_after(retry_after)
+                }
+                FrameType::Data => {
+                    tx.send(Instant::now()).unwrap();
+                    Frame::new(FrameType::Data).with_payload(frame.payload)
+                }
+                _ => continue,
+            };
+            conn.send(reply).await.unwrap();
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_client_waits_out_draining_server() {
+    let (addr, mut arrivals) = spawn_draining_server(Duration::from_millis(500)).await;
+    let client = VstpClient::connect_tcp(addr).await.unwrap();
+    let mut events = client.events();
+
+    let note = Note {
+        text: "hello".to_string(),
+    };
+    let start = Instant::now();
+    let echoed: Note = client.call("notes.echo", note.clone()).await.unwrap();
+    assert_eq!(echoed, note);
+
+    // The retry landed once the advertised pause was over
+    let waited = arrivals.recv().await.unwrap() - start;
+    assert!(waited >= Duration::from_millis(480), "{:?}", waited);
+    assert!(waited < Duration::from_millis(1500), "{:?}", waited);
+    assert_eq!(
+        events.recv().await.unwrap(),
+        ClientEvent::BackingOff {
+            code: ErrorCode::ShuttingDown,
+            retry_after: Duration::from_millis(500),
+        }
+    );
+    assert_eq!(events.recv().await.unwrap(), ClientEvent::Resumed);
+}
+
+/// A server whose every reply to a request is a note saying `name`
+async fn spawn_named_server(name: &'static str) -> String {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok(mut conn) = server.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    let reply = match frame.typ {
+                        FrameType::Hello => Frame::new(FrameType::Welcome),
+                        FrameType::Data => {
+                            let note = Note {
+                                text: name.to_string(),
+                            };
+                            Frame::new(FrameType::Data)
+                                .with_payload(serde_json::to_vec(&note).unwrap())
+                        }
+                        _ => continue,
+                    };
+                    conn.send(reply).await.unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_client_falls_back_when_preferred_server_is_down() {
+    let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let preferred = down.local_addr().unwrap().to_string();
+    drop(down);
+    let fallback = spawn_named_server("fallback").await;
+    let spare = spawn_named_server("spare").await;
+
+    let options = ConnectOptions {
+        fallback_addrs: vec![fallback, spare],
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(preferred, options)
+        .await
+        .unwrap();
+    let note = Note {
+        text: "hello".to_string(),
+    };
+    let reply: Note = client.call("notes.echo", note).await.unwrap();
+    assert_eq!(reply.text, "fallback");
+}
+
+#[tokio::test]
+async fn test_client_leaves_draining_server_for_fallback() {
+    let (preferred, mut arrivals) = spawn_draining_server(Duration::from_secs(5)).await;
+    let fallback = spawn_named_server("fallback").await;
+    let options = ConnectOptions {
+        fallback_addrs: vec![fallback],
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(preferred, options)
+        .await
+        .unwrap();
+    let mut events = client.events();
+
+    // The request goes to the fallback at once instead of waiting 5 s
+    let note = Note {
+        text: "hello".to_string(),
+    };
+    let start = Instant::now();
+    let reply: Note = client.call("notes.echo", note).await.unwrap();
+    assert_eq!(reply.text, "fallback");
+    assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+    assert!(arrivals.try_recv().is_err());
+    assert_eq!(
+        events.recv().await.unwrap(),
+        ClientEvent::BackingOff {
+            code: ErrorCode::ShuttingDown,
+            retry_after: Duration::from_secs(5),
+        }
+    );
+    assert_eq!(events.recv().await.unwrap(), ClientEvent::Resumed);
+}
+
+#[tokio::test]
+async fn test_client_opting_out_of_backoff_gets_error() {
+    let (addr, mut arrivals) = spawn_draining_server(Duration::from_millis(500)).await;
+    let options = ConnectOptions {
+        wait_out_backoff: false,
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(addr, options)
+        .await
+        .unwrap();
+    let note = Note {
+        text: "hello".to_string(),
+    };
+
+    let until = match client.call::<_, Note>("notes.echo", note.clone()).await {
+        Err(VstpError::Backoff { until }) => until,
+        other => panic!("Expected Backoff, got {:?}", other),
+    };
+    let remaining = until - tokio::time::Instant::now();
+    assert!(remaining > Duration::from_millis(300), "{:?}", remaining);
+
+    // New requests fail locally while the pause lasts
+    assert!(matches!(
+        client.send(note.clone()).await,
+        Err(VstpError::Backoff { .. })
+    ));
+    assert!(arrivals.try_recv().is_err());
+
+    // Ending the pause early lets requests through at once
+    client.resume();
+    let start = Instant::now();
+    let echoed: Note = client.call("notes.echo", note.clone()).await.unwrap();
+    assert_eq!(echoed, note);
+    assert!(start.elapsed() < Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn test_handshake_timeout_is_reported_per_phase() {
+    // Accepts connections but never answers the HELLO
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = silent.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = silent.accept().await {
+            held.push(socket);
+        }
+    });
+    let options = ConnectOptions {
+        handshake_timeout: Duration::from_millis(200),
+        ..ConnectOptions::default()
+    };
+    match VstpClient::connect_tcp_with_options(addr.to_string(), options).await {
+        Err(VstpError::HandshakeTimeout { after }) => {
+            assert_eq!(after, Duration::from_millis(200))
+        }
+        other => panic!("Expected HandshakeTimeout, got {:?}", other.err()),
+    }
+
+    // A listener whose backlog is full leaves new connections unanswered
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    let any_port: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+    socket.bind(&any_port.into()).unwrap();
+    socket.listen(0).unwrap();
+    let addr = socket.local_addr().unwrap().as_socket().unwrap();
+    let mut queued = Vec::new();
+    for _ in 0..4 {
+        let connect = tokio::net::TcpStream::connect(addr);
+        if let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), connect).await {
+            queued.push(stream);
+        }
+    }
+    let options = ConnectOptions {
+        connect_timeout: Duration::from_millis(200),
+        ..ConnectOptions::default()
+    };
+    match VstpClient::connect_tcp_with_options(addr.to_string(), options).await {
+        Err(VstpError::ConnectTimeout { after }) => {
+            assert_eq!(after, Duration::from_millis(200))
+        }
+        other => panic!("Expected ConnectTimeout, got {:?}", other.err()),
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_renegotiates_with_rolled_back_server() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let (tx, mut received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        // A v2 server reassembling chunks of up to 16 bytes, which goes away
+        // after the first message
+        let mut conn = server.accept().await.unwrap();
+        while let Ok(Some(frame)) = conn.recv().await {
+            if frame.typ == FrameType::Hello {
+                let welcome = Frame::new(FrameType::Welcome)
+                    .with_header(PROTOCOL_VERSION_HEADER, "2")
+                    .with_header(MAX_MESSAGE_BYTES_HEADER, "16")
+                    .with_header(CAPABILITIES_HEADER, FRAGMENTATION_CAPABILITY);
+                conn.send(welcome).await.unwrap();
+                conn.set_frame_version(VSTP_VERSION_2);
+                continue;
+            }
+            let last = frame.get_header(FIN_HEADER).is_some();
+            tx.send((1, frame)).unwrap();
+            if last {
+                break;
+            }
+        }
+        drop(conn);
+
+        // Rolled back to a v1 server that announces nothing
+        let mut conn = server.accept().await.unwrap();
+        while let Ok(Some(frame)) = conn.recv().await {
+            if frame.typ != FrameType::Hello {
+                tx.send((2, frame)).unwrap();
+                continue;
+            }
+            conn.send(Frame::new(FrameType::Welcome)).await.unwrap();
+            let note = serde_json::to_vec(&Note {
+                text: "back".to_string(),
+            })
+            .unwrap();
+            conn.send(Frame::new(FrameType::Data).with_payload(note))
+                .await
+                .unwrap();
+        }
+    });
+
+    let options = ConnectOptions {
+        auto_reconnect: true,
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(addr.to_string(), options)
+        .await
+        .unwrap();
+    let mut events = client.events();
+    let note = Note {
+        text: "x".repeat(40),
+    };
+    client.send(note.clone()).await.unwrap();
+    let mut chunks = 0;
+    loop {
+        let (conn, frame) = received.recv().await.unwrap();
+        assert_eq!((conn, frame.version), (1, VSTP_VERSION_2));
+        chunks += 1;
+        if frame.get_header(FIN_HEADER).is_some() {
+            break;
+        }
+    }
+    assert!(chunks > 1);
+
+    // The client finds the connection closed, reconnects and starts over
+    let greeting: Note = client.receive().await.unwrap();
+    assert_eq!(greeting.text, "back");
+    assert_eq!(
+        events.recv().await.unwrap(),
+        ClientEvent::CapabilitiesChanged {
+            limits: PeerLimits::default(),
+            frame_version: VSTP_VERSION,
+        }
+    );
+    assert_eq!(client.peer_limits(), PeerLimits::default());
+
+    // The same message now goes out whole, as v1, uncompressed and without window headers
+    client.send(note.clone()).await.unwrap();
+    let (conn, frame) = received.recv().await.unwrap();
+    assert_eq!((conn, frame.version), (2, VSTP_VERSION));
+    assert_eq!(frame.get_header(STREAM_ID_HEADER), None);
+    assert!(!frame.flags.contains(Flags::COMP));
+    assert_eq!(WindowCredit::from_frame(&frame), None);
+    assert_eq!(serde_json::from_slice::<Note>(&frame.payload).unwrap(), note);
+}
+
+#[tokio::test]
+async fn test_udp_clients_refuse_auto_reconnect() {
+    spawn_echo_server("127.0.0.1:8112", None, true).await;
+
+    let options = ConnectOptions {
+        auto_reconnect: true,
+        ..ConnectOptions::default()
+    };
+    let error = VstpClient::connect_udp_with_options("127.0.0.1:8112", options)
+        .await
+        .err()
+        .expect("refused for UDP");
+    assert!(
+        matches!(&error, VstpError::Protocol(message) if message.contains("auto_reconnect")),
+        "{:?}",
+        error
+    );
+
+    // Nor can a UDP client be told to reconnect
+    let client = VstpClient::connect_udp("127.0.0.1:8112").await.unwrap();
+    assert!(matches!(client.reconnect().await, Err(VstpError::Protocol(_))));
+    let note = Note {
+        text: "still here".to_string(),
+    };
+    client.send(note).await.unwrap();
+    let echo: Note = client.receive().await.unwrap();
+    assert_eq!(echo.text, "still here");
+}
+
+#[tokio::test]
+async fn test_bare_hello_gets_v1_defaults() -> Result<(), VstpError> {
+    // A server that would negotiate version 2, fragments and a message limit
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    server.set_options(ServerOptions {
+        max_message_bytes: Some(4096),
+        accept_fragments: true,
+        ..ServerOptions::default()
+    });
+    tokio::spawn(server.serve(|_: Note| async move {
+        let params = current_negotiated_params().expect("set for handlers");
+        Ok(Note {
+            text: format!(
+                "v{} {} {} {:?}",
+                params.version,
+                params.compression,
+                params.content_type(),
+                params.peer_window
+            ),
+        })
+    }));
+
+    // An older peer: no versions, content type or window on its HELLO
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await?;
+    client.send(Frame::new(FrameType::Hello)).await?;
+    let welcome = client.recv().await?.expect("WELCOME");
+    assert_eq!(welcome.typ, FrameType::Welcome);
+    assert_eq!(welcome.get_header(PROTOCOL_VERSION_HEADER), Some("1"));
+
+    let note = Note {
+        text: "hi".to_string(),
+    };
+    client
+        .send(Frame::new(FrameType::Data).with_payload(serde_json::to_vec(&note).unwrap()))
+        .await?;
+    let reply = client.recv().await?.expect("reply");
+    assert_eq!(reply.version, VSTP_VERSION);
+    let note: Note = serde_json::from_slice(&reply.payload).unwrap();
+    assert_eq!(note.text, format!("v1 false {} None", DEFAULT_CONTENT_TYPE));
+    Ok(())
+}
+
+type AcceptedLog = Arc<Mutex<Vec<Accepted>>>;
+
+/// Serves an echo handler whose sessions are welcomed by `on_accept`, which
+/// also records every HELLO it is given
+fn spawn_welcoming_server(
+    udp: bool,
+    auth_token: Option<&str>,
+) -> Result<(String, AcceptedLog), VstpError> {
+    let accepted = Arc::new(Mutex::new(Vec::new()));
+    let seen = accepted.clone();
+    let on_accept = WelcomeProvider::new(move |hello: Accepted| {
+        seen.lock().unwrap().push(hello);
+        async {
+            Frame::new(FrameType::Welcome)
+                .with_header("x-motd", "be nice")
+                .with_header(PROTOCOL_VERSION_HEADER, "99")
+                .with_payload(b"server 1.2.3".to_vec())
+        }
+    });
+    let (mut server, addr) = if udp {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let addr = socket.local_addr()?;
+        (VstpServer::from_udp_socket(socket)?, addr)
+    } else {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        (VstpServer::from_tcp_listener(listener)?, addr)
+    };
+    server.set_options(ServerOptions {
+        auth_token: auth_token.map(str::to_string),
+        on_accept: Some(on_accept),
+        ..ServerOptions::default()
+    });
+    tokio::spawn(server.serve(|note: Note| async move { Ok(note) }));
+    Ok((addr.to_string(), accepted))
+}
+
+#[tokio::test]
+async fn test_on_accept_fills_in_the_welcome() -> Result<(), VstpError> {
+    for udp in [false, true] {
+        let (addr, accepted) = spawn_welcoming_server(udp, None)?;
+        let client = if udp {
+            VstpClient::connect_udp(&addr).await?
+        } else {
+            VstpClient::connect_tcp(&addr).await?
+        };
+
+        let welcome = client.welcome().expect("WELCOME");
+        assert_eq!(welcome.payload, b"server 1.2.3".to_vec());
+        assert_eq!(welcome.get_header("x-motd"), Some("be nice"));
+        // The server's own handshake headers win over the provider's
+        assert_ne!(welcome.get_header(PROTOCOL_VERSION_HEADER), Some("99"));
+
+        let accepted = accepted.lock().unwrap().clone();
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].hello.typ, FrameType::Hello);
+        assert!(accepted[0].peer.ip().is_loopback());
+
+        let note = Note {
+            text: "after welcome".to_string(),
+        };
+        client.send(note.clone()).await?;
+        assert_eq!(client.receive::<Note>().await?, note);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_accept_is_not_called_for_rejected_hellos() -> Result<(), VstpError> {
+    let (addr, accepted) = spawn_welcoming_server(false, Some("secret"))?;
+    match VstpClient::connect_tcp_with_options(&addr, with_token("wrong")).await {
+        Err(VstpError::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, error_codes::UNAUTHORIZED)
+        }
+        Err(other) => panic!("Expected HandshakeRejected, got {:?}", other),
+        Ok(_) => panic!("Expected HandshakeRejected, got a connection"),
+    }
+    assert!(accepted.lock().unwrap().is_empty());
+
+    let client = VstpClient::connect_tcp_with_options(&addr, with_token("secret")).await?;
+    assert_eq!(
+        client.welcome().expect("WELCOME").payload,
+        b"server 1.2.3".to_vec()
+    );
+    assert_eq!(accepted.lock().unwrap().len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_welcome_is_empty_without_on_accept() -> Result<(), VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    tokio::spawn(server.serve(|note: Note| async move { Ok(note) }));
+
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+    let welcome = client.welcome().expect("WELCOME");
+    assert_eq!(welcome.typ, FrameType::Welcome);
+    assert!(welcome.payload.is_empty());
+    Ok(())
+}
@@ -0,0 +1,314 @@
+//! Tests for replaying replies to requests sent again with the same idempotency key
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use vstp::{
+    easy::{ConnectOptions, ServerOptions, VstpClient, VstpServer, IDEMPOTENCY_KEY_HEADER},
+    idempotency::{MemoryIdempotencyStore, REPLAYED_HEADER},
+    router::METHOD_HEADER,
+    ErrorCode, Frame, FrameType, Router, VstpError,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Charge {
+    amount: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Receipt {
+    charge_no: u64,
+    amount: u64,
+}
+
+/// A router whose `charge` handler numbers the charges it makes, and whose
+/// `decline` handler always fails; both count their runs in the returned counter
+fn payment_router() -> (Router, Arc<AtomicU64>) {
+    let runs = Arc::new(AtomicU64::new(0));
+    let (charges, declines) = (runs.clone(), runs.clone());
+    let router = Router::new()
+        .route("charge", move |charge: Charge| {
+            let charge_no = charges.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok(Receipt {
+                    charge_no,
+                    amount: charge.amount,
+                })
+            }
+        })
+        .route("decline", move |_: Charge| {
+            let attempt = declines.fetch_add(1, Ordering::SeqCst) + 1;
+            async move { Err::<Receipt, _>(VstpError::Protocol(format!("declined #{}", attempt))) }
+        });
+    (router, runs)
+}
+
+/// A server for [`payment_router`] keeping replies as `options` say, in a
+/// store of its own unless they name one
+async fn payment_server(options: ServerOptions) -> Result<(SocketAddr, Arc<AtomicU64>), VstpError> {
+    let (router, runs) = payment_router();
+    let addr = serve(router, options).await?;
+    Ok((addr, runs))
+}
+
+async fn serve(router: Router, options: ServerOptions) -> Result<SocketAddr, VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    let idempotency = options
+        .idempotency
+        .clone()
+        .or_else(|| Some(Arc::new(MemoryIdempotencyStore::default())));
+    server.set_options(ServerOptions {
+        idempotency,
+        ..options
+    });
+    tokio::spawn(server.serve_router(router));
+    Ok(addr)
+}
+
+fn request(method: &str, key: &str) -> Frame {
+    let payload = serde_json::to_vec(&Charge { amount: 5 }).unwrap();
+    Frame::new(FrameType::Data)
+        .with_header(METHOD_HEADER, method)
+        .with_header(IDEMPOTENCY_KEY_HEADER, key)
+        .with_payload(payload)
+}
+
+async fn exchange(client: &VstpClient, frame: Frame) -> Result<Frame, VstpError> {
+    client.send_raw(frame).await?;
+    client.receive_raw().await
+}
+
+/// Relays TCP connections to a server, and when told to, loses the next
+/// reply along with the connection it was on
+struct ReplyLosingRelay {
+    addr: SocketAddr,
+    lose_next: Arc<AtomicBool>,
+}
+
+impl ReplyLosingRelay {
+    async fn start(upstream: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let lose_next = Arc::new(AtomicBool::new(false));
+        let flag = lose_next.clone();
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let Ok(server) = TcpStream::connect(upstream).await else {
+                    continue;
+                };
+                tokio::spawn(relay(client, server, flag.clone()));
+            }
+        });
+        Ok(Self { addr, lose_next })
+    }
+
+    fn lose_next_reply(&self) {
+        self.lose_next.store(true, Ordering::SeqCst);
+    }
+}
+
+async fn relay(client: TcpStream, server: TcpStream, lose_next: Arc<AtomicBool>) {
+    let (mut client_rx, mut client_tx) = client.into_split();
+    let (mut server_rx, mut server_tx) = server.into_split();
+    let downstream = async {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = server_rx.read(&mut buf).await?;
+            if n == 0 || lose_next.swap(false, Ordering::SeqCst) {
+                return Ok::<_, std::io::Error>(());
+            }
+            client_tx.write_all(&buf[..n]).await?;
+        }
+    };
+    // Either side ending drops both connections
+    tokio::select! {
+        _ = tokio::io::copy(&mut client_rx, &mut server_tx) => {}
+        _ = downstream => {}
+    }
+}
+
+#[tokio::test]
+async fn test_call_whose_reply_is_lost_is_replayed_after_reconnecting() -> Result<(), VstpError> {
+    let (addr, runs) = payment_server(ServerOptions::default()).await?;
+    let relay = ReplyLosingRelay::start(addr).await?;
+    let options = ConnectOptions {
+        auto_reconnect: true,
+        idempotency_keys: true,
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(relay.addr.to_string(), options).await?;
+
+    relay.lose_next_reply();
+    let receipt: Receipt = client.call("charge", Charge { amount: 5 }).await?;
+    assert_eq!(
+        receipt,
+        Receipt {
+            charge_no: 1,
+            amount: 5
+        }
+    );
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // A new call gets a new key, so it is a new charge
+    let receipt: Receipt = client.call("charge", Charge { amount: 5 }).await?;
+    assert_eq!(receipt.charge_no, 2);
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_same_key_gets_the_same_reply_without_running_the_handler() -> Result<(), VstpError> {
+    let (addr, runs) = payment_server(ServerOptions::default()).await?;
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    let first = exchange(&client, request("charge", "order-1")).await?;
+    let again = exchange(&client, request("charge", "order-1")).await?;
+    assert_eq!(first.get_header(REPLAYED_HEADER), None);
+    assert_eq!(again.get_header(REPLAYED_HEADER), Some("true"));
+    assert_eq!((again.typ, &again.payload), (first.typ, &first.payload));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // Failed handlers are replayed too
+    let first = exchange(&client, request("decline", "order-2")).await?;
+    let again = exchange(&client, request("decline", "order-2")).await?;
+    assert_eq!(first.typ, FrameType::Err);
+    assert_eq!((again.typ, &again.payload), (first.typ, &first.payload));
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_key_reused_for_a_different_request_is_refused() -> Result<(), VstpError> {
+    let (addr, runs) = payment_server(ServerOptions::default()).await?;
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+    let larger = |key: &str| {
+        let mut frame = request("charge", key);
+        frame.payload = serde_json::to_vec(&Charge { amount: 500 }).unwrap();
+        frame
+    };
+
+    let first = exchange(&client, request("charge", "order-1")).await?;
+    assert_eq!(first.typ, FrameType::Data);
+    let reused = exchange(&client, larger("order-1")).await?;
+    assert_eq!(reused.error_code(), Some(ErrorCode::IdempotencyKeyReused));
+    assert_eq!(reused.get_header(REPLAYED_HEADER), None);
+    // Only the method and payload count, not e.g. tracing headers
+    let resent = request("charge", "order-1").with_header("trace-id", "retry");
+    let again = exchange(&client, resent).await?;
+    assert_eq!(again.get_header(REPLAYED_HEADER), Some("true"));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+    // The same while the first is still running
+    let other = VstpClient::connect_tcp(addr.to_string()).await?;
+    let (first, reused) = tokio::join!(exchange(&client, request("charge", "order-2")), async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        exchange(&other, larger("order-2")).await
+    },);
+    assert_eq!(first?.typ, FrameType::Data);
+    assert_eq!(reused?.error_code(), Some(ErrorCode::IdempotencyKeyReused));
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_duplicates_wait_for_the_first() -> Result<(), VstpError> {
+    let (addr, runs) = payment_server(ServerOptions::default()).await?;
+    let a = VstpClient::connect_tcp(addr.to_string()).await?;
+    let b = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    let (first, second) = tokio::join!(
+        exchange(&a, request("charge", "order-1")),
+        exchange(&b, request("charge", "order-1")),
+    );
+    let (first, second) = (first?, second?);
+    assert_eq!(first.payload, second.payload);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keys_of_different_identities_do_not_collide() -> Result<(), VstpError> {
+    let api_keys = HashMap::from([
+        ("key-a".to_string(), "alice".to_string()),
+        ("key-b".to_string(), "bob".to_string()),
+    ]);
+    let (addr, runs) = payment_server(ServerOptions {
+        api_keys,
+        ..ServerOptions::default()
+    })
+    .await?;
+    let connect = |token: &str| {
+        let options = ConnectOptions {
+            auth_token: Some(token.to_string()),
+            ..ConnectOptions::default()
+        };
+        VstpClient::connect_tcp_with_options(addr.to_string(), options)
+    };
+    let alice = connect("key-a").await?;
+    let bob = connect("key-b").await?;
+
+    let a = exchange(&alice, request("charge", "order-1")).await?;
+    let b = exchange(&bob, request("charge", "order-1")).await?;
+    assert_ne!(a.payload, b.payload);
+    assert_eq!(b.get_header(REPLAYED_HEADER), None);
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idempotent_router_replays_on_its_own() {
+    let store = Arc::new(MemoryIdempotencyStore::default());
+    let (router, runs) = payment_router();
+    let router = router.idempotent(store.clone());
+
+    let first = router.handle(&request("charge", "order-1")).await;
+    let again = router.handle(&request("charge", "order-1")).await;
+    assert_eq!(first.get_header(REPLAYED_HEADER), None);
+    assert_eq!(again.get_header(REPLAYED_HEADER), Some("true"));
+    assert_eq!(again.payload, first.payload);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!(store.len(), 1);
+
+    let mut larger = request("charge", "order-1");
+    larger.payload = serde_json::to_vec(&Charge { amount: 500 }).unwrap();
+    let reused = router.handle(&larger).await;
+    assert_eq!(reused.error_code(), Some(ErrorCode::IdempotencyKeyReused));
+
+    // Requests without a key run every time
+    let unkeyed = Frame::new(FrameType::Data)
+        .with_header(METHOD_HEADER, "charge")
+        .with_payload(serde_json::to_vec(&Charge { amount: 5 }).unwrap());
+    router.handle(&unkeyed).await;
+    router.handle(&unkeyed).await;
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_server_uses_the_idempotent_routers_store() -> Result<(), VstpError> {
+    let router_store = Arc::new(MemoryIdempotencyStore::default());
+    let server_store = Arc::new(MemoryIdempotencyStore::default());
+    let (router, runs) = payment_router();
+    let options = ServerOptions {
+        idempotency: Some(server_store.clone()),
+        ..ServerOptions::default()
+    };
+    let addr = serve(router.idempotent(router_store.clone()), options).await?;
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    let first = exchange(&client, request("charge", "order-1")).await?;
+    let again = exchange(&client, request("charge", "order-1")).await?;
+    assert_eq!(again.get_header(REPLAYED_HEADER), Some("true"));
+    assert_eq!(again.payload, first.payload);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!((router_store.len(), server_store.len()), (1, 0));
+    Ok(())
+}
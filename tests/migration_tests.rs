@@ -0,0 +1,106 @@
+//! Tests for moving sessions between server instances
+
+use std::net::SocketAddr;
+
+use vstp::{
+    easy::{current_session_extensions, VstpClient, VstpServer},
+    migration::{SessionRegistry, Snapshot},
+    Router, VstpError,
+};
+
+/// Requests the session made so far, which moves with it
+#[derive(Debug, Clone, Default)]
+struct Counter(u64);
+
+impl Snapshot for Counter {
+    const NAME: &'static str = "counter";
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_be_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Counter(u64::from_be_bytes(bytes.try_into().ok()?)))
+    }
+}
+
+/// Per-session state that can't move
+#[derive(Debug, Clone)]
+struct Scratch;
+
+/// A server whose `bump` route counts the session's requests
+fn counting_server() -> Result<(SocketAddr, SessionRegistry), VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let sessions = server.sessions();
+    let router = Router::new().route("bump", |_: ()| async {
+        let extensions = current_session_extensions().expect("inside a handler");
+        let count = extensions.get_snapshot::<Counter>().unwrap_or_default().0 + 1;
+        extensions.insert_snapshot(Counter(count));
+        extensions.insert(Scratch);
+        Ok(count)
+    });
+    tokio::spawn(server.serve_router(router));
+    Ok((addr, sessions))
+}
+
+#[tokio::test]
+async fn test_session_state_follows_the_client_to_a_new_instance() -> Result<(), VstpError> {
+    let (old_addr, old) = counting_server()?;
+    let (new_addr, new) = counting_server()?;
+
+    let client = VstpClient::connect_tcp(old_addr.to_string()).await?;
+    for expected in 1..=3u64 {
+        assert_eq!(client.call::<_, u64>("bump", ()).await?, expected);
+    }
+    let id = client.session_id().expect("the WELCOME names the session");
+    assert_eq!(old.session_ids(), vec![id]);
+
+    let snapshot = old.snapshot_session(id, &new_addr.to_string())?;
+    assert_eq!(snapshot.id, id);
+    assert_eq!(snapshot.extensions["counter"], 3u64.to_be_bytes());
+    assert_eq!(snapshot.dropped.len(), 1);
+    assert!(snapshot.dropped[0].ends_with("Scratch"));
+    assert!(old.session_ids().is_empty());
+    assert_eq!(new.restore_session(&snapshot.to_bytes())?, id);
+
+    // The old instance redirects the call, and the new one carries on counting
+    assert_eq!(client.call::<_, u64>("bump", ()).await?, 4);
+    assert_eq!(client.session_id(), Some(id));
+    assert_eq!(new.session_ids(), vec![id]);
+
+    // Later connections go to the new instance too, in sessions of their own
+    client.reconnect().await?;
+    assert_eq!(client.call::<_, u64>("bump", ()).await?, 1);
+    assert_ne!(client.session_id(), Some(id));
+    assert!(new.session_ids().contains(&client.session_id().unwrap()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshots_are_taken_and_restored_once() -> Result<(), VstpError> {
+    let (old_addr, old) = counting_server()?;
+    let (new_addr, new) = counting_server()?;
+    let client = VstpClient::connect_tcp(old_addr.to_string()).await?;
+    client.call::<_, u64>("bump", ()).await?;
+    let id = client.session_id().unwrap();
+
+    assert!(matches!(
+        old.snapshot_session(id, "not an address"),
+        Err(VstpError::InvalidAddress)
+    ));
+    assert!(old.snapshot_session(id.wrapping_add(1), &new_addr.to_string()).is_err());
+    let snapshot = old.snapshot_session(id, &new_addr.to_string())?;
+    assert!(old.snapshot_session(id, &new_addr.to_string()).is_err());
+
+    new.restore_session(&snapshot.to_bytes())?;
+    assert_eq!(client.call::<_, u64>("bump", ()).await?, 2);
+    assert!(new.restore_session(b"not a snapshot").is_err());
+
+    // A second client presenting nothing gets a session of its own
+    let other = VstpClient::connect_tcp(new_addr.to_string()).await?;
+    assert_eq!(other.call::<_, u64>("bump", ()).await?, 1);
+    assert_ne!(other.session_id(), Some(id));
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Tests for the order in which TCP frames reach server handlers
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vstp::{
+    easy::{VstpClient, VstpServer},
+    tcp::{VstpTcpClient, VstpTcpServer},
+    Frame, FrameType, VstpError,
+};
+
+const FRAMES: u64 = 10_000;
+
+/// Requests in the order the handler saw them
+type Seen = Arc<Mutex<Vec<Seq>>>;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+struct Seq {
+    writer: u64,
+    n: u64,
+}
+
+/// Panics unless `seen` counts 0, 1, 2, ... up to `count` for every writer
+fn assert_in_order(seen: &[Seq], writers: u64, count: u64) {
+    for writer in 0..writers {
+        let ns: Vec<u64> = seen
+            .iter()
+            .filter(|seq| seq.writer == writer)
+            .map(|seq| seq.n)
+            .collect();
+        assert_eq!(ns.len() as u64, count, "writer {}", writer);
+        if let Some(i) = ns.iter().enumerate().position(|(i, n)| *n != i as u64) {
+            panic!("writer {}: frame {} arrived as number {}", writer, ns[i], i);
+        }
+    }
+}
+
+/// An easy server recording the order its handler sees requests in
+fn recording_server() -> Result<(std::net::SocketAddr, Seen), VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let seen: Seen = Arc::default();
+    let recorded = seen.clone();
+    tokio::spawn(server.serve(move |seq: Seq| {
+        let seen = recorded.clone();
+        async move {
+            // Give later frames every chance to overtake this one
+            if seq.n.is_multiple_of(7) {
+                tokio::task::yield_now().await;
+            }
+            seen.lock().unwrap().push(seq);
+            Ok(seq)
+        }
+    }));
+    Ok((addr, seen))
+}
+
+#[tokio::test]
+async fn test_tcp_frames_reach_the_handler_in_send_order() -> Result<(), VstpError> {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await?;
+    let addr = server.local_addr()?;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = seen.clone();
+    tokio::spawn(server.run(move |_, frame| {
+        let seen = recorded.clone();
+        async move {
+            let n = frame.get_header("seq").unwrap().parse().unwrap();
+            seen.lock().unwrap().push(Seq { writer: 0, n });
+        }
+    }));
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await?;
+    for n in 0..FRAMES {
+        let frame = Frame::new(FrameType::Data).with_header("seq", &n.to_string());
+        client.send(frame).await?;
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while (seen.lock().unwrap().len() as u64) < FRAMES {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("every frame handled");
+
+    assert_in_order(&seen.lock().unwrap(), 1, FRAMES);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_easy_client_requests_are_handled_in_send_order() -> Result<(), VstpError> {
+    let (addr, seen) = recording_server()?;
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    // Pipelined: a window of requests is in flight before any reply is read
+    for window in 0..FRAMES / 100 {
+        for n in window * 100..(window + 1) * 100 {
+            client.send(Seq { writer: 0, n }).await?;
+        }
+        for n in window * 100..(window + 1) * 100 {
+            let reply: Seq = client.receive().await?;
+            assert_eq!(reply, Seq { writer: 0, n });
+        }
+    }
+
+    assert_in_order(&seen.lock().unwrap(), 1, FRAMES);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cloned_clients_keep_their_own_order() -> Result<(), VstpError> {
+    let (addr, seen) = recording_server()?;
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    // Clones interleave whole frames; each clone's frames stay in order
+    let writers = 4;
+    let per_writer = 250;
+    let sends = (0..writers).map(|writer| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            for n in 0..per_writer {
+                client.send(Seq { writer, n }).await?;
+            }
+            Ok::<_, VstpError>(())
+        })
+    });
+    for send in futures::future::join_all(sends).await {
+        send.unwrap()?;
+    }
+    for _ in 0..writers * per_writer {
+        let _: Seq = client.receive().await?;
+    }
+
+    assert_in_order(&seen.lock().unwrap(), writers, per_writer);
+    Ok(())
+}
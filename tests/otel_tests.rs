@@ -0,0 +1,111 @@
+//! Tests for OpenTelemetry spans and trace context propagation
+#![cfg(feature = "otel")]
+
+use opentelemetry::context::FutureExt;
+use opentelemetry::trace::{SpanKind, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+use serde::{Deserialize, Serialize};
+use vstp::{
+    easy::{ConnectOptions, VstpClient, VstpServer},
+    otel::{OtelLayer, TRACEPARENT_HEADER},
+    router::METHOD_HEADER,
+    testing::{FrameTap, TapDirection},
+    Router, VstpError,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Note {
+    text: String,
+}
+
+fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a KeyValue> {
+    span.attributes.iter().find(|kv| kv.key.as_str() == key)
+}
+
+#[tokio::test]
+async fn test_call_spans_join_the_callers_trace() -> Result<(), VstpError> {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    global::set_tracer_provider(provider.clone());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let router = Router::new()
+        .route("notes.echo", |note: Note| async move { Ok(note) })
+        .layer(OtelLayer::new());
+    tokio::spawn(server.serve_router(router));
+
+    let tap = FrameTap::start(addr).await?;
+    let options = ConnectOptions {
+        propagate_trace_context: true,
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options(tap.addr().to_string(), options).await?;
+
+    let root = Context::current_with_span(global::tracer("checkout").start("checkout"));
+    let note = Note {
+        text: "traced".to_string(),
+    };
+    let reply: Note = client
+        .call("notes.echo", note.clone())
+        .with_context(root.clone())
+        .await?;
+    assert_eq!(reply, note);
+    root.span().end();
+
+    let spans = exporter.get_finished_spans().unwrap();
+    let root = spans.iter().find(|s| s.name == "checkout").unwrap();
+    let client_span = spans
+        .iter()
+        .find(|s| s.span_kind == SpanKind::Client)
+        .unwrap();
+    let server_span = spans
+        .iter()
+        .find(|s| s.span_kind == SpanKind::Server)
+        .unwrap();
+
+    // checkout -> client call -> server handler, all in one trace
+    let trace_id = root.span_context.trace_id();
+    assert_eq!(client_span.span_context.trace_id(), trace_id);
+    assert_eq!(server_span.span_context.trace_id(), trace_id);
+    assert_eq!(client_span.parent_span_id, root.span_context.span_id());
+    assert_eq!(
+        server_span.parent_span_id,
+        client_span.span_context.span_id()
+    );
+    assert!(server_span.parent_span_is_remote);
+
+    for span in [client_span, server_span] {
+        assert_eq!(span.name, "notes.echo");
+        assert_eq!(
+            attribute(span, "vstp.method").map(|kv| kv.value.as_str().into_owned()),
+            Some("notes.echo".to_string())
+        );
+        assert_eq!(
+            attribute(span, "vstp.frame_type").map(|kv| kv.value.as_str().into_owned()),
+            Some("DATA".to_string())
+        );
+        assert!(attribute(span, "vstp.payload_bytes").is_some());
+        assert_eq!(
+            attribute(span, "network.peer.address").map(|kv| kv.value.as_str().into_owned()),
+            Some("127.0.0.1".to_string())
+        );
+    }
+
+    // The request carried the client span's context on the wire
+    let frames = tap.frames();
+    let request = frames
+        .iter()
+        .find(|(dir, f)| *dir == TapDirection::ToServer && f.get_header(METHOD_HEADER).is_some())
+        .map(|(_, f)| f)
+        .unwrap();
+    assert_eq!(
+        request.get_header(TRACEPARENT_HEADER),
+        Some(format!("00-{}-{}-01", trace_id, client_span.span_context.span_id()).as_str())
+    );
+    Ok(())
+}
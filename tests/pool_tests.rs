@@ -0,0 +1,153 @@
+//! Tests for pools of pre-handshaken clients
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vstp::{
+    easy::{ConnectOptions, VstpServer},
+    pool::{PoolConfig, PoolStats, VstpClientPool},
+    testing::{FrameTap, TapDirection},
+    FrameType, Router, VstpError,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Note {
+    text: String,
+}
+
+fn echo_server() -> Result<std::net::SocketAddr, VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+    Ok(addr)
+}
+
+async fn wait_for(pool: &VstpClientPool, stats: PoolStats) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while pool.stats() != stats {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("pool stuck at {:?}, wanted {:?}", pool.stats(), stats));
+}
+
+#[tokio::test]
+async fn test_get_from_warm_pool_skips_the_handshake() -> Result<(), VstpError> {
+    let tap = FrameTap::start(echo_server()?).await?;
+    // The tap relays one connection, which is the one warmed here
+    let pool = VstpClientPool::new(tap.addr().to_string(), ConnectOptions::default());
+    pool.warm(1).await?;
+    let hellos = |tap: &FrameTap| {
+        tap.frames()
+            .iter()
+            .filter(|(dir, f)| *dir == TapDirection::ToServer && f.typ == FrameType::Hello)
+            .count()
+    };
+    assert_eq!(hellos(&tap), 1);
+
+    let client = pool.get().await?;
+    let note = Note {
+        text: "warm".to_string(),
+    };
+    let reply: Note = client.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+    // The session was already established: no HELLO went out for it
+    assert_eq!(hellos(&tap), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_replenishes_in_the_background() -> Result<(), VstpError> {
+    let addr = echo_server()?;
+    let pool = VstpClientPool::new(addr.to_string(), ConnectOptions::default());
+    pool.warm(2).await?;
+    assert_eq!(
+        pool.stats(),
+        PoolStats {
+            warm: 2,
+            cold_handshakes: 2,
+            resumed_handshakes: 0,
+        }
+    );
+
+    let first = pool.get().await?;
+    let second = pool.get().await?;
+    wait_for(
+        &pool,
+        PoolStats {
+            warm: 2,
+            cold_handshakes: 4,
+            resumed_handshakes: 0,
+        },
+    )
+    .await;
+
+    let note = Note {
+        text: "kept".to_string(),
+    };
+    let reply: Note = first.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+    let reply: Note = second.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+    Ok(())
+}
+
+/// Relays connections to `upstream` until the returned sender fires, which
+/// cuts every relayed connection while still accepting new ones
+async fn cuttable_relay(
+    upstream: std::net::SocketAddr,
+) -> Result<(std::net::SocketAddr, tokio::sync::watch::Sender<u32>), VstpError> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (cut, cuts) = tokio::sync::watch::channel(0);
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            let mut cuts = cuts.clone();
+            cuts.mark_unchanged();
+            tokio::spawn(async move {
+                let Ok(mut outbound) = tokio::net::TcpStream::connect(upstream).await else {
+                    return;
+                };
+                tokio::select! {
+                    _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+                    _ = cuts.changed() => {}
+                }
+            });
+        }
+    });
+    Ok((addr, cut))
+}
+
+#[tokio::test]
+async fn test_pool_replaces_connections_that_die_while_idle() -> Result<(), VstpError> {
+    let (addr, cut) = cuttable_relay(echo_server()?).await?;
+    let config = PoolConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        heartbeat_timeout: Duration::from_millis(500),
+        retry_backoff: Duration::from_millis(20),
+    };
+    let pool = VstpClientPool::with_config(addr.to_string(), ConnectOptions::default(), config);
+    pool.warm(1).await?;
+
+    cut.send_modify(|n| *n += 1);
+    // The heartbeat finds the connection closed and the pool opens another
+    wait_for(
+        &pool,
+        PoolStats {
+            warm: 1,
+            cold_handshakes: 2,
+            resumed_handshakes: 0,
+        },
+    )
+    .await;
+
+    let note = Note {
+        text: "back".to_string(),
+    };
+    let reply: Note = pool.get().await?.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! Tests for frame priority in UDP send pacing and worker dispatch
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tokio::time::{advance, timeout, Instant};
+use vstp::{
+    udp::{
+        client::UdpConfig, PacedQueue, PacingConfig, ShardStrategy, UdpServerConfig,
+        VstpUdpClient, VstpUdpServer,
+    },
+    try_decode_frame, Frame, FrameType, Priority,
+};
+
+fn dest() -> SocketAddr {
+    "127.0.0.1:9".parse().unwrap()
+}
+
+fn pacing(rate: u64) -> PacingConfig {
+    PacingConfig {
+        rate_bytes_per_sec: rate,
+        burst_bytes: 1000,
+        p0_bypass_bytes: 2000,
+        max_queued: 64,
+    }
+}
+
+#[test]
+fn test_priority_header() {
+    assert_eq!(Frame::new(FrameType::Data).priority(), Priority::P2);
+    let frame = Frame::new(FrameType::Data)
+        .with_priority(Priority::P3)
+        .with_priority(Priority::P0);
+    assert_eq!(frame.priority(), Priority::P0);
+    assert_eq!(frame.get_header("priority"), Some("0"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_paced_queue_orders_by_priority_and_lets_p0_bypass() {
+    let mut queue = PacedQueue::new(pacing(10_000));
+    for i in 0..5u8 {
+        queue.push(Priority::P3, Bytes::from(vec![i; 1000]), dest());
+    }
+
+    // The burst covers one bulk datagram, then the bucket is empty
+    assert_eq!(queue.pop_ready().unwrap().0[0], 0);
+    assert!(queue.pop_ready().is_none());
+    assert_eq!(queue.next_ready_in(), Some(Duration::from_millis(100)));
+
+    // P0 jumps the queue and overdraws the bucket instead of waiting
+    queue.push(Priority::P0, Bytes::from_static(b"delta"), dest());
+    assert_eq!(&queue.pop_ready().unwrap().0[..], b"delta");
+    assert!(queue.pop_ready().is_none());
+
+    // Bulk resumes once the debt is paid back
+    advance(Duration::from_millis(100)).await;
+    assert!(queue.pop_ready().is_none());
+    advance(Duration::from_millis(1)).await;
+    assert_eq!(queue.pop_ready().unwrap().0[0], 1);
+    assert_eq!(queue.len(), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_paced_queue_drops_lowest_priority_when_full() {
+    let mut queue = PacedQueue::new(PacingConfig {
+        max_queued: 2,
+        ..pacing(1)
+    });
+    assert!(queue.push(Priority::P3, Bytes::from_static(b"bulk-1"), dest()));
+    assert!(queue.push(Priority::P3, Bytes::from_static(b"bulk-2"), dest()));
+    assert!(!queue.push(Priority::P0, Bytes::from_static(b"delta"), dest()));
+    assert!(!queue.push(Priority::P3, Bytes::from_static(b"bulk-3"), dest()));
+    assert_eq!(queue.dropped(), 2);
+
+    assert_eq!(&queue.pop_ready().unwrap().0[..], b"delta");
+    assert_eq!(queue.len(), 1);
+}
+
+#[tokio::test]
+async fn test_paced_client_p0_latency_under_saturation() {
+    let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sink_addr = sink.local_addr().unwrap();
+
+    // A 50 KB/s link with 40 KB of bulk data queued ahead of the delta
+    let config = UdpConfig {
+        pacing: Some(PacingConfig {
+            rate_bytes_per_sec: 50_000,
+            burst_bytes: 1200,
+            p0_bypass_bytes: 2400,
+            max_queued: 1024,
+        }),
+        ..UdpConfig::default()
+    };
+    let client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    for i in 0..40u32 {
+        let chunk = Frame::new(FrameType::Data)
+            .with_priority(Priority::P3)
+            .with_header("chunk", &i.to_string())
+            .with_payload(vec![0u8; 1000]);
+        client.send(chunk, sink_addr).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let sent_at = Instant::now();
+    let delta = Frame::new(FrameType::Data)
+        .with_priority(Priority::P0)
+        .with_payload(b"delta".to_vec());
+    client.send(delta, sink_addr).await.unwrap();
+
+    let mut buf = vec![0u8; 2048];
+    let mut chunks_before_delta = 0;
+    let delta_latency = loop {
+        let (len, _) = timeout(Duration::from_secs(2), sink.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut data = bytes::BytesMut::from(&buf[..len]);
+        let frame = try_decode_frame(&mut data, 65536).unwrap().unwrap();
+        if frame.priority() == Priority::P0 {
+            break sent_at.elapsed();
+        }
+        chunks_before_delta += 1;
+    };
+
+    assert!(delta_latency < Duration::from_millis(100), "{:?}", delta_latency);
+    // Most of the bulk data is still queued behind the pacing limit
+    assert!(chunks_before_delta < 10, "{}", chunks_before_delta);
+}
+
+#[tokio::test]
+async fn test_workers_prefer_high_priority_and_drop_lowest() {
+    let config = UdpServerConfig {
+        worker_queue_depth: 3,
+        ..UdpServerConfig::default()
+    };
+    let server = Arc::new(
+        VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addr().unwrap();
+
+    let gate = Arc::new(Semaphore::new(0));
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let (runner, worker_gate, recorder) = (server.clone(), gate.clone(), handled.clone());
+    let server_handle = tokio::spawn(async move {
+        runner
+            .run_workers(1, ShardStrategy::PeerHash, move |_addr, frame| {
+                let gate = worker_gate.clone();
+                let recorder = recorder.clone();
+                async move {
+                    let name = frame.payload_text().unwrap().to_string();
+                    if name == "blocker" {
+                        gate.acquire().await.unwrap().forget();
+                    }
+                    recorder.lock().unwrap().push(name);
+                }
+            })
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let send = |name: &str, priority| {
+        Frame::new(FrameType::Data)
+            .with_priority(priority)
+            .with_payload_text(name)
+    };
+    client.send(send("blocker", Priority::P2), server_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for name in ["bulk-1", "bulk-2", "bulk-3"] {
+        client.send(send(name, Priority::P3), server_addr).await.unwrap();
+    }
+    client.send(send("delta", Priority::P0), server_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(server.dropped_frame_count(), 1);
+    gate.add_permits(1);
+    timeout(Duration::from_secs(2), async {
+        while handled.lock().unwrap().len() < 4 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        *handled.lock().unwrap(),
+        vec!["blocker", "delta", "bulk-1", "bulk-2"]
+    );
+
+    server_handle.abort();
+}
@@ -0,0 +1,315 @@
+//! Tests for publishing to topics and subscribing with backfill
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use vstp::{
+    easy::{ConnectOptions, ServerOptions, VstpClient, VstpServer},
+    pubsub::{topic_matches, Delivery, PubSub, PubSubConfig, TopicCheck},
+    Router, VstpError,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Alert {
+    no: u32,
+}
+
+/// A server for a broker with `config`, with a handle to the broker
+fn pubsub_server(config: PubSubConfig) -> Result<(SocketAddr, PubSub), VstpError> {
+    let pubsub = PubSub::new(config);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    tokio::spawn(server.serve_router(pubsub.routes(Router::new())));
+    Ok((addr, pubsub))
+}
+
+/// A broker's server where `key-a` authenticates alice and `key-b` bob
+fn tenant_server(config: PubSubConfig) -> Result<(SocketAddr, PubSub), VstpError> {
+    let pubsub = PubSub::new(config);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    server.set_options(ServerOptions {
+        api_keys: [("key-a", "alice"), ("key-b", "bob")]
+            .into_iter()
+            .map(|(key, identity)| (key.to_string(), identity.to_string()))
+            .collect(),
+        ..ServerOptions::default()
+    });
+    tokio::spawn(server.serve_router(pubsub.routes(Router::new())));
+    Ok((addr, pubsub))
+}
+
+async fn connect_as(addr: SocketAddr, key: &str) -> Result<VstpClient, VstpError> {
+    let options = ConnectOptions {
+        auth_token: Some(key.to_string()),
+        ..ConnectOptions::default()
+    };
+    VstpClient::connect_tcp_with_options(addr.to_string(), options).await
+}
+
+/// Allows each identity the topics under its own name
+fn own_topics() -> TopicCheck {
+    TopicCheck::new(|identity, topic| topic.starts_with(&format!("{}.", identity)))
+}
+
+fn retaining(retain: usize) -> PubSubConfig {
+    PubSubConfig {
+        retain,
+        ..PubSubConfig::default()
+    }
+}
+
+/// Publish probes, `Alert { no: 0 }`, on `topic` until one reaches exactly
+/// `subscriptions` subscriptions
+async fn await_subscriptions(pubsub: &PubSub, topic: &str, subscriptions: usize) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while pubsub.publish(topic, &Alert { no: 0 }).unwrap() != subscriptions {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("subscriptions to settle");
+}
+
+#[tokio::test]
+async fn test_backfill_comes_before_live_messages() -> Result<(), VstpError> {
+    let (addr, _) = pubsub_server(retaining(10))?;
+    let publisher = VstpClient::connect_tcp(addr.to_string()).await?;
+    for no in 1..=5 {
+        assert_eq!(publisher.publish("alerts", Alert { no }).await?, 0);
+    }
+
+    let subscriber = VstpClient::connect_tcp(addr.to_string()).await?;
+    let mut alerts = subscriber.subscribe_with_backfill::<Alert>("alerts", 3);
+    for no in 3..=5 {
+        let delivery = alerts.next().await.unwrap()?;
+        assert_eq!(
+            delivery,
+            Delivery {
+                topic: "alerts".to_string(),
+                message: Alert { no },
+                retained: true,
+            }
+        );
+    }
+
+    assert_eq!(publisher.publish("alerts", Alert { no: 6 }).await?, 1);
+    let live = alerts.next().await.unwrap()?;
+    assert_eq!(live.message, Alert { no: 6 });
+    assert!(!live.retained);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscriptions_get_only_their_topic_until_dropped() -> Result<(), VstpError> {
+    let (addr, pubsub) = pubsub_server(PubSubConfig::default())?;
+    let publisher = VstpClient::connect_tcp(addr.to_string()).await?;
+    let subscriber = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    let mut alerts = subscriber.subscribe::<Alert>("alerts");
+    let (probe, ()) = tokio::join!(alerts.next(), await_subscriptions(&pubsub, "alerts", 1));
+    assert_eq!(probe.unwrap()?.message, Alert { no: 0 });
+
+    publisher.publish("news", Alert { no: 2 }).await?;
+    publisher.publish("alerts", Alert { no: 3 }).await?;
+    let delivery = alerts.next().await.unwrap()?;
+    assert_eq!(delivery.topic, "alerts");
+    assert_eq!(delivery.message, Alert { no: 3 });
+
+    drop(alerts);
+    await_subscriptions(&pubsub, "alerts", 0).await;
+    assert!(pubsub.stats().published() >= 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retention_outlives_subscribers_within_its_bounds() -> Result<(), VstpError> {
+    let (addr, pubsub) = pubsub_server(PubSubConfig {
+        retain: 2,
+        // Room for three messages of either topic
+        max_retained_bytes: 3 * r#"{"no":1}"#.len() + 3 * "ticks".len(),
+        ..PubSubConfig::default()
+    })?;
+    let subscriber = VstpClient::connect_tcp(addr.to_string()).await?;
+
+    // A subscriber comes and goes between publishes
+    pubsub.publish("ticks", &Alert { no: 1 })?;
+    {
+        let mut ticks = subscriber.subscribe_with_backfill::<Alert>("ticks", 5);
+        assert_eq!(ticks.next().await.unwrap()?.message, Alert { no: 1 });
+    }
+    pubsub.publish("ticks", &Alert { no: 2 })?;
+    pubsub.publish("ticks", &Alert { no: 3 })?;
+
+    // Two per topic, but the bytes of only three across topics
+    pubsub.publish("tocks", &Alert { no: 4 })?;
+    pubsub.publish("tocks", &Alert { no: 5 })?;
+    assert_eq!(pubsub.stats().evicted(), 2);
+
+    let mut ticks = subscriber.subscribe_with_backfill::<Alert>("ticks", 5);
+    let only = ticks.next().await.unwrap()?;
+    assert_eq!(only.message, Alert { no: 3 });
+    assert!(only.retained);
+    drop(ticks);
+
+    let mut tocks = subscriber.subscribe_with_backfill::<Alert>("tocks", 5);
+    for no in [4, 5] {
+        assert_eq!(tocks.next().await.unwrap()?.message, Alert { no });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_wildcards_match_whole_segments() {
+    assert!(topic_matches("alerts", "alerts"));
+    assert!(!topic_matches("alerts", "alerts.disk"));
+    assert!(topic_matches("alerts.*", "alerts.disk"));
+    assert!(!topic_matches("alerts.*", "alerts.disk.sda"));
+    assert!(!topic_matches("alerts.*", "alerts"));
+    assert!(topic_matches("alerts.>", "alerts.disk.sda"));
+    assert!(!topic_matches("alerts.>", "alerts"));
+    assert!(topic_matches("*.disk.>", "alerts.disk.sda"));
+    assert!(!topic_matches("*.disk.>", "alerts.cpu.0"));
+    assert!(topic_matches(">", "alerts"));
+}
+
+#[tokio::test]
+async fn test_subscriptions_are_checked_against_the_pattern_given() -> Result<(), VstpError> {
+    let (addr, pubsub) = tenant_server(PubSubConfig {
+        retain: 10,
+        can_subscribe: Some(own_topics()),
+        ..PubSubConfig::default()
+    })?;
+    for (no, topic) in [
+        (1, "alice.disk.sda"),
+        (2, "alice.cpu"),
+        (3, "bob.cpu"),
+        (4, "alice"),
+    ] {
+        pubsub.publish(topic, &Alert { no })?;
+    }
+    let alice = connect_as(addr, "key-a").await?;
+    let alice_too = connect_as(addr, "key-a").await?;
+    let bob = connect_as(addr, "key-b").await?;
+
+    // A backfill for a pattern spans the topics it matches, in publish order
+    let mut everything = alice.subscribe_with_backfill::<Alert>("alice.>", 10);
+    for (no, topic) in [(1, "alice.disk.sda"), (2, "alice.cpu")] {
+        let delivery = everything.next().await.unwrap()?;
+        assert_eq!((delivery.message.no, delivery.topic.as_str()), (no, topic));
+    }
+    let mut shallow = alice_too.subscribe_with_backfill::<Alert>("alice.*", 10);
+    assert_eq!(shallow.next().await.unwrap()?.message, Alert { no: 2 });
+    assert_eq!(pubsub.publish("alice.net", &Alert { no: 5 })?, 2);
+    assert_eq!(shallow.next().await.unwrap()?.message, Alert { no: 5 });
+    assert_eq!(everything.next().await.unwrap()?.message, Alert { no: 5 });
+
+    // Patterns reaching past alice's topics are denied, even where they
+    // match only hers now
+    for (client, topic) in [
+        (&alice, ">"),
+        (&alice, "*.cpu"),
+        (&alice, "alice"),
+        (&bob, "alice.cpu"),
+    ] {
+        let mut denied = client.subscribe::<Alert>(topic);
+        assert!(matches!(
+            denied.next().await,
+            Some(Err(VstpError::Forbidden(_)))
+        ));
+        assert!(denied.next().await.is_none());
+    }
+    assert_eq!(pubsub.stats().denied_subscribes(), 4);
+
+    let mut malformed = alice.subscribe::<Alert>("alice.c*");
+    assert!(matches!(
+        malformed.next().await,
+        Some(Err(VstpError::ServerError(_)))
+    ));
+    assert!(alice.publish("alice.*", Alert { no: 6 }).await.is_err());
+    assert_eq!(pubsub.stats().denied_subscribes(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_publishes_are_checked_once_per_session() -> Result<(), VstpError> {
+    let checks = Arc::new(AtomicUsize::new(0));
+    let counted = checks.clone();
+    let (addr, pubsub) = tenant_server(PubSubConfig {
+        can_publish: Some(TopicCheck::new(move |identity, topic| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            topic.starts_with(&format!("{}.", identity))
+        })),
+        ..PubSubConfig::default()
+    })?;
+    let alice = connect_as(addr, "key-a").await?;
+
+    alice.publish("alice.cpu", Alert { no: 1 }).await?;
+    alice.publish("alice.cpu", Alert { no: 2 }).await?;
+    for _ in 0..2 {
+        match alice.publish("bob.cpu", Alert { no: 3 }).await {
+            Err(VstpError::Forbidden(reason)) => {
+                assert_eq!(reason, "alice may not publish to bob.cpu")
+            }
+            other => panic!("expected Forbidden, got {:?}", other),
+        }
+    }
+    assert_eq!(checks.load(Ordering::SeqCst), 2);
+    assert_eq!(pubsub.stats().denied_publishes(), 2);
+    assert_eq!(pubsub.stats().published(), 2);
+
+    // A new session starts with nothing cached
+    alice.reconnect().await?;
+    alice.publish("alice.cpu", Alert { no: 4 }).await?;
+    assert_eq!(checks.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscriptions_can_be_listed_and_ended() -> Result<(), VstpError> {
+    let (addr, pubsub) = tenant_server(PubSubConfig::default())?;
+    let alice = connect_as(addr, "key-a").await?;
+    let bob = connect_as(addr, "key-b").await?;
+
+    // One subscription at a time, each probed on a topic only it gets
+    let mut cpu = alice.subscribe::<Alert>("alice.cpu");
+    tokio::join!(cpu.next(), await_subscriptions(&pubsub, "alice.cpu", 1));
+    let mut everything = alice.subscribe::<Alert>("alice.>");
+    tokio::join!(
+        everything.next(),
+        await_subscriptions(&pubsub, "alice.disk", 1)
+    );
+    let mut bobs = bob.subscribe::<Alert>("bob.cpu");
+    tokio::join!(bobs.next(), await_subscriptions(&pubsub, "bob.cpu", 1));
+
+    let session = alice.session_id().expect("the WELCOME names the session");
+    let mut listed = pubsub.session_subscriptions(session);
+    assert_eq!(listed.len(), 2);
+    assert!(listed
+        .iter()
+        .all(|s| s.identity == "alice" && s.session == Some(session)));
+    listed.sort_by(|a, b| a.topic.cmp(&b.topic));
+    assert_eq!(listed[0].topic, "alice.>");
+    assert_eq!(listed[1].topic, "alice.cpu");
+    assert_eq!(pubsub.subscriptions().len(), 3);
+
+    assert!(pubsub.unsubscribe(listed[0].id));
+    assert!(!pubsub.unsubscribe(listed[0].id));
+    match everything.next().await {
+        Some(Err(VstpError::Forbidden(reason))) => assert_eq!(reason, "unsubscribed from alice.>"),
+        other => panic!("expected Forbidden, got {:?}", other),
+    }
+    assert!(everything.next().await.is_none());
+
+    // The session's other subscription carries on
+    assert_eq!(pubsub.publish("alice.cpu", &Alert { no: 1 })?, 1);
+    assert_eq!(cpu.next().await.unwrap()?.message, Alert { no: 1 });
+    assert_eq!(pubsub.subscriptions().len(), 2);
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Tests for ordered, exactly-once delivery with ReliableChannel
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vstp::{
+    testing::{udp_client, udp_roundtrip, LossConfig, LossyUdpProxy},
+    udp::channel::{CHANNEL_ID_HEADER, CHANNEL_SEQ_HEADER, PROCESSED_HEADER},
+    udp::{ChannelConfig, MemorySeqStore, ReliableChannel, ReliableReceiver, SeqStore, VstpUdpServer},
+    Frame, FrameType, VstpError,
+};
+
+type Applied = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Run a receiver that records every payload its handler sees
+async fn spawn_receiver(
+    addr: &str,
+    store: Arc<dyn SeqStore>,
+    applied: Applied,
+) -> (SocketAddr, JoinHandle<Result<(), VstpError>>) {
+    let server = VstpUdpServer::bind(addr).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = tokio::spawn(ReliableReceiver::new(server, store).run(move |_from, frame| {
+        let applied = applied.clone();
+        async move {
+            applied.lock().unwrap().push(frame.payload);
+        }
+    }));
+    (addr, handle)
+}
+
+fn command(n: u8) -> Frame {
+    Frame::new(FrameType::Data).with_payload(vec![n])
+}
+
+fn fast_config(channel_id: &str) -> ChannelConfig {
+    ChannelConfig {
+        retry_interval: Duration::from_millis(50),
+        ..ChannelConfig::new(channel_id)
+    }
+}
+
+#[tokio::test]
+async fn test_channel_delivers_in_order_exactly_once_over_lossy_link() {
+    let applied = Applied::default();
+    let (addr, receiver) =
+        spawn_receiver("127.0.0.1:0", Arc::new(MemorySeqStore::default()), applied.clone()).await;
+    let proxy = LossyUdpProxy::start(
+        addr,
+        LossConfig {
+            drop_rate: 0.3,
+            seed: 7,
+            ..LossConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let channel = ReliableChannel::new(udp_client().await.unwrap(), proxy.addr(), fast_config("lossy"));
+    let deliveries: Vec<_> = (0..20).map(|n| channel.send(command(n))).collect();
+    for delivery in deliveries {
+        delivery.await.unwrap();
+    }
+
+    let expected: Vec<Vec<u8>> = (0..20).map(|n| vec![n]).collect();
+    assert_eq!(*applied.lock().unwrap(), expected);
+    assert!(proxy.dropped() > 0);
+    receiver.abort();
+}
+
+#[tokio::test]
+async fn test_gap_holds_back_later_frames() {
+    let applied = Applied::default();
+    let (addr, receiver) =
+        spawn_receiver("127.0.0.1:0", Arc::new(MemorySeqStore::default()), applied.clone()).await;
+    let mut client = udp_client().await.unwrap();
+
+    let frame = |seq: u64| {
+        command(seq as u8)
+            .with_header(CHANNEL_ID_HEADER, "gap")
+            .with_header(CHANNEL_SEQ_HEADER, &seq.to_string())
+    };
+
+    let ack = udp_roundtrip(&mut client, addr, frame(2)).await.unwrap();
+    assert_eq!(ack.get_header(PROCESSED_HEADER), Some("0"));
+    assert!(applied.lock().unwrap().is_empty());
+
+    let ack = udp_roundtrip(&mut client, addr, frame(1)).await.unwrap();
+    assert_eq!(ack.get_header(PROCESSED_HEADER), Some("2"));
+    assert_eq!(*applied.lock().unwrap(), vec![vec![1], vec![2]]);
+    receiver.abort();
+}
+
+#[tokio::test]
+async fn test_duplicate_after_restart_is_not_reapplied() {
+    let store: Arc<dyn SeqStore> = Arc::new(MemorySeqStore::default());
+    let applied = Applied::default();
+    let (addr, receiver) = spawn_receiver("127.0.0.1:0", store.clone(), applied.clone()).await;
+
+    let channel = ReliableChannel::new(udp_client().await.unwrap(), addr, fast_config("device-1"));
+    for n in 0..3 {
+        channel.send(command(n)).await.unwrap();
+    }
+
+    // Restart the server on the same port, keeping the store
+    receiver.abort();
+    let _ = receiver.await;
+    let (_, receiver) = spawn_receiver(&addr.to_string(), store.clone(), applied.clone()).await;
+
+    // A retransmission of seq 2 that was in flight across the restart
+    let mut client = udp_client().await.unwrap();
+    let replay = command(1)
+        .with_header(CHANNEL_ID_HEADER, "device-1")
+        .with_header(CHANNEL_SEQ_HEADER, "2");
+    let ack = udp_roundtrip(&mut client, addr, replay).await.unwrap();
+    assert_eq!(ack.get_header(PROCESSED_HEADER), Some("3"));
+    assert_eq!(applied.lock().unwrap().len(), 3);
+
+    // The channel carries on where it left off
+    channel.send(command(3)).await.unwrap();
+    let expected: Vec<Vec<u8>> = (0..4).map(|n| vec![n]).collect();
+    assert_eq!(*applied.lock().unwrap(), expected);
+    assert_eq!(store.load("device-1").unwrap(), 4);
+    receiver.abort();
+}
+
+#[tokio::test]
+async fn test_channel_fails_when_peer_never_processes() {
+    // A socket that never answers
+    let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = ChannelConfig {
+        delivery_timeout: Duration::from_millis(300),
+        ..fast_config("silent")
+    };
+    let channel = ReliableChannel::new(udp_client().await.unwrap(), silent.local_addr().unwrap(), config);
+
+    assert!(matches!(channel.send(command(0)).await, Err(VstpError::Timeout)));
+    assert!(matches!(channel.send(command(1)).await, Err(VstpError::Timeout)));
+}
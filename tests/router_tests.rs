@@ -0,0 +1,1119 @@
+//! Tests for method routing and response caching
+
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::advance;
+use vstp::{
+    easy::{
+        current_deadline, current_frame_meta, current_negotiated_params, current_quota_remaining,
+        current_session_token, ConnectOptions,
+        ServerOptions, TransportKind, VstpClient, VstpServer, COALESCED_HEADER, ERROR_CODE_HEADER,
+        IDEMPOTENCY_KEY_HEADER,
+    },
+    encode_frame,
+    router::{
+        CacheConfig, CACHE_BUST_HEADER, CACHE_HEADER, CALL_ID_HEADER, METHOD_HEADER,
+        STREAM_END_HEADER,
+    },
+    schema::{SCHEMA_VERSION_HEADER, SERVED_SCHEMA_VERSION_HEADER},
+    tcp::SessionQuota,
+    types::error_codes,
+    udp::VstpUdpClient,
+    usage::{MemoryUsageRecorder, Quota},
+    ErrorCode, Frame, FrameType, Router, VstpError, VstpMessage,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Lookup {
+    sku: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Item {
+    sku: String,
+    calls: usize,
+}
+
+fn request(method: &str, sku: &str) -> Frame {
+    Frame::new(FrameType::Data)
+        .with_header(METHOD_HEADER, method)
+        .with_payload(
+            serde_json::to_vec(&Lookup {
+                sku: sku.to_string(),
+            })
+            .unwrap(),
+        )
+}
+
+fn item(reply: &Frame) -> Item {
+    assert_eq!(reply.typ, FrameType::Data);
+    serde_json::from_slice(reply.payload()).unwrap()
+}
+
+/// A router whose `catalog.get` is cached for a minute, busting allowed, and
+/// whose `cart.add` is not cached
+fn catalog_router(calls: Arc<AtomicUsize>) -> Router {
+    let cart_calls = calls.clone();
+    Router::new()
+        .route("catalog.get", move |lookup: Lookup| {
+            let calls = calls.clone();
+            async move {
+                let calls = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                if lookup.sku == "missing" {
+                    return Err(VstpError::Protocol("no such item".to_string()));
+                }
+                Ok(Item {
+                    sku: lookup.sku,
+                    calls,
+                })
+            }
+        })
+        .cached_with(CacheConfig {
+            allow_bust: true,
+            ..CacheConfig::new(Duration::from_secs(60))
+        })
+        .route("cart.add", move |lookup: Lookup| {
+            let calls = cart_calls.clone();
+            async move {
+                Ok(Item {
+                    sku: lookup.sku,
+                    calls: calls.fetch_add(1, Ordering::SeqCst) + 1,
+                })
+            }
+        })
+}
+
+#[tokio::test]
+async fn test_cache_hit_and_miss() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+
+    let first = router.handle(&request("catalog.get", "A1")).await;
+    assert_eq!(first.get_header(CACHE_HEADER), Some("miss"));
+    let second = router.handle(&request("catalog.get", "A1")).await;
+    assert_eq!(second.get_header(CACHE_HEADER), Some("hit"));
+    assert_eq!(item(&first), item(&second));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // A different payload is a different entry
+    let other = router.handle(&request("catalog.get", "B2")).await;
+    assert_eq!(other.get_header(CACHE_HEADER), Some("miss"));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cached_response_expires() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+
+    router.handle(&request("catalog.get", "A1")).await;
+    advance(Duration::from_secs(59)).await;
+    let reply = router.handle(&request("catalog.get", "A1")).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("hit"));
+
+    advance(Duration::from_secs(2)).await;
+    let reply = router.handle(&request("catalog.get", "A1")).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("miss"));
+    assert_eq!(item(&reply).calls, 2);
+}
+
+#[tokio::test]
+async fn test_routes_on_custom_header() {
+    #[derive(Serialize, Deserialize)]
+    struct Subscribe {
+        topic: String,
+    }
+    let router = Router::new()
+        .dispatch_on("type")
+        .route("subscribe", |sub: Subscribe| async move { Ok(vec![sub.topic]) })
+        .route("lookup", |lookup: Lookup| async move {
+            Ok(Item {
+                sku: lookup.sku,
+                calls: 0,
+            })
+        });
+
+    let subscribe = Frame::new(FrameType::Data)
+        .with_header("type", "subscribe")
+        .with_payload(br#"{"topic":"news"}"#.to_vec());
+    let reply = router.handle(&subscribe).await;
+    assert_eq!(reply.payload, br#"["news"]"#);
+
+    let lookup = request("ignored", "A1").with_header("type", "lookup");
+    assert_eq!(item(&router.handle(&lookup).await).sku, "A1");
+
+    // The method header alone no longer picks a route
+    let reply = router.handle(&request("lookup", "A1")).await;
+    assert_eq!(reply.error_code(), Some(ErrorCode::UnknownMethod));
+}
+
+#[tokio::test]
+async fn test_mutating_route_is_never_cached() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+
+    for expected in 1..=3 {
+        let reply = router.handle(&request("cart.add", "A1")).await;
+        assert_eq!(reply.get_header(CACHE_HEADER), None);
+        assert_eq!(item(&reply).calls, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_errors_are_not_cached() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+
+    for _ in 0..2 {
+        let reply = router.handle(&request("catalog.get", "missing")).await;
+        assert_eq!(reply.typ, FrameType::Err);
+        assert_eq!(
+            reply.get_header(ERROR_CODE_HEADER),
+            Some(error_codes::HANDLER_FAILED)
+        );
+        assert_eq!(reply.get_header(CACHE_HEADER), None);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    let reply = router.handle(&request("catalog.remove", "A1")).await;
+    assert_eq!(
+        reply.get_header(ERROR_CODE_HEADER),
+        Some(error_codes::UNKNOWN_METHOD)
+    );
+    assert_eq!(reply.error_code(), Some(ErrorCode::UnknownMethod));
+}
+
+#[tokio::test]
+async fn test_invalidate_and_cache_bust() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+
+    router.handle(&request("catalog.get", "A1")).await;
+    router.invalidate("catalog.get");
+    let reply = router.handle(&request("catalog.get", "A1")).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("miss"));
+    assert_eq!(item(&reply).calls, 2);
+
+    // Busting runs the handler and refreshes the entry for later requests
+    let busted = request("catalog.get", "A1").with_header(CACHE_BUST_HEADER, "1");
+    let reply = router.handle(&busted).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("miss"));
+    assert_eq!(item(&reply).calls, 3);
+    let reply = router.handle(&request("catalog.get", "A1")).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("hit"));
+    assert_eq!(item(&reply).calls, 3);
+}
+
+#[tokio::test]
+async fn test_cache_bust_is_ignored_unless_allowed() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let router = Router::new()
+        .route("catalog.get", move |lookup: Lookup| {
+            let calls = counted.clone();
+            async move {
+                Ok(Item {
+                    sku: lookup.sku,
+                    calls: calls.fetch_add(1, Ordering::SeqCst) + 1,
+                })
+            }
+        })
+        .cached(Duration::from_secs(60));
+
+    router.handle(&request("catalog.get", "A1")).await;
+    let busted = request("catalog.get", "A1").with_header(CACHE_BUST_HEADER, "1");
+    let reply = router.handle(&busted).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("hit"));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_requests_of_other_schema_versions_do_not_share_an_entry() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+
+    let v1 = request("catalog.get", "A1").with_header(SCHEMA_VERSION_HEADER, "1");
+    let v2 = request("catalog.get", "A1").with_header(SCHEMA_VERSION_HEADER, "2");
+    router.handle(&v1).await;
+    let reply = router.handle(&v2).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("miss"));
+    let reply = router.handle(&v1).await;
+    assert_eq!(reply.get_header(CACHE_HEADER), Some("hit"));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_serve_router_over_tcp() -> Result<(), VstpError> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let router = catalog_router(calls.clone());
+    let server = VstpServer::bind_tcp("127.0.0.1:8099").await?;
+    tokio::spawn(server.serve_router(router.clone()));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = VstpClient::connect_tcp("127.0.0.1:8099").await?;
+    let lookup = Lookup {
+        sku: "A1".to_string(),
+    };
+    let first: Item = client.call("catalog.get", lookup.clone()).await?;
+    let second: Item = client.call("catalog.get", lookup.clone()).await?;
+    assert_eq!(first, second);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Invalidating through a clone reaches the serving router's cache
+    router.invalidate("catalog.get");
+    let third: Item = client.call("catalog.get", lookup).await?;
+    assert_eq!(third.calls, 2);
+
+    let missing = client
+        .call::<_, Item>(
+            "catalog.get",
+            Lookup {
+                sku: "missing".to_string(),
+            },
+        )
+        .await;
+    assert!(matches!(missing, Err(VstpError::ServerError(_))));
+    Ok(())
+}
+
+/// A router answering `method` with an item whose sku names `service`
+fn service_router(service: &'static str, method: &str) -> Router {
+    Router::new().route(method, move |_: Lookup| async move {
+        Ok(Item {
+            sku: service.to_string(),
+            calls: 0,
+        })
+    })
+}
+
+fn service_options(service: &str, token: &str) -> ConnectOptions {
+    ConnectOptions {
+        service: Some(service.to_string()),
+        auth_token: Some(token.to_string()),
+        ..ConnectOptions::default()
+    }
+}
+
+fn lookup() -> Lookup {
+    Lookup {
+        sku: "A1".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_services_are_isolated() -> Result<(), VstpError> {
+    let server = VstpServer::bind_tcp("127.0.0.1:8105")
+        .await?
+        .service_with_options(
+            "billing",
+            service_router("billing", "invoice.total"),
+            ServerOptions {
+                auth_token: Some("billing-secret".to_string()),
+                ..ServerOptions::default()
+            },
+        )
+        .service_with_options(
+            "telemetry",
+            service_router("telemetry", "metrics.push"),
+            ServerOptions {
+                auth_token: Some("telemetry-secret".to_string()),
+                ..ServerOptions::default()
+            },
+        );
+    tokio::spawn(server.serve_services());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let billing = VstpClient::connect_tcp_with_options(
+        "127.0.0.1:8105",
+        service_options("billing", "billing-secret"),
+    )
+    .await?;
+    let reply: Item = billing.call("invoice.total", lookup()).await?;
+    assert_eq!(reply.sku, "billing");
+    // Telemetry's methods aren't reachable from a billing session
+    let other = billing.call::<_, Item>("metrics.push", lookup()).await;
+    assert!(matches!(other, Err(VstpError::ServerError(_))));
+
+    let telemetry = VstpClient::connect_tcp_with_options(
+        "127.0.0.1:8105",
+        service_options("telemetry", "telemetry-secret"),
+    )
+    .await?;
+    let reply: Item = telemetry.call("metrics.push", lookup()).await?;
+    assert_eq!(reply.sku, "telemetry");
+
+    // Billing's token doesn't open a telemetry session
+    let crossed = VstpClient::connect_tcp_with_options(
+        "127.0.0.1:8105",
+        service_options("telemetry", "billing-secret"),
+    )
+    .await;
+    match crossed {
+        Err(VstpError::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, error_codes::UNAUTHORIZED)
+        }
+        other => panic!("Expected HandshakeRejected, got {:?}", other.map(|_| ())),
+    }
+
+    // Without a default, unknown and missing service names are turned away
+    for unknown in [
+        VstpClient::connect_tcp_service("127.0.0.1:8105", "payroll").await,
+        VstpClient::connect_tcp("127.0.0.1:8105").await,
+    ] {
+        match unknown {
+            Err(err @ VstpError::HandshakeRejected { .. }) => {
+                assert!(!err.is_retryable());
+                if let VstpError::HandshakeRejected { code, .. } = err {
+                    assert_eq!(code, error_codes::SERVICE_NOT_FOUND);
+                }
+            }
+            other => panic!("Expected HandshakeRejected, got {:?}", other.map(|_| ())),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_service_uses_default_router() -> Result<(), VstpError> {
+    let server = VstpServer::bind_tcp("127.0.0.1:8106")
+        .await?
+        .service("billing", service_router("billing", "whoami"));
+    tokio::spawn(server.serve_router(service_router("default", "whoami")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    for (service, expected) in [
+        (Some("billing"), "billing"),
+        (Some("payroll"), "default"),
+        (None, "default"),
+    ] {
+        let client = match service {
+            Some(service) => VstpClient::connect_tcp_service("127.0.0.1:8106", service).await?,
+            None => VstpClient::connect_tcp("127.0.0.1:8106").await?,
+        };
+        let reply: Item = client.call("whoami", lookup()).await?;
+        assert_eq!(reply.sku, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_echo_headers_copied_onto_replies() -> Result<(), VstpError> {
+    let mut server = VstpServer::bind_tcp("127.0.0.1:8107").await?;
+    server.set_options(ServerOptions {
+        echo_headers: vec!["trace-id".to_string(), "tenant-id".to_string()],
+        ..ServerOptions::default()
+    });
+    tokio::spawn(server.serve_router(service_router("catalog", "catalog.get")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = VstpClient::connect_tcp("127.0.0.1:8107").await?;
+    let traced = request("catalog.get", "A1")
+        .with_header("trace-id", "t-42")
+        .with_header("tenant-id", "acme")
+        .with_header("session-note", "private");
+    client.send_raw(traced).await?;
+    let reply = client.receive_raw().await?;
+    assert_eq!(item(&reply).sku, "catalog");
+    assert_eq!(reply.get_header("trace-id"), Some("t-42"));
+    assert_eq!(reply.get_header("tenant-id"), Some("acme"));
+    assert_eq!(reply.get_header("session-note"), None);
+
+    // ERR replies carry them too; headers the request lacks are not invented
+    let unknown = request("catalog.list", "A1").with_header("trace-id", "t-43");
+    client.send_raw(unknown).await?;
+    let reply = client.receive_raw().await?;
+    assert_eq!(reply.error_code(), Some(ErrorCode::UnknownMethod));
+    assert_eq!(reply.get_header("trace-id"), Some("t-43"));
+    assert_eq!(reply.get_header("tenant-id"), None);
+    Ok(())
+}
+
+async fn connect_as(addr: &str, api_key: &str) -> Result<VstpClient, VstpError> {
+    let options = ConnectOptions {
+        auth_token: Some(api_key.to_string()),
+        ..ConnectOptions::default()
+    };
+    VstpClient::connect_tcp_with_options(addr, options).await
+}
+
+#[tokio::test]
+async fn test_usage_is_recorded_and_quota_enforced_per_identity() -> Result<(), VstpError> {
+    let recorder = Arc::new(MemoryUsageRecorder::new());
+    let mut server = VstpServer::bind_tcp("127.0.0.1:8108").await?;
+    server.set_options(ServerOptions {
+        api_keys: [("key-a", "alice"), ("key-b", "bob")]
+            .into_iter()
+            .map(|(key, identity)| (key.to_string(), identity.to_string()))
+            .collect(),
+        usage: Some(recorder.clone()),
+        quota: Some(Quota::daily(Some(3), None)),
+        ..ServerOptions::default()
+    });
+    tokio::spawn(server.serve_router(service_router("catalog", "catalog.get")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let alice = connect_as("127.0.0.1:8108", "key-a").await?;
+    let bob = connect_as("127.0.0.1:8108", "key-b").await?;
+    for _ in 0..3 {
+        let _: Item = alice.call("catalog.get", lookup()).await?;
+    }
+    let _: Item = bob.call("catalog.get", lookup()).await?;
+
+    // Alice's fourth request is refused without reaching the handler; Bob is unaffected
+    match alice.call::<_, Item>("catalog.get", lookup()).await {
+        Err(VstpError::ServerError(msg)) => {
+            assert!(msg.starts_with(error_codes::QUOTA_EXCEEDED), "{}", msg)
+        }
+        other => panic!("expected QuotaExceeded, got {:?}", other),
+    }
+    let _: Item = bob.call("catalog.get", lookup()).await?;
+
+    let request_bytes = serde_json::to_vec(&lookup()).unwrap().len() as u64;
+    let reply_bytes = serde_json::to_vec(&Item {
+        sku: "catalog".to_string(),
+        calls: 0,
+    })
+    .unwrap()
+    .len() as u64;
+    let rows = recorder.snapshot();
+    assert_eq!(rows.len(), 2);
+    for (row, identity, requests) in [(&rows[0], "alice", 3), (&rows[1], "bob", 2)] {
+        assert_eq!(row.identity, identity);
+        assert_eq!(row.method, "catalog.get");
+        assert_eq!(row.requests, requests);
+        assert_eq!(row.bytes_in, requests * request_bytes);
+        assert_eq!(row.bytes_out, requests * reply_bytes);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handlers_see_frame_meta() -> Result<(), VstpError> {
+    assert!(current_frame_meta().is_none());
+    let mut server = VstpServer::bind_tcp("127.0.0.1:8109").await?;
+    server.set_options(ServerOptions {
+        keep_raw_bytes: true,
+        ..ServerOptions::default()
+    });
+    let router = Router::new().route("meta.get", |_: Lookup| async move {
+        let meta = current_frame_meta().expect("set for handlers");
+        assert_eq!(meta.transport, TransportKind::Tcp);
+        assert!(meta.queue_delay() < Duration::from_secs(5));
+        let raw = meta.raw_bytes.as_ref().expect("kept when asked for");
+        assert_eq!(raw.len(), meta.wire_len);
+        assert_eq!(raw[2], vstp::VSTP_VERSION_2, "sent after the handshake");
+        Ok(Item {
+            sku: meta.wire_len.to_string(),
+            calls: 0,
+        })
+    });
+    tokio::spawn(server.serve_router(router));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = VstpClient::connect_tcp("127.0.0.1:8109").await?;
+    let frame = request("meta.get", "A1");
+    let encoded_len = encode_frame(&frame)?.len();
+    client.send_raw(frame).await?;
+    // The client adds its own headers on the way out
+    let wire_len: usize = item(&client.receive_raw().await?).sku.parse().unwrap();
+    assert!(wire_len >= encoded_len, "{} < {}", wire_len, encoded_len);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spawned_work_is_cancelled_when_the_client_disconnects() -> Result<(), VstpError> {
+    assert!(current_session_token().is_none());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let (cancelled_tx, cancelled_rx) = tokio::sync::oneshot::channel();
+    let cancelled_tx = Arc::new(std::sync::Mutex::new(Some(cancelled_tx)));
+    let router = Router::new().route("report.start", move |lookup: Lookup| {
+        let cancelled_tx = cancelled_tx.clone();
+        async move {
+            let closed = current_session_token().expect("set for handlers");
+            // Long work left running after the reply
+            tokio::spawn(async move {
+                let finished = closed
+                    .run_until_cancelled(tokio::time::sleep(Duration::from_secs(60)))
+                    .await;
+                if let Some(tx) = cancelled_tx.lock().unwrap().take() {
+                    let _ = tx.send(finished.is_none());
+                }
+            });
+            Ok(Item {
+                sku: lookup.sku,
+                calls: 0,
+            })
+        }
+    });
+    tokio::spawn(server.serve_router(router));
+
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+    let _: Item = client.call("report.start", lookup()).await?;
+    drop(client);
+    let cancelled = tokio::time::timeout(Duration::from_secs(5), cancelled_rx)
+        .await
+        .expect("cancelled once the client is gone")
+        .unwrap();
+    assert!(cancelled);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_route_timeout_replaces_the_server_handler_timeout() -> Result<(), VstpError> {
+    assert!(current_deadline().is_none());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    server.set_options(ServerOptions {
+        handler_timeout: Some(Duration::from_millis(200)),
+        ..ServerOptions::default()
+    });
+    let stats = server.stats();
+    let router = Router::new()
+        .route("report.quick", |lookup: Lookup| async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(Item {
+                sku: lookup.sku,
+                calls: 0,
+            })
+        })
+        .timeout(Duration::from_millis(20))
+        .route("report.full", |lookup: Lookup| async move {
+            let deadline = current_deadline().expect("set for calls with a time limit");
+            let left = deadline - tokio::time::Instant::now();
+            assert!(left > Duration::from_secs(4), "{:?} left", left);
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            Ok(Item {
+                sku: lookup.sku,
+                calls: 0,
+            })
+        })
+        .timeout(Duration::from_secs(5));
+    tokio::spawn(server.serve_router(router));
+
+    let client = VstpClient::connect_tcp(addr.to_string()).await?;
+    let error = client
+        .call::<_, Item>("report.quick", lookup())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&error, VstpError::ServerError(message) if message.starts_with("DeadlineExceeded")),
+        "{:?}",
+        error
+    );
+    assert_eq!(stats.timed_out_handlers(), 1);
+
+    let item: Item = client.call("report.full", lookup()).await?;
+    assert_eq!(item.sku, lookup().sku);
+    assert_eq!(stats.timed_out_handlers(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_deadline_without_a_time_limit() -> Result<(), VstpError> {
+    let router = Router::new().route("report.start", |lookup: Lookup| async move {
+        assert!(current_deadline().is_none());
+        Ok(Item {
+            sku: lookup.sku,
+            calls: 0,
+        })
+    });
+    let client = users_client(router).await?;
+    let _: Item = client.call("report.start", lookup()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_sessions_are_dropped_when_idle_or_over_the_limit() -> Result<(), VstpError> {
+    let addr = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = VstpServer::bind_udp(addr.to_string()).await?;
+    server.set_options(ServerOptions {
+        udp_session_idle: Duration::from_millis(300),
+        max_udp_sessions: 2,
+        ..ServerOptions::default()
+    });
+    // Each caller's session token, by the sku it sent
+    let tokens = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let seen = tokens.clone();
+    let router = Router::new().route("session.watch", move |lookup: Lookup| {
+        let seen = seen.clone();
+        async move {
+            let closed = current_session_token().expect("set for handlers");
+            seen.lock().unwrap().insert(lookup.sku.clone(), closed);
+            Ok(Item {
+                sku: lookup.sku,
+                calls: 0,
+            })
+        }
+    });
+    tokio::spawn(server.serve_router(router));
+
+    let watch = |client: &VstpClient, sku: &str| {
+        let lookup = Lookup {
+            sku: sku.to_string(),
+        };
+        let client = client.clone();
+        async move { client.call::<_, Item>("session.watch", lookup).await }
+    };
+    let cancelled = |sku: &str| tokens.lock().unwrap()[sku].is_cancelled();
+
+    let a = VstpClient::connect_udp(addr.to_string()).await?;
+    watch(&a, "a").await?;
+    let b = VstpClient::connect_udp(addr.to_string()).await?;
+    watch(&b, "b").await?;
+    // A third peer pushes out the session heard from least recently
+    let c = VstpClient::connect_udp(addr.to_string()).await?;
+    watch(&c, "c").await?;
+    assert!(cancelled("a"));
+    assert!(!cancelled("b") && !cancelled("c"));
+
+    // Sessions left alone past the idle time are dropped too
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    watch(&a, "a again").await?;
+    assert!(cancelled("b") && cancelled("c"));
+    assert!(!cancelled("a again"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handlers_see_negotiated_params() -> Result<(), VstpError> {
+    assert!(current_negotiated_params().is_none());
+    let mut server = VstpServer::bind_tcp("127.0.0.1:8110").await?;
+    server.set_options(ServerOptions {
+        max_message_bytes: Some(4096),
+        ..ServerOptions::default()
+    });
+    let router = Router::new().route("params.get", |_: Lookup| async move {
+        let params = current_negotiated_params().expect("set for handlers");
+        Ok(Item {
+            sku: format!(
+                "v{} {} {:?} {}",
+                params.version,
+                params.format.as_deref().unwrap_or("-"),
+                params.max_message_bytes,
+                params.compression
+            ),
+            calls: 0,
+        })
+    });
+    tokio::spawn(server.serve_router(router));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let options = ConnectOptions {
+        format: Some("application/msgpack".to_string()),
+        ..ConnectOptions::default()
+    };
+    let client = VstpClient::connect_tcp_with_options("127.0.0.1:8110", options).await?;
+    let item: Item = client.call("params.get", lookup()).await?;
+    assert_eq!(item.sku, "v2 application/msgpack Some(4096) false");
+
+    // Without a format the session falls back to the server's default
+    let client = VstpClient::connect_tcp_with_options(
+        "127.0.0.1:8110",
+        ConnectOptions {
+            max_frame_version: vstp::VSTP_VERSION,
+            ..ConnectOptions::default()
+        },
+    )
+    .await?;
+    let item: Item = client.call("params.get", lookup()).await?;
+    assert_eq!(item.sku, "v1 - Some(4096) false");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_single_flight_coalesces_requests_with_one_key() -> Result<(), VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut server = VstpServer::from_tcp_listener(listener)?;
+    server.set_options(ServerOptions {
+        single_flight: true,
+        echo_headers: vec!["trace-id".to_string()],
+        ..ServerOptions::default()
+    });
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler_calls = calls.clone();
+    let router = Router::new().route("orders.place", move |lookup: Lookup| {
+        let calls = handler_calls.clone();
+        async move {
+            let calls = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok(Item {
+                sku: lookup.sku,
+                calls,
+            })
+        }
+    });
+    tokio::spawn(server.serve_router(router));
+
+    let place = |key: &'static str, trace: usize| {
+        let addr = addr.clone();
+        async move {
+            let client = VstpClient::connect_tcp(&addr).await?;
+            let order = request("orders.place", "A1")
+                .with_header(IDEMPOTENCY_KEY_HEADER, key)
+                .with_header("trace-id", &trace.to_string());
+            client.send_raw(order).await?;
+            client.receive_raw().await
+        }
+    };
+    let replies =
+        futures::future::try_join_all((0..5).map(|trace| place("order-1", trace))).await?;
+
+    // One handler call, its reply fanned out to every client
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    for (trace, reply) in replies.iter().enumerate() {
+        assert_eq!(item(reply).calls, 1);
+        assert_eq!(
+            reply.get_header("trace-id"),
+            Some(trace.to_string().as_str())
+        );
+    }
+    let coalesced = replies
+        .iter()
+        .filter(|reply| reply.get_header(COALESCED_HEADER) == Some("true"))
+        .count();
+    assert_eq!(coalesced, 4);
+
+    // Nothing is cached once the reply is out, and other keys run on their own
+    let again = place("order-1", 0).await?;
+    assert_eq!(item(&again).calls, 2);
+    assert_eq!(again.get_header(COALESCED_HEADER), None);
+    let other = place("order-2", 0).await?;
+    assert_eq!(item(&other).calls, 3);
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GetUserV1 {
+    id: u64,
+}
+
+impl VstpMessage for GetUserV1 {
+    const METHOD: &'static str = "users.get";
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GetUserV2 {
+    user_id: u64,
+    include_email: bool,
+}
+
+impl VstpMessage for GetUserV2 {
+    const METHOD: &'static str = "users.get";
+    const SCHEMA_VERSION: u32 = 2;
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct User {
+    id: u64,
+}
+
+async fn users_client(router: Router) -> Result<VstpClient, VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    tokio::spawn(server.serve_router(router));
+    VstpClient::connect_tcp(addr.to_string()).await
+}
+
+fn users_v1() -> Router {
+    Router::new().route_typed(|req: GetUserV1| async move { Ok(User { id: req.id }) })
+}
+
+#[tokio::test]
+async fn test_typed_call_to_route_of_another_schema_version_fails() -> Result<(), VstpError> {
+    let client = users_client(users_v1()).await?;
+
+    let user: User = client.call_typed(GetUserV1 { id: 7 }).await?;
+    assert_eq!(user, User { id: 7 });
+
+    let v2 = GetUserV2 {
+        user_id: 7,
+        include_email: true,
+    };
+    let error = client.call_typed::<_, User>(v2).await.unwrap_err();
+    assert!(
+        matches!(
+            &error,
+            VstpError::SchemaMismatch { method, sent: 2, served: 1 } if method == "users.get"
+        ),
+        "{:?}",
+        error
+    );
+    assert!(!error.is_retryable());
+
+    // Untyped requests skip the check
+    let user: User = client
+        .call("users.get", serde_json::json!({ "id": 8 }))
+        .await?;
+    assert_eq!(user, User { id: 8 });
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compat_shim_serves_another_schema_version() -> Result<(), VstpError> {
+    let router = users_v1().compat(|req: GetUserV2| GetUserV1 { id: req.user_id });
+    let client = users_client(router.clone()).await?;
+
+    let v2 = GetUserV2 {
+        user_id: 9,
+        include_email: false,
+    };
+    let user: User = client.call_typed(v2).await?;
+    assert_eq!(user, User { id: 9 });
+
+    // Versions without a shim are still turned away
+    let v3 = Frame::new(FrameType::Data)
+        .with_header(METHOD_HEADER, "users.get")
+        .with_header(SCHEMA_VERSION_HEADER, "3")
+        .with_payload(br#"{"id":1}"#.to_vec());
+    let reply = router.handle(&v3).await;
+    assert_eq!(reply.error_code(), Some(ErrorCode::SchemaMismatch));
+    assert_eq!(reply.get_header(SERVED_SCHEMA_VERSION_HEADER), Some("1"));
+    Ok(())
+}
+
+/// A router whose `log.tail` streams `count` lines for the requested sku,
+/// failing instead of sending the one at `fail_at`
+fn log_router(count: usize, fail_at: Option<usize>) -> Router {
+    Router::new()
+        .stream_route("log.tail", move |lookup: Lookup| {
+            stream::iter((0..count).map(move |calls| match fail_at {
+                Some(at) if at == calls => Err(VstpError::Protocol("log rotated".to_string())),
+                _ => Ok(Item {
+                    sku: lookup.sku.clone(),
+                    calls,
+                }),
+            }))
+        })
+        .route("catalog.get", |lookup: Lookup| async move {
+            Ok(Item {
+                sku: lookup.sku,
+                calls: 0,
+            })
+        })
+}
+
+#[tokio::test]
+async fn test_server_stream_yields_every_response_then_ends() -> Result<(), VstpError> {
+    let client = users_client(log_router(5, None)).await?;
+    let lines: Vec<Item> = client
+        .call_server_stream("log.tail", lookup())
+        .map(|line| line.unwrap())
+        .collect()
+        .await;
+    assert_eq!(
+        lines.iter().map(|l| l.calls).collect::<Vec<_>>(),
+        [0, 1, 2, 3, 4]
+    );
+    assert!(lines.iter().all(|l| l.sku == lookup().sku));
+
+    // The connection is back to one reply per request
+    let item: Item = client.call("catalog.get", lookup()).await?;
+    assert_eq!(item.calls, 0);
+
+    // Unary calls to a streaming route are refused
+    let unary = client.call::<_, Item>("log.tail", lookup()).await;
+    assert!(matches!(unary, Err(VstpError::ServerError(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_stream_ends_with_the_handler_error() -> Result<(), VstpError> {
+    let client = users_client(log_router(5, Some(2))).await?;
+    let lines: Vec<Result<Item, VstpError>> = client
+        .call_server_stream("log.tail", lookup())
+        .collect()
+        .await;
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[1].as_ref().unwrap().calls, 1);
+    assert!(matches!(&lines[2], Err(VstpError::ServerError(m)) if m.contains("log rotated")));
+
+    // Methods without a streaming route answer once, which ends the stream
+    let lines: Vec<Result<Item, VstpError>> = client
+        .call_server_stream("catalog.get", lookup())
+        .collect()
+        .await;
+    assert_eq!(lines.len(), 1);
+    let lines: Vec<Result<Item, VstpError>> = client
+        .call_server_stream("log.missing", lookup())
+        .collect()
+        .await;
+    assert!(matches!(lines[..], [Err(VstpError::ServerError(_))]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dropped_server_stream_does_not_leak_into_later_calls() -> Result<(), VstpError> {
+    let client = users_client(log_router(50, None)).await?;
+    let first: Vec<Item> = client
+        .call_server_stream("log.tail", lookup())
+        .take(2)
+        .map(|line| line.unwrap())
+        .collect()
+        .await;
+    assert_eq!(first.len(), 2);
+
+    for _ in 0..3 {
+        let item: Item = client.call("catalog.get", lookup()).await?;
+        assert_eq!(item.calls, 0);
+    }
+    Ok(())
+}
+
+/// Sets its flag once dropped, to tell when a handler's stream is gone
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A router whose `log.follow` streams lines until the call is cancelled,
+/// setting `dropped` once its stream is gone
+fn follow_router(dropped: Arc<AtomicBool>) -> Router {
+    log_router(0, None).stream_route("log.follow", move |lookup: Lookup| {
+        let flag = DropFlag(dropped.clone());
+        stream::iter(0..).then(move |calls| {
+            let _alive = &flag;
+            let sku = lookup.sku.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                Ok(Item { sku, calls })
+            }
+        })
+    })
+}
+
+#[tokio::test]
+async fn test_dropping_an_endless_stream_cancels_it() -> Result<(), VstpError> {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let client = users_client(follow_router(dropped.clone())).await?;
+    let first: Vec<Item> = client
+        .call_server_stream("log.follow", lookup())
+        .take(3)
+        .map(|line| line.unwrap())
+        .collect()
+        .await;
+    assert_eq!(first.len(), 3);
+
+    let item: Item = client.call("catalog.get", lookup()).await?;
+    assert_eq!(item.calls, 0);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !dropped.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the server should drop the cancelled stream");
+
+    // Nothing of the cancelled call reaches later requests
+    for _ in 0..3 {
+        let item: Item = client.call("catalog.get", lookup()).await?;
+        assert_eq!(item.calls, 0);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_endless_udp_stream_does_not_hold_up_other_peers() -> Result<(), VstpError> {
+    let addr = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let server = VstpServer::bind_udp(addr.to_string()).await?;
+    tokio::spawn(server.serve_router(follow_router(Arc::default())));
+
+    let follower = VstpClient::connect_udp(addr.to_string()).await?;
+    let mut lines = follower.call_server_stream::<_, Item>("log.follow", lookup());
+    assert_eq!(lines.next().await.unwrap()?.calls, 0);
+
+    // The stream is still going while another peer is answered
+    let other = VstpClient::connect_udp(addr.to_string()).await?;
+    let item: Item = other.call("catalog.get", lookup()).await?;
+    assert_eq!(item.calls, 0);
+    assert_eq!(lines.next().await.unwrap()?.calls, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_frames_carry_the_call_id() {
+    let router = log_router(2, None);
+    assert!(router
+        .handle_stream(&request("catalog.get", "A1"))
+        .is_none());
+    assert!(router.handle_stream(&request("log.tail", "A1")).is_none());
+    let tail = request("log.tail", "A1").with_header(CALL_ID_HEADER, "c1");
+    let frames: Vec<Frame> = router.handle_stream(&tail).unwrap().collect().await;
+    assert_eq!(frames.len(), 3);
+    assert!(frames
+        .iter()
+        .all(|f| f.get_header(CALL_ID_HEADER) == Some("c1")));
+    assert_eq!(item(&frames[1]).calls, 1);
+    assert_eq!(frames[2].get_header(STREAM_END_HEADER), Some("true"));
+    assert!(frames[2].payload().is_empty());
+}
+
+#[tokio::test]
+async fn test_udp_sessions_are_closed_over_their_quota() -> Result<(), VstpError> {
+    let addr = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = VstpServer::bind_udp(addr.to_string()).await?;
+    server.set_options(ServerOptions {
+        udp_session_quota: Some(SessionQuota {
+            max_frames: Some(3),
+            ..SessionQuota::default()
+        }),
+        ..ServerOptions::default()
+    });
+    let stats = server.stats();
+    let router = Router::new().route("quota.get", |lookup: Lookup| async move {
+        let remaining = current_quota_remaining().expect("set with a quota");
+        Ok(Item {
+            sku: lookup.sku,
+            calls: remaining.frames.unwrap() as usize,
+        })
+    });
+    tokio::spawn(server.serve_router(router));
+
+    let mut greedy = VstpUdpClient::bind("127.0.0.1:0").await?;
+    greedy.start_receiver(16);
+    let mut modest = VstpUdpClient::bind("127.0.0.1:0").await?;
+    modest.start_receiver(16);
+    let wait = Duration::from_secs(2);
+    for client in [&greedy, &modest] {
+        client.send(Frame::new(FrameType::Hello), addr).await?;
+    }
+    for client in [&mut greedy, &mut modest] {
+        assert_eq!(client.recv_timeout(wait).await?.0.typ, FrameType::Welcome);
+    }
+
+    // The HELLO is the first of the three frames
+    for left in [1, 0] {
+        greedy.send(request("quota.get", "A1"), addr).await?;
+        assert_eq!(item(&greedy.recv_timeout(wait).await?.0).calls, left);
+    }
+    greedy.send(request("quota.get", "A1"), addr).await?;
+    let err = greedy.recv_timeout(wait).await?.0;
+    assert_eq!(err.typ, FrameType::Err);
+    assert_eq!(err.error_code(), Some(ErrorCode::QuotaExceeded));
+    assert_eq!(greedy.recv_timeout(wait).await?.0.typ, FrameType::Bye);
+    assert_eq!(stats.quota_closed_udp_sessions(), 1);
+
+    // The other peer's session still has the rest of its own
+    modest.send(request("quota.get", "B2"), addr).await?;
+    assert_eq!(item(&modest.recv_timeout(wait).await?.0).calls, 1);
+
+    // A new HELLO starts the closed session's quota afresh
+    greedy.send(Frame::new(FrameType::Hello), addr).await?;
+    assert_eq!(greedy.recv_timeout(wait).await?.0.typ, FrameType::Welcome);
+    greedy.send(request("quota.get", "A1"), addr).await?;
+    assert_eq!(item(&greedy.recv_timeout(wait).await?.0).calls, 1);
+    assert_eq!(stats.quota_closed_udp_sessions(), 1);
+    Ok(())
+}
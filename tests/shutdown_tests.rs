@@ -0,0 +1,87 @@
+//! Tests for shutting down a client with requests in flight
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vstp::{
+    easy::{VstpClient, VstpServer},
+    testing::{FrameTap, TapDirection},
+    FrameType, Router, VstpError,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Nap {
+    ms: u64,
+}
+
+/// A server whose `nap` method takes as long as it's told to
+async fn napping_server() -> Result<FrameTap, VstpError> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let router = Router::new().route("nap", |nap: Nap| async move {
+        tokio::time::sleep(Duration::from_millis(nap.ms)).await;
+        Ok(nap)
+    });
+    tokio::spawn(server.serve_router(router));
+    FrameTap::start(addr).await
+}
+
+async fn byes_sent(tap: &FrameTap) -> usize {
+    // The BYE may still be on its way through the tap
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    tap.frames()
+        .iter()
+        .filter(|(dir, f)| *dir == TapDirection::ToServer && f.typ == FrameType::Bye)
+        .count()
+}
+
+#[tokio::test]
+async fn test_shutdown_lets_requests_within_grace_finish() -> Result<(), VstpError> {
+    let tap = napping_server().await?;
+    let client = VstpClient::connect_tcp(tap.addr().to_string()).await?;
+
+    let caller = client.clone();
+    let request = tokio::spawn(async move { caller.call::<_, Nap>("nap", Nap { ms: 200 }).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client.shutdown(Duration::from_secs(5)).await?;
+
+    assert_eq!(request.await.unwrap()?, Nap { ms: 200 });
+    assert!(matches!(
+        client.send(Nap { ms: 0 }).await,
+        Err(VstpError::Closed)
+    ));
+    assert_eq!(byes_sent(&tap).await, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_fails_requests_past_grace_as_closed() -> Result<(), VstpError> {
+    let tap = napping_server().await?;
+    let client = VstpClient::connect_tcp(tap.addr().to_string()).await?;
+
+    let caller = client.clone();
+    let request =
+        tokio::spawn(async move { caller.call::<_, Nap>("nap", Nap { ms: 5_000 }).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client.shutdown(Duration::from_millis(100)).await?;
+
+    let error = request.await.unwrap().unwrap_err();
+    assert!(matches!(error, VstpError::Closed));
+    assert!(!error.is_retryable());
+    assert_eq!(byes_sent(&tap).await, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dropping_the_last_handle_says_bye() -> Result<(), VstpError> {
+    let tap = napping_server().await?;
+    let client = VstpClient::connect_tcp(tap.addr().to_string()).await?;
+    let clone = client.clone();
+
+    drop(client);
+    assert_eq!(byes_sent(&tap).await, 0);
+    drop(clone);
+    assert_eq!(byes_sent(&tap).await, 1);
+    Ok(())
+}
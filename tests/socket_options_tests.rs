@@ -0,0 +1,190 @@
+//! Tests for socket options on server binds and adopting pre-bound sockets
+
+use std::time::Duration;
+use vstp::socket::SocketOptions;
+use vstp::{
+    easy::{VstpClient, VstpServer},
+    tcp::{TcpServerConfig, VstpTcpClient, VstpTcpServer},
+    udp::{UdpServerConfig, VstpUdpClient, VstpUdpServer},
+    Frame, FrameType, VstpError,
+};
+
+/// Accept one connection and close it from the server side, leaving the
+/// server's end of the connection in TIME_WAIT
+async fn close_after_accept(server: VstpTcpServer) {
+    let addr = server.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+        // Wait for the server to close first
+        let _ = client.recv().await;
+    });
+    let conn = server.accept().await.unwrap();
+    drop(conn);
+    drop(server);
+    client.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn test_tcp_fast_restart_with_reuseaddr() {
+    let server = VstpTcpServer::bind_reuseaddr("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    close_after_accept(server).await;
+
+    let restarted = VstpTcpServer::bind_reuseaddr(addr).await.unwrap();
+    assert_eq!(restarted.local_addr().unwrap(), addr);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_restart_without_reuseaddr_fails_in_time_wait() {
+    let config = TcpServerConfig {
+        socket: SocketOptions::default(),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config.clone())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    close_after_accept(server).await;
+
+    match VstpTcpServer::bind_with_config(addr, config).await {
+        Err(VstpError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("rebinding a port in TIME_WAIT should fail without SO_REUSEADDR"),
+    }
+}
+
+#[tokio::test]
+async fn test_udp_bind_reuseaddr() {
+    let server = VstpUdpServer::bind_reuseaddr("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    drop(server);
+
+    let restarted = VstpUdpServer::bind_reuseaddr(&addr.to_string())
+        .await
+        .unwrap();
+    assert_eq!(restarted.local_addr().unwrap(), addr);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_udp_reuse_port_allows_shared_bind() {
+    let config = UdpServerConfig {
+        socket: SocketOptions {
+            reuse_addr: true,
+            reuse_port: true,
+            ..SocketOptions::default()
+        },
+        ..UdpServerConfig::default()
+    };
+    let first = VstpUdpServer::bind_with_config("127.0.0.1:0", config.clone())
+        .await
+        .unwrap();
+    let addr = first.local_addr().unwrap().to_string();
+
+    let second = VstpUdpServer::bind_with_config(&addr, config).await.unwrap();
+    assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+
+    // Without the options the port is taken
+    assert!(VstpUdpServer::bind(&addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tcp_server_from_std_listener() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = TcpServerConfig {
+        max_frame_size: 1024,
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::from_std_listener_with_config(listener, config).unwrap();
+    assert_eq!(server.local_addr().unwrap(), addr);
+
+    let client_handle = tokio::spawn(async move {
+        let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+        client.send_data(b"small".to_vec()).await.unwrap();
+        client.send_data(vec![0u8; 4096]).await.unwrap();
+        client
+    });
+
+    let mut conn = server.accept().await.unwrap();
+    let frame = conn.recv().await.unwrap().unwrap();
+    assert_eq!(frame.payload, b"small");
+    // The configured frame limit applies to the adopted listener
+    assert!(conn.recv().await.is_err());
+
+    client_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_tcp_server_into_std_roundtrip() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let listener = server.into_std().unwrap();
+    let server = VstpTcpServer::from_std_listener(listener).unwrap();
+    assert_eq!(server.local_addr().unwrap(), addr);
+
+    let client = tokio::spawn(async move { VstpTcpClient::connect(&addr.to_string()).await });
+    server.accept().await.unwrap();
+    client.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_udp_server_from_std_socket() {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let server = VstpUdpServer::from_std_socket(socket).unwrap();
+    assert_eq!(server.local_addr().unwrap(), addr);
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let frame = Frame::new(FrameType::Data).with_payload(b"inherited".to_vec());
+    client.send(frame, addr).await.unwrap();
+
+    let (received, _) = tokio::time::timeout(Duration::from_secs(2), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.payload, b"inherited");
+
+    let socket = server.into_std().unwrap();
+    assert_eq!(socket.local_addr().unwrap(), addr);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_servers_expose_raw_fd() {
+    use std::os::unix::io::AsRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let fd = listener.as_raw_fd();
+    let server = VstpTcpServer::from_std_listener(listener).unwrap();
+    assert_eq!(server.as_raw_fd(), fd);
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let fd = socket.as_raw_fd();
+    let server = VstpUdpServer::from_std_socket(socket).unwrap();
+    assert_eq!(server.as_raw_fd(), fd);
+}
+
+#[tokio::test]
+async fn test_easy_server_from_std_sockets() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let tcp_addr = listener.local_addr().unwrap();
+    let server = VstpServer::from_tcp_listener(listener).unwrap();
+    tokio::spawn(server.serve(|msg: String| async move { Ok(format!("tcp:{msg}")) }));
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let udp_addr = socket.local_addr().unwrap();
+    let server = VstpServer::from_udp_socket(socket).unwrap();
+    tokio::spawn(server.serve(|msg: String| async move { Ok(format!("udp:{msg}")) }));
+
+    let client = VstpClient::connect_tcp(tcp_addr.to_string()).await.unwrap();
+    client.send("hi".to_string()).await.unwrap();
+    assert_eq!(client.receive::<String>().await.unwrap(), "tcp:hi");
+
+    let client = VstpClient::connect_udp(udp_addr.to_string()).await.unwrap();
+    client.send("hi".to_string()).await.unwrap();
+    assert_eq!(client.receive::<String>().await.unwrap(), "udp:hi");
+}
@@ -1,8 +1,24 @@
-use std::time::Duration;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::timeout;
 use vstp::{
-    tcp::{VstpTcpClient, VstpTcpServer},
-    types::{Frame, FrameType, SessionId},
+    encode_frame,
+    header_table::HeaderTableStats,
+    ingress::IngressPolicy,
+    tcp::{
+        ReconnectConfig, ReconnectingStream, SessionQuota, StreamEvent, TcpServerConfig,
+        VstpTcpClient, VstpTcpServer,
+    },
+    types::{
+        DisconnectReason, ErrorCode, Flags, Frame, FrameType, Header, HeaderRatioLimit, SessionId,
+        VstpError, VSTP_VERSION_2,
+    },
+    easy::TransportKind,
+    WireTap,
 };
 
 #[tokio::test]
@@ -118,3 +134,1308 @@ async fn test_tcp_multiple_clients() {
 
     println!("Multiple clients test completed successfully!");
 }
+
+fn probing_config() -> TcpServerConfig {
+    TcpServerConfig {
+        probe_after: Some(Duration::from_millis(200)),
+        probe_timeout: Duration::from_millis(200),
+        ..TcpServerConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_half_open_session_is_reaped() {
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", probing_config())
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A peer that stays connected but never reads or answers, like a
+    // laptop that went to sleep: no FIN ever reaches the server
+    let silent_peer = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+    let mut conn = server.accept().await.unwrap();
+
+    let start = Instant::now();
+    let result = timeout(Duration::from_secs(2), conn.recv())
+        .await
+        .expect("session should be reaped");
+    assert!(result.unwrap().is_none());
+    assert_eq!(conn.disconnect_reason(), Some(&DisconnectReason::Unreachable));
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    drop(silent_peer);
+}
+
+#[tokio::test]
+async fn test_tcp_answered_probes_keep_session_alive() {
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", probing_config())
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let client_handle = tokio::spawn(async move {
+        let mut client = VstpTcpClient::connect(&server_addr.to_string()).await.unwrap();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(800) {
+            if let Ok(Ok(Some(frame))) = timeout(Duration::from_millis(50), client.recv()).await {
+                if frame.typ == FrameType::Ping {
+                    client.send(Frame::new(FrameType::Pong)).await.unwrap();
+                }
+            }
+        }
+        client.send_data(b"still here".to_vec()).await.unwrap();
+        client.close().await.unwrap();
+    });
+
+    let mut conn = server.accept().await.unwrap();
+    let frame = timeout(Duration::from_secs(3), conn.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .expect("probes were answered, session should stay up");
+    assert_eq!(frame.typ, FrameType::Data);
+    assert_eq!(frame.payload, b"still here");
+
+    // The client's BYE and FIN end the session normally
+    while let Ok(Some(_)) = conn.recv().await {}
+    assert_eq!(conn.disconnect_reason(), Some(&DisconnectReason::Closed));
+
+    client_handle.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tcp_connection_driven_without_await() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Echo everything back from the async side
+    let mut client = VstpTcpClient::connect(&server_addr.to_string()).await.unwrap();
+    let client_handle = tokio::spawn(async move {
+        while let Ok(Some(frame)) = client.recv().await {
+            if client.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut conn = server.accept().await.unwrap();
+    let handle = tokio::runtime::Handle::current();
+
+    // A plain thread standing in for a game loop: it polls and drives, never awaits
+    let received = std::thread::spawn(move || {
+        let _guard = handle.enter();
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+        let mut sent = 0u32;
+        let mut received = Vec::new();
+
+        while received.len() < 100 {
+            while sent < 100 {
+                match conn.poll_send_ready(&mut cx) {
+                    std::task::Poll::Ready(result) => result.unwrap(),
+                    std::task::Poll::Pending => break,
+                }
+                let frame = Frame::new(FrameType::Data).with_payload(sent.to_be_bytes().to_vec());
+                conn.start_send(frame).unwrap();
+                sent += 1;
+            }
+            received.extend(conn.drive(Duration::from_millis(5)).unwrap());
+            while let std::task::Poll::Ready(frame) = conn.poll_recv(&mut cx) {
+                received.push(frame.unwrap().expect("connection closed early"));
+            }
+            assert!(conn.disconnect_reason().is_none());
+        }
+        received
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(received.len(), 100);
+    for (i, frame) in received.iter().enumerate() {
+        assert_eq!(frame.payload, (i as u32).to_be_bytes());
+    }
+
+    client_handle.abort();
+}
+
+/// Echo a compressible payload and return the server's compression setting
+async fn echo_compressible(client: &mut VstpTcpClient) -> String {
+    let payload = b"temperature=21.5;".repeat(200);
+    let frame = Frame::new(FrameType::Data).with_payload(payload.clone());
+    client.send(frame).await.unwrap();
+    let echoed = client.recv().await.unwrap().unwrap();
+    assert_eq!(echoed.payload, payload);
+    echoed.get_header("server-compression").unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_tcp_compression_renegotiated_mid_session() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Echo server that reports whether it is compressing its replies
+    let server_handle = tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        while let Ok(Some(frame)) = conn.recv().await {
+            let compressing = if conn.compression().enabled() { "on" } else { "off" };
+            let reply = frame.with_header("server-compression", compressing);
+            conn.send(reply).await.unwrap();
+        }
+    });
+
+    let mut client = VstpTcpClient::connect(&server_addr.to_string())
+        .await
+        .unwrap();
+
+    // The server agrees before echoing; we switch once its answer arrives
+    client.set_compression(true).await.unwrap();
+    assert!(!client.compression().enabled());
+    assert_eq!(echo_compressible(&mut client).await, "on");
+    assert!(client.compression().enabled());
+
+    assert_eq!(echo_compressible(&mut client).await, "on");
+    let ratio = client.compression().observed_ratio().unwrap();
+    assert!(ratio < 0.2, "ratio {}", ratio);
+
+    // Turning it back off mid-session
+    client.set_compression(false).await.unwrap();
+    assert_eq!(echo_compressible(&mut client).await, "off");
+    assert!(!client.compression().enabled());
+
+    server_handle.abort();
+}
+
+/// Accept one subscriber, check its HELLO and send it `payload`
+async fn publish_once(server: &VstpTcpServer, payload: &[u8]) -> vstp::tcp::VstpTcpConnection {
+    let mut conn = server.accept().await.unwrap();
+    let hello = conn.recv().await.unwrap().unwrap();
+    assert_eq!(hello.typ, FrameType::Hello);
+    assert_eq!(hello.payload, b"subscriber-7");
+    let frame = Frame::new(FrameType::Data).with_payload(payload.to_vec());
+    conn.send(frame).await.unwrap();
+    conn
+}
+
+async fn next(stream: &mut ReconnectingStream) -> StreamEvent {
+    let event = timeout(Duration::from_secs(5), stream.next()).await;
+    event.unwrap().unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn test_tcp_reconnecting_stream_survives_server_restart() {
+    let server = VstpTcpServer::bind_reuseaddr("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let config = ReconnectConfig {
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+        ..ReconnectConfig::default()
+    }
+    .with_hello_payload(b"subscriber-7".to_vec());
+    let mut stream =
+        VstpTcpClient::reconnecting_stream_with_config(server_addr.to_string(), config);
+
+    let (event, conn) = tokio::join!(next(&mut stream), publish_once(&server, b"before"));
+    assert!(matches!(event, StreamEvent::Frame(ref f) if f.payload == b"before"));
+
+    // Restart: the connection and the listener go away, and the listener comes
+    // back on the same port while the subscriber keeps retrying
+    drop(conn);
+    drop(server);
+    let publisher = async {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let server = VstpTcpServer::bind_reuseaddr(server_addr).await.unwrap();
+        let conn = publish_once(&server, b"after").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        conn
+    };
+    let (events, _conn) = tokio::join!(
+        async {
+            let reconnected = next(&mut stream).await;
+            (reconnected, next(&mut stream).await)
+        },
+        publisher
+    );
+    assert!(matches!(events.0, StreamEvent::Reconnected { attempts } if attempts > 1));
+    assert!(matches!(events.1, StreamEvent::Frame(ref f) if f.payload == b"after"));
+}
+
+#[tokio::test]
+async fn test_tcp_reconnecting_stream_gives_up_after_max_attempts() {
+    // Nothing listens on a port we just released
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = ReconnectConfig {
+        initial_backoff: Duration::from_millis(10),
+        max_attempts: Some(3),
+        ..ReconnectConfig::default()
+    };
+    let mut stream = VstpTcpClient::reconnecting_stream_with_config(addr.to_string(), config);
+    assert!(stream.next().await.unwrap().is_err());
+    assert!(stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn test_tcp_reconnecting_stream_waits_out_draining_server() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let publisher = tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        conn.recv().await.unwrap().unwrap();
+        let drain = Frame::coded_error(ErrorCode::ShuttingDown, "draining")
+            .with_retry_after(Duration::from_millis(300));
+        conn.send(drain).await.unwrap();
+        let drained_at = Instant::now();
+
+        let mut conn = server.accept().await.unwrap();
+        let waited = drained_at.elapsed();
+        conn.recv().await.unwrap().unwrap();
+        let frame = Frame::new(FrameType::Data).with_payload(b"back".to_vec());
+        conn.send(frame).await.unwrap();
+        (waited, conn)
+    });
+
+    let mut stream = VstpTcpClient::reconnecting_stream(addr.to_string());
+    assert_eq!(
+        next(&mut stream).await,
+        StreamEvent::Draining {
+            retry_after: Duration::from_millis(300)
+        }
+    );
+    assert_eq!(
+        next(&mut stream).await,
+        StreamEvent::Reconnected { attempts: 1 }
+    );
+    assert!(matches!(next(&mut stream).await, StreamEvent::Frame(ref f) if f.payload == b"back"));
+    let (waited, _conn) = publisher.await.unwrap();
+    assert!(waited >= Duration::from_millis(280), "{:?}", waited);
+}
+
+#[tokio::test]
+async fn test_tcp_dictionary_compression() {
+    let shared =
+        br#"{"sensor":"greenhouse-3","kind":"temperature","unit":"celsius","status":"ok"}"#;
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Echo server that holds dictionary 1 and replies uncompressed
+    let server_handle = tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        conn.register_dictionary(1, shared.to_vec());
+        while let Ok(Some(frame)) = conn.recv().await {
+            conn.send(frame).await.unwrap();
+        }
+    });
+
+    let mut client = VstpTcpClient::connect(&server_addr.to_string())
+        .await
+        .unwrap();
+    assert!(client.use_dictionary(Some(1)).is_err());
+    client.register_dictionary(1, shared.to_vec());
+    client.use_dictionary(Some(1)).unwrap();
+
+    let payload = shared.to_vec();
+    let frame = Frame::new(FrameType::Data).with_payload(payload.clone());
+    client.send(frame).await.unwrap();
+    let echoed = client.recv().await.unwrap().unwrap();
+    assert_eq!(echoed.payload, payload);
+    let ratio = client.compression().observed_ratio().unwrap();
+    assert!(ratio < 0.5, "ratio {}", ratio);
+
+    // A dictionary the server doesn't hold ends the session with an error
+    client.register_dictionary(2, b"something else".to_vec());
+    client.use_dictionary(Some(2)).unwrap();
+    let frame = Frame::new(FrameType::Data).with_payload(payload);
+    client.send(frame).await.unwrap();
+    assert!(client.recv().await.unwrap().is_none());
+
+    server_handle.await.unwrap();
+}
+
+/// A market data tick whose headers differ from the last one only in `seq`
+fn tick(seq: usize) -> Frame {
+    Frame::new(FrameType::Data)
+        .with_header("session-id", "7f3a9c21")
+        .with_header("content-type", "application/json")
+        .with_header("stream-id", "quotes/XNAS")
+        .with_header("symbol", "ACME")
+        .with_header("venue", "XNAS")
+        .with_header("seq", &seq.to_string())
+        .with_payload(br#"{"bid":101.25,"ask":101.27}"#.to_vec())
+}
+
+/// Stream `count` ticks to a server, with a header table of `size` if it
+/// isn't 0, and return the headers the server saw and the client's stats
+async fn stream_ticks(count: usize, size: usize) -> (Vec<Vec<Header>>, HeaderTableStats) {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let receiver = tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        // The client waits for this, by which time it has the table's size
+        conn.recv().await.unwrap().unwrap();
+        conn.send(Frame::new(FrameType::Ack)).await.unwrap();
+        let mut seen = Vec::with_capacity(count);
+        for _ in 0..count {
+            seen.push(conn.recv().await.unwrap().unwrap().headers);
+        }
+        seen
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    if size > 0 {
+        client.set_header_table(size).await.unwrap();
+    }
+    client.send(Frame::new(FrameType::Hello)).await.unwrap();
+    assert_eq!(client.recv().await.unwrap().unwrap().typ, FrameType::Ack);
+    assert_eq!(client.compression().header_table().send_size(), size);
+    for seq in 0..count {
+        client.send(tick(seq)).await.unwrap();
+    }
+    let stats = client.compression().header_table().stats();
+    (receiver.await.unwrap(), stats)
+}
+
+#[tokio::test]
+async fn test_tcp_header_table_saves_bytes_and_keeps_headers() {
+    let (baseline, plain) = stream_ticks(10_000, 0).await;
+    let (templated, stats) = stream_ticks(10_000, 8).await;
+
+    assert_eq!(templated, baseline);
+    assert_eq!(plain.bytes_saved(), 0);
+    assert_eq!(stats.referenced_frames, 9_999);
+    assert_eq!(stats.header_bytes_in, plain.header_bytes_in);
+    assert!(
+        stats.header_bytes_out * 3 < stats.header_bytes_in,
+        "{:?}",
+        stats
+    );
+}
+
+#[tokio::test]
+async fn test_tcp_wire_tap_sees_every_frame() {
+    let wire_in = Arc::new(Mutex::new(Vec::new()));
+    let wire_out = Arc::new(Mutex::new(Vec::new()));
+    let tapped = wire_in.clone();
+    let config = TcpServerConfig {
+        wire_tap: WireTap::new().on_wire_in(move |b| tapped.lock().unwrap().push(b.to_vec())),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let receiver = tokio::spawn(async move {
+        let mut connection = server.accept().await.unwrap();
+        for _ in 0..2 {
+            connection.recv().await.unwrap().unwrap();
+        }
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let tapped = wire_out.clone();
+    let tap = WireTap::new().on_wire_out(move |b| tapped.lock().unwrap().push(b.to_vec()));
+    client.set_wire_tap(tap);
+    let data = Frame::new(FrameType::Data)
+        .with_header("audit", "yes")
+        .with_payload(b"captured".to_vec());
+    client.send(Frame::new(FrameType::Hello)).await.unwrap();
+    client.send(data.clone()).await.unwrap();
+    timeout(Duration::from_secs(5), receiver)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let wire_out = wire_out.lock().unwrap();
+    assert_eq!(wire_out.len(), 2);
+    assert_eq!(wire_out[1], encode_frame(&data).unwrap().to_vec());
+    assert_eq!(*wire_in.lock().unwrap(), *wire_out);
+}
+
+#[tokio::test]
+async fn test_tcp_ingress_policy_strips_and_rejects() {
+    let config = TcpServerConfig {
+        ingress: Some(IngressPolicy {
+            strip_headers: vec!["x-internal-*".to_string()],
+            reject_headers: vec!["x-debug".to_string()],
+            max_headers: Some(2),
+            normalize_keys: true,
+            ..IngressPolicy::default()
+        }),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let stats = server.ingress_stats();
+    let receiver = tokio::spawn(async move {
+        let mut connection = server.accept().await.unwrap();
+        let first = connection.recv().await.unwrap().unwrap();
+        let second = connection.recv().await.unwrap().unwrap();
+        (first, second)
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let stripped = Frame::new(FrameType::Data)
+        .with_header("X-Internal-User", "admin")
+        .with_header("Trace", "abc");
+    client.send(stripped).await.unwrap();
+    let rejected = Frame::new(FrameType::Data).with_header("x-debug", "1");
+    client.send(rejected).await.unwrap();
+    let crowded = Frame::new(FrameType::Data)
+        .with_header("a", "1")
+        .with_header("b", "2")
+        .with_header("c", "3");
+    client.send(crowded).await.unwrap();
+    client
+        .send(Frame::new(FrameType::Data).with_payload(b"after".to_vec()))
+        .await
+        .unwrap();
+
+    // Rejections are answered, and the connection stays up
+    for _ in 0..2 {
+        let reply = timeout(Duration::from_secs(5), client.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.error_code(), Some(ErrorCode::PolicyViolation));
+    }
+    let (first, second) = timeout(Duration::from_secs(5), receiver)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(first.headers.len(), 1);
+    assert_eq!(first.get_header("trace"), Some("abc"));
+    assert_eq!(second.payload, b"after");
+
+    assert_eq!(stats.stripped_header_count(), 1);
+    assert_eq!(stats.rejected_frame_count(), 2);
+    assert_eq!(stats.normalized_key_count(), 2);
+}
+
+#[tokio::test]
+async fn test_tcp_disallowed_frame_types_refused() {
+    let config = TcpServerConfig {
+        allowed_frame_types: Some(HashSet::from([
+            FrameType::Hello,
+            FrameType::Data,
+            FrameType::Ping,
+            FrameType::Bye,
+        ])),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let receiver = tokio::spawn(async move {
+        let mut connection = server.accept().await.unwrap();
+        let frame = connection.recv().await.unwrap().unwrap();
+        (frame, server.rejected_frame_type_count())
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    client.send(Frame::new(FrameType::Ack)).await.unwrap();
+    client.send(Frame::new(FrameType::Welcome)).await.unwrap();
+    client
+        .send(Frame::new(FrameType::Data).with_payload(b"allowed".to_vec()))
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        let reply = timeout(Duration::from_secs(5), client.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.error_code(), Some(ErrorCode::BadRequest));
+    }
+    let (frame, rejected) = timeout(Duration::from_secs(5), receiver)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.typ, FrameType::Data);
+    assert_eq!(frame.payload, b"allowed");
+    assert_eq!(rejected, 2);
+}
+
+#[tokio::test]
+async fn test_tcp_oversized_frame_rejected_before_payload() {
+    let config = TcpServerConfig {
+        max_frame_size: 64 * 1024,
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut peer = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut connection = server.accept().await.unwrap();
+
+    // Claim a 1 GiB payload but send nothing past the length fields
+    let mut header = vec![0x56, 0x54, 0x01, 0x03, 0x00, 0x00, 0x00];
+    header.extend_from_slice(&(1u32 << 30).to_be_bytes());
+    peer.write_all(&header).await.unwrap();
+
+    let result = timeout(Duration::from_secs(2), connection.recv())
+        .await
+        .expect("rejected without waiting for the payload");
+    assert!(matches!(
+        result,
+        Err(VstpError::FrameTooLarge { size, limit: 65536 }) if size == 11 + (1 << 30) + 4
+    ));
+    drop(connection);
+
+    let mut buf = [0u8; 16];
+    let read = timeout(Duration::from_secs(2), peer.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read, 0);
+}
+
+#[tokio::test]
+async fn test_tcp_send_batch_takes_one_write() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let mut connection = server.accept().await.unwrap();
+    let fan_out = |round: &str| -> Vec<Frame> {
+        (0..32)
+            .map(|i| {
+                Frame::new(FrameType::Data)
+                    .with_header("round", round)
+                    .with_payload(format!("update {}", i).into_bytes())
+            })
+            .collect()
+    };
+
+    let before = connection.socket_writes();
+    for frame in fan_out("single") {
+        connection.send(frame).await.unwrap();
+    }
+    let singles = connection.socket_writes() - before;
+    let before = connection.socket_writes();
+    connection.send_batch(fan_out("batch")).await.unwrap();
+    let batched = connection.socket_writes() - before;
+    assert_eq!((singles, batched), (32, 1));
+
+    // Every frame still arrives on its own, in order
+    for round in ["single", "batch"] {
+        for i in 0..32 {
+            let frame = client.recv().await.unwrap().unwrap();
+            assert_eq!(frame.get_header("round"), Some(round));
+            assert_eq!(frame.payload, format!("update {}", i).into_bytes());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_large_frames_with_sized_read_buffers() {
+    let config = TcpServerConfig {
+        read_buffer_capacity: 320 * 1024,
+        max_read_buffer_capacity: Some(1024 * 1024),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    client.set_read_buffer_capacity(320 * 1024, Some(1024 * 1024));
+    let mut connection = server.accept().await.unwrap();
+
+    // Steady 256 KiB frames, then one far past the maximum
+    for size in [256 * 1024, 256 * 1024, 2 * 1024 * 1024, 256 * 1024] {
+        let payload = vec![(size % 251) as u8; size];
+        client.send_data(payload.clone()).await.unwrap();
+        let frame = connection.recv().await.unwrap().unwrap();
+        assert_eq!(frame.payload, payload);
+        connection
+            .send(Frame::new(FrameType::Data).with_payload(frame.payload))
+            .await
+            .unwrap();
+        assert_eq!(client.recv().await.unwrap().unwrap().payload, payload);
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_header_heavy_frame_is_suspicious() {
+    let config = TcpServerConfig {
+        max_header_to_payload_ratio: Some(HeaderRatioLimit::default()),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut peer = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut connection = server.accept().await.unwrap();
+
+    let normal = Frame::new(FrameType::Data)
+        .with_header("content-type", "text/plain")
+        .with_payload(b"ok".to_vec());
+    let encoded = encode_frame(&normal).unwrap();
+    peer.write_all(&encoded).await.unwrap();
+    assert_eq!(connection.recv().await.unwrap().unwrap().payload, b"ok");
+
+    // 60KB of headers wrapped around a 2-byte payload
+    let wrapped = (0..240)
+        .fold(Frame::new(FrameType::Data), |frame, i| {
+            frame.with_header(&format!("x-{}", i), &"a".repeat(250))
+        })
+        .with_payload(b"hi".to_vec());
+    let encoded = encode_frame(&wrapped).unwrap();
+    peer.write_all(&encoded).await.unwrap();
+    let result = timeout(Duration::from_secs(2), connection.recv())
+        .await
+        .unwrap();
+    assert!(matches!(
+        result,
+        Err(VstpError::SuspiciousFrame {
+            payload_bytes: 2,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_tcp_busy_session_closed_at_max_age() {
+    // Once through the plain poll path and once through the probing one
+    for probe_after in [None, Some(Duration::from_secs(5))] {
+        let config = TcpServerConfig {
+            max_connection_age: Some(Duration::from_millis(300)),
+            probe_after,
+            ..TcpServerConfig::default()
+        };
+        let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let server_handle = tokio::spawn(async move {
+            let mut conn = server.accept().await.unwrap();
+            let start = Instant::now();
+            let mut received = 0;
+            while conn.recv().await.unwrap().is_some() {
+                received += 1;
+            }
+            assert_eq!(conn.disconnect_reason(), Some(&DisconnectReason::MaxAge));
+            (received, start.elapsed())
+        });
+
+        // Stay busy the whole time; the server closes the session anyway
+        let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+        let bye = loop {
+            client.send(Frame::new(FrameType::Data)).await.unwrap();
+            if let Ok(reply) = timeout(Duration::from_millis(50), client.recv()).await {
+                break reply.unwrap();
+            }
+        };
+        assert_eq!(bye.map(|frame| frame.typ), Some(FrameType::Bye));
+
+        let (received, age) = timeout(Duration::from_secs(2), server_handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received >= 3, "only {} frames", received);
+        assert!(age >= Duration::from_millis(300));
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_session_stalled_mid_frame_is_closed() {
+    // Once through the plain poll path and once through the probing one
+    for probe_after in [None, Some(Duration::from_secs(5))] {
+        let config = TcpServerConfig {
+            partial_frame_timeout: Some(Duration::from_millis(200)),
+            partial_frame_min_bps: 4 * 1000 * 1000,
+            probe_after,
+            ..TcpServerConfig::default()
+        };
+        let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let mut stream = tokio::net::TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut conn = server.accept().await.unwrap();
+        let session = tokio::spawn(async move {
+            let start = Instant::now();
+            while conn.recv().await.unwrap().is_some() {}
+            assert_eq!(conn.disconnect_reason(), Some(&DisconnectReason::Stalled));
+            start.elapsed()
+        });
+
+        // Declare a 1 MB frame, send the start of it and go silent
+        let frame = Frame::new(FrameType::Data).with_payload(vec![7; 1_000_000]);
+        let encoded = encode_frame(&frame).unwrap();
+        stream.write_all(&encoded[..4096]).await.unwrap();
+
+        let mut farewell = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut farewell))
+            .await
+            .expect("closed at the deadline")
+            .unwrap();
+        let mut farewell = bytes::BytesMut::from(&farewell[..]);
+        let err = vstp::try_decode_frame(&mut farewell, 65536).unwrap().unwrap();
+        assert_eq!(err.error_code(), Some(ErrorCode::DeadlineExceeded));
+        let bye = vstp::try_decode_frame(&mut farewell, 65536).unwrap().unwrap();
+        assert_eq!(bye.typ, FrameType::Bye);
+
+        // 200 ms plus a quarter second for a megabyte at 4 MB/s
+        let stalled_for = timeout(Duration::from_secs(2), session)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stalled_for >= Duration::from_millis(450), "{:?}", stalled_for);
+        assert_eq!(server.stalled_count(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_session_stalled_inside_the_fixed_header_is_closed() {
+    let config = TcpServerConfig {
+        partial_frame_timeout: Some(Duration::from_millis(200)),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let mut stream = tokio::net::TcpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut conn = server.accept().await.unwrap();
+    let session = tokio::spawn(async move {
+        while conn.recv().await.unwrap().is_some() {}
+        conn.disconnect_reason().cloned()
+    });
+
+    // Too little to tell the frame's length
+    let encoded = encode_frame(&Frame::new(FrameType::Ping)).unwrap();
+    stream.write_all(&encoded[..3]).await.unwrap();
+
+    let reason = timeout(Duration::from_secs(2), session)
+        .await
+        .expect("closed at the deadline")
+        .unwrap();
+    assert_eq!(reason, Some(DisconnectReason::Stalled));
+    assert_eq!(server.stalled_count(), 1);
+}
+
+#[tokio::test]
+async fn test_tcp_huge_partial_frame_timeout_does_not_overflow() {
+    let config = TcpServerConfig {
+        partial_frame_timeout: Some(Duration::MAX),
+        partial_frame_min_bps: 1,
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let mut stream = tokio::net::TcpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut conn = server.accept().await.unwrap();
+    let session = tokio::spawn(async move { conn.recv().await.unwrap() });
+
+    let frame = Frame::new(FrameType::Data).with_payload(vec![7; 1024]);
+    let encoded = encode_frame(&frame).unwrap();
+    stream.write_all(&encoded[..512]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stream.write_all(&encoded[512..]).await.unwrap();
+
+    let received = timeout(Duration::from_secs(2), session)
+        .await
+        .unwrap()
+        .unwrap()
+        .expect("the frame");
+    assert_eq!(received.payload.len(), 1024);
+}
+
+#[tokio::test]
+async fn test_tcp_slow_steady_upload_outlives_partial_frame_timeout() {
+    let config = TcpServerConfig {
+        partial_frame_timeout: Some(Duration::from_millis(200)),
+        partial_frame_min_bps: 16 * 1024,
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let mut stream = tokio::net::TcpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut conn = server.accept().await.unwrap();
+    let session = tokio::spawn(async move { conn.recv().await.unwrap() });
+
+    // 32 KiB at 40 KiB/s, well past the bare 200 ms but within the floor
+    let frame = Frame::new(FrameType::Data).with_payload(vec![7; 32 * 1024]);
+    let encoded = encode_frame(&frame).unwrap();
+    for chunk in encoded.chunks(4096) {
+        stream.write_all(chunk).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let received = timeout(Duration::from_secs(2), session)
+        .await
+        .unwrap()
+        .unwrap()
+        .expect("the frame, not a closed session");
+    assert_eq!(received.payload.len(), 32 * 1024);
+    assert_eq!(server.stalled_count(), 0);
+}
+
+/// Read frames until the server closes the connection
+async fn recv_until_closed(client: &mut VstpTcpClient) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    while let Ok(Ok(Some(frame))) = timeout(Duration::from_secs(2), client.recv()).await {
+        frames.push(frame);
+    }
+    frames
+}
+
+#[tokio::test]
+async fn test_tcp_session_frame_quota() {
+    // Once through the plain poll path and once through the probing one
+    for probe_after in [None, Some(Duration::from_secs(5))] {
+        let config = TcpServerConfig {
+            session_quota: Some(SessionQuota {
+                max_frames: Some(2),
+                ..SessionQuota::default()
+            }),
+            probe_after,
+            ..TcpServerConfig::default()
+        };
+        let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let server_handle = tokio::spawn(async move {
+            let mut sessions = Vec::new();
+            for _ in 0..2 {
+                let mut conn = server.accept().await.unwrap();
+                sessions.push(tokio::spawn(async move {
+                    let mut remaining = Vec::new();
+                    while let Some(frame) = conn.recv().await.unwrap() {
+                        remaining.push(conn.quota_remaining().unwrap().frames);
+                        conn.send(frame).await.unwrap();
+                    }
+                    (remaining, conn.disconnect_reason().cloned())
+                }));
+            }
+            let mut results = Vec::new();
+            for session in sessions {
+                results.push(session.await.unwrap());
+            }
+            (results, server.quota_closed_count())
+        });
+
+        let mut greedy = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+        let mut modest = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+        for n in 0..3 {
+            let frame = Frame::new(FrameType::Data).with_payload(vec![n]);
+            greedy.send(frame).await.unwrap();
+        }
+        let frames = recv_until_closed(&mut greedy).await;
+        let types: Vec<FrameType> = frames.iter().map(|frame| frame.typ).collect();
+        assert_eq!(
+            types,
+            [FrameType::Data, FrameType::Data, FrameType::Err, FrameType::Bye]
+        );
+        assert_eq!(frames[2].error_code(), Some(ErrorCode::QuotaExceeded));
+
+        // The other session still has its whole quota
+        let frame = Frame::new(FrameType::Data).with_payload(b"hi".to_vec());
+        modest.send(frame.clone()).await.unwrap();
+        assert_eq!(modest.recv().await.unwrap(), Some(frame));
+        modest.close().await.unwrap();
+
+        let (results, quota_closed) = timeout(Duration::from_secs(5), server_handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results[0].0, [Some(1), Some(0)]);
+        assert_eq!(
+            results[0].1,
+            Some(DisconnectReason::QuotaExceeded("frame".to_string()))
+        );
+        // The second frame is the BYE sent by `close`
+        assert_eq!(results[1].0, [Some(1), Some(0)]);
+        assert_eq!(results[1].1, Some(DisconnectReason::Closed));
+        assert_eq!(quota_closed, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_session_send_and_time_quota() {
+    let config = TcpServerConfig {
+        session_quota: Some(SessionQuota {
+            max_bytes_out: Some(100),
+            max_duration: Some(Duration::from_millis(300)),
+            ..SessionQuota::default()
+        }),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let server_handle = tokio::spawn(async move {
+        // Send until the byte quota runs out, then let the next receive close the session
+        let mut talker = server.accept().await.unwrap();
+        let mut sent = 0;
+        while talker
+            .send(Frame::new(FrameType::Data).with_payload(vec![0; 40]))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+        }
+        assert_eq!(talker.quota_remaining().unwrap().bytes_out, Some(0));
+        assert_eq!(talker.recv().await.unwrap(), None);
+
+        // Wait out the time limit without the client sending anything
+        let mut idler = server.accept().await.unwrap();
+        let start = Instant::now();
+        assert_eq!(idler.recv().await.unwrap(), None);
+        (
+            sent,
+            talker.disconnect_reason().cloned(),
+            idler.disconnect_reason().cloned(),
+            start.elapsed(),
+            server.quota_closed_count(),
+        )
+    });
+
+    let mut talker = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let frames = recv_until_closed(&mut talker).await;
+    let mut idler = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let idler_frames = recv_until_closed(&mut idler).await;
+
+    let (sent, talker_reason, idler_reason, idled, quota_closed) =
+        timeout(Duration::from_secs(5), server_handle)
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(sent, 2);
+    let types: Vec<FrameType> = frames.iter().map(|frame| frame.typ).collect();
+    assert_eq!(
+        types,
+        [FrameType::Data, FrameType::Data, FrameType::Err, FrameType::Bye]
+    );
+    let types: Vec<FrameType> = idler_frames.iter().map(|frame| frame.typ).collect();
+    assert_eq!(types, [FrameType::Err, FrameType::Bye]);
+    assert_eq!(
+        talker_reason,
+        Some(DisconnectReason::QuotaExceeded("outbound byte".to_string()))
+    );
+    assert_eq!(
+        idler_reason,
+        Some(DisconnectReason::QuotaExceeded("time".to_string()))
+    );
+    assert!(idled >= Duration::from_millis(250), "closed after {:?}", idled);
+    assert_eq!(quota_closed, 2);
+}
+
+#[tokio::test]
+async fn test_tcp_frame_meta() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let frames: Vec<Frame> = (0..5)
+        .map(|i| {
+            Frame::new(FrameType::Data)
+                .with_header("n", &i.to_string())
+                .with_payload(vec![7; i * 100])
+        })
+        .collect();
+    let sent = frames.clone();
+    let server_handle = tokio::spawn(async move {
+        let mut conn = server.accept().await.unwrap();
+        let mut metas = Vec::new();
+        while let Some((frame, meta)) = conn.recv_with_meta().await.unwrap() {
+            if frame.typ == FrameType::Data {
+                metas.push((frame, meta));
+            }
+        }
+        metas
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    for frame in frames {
+        client.send(frame).await.unwrap();
+    }
+    client.close().await.unwrap();
+
+    let metas = timeout(Duration::from_secs(5), server_handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(metas.len(), sent.len());
+    for ((frame, meta), expected) in metas.iter().zip(&sent) {
+        assert_eq!(frame, expected);
+        assert_eq!(meta.wire_len, encode_frame(expected).unwrap().len());
+        assert_eq!(meta.transport, TransportKind::Tcp);
+        assert_eq!(meta.kernel_timestamp, None);
+    }
+    assert!(metas
+        .windows(2)
+        .all(|pair| pair[0].1.received_at <= pair[1].1.received_at));
+}
+
+#[tokio::test]
+async fn test_tcp_server_send_rate_is_capped() {
+    let config = TcpServerConfig {
+        max_send_bps: Some(100_000),
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let shaper = server.send_shaper().unwrap();
+    tokio::spawn(async move {
+        let mut connection = server.accept().await.unwrap();
+        for _ in 0..16 {
+            let frame = Frame::new(FrameType::Data).with_payload(vec![7; 10_000]);
+            connection.send(frame).await.unwrap();
+        }
+    });
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let start = Instant::now();
+    let mut received = 0;
+    while received < 16 * 10_000 {
+        let frame = timeout(Duration::from_secs(10), client.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        received += frame.payload.len();
+    }
+    // 10 KB of burst, then 150 KB more at 100 KB/s
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(1_300), "took {:?}", elapsed);
+    assert!(shaper.bytes_sent() >= 16 * 10_000);
+    assert!(shaper.current_bps() <= 120_000);
+}
+
+#[tokio::test]
+async fn test_tcp_server_logs_under_its_span() {
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // The test runtime is single-threaded, so spawned tasks see this too
+    let _default = tracing::subscriber::set_default(subscriber);
+    let text = || String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+
+    let mut servers = Vec::new();
+    for name in ["ingest", "control"] {
+        let config = TcpServerConfig {
+            span: tracing::info_span!("vstp", server = name),
+            ..TcpServerConfig::default()
+        };
+        let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap();
+        servers.push(server.local_addr().unwrap());
+        tokio::spawn(server.run(|_, frame: Frame| async move {
+            tracing::info!("handled {:?}", frame.typ);
+        }));
+    }
+
+    let mut client = VstpTcpClient::connect(&servers[0].to_string()).await.unwrap();
+    client.send(Frame::new(FrameType::Data)).await.unwrap();
+    client.close().await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while !text().contains("ended") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let text = text();
+    let line = |needle: &str| {
+        text.lines()
+            .find(|line| line.contains(needle))
+            .unwrap_or_else(|| panic!("no log line with {:?} in\n{}", needle, text))
+    };
+    assert!(line(&format!("bound to {}", servers[0])).contains("vstp{server=\"ingest\"}"));
+    assert!(line(&format!("bound to {}", servers[1])).contains("vstp{server=\"control\"}"));
+    for needle in ["New connection", "handled Data", "ended"] {
+        assert!(line(needle).contains("vstp{server=\"ingest\"}"), "{}", line(needle));
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_incoming_frames_pull_model() {
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    // Room for one frame, so sessions have to wait for the loop
+    let mut frames = server.incoming_with_capacity(1);
+
+    let mut clients = Vec::new();
+    for name in ["a", "b"] {
+        let mut client = VstpTcpClient::connect(&addr).await.unwrap();
+        for seq in 0..5 {
+            let frame = Frame::new(FrameType::Data)
+                .with_header("client", name)
+                .with_header("seq", &seq.to_string());
+            client.send(frame).await.unwrap();
+        }
+        clients.push(client);
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Every frame arrives, in order within its session
+    let mut seen: Vec<(SessionId, String, String)> = Vec::new();
+    while seen.len() < 10 {
+        let (session, frame) = timeout(Duration::from_secs(2), frames.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let name = frame.get_header("client").unwrap().to_string();
+        let seq = frame.get_header("seq").unwrap().to_string();
+        session
+            .send(Frame::new(FrameType::Ack).with_header("seq", &seq))
+            .await
+            .unwrap();
+        seen.push((session.id(), name, seq));
+    }
+    for name in ["a", "b"] {
+        let session: Vec<_> = seen.iter().filter(|(_, n, _)| n == name).collect();
+        let seqs: Vec<&str> = session.iter().map(|(_, _, seq)| seq.as_str()).collect();
+        assert_eq!(seqs, ["0", "1", "2", "3", "4"]);
+        assert!(session.iter().all(|(id, _, _)| *id == session[0].0));
+    }
+    assert_ne!(
+        seen[0].0,
+        seen.iter().find(|(_, n, _)| *n != seen[0].1).unwrap().0
+    );
+
+    // Replies reach their own session
+    for client in &mut clients {
+        for seq in 0..5 {
+            let ack = timeout(Duration::from_secs(2), client.recv())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(ack.get_header("seq"), Some(seq.to_string().as_str()));
+        }
+    }
+
+    // Dropping the stream ends the sessions
+    drop(frames);
+    let closed = timeout(Duration::from_secs(2), clients[0].recv())
+        .await
+        .unwrap();
+    assert!(matches!(closed, Ok(None) | Err(_)));
+}
+
+/// Keyed SHA-256 standing in for a downstream HMAC check
+fn sign(key: &[u8], bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(bytes);
+    hasher.finalize().to_vec()
+}
+
+#[tokio::test]
+async fn test_relay_forwards_signed_frames_verbatim() -> Result<(), VstpError> {
+    const KEY: &[u8] = b"origin-secret";
+    let config = || TcpServerConfig {
+        keep_raw_bytes: true,
+        ..TcpServerConfig::default()
+    };
+
+    // Final hop: verify each frame's signature over the bytes it arrived as
+    let signatures: Arc<Mutex<Vec<Vec<u8>>>> = Arc::default();
+    let (verified_tx, mut verified) = tokio::sync::mpsc::unbounded_channel();
+    let sink = VstpTcpServer::bind_with_config("127.0.0.1:0", config()).await?;
+    let sink_addr = sink.local_addr()?;
+    let expected = signatures.clone();
+    tokio::spawn(sink.run_with_meta(move |_, frame: Frame, meta| {
+        let raw = meta.raw_bytes.expect("raw bytes are kept");
+        let verified = expected.lock().unwrap().contains(&sign(KEY, &raw));
+        let _ = verified_tx.send((frame, verified));
+        async {}
+    }));
+
+    // Relay: store the raw bytes, then forward them from another task
+    let (stored_tx, mut stored) = tokio::sync::mpsc::unbounded_channel();
+    let relay = VstpTcpServer::bind_with_config("127.0.0.1:0", config()).await?;
+    let relay_addr = relay.local_addr()?;
+    tokio::spawn(relay.run_with_meta(move |_, _, meta| {
+        let _ = stored_tx.send(meta.raw_bytes.expect("raw bytes are kept"));
+        async {}
+    }));
+    tokio::spawn(async move {
+        let mut upstream = VstpTcpClient::connect(&sink_addr.to_string()).await?;
+        while let Some(raw) = stored.recv().await {
+            upstream.send_wire_bytes(&raw).await?;
+        }
+        Ok::<_, VstpError>(())
+    });
+
+    // Version 2, a SHA-256 trailer, an unknown flag and repeated headers,
+    // none of which a codec would reproduce when re-encoding
+    let mut origin = VstpTcpClient::connect(&relay_addr.to_string()).await?;
+    let mut sent = Vec::new();
+    for n in 0..3 {
+        let frame = Frame::new(FrameType::Data)
+            .with_header("z-last", "first")
+            .with_header("msg-id", &n.to_string())
+            .with_header("z-last", "again")
+            .with_flag(Flags::SHA256 | Flags::from_bits_retain(0x80))
+            .with_payload(format!("order {}", n).into_bytes());
+        let frame = Frame {
+            version: VSTP_VERSION_2,
+            ..frame
+        };
+        let bytes = encode_frame(&frame)?;
+        signatures.lock().unwrap().push(sign(KEY, &bytes));
+        origin.send_wire_bytes(&bytes).await?;
+        sent.push(frame);
+    }
+
+    for frame in sent {
+        let (received, verified) = timeout(Duration::from_secs(5), verified.recv())
+            .await
+            .expect("frame reached the final hop")
+            .unwrap();
+        assert!(
+            verified,
+            "signature of {:?} failed",
+            frame.get_header("msg-id")
+        );
+        assert_eq!(received, frame);
+    }
+
+    // Half a frame, or two, can't be sent as wire bytes
+    let ping = encode_frame(&Frame::new(FrameType::Ping))?;
+    let two = [&ping[..], &ping[..]].concat();
+    assert!(origin.send_wire_bytes(&two).await.is_err());
+    assert!(origin.send_wire_bytes(&two[..5]).await.is_err());
+    Ok(())
+}
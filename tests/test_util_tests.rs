@@ -0,0 +1,88 @@
+//! Tests for the `test-util` loopback helpers
+
+use std::time::Duration;
+use vstp::testing::{
+    echo, spawn_tcp_server, spawn_udp_server, tcp_roundtrip, udp_client, udp_roundtrip,
+    LossConfig, LossyUdpProxy,
+};
+use vstp::udp::client::UdpConfig;
+use vstp::{Frame, FrameType, VstpUdpClient};
+
+#[tokio::test]
+async fn test_tcp_echo_helpers() {
+    let server = spawn_tcp_server(echo).await.unwrap();
+    let mut client = server.tcp_client().await.unwrap();
+
+    let frame = Frame::new(FrameType::Data).with_payload_text("over tcp");
+    let reply = tcp_roundtrip(&mut client, frame.clone()).await.unwrap();
+    assert_eq!(reply, frame);
+}
+
+#[tokio::test]
+async fn test_udp_reply_helpers() {
+    let server = spawn_udp_server(|frame| {
+        let text = frame.payload_text().ok()?.to_uppercase();
+        Some(Frame::new(FrameType::Data).with_payload_text(&text))
+    })
+    .await
+    .unwrap();
+    let mut client = udp_client().await.unwrap();
+
+    let frame = Frame::new(FrameType::Data).with_payload_text("over udp");
+    let reply = udp_roundtrip(&mut client, server.addr(), frame).await.unwrap();
+    assert_eq!(reply.payload_text().unwrap(), "OVER UDP");
+}
+
+#[tokio::test]
+async fn test_lossy_proxy_exercises_retransmission() {
+    let server = spawn_udp_server(|_| None).await.unwrap();
+    let proxy = LossyUdpProxy::start(server.addr(), LossConfig::default())
+        .await
+        .unwrap();
+
+    let config = UdpConfig {
+        retry_delay: Duration::from_millis(20),
+        ack_timeout: Duration::from_millis(200),
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+
+    // Lose the first transmission; the retry gets through and is ACKed
+    proxy.drop_next(1);
+    let frame = Frame::new(FrameType::Data).with_payload_text("reliable");
+    client.send_with_ack(frame, proxy.addr()).await.unwrap();
+    assert_eq!(proxy.dropped(), 1);
+    assert_eq!(proxy.forwarded(), 2); // retransmission and its ACK
+}
+
+#[tokio::test]
+async fn test_lossy_proxy_drop_rate_is_reproducible() {
+    let server = spawn_udp_server(|_| None).await.unwrap();
+    let mut dropped = Vec::new();
+    for _ in 0..2 {
+        let proxy = LossyUdpProxy::start(
+            server.addr(),
+            LossConfig {
+                drop_rate: 0.5,
+                seed: 11,
+                ..LossConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+        let client = udp_client().await.unwrap();
+        for _ in 0..40 {
+            client
+                .send(Frame::new(FrameType::Ping), proxy.addr())
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(proxy.dropped() + proxy.forwarded(), 40);
+        dropped.push(proxy.dropped());
+    }
+    assert_eq!(dropped[0], dropped[1]);
+    assert!(dropped[0] > 0 && dropped[0] < 40);
+}
@@ -0,0 +1,860 @@
+//! Tests for TLS on the TCP transport
+#![cfg(feature = "tls")]
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustls::pki_types::{pem::PemObject, CertificateDer};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use vstp::{
+    easy::{ConnectOptions, ServerOptions, VstpClient, VstpServer},
+    pool::{PoolConfig, PoolStats, VstpClientPool},
+    tcp::{
+        tls::{PemWatcher, TlsConfig, EARLY_DATA_HEADER},
+        TcpServerConfig, VstpTcpClient, VstpTcpServer,
+    },
+    types::error_codes,
+    Frame, FrameType, Router, VstpError,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Note {
+    text: String,
+}
+
+/// Echo the payload of every DATA frame of every session
+fn echo(server: VstpTcpServer) {
+    tokio::spawn(async move {
+        while let Ok(mut conn) = server.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    if frame.typ == FrameType::Data {
+                        let reply = Frame::new(FrameType::Data).with_payload(frame.payload);
+                        let _ = conn.send(reply).await;
+                    }
+                }
+            });
+        }
+    });
+}
+
+async fn round_trip(client: &mut VstpTcpClient, payload: &[u8]) -> Vec<u8> {
+    client.send_data(payload.to_vec()).await.unwrap();
+    let reply = timeout(Duration::from_secs(5), client.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    reply.payload.to_vec()
+}
+
+#[tokio::test]
+async fn test_tls_round_trip_with_self_signed_certificate() {
+    let tls = TlsConfig::self_signed().unwrap();
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    echo(server);
+
+    let mut client = VstpTcpClient::connect_tls(&addr.to_string(), tls.client_config())
+        .await
+        .unwrap();
+    assert_eq!(round_trip(&mut client, b"sealed").await, b"sealed");
+    let large = vec![7u8; 256 * 1024];
+    assert_eq!(round_trip(&mut client, &large).await, large);
+}
+
+#[tokio::test]
+async fn test_tls_refuses_unpinned_certificate_and_keeps_serving() {
+    let tls = TlsConfig::self_signed().unwrap();
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    echo(server);
+
+    let stranger = TlsConfig::self_signed().unwrap();
+    match VstpTcpClient::connect_tls(&addr, stranger.client_config()).await {
+        Err(VstpError::TlsHandshake(_)) => {}
+        other => panic!("expected a TLS error, got {:?}", other.map(|_| ())),
+    }
+
+    let mut client = VstpTcpClient::connect_tls(&addr, tls.client_config())
+        .await
+        .unwrap();
+    assert_eq!(round_trip(&mut client, b"still here").await, b"still here");
+}
+
+#[tokio::test]
+async fn test_broken_handshakes_dont_stop_the_accept_loop() {
+    let tls = TlsConfig::self_signed().unwrap();
+    let server = Arc::new(
+        VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config())
+            .await
+            .unwrap(),
+    );
+    let addr = server.local_addr().unwrap().to_string();
+    let accepting = server.clone();
+    tokio::spawn(async move {
+        while let Ok(mut conn) = accepting.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    let _ = conn.send(frame).await;
+                }
+            });
+        }
+    });
+
+    // Hangs up halfway through its ClientHello
+    let mut quitter = TcpStream::connect(&addr).await.unwrap();
+    quitter
+        .write_all(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01])
+        .await
+        .unwrap();
+    drop(quitter);
+    // Connects and never says anything, holding its handshake open for the
+    // whole default timeout
+    let _silent = TcpStream::connect(&addr).await.unwrap();
+    let timeout_left = TcpServerConfig::default().tls_handshake_timeout / 5;
+
+    let served = timeout(timeout_left, async {
+        let mut client = VstpTcpClient::connect_tls(&addr, tls.client_config())
+            .await
+            .unwrap();
+        round_trip(&mut client, b"made it").await
+    })
+    .await
+    .expect("a silent peer held up the handshake of the next one");
+    assert_eq!(served, b"made it");
+
+    timeout(Duration::from_secs(5), async {
+        while server.tls_handshake_failures() < 1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    // The silent peer is still within its timeout
+    assert_eq!(server.tls_handshake_failures(), 1);
+}
+
+#[tokio::test]
+async fn test_plaintext_client_gets_nothing_from_tls_server() {
+    let tls = TlsConfig::self_signed().unwrap();
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    echo(server);
+
+    let mut client = VstpTcpClient::connect(&addr.to_string()).await.unwrap();
+    let _ = client.send_data(b"in the clear".to_vec()).await;
+    let reply = timeout(Duration::from_secs(5), client.recv())
+        .await
+        .unwrap();
+    assert!(!matches!(reply, Ok(Some(_))), "got {:?}", reply);
+}
+
+#[tokio::test]
+async fn test_easy_api_calls_over_tls() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed()?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?.with_tls(tls.server_config())?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+
+    let options = ConnectOptions::default().with_tls(tls.client_config());
+    let client = VstpClient::connect_tcp_with_options(addr.to_string(), options).await?;
+    let note = Note {
+        text: "encrypted".to_string(),
+    };
+    let reply: Note = client.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+
+    let plain = VstpClient::connect_tcp_with_options(
+        addr.to_string(),
+        ConnectOptions {
+            handshake_timeout: Duration::from_millis(500),
+            ..ConnectOptions::default()
+        },
+    )
+    .await;
+    assert!(plain.is_err());
+    Ok(())
+}
+
+/// Options for a TLS client asking for `server_name` with SNI
+fn sni_options(tls: &TlsConfig, server_name: &str, token: &str) -> ConnectOptions {
+    ConnectOptions {
+        auth_token: Some(token.to_string()),
+        tls_server_name: Some(server_name.to_string()),
+        ..ConnectOptions::default().with_tls(tls.client_config())
+    }
+}
+
+#[tokio::test]
+async fn test_tls_server_name_picks_the_service() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed_for(vec!["a.example".to_string(), "b.example".to_string()])?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let service = |name: &'static str, token: &str| {
+        let router = Router::new().route("whoami", move |_: Note| async move {
+            Ok(Note {
+                text: name.to_string(),
+            })
+        });
+        let options = ServerOptions {
+            auth_token: Some(token.to_string()),
+            ..ServerOptions::default()
+        };
+        (router, options)
+    };
+    let (a, a_options) = service("a", "a-secret");
+    let (b, b_options) = service("b", "b-secret");
+    let server = VstpServer::from_tcp_listener(listener)?
+        .with_tls(tls.server_config())?
+        .service_with_options("a.example", a, a_options)
+        .service_with_options("b.example", b, b_options);
+    tokio::spawn(server.serve_services());
+
+    // No service header: the server name alone picks B
+    let options = sni_options(&tls, "b.example", "b-secret");
+    let client = VstpClient::connect_tcp_with_options(addr.clone(), options).await?;
+    let hello = Note {
+        text: "hello".to_string(),
+    };
+    let reply: Note = client.call("whoami", hello.clone()).await?;
+    assert_eq!(reply.text, "b");
+
+    // A's credentials don't open a session at B's name
+    let options = sni_options(&tls, "b.example", "a-secret");
+    let crossed = VstpClient::connect_tcp_with_options(addr.clone(), options).await;
+    match crossed {
+        Err(VstpError::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, error_codes::UNAUTHORIZED)
+        }
+        other => panic!("expected HandshakeRejected, got {:?}", other.map(|_| ())),
+    }
+
+    // Nor can a HELLO at B's name switch to A
+    let switched = VstpClient::connect_tcp_with_options(
+        addr.clone(),
+        ConnectOptions {
+            service: Some("a.example".to_string()),
+            ..sni_options(&tls, "b.example", "a-secret")
+        },
+    )
+    .await;
+    match switched {
+        Err(VstpError::HandshakeRejected { code, .. }) => {
+            assert_eq!(code, error_codes::SERVICE_NOT_FOUND)
+        }
+        other => panic!("expected HandshakeRejected, got {:?}", other.map(|_| ())),
+    }
+
+    let options = sni_options(&tls, "a.example", "a-secret");
+    let client = VstpClient::connect_tcp_with_options(addr, options).await?;
+    let reply: Note = client.call("whoami", hello).await?;
+    assert_eq!(reply.text, "a");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_handshake_failures_have_their_own_error() -> Result<(), VstpError> {
+    // A TLS client talking to a plain TCP server
+    let tls = TlsConfig::self_signed()?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+
+    match VstpClient::connect_tcp_tls(addr.to_string(), tls.client_config()).await {
+        Err(VstpError::TlsHandshake(_)) => {}
+        other => panic!("expected a handshake error, got {:?}", other.err()),
+    }
+
+    // A refused certificate is refused again on retry
+    let tls_server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config()).await?;
+    let addr = tls_server.local_addr()?;
+    echo(tls_server);
+    let stranger = TlsConfig::self_signed()?;
+    let refused = VstpClient::connect_tcp_tls(addr.to_string(), stranger.client_config())
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(refused, VstpError::TlsHandshake(_)));
+    assert!(!refused.is_retryable());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stalled_tls_handshake_has_its_own_timeout() -> Result<(), VstpError> {
+    // Accepts connections but never answers the ClientHello
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = silent.local_addr()?;
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = silent.accept().await {
+            held.push(socket);
+        }
+    });
+
+    let tls = TlsConfig::self_signed()?;
+    let options = ConnectOptions {
+        tls_handshake_timeout: Duration::from_millis(200),
+        handshake_timeout: Duration::from_secs(10),
+        ..ConnectOptions::default().with_tls(tls.client_config())
+    };
+    match VstpClient::connect_tcp_with_options(addr.to_string(), options).await {
+        Err(VstpError::TlsHandshakeTimeout { after }) => {
+            assert_eq!(after, Duration::from_millis(200))
+        }
+        other => panic!("expected TlsHandshakeTimeout, got {:?}", other.err()),
+    }
+    Ok(())
+}
+
+/// A server config for a fresh certificate speaking only `versions`
+fn server_config_with(
+    versions: &[&'static rustls::SupportedProtocolVersion],
+) -> rustls::ServerConfig {
+    let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = rustls::pki_types::PrivatePkcs8KeyDer::from(issued.key_pair.serialize_der());
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![issued.cert.der().clone()], key.into())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_server_refuses_configs_below_tls13() {
+    for versions in [
+        &[&rustls::version::TLS12][..],
+        &[&rustls::version::TLS13, &rustls::version::TLS12][..],
+    ] {
+        let config = server_config_with(versions);
+        match VstpTcpServer::bind_tls("127.0.0.1:0", config).await {
+            Err(VstpError::Tls(message)) => assert!(message.contains("TLS 1.3"), "{}", message),
+            other => panic!("expected a TLS error, got {:?}", other.err()),
+        }
+    }
+
+    let server = VstpTcpServer::bind("127.0.0.1:0").await.unwrap();
+    let tls12 = Arc::new(server_config_with(&[&rustls::version::TLS12]));
+    assert!(matches!(server.set_tls(Some(tls12)), Err(VstpError::Tls(_))));
+    let tls13 = Arc::new(server_config_with(&[&rustls::version::TLS13]));
+    server.set_tls(Some(tls13)).unwrap();
+}
+
+#[tokio::test]
+async fn test_server_refuses_alpn_without_vstp() {
+    let tls = TlsConfig::self_signed().unwrap();
+    let mut config = (*tls.server_config()).clone();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    match VstpTcpServer::bind_tls("127.0.0.1:0", config.clone()).await {
+        Err(VstpError::Tls(message)) => assert!(message.contains("vstp"), "{}", message),
+        other => panic!("expected a TLS error, got {:?}", other.err()),
+    }
+
+    // Offering vstp among others is fine
+    config.alpn_protocols.push(vstp::tcp::tls::ALPN_PROTOCOL.to_vec());
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", config).await.unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    echo(server);
+    let mut client = VstpTcpClient::connect_tls(&addr, tls.client_config())
+        .await
+        .unwrap();
+    assert_eq!(round_trip(&mut client, b"negotiated").await, b"negotiated");
+}
+
+/// A client config speaking only `versions` and trusting no one
+fn client_config_with(
+    versions: &[&'static rustls::SupportedProtocolVersion],
+) -> Arc<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+#[tokio::test]
+async fn test_client_refuses_configs_below_tls13() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed()?;
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config()).await?;
+    let addr = server.local_addr()?.to_string();
+    echo(server);
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+
+    for versions in [
+        &[&rustls::version::TLS12][..],
+        &[&rustls::version::TLS13, &rustls::version::TLS12][..],
+    ] {
+        let config = client_config_with(versions);
+        let refused = [
+            VstpTcpClient::connect_tls(&addr, config.clone()).await.err(),
+            VstpTcpClient::connect_tls_with_config(&addr, name.clone(), config.clone())
+                .await
+                .err(),
+        ];
+        for error in refused {
+            match error {
+                Some(VstpError::Tls(message)) => {
+                    assert!(message.contains("TLS 1.3"), "{}", message)
+                }
+                other => panic!("expected a TLS error, got {:?}", other),
+            }
+        }
+        let options = ConnectOptions::default().with_tls(config);
+        assert!(matches!(
+            VstpClient::connect_tcp_with_options(addr.clone(), options).await,
+            Err(VstpError::Tls(_))
+        ));
+    }
+
+    let mut alpn = (*client_config_with(&[&rustls::version::TLS13])).clone();
+    alpn.alpn_protocols = vec![b"h2".to_vec()];
+    match VstpTcpClient::connect_tls(&addr, alpn).await {
+        Err(VstpError::Tls(message)) => assert!(message.contains("vstp"), "{}", message),
+        other => panic!("expected a TLS error, got {:?}", other.err()),
+    }
+
+    // A TLS 1.3 config gets as far as the handshake, which fails on the
+    // certificate it doesn't trust
+    let tls13 = client_config_with(&[&rustls::version::TLS13]);
+    assert!(matches!(
+        VstpTcpClient::connect_tls_with_config(&addr, name, tls13).await,
+        Err(VstpError::TlsHandshake(_))
+    ));
+    Ok(())
+}
+
+/// A fresh certificate for `localhost` as PEM chain and key, with a client
+/// config pinning it
+fn pem_certificate() -> (String, String, rustls::ClientConfig) {
+    let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let client = vstp::tcp::tls::pinned_client_config([issued.cert.der().clone()]).unwrap();
+    (issued.cert.pem(), issued.key_pair.serialize_pem(), client)
+}
+
+#[tokio::test]
+async fn test_watched_pem_files_are_reloaded_for_new_connections() {
+    let dir = std::env::temp_dir().join(format!("vstp-tls-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (chain_path, key_path) = (dir.join("chain.pem"), dir.join("key.pem"));
+    let (chain, key, old_client) = pem_certificate();
+    std::fs::write(&chain_path, &chain).unwrap();
+    std::fs::write(&key_path, &key).unwrap();
+
+    let config = vstp::tcp::tls::server_config(chain.as_bytes(), key.as_bytes()).unwrap();
+    let server = Arc::new(VstpTcpServer::bind_tls("127.0.0.1:0", config).await.unwrap());
+    let addr = server.local_addr().unwrap().to_string();
+    let watcher = vstp::tcp::tls::watch_pem_files(
+        server.clone(),
+        &chain_path,
+        &key_path,
+        Duration::from_millis(20),
+    );
+    let accepting = server.clone();
+    tokio::spawn(async move {
+        while let Ok(mut conn) = accepting.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    let reply = Frame::new(FrameType::Data).with_payload(frame.payload);
+                    let _ = conn.send(reply).await;
+                }
+            });
+        }
+    });
+    let old_client = Arc::new(old_client);
+    let mut old_session = VstpTcpClient::connect_tls(&addr, old_client.clone())
+        .await
+        .unwrap();
+
+    // Renew the certificate on disk
+    let (chain, key, new_client) = pem_certificate();
+    std::fs::write(&key_path, &key).unwrap();
+    std::fs::write(&chain_path, &chain).unwrap();
+    let new_client = Arc::new(new_client);
+    let mut new_session = None;
+    for _ in 0..100 {
+        if let Ok(client) = VstpTcpClient::connect_tls(&addr, new_client.clone()).await {
+            new_session = Some(client);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut new_session = new_session.expect("the renewed certificate is served");
+    assert_eq!(round_trip(&mut new_session, b"renewed").await, b"renewed");
+    assert_eq!(round_trip(&mut old_session, b"still up").await, b"still up");
+    assert!(matches!(
+        VstpTcpClient::connect_tls(&addr, old_client.clone()).await,
+        Err(VstpError::TlsHandshake(_))
+    ));
+
+    // Garbage on disk keeps the renewed certificate in service
+    std::fs::write(&chain_path, "not a certificate").unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut client = VstpTcpClient::connect_tls(&addr, new_client).await.unwrap();
+    assert_eq!(round_trip(&mut client, b"kept").await, b"kept");
+
+    watcher.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A certificate for `localhost` valid until midnight UTC on `year-05-17`,
+/// as PEM chain and key, and that time
+fn pem_certificate_until(year: i32) -> (String, String, SystemTime) {
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+    params.not_after = rcgen::date_time_ymd(year, 5, 17);
+    let expires = UNIX_EPOCH + Duration::from_secs(params.not_after.unix_timestamp() as u64);
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = params.self_signed(&key).unwrap();
+    (cert.pem(), key.serialize_pem(), expires)
+}
+
+async fn expiry_becomes(watcher: &PemWatcher, expected: SystemTime) {
+    timeout(Duration::from_secs(5), async {
+        while watcher.cert_not_after() != Some(expected) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("expiry stuck at {:?}", watcher.cert_not_after()));
+}
+
+#[tokio::test]
+async fn test_pem_watcher_reports_when_the_loaded_certificate_expires() {
+    let dir = std::env::temp_dir().join(format!("vstp-tls-expiry-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (chain_path, key_path) = (dir.join("chain.pem"), dir.join("key.pem"));
+    let (chain, key, first_expiry) = pem_certificate_until(2031);
+    std::fs::write(&chain_path, &chain).unwrap();
+    std::fs::write(&key_path, &key).unwrap();
+
+    let server = Arc::new(VstpTcpServer::bind("127.0.0.1:0").await.unwrap());
+    let watcher = vstp::tcp::tls::watch_pem_files(
+        server,
+        &chain_path,
+        &key_path,
+        Duration::from_millis(20),
+    );
+    expiry_becomes(&watcher, first_expiry).await;
+
+    let (chain, key, renewed_expiry) = pem_certificate_until(2032);
+    std::fs::write(&key_path, &key).unwrap();
+    std::fs::write(&chain_path, &chain).unwrap();
+    expiry_becomes(&watcher, renewed_expiry).await;
+
+    // A chain that doesn't load leaves the one in use, and its expiry
+    std::fs::write(&chain_path, "not a certificate").unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(watcher.cert_not_after(), Some(renewed_expiry));
+
+    watcher.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_watched_pem_files_drive_a_pool() -> Result<(), VstpError> {
+    let dir = std::env::temp_dir().join(format!("vstp-tls-pool-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (chain_path, key_path) = (dir.join("chain.pem"), dir.join("key.pem"));
+    let (retired_chain, retired_key, _) = pem_certificate_until(2031);
+    std::fs::write(&chain_path, &retired_chain).unwrap();
+    std::fs::write(&key_path, &retired_key).unwrap();
+
+    let (chain, key, _) = pem_certificate_until(2032);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server_config = vstp::tcp::tls::server_config(chain.as_bytes(), key.as_bytes())?;
+    let server = VstpServer::from_tcp_listener(listener)?.with_tls(server_config)?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+
+    // The pool pins whatever certificate is on disk
+    let pool = Arc::new(VstpClientPool::with_config(
+        addr.to_string(),
+        ConnectOptions::default(),
+        PoolConfig {
+            retry_backoff: Duration::from_millis(20),
+            ..PoolConfig::default()
+        },
+    ));
+    let reloaded = pool.clone();
+    let watcher = vstp::tcp::tls::watch_pem_files_with(
+        &chain_path,
+        &key_path,
+        Duration::from_millis(20),
+        move |chain, _key| {
+            let pins = CertificateDer::pem_slice_iter(chain)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| VstpError::Tls(e.to_string()))?;
+            reloaded.set_tls(vstp::tcp::tls::pinned_client_config(pins)?);
+            Ok(())
+        },
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(matches!(pool.warm(1).await, Err(VstpError::TlsHandshake(_))));
+
+    std::fs::write(&key_path, &key).unwrap();
+    std::fs::write(&chain_path, &chain).unwrap();
+    timeout(Duration::from_secs(5), async {
+        while pool.stats().warm < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("a replacement connected with the renewed pin");
+    let note = Note {
+        text: "rotated".to_string(),
+    };
+    let reply: Note = pool.get().await?.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+
+    watcher.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_replacements_use_the_new_tls_config() -> Result<(), VstpError> {
+    let renewed = TlsConfig::self_signed()?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?.with_tls(renewed.server_config())?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+
+    // Still trusting the certificate the server has moved on from
+    let retired = TlsConfig::self_signed()?;
+    let options = ConnectOptions::default().with_tls(retired.client_config());
+    let config = PoolConfig {
+        retry_backoff: Duration::from_millis(20),
+        ..PoolConfig::default()
+    };
+    let pool = VstpClientPool::with_config(addr.to_string(), options, config);
+    assert!(matches!(pool.warm(1).await, Err(VstpError::TlsHandshake(_))));
+
+    pool.set_tls(renewed.client_config());
+    timeout(Duration::from_secs(5), async {
+        while pool.stats().warm < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("a replacement connected with the new config");
+    let note = Note {
+        text: "rotated".to_string(),
+    };
+    let reply: Note = pool.get().await?.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pool_resumes_tls_sessions_for_replacements() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed()?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?.with_tls(tls.server_config())?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+
+    let options = ConnectOptions::default().with_tls(tls.client_config());
+    let pool = VstpClientPool::new(addr.to_string(), options);
+    pool.warm(1).await?;
+    let first = pool.get().await?;
+    assert!(!first.tls_resumed());
+
+    // The replacement reuses the session the first connection was handed
+    let resumed = PoolStats {
+        warm: 1,
+        cold_handshakes: 1,
+        resumed_handshakes: 1,
+    };
+    timeout(Duration::from_secs(5), async {
+        while pool.stats() != resumed {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("pool stuck at {:?}", pool.stats()));
+    let second = pool.get().await?;
+    assert!(second.tls_resumed());
+    let note = Note {
+        text: "resumed".to_string(),
+    };
+    let reply: Note = second.call("notes.echo", note.clone()).await?;
+    assert_eq!(reply, note);
+    Ok(())
+}
+
+/// Answer every DATA frame with the value of its early data marker, `-`
+/// without one
+fn report_early_data(server: VstpTcpServer) {
+    tokio::spawn(async move {
+        while let Ok(mut conn) = server.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    if frame.typ == FrameType::Data {
+                        let marker = frame.get_header(EARLY_DATA_HEADER).unwrap_or("-");
+                        let reply = Frame::new(FrameType::Data).with_payload(marker.into());
+                        let _ = conn.send(reply).await;
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Send a DATA frame and return the server's answer
+async fn early_data_marker(client: &mut VstpTcpClient) -> String {
+    client.send_data(b"ping".to_vec()).await.unwrap();
+    let reply = timeout(Duration::from_secs(5), client.recv())
+        .await
+        .expect("no answer")
+        .unwrap()
+        .expect("connection closed");
+    String::from_utf8(reply.payload.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn test_server_marks_frames_sent_as_early_data() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed()?;
+    let config = TcpServerConfig {
+        tls: Some(tls.server_config()),
+        accept_early_data: true,
+        ..TcpServerConfig::default()
+    };
+    let server = VstpTcpServer::bind_with_config("127.0.0.1:0", config).await?;
+    let addr = server.local_addr()?.to_string();
+    report_early_data(server);
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+    let client_config = tls.client_config();
+
+    // Without a session to resume there is nothing to send early
+    let mut first =
+        VstpTcpClient::connect_tls_with_early_data(&addr, name.clone(), client_config.clone())
+            .await?;
+    assert!(!first.tls_resumed());
+    assert_eq!(early_data_marker(&mut first).await, "-");
+
+    // The resumed connection sends its first frame with the ClientHello
+    let mut second =
+        VstpTcpClient::connect_tls_with_early_data(&addr, name.clone(), client_config.clone())
+            .await?;
+    assert!(second.tls_resumed());
+    assert_eq!(early_data_marker(&mut second).await, "1");
+    assert_eq!(early_data_marker(&mut second).await, "-");
+
+    // Clients that don't ask for early data wait out the handshake
+    let mut third = VstpTcpClient::connect_tls_with_config(&addr, name, client_config).await?;
+    assert!(third.tls_resumed());
+    assert_eq!(early_data_marker(&mut third).await, "-");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_early_data_waits_for_the_handshake_unless_accepted() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed()?;
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config()).await?;
+    let addr = server.local_addr()?.to_string();
+    report_early_data(server);
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+
+    for _ in 0..2 {
+        let mut client =
+            VstpTcpClient::connect_tls_with_early_data(&addr, name.clone(), tls.client_config())
+                .await?;
+        assert_eq!(early_data_marker(&mut client).await, "-");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_easy_client_sends_its_hello_as_early_data() -> Result<(), VstpError> {
+    let tls = TlsConfig::self_signed()?;
+    let mut server_config = (*tls.server_config()).clone();
+    server_config.max_early_data_size = 16 * 1024;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = VstpServer::from_tcp_listener(listener)?.with_tls(server_config)?;
+    let router = Router::new().route("notes.echo", |note: Note| async move { Ok(note) });
+    tokio::spawn(server.serve_router(router));
+
+    let options = ConnectOptions {
+        allow_early_data: true,
+        ..ConnectOptions::default().with_tls(tls.client_config())
+    };
+    for resumed in [false, true] {
+        let client = VstpClient::connect_tcp_with_options(addr.to_string(), options.clone()).await?;
+        assert_eq!(client.tls_resumed(), resumed);
+        let note = Note {
+            text: "early".to_string(),
+        };
+        let reply: Note = client.call("notes.echo", note.clone()).await?;
+        assert_eq!(reply, note);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_self_test_runs_the_tls_step() {
+    use vstp::diagnostics::{self, health_reply, DiagnosticOptions, Endpoint, StepStatus};
+
+    let tls = TlsConfig::self_signed().unwrap();
+    let server = VstpTcpServer::bind_tls("127.0.0.1:0", tls.server_config())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok(mut conn) = server.accept().await {
+            tokio::spawn(async move {
+                while let Ok(Some(frame)) = conn.recv().await {
+                    if let Some(reply) = health_reply(&frame) {
+                        let _ = conn.send(reply).await;
+                    }
+                }
+            });
+        }
+    });
+
+    let opts = DiagnosticOptions {
+        tls: Some(tls.client_config()),
+        ..DiagnosticOptions::default()
+    };
+    let report = diagnostics::self_test(Endpoint::Tcp(addr), opts).await;
+    assert!(report.passed(), "{}", report);
+    for name in ["tcp-connect", "tls-handshake", "handshake", "ping", "echo"] {
+        assert!(report.step(name).unwrap().passed(), "step {} failed", name);
+    }
+
+    let stranger = TlsConfig::self_signed().unwrap();
+    let opts = DiagnosticOptions {
+        tls: Some(stranger.client_config()),
+        ..DiagnosticOptions::default()
+    };
+    let report = diagnostics::self_test(Endpoint::Tcp(addr), opts).await;
+    assert!(!report.passed());
+    assert!(matches!(
+        report.step("tls-handshake").unwrap().status,
+        StepStatus::Failed(VstpError::TlsHandshake(_))
+    ));
+    assert!(matches!(
+        report.step("handshake").unwrap().status,
+        StepStatus::Skipped(_)
+    ));
+}
@@ -0,0 +1,137 @@
+//! Tests for frame TTL handling in queues, retransmission and servers
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{advance, timeout, Instant};
+use vstp::{
+    types::{EXPIRES_AT_MS_HEADER, TTL_MS_HEADER},
+    udp::{client::UdpConfig, UdpServerConfig, VstpUdpClient, VstpUdpServer},
+    try_decode_frame, Flags, Frame, FrameType, VstpError,
+};
+
+#[tokio::test(start_paused = true)]
+async fn test_queued_frame_expires() {
+    let frame = Frame::new(FrameType::Data)
+        .with_payload(b"cpu=93%".to_vec())
+        .with_ttl(Duration::from_secs(5));
+    assert_eq!(frame.ttl(), Some(Duration::from_secs(5)));
+    assert!(frame.expires_at().is_some());
+
+    // The frame sits in a queue after being received
+    let received_at = Instant::now();
+    advance(Duration::from_secs(4)).await;
+    assert!(!frame.is_expired(received_at));
+    advance(Duration::from_secs(2)).await;
+    assert!(frame.is_expired(received_at));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_ttl_preferred_over_absolute_expiry() {
+    // A peer with a clock far behind ours: the absolute expiry is long past
+    let frame = Frame::new(FrameType::Data)
+        .with_header(TTL_MS_HEADER, "1000")
+        .with_header(EXPIRES_AT_MS_HEADER, "1");
+    assert!(!frame.is_expired(Instant::now()));
+
+    let frame = Frame::new(FrameType::Data).with_header(EXPIRES_AT_MS_HEADER, "1");
+    assert!(frame.is_expired(Instant::now()));
+
+    assert!(!Frame::new(FrameType::Data).is_expired(Instant::now()));
+}
+
+#[test]
+fn test_with_ttl_replaces_previous_ttl() {
+    let frame = Frame::new(FrameType::Data)
+        .with_ttl(Duration::from_secs(10))
+        .with_ttl(Duration::from_millis(250));
+    let ttl_headers = frame
+        .headers
+        .iter()
+        .filter(|h| h.key == TTL_MS_HEADER.as_bytes())
+        .count();
+    assert_eq!(ttl_headers, 1);
+    assert_eq!(frame.get_header(TTL_MS_HEADER), Some("250"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_retransmission_stops_when_frame_expires() {
+    // A peer that never ACKs
+    let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sink_addr = sink.local_addr().unwrap();
+
+    let config = UdpConfig {
+        max_retries: 10,
+        retry_delay: Duration::from_millis(100),
+        max_retry_delay: Duration::from_millis(100),
+        ack_timeout: Duration::from_secs(1),
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+
+    let frame = Frame::new(FrameType::Data)
+        .with_payload(b"stale soon".to_vec())
+        .with_ttl(Duration::from_millis(2500));
+    let start = Instant::now();
+    let result = client.send_with_ack(frame, sink_addr).await;
+    assert!(matches!(result, Err(VstpError::Expired)), "{:?}", result);
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    // Far fewer than the 11 attempts allowed, each carrying less time than the last
+    let mut buf = vec![0u8; 2048];
+    let mut ttls = Vec::new();
+    while let Ok((len, _)) = sink.try_recv_from(&mut buf) {
+        let mut data = bytes::BytesMut::from(&buf[..len]);
+        let sent = try_decode_frame(&mut data, 65536).unwrap().unwrap();
+        ttls.push(sent.ttl().unwrap());
+    }
+    assert!((2..=3).contains(&ttls.len()), "{:?}", ttls);
+    assert!(ttls.windows(2).all(|w| w[1] < w[0]), "{:?}", ttls);
+}
+
+#[tokio::test]
+async fn test_udp_server_drops_expired_frames() {
+    let config = UdpServerConfig {
+        drop_expired: true,
+        ..UdpServerConfig::default()
+    };
+    let server = Arc::new(
+        VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addr().unwrap();
+
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let expired = Frame::new(FrameType::Data)
+        .with_payload(b"expired".to_vec())
+        .with_header(EXPIRES_AT_MS_HEADER, "1")
+        .with_header("msg-id", "7")
+        .with_flag(Flags::REQ_ACK);
+    let fresh = Frame::new(FrameType::Data)
+        .with_payload(b"fresh".to_vec())
+        .with_ttl(Duration::from_secs(30));
+
+    let receiver = server.clone();
+    let recv_handle = tokio::spawn(async move { receiver.recv().await });
+
+    // The expired frame is still ACKed so the sender stops retrying
+    client.send(expired, server_addr).await.unwrap();
+    let (ack, _) = timeout(Duration::from_secs(2), client.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(ack.typ, FrameType::Ack);
+    assert_eq!(ack.get_header("msg-id"), Some("7"));
+    client.send(fresh, server_addr).await.unwrap();
+
+    let (frame, _) = timeout(Duration::from_secs(2), recv_handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.payload, b"fresh");
+    assert_eq!(server.expired_frame_count(), 1);
+}
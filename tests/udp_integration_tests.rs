@@ -1,8 +1,30 @@
 //! Integration tests for VSTP UDP functionality
 
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::timeout;
-use vstp::{udp::{VstpUdpClient, VstpUdpServer}, types::FrameType};
+use vstp::{
+    chunk::{ChunkEvent, ChunkReceiver, ChunkSender},
+    encode_frame,
+    ingress::IngressPolicy,
+    try_decode_frame,
+    testing::{spawn_udp_server, LossConfig, LossyUdpProxy},
+    types::{Flags, Frame, FrameType, Priority, VstpError},
+    udp::{
+        client::{RetryBackoff, UdpConfig},
+        reassembly::{fragment_frame, reassembled_from, ReassemblyProgress, MAX_DATAGRAM_SIZE},
+        reflector::{ECHO_HEADER, PADDING_HEADER, REFLECTED_AT_MS_HEADER},
+        acks::{acknowledges, decode_msg_ids},
+        server::UdpServerConfig,
+        AckSource, DedupConfig, DestinationGroup, FailoverEvent, FailoverPolicy, MemoryDedupStore,
+        OverflowPolicy, PathProbeConfig, ReflectorConfig, ShardStrategy, VstpUdpClient,
+        VstpUdpServer, MSG_IDS_HEADER, OBSERVED_ADDR_HEADER,
+    },
+    easy::TransportKind,
+    WireTap,
+};
 
 #[tokio::test]
 async fn test_udp_client_server_communication() {
@@ -75,6 +97,35 @@ async fn test_udp_ack_reliability() {
     server_handle.abort();
 }
 
+#[tokio::test]
+async fn test_udp_fragments_carry_consecutive_chunks() {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+
+    // No byte repeats within a chunk, so a repeated or misplaced chunk shows
+    let payload: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    let request = vstp::Frame::new(FrameType::Data)
+        .with_header("kind", "bulk")
+        .with_payload(payload.clone());
+    client.send(request, server_addr).await.unwrap();
+    let (received, client_addr) = timeout(Duration::from_secs(5), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.payload, payload);
+    assert_eq!(received.get_header("kind"), Some("bulk"));
+
+    // The server fragments large frames on the way back too
+    let reply = vstp::Frame::new(FrameType::Data).with_payload(payload.clone());
+    server.send(reply, client_addr).await.unwrap();
+    let (echoed, _) = timeout(Duration::from_secs(5), client.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed.payload, payload);
+}
+
 #[tokio::test]
 async fn test_udp_fragmentation() {
     // Start a UDP server
@@ -109,6 +160,108 @@ async fn test_udp_fragmentation() {
     server_handle.abort();
 }
 
+#[tokio::test]
+async fn test_udp_reassembly_progress() {
+    let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let events = progress.clone();
+    let config = UdpServerConfig {
+        on_reassembly_progress: Some(ReassemblyProgress::new(move |peer, id, received, total| {
+            events.lock().unwrap().push((peer, id, received, total));
+        })),
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let frame = Frame::new(FrameType::Data).with_payload(vec![7u8; 4000]);
+    let total = fragment_frame(&frame, 0).unwrap().len() as u8;
+    assert!(total > 3);
+    client.send(frame, server_addr).await.unwrap();
+
+    let (received, _) = timeout(Duration::from_secs(5), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.payload.len(), 4000);
+
+    // One event per fragment, the last one before the frame was delivered
+    let events = progress.lock().unwrap().clone();
+    let counts: Vec<u8> = events.iter().map(|event| event.2).collect();
+    assert_eq!(counts, (1..=total).collect::<Vec<_>>());
+    let frag_id = events[0].1;
+    assert!(events
+        .iter()
+        .all(|event| (event.0, event.1, event.3) == (client_addr, frag_id, total)));
+}
+
+#[test]
+fn test_fragment_accessors() {
+    let frame = Frame::new(FrameType::Data)
+        .with_header("key", "value")
+        .with_payload(vec![0x42u8; 3000]);
+    assert!(!frame.is_fragment());
+    assert_eq!(frame.fragment_id(), None);
+
+    let fragments = fragment_frame(&frame, 7).unwrap();
+    assert!(fragments.len() > 1);
+    for (i, fragment) in fragments.iter().enumerate() {
+        assert!(fragment.is_fragment());
+        assert_eq!(fragment.fragment_id(), Some(7));
+        assert_eq!(fragment.fragment_index(), Some(i as u8));
+        assert_eq!(fragment.fragment_total(), Some(fragments.len() as u8));
+        assert_eq!(fragment.get_header("key"), Some("value"));
+    }
+
+    // All three headers must be present and numeric
+    let partial = Frame::new(FrameType::Data)
+        .with_header("frag-id", "1")
+        .with_header("frag-index", "x")
+        .with_header("frag-total", "2");
+    assert!(!partial.is_fragment());
+    assert_eq!(partial.fragment_id(), Some(1));
+}
+
+#[tokio::test]
+async fn test_fragments_fit_a_datagram_with_their_headers() {
+    let payload: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    let mut frame = Frame::new(FrameType::Data).with_payload(payload);
+    for i in 0..8 {
+        frame = frame.with_header(&format!("meta-{}", i), &"x".repeat(60));
+    }
+
+    let fragments = fragment_frame(&frame, 9).unwrap();
+    assert!(fragments.len() > 1);
+    for fragment in &fragments {
+        let encoded = encode_frame(fragment).unwrap();
+        assert!(encoded.len() <= MAX_DATAGRAM_SIZE, "fragment is {} bytes", encoded.len());
+    }
+
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    client.send(frame.clone(), server_addr).await.unwrap();
+
+    let (received, _) = timeout(Duration::from_secs(5), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.payload, frame.payload);
+    assert_eq!(received.get_header("meta-7"), frame.get_header("meta-7"));
+}
+
+#[test]
+fn test_fragment_frame_refuses_headers_that_fill_a_datagram() {
+    let mut frame = Frame::new(FrameType::Data).with_payload(vec![0u8; 2000]);
+    for i in 0..5 {
+        frame = frame.with_header(&format!("big-{}", i), &"y".repeat(250));
+    }
+    assert!(matches!(fragment_frame(&frame, 0), Err(VstpError::Protocol(_))));
+}
+
 #[tokio::test]
 async fn test_udp_multiple_clients() {
     // Start a UDP server
@@ -142,3 +295,1310 @@ async fn test_udp_multiple_clients() {
     // Stop the server
     server_handle.abort();
 }
+
+#[tokio::test]
+async fn test_udp_ack_and_response_ack_only() {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Server only ACKs, never responds
+    let server_handle = tokio::spawn(async move {
+        server.run(|_addr, _frame| async move {}).await.unwrap();
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let request = vstp::Frame::new(FrameType::Data).with_payload(b"ping?".to_vec());
+
+    let response = client
+        .send_with_ack_and_response(request, server_addr, Duration::from_millis(300))
+        .await
+        .unwrap();
+    assert!(response.is_none(), "no response was sent, only the ACK");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_udp_ack_then_response() {
+    let server = Arc::new(VstpUdpServer::bind("127.0.0.1:0").await.unwrap());
+    let server_addr = server.local_addr().unwrap();
+
+    // Server ACKs on receipt, then sends the response as a separate frame
+    let responder = server.clone();
+    let server_handle = tokio::spawn(async move {
+        while let Ok((frame, addr)) = responder.recv().await {
+            let response = vstp::Frame::new(FrameType::Data).with_payload(b"pong".to_vec());
+            responder.respond(&frame, response, addr).await.unwrap();
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let request = vstp::Frame::new(FrameType::Data)
+        .with_header("request-id", "req-1")
+        .with_payload(b"ping?".to_vec());
+
+    let response = client
+        .send_with_ack_and_response(request, server_addr, Duration::from_secs(2))
+        .await
+        .unwrap()
+        .expect("response should arrive after the ACK");
+    assert_eq!(response.payload, b"pong");
+    assert_eq!(response.get_header("request-id"), Some("req-1"));
+    assert_eq!(response.get_header("ack-for"), None);
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_udp_piggybacked_response() {
+    let config = UdpServerConfig {
+        auto_ack: false,
+        ..UdpServerConfig::default()
+    };
+    let server = Arc::new(
+        VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addr().unwrap();
+
+    // No standalone ACK: the response itself acknowledges the request
+    let responder = server.clone();
+    let server_handle = tokio::spawn(async move {
+        while let Ok((frame, addr)) = responder.recv().await {
+            let response = vstp::Frame::new(FrameType::Data).with_payload(b"pong".to_vec());
+            responder.respond(&frame, response, addr).await.unwrap();
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let request = vstp::Frame::new(FrameType::Data).with_payload(b"ping?".to_vec());
+
+    let response = client
+        .send_with_ack_and_response(request, server_addr, Duration::from_secs(2))
+        .await
+        .unwrap()
+        .expect("piggybacked response should be returned");
+    assert_eq!(response.typ, FrameType::Data);
+    assert_eq!(response.payload, b"pong");
+    assert_eq!(response.get_header("ack-for"), response.get_header("msg-id"));
+    assert!(response.get_header("ack-for").is_some());
+
+    // A plain send_with_ack is satisfied by the piggybacked response as well
+    let request = vstp::Frame::new(FrameType::Data).with_payload(b"again".to_vec());
+    timeout(Duration::from_secs(2), client.send_with_ack(request, server_addr))
+        .await
+        .unwrap()
+        .unwrap();
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_udp_oversized_datagram_is_counted_not_delivered() {
+    let config = UdpServerConfig {
+        recv_buffer_size: 512,
+        ..UdpServerConfig::default()
+    };
+    let server = Arc::new(
+        VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addr().unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = server.clone();
+    let server_handle = tokio::spawn(async move {
+        while let Ok((frame, _addr)) = receiver.recv().await {
+            tx.send(frame).unwrap();
+        }
+    });
+
+    // A valid frame that doesn't fit the 512-byte receive buffer, sent raw so
+    // the client doesn't fragment it
+    let oversized = vstp::Frame::new(FrameType::Data).with_payload(vec![0x42; 1000]);
+    let encoded = vstp::encode_frame(&oversized).unwrap();
+    let raw = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    raw.send_to(&encoded, server_addr).unwrap();
+
+    // A frame that fits is still delivered afterwards
+    let small = vstp::Frame::new(FrameType::Data).with_payload(b"fits".to_vec());
+    raw.send_to(&vstp::encode_frame(&small).unwrap(), server_addr)
+        .unwrap();
+
+    let delivered = timeout(Duration::from_secs(2), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(delivered.payload, b"fits");
+    assert_eq!(server.truncated_datagram_count(), 1);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(rx.try_recv().is_err(), "oversized frame must not be delivered");
+
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_udp_workers_keep_peer_order() {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = seen.clone();
+    let server_handle = tokio::spawn(async move {
+        server
+            .run_workers(4, ShardStrategy::PeerHash, move |_addr, frame| {
+                let recorder = recorder.clone();
+                async move {
+                    let index: u64 = frame.get_header("seq").unwrap().parse().unwrap();
+                    // Early frames take longest, so any concurrency would reorder them
+                    tokio::time::sleep(Duration::from_millis(20 - index)).await;
+                    recorder.lock().unwrap().push(index);
+                }
+            })
+            .await
+            .unwrap();
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    for i in 0..20u64 {
+        let frame = vstp::Frame::new(FrameType::Data).with_header("seq", &i.to_string());
+        client.send(frame, server_addr).await.unwrap();
+    }
+
+    timeout(Duration::from_secs(5), async {
+        while seen.lock().unwrap().len() < 20 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(*seen.lock().unwrap(), (0..20).collect::<Vec<_>>());
+
+    server_handle.abort();
+}
+
+#[test]
+fn test_shard_strategy_assignment() {
+    let peer: std::net::SocketAddr = "10.0.0.7:4000".parse().unwrap();
+    let worker = ShardStrategy::PeerHash.worker_for(peer, 0, 8);
+    assert!(worker < 8);
+    for sequence in 1..100 {
+        assert_eq!(ShardStrategy::PeerHash.worker_for(peer, sequence, 8), worker);
+    }
+
+    let assigned: Vec<_> = (0..6)
+        .map(|sequence| ShardStrategy::RoundRobin.worker_for(peer, sequence, 3))
+        .collect();
+    assert_eq!(assigned, vec![0, 1, 2, 0, 1, 2]);
+}
+
+#[test]
+fn test_retry_backoff_jitter_is_seeded_and_bounded() {
+    let config = UdpConfig {
+        retry_delay: Duration::from_millis(100),
+        max_retry_delay: Duration::from_secs(5),
+        jitter_fraction: 0.25,
+        jitter_seed: Some(42),
+        ..UdpConfig::default()
+    };
+
+    let delays = |config: &UdpConfig| {
+        let mut backoff = RetryBackoff::from_config(config);
+        (0..6).map(|attempt| backoff.delay(attempt)).collect::<Vec<_>>()
+    };
+    let first = delays(&config);
+    assert_eq!(first, delays(&config));
+    assert_ne!(
+        first,
+        delays(&UdpConfig {
+            jitter_seed: Some(7),
+            ..config.clone()
+        })
+    );
+
+    for (attempt, delay) in first.iter().enumerate() {
+        let nominal = Duration::from_millis(100 * 2u64.pow(attempt as u32));
+        assert!(*delay >= nominal.mul_f64(0.75), "attempt {}: {:?}", attempt, delay);
+        assert!(*delay <= nominal.mul_f64(1.25), "attempt {}: {:?}", attempt, delay);
+    }
+
+    // Without jitter the plain exponential backoff is unchanged
+    let mut plain = RetryBackoff::from_config(&UdpConfig::default());
+    assert_eq!(plain.delay(0), Duration::from_millis(100));
+    assert_eq!(plain.delay(3), Duration::from_millis(800));
+    assert_eq!(plain.delay(10), Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_udp_reassembled_frames_are_marked() {
+    let config = UdpServerConfig {
+        mark_reassembled: true,
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let large = vstp::Frame::new(FrameType::Data).with_payload(vec![7u8; 5000]);
+    client.send(large, server_addr).await.unwrap();
+    let small = vstp::Frame::new(FrameType::Data).with_payload(b"small".to_vec());
+    client.send(small, server_addr).await.unwrap();
+
+    let (frame, _) = timeout(Duration::from_secs(2), server.recv()).await.unwrap().unwrap();
+    assert_eq!(frame.payload.len(), 5000);
+    assert_eq!(reassembled_from(&frame), Some(5));
+
+    let (frame, _) = timeout(Duration::from_secs(2), server.recv()).await.unwrap().unwrap();
+    assert_eq!(frame.payload, b"small");
+    assert_eq!(reassembled_from(&frame), None);
+}
+
+#[tokio::test]
+async fn test_udp_decode_errors_get_rate_limited_err_replies() {
+    let config = UdpServerConfig {
+        error_replies_per_sec: Some(3),
+        ..UdpServerConfig::default()
+    };
+    let server = Arc::new(
+        VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addr().unwrap();
+    let receiver = server.clone();
+    let server_handle = tokio::spawn(async move { while receiver.recv().await.is_ok() {} });
+
+    // A frame whose CRC no longer matches, then a burst of garbage
+    let mut corrupted = vstp::encode_frame(
+        &vstp::Frame::new(FrameType::Data).with_payload(b"hello".to_vec()),
+    )
+    .unwrap()
+    .to_vec();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(&corrupted, server_addr).await.unwrap();
+    for _ in 0..9 {
+        socket.send_to(b"XXnot a frame", server_addr).await.unwrap();
+    }
+
+    let mut codes = Vec::new();
+    let mut buf = vec![0u8; 2048];
+    while let Ok(received) = timeout(Duration::from_millis(300), socket.recv_from(&mut buf)).await {
+        let (len, _) = received.unwrap();
+        let mut bytes = bytes::BytesMut::from(&buf[..len]);
+        let err = vstp::try_decode_frame(&mut bytes, 65536).unwrap().unwrap();
+        assert_eq!(err.typ, FrameType::Err);
+        codes.push(err.get_header("error-code").unwrap().to_string());
+    }
+
+    assert_eq!(codes, ["BadCrc", "BadMagic", "BadMagic"]);
+    assert_eq!(server.decode_error_count(), 10);
+    server_handle.abort();
+}
+
+#[tokio::test]
+async fn test_udp_cancelled_send_stops_retransmitting() {
+    // A peer that never acknowledges and counts what it receives
+    let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    let received = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = received.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        while silent.recv_from(&mut buf).await.is_ok() {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    });
+
+    let config = UdpConfig {
+        max_retries: 100,
+        retry_delay: Duration::from_millis(10),
+        max_retry_delay: Duration::from_millis(10),
+        ack_timeout: Duration::from_millis(50),
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+
+    let frame = Frame::new(FrameType::Data).with_payload(b"stale request".to_vec());
+    let send = client.send_reliable(frame, silent_addr);
+    let handle = send.handle();
+    assert_eq!(handle.msg_id(), 1);
+    let canceller = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        handle.cancel();
+    });
+
+    let result = timeout(Duration::from_secs(2), send).await.unwrap();
+    assert!(matches!(result, Err(VstpError::Cancelled)));
+    canceller.await.unwrap();
+
+    // Let a transmission that raced the cancel land before counting
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let sent = received.load(std::sync::atomic::Ordering::SeqCst);
+    assert!(sent >= 2, "only {} transmissions", sent);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(received.load(std::sync::atomic::Ordering::SeqCst), sent);
+
+    // The client stays usable and the next send gets a fresh msg-id
+    let frame = Frame::new(FrameType::Data).with_payload(b"next".to_vec());
+    let send = client.send_reliable(frame, silent_addr);
+    assert_eq!(send.handle().msg_id(), 2);
+    send.handle().cancel();
+    assert!(matches!(send.await, Err(VstpError::Cancelled)));
+}
+
+async fn spawn_reflector(config: ReflectorConfig) -> std::net::SocketAddr {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(async move { server.run_reflector(config).await });
+    addr
+}
+
+#[tokio::test]
+async fn test_udp_reflector_path_report_over_lossy_link() {
+    let reflector = spawn_reflector(ReflectorConfig {
+        max_replies_per_peer: 10_000,
+        ..ReflectorConfig::default()
+    })
+    .await;
+    let proxy = LossyUdpProxy::start(
+        reflector,
+        LossConfig {
+            drop_rate: 0.2,
+            seed: 42,
+            ..LossConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let probes = PathProbeConfig {
+        interval: Duration::from_millis(2),
+        linger: Duration::from_millis(500),
+    };
+    let report = client
+        .measure_path_with(proxy.addr(), 200, probes)
+        .await
+        .unwrap();
+
+    // A probe is lost if either the PING or its PONG was dropped, never both
+    assert_eq!(report.sent, 200);
+    assert_eq!(report.received as u64 + proxy.dropped(), 200);
+    assert!((report.loss - proxy.dropped() as f64 / 200.0).abs() < 1e-9);
+    assert!(
+        report.loss > 0.2 && report.loss < 0.55,
+        "loss {}",
+        report.loss
+    );
+    assert_eq!(report.reorder_count, 0);
+    assert_eq!(report.rtt_histogram.len(), report.received);
+    let bucketed: usize = report.rtt_histogram.buckets().iter().map(|b| b.1).sum();
+    assert_eq!(bucketed, report.received);
+}
+
+#[tokio::test]
+async fn test_udp_reflector_refuses_amplifying_and_unmarked_frames() {
+    let reflector = spawn_reflector(ReflectorConfig {
+        max_replies_per_peer: 3,
+        ..ReflectorConfig::default()
+    })
+    .await;
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let padding = "0".repeat(64);
+
+    // Unmarked DATA and an echo that would come back larger are both ignored
+    let unmarked = Frame::new(FrameType::Data).with_payload(b"hi".to_vec());
+    let unpadded = unmarked.clone().with_header(ECHO_HEADER, "true");
+    let hello = Frame::new(FrameType::Hello);
+    client.send(hello, reflector).await.unwrap();
+    client.send(unmarked, reflector).await.unwrap();
+    client.send(unpadded, reflector).await.unwrap();
+
+    let padded = Frame::new(FrameType::Data)
+        .with_payload(b"hi".to_vec())
+        .with_header(ECHO_HEADER, "true")
+        .with_header(PADDING_HEADER, &padding);
+    client.send(padded, reflector).await.unwrap();
+    let (echo, _) = timeout(Duration::from_secs(1), client.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echo.typ, FrameType::Data);
+    assert_eq!(echo.payload, b"hi");
+    assert!(echo.get_header(REFLECTED_AT_MS_HEADER).is_some());
+    assert_eq!(echo.get_header(PADDING_HEADER), None);
+
+    // Three replies per second per peer; the echo used one of them
+    for _ in 0..5 {
+        let ping = Frame::new(FrameType::Ping).with_header(PADDING_HEADER, &padding);
+        client.send(ping, reflector).await.unwrap();
+    }
+    let mut pongs = 0;
+    while let Ok(received) = timeout(Duration::from_millis(300), client.recv()).await {
+        assert_eq!(received.unwrap().0.typ, FrameType::Pong);
+        pongs += 1;
+    }
+    assert_eq!(pongs, 2);
+}
+
+/// Send a frame asking for an ACK under `msg_id`, as a retransmitting client would
+async fn send_req_ack(client: &VstpUdpClient, msg_id: u64, dest: std::net::SocketAddr) {
+    let frame = Frame::new(FrameType::Data)
+        .with_flag(Flags::REQ_ACK)
+        .with_header("msg-id", &msg_id.to_string())
+        .with_payload(format!("command {}", msg_id).into_bytes());
+    client.send(frame, dest).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_udp_dedup_survives_server_restart() {
+    let store = Arc::new(MemoryDedupStore::new());
+    let config = || UdpServerConfig {
+        dedup: Some(DedupConfig::with_store(
+            store.clone(),
+            Duration::from_secs(60),
+        )),
+        ..UdpServerConfig::default()
+    };
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    send_req_ack(&client, 1, addr).await;
+    let (frame, _) = server.recv().await.unwrap();
+    assert_eq!(frame.payload, b"command 1");
+    drop(server);
+
+    // The restarted server shares the store and recognizes the retransmission
+    let server = VstpUdpServer::bind_with_config(&addr.to_string(), config())
+        .await
+        .unwrap();
+    send_req_ack(&client, 1, addr).await;
+    send_req_ack(&client, 2, addr).await;
+    let (frame, _) = timeout(Duration::from_secs(1), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.payload, b"command 2");
+    assert_eq!(server.duplicate_frame_count(), 1);
+    assert_eq!(store.len(), 2);
+
+    // The duplicate was still acknowledged, so the client stops retransmitting;
+    // its ACK may share a datagram with the next one's
+    let mut acked = Vec::new();
+    while acked.len() < 3 {
+        let (ack, _) = timeout(Duration::from_secs(1), client.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ack.typ, FrameType::Ack);
+        acked.extend(acked_ids(&ack));
+    }
+    assert_eq!(acked, [1, 1, 2]);
+}
+
+#[tokio::test]
+async fn test_udp_client_restarted_on_the_same_port_is_heard() {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+
+    for run in ["first run", "second run"] {
+        let frame = Frame::new(FrameType::Data).with_payload(run.as_bytes().to_vec());
+        let (sent, received) = tokio::join!(
+            client.send_with_ack(frame, addr),
+            timeout(Duration::from_secs(1), server.recv()),
+        );
+        sent.unwrap();
+        let (frame, from) = received
+            .expect("the restarted client's message was dropped")
+            .unwrap();
+        assert_eq!((frame.payload.as_slice(), from), (run.as_bytes(), client_addr));
+
+        // Starts over at the same msg-id from the same address
+        drop(client);
+        client = VstpUdpClient::bind(&client_addr.to_string()).await.unwrap();
+    }
+    assert_eq!(server.duplicate_frame_count(), 0);
+}
+
+#[tokio::test]
+async fn test_udp_wire_tap_sees_every_datagram() {
+    let wire_in = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let wire_out = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let tapped = wire_in.clone();
+    let server_config = UdpServerConfig {
+        wire_tap: WireTap::new().on_wire_in(move |b| tapped.lock().unwrap().push(b.to_vec())),
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", server_config)
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let tapped = wire_out.clone();
+    let client_config = UdpConfig {
+        wire_tap: WireTap::new().on_wire_out(move |b| tapped.lock().unwrap().push(b.to_vec())),
+        ..UdpConfig::default()
+    };
+    let client = VstpUdpClient::bind_with_config("127.0.0.1:0", client_config)
+        .await
+        .unwrap();
+
+    // Large enough to be fragmented: every fragment is tapped on its own
+    let frame = Frame::new(FrameType::Data).with_payload(vec![7u8; 3000]);
+    client.send(frame, server_addr).await.unwrap();
+    let (received, _) = timeout(Duration::from_secs(2), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.payload.len(), 3000);
+
+    let wire_out = wire_out.lock().unwrap();
+    assert!(wire_out.len() > 1);
+    assert_eq!(*wire_in.lock().unwrap(), *wire_out);
+}
+
+#[tokio::test]
+async fn test_udp_ingress_policy_drops_and_clamps() {
+    let config = UdpServerConfig {
+        ingress: Some(IngressPolicy {
+            strip_headers: vec!["x-internal-*".to_string()],
+            allowed_types: Some(vec![FrameType::Data]),
+            clamp_flags: true,
+            ..IngressPolicy::default()
+        }),
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let stats = server.ingress_stats();
+    let receiver = tokio::spawn(async move { server.recv().await.unwrap().0 });
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    // Not an allowed type: dropped without a reply
+    client.send(Frame::new(FrameType::Ping), addr).await.unwrap();
+    let mut data = Frame::new(FrameType::Data)
+        .with_header("x-internal-route", "7")
+        .with_payload(b"kept".to_vec());
+    data.flags = Flags::from_bits_retain(0b1000_0000);
+    client.send(data, addr).await.unwrap();
+
+    let frame = timeout(Duration::from_secs(5), receiver)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.payload, b"kept");
+    assert_eq!(frame.get_header("x-internal-route"), None);
+    assert_eq!(frame.flags.bits() & 0b1000_0000, 0);
+    assert_eq!(stats.rejected_frame_count(), 1);
+    assert_eq!(stats.stripped_header_count(), 1);
+    assert_eq!(stats.clamped_flag_count(), 1);
+}
+
+#[tokio::test]
+async fn test_udp_disallowed_frame_types_dropped() {
+    let config = UdpServerConfig {
+        allowed_frame_types: Some(HashSet::from([
+            FrameType::Hello,
+            FrameType::Data,
+            FrameType::Ping,
+            FrameType::Bye,
+        ])),
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let receiver = tokio::spawn(async move {
+        let (frame, _) = server.recv().await.unwrap();
+        (frame, server.rejected_frame_type_count())
+    });
+
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    client.send(Frame::new(FrameType::Ack), addr).await.unwrap();
+    client.send(Frame::new(FrameType::Welcome), addr).await.unwrap();
+    let data = Frame::new(FrameType::Data).with_payload(b"allowed".to_vec());
+    client.send(data, addr).await.unwrap();
+
+    let (frame, rejected) = timeout(Duration::from_secs(5), receiver)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.typ, FrameType::Data);
+    assert_eq!(frame.payload, b"allowed");
+    assert_eq!(rejected, 2);
+}
+
+#[tokio::test]
+async fn test_udp_receiver_queue_is_bounded() {
+    let config = UdpConfig {
+        receive_overflow: OverflowPolicy::DropNewest,
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    client.start_receiver(8);
+    let client_addr = client.local_addr().unwrap();
+
+    // Flood the client while nobody is reading
+    let sender = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    for n in 0..50u8 {
+        let frame = Frame::new(FrameType::Data).with_payload(vec![n]);
+        sender.send(frame, client_addr).await.unwrap();
+    }
+    let start = std::time::Instant::now();
+    while client.dropped_frame_count() < 42 && start.elapsed() < Duration::from_secs(2) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(client.queued_frame_count(), 8);
+    assert_eq!(client.dropped_frame_count(), 42);
+
+    // The oldest frames were kept
+    for n in 0..8u8 {
+        let (frame, _) = client.recv_timeout(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(frame.payload, [n]);
+    }
+    let result = client.recv_timeout(Duration::from_millis(50)).await;
+    assert!(matches!(result, Err(VstpError::Timeout)));
+}
+
+#[tokio::test]
+async fn test_udp_ack_wait_leaves_other_frames_queued() {
+    let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    client.start_receiver(16);
+
+    // Push two unrelated frames to the client before acknowledging
+    let responder = tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        let (_, client_addr) = peer.recv_from(&mut buf).await.unwrap();
+        for text in ["first", "second"] {
+            let frame = Frame::new(FrameType::Data).with_payload(text.as_bytes().to_vec());
+            let bytes = encode_frame(&frame).unwrap();
+            peer.send_to(&bytes, client_addr).await.unwrap();
+        }
+        let ack = Frame::new(FrameType::Ack).with_header("msg-id", "1");
+        let bytes = encode_frame(&ack).unwrap();
+        peer.send_to(&bytes, client_addr).await.unwrap();
+    });
+
+    let frame = Frame::new(FrameType::Data).with_payload(b"request".to_vec());
+    timeout(Duration::from_secs(5), client.send_with_ack(frame, peer_addr))
+        .await
+        .unwrap()
+        .unwrap();
+    responder.await.unwrap();
+
+    for text in ["first", "second"] {
+        let (frame, from) = client.recv_timeout(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(from, peer_addr);
+        assert_eq!(frame.payload, text.as_bytes());
+    }
+    assert_eq!(client.dropped_frame_count(), 0);
+}
+
+#[tokio::test]
+async fn test_udp_ack_gets_through_a_full_receive_queue() {
+    let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let config = UdpConfig {
+        receive_overflow: OverflowPolicy::DropNewest,
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    client.start_receiver(4);
+
+    // Fill the queue before acknowledging, so the ACK arrives to a full queue
+    let responder = tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        let (_, client_addr) = peer.recv_from(&mut buf).await.unwrap();
+        for n in 0..10u8 {
+            let frame = Frame::new(FrameType::Data).with_payload(vec![n]);
+            let bytes = encode_frame(&frame).unwrap();
+            peer.send_to(&bytes, client_addr).await.unwrap();
+        }
+        let ack = Frame::new(FrameType::Ack).with_header("msg-id", "1");
+        let bytes = encode_frame(&ack).unwrap();
+        peer.send_to(&bytes, client_addr).await.unwrap();
+    });
+
+    let frame = Frame::new(FrameType::Data).with_payload(b"request".to_vec());
+    timeout(Duration::from_secs(5), client.send_with_ack(frame, peer_addr))
+        .await
+        .unwrap()
+        .unwrap();
+    responder.await.unwrap();
+
+    assert_eq!(client.dropped_frame_count(), 6);
+    for n in 0..4u8 {
+        let (frame, _) = client.recv_timeout(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(frame.payload, [n]);
+    }
+}
+
+#[tokio::test]
+async fn test_udp_frame_meta() {
+    let config = UdpServerConfig {
+        kernel_timestamps: true,
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    let frames: Vec<Frame> = (0..5)
+        .map(|i| Frame::new(FrameType::Data).with_payload(vec![1; i * 50]))
+        .collect();
+    for frame in &frames {
+        client.send(frame.clone(), addr).await.unwrap();
+    }
+
+    let mut previous = None;
+    for expected in &frames {
+        let (frame, _, meta) = timeout(Duration::from_secs(5), server.recv_with_meta())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&frame, expected);
+        assert_eq!(meta.wire_len, encode_frame(expected).unwrap().len());
+        assert_eq!(meta.transport, TransportKind::Udp);
+        if cfg!(target_os = "linux") {
+            let kernel = meta.kernel_timestamp.expect("kernel timestamp");
+            assert!(kernel <= std::time::SystemTime::now());
+        }
+        if let Some(previous) = previous {
+            assert!(previous <= meta.received_at);
+        }
+        previous = Some(meta.received_at);
+    }
+}
+
+/// Send `count` acknowledged frames to `dest`
+async fn send_acked(client: &mut VstpUdpClient, dest: std::net::SocketAddr, count: usize) {
+    for n in 0..count {
+        let frame = Frame::new(FrameType::Data).with_payload(n.to_string().into_bytes());
+        client.send_with_ack(frame, dest).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_udp_ack_timeout_adapts_to_link_latency() {
+    let server = spawn_udp_server(|_| None).await.unwrap();
+    let link = |one_way_ms| LossConfig {
+        latency: Duration::from_millis(one_way_ms),
+        ..LossConfig::default()
+    };
+    let fast = LossyUdpProxy::start(server.addr(), link(10)).await.unwrap();
+    let slow = LossyUdpProxy::start(server.addr(), link(75)).await.unwrap();
+
+    let config = UdpConfig {
+        ack_timeout: Duration::from_secs(1),
+        retry_delay: Duration::from_millis(10),
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    assert_eq!(client.peer_rtt(fast.addr()), None);
+    send_acked(&mut client, fast.addr(), 10).await;
+    send_acked(&mut client, slow.addr(), 10).await;
+
+    // Each destination has its own estimate; 150ms round trips cause no spurious retransmissions
+    let fast_rtt = client.peer_rtt(fast.addr()).unwrap();
+    let slow_rtt = client.peer_rtt(slow.addr()).unwrap();
+    assert_eq!((fast_rtt.samples, fast_rtt.retransmits), (10, 0));
+    assert_eq!((slow_rtt.samples, slow_rtt.retransmits), (10, 0));
+    let fast_srtt = fast_rtt.srtt.unwrap();
+    let slow_srtt = slow_rtt.srtt.unwrap();
+    assert!(
+        fast_srtt >= Duration::from_millis(20) && fast_srtt < Duration::from_millis(100),
+        "fast srtt {:?}",
+        fast_srtt
+    );
+    assert!(
+        slow_srtt >= Duration::from_millis(150) && slow_srtt < Duration::from_millis(300),
+        "slow srtt {:?}",
+        slow_srtt
+    );
+    assert!(fast_rtt.rto < slow_rtt.rto);
+    assert!(slow_rtt.rto > slow_srtt && slow_rtt.rto < Duration::from_secs(1));
+
+    // A lost frame is resent after the learned timeout rather than `ack_timeout`
+    slow.drop_next(1);
+    let start = tokio::time::Instant::now();
+    send_acked(&mut client, slow.addr(), 1).await;
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= slow_rtt.rto && elapsed < Duration::from_secs(1),
+        "took {:?}",
+        elapsed
+    );
+    let backed_off = client.peer_rtt(slow.addr()).unwrap();
+    assert_eq!((backed_off.samples, backed_off.retransmits), (10, 1));
+    assert_eq!(backed_off.rto, slow_rtt.rto * 2);
+
+    // After a path change the estimate starts over
+    client.reset_rtt(slow.addr());
+    assert_eq!(client.peer_rtt(slow.addr()), None);
+    send_acked(&mut client, slow.addr(), 1).await;
+    assert_eq!(client.peer_rtt(slow.addr()).unwrap().samples, 1);
+}
+
+#[tokio::test]
+async fn test_udp_corrupted_chunks_are_repaired() {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let proxy = LossyUdpProxy::start(server.local_addr().unwrap(), LossConfig::default())
+        .await
+        .unwrap();
+    let receiving = tokio::spawn(async move {
+        let mut receiver = ChunkReceiver::new();
+        loop {
+            let (frame, from) = server.recv().await.unwrap();
+            match receiver.receive(frame).unwrap() {
+                ChunkEvent::Pending => {}
+                ChunkEvent::Repair(request) => server.send(request, from).await.unwrap(),
+                ChunkEvent::Complete(payload) => return (payload, receiver.report()),
+            }
+        }
+    });
+
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let sender = ChunkSender::new(&data, 1000);
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    for (seq, chunk) in sender.chunks().iter().enumerate() {
+        if seq == 3 || seq == 11 {
+            proxy.corrupt_next(1);
+        }
+        client.send(chunk.clone(), proxy.addr()).await.unwrap();
+    }
+    let repairing = tokio::spawn(async move {
+        loop {
+            let (request, _) = client.recv().await.unwrap();
+            let chunk = sender.repair(&request).unwrap();
+            client.send(chunk, proxy.addr()).await.unwrap();
+        }
+    });
+
+    let (payload, report) = timeout(Duration::from_secs(5), receiving)
+        .await
+        .unwrap()
+        .unwrap();
+    repairing.abort();
+    // The final chunk's payload-hash matched, or the receiver would have failed
+    assert_eq!(payload, data);
+    assert_eq!(report.chunks, 20);
+    assert_eq!(report.repairs, 2);
+}
+
+#[tokio::test]
+async fn test_udp_group_fails_over_and_back() {
+    // Collectors count readings and answer probes
+    let collector = |readings: Arc<AtomicU64>| {
+        move |frame: Frame| match frame.typ {
+            FrameType::Ping => Some(Frame::new(FrameType::Pong)),
+            _ => {
+                readings.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    };
+    let (primary_readings, secondary_readings) = (Arc::default(), Arc::default());
+    let primary = spawn_udp_server(collector(Arc::clone(&primary_readings)))
+        .await
+        .unwrap();
+    let secondary = spawn_udp_server(collector(Arc::clone(&secondary_readings)))
+        .await
+        .unwrap();
+    let proxy = LossyUdpProxy::start(primary.addr(), LossConfig::default())
+        .await
+        .unwrap();
+
+    let group = DestinationGroup::new(
+        vec![proxy.addr(), secondary.addr()],
+        FailoverPolicy {
+            failures_to_trip: 2,
+            probe_interval: Duration::from_millis(300),
+        },
+    );
+    let mut status = group.subscribe();
+    let config = UdpConfig {
+        ack_timeout: Duration::from_millis(50),
+        retry_delay: Duration::from_millis(10),
+        max_retries: 3,
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let reading = |n: u32| Frame::new(FrameType::Data).with_payload(n.to_string().into_bytes());
+
+    assert_eq!(
+        client
+            .send_with_ack_group(reading(0), &group)
+            .await
+            .unwrap(),
+        proxy.addr()
+    );
+
+    // Black-holed, the primary gets exactly `failures_to_trip` tries
+    proxy.set_drop_rate(1.0);
+    assert_eq!(
+        client
+            .send_with_ack_group(reading(1), &group)
+            .await
+            .unwrap(),
+        secondary.addr()
+    );
+    assert_eq!(proxy.dropped(), 2);
+    assert_eq!(
+        *status.borrow_and_update(),
+        vstp::udp::GroupStatus {
+            active: secondary.addr(),
+            tripped: vec![proxy.addr()],
+            last_event: Some(FailoverEvent::Tripped(proxy.addr())),
+        }
+    );
+    assert_eq!(
+        client
+            .send_with_ack_group(reading(2), &group)
+            .await
+            .unwrap(),
+        secondary.addr()
+    );
+
+    // Healed, the primary answers the next probe and takes over again
+    proxy.set_drop_rate(0.0);
+    timeout(Duration::from_secs(2), status.changed())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        status.borrow().last_event,
+        Some(FailoverEvent::Restored(proxy.addr()))
+    );
+    assert_eq!(group.active(), proxy.addr());
+    assert_eq!(
+        client
+            .send_with_ack_group(reading(3), &group)
+            .await
+            .unwrap(),
+        proxy.addr()
+    );
+    assert_eq!(primary_readings.load(Ordering::Relaxed), 2);
+    assert_eq!(secondary_readings.load(Ordering::Relaxed), 2);
+}
+
+/// Serve on all addresses with `ack_source`, returning the port
+#[cfg(target_os = "linux")]
+async fn spawn_acking_server(ack_source: AckSource) -> u16 {
+    let config = UdpServerConfig {
+        ack_source,
+        ack_observed_addr: true,
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("0.0.0.0:0", config)
+        .await
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+    tokio::spawn(async move { while server.recv().await.is_ok() {} });
+    port
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_udp_ack_source_on_multi_address_host() {
+    // Loopback answers on all of 127.0.0.0/8, which stands in for a host
+    // with several addresses: routing sends replies to 127.0.0.1 from 127.0.0.1
+    let second = |port| std::net::SocketAddr::from(([127, 0, 0, 2], port));
+    let config = UdpConfig {
+        max_retries: 1,
+        ack_timeout: Duration::from_millis(200),
+        retry_delay: Duration::from_millis(10),
+        ..UdpConfig::default()
+    };
+    let mut client = VstpUdpClient::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let data = || Frame::new(FrameType::Data).with_payload(b"reading".to_vec());
+
+    // Left to routing, the ACK comes from the wrong address and is dropped
+    let port = spawn_acking_server(AckSource::Routing).await;
+    assert!(client.send_with_ack(data(), second(port)).await.is_err());
+
+    let port = spawn_acking_server(AckSource::ArrivalAddress).await;
+    client.send_with_ack(data(), second(port)).await.unwrap();
+
+    // A fixed source, whatever address the frame was sent to
+    let port = spawn_acking_server(AckSource::Address([127, 0, 0, 3].into())).await;
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let request = data().with_flag(Flags::REQ_ACK).with_header("msg-id", "7");
+    socket
+        .send_to(&encode_frame(&request).unwrap(), ("127.0.0.1", port))
+        .await
+        .unwrap();
+    let mut buf = [0u8; 1024];
+    let (len, from) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from, std::net::SocketAddr::from(([127, 0, 0, 3], port)));
+    let ack = try_decode_frame(&mut bytes::BytesMut::from(&buf[..len]), 1024)
+        .unwrap()
+        .unwrap();
+    assert_eq!(ack.typ, FrameType::Ack);
+    assert_eq!(ack.get_header("msg-id"), Some("7"));
+    let observed = socket.local_addr().unwrap().to_string();
+    assert_eq!(
+        ack.get_header(OBSERVED_ADDR_HEADER),
+        Some(observed.as_str())
+    );
+}
+
+#[tokio::test]
+async fn test_udp_peer_state_stays_within_limits_under_spray() {
+    let config = UdpServerConfig {
+        max_peers: 64,
+        max_total_peer_memory_bytes: 32 * 1024,
+        ..UdpServerConfig::default()
+    };
+    let server = Arc::new(
+        VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addr().unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = server.clone();
+    tokio::spawn(async move {
+        while let Ok(received) = receiver.recv().await {
+            let _ = tx.send(received);
+        }
+    });
+
+    // A good peer sends half of a large frame...
+    let good = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let good_addr = good.local_addr().unwrap();
+    let frame = Frame::new(FrameType::Data).with_payload(vec![0x5a; 5000]);
+    let fragments = fragment_frame(&frame, 3).unwrap();
+    let half = fragments.len() / 2;
+    for fragment in &fragments[..half] {
+        let datagram = encode_frame(fragment).unwrap();
+        good.send_to(&datagram, server_addr).await.unwrap();
+    }
+
+    // ...then 2000 other addresses send a frame each
+    for n in 0..2000u32 {
+        let spray = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let frame = Frame::new(FrameType::Data).with_payload(n.to_be_bytes().to_vec());
+        spray
+            .send_to(&encode_frame(&frame).unwrap(), server_addr)
+            .unwrap();
+        if n % 50 == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    // The good peer's half-received frame survived and completes
+    for fragment in &fragments[half..] {
+        let datagram = encode_frame(fragment).unwrap();
+        good.send_to(&datagram, server_addr).await.unwrap();
+    }
+    let complete = timeout(Duration::from_secs(5), async {
+        loop {
+            let (frame, from) = rx.recv().await.unwrap();
+            if from == good_addr {
+                return frame;
+            }
+        }
+    })
+    .await
+    .expect("the good peer's frame");
+    assert_eq!(complete.payload, frame.payload);
+
+    let stats = server.peer_state_stats();
+    assert!(stats.peak_peers <= 64, "{:?}", stats);
+    assert!(stats.peak_bytes <= 32 * 1024, "{:?}", stats);
+    assert!(stats.evicted_idle >= 1000, "{:?}", stats);
+    assert_eq!(stats.evicted_busy, 0);
+    assert_eq!(server.reassembly_session_count().await, 0);
+}
+
+/// A `REQ_ACK` DATA frame sent under `msg_id`
+fn reliable_frame(msg_id: u64) -> Frame {
+    Frame::new(FrameType::Data)
+        .with_flag(Flags::REQ_ACK)
+        .with_header("msg-id", &msg_id.to_string())
+        .with_payload(b"burst".to_vec())
+}
+
+/// Ids acknowledged by an ACK frame, plain or batched
+fn acked_ids(ack: &Frame) -> Vec<u64> {
+    if let Some(id) = ack.get_header("msg-id") {
+        return vec![id.parse().unwrap()];
+    }
+    let ranges = decode_msg_ids(ack.get_header(MSG_IDS_HEADER).unwrap()).unwrap();
+    ranges.into_iter().flatten().collect()
+}
+
+#[tokio::test]
+async fn test_burst_of_req_ack_frames_gets_batched_acks() {
+    let server = VstpUdpServer::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move { while server.recv().await.is_ok() {} });
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut acked = HashSet::new();
+    let mut ack_datagrams = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    // Waves small enough for the server's receive buffer, each sent before
+    // the server task gets to run
+    for wave in 0..10u64 {
+        socket.writable().await.unwrap();
+        for msg_id in wave * 100..(wave + 1) * 100 {
+            let encoded = encode_frame(&reliable_frame(msg_id)).unwrap();
+            socket.try_send_to(&encoded, server_addr).unwrap();
+        }
+        while acked.len() < (wave as usize + 1) * 100 {
+            let (len, _) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+                .await
+                .expect("ACKs for the whole wave")
+                .unwrap();
+            let ack = try_decode_frame(&mut buf[..len].into(), 65536)
+                .unwrap()
+                .unwrap();
+            assert_eq!(ack.typ, FrameType::Ack);
+            ack_datagrams += 1;
+            acked.extend(acked_ids(&ack));
+        }
+    }
+    assert_eq!(acked, (0..1000).collect::<HashSet<u64>>());
+    assert!(ack_datagrams <= 100, "{} ACK datagrams", ack_datagrams);
+}
+
+#[tokio::test]
+async fn test_udp_dedup_by_content_hash_without_msg_ids() {
+    let config = UdpServerConfig {
+        dedup_by_content_hash: Some(Duration::from_millis(300)),
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let reading = |value: &str| {
+        let frame = Frame::new(FrameType::Data).with_payload(value.as_bytes().to_vec());
+        encode_frame(&frame).unwrap()
+    };
+
+    // A sender without msg-ids retransmitting the same reading
+    for _ in 0..3 {
+        socket.send_to(&reading("21.5C"), server_addr).await.unwrap();
+    }
+    socket.send_to(&reading("21.6C"), server_addr).await.unwrap();
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let (frame, _) = timeout(Duration::from_secs(1), server.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        received.push(frame.payload);
+    }
+    assert_eq!(received, [b"21.5C".to_vec(), b"21.6C".to_vec()]);
+    assert_eq!(server.duplicate_frame_count(), 2);
+
+    // Past the window, the same reading counts as a new one
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    socket.send_to(&reading("21.5C"), server_addr).await.unwrap();
+    let (frame, _) = timeout(Duration::from_secs(1), server.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.payload, b"21.5C");
+    assert_eq!(server.duplicate_frame_count(), 2);
+}
+
+#[tokio::test]
+async fn test_delayed_acks_cause_no_retransmissions() {
+    let server = Arc::new(VstpUdpServer::bind("127.0.0.1:0").await.unwrap());
+    let server_addr = server.local_addr().unwrap();
+    let receiver = server.clone();
+    tokio::spawn(async move { while receiver.recv().await.is_ok() {} });
+
+    let mut client = VstpUdpClient::bind("127.0.0.1:0").await.unwrap();
+    for i in 0..200 {
+        let frame = Frame::new(FrameType::Data).with_payload(format!("frame {}", i).into_bytes());
+        client.send_with_ack(frame, server_addr).await.unwrap();
+    }
+    // Every retransmission would have been dropped as a duplicate
+    assert_eq!(server.duplicate_frame_count(), 0);
+}
+
+#[tokio::test]
+async fn test_urgent_frame_is_acked_at_once_with_pending_acks() {
+    let config = UdpServerConfig {
+        ack_delay: Some(Duration::from_secs(10)),
+        ..UdpServerConfig::default()
+    };
+    let server = VstpUdpServer::bind_with_config("127.0.0.1:0", config)
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    // Delivered while the server isn't receiving again, so its ACK waits
+    let encoded = encode_frame(&reliable_frame(1)).unwrap();
+    socket.send_to(&encoded, server_addr).await.unwrap();
+    server.recv().await.unwrap();
+    assert!(
+        timeout(Duration::from_millis(200), socket.recv_from(&mut buf))
+            .await
+            .is_err()
+    );
+
+    let urgent = reliable_frame(2).with_priority(Priority::P0);
+    socket
+        .send_to(&encode_frame(&urgent).unwrap(), server_addr)
+        .await
+        .unwrap();
+    let (frame, _) = server.recv().await.unwrap();
+    assert_eq!(frame.priority(), Priority::P0);
+    let (len, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+        .await
+        .expect("ACK for the urgent frame")
+        .unwrap();
+    let ack = try_decode_frame(&mut buf[..len].into(), 65536)
+        .unwrap()
+        .unwrap();
+    assert!(acknowledges(&ack, 1) && acknowledges(&ack, 2));
+}